@@ -0,0 +1,801 @@
+//! Tar and zip archives of a tree, like `git archive`.
+//!
+//! [`archive`] resolves a commit, tag or tree, walks the tree through a [`RepositoryAccess`]
+//! and streams the archive as it reads the blobs, for upload-archive and "Download ZIP"
+//! endpoints alike. Like git, every entry is dated with the committer time of the commit, or
+//! the current time for a tree; files are `0664`, or `0775` when executable, symlinks keep
+//! their target, and submodules are empty directories. The commit id is recorded in the pax
+//! global header of a tar and in the comment of a zip, where `git get-tar-commit-id` finds it.
+use std::fmt::{self, Display};
+use std::io::Write;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use flate2::Compression;
+use flate2::write::{DeflateEncoder, GzEncoder};
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::hash::SHA1;
+use crate::internal::object::ObjectTrait;
+use crate::internal::object::commit::Commit;
+use crate::internal::object::tag::Tag;
+use crate::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+use crate::internal::object::types::ObjectType;
+use crate::protocol::RepositoryAccess;
+use crate::protocol::pack::load_object;
+use crate::protocol::types::{ProtocolError, ProtocolStream};
+
+/// The size of the blocks of a tar.
+const BLOCK_SIZE: usize = 512;
+
+/// Tars are padded to records of 20 blocks, like git.
+const RECORD_SIZE: usize = 20 * BLOCK_SIZE;
+
+/// The compression level of zips and gzipped tars unless set, zlib's default like git.
+const DEFAULT_COMPRESSION_LEVEL: u32 = 6;
+
+/// The formats of `git archive --format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArchiveFormat {
+    #[default]
+    Tar,
+    /// A gzipped tar, `tgz` or `tar.gz`
+    TarGz,
+    Zip,
+}
+
+impl Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveFormat::Tar => write!(f, "tar"),
+            ArchiveFormat::TarGz => write!(f, "tar.gz"),
+            ArchiveFormat::Zip => write!(f, "zip"),
+        }
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = ProtocolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tar" => Ok(ArchiveFormat::Tar),
+            "tgz" | "tar.gz" => Ok(ArchiveFormat::TarGz),
+            "zip" => Ok(ArchiveFormat::Zip),
+            _ => Err(ProtocolError::invalid_request(&format!(
+                "Unknown archive format '{s}'"
+            ))),
+        }
+    }
+}
+
+/// What [`archive`] archives of a tree, and how.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ArchiveOptions {
+    pub format: ArchiveFormat,
+    /// Prepended to every path of the archive, e.g. `project-1.0/`
+    pub prefix: String,
+    /// The compression level from 0 to 9 of a zip or gzipped tar, 6 unless set
+    pub compression_level: Option<u32>,
+    /// The subtree to archive instead of the whole tree, e.g. `docs` for `main:docs`
+    pub tree_path: Option<String>,
+    /// The paths to archive, the whole tree when empty
+    pub paths: Vec<String>,
+}
+
+/// What an archive entry is, from the mode of its tree item.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EntryKind {
+    Directory,
+    File { executable: bool },
+    Symlink,
+}
+
+/// Stream the archive of the tree of `id`, a commit, a tag of one or a tree.
+///
+/// The tree-ish, its `tree_path` and its `paths` are checked before the archive starts, so
+/// those errors come first; failures to read the blobs end the stream.
+pub async fn archive<R: RepositoryAccess + 'static>(
+    repo: &R,
+    id: &SHA1,
+    options: &ArchiveOptions,
+) -> Result<ProtocolStream, ProtocolError> {
+    let (commit, mtime, mut tree) = resolve_tree_ish(repo, *id).await?;
+    if let Some(path) = options.tree_path.as_deref()
+        && !path.is_empty()
+    {
+        tree = match find_item(repo, tree, path).await? {
+            Some(item) if item.mode == TreeItemMode::Tree => {
+                repo.get_tree(&item.id.to_string()).await?
+            }
+            _ => {
+                return Err(ProtocolError::invalid_request(&format!(
+                    "Not a valid object name {id}:{path}"
+                )));
+            }
+        };
+    }
+    let paths: Vec<String> = options
+        .paths
+        .iter()
+        .map(|path| path.trim_matches('/').to_string())
+        .collect();
+    for path in &paths {
+        if !path.is_empty() && find_item(repo, tree.clone(), path).await?.is_none() {
+            return Err(ProtocolError::invalid_request(&format!(
+                "pathspec '{path}' did not match any files"
+            )));
+        }
+    }
+
+    let mut writer = ArchiveWriter::new(options, commit, mtime);
+    let prefix = options.prefix.clone();
+    let repo = repo.clone();
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    tokio::spawn(async move {
+        let written = async {
+            let mut entries = Vec::new();
+            if prefix.ends_with('/') {
+                entries.push(writer.entry(&prefix, EntryKind::Directory, &[])?);
+            }
+            // Depth first, each directory right before its content
+            let mut stack = vec![(tree.tree_items.into_iter(), String::new())];
+            while let Some((items, base)) = stack.last_mut() {
+                let Some(item) = items.next() else {
+                    stack.pop();
+                    continue;
+                };
+                let path = format!("{base}{}", item.name);
+                if !is_selected(&paths, &path) {
+                    continue;
+                }
+                let name = format!("{prefix}{path}");
+                let kind = match item.mode {
+                    TreeItemMode::Tree | TreeItemMode::Commit => EntryKind::Directory,
+                    TreeItemMode::Link => EntryKind::Symlink,
+                    TreeItemMode::Blob => EntryKind::File { executable: false },
+                    TreeItemMode::BlobExecutable => EntryKind::File { executable: true },
+                };
+                let entry = match item.mode {
+                    TreeItemMode::Tree => {
+                        let subtree = repo.get_tree(&item.id.to_string()).await?;
+                        stack.push((subtree.tree_items.into_iter(), format!("{path}/")));
+                        writer.entry(&format!("{name}/"), kind, &[])?
+                    }
+                    TreeItemMode::Commit => writer.entry(&format!("{name}/"), kind, &[])?,
+                    _ => {
+                        let data = repo.get_object(&item.id.to_string()).await?;
+                        writer.entry(&name, kind, &data)?
+                    }
+                };
+                entries.push(entry);
+                if entries.len() >= 16 || entries.iter().map(Vec::len).sum::<usize>() > 1 << 20 {
+                    let chunk = Bytes::from(std::mem::take(&mut entries).concat());
+                    if tx.send(Ok(chunk)).await.is_err() {
+                        // The client is gone
+                        return Ok(());
+                    }
+                }
+            }
+            entries.push(writer.finish()?);
+            let _ = tx.send(Ok(Bytes::from(entries.concat()))).await;
+            Ok(())
+        }
+        .await;
+        if let Err(e) = written {
+            let _ = tx.send(Err(e)).await;
+        }
+    });
+    Ok(Box::pin(ReceiverStream::new(rx)))
+}
+
+/// The commit, the time of the entries and the tree of the tree-ish `id`.
+async fn resolve_tree_ish<R: RepositoryAccess>(
+    repo: &R,
+    mut id: SHA1,
+) -> Result<(Option<SHA1>, u64, Tree), ProtocolError> {
+    let invalid = |id: SHA1, e: &dyn Display| ProtocolError::corrupt_object(id, e);
+    loop {
+        let (obj_type, data) = load_object(repo, &id)
+            .await?
+            .ok_or_else(|| ProtocolError::ObjectNotFound(id.to_string()))?;
+        match obj_type {
+            ObjectType::Tag => {
+                id = Tag::from_bytes(&data, id)
+                    .map_err(|e| invalid(id, &e))?
+                    .object_hash
+            }
+            ObjectType::Commit => {
+                let commit = Commit::from_bytes(&data, id).map_err(|e| invalid(id, &e))?;
+                let tree = repo.get_tree(&commit.tree_id.to_string()).await?;
+                return Ok((Some(id), commit.committer.timestamp as u64, tree));
+            }
+            ObjectType::Tree => {
+                let tree = Tree::from_bytes(&data, id).map_err(|e| invalid(id, &e))?;
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                return Ok((None, now.as_secs(), tree));
+            }
+            _ => {
+                return Err(ProtocolError::invalid_request(&format!(
+                    "{id} is not a tree object"
+                )));
+            }
+        }
+    }
+}
+
+/// The item at `path` below `tree`.
+async fn find_item<R: RepositoryAccess>(
+    repo: &R,
+    mut tree: Tree,
+    path: &str,
+) -> Result<Option<TreeItem>, ProtocolError> {
+    let components: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+    for (i, name) in components.iter().enumerate() {
+        let Some(item) = tree.tree_items.iter().find(|item| item.name == *name) else {
+            return Ok(None);
+        };
+        if i + 1 == components.len() {
+            return Ok(Some(item.clone()));
+        }
+        if item.mode != TreeItemMode::Tree {
+            return Ok(None);
+        }
+        tree = repo.get_tree(&item.id.to_string()).await?;
+    }
+    Ok(None)
+}
+
+/// Whether `path` is archived: it is one of `paths`, below one or a directory leading to one.
+fn is_selected(paths: &[String], path: &str) -> bool {
+    let below = |path: &str, dir: &str| {
+        dir.is_empty()
+            || path
+                .strip_prefix(dir)
+                .is_some_and(|rest| rest.starts_with('/'))
+    };
+    paths.is_empty()
+        || paths
+            .iter()
+            .any(|wanted| wanted == path || below(path, wanted) || below(wanted, path))
+}
+
+/// Encodes the entries of an archive, one at a time.
+enum ArchiveWriter {
+    Tar(TarWriter),
+    TarGz(TarWriter, GzEncoder<Vec<u8>>),
+    Zip(ZipWriter),
+}
+
+impl ArchiveWriter {
+    fn new(options: &ArchiveOptions, commit: Option<SHA1>, mtime: u64) -> Self {
+        let level = Compression::new(
+            options
+                .compression_level
+                .unwrap_or(DEFAULT_COMPRESSION_LEVEL),
+        );
+        match options.format {
+            ArchiveFormat::Tar => ArchiveWriter::Tar(TarWriter::new(commit, mtime)),
+            ArchiveFormat::TarGz => ArchiveWriter::TarGz(
+                TarWriter::new(commit, mtime),
+                GzEncoder::new(Vec::new(), level),
+            ),
+            ArchiveFormat::Zip => ArchiveWriter::Zip(ZipWriter::new(commit, mtime, level)),
+        }
+    }
+
+    /// The bytes of the entry `path`, a directory when it ends with `/`.
+    fn entry(
+        &mut self,
+        path: &str,
+        kind: EntryKind,
+        data: &[u8],
+    ) -> Result<Vec<u8>, ProtocolError> {
+        match self {
+            ArchiveWriter::Tar(tar) => Ok(tar.entry(path, kind, data)),
+            ArchiveWriter::TarGz(tar, gz) => {
+                gz.write_all(&tar.entry(path, kind, data))?;
+                Ok(std::mem::take(gz.get_mut()))
+            }
+            ArchiveWriter::Zip(zip) => zip.entry(path, kind, data),
+        }
+    }
+
+    /// The last bytes of the archive.
+    fn finish(&mut self) -> Result<Vec<u8>, ProtocolError> {
+        match self {
+            ArchiveWriter::Tar(tar) => Ok(tar.finish()),
+            ArchiveWriter::TarGz(tar, gz) => {
+                gz.write_all(&tar.finish())?;
+                gz.try_finish()?;
+                Ok(std::mem::take(gz.get_mut()))
+            }
+            ArchiveWriter::Zip(zip) => zip.finish(),
+        }
+    }
+}
+
+/// A ustar archive with pax headers for long names, like git's.
+struct TarWriter {
+    commit: Option<SHA1>,
+    mtime: u64,
+    /// The bytes written so far, padded to a record at the end
+    written: usize,
+}
+
+impl TarWriter {
+    fn new(commit: Option<SHA1>, mtime: u64) -> Self {
+        Self {
+            commit,
+            mtime,
+            written: 0,
+        }
+    }
+
+    fn entry(&mut self, path: &str, kind: EntryKind, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(3 * BLOCK_SIZE + data.len());
+        if self.written == 0
+            && let Some(commit) = self.commit
+        {
+            let comment = pax_record("comment", commit.to_string().as_bytes());
+            self.push_file(&mut out, b"pax_global_header", 0o666, b'g', b"", &comment);
+        }
+        let (mode, typeflag, link, content) = match kind {
+            EntryKind::Directory => (0o775, b'5', &[][..], &[][..]),
+            EntryKind::File { executable } => {
+                (if executable { 0o775 } else { 0o664 }, b'0', &[][..], data)
+            }
+            EntryKind::Symlink => (0o777, b'2', data, &[][..]),
+        };
+        let mut extended = Vec::new();
+        if path.len() > 100 {
+            extended.extend(pax_record("path", path.as_bytes()));
+        }
+        if link.len() > 100 {
+            extended.extend(pax_record("linkpath", link));
+        }
+        if !extended.is_empty() {
+            self.push_file(&mut out, b"pax_header", 0o666, b'x', b"", &extended);
+        }
+        self.push_file(&mut out, path.as_bytes(), mode, typeflag, link, content);
+        out
+    }
+
+    /// A header and its padded content.
+    fn push_file(
+        &mut self,
+        out: &mut Vec<u8>,
+        name: &[u8],
+        mode: u32,
+        typeflag: u8,
+        link: &[u8],
+        content: &[u8],
+    ) {
+        let start = out.len();
+        out.extend_from_slice(&tar_header(
+            name,
+            mode,
+            content.len(),
+            self.mtime,
+            typeflag,
+            link,
+        ));
+        out.extend_from_slice(content);
+        out.resize(
+            out.len() + content.len().next_multiple_of(BLOCK_SIZE) - content.len(),
+            0,
+        );
+        self.written += out.len() - start;
+    }
+
+    /// Two zero blocks, then the padding of the last record.
+    fn finish(&mut self) -> Vec<u8> {
+        let end = (self.written + 2 * BLOCK_SIZE).next_multiple_of(RECORD_SIZE);
+        let padding = vec![0; end - self.written];
+        self.written = end;
+        padding
+    }
+}
+
+/// A pax record, `<length> <key>=<value>\n` where the length counts its own digits.
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    let len = key.len() + value.len() + 3;
+    let mut total = len + len.to_string().len();
+    if total.to_string().len() > len.to_string().len() {
+        total += 1;
+    }
+    let mut record = format!("{total} {key}=").into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+/// A ustar header, owned by root; names longer than their field are cut, a pax header holding
+/// them in full.
+fn tar_header(
+    name: &[u8],
+    mode: u32,
+    size: usize,
+    mtime: u64,
+    typeflag: u8,
+    link: &[u8],
+) -> [u8; BLOCK_SIZE] {
+    let mut header = [0u8; BLOCK_SIZE];
+    let put = |header: &mut [u8; BLOCK_SIZE], at: usize, len: usize, value: &[u8]| {
+        let value = &value[..value.len().min(len)];
+        header[at..at + value.len()].copy_from_slice(value);
+    };
+    let octal = |header: &mut [u8; BLOCK_SIZE], at: usize, len: usize, value: u64| {
+        let digits = format!("{value:0width$o}", width = len - 1);
+        put(header, at, len, digits.as_bytes());
+    };
+    put(&mut header, 0, 100, name);
+    octal(&mut header, 100, 8, u64::from(mode));
+    octal(&mut header, 108, 8, 0);
+    octal(&mut header, 116, 8, 0);
+    octal(&mut header, 124, 12, size as u64);
+    octal(&mut header, 136, 12, mtime);
+    header[148..156].fill(b' ');
+    header[156] = typeflag;
+    put(&mut header, 157, 100, link);
+    put(&mut header, 257, 8, b"ustar\x0000");
+    put(&mut header, 265, 32, b"root");
+    put(&mut header, 297, 32, b"root");
+    octal(&mut header, 329, 8, 0);
+    octal(&mut header, 337, 8, 0);
+    let checksum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+    octal(&mut header, 148, 8, u64::from(checksum));
+    header[155] = 0;
+    header
+}
+
+/// A zip without zip64 extensions, with unix modes and extended timestamps, like git's.
+struct ZipWriter {
+    commit: Option<SHA1>,
+    mtime: u32,
+    dos_time: u16,
+    dos_date: u16,
+    level: Compression,
+    offset: usize,
+    central_directory: Vec<u8>,
+    entries: usize,
+}
+
+/// Unix, version 2.3 of the spec: the high bytes of the external attributes are the mode.
+const ZIP_CREATOR_VERSION: u16 = 0x0317;
+
+/// The "universal time" extra field holding the modification time.
+const ZIP_EXTENDED_TIMESTAMP: u16 = 0x5455;
+
+impl ZipWriter {
+    fn new(commit: Option<SHA1>, mtime: u64, level: Compression) -> Self {
+        let time = chrono::DateTime::from_timestamp(mtime as i64, 0).unwrap_or_default();
+        let (dos_time, dos_date) = dos_date_time(&time);
+        Self {
+            commit,
+            mtime: mtime.min(u64::from(u32::MAX)) as u32,
+            dos_time,
+            dos_date,
+            level,
+            offset: 0,
+            central_directory: Vec::new(),
+            entries: 0,
+        }
+    }
+
+    fn entry(
+        &mut self,
+        path: &str,
+        kind: EntryKind,
+        data: &[u8],
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let (mode, external): (u32, u32) = match kind {
+            // The MS-DOS directory attribute
+            EntryKind::Directory => (0o40775, 0x10),
+            EntryKind::File { executable } => (if executable { 0o100775 } else { 0o100664 }, 0),
+            EntryKind::Symlink => (0o120777, 0),
+        };
+        let mut crc = flate2::Crc::new();
+        crc.update(data);
+        let deflate = matches!(kind, EntryKind::File { .. })
+            && !data.is_empty()
+            && self.level != Compression::none();
+        let (method, version, content) = if deflate {
+            let mut encoder = DeflateEncoder::new(Vec::new(), self.level);
+            encoder.write_all(data)?;
+            (8u16, 20u16, encoder.finish()?)
+        } else {
+            (0, 10, data.to_vec())
+        };
+        let too_large = || ProtocolError::invalid_request("Archive too large for a zip");
+        let compressed = u32::try_from(content.len()).map_err(|_| too_large())?;
+        let size = u32::try_from(data.len()).map_err(|_| too_large())?;
+        let offset = u32::try_from(self.offset).map_err(|_| too_large())?;
+        // UTF-8 names
+        let flags: u16 = if path.is_ascii() { 0 } else { 1 << 11 };
+        let mut extra = Vec::with_capacity(9);
+        extra.extend(ZIP_EXTENDED_TIMESTAMP.to_le_bytes());
+        extra.extend(5u16.to_le_bytes());
+        extra.push(1);
+        extra.extend(self.mtime.to_le_bytes());
+
+        let mut common = Vec::with_capacity(26);
+        common.extend(version.to_le_bytes());
+        common.extend(flags.to_le_bytes());
+        common.extend(method.to_le_bytes());
+        common.extend(self.dos_time.to_le_bytes());
+        common.extend(self.dos_date.to_le_bytes());
+        common.extend(crc.sum().to_le_bytes());
+        common.extend(compressed.to_le_bytes());
+        common.extend(size.to_le_bytes());
+        common.extend((path.len() as u16).to_le_bytes());
+        common.extend((extra.len() as u16).to_le_bytes());
+
+        let mut local = Vec::with_capacity(30 + path.len() + extra.len() + content.len());
+        local.extend(0x04034b50u32.to_le_bytes());
+        local.extend(&common);
+        local.extend(path.as_bytes());
+        local.extend(&extra);
+        local.extend(&content);
+
+        let central = &mut self.central_directory;
+        central.extend(0x02014b50u32.to_le_bytes());
+        central.extend(ZIP_CREATOR_VERSION.to_le_bytes());
+        central.extend(&common);
+        // No comment, first disk, no internal attributes
+        central.extend([0; 6]);
+        central.extend(((mode << 16) | external).to_le_bytes());
+        central.extend(offset.to_le_bytes());
+        central.extend(path.as_bytes());
+        central.extend(&extra);
+
+        self.offset += local.len();
+        self.entries += 1;
+        Ok(local)
+    }
+
+    /// The central directory and its end record, holding the commit id as comment.
+    fn finish(&mut self) -> Result<Vec<u8>, ProtocolError> {
+        let too_large = || ProtocolError::invalid_request("Archive too large for a zip");
+        let entries = u16::try_from(self.entries).map_err(|_| too_large())?;
+        let size = u32::try_from(self.central_directory.len()).map_err(|_| too_large())?;
+        let offset = u32::try_from(self.offset).map_err(|_| too_large())?;
+        let comment = self.commit.map(|id| id.to_string()).unwrap_or_default();
+        let mut out = std::mem::take(&mut self.central_directory);
+        out.extend(0x06054b50u32.to_le_bytes());
+        out.extend([0; 4]);
+        out.extend(entries.to_le_bytes());
+        out.extend(entries.to_le_bytes());
+        out.extend(size.to_le_bytes());
+        out.extend(offset.to_le_bytes());
+        out.extend((comment.len() as u16).to_le_bytes());
+        out.extend(comment.as_bytes());
+        Ok(out)
+    }
+}
+
+/// The MS-DOS time and date of `time`, from 1980 on.
+fn dos_date_time(time: &chrono::DateTime<chrono::Utc>) -> (u16, u16) {
+    use chrono::{Datelike, Timelike};
+    if time.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let dos_time = (time.hour() << 11) | (time.minute() << 5) | (time.second() / 2);
+    let year = ((time.year() - 1980) as u32).min(127);
+    let dos_date = (year << 9) | (time.month() << 5) | time.day();
+    (dos_time as u16, dos_date as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::object::blob::Blob;
+    use crate::internal::object::signature::{Signature, SignatureType};
+    use crate::protocol::memory::MemoryRepository;
+    use flate2::read::{DeflateDecoder, GzDecoder};
+    use futures::TryStreamExt;
+    use std::io::Read;
+
+    /// A commit of `README`, the executable `bin/run`, the symlink `link` and the submodule
+    /// `vendor`.
+    fn seeded() -> (MemoryRepository, Commit) {
+        let repo = MemoryRepository::new();
+        let readme = Blob::from_content("hello\n");
+        let run = Blob::from_content("#!/bin/sh\n");
+        let target = Blob::from_content("README");
+        let bin = Tree::from_tree_items(vec![TreeItem::new(
+            TreeItemMode::BlobExecutable,
+            run.id,
+            "run".to_string(),
+        )])
+        .unwrap();
+        let tree = Tree::from_tree_items(vec![
+            TreeItem::new(TreeItemMode::Blob, readme.id, "README".to_string()),
+            TreeItem::new(TreeItemMode::Tree, bin.id, "bin".to_string()),
+            TreeItem::new(TreeItemMode::Link, target.id, "link".to_string()),
+            TreeItem::new(TreeItemMode::Commit, readme.id, "vendor".to_string()),
+        ])
+        .unwrap();
+        let mut committer = Signature::new(
+            SignatureType::Committer,
+            "t".to_string(),
+            "t@example.com".to_string(),
+        );
+        committer.timestamp = 1_700_000_000;
+        let mut author = committer.clone();
+        author.signature_type = SignatureType::Author;
+        let commit = Commit::new(author, committer, tree.id, vec![], "\ninit");
+        for blob in [&readme, &run, &target] {
+            repo.insert_object(blob).unwrap();
+        }
+        repo.insert_object(&bin).unwrap();
+        repo.insert_object(&tree).unwrap();
+        repo.insert_object(&commit).unwrap();
+        (repo, commit)
+    }
+
+    async fn collect(stream: ProtocolStream) -> Vec<u8> {
+        let chunks: Vec<Bytes> = stream.try_collect().await.unwrap();
+        chunks.concat()
+    }
+
+    /// The name, mode, type, mtime, link and content of each entry of a tar.
+    fn tar_entries(tar: &[u8]) -> Vec<(String, u32, u8, u64, String, Vec<u8>)> {
+        let field = |block: &[u8], at: usize, len: usize| {
+            let raw = &block[at..at + len];
+            let end = raw.iter().position(|&b| b == 0).unwrap_or(len);
+            String::from_utf8(raw[..end].to_vec()).unwrap()
+        };
+        let octal = |block: &[u8], at, len| u64::from_str_radix(&field(block, at, len), 8).unwrap();
+        let mut entries = Vec::new();
+        let mut at = 0;
+        while tar[at..at + BLOCK_SIZE].iter().any(|&b| b != 0) {
+            let block = &tar[at..at + BLOCK_SIZE];
+            let sum: u64 = block
+                .iter()
+                .enumerate()
+                .map(|(i, &b)| {
+                    if (148..156).contains(&i) {
+                        32
+                    } else {
+                        u64::from(b)
+                    }
+                })
+                .sum();
+            assert_eq!(octal(block, 148, 8), sum);
+            let size = octal(block, 124, 12) as usize;
+            let content = tar[at + BLOCK_SIZE..at + BLOCK_SIZE + size].to_vec();
+            entries.push((
+                field(block, 0, 100),
+                octal(block, 100, 8) as u32,
+                block[156],
+                octal(block, 136, 12),
+                field(block, 157, 100),
+                content,
+            ));
+            at += BLOCK_SIZE + size.next_multiple_of(BLOCK_SIZE);
+        }
+        assert_eq!(tar.len() % RECORD_SIZE, 0);
+        entries
+    }
+
+    #[tokio::test]
+    async fn test_tar_archive() {
+        let (repo, commit) = seeded();
+        let options = ArchiveOptions {
+            prefix: "p-1/".to_string(),
+            ..Default::default()
+        };
+        let tar = collect(archive(&repo, &commit.id, &options).await.unwrap()).await;
+        let entries = tar_entries(&tar);
+        let summary: Vec<(&str, u32, u8)> = entries
+            .iter()
+            .map(|(name, mode, typeflag, ..)| (name.as_str(), *mode, *typeflag))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("pax_global_header", 0o666, b'g'),
+                ("p-1/", 0o775, b'5'),
+                ("p-1/README", 0o664, b'0'),
+                ("p-1/bin/", 0o775, b'5'),
+                ("p-1/bin/run", 0o775, b'0'),
+                ("p-1/link", 0o777, b'2'),
+                ("p-1/vendor/", 0o775, b'5'),
+            ]
+        );
+        assert_eq!(
+            entries[0].5,
+            format!("52 comment={}\n", commit.id).into_bytes()
+        );
+        assert!(entries.iter().all(|entry| entry.3 == 1_700_000_000));
+        assert_eq!(entries[2].5, b"hello\n");
+        assert_eq!(entries[5].4, "README");
+
+        // A subtree of a tree, with a long name, gzipped
+        let long = format!("{}/", "d".repeat(120));
+        let options = ArchiveOptions {
+            format: ArchiveFormat::TarGz,
+            prefix: long.clone(),
+            tree_path: Some("bin".to_string()),
+            ..Default::default()
+        };
+        let gz = collect(archive(&repo, &commit.tree_id, &options).await.unwrap()).await;
+        let mut tar = Vec::new();
+        GzDecoder::new(&gz[..]).read_to_end(&mut tar).unwrap();
+        let entries = tar_entries(&tar);
+        assert_eq!(entries[0].2, b'x');
+        assert_eq!(entries[0].5, pax_record("path", long.as_bytes()));
+        assert_eq!(entries.last().unwrap().5, b"#!/bin/sh\n");
+        assert_eq!(entries.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_zip_archive() {
+        let (repo, commit) = seeded();
+        let options = ArchiveOptions {
+            format: ArchiveFormat::Zip,
+            paths: vec!["bin/run".to_string(), "README".to_string()],
+            ..Default::default()
+        };
+        let zip = collect(archive(&repo, &commit.id, &options).await.unwrap()).await;
+        let u16_at = |at: usize| u16::from_le_bytes(zip[at..at + 2].try_into().unwrap());
+        let u32_at = |at: usize| u32::from_le_bytes(zip[at..at + 4].try_into().unwrap());
+
+        // The end record, then the central directory it points to
+        let comment = commit.id.to_string();
+        let end = zip.len() - 22 - comment.len();
+        assert_eq!(u32_at(end), 0x06054b50);
+        assert!(zip.ends_with(comment.as_bytes()));
+        let count = u16_at(end + 10);
+        let mut at = u32_at(end + 16) as usize;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            assert_eq!(u32_at(at), 0x02014b50);
+            let name_len = u16_at(at + 28) as usize;
+            let extra_len = u16_at(at + 30) as usize;
+            let name = String::from_utf8(zip[at + 46..at + 46 + name_len].to_vec()).unwrap();
+            let mode = u32_at(at + 38) >> 16;
+
+            // The content of its local header
+            let local = u32_at(at + 42) as usize;
+            assert_eq!(u32_at(local), 0x04034b50);
+            let start = local + 30 + u16_at(local + 26) as usize + u16_at(local + 28) as usize;
+            let data = &zip[start..start + u32_at(local + 18) as usize];
+            let mut content = Vec::new();
+            match u16_at(local + 8) {
+                8 => DeflateDecoder::new(data).read_to_end(&mut content).unwrap(),
+                _ => content.write(data).unwrap(),
+            };
+            let mut crc = flate2::Crc::new();
+            crc.update(&content);
+            assert_eq!(crc.sum(), u32_at(local + 14));
+            entries.push((name, mode, content));
+            at += 46 + name_len + extra_len;
+        }
+        assert_eq!(
+            entries,
+            [
+                ("README".to_string(), 0o100664, b"hello\n".to_vec()),
+                ("bin/".to_string(), 0o40775, vec![]),
+                ("bin/run".to_string(), 0o100775, b"#!/bin/sh\n".to_vec()),
+            ]
+        );
+
+        let options = ArchiveOptions {
+            paths: vec!["missing".to_string()],
+            ..Default::default()
+        };
+        let err = archive(&repo, &commit.id, &options).await.err().unwrap();
+        assert!(err.to_string().contains("pathspec 'missing'"), "{err}");
+        let readme = Blob::from_content("hello\n").id;
+        assert!(
+            archive(&repo, &readme, &ArchiveOptions::default())
+                .await
+                .is_err()
+        );
+    }
+}
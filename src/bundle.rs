@@ -0,0 +1,502 @@
+//! Git bundles, the files of `git bundle create` and `git bundle unbundle`.
+//!
+//! A bundle is a header followed by a pack: the signature line `# v2 git bundle` or
+//! `# v3 git bundle`, the `@<key>=<value>` capabilities of a version 3 bundle, a
+//! `-<id> <subject>` line per prerequisite commit the receiving repository must already have,
+//! a `<id> <ref>` line per bundled ref, and an empty line. [`create_bundle`] streams one from a
+//! [`RepositoryAccess`], its pack generated by [`PackGenerator`], and [`unbundle`] reads one
+//! into a repository after checking its prerequisites, so backups and transfers to air-gapped
+//! repositories don't need a connection to the source.
+use std::collections::HashSet;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+
+use crate::hash::SHA1;
+use crate::internal::object::ObjectTrait;
+use crate::internal::object::tag::Tag;
+use crate::internal::object::types::ObjectType;
+use crate::protocol::RepositoryAccess;
+use crate::protocol::pack::{PackGenerator, load_object, store_objects};
+use crate::protocol::types::{ProtocolError, ProtocolStream};
+use crate::revwalk::CommitWalker;
+
+/// How many tags of tags are peeled before giving up, like git.
+const MAX_PEEL_DEPTH: usize = 32;
+
+/// The version of a bundle, named by its signature line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BundleVersion {
+    #[default]
+    V2,
+    /// Adds capabilities to the header, for other object formats and partial bundles
+    V3,
+}
+
+impl BundleVersion {
+    /// The signature line, without its newline.
+    pub fn signature(&self) -> &'static str {
+        match self {
+            BundleVersion::V2 => "# v2 git bundle",
+            BundleVersion::V3 => "# v3 git bundle",
+        }
+    }
+}
+
+impl Display for BundleVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BundleVersion::V2 => write!(f, "2"),
+            BundleVersion::V3 => write!(f, "3"),
+        }
+    }
+}
+
+/// A prerequisite of a bundle, a commit whose history is left out of its pack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prerequisite {
+    pub id: SHA1,
+    /// The subject of the commit, for people reading the header
+    pub comment: String,
+}
+
+/// The header of a bundle, see the [module documentation](self).
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BundleHeader {
+    pub version: BundleVersion,
+    /// The `@filter=` capability of a partial bundle, whose pack misses the objects filtered
+    /// out, e.g. `blob:none`
+    pub filter: Option<String>,
+    pub prerequisites: Vec<Prerequisite>,
+    /// The bundled refs and the objects they point to, in header order
+    pub references: Vec<(String, SHA1)>,
+}
+
+impl BundleHeader {
+    /// Parse a header up to its empty line, returning it and the length it takes, where the
+    /// pack starts.
+    pub fn parse(data: &[u8]) -> Result<(Self, usize), ProtocolError> {
+        let end = data
+            .windows(2)
+            .position(|w| w == b"\n\n")
+            .ok_or_else(|| invalid("missing the end of the header"))?;
+        let text =
+            std::str::from_utf8(&data[..end]).map_err(|_| invalid("the header is not UTF-8"))?;
+        let mut lines = text.split('\n');
+        let version = match lines.next() {
+            Some(line) if line == BundleVersion::V2.signature() => BundleVersion::V2,
+            Some(line) if line == BundleVersion::V3.signature() => BundleVersion::V3,
+            _ => return Err(invalid("unknown signature")),
+        };
+        let mut header = Self {
+            version,
+            ..Default::default()
+        };
+        let parse_id = |hex: &str| {
+            SHA1::from_str(hex).map_err(|_| invalid(&format!("invalid object id '{hex}'")))
+        };
+        let mut capabilities_done = version == BundleVersion::V2;
+        for line in lines {
+            if !capabilities_done && let Some(capability) = line.strip_prefix('@') {
+                let (key, value) = capability.split_once('=').unwrap_or((capability, ""));
+                match key {
+                    "object-format" if value == "sha1" => {}
+                    "object-format" => {
+                        return Err(ProtocolError::UnsupportedObjectFormat(value.to_string()));
+                    }
+                    "filter" => header.filter = Some(value.to_string()),
+                    _ => return Err(invalid(&format!("unknown capability '{capability}'"))),
+                }
+                continue;
+            }
+            capabilities_done = true;
+            if let Some(prerequisite) = line.strip_prefix('-') {
+                let (hex, comment) = prerequisite.split_once(' ').unwrap_or((prerequisite, ""));
+                header.prerequisites.push(Prerequisite {
+                    id: parse_id(hex)?,
+                    comment: comment.to_string(),
+                });
+            } else {
+                let (hex, name) = line
+                    .split_once(' ')
+                    .ok_or_else(|| invalid(&format!("invalid ref line '{line}'")))?;
+                header.references.push((name.to_string(), parse_id(hex)?));
+            }
+        }
+        Ok((header, end + 2))
+    }
+
+    /// The header, ending with its empty line.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut header = format!("{}\n", self.version.signature());
+        if self.version == BundleVersion::V3 {
+            header.push_str("@object-format=sha1\n");
+            if let Some(filter) = &self.filter {
+                header.push_str(&format!("@filter={filter}\n"));
+            }
+        }
+        for prerequisite in &self.prerequisites {
+            header.push_str(&format!("-{} {}\n", prerequisite.id, prerequisite.comment));
+        }
+        for (name, id) in &self.references {
+            header.push_str(&format!("{id} {name}\n"));
+        }
+        header.push('\n');
+        header.into_bytes()
+    }
+
+    /// The objects the refs of the bundle point to, which the receiving repository has once it
+    /// is unbundled: the tips to exclude from the next bundle of a chain.
+    pub fn tips(&self) -> Vec<SHA1> {
+        self.references.iter().map(|(_, id)| *id).collect()
+    }
+
+    /// Check that `repo` has the prerequisites of the bundle, like `git bundle verify`.
+    pub async fn verify<R: RepositoryAccess>(&self, repo: &R) -> Result<(), ProtocolError> {
+        let mut missing = Vec::new();
+        for prerequisite in &self.prerequisites {
+            if !repo.has_object(&prerequisite.id.to_string()).await? {
+                missing.push(format!("{} {}", prerequisite.id, prerequisite.comment));
+            }
+        }
+        if !missing.is_empty() {
+            return Err(ProtocolError::invalid_request(&format!(
+                "Repository lacks these prerequisite commits: {}",
+                missing.join(", ")
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn invalid(msg: &str) -> ProtocolError {
+    ProtocolError::invalid_request(&format!("Invalid bundle: {msg}"))
+}
+
+/// Stream a bundle of `references`, the history of the objects they point to without that of
+/// `exclude`, like `git bundle create <file> <refs> ^<exclude>`.
+///
+/// With `exclude`, the bundle is incremental: its pack is generated by
+/// [`PackGenerator::generate_incremental_pack`] and its prerequisites are the excluded commits
+/// the bundled history starts from, those the receiving repository must have. Backups chain
+/// them by excluding the [`tips`](BundleHeader::tips) of the previous bundle. Excluded tips
+/// the repository doesn't have, e.g. of a branch deleted and pruned since, are skipped, and
+/// like git an incremental bundle with nothing new since its excluded tips is refused.
+pub async fn create_bundle<R: RepositoryAccess>(
+    repo: &R,
+    references: &[(String, SHA1)],
+    exclude: &[SHA1],
+    version: BundleVersion,
+) -> Result<ProtocolStream, ProtocolError> {
+    if references.is_empty() {
+        return Err(ProtocolError::invalid_request(
+            "Refusing to create empty bundle",
+        ));
+    }
+    let mut excluded = Vec::new();
+    let mut hidden = Vec::new();
+    for id in exclude {
+        match peel_to_commit(repo, *id).await {
+            Ok(commit) => {
+                excluded.push(id.to_string());
+                hidden.push(commit);
+            }
+            Err(ProtocolError::ObjectNotFound(_)) => {
+                tracing::debug!("Skipping missing prerequisite tip {id}");
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    let mut prerequisites = Vec::new();
+    if !hidden.is_empty() {
+        let mut walker = CommitWalker::new(repo);
+        for (_, id) in references {
+            walker = walker.push(peel_to_commit(repo, *id).await?);
+        }
+        for commit in hidden {
+            walker = walker.hide(commit);
+        }
+        let commits = walker.collect().await?;
+        if commits.is_empty() && references.iter().all(|(_, id)| exclude.contains(id)) {
+            return Err(ProtocolError::invalid_request(
+                "Refusing to create empty bundle",
+            ));
+        }
+        let included: HashSet<SHA1> = commits.iter().map(|c| c.id).collect();
+        for commit in &commits {
+            for parent in &commit.parent_commit_ids {
+                if !included.contains(parent)
+                    && !prerequisites.iter().any(|p: &Prerequisite| p.id == *parent)
+                {
+                    let comment = repo.get_commit(&parent.to_string()).await?.format_message();
+                    prerequisites.push(Prerequisite {
+                        id: *parent,
+                        comment,
+                    });
+                }
+            }
+        }
+    }
+    let header = BundleHeader {
+        version,
+        filter: None,
+        prerequisites,
+        references: references.to_vec(),
+    };
+
+    let generator = PackGenerator::new(repo);
+    let want: Vec<String> = references.iter().map(|(_, id)| id.to_string()).collect();
+    let pack = match excluded.is_empty() {
+        true => generator.generate_full_pack(want).await?,
+        false => generator.generate_incremental_pack(want, excluded).await?,
+    };
+    let head = futures::stream::once(async move { Ok(Bytes::from(header.to_bytes())) });
+    Ok(Box::pin(
+        head.chain(pack.map(|chunk| Ok(Bytes::from(chunk)))),
+    ))
+}
+
+/// Read the header of the bundle `stream`, returning it and the stream of its pack.
+pub async fn read_bundle(
+    mut stream: ProtocolStream,
+) -> Result<(BundleHeader, ProtocolStream), ProtocolError> {
+    let mut buffered = BytesMut::new();
+    loop {
+        if buffered.windows(2).any(|w| w == b"\n\n") {
+            let (header, len) = BundleHeader::parse(&buffered)?;
+            let pack = buffered.split_off(len).freeze();
+            let head = futures::stream::once(async move { Ok(pack) });
+            return Ok((header, Box::pin(head.chain(stream))));
+        }
+        match stream.next().await.transpose()? {
+            Some(chunk) => buffered.extend_from_slice(&chunk),
+            None => return Err(invalid("missing the end of the header")),
+        }
+    }
+}
+
+/// Store the objects of the bundle `stream` in `repo`, like `git bundle unbundle`, returning
+/// its header.
+///
+/// The prerequisites are checked first, and the refs are left to the caller to update from
+/// [`BundleHeader::references`], e.g. through a fetch refspec.
+pub async fn unbundle<R: RepositoryAccess>(
+    repo: &R,
+    stream: ProtocolStream,
+) -> Result<BundleHeader, ProtocolError> {
+    let (header, pack) = read_bundle(stream).await?;
+    header.verify(repo).await?;
+    PackGenerator::new(repo)
+        .unpack_received_in_batches(pack, false, |objects| store_objects(repo, objects))
+        .await?;
+    for (name, id) in &header.references {
+        if !repo.has_object(&id.to_string()).await? {
+            return Err(invalid(&format!("{name} points to missing object {id}")));
+        }
+    }
+    Ok(header)
+}
+
+/// The commit `id` names, through annotated tags.
+async fn peel_to_commit<R: RepositoryAccess>(
+    repo: &R,
+    mut id: SHA1,
+) -> Result<SHA1, ProtocolError> {
+    for _ in 0..MAX_PEEL_DEPTH {
+        match load_object(repo, &id).await? {
+            Some((ObjectType::Commit, _)) => return Ok(id),
+            Some((ObjectType::Tag, data)) => {
+                let tag =
+                    Tag::from_bytes(&data, id).map_err(|e| ProtocolError::corrupt_object(id, e))?;
+                id = tag.object_hash;
+            }
+            Some(_) => {
+                return Err(ProtocolError::invalid_request(&format!(
+                    "{id} is not a commit"
+                )));
+            }
+            None => return Err(ProtocolError::ObjectNotFound(id.to_string())),
+        }
+    }
+    Err(ProtocolError::invalid_request(&format!(
+        "Too many tags of tags at {id}"
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::object::blob::Blob;
+    use crate::internal::object::commit::Commit;
+    use crate::internal::object::signature::{Signature, SignatureType};
+    use crate::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+    use crate::protocol::memory::MemoryRepository;
+
+    /// Store a commit of `content` in `file.txt` on top of `parents`.
+    fn commit(repo: &MemoryRepository, content: &str, parents: Vec<SHA1>, message: &str) -> Commit {
+        let blob = Blob::from_content(content);
+        let item = TreeItem::new(TreeItemMode::Blob, blob.id, "file.txt".to_string());
+        let tree = Tree::from_tree_items(vec![item]).unwrap();
+        let signature = |kind| Signature::new(kind, "t".to_string(), "t@example.com".to_string());
+        let commit = Commit::new(
+            signature(SignatureType::Author),
+            signature(SignatureType::Committer),
+            tree.id,
+            parents,
+            &format!("\n{message}"),
+        );
+        repo.insert_object(&blob).unwrap();
+        repo.insert_object(&tree).unwrap();
+        repo.insert_object(&commit).unwrap();
+        commit
+    }
+
+    async fn collect(stream: ProtocolStream) -> Vec<u8> {
+        let chunks: Vec<Bytes> = stream.map(Result::unwrap).collect().await;
+        chunks.concat()
+    }
+
+    fn stream(data: Vec<u8>) -> ProtocolStream {
+        // Split, so the header spans several chunks
+        let chunks: Vec<Result<Bytes, ProtocolError>> = data
+            .chunks(7)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        Box::pin(futures::stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn test_incremental_bundles() {
+        let source = MemoryRepository::new();
+        let first = commit(&source, "one", vec![], "first");
+        let main = |id| vec![("refs/heads/main".to_string(), id)];
+        let backup = |references: Vec<(String, SHA1)>, exclude: Vec<SHA1>| {
+            let source = source.clone();
+            async move {
+                let bundle = create_bundle(&source, &references, &exclude, BundleVersion::V2);
+                Ok::<_, ProtocolError>(collect(bundle.await?).await)
+            }
+        };
+
+        // A chain of backups, each excluding the tips of the previous one
+        let full = backup(main(first.id), vec![]).await.unwrap();
+        let second = commit(&source, "two", vec![first.id], "second");
+        let (previous, _) = BundleHeader::parse(&full).unwrap();
+        let increment = backup(main(second.id), previous.tips()).await.unwrap();
+        let (header, len) = BundleHeader::parse(&increment).unwrap();
+        assert_eq!(header.prerequisites[0].id, first.id);
+        let (commits, trees, blobs, _) = PackGenerator::new(&source)
+            .unpack_stream(Bytes::copy_from_slice(&increment[len..]))
+            .await
+            .unwrap();
+        assert_eq!((commits.len(), trees.len(), blobs.len()), (1, 1, 1));
+        assert_eq!(commits[0].id, second.id);
+
+        // Missing tips are skipped, nothing new is refused
+        let gone = SHA1::from_str(&"2".repeat(40)).unwrap();
+        let err = backup(main(second.id), vec![second.id, gone])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("empty bundle"), "{err}");
+
+        let target = MemoryRepository::new();
+        unbundle(&target, stream(full)).await.unwrap();
+        let header = unbundle(&target, stream(increment)).await.unwrap();
+        assert_eq!(header.references, main(second.id));
+        assert!(target.has_object(&second.id.to_string()).await.unwrap());
+    }
+
+    #[test]
+    fn test_bundle_header() {
+        let id = SHA1::from_str(&"1".repeat(40)).unwrap();
+        let data = format!(
+            "# v3 git bundle\n@object-format=sha1\n@filter=blob:none\n-{id} first\n{id} refs/heads/main\n\nPACK"
+        );
+        let (header, len) = BundleHeader::parse(data.as_bytes()).unwrap();
+        assert_eq!(&data[len..], "PACK");
+        assert_eq!(header.version, BundleVersion::V3);
+        assert_eq!(header.filter.as_deref(), Some("blob:none"));
+        assert_eq!(
+            header.prerequisites,
+            [Prerequisite {
+                id,
+                comment: "first".to_string()
+            }]
+        );
+        assert_eq!(header.references, [("refs/heads/main".to_string(), id)]);
+        assert_eq!(header.to_bytes(), &data.as_bytes()[..len]);
+
+        for data in [
+            "# v4 git bundle\n\n".to_string(),
+            "# v3 git bundle\n@object-format=sha256\n\n".to_string(),
+            "# v3 git bundle\n@compression=zstd\n\n".to_string(),
+            format!("# v2 git bundle\n@filter=blob:none\n{id} refs/heads/main\n\n"),
+            format!("# v2 git bundle\n{id} refs/heads/main\n"),
+        ] {
+            assert!(BundleHeader::parse(data.as_bytes()).is_err(), "{data}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_and_unbundle() {
+        let source = MemoryRepository::new();
+        let first = commit(&source, "one", vec![], "first");
+        let second = commit(&source, "two", vec![first.id], "second");
+        let main = vec![("refs/heads/main".to_string(), second.id)];
+
+        // A full bundle restores the whole history
+        let full = create_bundle(&source, &main, &[], BundleVersion::V2)
+            .await
+            .unwrap();
+        let full = collect(full).await;
+        assert!(full.starts_with(
+            format!("# v2 git bundle\n{} refs/heads/main\n\nPACK", second.id).as_bytes()
+        ));
+        let target = MemoryRepository::new();
+        let header = unbundle(&target, stream(full)).await.unwrap();
+        assert_eq!(header.references, main);
+        for id in [first.id, second.id, first.tree_id] {
+            assert!(target.has_object(&id.to_string()).await.unwrap());
+        }
+
+        // An incremental one needs its prerequisites
+        let incremental = create_bundle(&source, &main, &[first.id], BundleVersion::V3)
+            .await
+            .unwrap();
+        let incremental = collect(incremental).await;
+        let (header, _) = BundleHeader::parse(&incremental).unwrap();
+        assert_eq!(
+            header.prerequisites,
+            [Prerequisite {
+                id: first.id,
+                comment: "first".to_string()
+            }]
+        );
+        let empty = MemoryRepository::new();
+        let err = unbundle(&empty, stream(incremental.clone()))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("lacks these prerequisite commits"),
+            "{err}"
+        );
+        assert!(!empty.has_object(&second.id.to_string()).await.unwrap());
+
+        let target = MemoryRepository::new();
+        let base = vec![("refs/heads/main".to_string(), first.id)];
+        let base = create_bundle(&source, &base, &[], BundleVersion::V2);
+        unbundle(&target, stream(collect(base.await.unwrap()).await))
+            .await
+            .unwrap();
+        unbundle(&target, stream(incremental)).await.unwrap();
+        assert!(target.has_object(&second.id.to_string()).await.unwrap());
+
+        assert!(
+            create_bundle(&source, &[], &[], BundleVersion::V2)
+                .await
+                .is_err()
+        );
+    }
+}
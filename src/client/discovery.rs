@@ -0,0 +1,325 @@
+//! Ref discovery: the advertisement a service opens with, and `ls-refs` in protocol v2.
+//!
+//! In protocol v0 and v1 the advertisement lists the refs of the remote, the capabilities of
+//! the service following the first one after a NUL. In protocol v2 it lists the capabilities
+//! and commands of the service only, and [`discover`] asks for the refs with `ls-refs`.
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::StreamExt;
+
+use super::transport::Transport;
+use super::url::RemoteUrl;
+use crate::hash::SHA1;
+use crate::protocol::types::{
+    DEFAULT_AGENT, PKT_LINE_DELIM_MARKER, PKT_LINE_END_MARKER, ProtocolError, ProtocolStream,
+    ProtocolVersion, ServiceType,
+};
+use crate::protocol::utils::{PktLine, add_pkt_line_string, parse_pkt_line};
+
+/// A ref of a remote repository
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteRef {
+    pub name: String,
+    pub id: SHA1,
+    /// The object an annotated tag points to
+    pub peeled: Option<SHA1>,
+    /// The ref a symbolic ref points to, e.g. the default branch for `HEAD`
+    pub symref_target: Option<String>,
+}
+
+/// The refs and capabilities of a service of a remote repository
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Advertisement {
+    /// The protocol version the service answered with
+    pub version: ProtocolVersion,
+    pub refs: Vec<RemoteRef>,
+    /// The capabilities, e.g. `ofs-delta` or `agent=git/2.43.0`; in protocol v2, the
+    /// commands as well, e.g. `fetch=shallow wait-for-done`
+    pub capabilities: Vec<String>,
+}
+
+impl Advertisement {
+    /// Parse the advertisement a service opens with, see the [module documentation](self).
+    pub fn parse(advertisement: &Bytes) -> Result<Self, ProtocolError> {
+        let mut parsed = Advertisement::default();
+        let mut lines = pkt_line_strings(advertisement)?.into_iter().peekable();
+        match lines.peek().map(String::as_str) {
+            Some("version 2") => {
+                lines.next();
+                parsed.version = ProtocolVersion::V2;
+                parsed.capabilities = lines.collect();
+                return Ok(parsed);
+            }
+            Some("version 1") => {
+                lines.next();
+                parsed.version = ProtocolVersion::V1;
+            }
+            _ => {}
+        }
+        for (i, line) in lines.enumerate() {
+            if let Some(message) = line.strip_prefix("ERR ") {
+                return Err(ProtocolError::Remote(message.to_string()));
+            }
+            // The refs a shallow repository is cut at
+            if line.starts_with("shallow ") {
+                continue;
+            }
+            let line = if i == 0 {
+                let (line, capabilities) = line.split_once('\0').unwrap_or((&line, ""));
+                parsed.capabilities = capabilities.split_whitespace().map(String::from).collect();
+                line.to_string()
+            } else {
+                line
+            };
+            let (id, name) = line
+                .split_once(' ')
+                .ok_or_else(|| invalid_line("ref", &line))?;
+            let id: SHA1 = id.parse().map_err(|_| invalid_line("ref", &line))?;
+            // The only line of a repository without refs
+            if name == "capabilities^{}" {
+                continue;
+            }
+            if let Some(name) = name.strip_suffix("^{}") {
+                match parsed.refs.last_mut() {
+                    Some(tag) if tag.name == name => tag.peeled = Some(id),
+                    _ => return Err(invalid_line("peeled ref", &line)),
+                }
+                continue;
+            }
+            parsed.refs.push(RemoteRef {
+                name: name.to_string(),
+                id,
+                peeled: None,
+                symref_target: None,
+            });
+        }
+        let symrefs: Vec<(String, String)> = parsed
+            .capabilities
+            .iter()
+            .filter_map(|capability| capability.strip_prefix("symref="))
+            .filter_map(|symref| symref.split_once(':'))
+            .map(|(name, target)| (name.to_string(), target.to_string()))
+            .collect();
+        for remote_ref in &mut parsed.refs {
+            remote_ref.symref_target = symrefs
+                .iter()
+                .find(|(name, _)| *name == remote_ref.name)
+                .map(|(_, target)| target.clone());
+        }
+        Ok(parsed)
+    }
+
+    /// The value of capability `name`, e.g. `Some("sha1")` for `object-format`, or `Some("")`
+    /// for a capability without value.
+    pub fn capability(&self, name: &str) -> Option<&str> {
+        self.capabilities
+            .iter()
+            .find_map(|capability| match capability.split_once('=') {
+                Some((key, value)) if key == name => Some(value),
+                None if capability == name => Some(""),
+                _ => None,
+            })
+    }
+
+    pub fn has_capability(&self, name: &str) -> bool {
+        self.capability(name).is_some()
+    }
+
+    /// The ref called `name`
+    pub fn find_ref(&self, name: &str) -> Option<&RemoteRef> {
+        self.refs.iter().find(|remote_ref| remote_ref.name == name)
+    }
+}
+
+/// Connect to `service` over `transport` and return its refs and capabilities.
+///
+/// In protocol v2, the refs are listed by `ls-refs`, limited to those starting with one of
+/// `ref_prefixes` unless it's empty; earlier versions advertise all refs. The version the
+/// server answers with may be older than the one asked for.
+pub async fn discover(
+    transport: &mut dyn Transport,
+    service: ServiceType,
+    version: ProtocolVersion,
+    ref_prefixes: &[String],
+) -> Result<Advertisement, ProtocolError> {
+    let advertisement = transport.connect(service, version).await?;
+    let mut advertisement = Advertisement::parse(&advertisement)?;
+    if advertisement.version != ProtocolVersion::V2 {
+        return Ok(advertisement);
+    }
+    if !advertisement.has_capability("ls-refs") {
+        return Err(ProtocolError::invalid_request(
+            "The remote doesn't support ls-refs",
+        ));
+    }
+    let mut arguments = vec!["peel".to_string(), "symrefs".to_string()];
+    arguments.extend(
+        ref_prefixes
+            .iter()
+            .map(|prefix| format!("ref-prefix {prefix}")),
+    );
+    let request = v2_request(&advertisement, "ls-refs", &arguments);
+    let response = read_response(transport.request(request).await?).await?;
+    for line in pkt_line_strings(&response)? {
+        let mut fields = line.split(' ');
+        let id = fields.next().unwrap_or_default();
+        let name = fields
+            .next()
+            .ok_or_else(|| invalid_line("ls-refs", &line))?;
+        let mut remote_ref = RemoteRef {
+            name: name.to_string(),
+            id: id.parse().map_err(|_| invalid_line("ls-refs", &line))?,
+            peeled: None,
+            symref_target: None,
+        };
+        for attribute in fields {
+            if let Some(target) = attribute.strip_prefix("symref-target:") {
+                remote_ref.symref_target = Some(target.to_string());
+            } else if let Some(peeled) = attribute.strip_prefix("peeled:") {
+                remote_ref.peeled =
+                    Some(peeled.parse().map_err(|_| invalid_line("ls-refs", &line))?);
+            }
+        }
+        advertisement.refs.push(remote_ref);
+    }
+    Ok(advertisement)
+}
+
+/// List the refs of the repository at `url` with their capabilities, like `git ls-remote`.
+///
+/// Only the refs are discovered, in protocol v2 when the server speaks it; `url` is any
+/// [`RemoteUrl`], reached with its default transport.
+pub async fn ls_remote(url: &str) -> Result<Advertisement, ProtocolError> {
+    let mut transport = RemoteUrl::parse(url)?.transport().await?;
+    let advertisement = discover(
+        transport.as_mut(),
+        ServiceType::UploadPack,
+        ProtocolVersion::V2,
+        &[],
+    )
+    .await?;
+    transport.close().await?;
+    Ok(advertisement)
+}
+
+/// A protocol v2 request for `command` with `arguments`, and the capabilities the client
+/// sends with every command.
+pub(crate) fn v2_request(
+    advertisement: &Advertisement,
+    command: &str,
+    arguments: &[String],
+) -> Bytes {
+    let mut request = BytesMut::new();
+    add_pkt_line_string(&mut request, format!("command={command}\n"));
+    add_pkt_line_string(&mut request, format!("agent={DEFAULT_AGENT}\n"));
+    if let Some(object_format) = advertisement.capability("object-format") {
+        add_pkt_line_string(&mut request, format!("object-format={object_format}\n"));
+    }
+    request.put(&PKT_LINE_DELIM_MARKER[..]);
+    for argument in arguments {
+        add_pkt_line_string(&mut request, format!("{argument}\n"));
+    }
+    request.put(&PKT_LINE_END_MARKER[..]);
+    request.freeze()
+}
+
+/// All of `response`, for the responses small enough to be read at once
+pub(crate) async fn read_response(mut response: ProtocolStream) -> Result<Bytes, ProtocolError> {
+    let mut data = BytesMut::new();
+    while let Some(chunk) = response.next().await {
+        data.extend_from_slice(&chunk?);
+    }
+    Ok(data.freeze())
+}
+
+/// The data lines of `pkt_lines`, without their line feed
+pub(crate) fn pkt_line_strings(pkt_lines: &Bytes) -> Result<Vec<String>, ProtocolError> {
+    let mut pkt_lines = pkt_lines.clone();
+    let mut lines = Vec::new();
+    while let Some(pkt_line) = parse_pkt_line(&mut pkt_lines)? {
+        if let PktLine::Data(data) = pkt_line {
+            let line = String::from_utf8_lossy(&data);
+            lines.push(line.strip_suffix('\n').unwrap_or(&line).to_string());
+        }
+    }
+    Ok(lines)
+}
+
+fn invalid_line(kind: &str, line: &str) -> ProtocolError {
+    ProtocolError::invalid_request(&format!("Invalid {kind} line '{line}'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pkt_lines(lines: &[&str]) -> Bytes {
+        let mut pkt_lines = BytesMut::new();
+        for line in lines {
+            add_pkt_line_string(&mut pkt_lines, line.to_string());
+        }
+        pkt_lines.put(&PKT_LINE_END_MARKER[..]);
+        pkt_lines.freeze()
+    }
+
+    #[test]
+    fn test_parse_advertisement() {
+        let head = "1".repeat(40);
+        let tag = "2".repeat(40);
+        let advertisement = Advertisement::parse(&pkt_lines(&[
+            "version 1\n",
+            &format!("{head} HEAD\0ofs-delta symref=HEAD:refs/heads/main agent=git/2.43.0\n"),
+            &format!("{head} refs/heads/main\n"),
+            &format!("{tag} refs/tags/v1\n"),
+            &format!("{head} refs/tags/v1^{{}}\n"),
+        ]))
+        .unwrap();
+        assert_eq!(advertisement.version, ProtocolVersion::V1);
+        assert_eq!(advertisement.capability("agent"), Some("git/2.43.0"));
+        assert!(advertisement.has_capability("ofs-delta"));
+        assert!(!advertisement.has_capability("side-band"));
+        let head: SHA1 = head.parse().unwrap();
+        assert_eq!(
+            advertisement.refs,
+            vec![
+                RemoteRef {
+                    name: "HEAD".to_string(),
+                    id: head,
+                    peeled: None,
+                    symref_target: Some("refs/heads/main".to_string()),
+                },
+                RemoteRef {
+                    name: "refs/heads/main".to_string(),
+                    id: head,
+                    peeled: None,
+                    symref_target: None,
+                },
+                RemoteRef {
+                    name: "refs/tags/v1".to_string(),
+                    id: tag.parse().unwrap(),
+                    peeled: Some(head),
+                    symref_target: None,
+                },
+            ]
+        );
+
+        let empty = Advertisement::parse(&pkt_lines(&[&format!(
+            "{} capabilities^{{}}\0report-status\n",
+            "0".repeat(40)
+        )]))
+        .unwrap();
+        assert!(empty.refs.is_empty());
+        assert_eq!(empty.capabilities, vec!["report-status"]);
+
+        let v2 = Advertisement::parse(&pkt_lines(&[
+            "version 2\n",
+            "ls-refs=unborn\n",
+            "fetch=shallow\n",
+        ]))
+        .unwrap();
+        assert_eq!(v2.version, ProtocolVersion::V2);
+        assert_eq!(v2.capability("fetch"), Some("shallow"));
+
+        let error = Advertisement::parse(&pkt_lines(&["ERR access denied\n"])).unwrap_err();
+        assert!(matches!(error, ProtocolError::Remote(message) if message == "access denied"));
+    }
+}
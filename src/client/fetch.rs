@@ -0,0 +1,900 @@
+//! The fetching side of upload-pack, like `git fetch`.
+//!
+//! [`fetch`] discovers the refs of the remote, maps them to local refs with refspecs, and asks
+//! for the objects of the refs it misses: one `want` per missing tip, and a `have` per recent
+//! local commit so the remote leaves out what the repository already holds. The haves are
+//! sent in rounds of growing size until the remote is ready to send the pack, in protocol v2
+//! and with `multi_ack_detailed` in v0; other v0 remotes get them all at once. The pack the
+//! remote answers with is indexed and stored through `RepositoryAccess`, and the local refs
+//! are only updated once every object reachable from the wanted tips is there. Packs are
+//! received in a side-band and never thin, so they can be indexed on their own.
+use std::collections::{HashMap, HashSet};
+
+use bytes::{BufMut, BytesMut};
+use futures::StreamExt;
+
+use super::discovery::{Advertisement, discover, v2_request};
+use super::transport::{Transport, side_band_data, unread};
+use crate::errors::GitError;
+use crate::hash::SHA1;
+use crate::internal::object::ObjectTrait;
+use crate::internal::object::commit::Commit;
+use crate::internal::object::tag::Tag;
+use crate::internal::object::tree::{Tree, TreeItemMode};
+use crate::internal::object::types::ObjectType;
+use crate::protocol::RepositoryAccess;
+use crate::protocol::codec::PktLineReader;
+use crate::protocol::pack::{PackGenerator, load_object, store_objects};
+use crate::protocol::types::{
+    DEFAULT_AGENT, PKT_LINE_END_MARKER, ProtocolError, ProtocolStream, ProtocolVersion, RefUpdate,
+    ServiceType,
+};
+use crate::protocol::utils::{PktLine, add_pkt_line_string};
+use crate::refspec::{RefMapping, Refspec, map_refs};
+use crate::revwalk::{CommitWalker, GenerationNumbers, is_ancestor};
+
+/// The number of haves of the first negotiation round, doubled every round after
+const INITIAL_ROUND_HAVES: usize = 16;
+
+/// How [`fetch`] fetches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchOptions {
+    /// The remote refs to fetch and the local refs they update, by default every branch to
+    /// `refs/remotes/origin/*`
+    pub refspecs: Vec<Refspec>,
+    /// The protocol version to ask for; servers only speaking older versions answer with those
+    pub protocol_version: ProtocolVersion,
+    /// The most local commits to tell the remote about
+    pub max_haves: usize,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self {
+            refspecs: vec![Refspec::parse("+refs/heads/*:refs/remotes/origin/*").unwrap()],
+            protocol_version: ProtocolVersion::V2,
+            max_haves: 256,
+        }
+    }
+}
+
+/// How [`fetch`] updated a local ref
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchStatus {
+    Created,
+    FastForward,
+    /// Updated although commits of its old value aren't in the new one
+    Forced,
+    UpToDate,
+    /// Left alone: the update isn't a fast-forward, or would move a tag, and isn't forced
+    Rejected,
+}
+
+/// A ref mapped by the refspecs of a [`fetch`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchedRef {
+    /// The remote ref
+    pub src: String,
+    /// The local ref
+    pub dst: String,
+    /// The value of the local ref before the fetch
+    pub old: Option<SHA1>,
+    /// The value of the remote ref
+    pub new: SHA1,
+    pub status: FetchStatus,
+}
+
+/// What [`fetch`] did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchResult {
+    pub advertisement: Advertisement,
+    pub refs: Vec<FetchedRef>,
+    /// The checksum of the received pack, `None` when every object was already there
+    pub pack: Option<SHA1>,
+}
+
+/// Fetch the refs `options` maps from the remote into `repo`, see the
+/// [module documentation](self).
+pub async fn fetch<R: RepositoryAccess>(
+    repo: &R,
+    transport: &mut dyn Transport,
+    options: &FetchOptions,
+) -> Result<FetchResult, ProtocolError> {
+    let ref_prefixes = ref_prefixes(&options.refspecs);
+    let advertisement = discover(
+        transport,
+        ServiceType::UploadPack,
+        options.protocol_version,
+        &ref_prefixes,
+    )
+    .await?;
+    let remote_refs: HashMap<&str, SHA1> = advertisement
+        .refs
+        .iter()
+        .map(|remote_ref| (remote_ref.name.as_str(), remote_ref.id))
+        .collect();
+    let mappings = map_refs(
+        &options.refspecs,
+        advertisement
+            .refs
+            .iter()
+            .map(|remote_ref| remote_ref.name.as_str()),
+    );
+
+    let mut wants = Vec::new();
+    for mapping in &mappings {
+        let id = remote_refs[mapping.src.as_str()];
+        if !wants.contains(&id) && !repo.has_object(&id.to_string()).await? {
+            wants.push(id);
+        }
+    }
+    let local_refs: HashMap<String, String> =
+        repo.get_repository_refs().await?.into_iter().collect();
+    let pack = if wants.is_empty() {
+        None
+    } else {
+        let haves = haves(repo, &local_refs, options.max_haves).await?;
+        let pack = fetch_pack(transport, &advertisement, &wants, &haves).await?;
+        let pack_hash = store_pack(repo, pack).await?;
+        // The history of the local tips and of the haves was complete before the fetch
+        let mut known: HashSet<SHA1> = haves.into_iter().collect();
+        known.extend(local_refs.values().filter_map(|id| id.parse::<SHA1>().ok()));
+        check_connected(repo, &wants, known).await?;
+        Some(pack_hash)
+    };
+
+    let refs = update_refs(repo, &local_refs, &remote_refs, mappings).await?;
+    transport.close().await?;
+    Ok(FetchResult {
+        advertisement,
+        refs,
+        pack,
+    })
+}
+
+/// The `ref-prefix` arguments of `ls-refs` covering the sources of `refspecs`, all refs when
+/// one of them doesn't start with `refs/`
+fn ref_prefixes(refspecs: &[Refspec]) -> Vec<String> {
+    let mut prefixes = Vec::new();
+    for refspec in refspecs.iter().filter(|refspec| !refspec.is_negative()) {
+        let src = refspec.src();
+        let prefix = match src.split_once('*') {
+            Some((prefix, _)) => prefix,
+            None => src,
+        };
+        if !prefix.starts_with("refs/") {
+            return Vec::new();
+        }
+        prefixes.push(prefix.to_string());
+    }
+    prefixes
+}
+
+/// The most recent commits reachable from the local refs, up to `max_haves`
+async fn haves<R: RepositoryAccess>(
+    repo: &R,
+    local_refs: &HashMap<String, String>,
+    max_haves: usize,
+) -> Result<Vec<SHA1>, ProtocolError> {
+    let mut walker = CommitWalker::new(repo);
+    for id in local_refs.values() {
+        if let Ok(id) = id.parse::<SHA1>()
+            && matches!(load_object(repo, &id).await?, Some((ObjectType::Commit, _)))
+        {
+            walker = walker.push(id);
+        }
+    }
+    let mut haves = Vec::new();
+    while haves.len() < max_haves
+        && let Some(commit) = walker.next_commit().await?
+    {
+        haves.push(commit.id);
+    }
+    Ok(haves)
+}
+
+/// Negotiate the pack of `wants` with the remote and return the pack data, see the
+/// [module documentation](self)
+///
+/// Protocol v2 requests, and v0 ones over stateless transports, are served on their own: each
+/// round repeats the wants and the haves the remote found common so far.
+async fn fetch_pack(
+    transport: &mut dyn Transport,
+    advertisement: &Advertisement,
+    wants: &[SHA1],
+    haves: &[SHA1],
+) -> Result<ProtocolStream, ProtocolError> {
+    let v2 = advertisement.version == ProtocolVersion::V2;
+    let rounds = v2 || advertisement.has_capability("multi_ack_detailed");
+    let stateless = v2 || transport.is_stateless();
+    let mut common: Vec<SHA1> = Vec::new();
+    let mut sent = 0;
+    let mut round_haves = INITIAL_ROUND_HAVES;
+    let mut first = true;
+    let mut ready = false;
+    loop {
+        let end = match (ready, rounds) {
+            (true, _) => sent,
+            (false, true) => (sent + round_haves).min(haves.len()),
+            (false, false) => haves.len(),
+        };
+        let done = ready || end == haves.len();
+        let mut round = if stateless {
+            common.clone()
+        } else {
+            Vec::new()
+        };
+        round.extend_from_slice(&haves[sent..end]);
+        sent = end;
+        let request = if v2 {
+            v2_fetch_request(advertisement, wants, &round, done)?
+        } else {
+            // A session remembers the wants of the first round
+            let round_wants = if first || stateless { wants } else { &[] };
+            v0_fetch_request(advertisement, round_wants, &round, done)?
+        };
+        first = false;
+        let mut response = PktLineReader::new(transport.request(request.freeze()).await?);
+        if done && v2 {
+            v2_packfile(&mut response).await?;
+            return Ok(side_band_data(response));
+        }
+        if done {
+            let first = v0_packfile(&mut response).await?;
+            return Ok(side_band_data(unread(response, first)?));
+        }
+        let acknowledged = if v2 {
+            v2_acknowledgments(&mut response).await?
+        } else {
+            v0_acknowledgments(&mut response).await?
+        };
+        for id in acknowledged.common {
+            if !common.contains(&id) {
+                common.push(id);
+            }
+        }
+        ready = acknowledged.ready;
+        // In protocol v2, the pack follows the acknowledgments of a ready remote
+        if ready && v2 {
+            v2_packfile(&mut response).await?;
+            return Ok(side_band_data(response));
+        }
+        round_haves *= 2;
+    }
+}
+
+/// What the remote answered to a round of haves
+struct Acknowledgments {
+    /// The haves found common
+    common: Vec<SHA1>,
+    /// Whether the remote is ready to send the pack
+    ready: bool,
+}
+
+/// The id and status of an `ACK <id> [<status>]` line
+fn parse_ack(line: &str) -> Option<(SHA1, &str)> {
+    let ack = line.strip_prefix("ACK ")?;
+    let (id, status) = ack.split_once(' ').unwrap_or((ack, ""));
+    Some((id.parse().ok()?, status))
+}
+
+/// Read the acknowledgments of a protocol v0 round, up to the `NAK` ending them with
+/// `multi_ack_detailed`
+async fn v0_acknowledgments(
+    response: &mut PktLineReader<ProtocolStream>,
+) -> Result<Acknowledgments, ProtocolError> {
+    let mut acknowledged = Acknowledgments {
+        common: Vec::new(),
+        ready: false,
+    };
+    loop {
+        let line = match response.next().await.transpose()? {
+            Some(PktLine::Data(line)) if line.starts_with(b"ERR ") => {
+                return Err(remote_error(&line));
+            }
+            Some(PktLine::Data(line)) => String::from_utf8_lossy(&line).trim_end().to_string(),
+            _ => {
+                return Err(ProtocolError::invalid_request(
+                    "Missing NAK at the end of the acknowledgments",
+                ));
+            }
+        };
+        if line == "NAK" {
+            return Ok(acknowledged);
+        }
+        match parse_ack(&line) {
+            Some((id, "common")) => acknowledged.common.push(id),
+            Some((_, "ready")) => acknowledged.ready = true,
+            Some(_) => {}
+            None => {
+                return Err(ProtocolError::invalid_request(&format!(
+                    "Unexpected line in the acknowledgments: {line}"
+                )));
+            }
+        }
+    }
+}
+
+/// Read the `acknowledgments` section of a protocol v2 `fetch` response, which the pack
+/// sections follow after a delim-pkt when the remote is ready
+async fn v2_acknowledgments(
+    response: &mut PktLineReader<ProtocolStream>,
+) -> Result<Acknowledgments, ProtocolError> {
+    let mut acknowledged = Acknowledgments {
+        common: Vec::new(),
+        ready: false,
+    };
+    loop {
+        let line = match response.next().await.transpose()? {
+            Some(PktLine::Data(line)) if line.starts_with(b"ERR ") => {
+                return Err(remote_error(&line));
+            }
+            Some(PktLine::Data(line)) => String::from_utf8_lossy(&line).trim_end().to_string(),
+            Some(PktLine::Flush) if !acknowledged.ready => return Ok(acknowledged),
+            Some(PktLine::Delim) if acknowledged.ready => return Ok(acknowledged),
+            _ => {
+                return Err(ProtocolError::invalid_request(
+                    "Invalid acknowledgments section",
+                ));
+            }
+        };
+        match (line.as_str(), parse_ack(&line)) {
+            ("acknowledgments" | "NAK", _) => {}
+            ("ready", _) => acknowledged.ready = true,
+            (_, Some((id, ""))) => acknowledged.common.push(id),
+            _ => {
+                return Err(ProtocolError::invalid_request(&format!(
+                    "Unexpected line in the acknowledgments: {line}"
+                )));
+            }
+        }
+    }
+}
+
+/// A protocol v0 request of one negotiation round: the `wants`, with the capabilities of the
+/// first `want` line, unless a session already has them, and the `haves`, ending the
+/// negotiation with `done` when `done` is set
+fn v0_fetch_request(
+    advertisement: &Advertisement,
+    wants: &[SHA1],
+    haves: &[SHA1],
+    done: bool,
+) -> Result<BytesMut, ProtocolError> {
+    let side_band = ["side-band-64k", "side-band"]
+        .into_iter()
+        .find(|side_band| advertisement.has_capability(side_band))
+        .ok_or_else(|| ProtocolError::invalid_request("The remote doesn't support side-band"))?;
+    let mut capabilities = vec![side_band.to_string()];
+    for capability in ["multi_ack_detailed", "ofs-delta"] {
+        if advertisement.has_capability(capability) {
+            capabilities.push(capability.to_string());
+        }
+    }
+    capabilities.push(format!("agent={DEFAULT_AGENT}"));
+    if let Some(object_format) = advertisement.capability("object-format") {
+        capabilities.push(format!("object-format={object_format}"));
+    }
+    let mut request = BytesMut::new();
+    for (i, want) in wants.iter().enumerate() {
+        let line = match i {
+            0 => format!("want {want} {}\n", capabilities.join(" ")),
+            _ => format!("want {want}\n"),
+        };
+        add_pkt_line_string(&mut request, line);
+    }
+    if !wants.is_empty() {
+        request.put(&PKT_LINE_END_MARKER[..]);
+    }
+    for have in haves {
+        add_pkt_line_string(&mut request, format!("have {have}\n"));
+    }
+    match done {
+        true => add_pkt_line_string(&mut request, "done\n".to_string()),
+        false => request.put(&PKT_LINE_END_MARKER[..]),
+    }
+    Ok(request)
+}
+
+/// A protocol v2 `fetch` of `wants` with `haves`, ending the negotiation with `done` when
+/// `done` is set
+fn v2_fetch_request(
+    advertisement: &Advertisement,
+    wants: &[SHA1],
+    haves: &[SHA1],
+    done: bool,
+) -> Result<BytesMut, ProtocolError> {
+    if !advertisement.has_capability("fetch") {
+        return Err(ProtocolError::invalid_request(
+            "The remote doesn't support fetch",
+        ));
+    }
+    let mut arguments = vec!["ofs-delta".to_string()];
+    arguments.extend(wants.iter().map(|want| format!("want {want}")));
+    arguments.extend(haves.iter().map(|have| format!("have {have}")));
+    if done {
+        arguments.push("done".to_string());
+    }
+    Ok(v2_request(advertisement, "fetch", &arguments).into())
+}
+
+/// Read the `ACK` and `NAK` lines of a protocol v0 response, returning the first packet of
+/// the side-band following them
+async fn v0_packfile(
+    response: &mut PktLineReader<ProtocolStream>,
+) -> Result<PktLine, ProtocolError> {
+    loop {
+        match response.next().await.transpose()? {
+            Some(PktLine::Data(line)) if line.starts_with(b"ACK ") || line.starts_with(b"NAK") => {}
+            Some(PktLine::Data(line)) if line.starts_with(b"ERR ") => {
+                return Err(remote_error(&line));
+            }
+            Some(pkt_line) => return Ok(pkt_line),
+            None => {
+                return Err(ProtocolError::invalid_request(
+                    "Missing pack in the response",
+                ));
+            }
+        }
+    }
+}
+
+/// Skip the sections of a protocol v2 `fetch` response up to its `packfile` section
+async fn v2_packfile(response: &mut PktLineReader<ProtocolStream>) -> Result<(), ProtocolError> {
+    loop {
+        match response.next().await.transpose()? {
+            Some(PktLine::Data(line)) if line.as_ref() == b"packfile\n" => return Ok(()),
+            Some(PktLine::Data(line)) if line.starts_with(b"ERR ") => {
+                return Err(remote_error(&line));
+            }
+            Some(PktLine::Data(_)) | Some(PktLine::Delim) => {}
+            _ => {
+                return Err(ProtocolError::invalid_request(
+                    "Missing pack in the response",
+                ));
+            }
+        }
+    }
+}
+
+fn remote_error(line: &[u8]) -> ProtocolError {
+    let message = String::from_utf8_lossy(&line[b"ERR ".len()..]);
+    ProtocolError::Remote(message.trim_end().to_string())
+}
+
+/// Index `pack` and store its objects, returning its checksum
+async fn store_pack<R: RepositoryAccess>(
+    repo: &R,
+    pack: ProtocolStream,
+) -> Result<SHA1, ProtocolError> {
+    let indexed = PackGenerator::new(repo)
+        .index_pack_in_batches(pack, None, |objects| store_objects(repo, objects))
+        .await?;
+    Ok(indexed.pack_hash)
+}
+
+/// Check that every object reachable from `wants` is in `repo`, walking down to the objects
+/// of `known`, like git's `check_connected`. Submodule commits are left out.
+async fn check_connected<R: RepositoryAccess>(
+    repo: &R,
+    wants: &[SHA1],
+    mut known: HashSet<SHA1>,
+) -> Result<(), ProtocolError> {
+    let missing =
+        |id: SHA1| ProtocolError::pack_corrupt(format!("The fetched objects are missing {id}"));
+    let mut queue = wants.to_vec();
+    while let Some(id) = queue.pop() {
+        if !known.insert(id) {
+            continue;
+        }
+        let (obj_type, data) = load_object(repo, &id).await?.ok_or_else(|| missing(id))?;
+        let parse_error = |e: GitError| ProtocolError::corrupt_object(id, e);
+        match obj_type {
+            ObjectType::Commit => {
+                let commit = Commit::from_bytes(&data, id).map_err(parse_error)?;
+                queue.push(commit.tree_id);
+                queue.extend(commit.parent_commit_ids);
+            }
+            ObjectType::Tree => {
+                let tree = <Tree as ObjectTrait>::from_bytes(&data, id).map_err(parse_error)?;
+                for item in tree.tree_items {
+                    match item.mode {
+                        TreeItemMode::Tree => queue.push(item.id),
+                        TreeItemMode::Commit => {}
+                        // Blobs are only looked up
+                        _ => {
+                            if known.insert(item.id)
+                                && !repo.has_object(&item.id.to_string()).await?
+                            {
+                                return Err(missing(item.id));
+                            }
+                        }
+                    }
+                }
+            }
+            ObjectType::Tag => {
+                queue.push(Tag::from_bytes(&data, id).map_err(parse_error)?.object_hash);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Update the local refs of `mappings` to the remote values, refusing to lose commits or move
+/// tags unless forced
+async fn update_refs<R: RepositoryAccess>(
+    repo: &R,
+    local_refs: &HashMap<String, String>,
+    remote_refs: &HashMap<&str, SHA1>,
+    mappings: Vec<RefMapping>,
+) -> Result<Vec<FetchedRef>, ProtocolError> {
+    let mut fetched = Vec::new();
+    let mut updates = Vec::new();
+    let mut generations = GenerationNumbers::new();
+    for mapping in mappings {
+        let new = remote_refs[mapping.src.as_str()];
+        let old = local_refs.get(&mapping.dst).cloned();
+        let old_id = old.as_deref().and_then(|old| old.parse::<SHA1>().ok());
+        let status = match old_id {
+            None => FetchStatus::Created,
+            Some(old_id) if old_id == new => FetchStatus::UpToDate,
+            Some(_) if mapping.dst.starts_with("refs/tags/") => match mapping.force {
+                true => FetchStatus::Forced,
+                false => FetchStatus::Rejected,
+            },
+            Some(old_id) => {
+                let fast_forward = is_fast_forward(repo, old_id, new, &mut generations).await?;
+                match (fast_forward, mapping.force) {
+                    (true, _) => FetchStatus::FastForward,
+                    (false, true) => FetchStatus::Forced,
+                    (false, false) => FetchStatus::Rejected,
+                }
+            }
+        };
+        match status {
+            FetchStatus::Rejected => {
+                let old = old.as_deref().unwrap_or_default();
+                tracing::warn!("Not updating {} from {old} to {new}", mapping.dst);
+            }
+            FetchStatus::UpToDate => {}
+            _ => updates.push(RefUpdate {
+                ref_name: mapping.dst.clone(),
+                old_hash: old,
+                new_hash: new.to_string(),
+            }),
+        }
+        fetched.push(FetchedRef {
+            src: mapping.src,
+            dst: mapping.dst,
+            old: old_id,
+            new,
+            status,
+        });
+    }
+    for result in repo.update_references(&updates).await? {
+        result?;
+    }
+    Ok(fetched)
+}
+
+/// Whether moving a ref from `old` to `new` keeps its commits: both are commits of `repo` and
+/// `old` is an ancestor of `new`
+pub(super) async fn is_fast_forward<R: RepositoryAccess>(
+    repo: &R,
+    old: SHA1,
+    new: SHA1,
+    generations: &mut GenerationNumbers,
+) -> Result<bool, ProtocolError> {
+    for id in [old, new] {
+        if !matches!(load_object(repo, &id).await?, Some((ObjectType::Commit, _))) {
+            return Ok(false);
+        }
+    }
+    is_ancestor(repo, old, new, generations).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use tokio_util::io::ReaderStream;
+
+    use super::*;
+    use crate::client::transport::ConnectionTransport;
+    use crate::internal::object::commit::Commit;
+    use crate::internal::pack::entry::Entry;
+    use crate::protocol::memory::MemoryRepository;
+    use crate::protocol::smart::SmartProtocol;
+    use crate::protocol::smart::tests::{TestAuth, build_test_pack, encode_test_pack};
+    use crate::protocol::ssh::serve_git_command;
+    use crate::protocol::types::TransportProtocol;
+    use crate::protocol::utils::side_band_packets;
+
+    /// Fetch from the upload-pack of `remote` over an in-process connection
+    async fn fetch_from(
+        remote: &MemoryRepository,
+        repo: &MemoryRepository,
+        options: &FetchOptions,
+    ) -> FetchResult {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let (client_read, client_write) = tokio::io::split(client);
+        let (server_read, server_write) = tokio::io::split(server);
+        let serve = async {
+            let mut smart = SmartProtocol::new(TransportProtocol::Ssh, remote.clone(), TestAuth);
+            let input: ProtocolStream = Box::pin(
+                ReaderStream::new(server_read).map(|chunk| chunk.map_err(ProtocolError::from)),
+            );
+            serve_git_command(&mut smart, ServiceType::UploadPack, input, server_write).await
+        };
+        let mut transport = ConnectionTransport::new(client_read, client_write);
+        let (served, fetched) = tokio::join!(serve, fetch(repo, &mut transport, options));
+        served.unwrap();
+        fetched.unwrap()
+    }
+
+    fn statuses(result: &FetchResult) -> Vec<(&str, FetchStatus)> {
+        let mut statuses: Vec<_> = result
+            .refs
+            .iter()
+            .map(|fetched| (fetched.dst.as_str(), fetched.status))
+            .collect();
+        statuses.sort_by_key(|(dst, _)| *dst);
+        statuses
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let remote = MemoryRepository::new();
+        let (root, pack) = build_test_pack().await;
+        remote.store_pack_data(&pack).await.unwrap();
+        let child = |parent: &Commit, message: &str| {
+            let commit = Commit::new(
+                parent.author.clone(),
+                parent.committer.clone(),
+                parent.tree_id,
+                vec![parent.id],
+                message,
+            );
+            remote.insert_object(&commit).unwrap();
+            commit
+        };
+        let second = child(&root, "second");
+        remote.set_ref("refs/heads/main", second.id);
+
+        // A clone, asking for protocol v2 and answered in v0
+        let repo = MemoryRepository::new();
+        let options = FetchOptions::default();
+        let result = fetch_from(&remote, &repo, &options).await;
+        assert_eq!(result.advertisement.version, ProtocolVersion::V0);
+        assert!(result.pack.is_some());
+        assert_eq!(
+            statuses(&result),
+            vec![("refs/remotes/origin/main", FetchStatus::Created)]
+        );
+        assert_eq!(repo.get_ref("refs/remotes/origin/main"), Some(second.id));
+        assert!(repo.has_object(&root.tree_id.to_string()).await.unwrap());
+
+        // New commits on top of the fetched ones, and nothing new
+        let third = child(&second, "third");
+        remote.set_ref("refs/heads/main", third.id);
+        remote.set_ref("refs/heads/topic", second.id);
+        let result = fetch_from(&remote, &repo, &options).await;
+        assert!(result.pack.is_some());
+        assert_eq!(
+            statuses(&result),
+            vec![
+                ("refs/remotes/origin/main", FetchStatus::FastForward),
+                ("refs/remotes/origin/topic", FetchStatus::Created),
+            ]
+        );
+        assert!(repo.has_object(&third.id.to_string()).await.unwrap());
+        let result = fetch_from(&remote, &repo, &options).await;
+        assert_eq!(result.pack, None);
+        assert!(
+            result
+                .refs
+                .iter()
+                .all(|fetched| fetched.status == FetchStatus::UpToDate)
+        );
+
+        // A rewritten branch, only updated when forced
+        let rewritten = child(&root, "rewritten");
+        remote.set_ref("refs/heads/main", rewritten.id);
+        let unforced = FetchOptions {
+            refspecs: vec![Refspec::parse("refs/heads/main:refs/remotes/origin/main").unwrap()],
+            ..FetchOptions::default()
+        };
+        let result = fetch_from(&remote, &repo, &unforced).await;
+        assert_eq!(
+            statuses(&result),
+            vec![("refs/remotes/origin/main", FetchStatus::Rejected)]
+        );
+        assert_eq!(repo.get_ref("refs/remotes/origin/main"), Some(third.id));
+        let result = fetch_from(&remote, &repo, &options).await;
+        assert_eq!(
+            statuses(&result),
+            vec![
+                ("refs/remotes/origin/main", FetchStatus::Forced),
+                ("refs/remotes/origin/topic", FetchStatus::UpToDate),
+            ]
+        );
+        assert_eq!(repo.get_ref("refs/remotes/origin/main"), Some(rewritten.id));
+
+        // Local commits the remote doesn't have take rounds of haves to get past
+        let mut local = rewritten.clone();
+        for i in 0..40 {
+            local = Commit::new(
+                local.author.clone(),
+                local.committer.clone(),
+                local.tree_id,
+                vec![local.id],
+                &format!("local {i}"),
+            );
+            repo.insert_object(&local).unwrap();
+        }
+        repo.set_ref("refs/heads/local", local.id);
+        let fourth = child(&rewritten, "fourth");
+        remote.set_ref("refs/heads/main", fourth.id);
+        let result = fetch_from(&remote, &repo, &unforced).await;
+        assert_eq!(
+            statuses(&result),
+            vec![("refs/remotes/origin/main", FetchStatus::FastForward)]
+        );
+        assert!(repo.has_object(&fourth.id.to_string()).await.unwrap());
+    }
+
+    /// A protocol v2 server answering requests with canned responses
+    struct ScriptedTransport {
+        advertisement: Bytes,
+        responses: VecDeque<Bytes>,
+        requests: Vec<String>,
+    }
+
+    #[async_trait]
+    impl Transport for ScriptedTransport {
+        async fn connect(
+            &mut self,
+            _service: ServiceType,
+            version: ProtocolVersion,
+        ) -> Result<Bytes, ProtocolError> {
+            assert_eq!(version, ProtocolVersion::V2);
+            Ok(self.advertisement.clone())
+        }
+
+        async fn request(&mut self, request: Bytes) -> Result<ProtocolStream, ProtocolError> {
+            self.requests
+                .push(String::from_utf8_lossy(&request).into_owned());
+            let response = self.responses.pop_front().unwrap();
+            Ok(Box::pin(futures::stream::iter([Ok(response)])))
+        }
+    }
+
+    fn pkt_lines(lines: &[&str]) -> BytesMut {
+        let mut pkt_lines = BytesMut::new();
+        for line in lines {
+            add_pkt_line_string(&mut pkt_lines, line.to_string());
+        }
+        pkt_lines
+    }
+
+    /// The `packfile` section of `pack`, ending a protocol v2 `fetch` response
+    fn packfile(pack: &[u8]) -> Bytes {
+        let mut packfile = pkt_lines(&["packfile\n", "\x02Counting objects\n"]);
+        packfile.extend_from_slice(&side_band_packets(pack, 1000));
+        packfile.put(&PKT_LINE_END_MARKER[..]);
+        packfile.freeze()
+    }
+
+    /// A protocol v2 remote whose `refs/heads/main` is at `main`, answering the `fetch`
+    /// requests with `responses`
+    fn scripted_v2(main: SHA1, responses: Vec<Bytes>) -> ScriptedTransport {
+        let mut advertisement = pkt_lines(&[
+            "version 2\n",
+            "agent=git/2.43.0\n",
+            "ls-refs=unborn\n",
+            "fetch=shallow\n",
+            "object-format=sha1\n",
+        ]);
+        advertisement.put(&PKT_LINE_END_MARKER[..]);
+        let mut refs = pkt_lines(&[
+            &format!("{main} HEAD symref-target:refs/heads/main\n"),
+            &format!("{main} refs/heads/main\n"),
+        ]);
+        refs.put(&PKT_LINE_END_MARKER[..]);
+        ScriptedTransport {
+            advertisement: advertisement.freeze(),
+            responses: std::iter::once(refs.freeze()).chain(responses).collect(),
+            requests: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_v2() {
+        let (commit, pack) = build_test_pack().await;
+        let mut transport = scripted_v2(commit.id, vec![packfile(&pack)]);
+
+        let repo = MemoryRepository::new();
+        let result = fetch(&repo, &mut transport, &FetchOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(result.advertisement.version, ProtocolVersion::V2);
+        assert_eq!(
+            result.advertisement.find_ref("HEAD").unwrap().symref_target,
+            Some("refs/heads/main".to_string())
+        );
+        assert_eq!(
+            statuses(&result),
+            vec![("refs/remotes/origin/main", FetchStatus::Created)]
+        );
+        assert_eq!(repo.get_ref("refs/remotes/origin/main"), Some(commit.id));
+        assert!(repo.has_object(&commit.tree_id.to_string()).await.unwrap());
+
+        let agent = format!("agent={DEFAULT_AGENT}\n");
+        let ls_refs = &transport.requests[0];
+        assert!(ls_refs.starts_with("0014command=ls-refs\n"));
+        assert!(ls_refs.contains(&agent));
+        assert!(ls_refs.contains("object-format=sha1\n0001"));
+        assert!(ls_refs.ends_with("0009peel\n000csymrefs\n001bref-prefix refs/heads/\n0000"));
+        let fetch = &transport.requests[1];
+        assert!(fetch.starts_with("0012command=fetch\n"));
+        assert!(fetch.ends_with(&format!(
+            "000eofs-delta\n0032want {}\n0009done\n0000",
+            commit.id
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_v2_rounds() {
+        let (commit, pack) = build_test_pack().await;
+        let repo = MemoryRepository::new();
+        let mut local = Vec::new();
+        for i in 0..20 {
+            let local_commit = Commit::new(
+                commit.author.clone(),
+                commit.committer.clone(),
+                commit.tree_id,
+                local.last().copied().into_iter().collect(),
+                &format!("local {i}"),
+            );
+            repo.insert_object(&local_commit).unwrap();
+            local.push(local_commit.id);
+        }
+        repo.set_ref("refs/heads/local", local[19]);
+
+        // The first round tells about 16 haves, the next one about the 4 left and the common one
+        let mut acknowledgments =
+            pkt_lines(&["acknowledgments\n", &format!("ACK {}\n", local[10])]);
+        acknowledgments.put(&PKT_LINE_END_MARKER[..]);
+        let mut transport = scripted_v2(commit.id, vec![acknowledgments.freeze(), packfile(&pack)]);
+        let result = fetch(&repo, &mut transport, &FetchOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            statuses(&result),
+            vec![("refs/remotes/origin/main", FetchStatus::Created)]
+        );
+        let requests = &transport.requests;
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[1].matches("have ").count(), 16);
+        assert!(requests[1].ends_with(&format!("have {}\n0000", local[4])));
+        assert_eq!(requests[2].matches("have ").count(), 5);
+        assert!(requests[2].contains(&format!("have {}\n", local[10])));
+        assert!(requests[2].ends_with(&format!("have {}\n0009done\n0000", local[0])));
+
+        // A pack leaving out objects of the wanted tips updates no ref
+        let repo = MemoryRepository::new();
+        let incomplete = encode_test_pack(vec![Entry::from(commit.clone())]).await;
+        let mut transport = scripted_v2(commit.id, vec![packfile(&incomplete)]);
+        let err = fetch(&repo, &mut transport, &FetchOptions::default())
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains(&format!("missing {}", commit.tree_id)),
+            "{err}"
+        );
+        assert_eq!(repo.get_ref("refs/remotes/origin/main"), None);
+    }
+}
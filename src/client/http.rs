@@ -0,0 +1,194 @@
+//! The smart HTTP transport of the client.
+//!
+//! [`HttpTransport`] discovers the refs of a service with `GET <url>/info/refs?service=<service>`
+//! and posts each request to `<url>/<service>`, asking for protocol v2 with the `Git-Protocol`
+//! header. Servers answering the discovery with anything but the advertisement of the smart
+//! protocol only speak the dumb one, which the client doesn't.
+use std::pin::Pin;
+use std::sync::{Mutex, PoisonError};
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use base64::Engine;
+use bytes::Bytes;
+use http::StatusCode;
+
+use super::transport::Transport;
+use crate::protocol::types::{ProtocolError, ProtocolStream, ProtocolVersion, ServiceType};
+use crate::protocol::utils::{PktLine, parse_pkt_line};
+
+/// A [`Transport`] over smart HTTP, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct HttpTransport {
+    client: reqwest::Client,
+    /// The URL of the repository, e.g. `https://example.com/org/project.git`
+    url: String,
+    headers: Vec<(String, String)>,
+    service: ServiceType,
+    version: ProtocolVersion,
+}
+
+impl HttpTransport {
+    pub fn new(url: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            headers: Vec::new(),
+            service: ServiceType::UploadPack,
+            version: ProtocolVersion::V0,
+        }
+    }
+
+    /// Send the requests with `client`, e.g. one configured with proxies or timeouts.
+    pub fn set_client(&mut self, client: reqwest::Client) {
+        self.client = client;
+    }
+
+    /// Send `name: value` with every request.
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Authenticate with HTTP basic authentication, e.g. a user name and an access token.
+    pub fn set_basic_auth(&mut self, user: &str, password: &str) {
+        let credentials =
+            base64::engine::general_purpose::STANDARD.encode(format!("{user}:{password}"));
+        self.set_header("Authorization", &format!("Basic {credentials}"));
+    }
+
+    /// Post `body` to the service and return the body of the response.
+    async fn post(&self, body: reqwest::Body) -> Result<ProtocolStream, ProtocolError> {
+        let service = self.service;
+        let post = self
+            .client
+            .post(format!("{}/{service}", self.url))
+            .header(
+                http::header::CONTENT_TYPE,
+                format!("application/x-{service}-request"),
+            )
+            .header(
+                http::header::ACCEPT,
+                format!("application/x-{service}-result"),
+            )
+            .body(body);
+        let response = self.send(post).await?;
+        let body = futures::stream::try_unfold(response, |mut response| async move {
+            let chunk = response.chunk().await.map_err(http_error)?;
+            Ok(chunk.map(|chunk| (chunk, response)))
+        });
+        Ok(Box::pin(body))
+    }
+
+    async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, ProtocolError> {
+        let mut request = request.header("User-Agent", crate::protocol::types::DEFAULT_AGENT);
+        if self.version != ProtocolVersion::V0 {
+            let version = match self.version {
+                ProtocolVersion::V2 => "version=2",
+                _ => "version=1",
+            };
+            request = request.header("Git-Protocol", version);
+        }
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        let response = request.send().await.map_err(http_error)?;
+        match response.status() {
+            status if status.is_success() => Ok(response),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(ProtocolError::auth_failed(
+                None,
+                &format!("{} answered {}", self.url, response.status()),
+            )),
+            StatusCode::NOT_FOUND => Err(ProtocolError::RepositoryNotFound(self.url.clone())),
+            status => Err(ProtocolError::Remote(format!(
+                "{} answered {status}",
+                self.url
+            ))),
+        }
+    }
+}
+
+fn http_error(e: reqwest::Error) -> ProtocolError {
+    ProtocolError::Io(std::io::Error::other(e))
+}
+
+/// A request body sent in chunks as `0` yields them. The stream is only polled through
+/// `&mut`, the mutex just makes the body `Sync` as reqwest wants.
+struct StreamBody(Mutex<ProtocolStream>);
+
+impl http_body::Body for StreamBody {
+    type Data = Bytes;
+    type Error = ProtocolError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<http_body::Frame<Bytes>, ProtocolError>>> {
+        let stream = self
+            .get_mut()
+            .0
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        stream
+            .as_mut()
+            .poll_next(cx)
+            .map(|chunk| chunk.map(|chunk| chunk.map(http_body::Frame::data)))
+    }
+}
+
+#[async_trait]
+impl Transport for HttpTransport {
+    async fn connect(
+        &mut self,
+        service: ServiceType,
+        version: ProtocolVersion,
+    ) -> Result<Bytes, ProtocolError> {
+        self.service = service;
+        self.version = version;
+        let url = format!("{}/info/refs?service={service}", self.url);
+        let response = self.send(self.client.get(url)).await?;
+        let content_type = format!("application/x-{service}-advertisement");
+        let smart = response
+            .headers()
+            .get(http::header::CONTENT_TYPE)
+            .is_some_and(|value| value.as_bytes() == content_type.as_bytes());
+        if !smart {
+            return Err(ProtocolError::invalid_request(&format!(
+                "{} doesn't speak the smart HTTP protocol",
+                self.url
+            )));
+        }
+        let mut advertisement = response.bytes().await.map_err(http_error)?;
+        // The `# service=<service>` line and its flush-pkt, left out in protocol v2
+        let mut rest = advertisement.clone();
+        if let Some(PktLine::Data(line)) = parse_pkt_line(&mut rest)?
+            && line.starts_with(b"# service=")
+        {
+            if parse_pkt_line(&mut rest)? != Some(PktLine::Flush) {
+                return Err(ProtocolError::invalid_request(
+                    "Missing flush-pkt after the service line",
+                ));
+            }
+            advertisement = rest;
+        }
+        Ok(advertisement)
+    }
+
+    async fn request(&mut self, request: Bytes) -> Result<ProtocolStream, ProtocolError> {
+        self.post(request.into()).await
+    }
+
+    async fn request_stream(
+        &mut self,
+        request: ProtocolStream,
+    ) -> Result<ProtocolStream, ProtocolError> {
+        self.post(reqwest::Body::wrap(StreamBody(Mutex::new(request))))
+            .await
+    }
+
+    fn is_stateless(&self) -> bool {
+        true
+    }
+}
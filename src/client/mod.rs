@@ -0,0 +1,26 @@
+//! The client side of the git protocols: listing the refs of remote repositories, fetching
+//! from them into a `RepositoryAccess`, and pushing its refs to them.
+//!
+//! - `transport`: the [`Transport`] a client talks to a service through, over a byte stream
+//!   like an SSH channel or a `git://` connection, or over smart HTTP.
+//! - `discovery`: the refs and capabilities a service advertises, listed with `ls-refs` in
+//!   protocol v2, and [`ls_remote`] listing them for a URL.
+//! - `fetch`: [`fetch()`], negotiating and receiving the pack of the refs a repository misses.
+//! - `push`: [`push()`], sending the updates of refs and the pack of their new objects.
+//! - `url`: [`RemoteUrl`], the HTTP, SSH and `git://` URLs of remotes and their transports.
+//! - `ssh`: `SshTransport`, running services over a russh client, behind the `russh` feature.
+pub mod discovery;
+pub mod fetch;
+pub mod http;
+pub mod push;
+#[cfg(feature = "russh")]
+pub mod ssh;
+pub mod transport;
+pub mod url;
+
+pub use discovery::{Advertisement, RemoteRef, discover, ls_remote};
+pub use fetch::{FetchOptions, FetchResult, FetchStatus, FetchedRef, fetch};
+pub use http::HttpTransport;
+pub use push::{PushOptions, PushResult, PushStatus, PushedRef, push};
+pub use transport::{ConnectionTransport, Transport};
+pub use url::RemoteUrl;
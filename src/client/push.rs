@@ -0,0 +1,550 @@
+//! The pushing side of receive-pack, like `git push`.
+//!
+//! [`push`] maps local refs to refs of the remote with refspecs and sends one command per ref
+//! to update, followed by a pack of the objects the remote misses: those reachable from the
+//! new values but not from the refs the remote advertised. Like git, updates that would lose
+//! commits of the remote, or move one of its tags, are only sent when forced, since the
+//! remote refuses them by default anyway. The report of the remote tells how each command
+//! went.
+//!
+//! Pushes are made in protocol v0, the only version receive-pack speaks.
+use std::collections::HashMap;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::StreamExt;
+
+use super::discovery::{Advertisement, discover};
+use super::fetch::is_fast_forward;
+use super::transport::{Transport, side_band_data};
+use crate::hash::SHA1;
+use crate::internal::object::types::ObjectType;
+use crate::protocol::RepositoryAccess;
+use crate::protocol::codec::PktLineReader;
+use crate::protocol::pack::{PackGenerator, load_object};
+use crate::protocol::types::{
+    DEFAULT_AGENT, PKT_LINE_END_MARKER, ProtocolError, ProtocolStream, ProtocolVersion,
+    ServiceType, ZERO_ID,
+};
+use crate::protocol::utils::{PktLine, add_pkt_line_string};
+use crate::refspec::{Refspec, map_refs};
+use crate::revwalk::GenerationNumbers;
+
+/// How [`push`] pushes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PushOptions {
+    /// The local refs to push and the refs of the remote they update, by full name, e.g.
+    /// `refs/heads/main`, `+refs/heads/*:refs/heads/*`, or `:refs/heads/gone` to delete
+    pub refspecs: Vec<Refspec>,
+    /// Have the remote apply every update or none, like `--atomic`
+    pub atomic: bool,
+    /// Strings handed to the hooks of the remote, like `--push-option`
+    pub push_options: Vec<String>,
+}
+
+/// How [`push`] updated a ref of the remote
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PushStatus {
+    Ok,
+    UpToDate,
+    /// Not sent: the update isn't a fast-forward, or would move a tag, and isn't forced, or is
+    /// a deletion the remote doesn't allow
+    Rejected,
+    /// Refused by the remote, for the given reason
+    RemoteRejected(String),
+}
+
+/// A ref mapped by the refspecs of a [`push`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PushedRef {
+    /// The local ref, `None` for a deletion
+    pub src: Option<String>,
+    /// The ref of the remote
+    pub dst: String,
+    /// The value of the ref of the remote before the push
+    pub old: Option<SHA1>,
+    /// The value pushed, `None` for a deletion
+    pub new: Option<SHA1>,
+    pub status: PushStatus,
+}
+
+/// What [`push`] did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PushResult {
+    pub advertisement: Advertisement,
+    pub refs: Vec<PushedRef>,
+}
+
+/// Push the refs of `repo` that `options` maps to the remote, see the
+/// [module documentation](self).
+///
+/// The whole push fails when a refspec without `*` names a local ref that doesn't exist, when
+/// the remote doesn't support an option, or fails to unpack the pack. With
+/// [`atomic`](PushOptions::atomic), it fails without sending anything when an update is
+/// rejected.
+pub async fn push<R: RepositoryAccess>(
+    repo: &R,
+    transport: &mut dyn Transport,
+    options: &PushOptions,
+) -> Result<PushResult, ProtocolError> {
+    let advertisement = discover(
+        transport,
+        ServiceType::ReceivePack,
+        ProtocolVersion::V0,
+        &[],
+    )
+    .await?;
+    // Nothing is sent when the push fails before its commands, which the remote is told of
+    let (capabilities, mut refs) = match plan(repo, &advertisement, options).await {
+        Ok(planned) => planned,
+        Err(e) => {
+            transport.close().await?;
+            return Err(e);
+        }
+    };
+
+    let commands: Vec<&PushedRef> = refs
+        .iter()
+        .filter(|pushed| pushed.status == PushStatus::Ok)
+        .collect();
+    if !commands.is_empty() {
+        let mut request = BytesMut::new();
+        for (i, command) in commands.iter().enumerate() {
+            let id = |id: Option<SHA1>| id.map_or_else(|| ZERO_ID.to_string(), |id| id.to_string());
+            let mut line = format!("{} {} {}", id(command.old), id(command.new), command.dst);
+            if i == 0 {
+                line.push('\0');
+                line.push_str(&capabilities.join(" "));
+            }
+            line.push('\n');
+            add_pkt_line_string(&mut request, line);
+        }
+        request.put(&PKT_LINE_END_MARKER[..]);
+        if !options.push_options.is_empty() {
+            for push_option in &options.push_options {
+                add_pkt_line_string(&mut request, format!("{push_option}\n"));
+            }
+            request.put(&PKT_LINE_END_MARKER[..]);
+        }
+        let wants: Vec<String> = commands
+            .iter()
+            .filter_map(|command| command.new)
+            .map(|id| id.to_string())
+            .collect();
+        let head = request.freeze();
+        let mut request: ProtocolStream = Box::pin(futures::stream::once(async { Ok(head) }));
+        // Only deletions come without a pack
+        if !wants.is_empty() {
+            let haves = haves(repo, &advertisement).await?;
+            let mut generator = PackGenerator::new(repo);
+            generator.set_ofs_delta(advertisement.has_capability("ofs-delta"));
+            let pack = match haves.is_empty() {
+                true => generator.generate_full_pack(wants).await?,
+                false => generator.generate_incremental_pack(wants, haves).await?,
+            };
+            request = Box::pin(request.chain(pack.map(|chunk| Ok(Bytes::from(chunk)))));
+        }
+
+        // The pack is sent as it is generated, instead of held in memory
+        let response = PktLineReader::new(transport.request_stream(request).await?);
+        let mut report = match capabilities.iter().any(|c| c.starts_with("side-band")) {
+            true => PktLineReader::new(side_band_data(response)),
+            false => response,
+        };
+        let statuses = read_report(&mut report).await?;
+        for pushed in refs
+            .iter_mut()
+            .filter(|pushed| pushed.status == PushStatus::Ok)
+        {
+            match statuses.get(&pushed.dst) {
+                Some(Ok(())) => {}
+                Some(Err(reason)) => pushed.status = PushStatus::RemoteRejected(reason.clone()),
+                None => {
+                    pushed.status = PushStatus::RemoteRejected("not reported".to_string());
+                }
+            }
+        }
+    }
+    transport.close().await?;
+    Ok(PushResult {
+        advertisement,
+        refs,
+    })
+}
+
+/// The capabilities to ask for and the refs to update
+async fn plan<R: RepositoryAccess>(
+    repo: &R,
+    advertisement: &Advertisement,
+    options: &PushOptions,
+) -> Result<(Vec<String>, Vec<PushedRef>), ProtocolError> {
+    let capabilities = capabilities(advertisement, options)?;
+    let refs = commands(repo, advertisement, &options.refspecs).await?;
+    if options.atomic
+        && let Some(rejected) = refs
+            .iter()
+            .find(|pushed| pushed.status == PushStatus::Rejected)
+    {
+        return Err(ProtocolError::invalid_request(&format!(
+            "Atomic push failed: the update of {} is rejected",
+            rejected.dst
+        )));
+    }
+    Ok((capabilities, refs))
+}
+
+/// The capabilities of the first command, those of `advertisement` that `options` asks for
+fn capabilities(
+    advertisement: &Advertisement,
+    options: &PushOptions,
+) -> Result<Vec<String>, ProtocolError> {
+    let unsupported =
+        |name: &str| ProtocolError::invalid_request(&format!("The remote doesn't support {name}"));
+    let mut capabilities = Vec::new();
+    match ["report-status-v2", "report-status"]
+        .into_iter()
+        .find(|report| advertisement.has_capability(report))
+    {
+        Some(report) => capabilities.push(report.to_string()),
+        None => return Err(unsupported("report-status")),
+    }
+    if advertisement.has_capability("side-band-64k") {
+        capabilities.push("side-band-64k".to_string());
+    }
+    if advertisement.has_capability("ofs-delta") {
+        capabilities.push("ofs-delta".to_string());
+    }
+    if options.atomic {
+        match advertisement.has_capability("atomic") {
+            true => capabilities.push("atomic".to_string()),
+            false => return Err(unsupported("atomic pushes")),
+        }
+    }
+    if !options.push_options.is_empty() {
+        match advertisement.has_capability("push-options") {
+            true => capabilities.push("push-options".to_string()),
+            false => return Err(unsupported("push options")),
+        }
+    }
+    capabilities.push(format!("agent={DEFAULT_AGENT}"));
+    if let Some(object_format) = advertisement.capability("object-format") {
+        capabilities.push(format!("object-format={object_format}"));
+    }
+    Ok(capabilities)
+}
+
+/// The refs of the remote `refspecs` map the local refs to, with the status their update
+/// starts with: [`PushStatus::Ok`] for those to send
+async fn commands<R: RepositoryAccess>(
+    repo: &R,
+    advertisement: &Advertisement,
+    refspecs: &[Refspec],
+) -> Result<Vec<PushedRef>, ProtocolError> {
+    let local_refs: HashMap<String, String> =
+        repo.get_repository_refs().await?.into_iter().collect();
+    let mut local_names: Vec<&str> = local_refs.keys().map(String::as_str).collect();
+    local_names.sort();
+    let remote_refs: HashMap<&str, SHA1> = advertisement
+        .refs
+        .iter()
+        .map(|remote_ref| (remote_ref.name.as_str(), remote_ref.id))
+        .collect();
+
+    // `refs/heads/main` pushes to the ref of the same name
+    let mut mapping_refspecs = Vec::new();
+    let mut deletions = Vec::new();
+    for refspec in refspecs {
+        if refspec.src().is_empty() {
+            deletions.extend(refspec.dst().map(str::to_string));
+            continue;
+        }
+        if !refspec.is_negative()
+            && !refspec.is_pattern()
+            && !local_refs.contains_key(refspec.src())
+        {
+            return Err(ProtocolError::invalid_request(&format!(
+                "src refspec {} does not match any local ref",
+                refspec.src()
+            )));
+        }
+        match refspec.dst() {
+            Some(_) => mapping_refspecs.push(refspec.clone()),
+            None if refspec.is_negative() => mapping_refspecs.push(refspec.clone()),
+            None => mapping_refspecs.push(
+                Refspec::parse(&format!("{refspec}:{}", refspec.src()))
+                    .map_err(|e| ProtocolError::invalid_request(&e.to_string()))?,
+            ),
+        }
+    }
+
+    let mut refs = Vec::new();
+    let mut generations = GenerationNumbers::new();
+    for mapping in map_refs(&mapping_refspecs, local_names) {
+        let new: SHA1 = local_refs[&mapping.src]
+            .parse()
+            .map_err(|e: String| ProtocolError::invalid_request(&e))?;
+        let old = remote_refs.get(mapping.dst.as_str()).copied();
+        let status = match old {
+            None => PushStatus::Ok,
+            Some(old) if old == new => PushStatus::UpToDate,
+            Some(_) if mapping.force => PushStatus::Ok,
+            Some(_) if mapping.dst.starts_with("refs/tags/") => PushStatus::Rejected,
+            Some(old) if is_fast_forward(repo, old, new, &mut generations).await? => PushStatus::Ok,
+            Some(_) => PushStatus::Rejected,
+        };
+        refs.push(PushedRef {
+            src: Some(mapping.src),
+            dst: mapping.dst,
+            old,
+            new: Some(new),
+            status,
+        });
+    }
+    for dst in deletions {
+        // Nothing to delete when the remote doesn't have the ref
+        let Some(&old) = remote_refs.get(dst.as_str()) else {
+            continue;
+        };
+        let status = match advertisement.has_capability("delete-refs") {
+            true => PushStatus::Ok,
+            false => PushStatus::Rejected,
+        };
+        refs.push(PushedRef {
+            src: None,
+            dst,
+            old: Some(old),
+            new: None,
+            status,
+        });
+    }
+    Ok(refs)
+}
+
+/// The advertised values of the remote that are in `repo`, which the pack leaves out with
+/// everything they reach
+async fn haves<R: RepositoryAccess>(
+    repo: &R,
+    advertisement: &Advertisement,
+) -> Result<Vec<String>, ProtocolError> {
+    let mut haves = Vec::new();
+    for remote_ref in &advertisement.refs {
+        let id = remote_ref.id.to_string();
+        if !haves.contains(&id)
+            && matches!(
+                load_object(repo, &remote_ref.id).await?,
+                Some((ObjectType::Commit, _))
+            )
+        {
+            haves.push(id);
+        }
+    }
+    Ok(haves)
+}
+
+/// Read a `report-status` or `report-status-v2` report, returning the status of each ref
+///
+/// Fails when the remote couldn't unpack the pack.
+async fn read_report<S>(
+    report: &mut PktLineReader<S>,
+) -> Result<HashMap<String, Result<(), String>>, ProtocolError>
+where
+    S: futures::Stream<Item = Result<bytes::Bytes, ProtocolError>> + Unpin,
+{
+    let invalid =
+        |line: &str| ProtocolError::invalid_request(&format!("Invalid report line '{line}'"));
+    let mut statuses = HashMap::new();
+    let mut unpacked = false;
+    while let Some(pkt_line) = report.next().await.transpose()? {
+        let PktLine::Data(line) = pkt_line else {
+            break;
+        };
+        let line = String::from_utf8_lossy(&line);
+        let line = line.strip_suffix('\n').unwrap_or(&line);
+        if let Some(unpack) = line.strip_prefix("unpack ") {
+            if unpack != "ok" {
+                return Err(ProtocolError::Remote(format!("unpack failed: {unpack}")));
+            }
+            unpacked = true;
+        } else if let Some(name) = line.strip_prefix("ok ") {
+            statuses.insert(name.to_string(), Ok(()));
+        } else if let Some(rest) = line.strip_prefix("ng ") {
+            let (name, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+            statuses.insert(name.to_string(), Err(reason.to_string()));
+        } else if line.starts_with("option ") {
+            // How the remote rewrote the ref of the previous line, in report-status-v2
+        } else {
+            return Err(invalid(line));
+        }
+    }
+    if !unpacked {
+        return Err(ProtocolError::invalid_request(
+            "Missing unpack status in the report",
+        ));
+    }
+    Ok(statuses)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio_util::io::ReaderStream;
+
+    use super::*;
+    use crate::client::transport::ConnectionTransport;
+    use crate::internal::object::commit::Commit;
+    use crate::protocol::memory::MemoryRepository;
+    use crate::protocol::smart::SmartProtocol;
+    use crate::protocol::smart::tests::{TestAuth, build_test_pack};
+    use crate::protocol::ssh::serve_git_command;
+    use crate::protocol::types::{ProtocolStream, TransportProtocol};
+
+    /// Push to the receive-pack of `remote` over an in-process connection
+    async fn push_to(
+        remote: &MemoryRepository,
+        repo: &MemoryRepository,
+        refspecs: &[&str],
+        atomic: bool,
+    ) -> Result<PushResult, ProtocolError> {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let (client_read, client_write) = tokio::io::split(client);
+        let (server_read, server_write) = tokio::io::split(server);
+        let serve = async {
+            let mut smart = SmartProtocol::new(TransportProtocol::Ssh, remote.clone(), TestAuth);
+            let input: ProtocolStream = Box::pin(
+                ReaderStream::new(server_read).map(|chunk| chunk.map_err(ProtocolError::from)),
+            );
+            serve_git_command(&mut smart, ServiceType::ReceivePack, input, server_write).await
+        };
+        let options = PushOptions {
+            refspecs: refspecs.iter().map(|spec| spec.parse().unwrap()).collect(),
+            atomic,
+            ..PushOptions::default()
+        };
+        let mut transport = ConnectionTransport::new(client_read, client_write);
+        let (served, pushed) = tokio::join!(serve, push(repo, &mut transport, &options));
+        served.unwrap();
+        pushed
+    }
+
+    fn statuses(result: &PushResult) -> Vec<(&str, PushStatus)> {
+        let mut statuses: Vec<_> = result
+            .refs
+            .iter()
+            .map(|pushed| (pushed.dst.as_str(), pushed.status.clone()))
+            .collect();
+        statuses.sort_by_key(|(dst, _)| *dst);
+        statuses
+    }
+
+    #[tokio::test]
+    async fn test_push() {
+        let repo = MemoryRepository::new();
+        let (root, pack) = build_test_pack().await;
+        repo.store_pack_data(&pack).await.unwrap();
+        let child = |parent: &Commit, message: &str| {
+            let commit = Commit::new(
+                parent.author.clone(),
+                parent.committer.clone(),
+                parent.tree_id,
+                vec![parent.id],
+                message,
+            );
+            repo.insert_object(&commit).unwrap();
+            commit
+        };
+        let second = child(&root, "second");
+        repo.set_ref("refs/heads/main", second.id);
+        repo.set_ref("refs/heads/topic", root.id);
+
+        // Every branch to an empty remote
+        let remote = MemoryRepository::new();
+        let result = push_to(&remote, &repo, &["refs/heads/*:refs/heads/*"], false)
+            .await
+            .unwrap();
+        assert_eq!(
+            statuses(&result),
+            vec![
+                ("refs/heads/main", PushStatus::Ok),
+                ("refs/heads/topic", PushStatus::Ok),
+            ]
+        );
+        assert_eq!(remote.get_ref("refs/heads/main"), Some(second.id));
+        assert!(remote.has_object(&root.tree_id.to_string()).await.unwrap());
+
+        // A fast-forward, sending the new commit only
+        let third = child(&second, "third");
+        repo.set_ref("refs/heads/main", third.id);
+        let result = push_to(&remote, &repo, &["refs/heads/*:refs/heads/*"], false)
+            .await
+            .unwrap();
+        assert_eq!(
+            statuses(&result),
+            vec![
+                ("refs/heads/main", PushStatus::Ok),
+                ("refs/heads/topic", PushStatus::UpToDate),
+            ]
+        );
+        assert_eq!(remote.get_ref("refs/heads/main"), Some(third.id));
+
+        // A rewritten branch, only pushed when forced
+        let rewritten = child(&root, "rewritten");
+        repo.set_ref("refs/heads/main", rewritten.id);
+        let result = push_to(&remote, &repo, &["refs/heads/main"], false)
+            .await
+            .unwrap();
+        assert_eq!(
+            statuses(&result),
+            vec![("refs/heads/main", PushStatus::Rejected)]
+        );
+        let atomic = push_to(&remote, &repo, &["refs/heads/main"], true).await;
+        assert!(matches!(atomic, Err(ProtocolError::InvalidRequest(_))));
+        assert_eq!(remote.get_ref("refs/heads/main"), Some(third.id));
+        let result = push_to(&remote, &repo, &["+refs/heads/main"], true)
+            .await
+            .unwrap();
+        assert_eq!(statuses(&result), vec![("refs/heads/main", PushStatus::Ok)]);
+        assert_eq!(remote.get_ref("refs/heads/main"), Some(rewritten.id));
+
+        // A deletion, and a source that doesn't exist
+        let result = push_to(&remote, &repo, &[":refs/heads/topic"], false)
+            .await
+            .unwrap();
+        assert_eq!(
+            statuses(&result),
+            vec![("refs/heads/topic", PushStatus::Ok)]
+        );
+        assert_eq!(remote.get_ref("refs/heads/topic"), None);
+        let missing = push_to(&remote, &repo, &["refs/heads/gone"], false).await;
+        assert!(matches!(missing, Err(ProtocolError::InvalidRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn test_read_report() {
+        let report = |lines: &[&str]| {
+            let mut report = BytesMut::new();
+            for line in lines {
+                add_pkt_line_string(&mut report, format!("{line}\n"));
+            }
+            report.put(&PKT_LINE_END_MARKER[..]);
+            let report: Bytes = report.freeze();
+            PktLineReader::new(futures::stream::iter([Ok::<_, ProtocolError>(report)]))
+        };
+        let statuses = read_report(&mut report(&[
+            "unpack ok",
+            "ok refs/heads/main",
+            "option refname refs/heads/main",
+            "ng refs/heads/protected protected branch hook declined",
+        ]))
+        .await
+        .unwrap();
+        assert_eq!(statuses["refs/heads/main"], Ok(()));
+        assert_eq!(
+            statuses["refs/heads/protected"],
+            Err("protected branch hook declined".to_string())
+        );
+
+        let failed = read_report(&mut report(&["unpack index-pack failed"])).await;
+        assert!(matches!(failed, Err(ProtocolError::Remote(_))));
+        let missing = read_report(&mut report(&["ok refs/heads/main"])).await;
+        assert!(matches!(missing, Err(ProtocolError::InvalidRequest(_))));
+    }
+}
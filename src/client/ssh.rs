@@ -0,0 +1,374 @@
+//! Git over SSH with a russh client, behind the `russh` feature.
+//!
+//! [`SshTransport`] connects to the SSH server of a remote, authenticates, and executes the
+//! git command of the service on a session channel, `git-upload-pack '<path>'` as git does,
+//! sending the protocol version in the [`GIT_PROTOCOL_ENV`] variable; servers not accepting
+//! the variable answer in protocol v0. The channel then carries the pkt-lines of the service
+//! like any [`ConnectionTransport`].
+use std::sync::Arc;
+
+use ::russh::client::{self, Handle, Msg};
+use ::russh::keys::agent::client::AgentClient;
+use ::russh::keys::{PrivateKey, PrivateKeyWithHashAlg, PublicKey, check_known_hosts};
+use ::russh::{ChannelStream, Disconnect};
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::io::{ReadHalf, WriteHalf};
+
+use super::transport::{ConnectionTransport, Transport};
+use crate::protocol::ssh::{GIT_PROTOCOL_ENV, GitSshCommand};
+use crate::protocol::types::{ProtocolError, ProtocolStream, ProtocolVersion, ServiceType};
+
+/// The port of SSH servers.
+pub const DEFAULT_PORT: u16 = 22;
+
+/// How [`SshTransport`] authenticates.
+#[derive(Debug, Clone)]
+pub enum SshAuth {
+    /// With the keys of the SSH agent at `SSH_AUTH_SOCK`, the default
+    Agent,
+    PrivateKey(Arc<PrivateKey>),
+    Password(String),
+}
+
+/// Whether the key of `host` on `port` is the one of the server.
+pub type HostKeyCheck = Arc<dyn Fn(&str, u16, &PublicKey) -> bool + Send + Sync>;
+
+/// A [`Transport`] over SSH, see the [module documentation](self).
+pub struct SshTransport {
+    host: String,
+    port: u16,
+    user: String,
+    /// The repository argument of the command, e.g. `org/project.git`
+    repo_path: String,
+    auth: SshAuth,
+    host_key_check: HostKeyCheck,
+    config: Arc<client::Config>,
+    session: Option<Handle<HostKeyHandler>>,
+    channel: Option<ConnectionTransport<WriteHalf<ChannelStream<Msg>>>>,
+}
+
+impl SshTransport {
+    pub fn new(host: &str, port: u16, user: &str, repo_path: &str) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            user: user.to_string(),
+            repo_path: repo_path.to_string(),
+            auth: SshAuth::Agent,
+            host_key_check: Arc::new(|host, port, key| {
+                check_known_hosts(host, port, key).unwrap_or(false)
+            }),
+            config: Arc::new(client::Config::default()),
+            session: None,
+            channel: None,
+        }
+    }
+
+    pub fn set_auth(&mut self, auth: SshAuth) {
+        self.auth = auth;
+    }
+
+    /// Accept the server keys for which `check` returns true, instead of those of
+    /// `~/.ssh/known_hosts`.
+    pub fn set_host_key_check(&mut self, check: HostKeyCheck) {
+        self.host_key_check = check;
+    }
+
+    /// Connect with `config`, e.g. one with other algorithms or an inactivity timeout.
+    pub fn set_config(&mut self, config: Arc<client::Config>) {
+        self.config = config;
+    }
+
+    async fn open_session(&self) -> Result<Handle<HostKeyHandler>, ProtocolError> {
+        let handler = HostKeyHandler {
+            host: self.host.clone(),
+            port: self.port,
+            check: self.host_key_check.clone(),
+        };
+        let address = (self.host.as_str(), self.port);
+        let mut session = client::connect(self.config.clone(), address, handler)
+            .await
+            .map_err(ssh_error)?;
+        let authenticated = match &self.auth {
+            SshAuth::Agent => authenticate_with_agent(&mut session, &self.user).await?,
+            SshAuth::PrivateKey(key) => {
+                let hash_alg = session
+                    .best_supported_rsa_hash()
+                    .await
+                    .map_err(ssh_error)?
+                    .flatten();
+                let key = PrivateKeyWithHashAlg::new(key.clone(), hash_alg);
+                session
+                    .authenticate_publickey(self.user.as_str(), key)
+                    .await
+                    .map_err(ssh_error)?
+                    .success()
+            }
+            SshAuth::Password(password) => session
+                .authenticate_password(self.user.as_str(), password.as_str())
+                .await
+                .map_err(ssh_error)?
+                .success(),
+        };
+        if !authenticated {
+            return Err(ProtocolError::auth_failed(
+                Some(&self.user),
+                &format!("{}@{} refused the authentication", self.user, self.host),
+            ));
+        }
+        Ok(session)
+    }
+}
+
+/// Try each key of the SSH agent, as OpenSSH does.
+async fn authenticate_with_agent(
+    session: &mut Handle<HostKeyHandler>,
+    user: &str,
+) -> Result<bool, ProtocolError> {
+    let mut agent = AgentClient::connect_env().await.map_err(ssh_error)?;
+    let keys = agent.request_identities().await.map_err(ssh_error)?;
+    let hash_alg = session
+        .best_supported_rsa_hash()
+        .await
+        .map_err(ssh_error)?
+        .flatten();
+    for key in keys {
+        let result = session
+            .authenticate_publickey_with(user, key, hash_alg, &mut agent)
+            .await
+            .map_err(ssh_error)?;
+        if result.success() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn ssh_error(e: impl std::error::Error + Send + Sync + 'static) -> ProtocolError {
+    ProtocolError::Io(std::io::Error::other(e))
+}
+
+/// The client handler of the session, checking the key of the server
+struct HostKeyHandler {
+    host: String,
+    port: u16,
+    check: HostKeyCheck,
+}
+
+impl client::Handler for HostKeyHandler {
+    type Error = ::russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &PublicKey,
+    ) -> Result<bool, Self::Error> {
+        Ok((self.check)(&self.host, self.port, server_public_key))
+    }
+}
+
+#[async_trait]
+impl Transport for SshTransport {
+    async fn connect(
+        &mut self,
+        service: ServiceType,
+        version: ProtocolVersion,
+    ) -> Result<Bytes, ProtocolError> {
+        let session = match self.session.take() {
+            Some(session) => session,
+            None => self.open_session().await?,
+        };
+        let channel = session.channel_open_session().await.map_err(ssh_error)?;
+        self.session = Some(session);
+        match version {
+            ProtocolVersion::V0 => {}
+            ProtocolVersion::V1 => channel
+                .set_env(false, GIT_PROTOCOL_ENV, "version=1")
+                .await
+                .map_err(ssh_error)?,
+            ProtocolVersion::V2 => channel
+                .set_env(false, GIT_PROTOCOL_ENV, "version=2")
+                .await
+                .map_err(ssh_error)?,
+        }
+        let command = GitSshCommand {
+            service,
+            repo_path: self.repo_path.clone(),
+        };
+        channel
+            .exec(false, command.to_command_line())
+            .await
+            .map_err(ssh_error)?;
+        let (reader, writer): (ReadHalf<_>, _) = tokio::io::split(channel.into_stream());
+        let channel = self
+            .channel
+            .insert(ConnectionTransport::new(reader, writer));
+        channel.connect(service, version).await
+    }
+
+    async fn request(&mut self, request: Bytes) -> Result<ProtocolStream, ProtocolError> {
+        match &mut self.channel {
+            Some(channel) => channel.request(request).await,
+            None => Err(ProtocolError::invalid_request("Not connected")),
+        }
+    }
+
+    async fn request_stream(
+        &mut self,
+        request: ProtocolStream,
+    ) -> Result<ProtocolStream, ProtocolError> {
+        match &mut self.channel {
+            Some(channel) => channel.request_stream(request).await,
+            None => Err(ProtocolError::invalid_request("Not connected")),
+        }
+    }
+
+    async fn close(&mut self) -> Result<(), ProtocolError> {
+        if let Some(mut channel) = self.channel.take() {
+            channel.close().await?;
+        }
+        if let Some(session) = self.session.take() {
+            session
+                .disconnect(Disconnect::ByApplication, "", "")
+                .await
+                .map_err(ssh_error)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::russh::keys::ssh_key::private::Ed25519Keypair;
+    use ::russh::server::{self, Auth, Session};
+    use ::russh::{Channel, ChannelId};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::client::discovery::discover;
+    use crate::protocol::core::RepositoryAccess;
+    use crate::protocol::memory::MemoryRepository;
+    use crate::protocol::smart::SmartProtocol;
+    use crate::protocol::smart::tests::{TestAuth, build_test_pack};
+    use crate::protocol::ssh::russh::serve_channel;
+    use crate::protocol::types::TransportProtocol;
+
+    /// A russh server of `repo` for user `git` with password `secret`
+    #[derive(Clone)]
+    struct TestServer {
+        repo: MemoryRepository,
+    }
+
+    impl server::Handler for TestServer {
+        type Error = ::russh::Error;
+
+        async fn auth_password(&mut self, user: &str, password: &str) -> Result<Auth, Self::Error> {
+            match (user, password) {
+                ("git", "secret") => Ok(Auth::Accept),
+                _ => Ok(Auth::reject()),
+            }
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            channel: Channel<server::Msg>,
+            _session: &mut Session,
+        ) -> Result<bool, Self::Error> {
+            let repo = self.repo.clone();
+            tokio::spawn(serve_channel(channel, |command| async move {
+                assert_eq!(command.repo_path, "org/project.git");
+                Ok(SmartProtocol::new(TransportProtocol::Ssh, repo, TestAuth))
+            }));
+            Ok(true)
+        }
+
+        async fn env_request(
+            &mut self,
+            channel: ChannelId,
+            _variable_name: &str,
+            _variable_value: &str,
+            session: &mut Session,
+        ) -> Result<(), Self::Error> {
+            session.channel_success(channel)
+        }
+
+        async fn exec_request(
+            &mut self,
+            channel: ChannelId,
+            _data: &[u8],
+            session: &mut Session,
+        ) -> Result<(), Self::Error> {
+            session.channel_success(channel)
+        }
+    }
+
+    /// Serve `repo` on a local port, returned with the key of the server
+    async fn serve(repo: MemoryRepository) -> (u16, PublicKey) {
+        let key = PrivateKey::from(Ed25519Keypair::from_seed(&[7; 32]));
+        let public_key = key.public_key().clone();
+        let config = Arc::new(server::Config {
+            keys: vec![key],
+            ..Default::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let handler = TestServer { repo: repo.clone() };
+                let session = server::run_stream(config.clone(), stream, handler).await;
+                tokio::spawn(async move { session.unwrap().await });
+            }
+        });
+        (port, public_key)
+    }
+
+    #[tokio::test]
+    async fn test_ssh_transport() {
+        let repo = MemoryRepository::new();
+        let (root, pack) = build_test_pack().await;
+        repo.store_pack_data(&pack).await.unwrap();
+        repo.set_ref("refs/heads/main", root.id);
+        let (port, server_key) = serve(repo).await;
+        let transport = |password: &str| {
+            let mut transport = SshTransport::new("127.0.0.1", port, "git", "org/project.git");
+            transport.set_auth(SshAuth::Password(password.to_string()));
+            let server_key = server_key.clone();
+            transport.set_host_key_check(Arc::new(move |_, _, key| *key == server_key));
+            transport
+        };
+
+        for version in [ProtocolVersion::V0, ProtocolVersion::V2] {
+            let mut transport = transport("secret");
+            let advertisement = discover(&mut transport, ServiceType::UploadPack, version, &[])
+                .await
+                .unwrap();
+            transport.close().await.unwrap();
+            let main = advertisement.find_ref("refs/heads/main").unwrap();
+            assert_eq!(main.id, root.id);
+        }
+
+        let mut refused = transport("wrong");
+        let error = discover(
+            &mut refused,
+            ServiceType::UploadPack,
+            ProtocolVersion::V2,
+            &[],
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            error,
+            ProtocolError::AuthFailed { user: Some(user), .. } if user == "git"
+        ));
+
+        let mut unknown = transport("secret");
+        unknown.set_host_key_check(Arc::new(|_, _, _| false));
+        let connected = discover(
+            &mut unknown,
+            ServiceType::UploadPack,
+            ProtocolVersion::V2,
+            &[],
+        );
+        assert!(connected.await.is_err());
+    }
+}
@@ -0,0 +1,223 @@
+//! The connections of the client to a remote repository.
+//!
+//! A [`Transport`] starts a service of the remote and carries its requests and responses:
+//! [`connect`](Transport::connect) returns the advertisement the service opens with, and each
+//! [`request`](Transport::request) sends pkt-lines and returns the response. Over smart HTTP,
+//! every response ends with its HTTP response; over a connection there is no such end, so a
+//! response is read up to the flush-pkt closing it, which every response the client waits for
+//! ends with: ref advertisements, `ls-refs` output, and packs and reports in a side-band.
+//!
+//! [`ConnectionTransport`] runs services over any byte stream, the channel of an SSH command
+//! or a `git://` connection; [`HttpTransport`](super::http::HttpTransport) speaks smart HTTP.
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Mutex;
+use tokio_util::codec::Encoder;
+use tokio_util::io::ReaderStream;
+
+use crate::protocol::codec::{PktLineCodec, PktLineReader};
+use crate::protocol::daemon::DaemonRequest;
+use crate::protocol::types::{
+    PKT_LINE_END_MARKER, ProtocolError, ProtocolStream, ProtocolVersion, ServiceType,
+};
+use crate::protocol::utils::{PktLine, add_pkt_line_string};
+
+/// A connection to a service of a remote repository, see the [module documentation](self).
+#[async_trait]
+pub trait Transport: Send {
+    /// Start `service`, asking for protocol `version`, and return the advertisement the
+    /// service opens with: its refs, or its capabilities in protocol v2.
+    async fn connect(
+        &mut self,
+        service: ServiceType,
+        version: ProtocolVersion,
+    ) -> Result<Bytes, ProtocolError>;
+
+    /// Send `request` to the service started by `connect` and return the response.
+    async fn request(&mut self, request: Bytes) -> Result<ProtocolStream, ProtocolError>;
+
+    /// Send the chunks of `request` as they come, e.g. commands followed by a pack, and return
+    /// the response like [`request`](Self::request).
+    ///
+    /// Default implementation collects the chunks and sends them with `request`.
+    async fn request_stream(
+        &mut self,
+        mut request: ProtocolStream,
+    ) -> Result<ProtocolStream, ProtocolError> {
+        let mut collected = BytesMut::new();
+        while let Some(chunk) = request.next().await {
+            collected.extend_from_slice(&chunk?);
+        }
+        self.request(collected.freeze()).await
+    }
+
+    /// Whether each request is served on its own, like over smart HTTP, so it has to repeat
+    /// what the service was told before, instead of in a session remembering it.
+    ///
+    /// Default implementation returns false, like connections.
+    fn is_stateless(&self) -> bool {
+        false
+    }
+
+    /// End the session, once the client is done with the service.
+    ///
+    /// Default implementation does nothing; connections tell the service with a flush-pkt.
+    async fn close(&mut self) -> Result<(), ProtocolError> {
+        Ok(())
+    }
+}
+
+/// A [`Transport`] over a byte stream, see the [module documentation](self).
+///
+/// The stream either already runs the service, like the channel of an SSH `exec` request for
+/// `git-upload-pack '<path>'`, or is a `git://` connection, to which
+/// [`set_daemon_request`](Self::set_daemon_request) has the request line naming the service
+/// sent first.
+pub struct ConnectionTransport<W> {
+    reader: Arc<Mutex<PktLineReader<ProtocolStream>>>,
+    writer: W,
+    daemon_request: Option<(String, Option<String>)>,
+}
+
+impl<W: AsyncWrite + Send + Unpin> ConnectionTransport<W> {
+    pub fn new<R: AsyncRead + Send + Unpin + 'static>(reader: R, writer: W) -> Self {
+        let stream: ProtocolStream =
+            Box::pin(ReaderStream::new(reader).map(|chunk| chunk.map_err(ProtocolError::from)));
+        Self {
+            reader: Arc::new(Mutex::new(PktLineReader::new(stream))),
+            writer,
+            daemon_request: None,
+        }
+    }
+
+    /// Open the connection with a `git://` request line for the repository `repo_path` on
+    /// `host`, the host and port of the URL.
+    pub fn set_daemon_request(&mut self, repo_path: &str, host: Option<&str>) {
+        self.daemon_request = Some((repo_path.to_string(), host.map(str::to_string)));
+    }
+}
+
+#[async_trait]
+impl<W: AsyncWrite + Send + Unpin> Transport for ConnectionTransport<W> {
+    async fn connect(
+        &mut self,
+        service: ServiceType,
+        version: ProtocolVersion,
+    ) -> Result<Bytes, ProtocolError> {
+        if let Some((repo_path, host)) = &self.daemon_request {
+            let extra_parameters = match version {
+                ProtocolVersion::V0 => vec![],
+                ProtocolVersion::V1 => vec!["version=1".to_string()],
+                ProtocolVersion::V2 => vec!["version=2".to_string()],
+            };
+            let request = DaemonRequest {
+                service,
+                repo_path: repo_path.clone(),
+                host: host.clone(),
+                extra_parameters,
+            };
+            let mut line = BytesMut::new();
+            add_pkt_line_string(&mut line, request.to_line());
+            self.writer.write_all(&line).await?;
+            self.writer.flush().await?;
+        }
+        let mut reader = self.reader.clone().lock_owned().await;
+        let mut advertisement = BytesMut::new();
+        while let Some(pkt_line) = reader.next().await.transpose()? {
+            let end = pkt_line == PktLine::Flush;
+            // A failing service answers with a single `ERR` line
+            let error = matches!(&pkt_line, PktLine::Data(data) if data.starts_with(b"ERR "));
+            PktLineCodec.encode(pkt_line, &mut advertisement)?;
+            if end || error {
+                break;
+            }
+        }
+        Ok(advertisement.freeze())
+    }
+
+    async fn request(&mut self, request: Bytes) -> Result<ProtocolStream, ProtocolError> {
+        self.request_stream(Box::pin(futures::stream::once(async { Ok(request) })))
+            .await
+    }
+
+    async fn request_stream(
+        &mut self,
+        mut request: ProtocolStream,
+    ) -> Result<ProtocolStream, ProtocolError> {
+        while let Some(chunk) = request.next().await {
+            self.writer.write_all(&chunk?).await?;
+        }
+        self.writer.flush().await?;
+        // The next request waits for this response to be read
+        let reader = self.reader.clone().lock_owned().await;
+        let response = futures::stream::try_unfold(Some(reader), |reader| async move {
+            let Some(mut reader) = reader else {
+                return Ok(None);
+            };
+            let Some(pkt_line) = reader.next().await.transpose()? else {
+                return Ok(None);
+            };
+            let end = pkt_line == PktLine::Flush;
+            let mut packet = BytesMut::new();
+            PktLineCodec.encode(pkt_line, &mut packet)?;
+            Ok(Some((packet.freeze(), (!end).then_some(reader))))
+        });
+        Ok(Box::pin(response))
+    }
+
+    async fn close(&mut self) -> Result<(), ProtocolError> {
+        // The service may be gone already, e.g. upload-pack after sending a pack
+        match self.writer.write_all(PKT_LINE_END_MARKER).await {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => return Ok(()),
+            written => written?,
+        }
+        let _ = self.writer.shutdown().await;
+        Ok(())
+    }
+}
+
+/// `reader` with `pkt_line` read again first, for a response whose end starts at a pkt-line
+/// the client read to find that end.
+pub(crate) fn unread(
+    reader: PktLineReader<ProtocolStream>,
+    pkt_line: PktLine,
+) -> Result<PktLineReader<ProtocolStream>, ProtocolError> {
+    let mut head = BytesMut::new();
+    PktLineCodec.encode(pkt_line, &mut head)?;
+    let (buffered, rest) = reader.into_parts();
+    head.extend_from_slice(&buffered);
+    let head = futures::stream::once(async move { Ok(head.freeze()) });
+    Ok(PktLineReader::new(Box::pin(head.chain(rest))))
+}
+
+/// The data of the side-band packets of `response` in band 1, up to the flush-pkt ending it.
+///
+/// Progress messages in band 2 are logged, and an error in band 3 fails the stream.
+pub(crate) fn side_band_data(response: PktLineReader<ProtocolStream>) -> ProtocolStream {
+    let data = futures::stream::try_unfold(response, |mut response| async move {
+        loop {
+            let packet = match response.next().await.transpose()? {
+                Some(PktLine::Data(packet)) => packet,
+                Some(PktLine::Flush) | None => return Ok(None),
+                Some(_) => continue,
+            };
+            let message = || String::from_utf8_lossy(&packet[1..]).trim_end().to_string();
+            match packet.first() {
+                Some(1) => return Ok(Some((packet.slice(1..), response))),
+                Some(2) => tracing::info!("remote: {}", message()),
+                Some(3) => return Err(ProtocolError::Remote(message())),
+                _ => {
+                    return Err(ProtocolError::invalid_request(&format!(
+                        "Invalid side-band packet: {}",
+                        String::from_utf8_lossy(&packet)
+                    )));
+                }
+            }
+        }
+    });
+    Box::pin(data)
+}
@@ -0,0 +1,217 @@
+//! The URLs of remote repositories, and the transports reaching them.
+//!
+//! [`RemoteUrl`] parses the URLs git accepts for remotes: `http://` and `https://` for smart
+//! HTTP, `ssh://[<user>@]<host>[:<port>]/<path>` and its scp-like form `[<user>@]<host>:<path>`,
+//! and `git://<host>[:<port>]/<path>`. SSH URLs are reached with
+//! `client::ssh::SshTransport`, behind the `russh` feature.
+use tokio::net::TcpStream;
+
+use super::http::HttpTransport;
+use super::transport::{ConnectionTransport, Transport};
+use crate::protocol::daemon::DEFAULT_PORT;
+use crate::protocol::types::ProtocolError;
+
+/// A parsed remote URL, see the [module documentation](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RemoteUrl {
+    Http {
+        url: String,
+    },
+    Ssh {
+        user: Option<String>,
+        host: String,
+        port: Option<u16>,
+        /// The repository argument of the command, e.g. `org/project.git` or `~/project.git`
+        path: String,
+    },
+    Git {
+        host: String,
+        port: Option<u16>,
+        path: String,
+    },
+}
+
+impl RemoteUrl {
+    pub fn parse(url: &str) -> Result<Self, ProtocolError> {
+        let invalid = |reason: &str| {
+            ProtocolError::invalid_request(&format!("Invalid remote URL '{url}': {reason}"))
+        };
+        if url.starts_with("http://") || url.starts_with("https://") {
+            return Ok(RemoteUrl::Http {
+                url: url.to_string(),
+            });
+        }
+        if let Some((scheme, rest)) = url.split_once("://") {
+            let (authority, path) = match rest.find('/') {
+                Some(slash) => rest.split_at(slash),
+                None => return Err(invalid("missing repository path")),
+            };
+            let (user, host_port) = match authority.rsplit_once('@') {
+                Some((user, host_port)) => (Some(user.to_string()), host_port),
+                None => (None, authority),
+            };
+            let (host, port) = split_port(host_port).map_err(|_| invalid("invalid port"))?;
+            if host.is_empty() {
+                return Err(invalid("missing host"));
+            }
+            return match scheme {
+                "ssh" | "git+ssh" | "ssh+git" => Ok(RemoteUrl::Ssh {
+                    user,
+                    host,
+                    port,
+                    // `ssh://host/~user/project` names a path in the home of `user`
+                    path: match path.strip_prefix("/~") {
+                        Some(home) => format!("~{home}"),
+                        None => path.to_string(),
+                    },
+                }),
+                "git" if user.is_none() => Ok(RemoteUrl::Git {
+                    host,
+                    port,
+                    path: path.to_string(),
+                }),
+                _ => Err(invalid("unsupported scheme")),
+            };
+        }
+        // The scp-like form, a colon before any slash
+        match url.split_once(':') {
+            Some((authority, path)) if !authority.contains('/') && !path.is_empty() => {
+                let (user, host) = match authority.rsplit_once('@') {
+                    Some((user, host)) => (Some(user.to_string()), host),
+                    None => (None, authority),
+                };
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                if host.is_empty() {
+                    return Err(invalid("missing host"));
+                }
+                Ok(RemoteUrl::Ssh {
+                    user,
+                    host: host.to_string(),
+                    port: None,
+                    path: path.to_string(),
+                })
+            }
+            _ => Err(invalid("local repositories aren't supported")),
+        }
+    }
+
+    /// A transport to the repository, connected to its host for `git://` URLs.
+    ///
+    /// SSH transports authenticate with the keys of the SSH agent and check the host key
+    /// against `~/.ssh/known_hosts`, like OpenSSH by default; build a `SshTransport` to
+    /// configure them.
+    pub async fn transport(&self) -> Result<Box<dyn Transport>, ProtocolError> {
+        match self {
+            RemoteUrl::Http { url } => Ok(Box::new(HttpTransport::new(url))),
+            #[cfg(feature = "russh")]
+            RemoteUrl::Ssh {
+                user,
+                host,
+                port,
+                path,
+            } => {
+                let user = match user {
+                    Some(user) => user.clone(),
+                    None => std::env::var("USER").map_err(|_| {
+                        ProtocolError::invalid_request("Missing user in the SSH URL")
+                    })?,
+                };
+                let port = port.unwrap_or(super::ssh::DEFAULT_PORT);
+                Ok(Box::new(super::ssh::SshTransport::new(
+                    host, port, &user, path,
+                )))
+            }
+            #[cfg(not(feature = "russh"))]
+            RemoteUrl::Ssh { .. } => Err(ProtocolError::invalid_request(
+                "SSH URLs need the `russh` feature",
+            )),
+            RemoteUrl::Git { host, port, path } => {
+                let stream =
+                    TcpStream::connect((host.as_str(), port.unwrap_or(DEFAULT_PORT))).await?;
+                let (reader, writer) = stream.into_split();
+                let mut transport = ConnectionTransport::new(reader, writer);
+                let host = match port {
+                    Some(port) => format!("{host}:{port}"),
+                    None => host.clone(),
+                };
+                transport.set_daemon_request(path, Some(&host));
+                Ok(Box::new(transport))
+            }
+        }
+    }
+}
+
+/// Split `host[:port]`, with IPv6 addresses in brackets
+fn split_port(host_port: &str) -> Result<(String, Option<u16>), std::num::ParseIntError> {
+    let (host, port) = match host_port.strip_prefix('[') {
+        Some(bracketed) => match bracketed.split_once(']') {
+            Some((host, rest)) => (host, rest.strip_prefix(':')),
+            None => (host_port, None),
+        },
+        None => match host_port.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (host_port, None),
+        },
+    };
+    let port = port.map(str::parse).transpose()?;
+    Ok((host.to_string(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_remote_url() {
+        let ssh = |user: Option<&str>, host: &str, port, path: &str| RemoteUrl::Ssh {
+            user: user.map(str::to_string),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        };
+        for (url, parsed) in [
+            (
+                "https://example.com/org/project.git",
+                RemoteUrl::Http {
+                    url: "https://example.com/org/project.git".to_string(),
+                },
+            ),
+            (
+                "ssh://git@example.com:2222/org/project.git",
+                ssh(Some("git"), "example.com", Some(2222), "/org/project.git"),
+            ),
+            (
+                "ssh://example.com/~me/project.git",
+                ssh(None, "example.com", None, "~me/project.git"),
+            ),
+            (
+                "ssh://[::1]:22/project.git",
+                ssh(None, "::1", Some(22), "/project.git"),
+            ),
+            (
+                "git@example.com:org/project.git",
+                ssh(Some("git"), "example.com", None, "org/project.git"),
+            ),
+            (
+                "git://example.com:9999/project.git",
+                RemoteUrl::Git {
+                    host: "example.com".to_string(),
+                    port: Some(9999),
+                    path: "/project.git".to_string(),
+                },
+            ),
+        ] {
+            assert_eq!(RemoteUrl::parse(url).unwrap(), parsed, "{url}");
+        }
+        for url in [
+            "/srv/project.git",
+            "./project",
+            "file:///srv/project.git",
+            "ssh://example.com",
+            "ssh://example.com:port/x",
+            "git://me@example.com/x",
+        ] {
+            assert!(RemoteUrl::parse(url).is_err(), "{url}");
+        }
+    }
+}
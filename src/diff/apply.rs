@@ -0,0 +1,740 @@
+//! Applying unified diffs to trees, like `git apply`.
+//!
+//! [`parse_patch`] reads the file patches out of a `git diff` output, a plain `diff -u`, or mail
+//! messages from [`format_patch`](super::patch::format_patch) (everything outside the diffs is
+//! skipped). [`apply_patches`] applies them to a tree and returns the new tree id together with the
+//! blobs and trees that have to be stored for it.
+//!
+//! Hunks are located by their context: a hunk that no longer matches at its line number is
+//! searched for in the rest of the file, as `git apply` does without `--unidiff-zero`. A hunk that
+//! matches nowhere, or a patch creating a file that exists (or changing one that doesn't), is a
+//! [`GitError::PatchConflict`]. Binary patches are not supported.
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use bstr::ByteSlice;
+
+use crate::errors::GitError;
+use crate::hash::SHA1;
+use crate::internal::object::blob::Blob;
+use crate::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+
+use super::text::{DiffLine, Hunk};
+
+const SUBPROJECT_COMMIT: &str = "Subproject commit ";
+
+/// The changes a patch makes to one file.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FilePatch {
+    /// Path before the patch, `None` for a new file.
+    pub old_path: Option<String>,
+    /// Path after the patch, `None` for a deleted file.
+    pub new_path: Option<String>,
+    pub old_mode: Option<TreeItemMode>,
+    pub new_mode: Option<TreeItemMode>,
+    /// `new_path` is created as a copy and `old_path` is kept.
+    pub copy: bool,
+    pub hunks: Vec<Hunk>,
+}
+
+impl FilePatch {
+    /// The path shown in error messages.
+    pub fn path(&self) -> &str {
+        self.new_path
+            .as_deref()
+            .or(self.old_path.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// Paths of a `diff --git a/<old> b/<new>` line. Both paths are equal unless the file is
+    /// renamed, which the `rename from` / `rename to` lines then spell out, so the line is split in
+    /// the middle when that is consistent.
+    fn from_git_header(paths: &str) -> Self {
+        let half = paths.len().saturating_sub(1) / 2;
+        let (old, new) = match (paths.get(..half), paths.get(half..)) {
+            (Some(old), Some(new))
+                if old.strip_prefix("a/").is_some()
+                    && new.strip_prefix(" b/") == old.strip_prefix("a/") =>
+            {
+                (&old[2..], &new[3..])
+            }
+            _ => {
+                let (old, new) = paths.split_once(" b/").unwrap_or((paths, paths));
+                (old.strip_prefix("a/").unwrap_or(old), new)
+            }
+        };
+        Self {
+            old_path: Some(old.to_string()),
+            new_path: Some(new.to_string()),
+            ..Self::default()
+        }
+    }
+}
+
+/// The path of a `---` / `+++` line, `None` for `/dev/null`.
+fn marker_path(path: &str) -> Option<String> {
+    let path = path.split('\t').next().unwrap_or(path);
+    if path == "/dev/null" {
+        return None;
+    }
+    let path = path
+        .strip_prefix("a/")
+        .or_else(|| path.strip_prefix("b/"))
+        .unwrap_or(path);
+    Some(path.to_string())
+}
+
+fn parse_mode(mode: &str) -> Result<TreeItemMode, GitError> {
+    TreeItemMode::tree_item_type_from_bytes(mode.trim().as_bytes())
+        .map_err(|_| GitError::InvalidPatch(format!("invalid file mode `{mode}`")))
+}
+
+/// `@@ -<old_start>,<old_lines> +<new_start>,<new_lines> @@`, lengths default to 1.
+fn parse_hunk_header(line: &str) -> Result<Hunk, GitError> {
+    let invalid = || GitError::InvalidPatch(format!("invalid hunk header `{line}`"));
+    let ranges = line
+        .strip_prefix("@@ -")
+        .and_then(|rest| rest.split_once(" @@"))
+        .ok_or_else(invalid)?
+        .0;
+    let (old, new) = ranges.split_once(" +").ok_or_else(invalid)?;
+    let range = |range: &str| -> Result<(usize, usize), GitError> {
+        let (start, lines) = range.split_once(',').unwrap_or((range, "1"));
+        Ok((
+            start.parse().map_err(|_| invalid())?,
+            lines.parse().map_err(|_| invalid())?,
+        ))
+    };
+    let ((old_start, old_lines), (new_start, new_lines)) = (range(old)?, range(new)?);
+    Ok(Hunk {
+        old_start,
+        old_lines,
+        new_start,
+        new_lines,
+        lines: Vec::new(),
+    })
+}
+
+/// Line without its terminator, with header lines read as UTF-8 (invalid bytes replaced).
+fn header_line(line: &[u8]) -> std::borrow::Cow<'_, str> {
+    String::from_utf8_lossy(line.trim_end_with(|c| matches!(c, '\n' | '\r')))
+}
+
+/// Parse every file patch in `text`, in order. Hunk lines are kept as bytes.
+pub fn parse_patch(text: &[u8]) -> Result<Vec<FilePatch>, GitError> {
+    let mut patches: Vec<FilePatch> = Vec::new();
+    // Between a `diff --git` line and its first hunk, where extended headers are read
+    let mut in_header = false;
+    let mut lines = text.lines_with_terminator().peekable();
+    while let Some(raw) = lines.next() {
+        let line = header_line(raw);
+        let line = line.as_ref();
+        if let Some(paths) = line.strip_prefix("diff --git ") {
+            patches.push(FilePatch::from_git_header(paths));
+            in_header = true;
+            continue;
+        }
+        if let Some(old) = line.strip_prefix("--- ")
+            && let Some(new) = lines.peek().and_then(|next| next.strip_prefix(b"+++ "))
+        {
+            let new = header_line(new);
+            if !in_header {
+                patches.push(FilePatch::default());
+                in_header = true;
+            }
+            let patch = patches.last_mut().expect("patch started above");
+            patch.old_path = marker_path(old);
+            patch.new_path = marker_path(&new);
+            lines.next();
+            continue;
+        }
+        if line.starts_with("@@ ") {
+            let patch = patches
+                .last_mut()
+                .ok_or_else(|| GitError::InvalidPatch(format!("hunk outside a file: `{line}`")))?;
+            let hunk = read_hunk(line, &mut lines, patch.path())?;
+            patch.hunks.push(hunk);
+            in_header = false;
+            continue;
+        }
+        if !in_header {
+            continue;
+        }
+
+        let patch = patches.last_mut().expect("in a file header");
+        if let Some(mode) = line.strip_prefix("new file mode ") {
+            patch.old_path = None;
+            patch.new_mode = Some(parse_mode(mode)?);
+        } else if let Some(mode) = line.strip_prefix("deleted file mode ") {
+            patch.new_path = None;
+            patch.old_mode = Some(parse_mode(mode)?);
+        } else if let Some(mode) = line.strip_prefix("old mode ") {
+            patch.old_mode = Some(parse_mode(mode)?);
+        } else if let Some(mode) = line.strip_prefix("new mode ") {
+            patch.new_mode = Some(parse_mode(mode)?);
+        } else if let Some(path) = line.strip_prefix("rename from ") {
+            patch.old_path = Some(path.to_string());
+        } else if let Some(path) = line.strip_prefix("rename to ") {
+            patch.new_path = Some(path.to_string());
+        } else if let Some(path) = line.strip_prefix("copy from ") {
+            patch.old_path = Some(path.to_string());
+            patch.copy = true;
+        } else if let Some(path) = line.strip_prefix("copy to ") {
+            patch.new_path = Some(path.to_string());
+            patch.copy = true;
+        } else if let Some(index) = line.strip_prefix("index ") {
+            if let Some((_, mode)) = index.split_once(' ') {
+                let mode = parse_mode(mode)?;
+                patch.old_mode.get_or_insert(mode);
+                patch.new_mode.get_or_insert(mode);
+            }
+        } else if line.starts_with("Binary files ") || line == "GIT binary patch" {
+            return Err(GitError::InvalidPatch(format!(
+                "{}: binary patches are not supported",
+                patch.path()
+            )));
+        }
+    }
+    Ok(patches)
+}
+
+/// Read the lines of the hunk starting at `header`.
+fn read_hunk<'a, I>(
+    header: &str,
+    lines: &mut std::iter::Peekable<I>,
+    path: &str,
+) -> Result<Hunk, GitError>
+where
+    I: Iterator<Item = &'a [u8]>,
+{
+    let mut hunk = parse_hunk_header(header)?;
+    let (mut old_left, mut new_left) = (hunk.old_lines, hunk.new_lines);
+    while old_left > 0 || new_left > 0 {
+        let line = lines
+            .next()
+            .ok_or_else(|| GitError::InvalidPatch(format!("{path}: truncated hunk `{header}`")))?;
+        let mut text = line.get(1..).unwrap_or_default().to_vec();
+        if !text.ends_with(b"\n") {
+            text.push(b'\n');
+        }
+        let line = match line.first() {
+            Some(b' ') => DiffLine::Context(text),
+            // Editors strip the trailing space of empty context lines
+            Some(b'\n' | b'\r') => DiffLine::Context(line.to_vec()),
+            Some(b'-') => DiffLine::Deleted(text),
+            Some(b'+') => DiffLine::Added(text),
+            _ => {
+                return Err(GitError::InvalidPatch(format!(
+                    "{path}: unexpected line `{}` in hunk `{header}`",
+                    line.trim_end().as_bstr()
+                )));
+            }
+        };
+        match line {
+            DiffLine::Context(_) if old_left > 0 && new_left > 0 => {
+                old_left -= 1;
+                new_left -= 1;
+            }
+            DiffLine::Deleted(_) if old_left > 0 => old_left -= 1,
+            DiffLine::Added(_) if new_left > 0 => new_left -= 1,
+            _ => {
+                return Err(GitError::InvalidPatch(format!(
+                    "{path}: hunk `{header}` has more lines than its header says"
+                )));
+            }
+        }
+        hunk.lines.push(line);
+        if lines.peek().is_some_and(|next| next.starts_with(b"\\")) {
+            lines.next();
+            if let Some(DiffLine::Context(text) | DiffLine::Deleted(text) | DiffLine::Added(text)) =
+                hunk.lines.last_mut()
+            {
+                text.pop();
+            }
+        }
+    }
+    Ok(hunk)
+}
+
+/// Apply `hunks` to the content of a file.
+pub fn apply_hunks(content: &[u8], hunks: &[Hunk]) -> Result<Vec<u8>, GitError> {
+    let lines: Vec<&[u8]> = content.lines_with_terminator().collect();
+    let mut out = Vec::with_capacity(content.len());
+    // First line not consumed by a previous hunk, and how far the previous hunk had moved
+    let mut pos = 0;
+    let mut offset = 0isize;
+    for (number, hunk) in hunks.iter().enumerate() {
+        let old: Vec<&[u8]> = hunk
+            .lines
+            .iter()
+            .filter_map(|line| match line {
+                DiffLine::Context(text) | DiffLine::Deleted(text) => Some(text.as_slice()),
+                DiffLine::Added(_) => None,
+            })
+            .collect();
+        // A hunk without old lines starts after `old_start`, others at it
+        let stated = hunk
+            .old_start
+            .saturating_sub(usize::from(hunk.old_lines > 0));
+        let last = lines
+            .len()
+            .checked_sub(old.len())
+            .filter(|last| *last >= pos);
+        let start = last.and_then(|last| {
+            let expected = (stated as isize + offset).clamp(pos as isize, last as isize) as usize;
+            (0..=last.max(expected) - pos)
+                .flat_map(|distance| [expected.checked_sub(distance), Some(expected + distance)])
+                .flatten()
+                .filter(|start| (pos..=last).contains(start))
+                .find(|&start| lines[start..start + old.len()] == old[..])
+        });
+        let Some(start) = start else {
+            return Err(GitError::PatchConflict(format!(
+                "hunk #{} at line {} does not match",
+                number + 1,
+                hunk.old_start
+            )));
+        };
+
+        lines[pos..start]
+            .iter()
+            .for_each(|line| out.extend_from_slice(line));
+        let mut current = start;
+        for line in &hunk.lines {
+            match line {
+                DiffLine::Context(_) => {
+                    out.extend_from_slice(lines[current]);
+                    current += 1;
+                }
+                DiffLine::Deleted(_) => current += 1,
+                DiffLine::Added(text) => out.extend_from_slice(text),
+            }
+        }
+        pos = current;
+        offset = start as isize - stated as isize;
+    }
+    lines[pos..]
+        .iter()
+        .for_each(|line| out.extend_from_slice(line));
+    Ok(out)
+}
+
+/// The objects produced by [`apply_patches`].
+#[derive(Debug, Clone)]
+pub struct AppliedPatch {
+    /// The patched root tree.
+    pub tree_id: SHA1,
+    /// Every tree written, the root last.
+    pub trees: Vec<Tree>,
+    /// The contents of every added or modified file.
+    pub blobs: Vec<Blob>,
+}
+
+/// Apply `patches` in order to `tree`. `tree_lookup` and `blob_lookup` load existing objects; a
+/// missing object is an error.
+pub fn apply_patches<T, B>(
+    tree: &Tree,
+    patches: &[FilePatch],
+    mut tree_lookup: T,
+    mut blob_lookup: B,
+) -> Result<AppliedPatch, GitError>
+where
+    T: FnMut(&SHA1) -> Option<Tree>,
+    B: FnMut(&SHA1) -> Option<Vec<u8>>,
+{
+    // Files touched so far, `None` once deleted
+    let mut files: HashMap<String, Option<(TreeItemMode, Vec<u8>)>> = HashMap::new();
+    for patch in patches {
+        let path = patch.path();
+        let conflict = |msg: &str| GitError::PatchConflict(format!("{path}: {msg}"));
+        let old = match &patch.old_path {
+            Some(old_path) => {
+                let file = match files.get(old_path) {
+                    Some(file) => file.clone(),
+                    None => find_file(tree, old_path, &mut tree_lookup)?
+                        .map(|(mode, id)| match mode {
+                            TreeItemMode::Commit => Ok((mode, gitlink_content(&id))),
+                            _ => blob_lookup(&id)
+                                .map(|data| (mode, data))
+                                .ok_or_else(|| GitError::ObjectNotFound(id.to_string())),
+                        })
+                        .transpose()?,
+                };
+                Some(file.ok_or_else(|| conflict("does not exist"))?)
+            }
+            None => None,
+        };
+        let content = old.as_ref().map_or(&[][..], |(_, data)| data.as_slice());
+        let content = apply_hunks(content, &patch.hunks).map_err(|e| match e {
+            GitError::PatchConflict(msg) => conflict(&msg),
+            e => e,
+        })?;
+
+        match &patch.new_path {
+            None if !content.is_empty() => return Err(conflict("deleted file still has content")),
+            None => {}
+            Some(new_path) => {
+                let new_exists = match files.get(new_path) {
+                    Some(file) => file.is_some(),
+                    None => find_file(tree, new_path, &mut tree_lookup)?.is_some(),
+                };
+                if patch.old_path.as_ref() != Some(new_path) && new_exists {
+                    return Err(conflict("already exists"));
+                }
+            }
+        }
+        if let Some(old_path) = &patch.old_path
+            && !patch.copy
+        {
+            files.insert(old_path.clone(), None);
+        }
+        if let Some(new_path) = &patch.new_path {
+            let mode = patch
+                .new_mode
+                .or(old.map(|(mode, _)| mode))
+                .unwrap_or(TreeItemMode::Blob);
+            files.insert(new_path.clone(), Some((mode, content)));
+        }
+    }
+
+    let mut blobs: Vec<Blob> = Vec::new();
+    let mut updates = BTreeMap::new();
+    for (path, file) in files {
+        let entry = match file {
+            Some((TreeItemMode::Commit, data)) => {
+                Some((TreeItemMode::Commit, gitlink_id(&path, &data)?))
+            }
+            Some((mode, data)) => {
+                let blob = Blob::from_content_bytes(data);
+                let id = blob.id;
+                if !blobs.iter().any(|existing| existing.id == id) {
+                    blobs.push(blob);
+                }
+                Some((mode, id))
+            }
+            None => None,
+        };
+        updates.insert(path, entry);
+    }
+    let mut trees = Vec::new();
+    let root = update_tree(Some(tree), updates, &mut tree_lookup, &mut trees)?
+        .ok_or_else(|| GitError::EmptyTreeItems("the patch deletes every file".to_string()))?;
+    Ok(AppliedPatch {
+        tree_id: root.id,
+        trees,
+        blobs,
+    })
+}
+
+/// Submodules are diffed as a `Subproject commit <id>` line, see
+/// [`submodule_content`](super::patch::submodule_content).
+fn gitlink_content(id: &SHA1) -> Vec<u8> {
+    format!("{SUBPROJECT_COMMIT}{id}\n").into_bytes()
+}
+
+/// The commit a patched submodule points to.
+fn gitlink_id(path: &str, content: &[u8]) -> Result<SHA1, GitError> {
+    content
+        .to_str()
+        .ok()
+        .and_then(|line| line.trim_end().strip_prefix(SUBPROJECT_COMMIT))
+        .and_then(|id| SHA1::from_str(id).ok())
+        .ok_or_else(|| GitError::InvalidPatch(format!("{path}: invalid submodule commit")))
+}
+
+/// The mode and id of the non-tree entry at `path`.
+fn find_file<T>(
+    tree: &Tree,
+    path: &str,
+    lookup: &mut T,
+) -> Result<Option<(TreeItemMode, SHA1)>, GitError>
+where
+    T: FnMut(&SHA1) -> Option<Tree>,
+{
+    let (dir, name) = match path.split_once('/') {
+        Some((dir, rest)) => (Some(dir), rest),
+        None => (None, path),
+    };
+    let item = tree
+        .tree_items
+        .iter()
+        .find(|item| item.name == dir.unwrap_or(name));
+    match (item, dir) {
+        (Some(item), None) if !item.is_tree() => Ok(Some((item.mode, item.id))),
+        (Some(item), Some(_)) if item.is_tree() => {
+            let subtree =
+                lookup(&item.id).ok_or_else(|| GitError::ObjectNotFound(item.id.to_string()))?;
+            find_file(&subtree, name, lookup)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Rewrite `tree` with `updates` (paths relative to it, `None` deletes), appending every new
+/// tree to `written`. `None` when nothing is left in it.
+fn update_tree<T>(
+    tree: Option<&Tree>,
+    updates: BTreeMap<String, Option<(TreeItemMode, SHA1)>>,
+    lookup: &mut T,
+    written: &mut Vec<Tree>,
+) -> Result<Option<Tree>, GitError>
+where
+    T: FnMut(&SHA1) -> Option<Tree>,
+{
+    let mut items: BTreeMap<String, TreeItem> = tree
+        .iter()
+        .flat_map(|tree| &tree.tree_items)
+        .map(|item| (item.name.clone(), item.clone()))
+        .collect();
+    let mut files = Vec::new();
+    let mut dirs: BTreeMap<String, BTreeMap<String, _>> = BTreeMap::new();
+    for (path, entry) in updates {
+        match path.split_once('/') {
+            Some((dir, rest)) => {
+                dirs.entry(dir.to_string())
+                    .or_default()
+                    .insert(rest.to_string(), entry);
+            }
+            None => files.push((path, entry)),
+        }
+    }
+
+    // Deletions first so a file can be replaced by a directory, additions last for the reverse
+    for (name, _) in files.iter().filter(|(_, entry)| entry.is_none()) {
+        items.remove(name);
+    }
+    for (name, updates) in dirs {
+        let subtree = match items.get(&name) {
+            Some(item) if item.is_tree() => Some(
+                lookup(&item.id).ok_or_else(|| GitError::ObjectNotFound(item.id.to_string()))?,
+            ),
+            Some(_) => {
+                return Err(GitError::PatchConflict(format!(
+                    "{name}: is a file, not a directory"
+                )));
+            }
+            None => None,
+        };
+        match update_tree(subtree.as_ref(), updates, lookup, written)? {
+            Some(subtree) => {
+                items.insert(
+                    name.clone(),
+                    TreeItem::new(TreeItemMode::Tree, subtree.id, name),
+                );
+            }
+            None => {
+                items.remove(&name);
+            }
+        }
+    }
+    for (name, entry) in files {
+        if let Some((mode, id)) = entry {
+            items.insert(name.clone(), TreeItem::new(mode, id, name));
+        }
+    }
+
+    if items.is_empty() {
+        return Ok(None);
+    }
+    let tree = Tree::from_tree_items(items.into_values().collect())?;
+    written.push(tree.clone());
+    Ok(Some(tree))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::patch::{FileDiff, format_patch};
+    use crate::diff::rename::{RenameOptions, detect_renames};
+    use crate::diff::tree::diff_trees;
+    use crate::internal::object::commit::Commit;
+    use crate::internal::object::signature::Signature;
+
+    #[derive(Default)]
+    struct Objects {
+        trees: HashMap<SHA1, Tree>,
+        blobs: HashMap<SHA1, Vec<u8>>,
+    }
+
+    impl Objects {
+        /// Store a tree of `path -> content` files, creating subtrees for directories.
+        fn tree(&mut self, files: &[(&str, &str)]) -> Tree {
+            let mut updates = BTreeMap::new();
+            for (path, content) in files {
+                let blob = Blob::from_content(content);
+                updates.insert(path.to_string(), Some((TreeItemMode::Blob, blob.id)));
+                self.blobs.insert(blob.id, blob.data);
+            }
+            let mut written = Vec::new();
+            let root = update_tree(None, updates, &mut |_: &SHA1| None, &mut written)
+                .unwrap()
+                .unwrap();
+            self.trees
+                .extend(written.into_iter().map(|tree| (tree.id, tree)));
+            root
+        }
+
+        fn apply(&self, tree: &Tree, patches: &[FilePatch]) -> Result<AppliedPatch, GitError> {
+            apply_patches(
+                tree,
+                patches,
+                |id| self.trees.get(id).cloned(),
+                |id| self.blobs.get(id).cloned(),
+            )
+        }
+    }
+
+    fn commit() -> Commit {
+        let signature = |kind: &str| {
+            Signature::from_data(
+                format!("{kind} Tester <tester@example.com> 1700000000 +0000").into_bytes(),
+            )
+            .unwrap()
+        };
+        Commit::new(
+            signature("author"),
+            signature("committer"),
+            SHA1::default(),
+            vec![],
+            "\nReorganise\n",
+        )
+    }
+
+    #[test]
+    fn test_apply_format_patch_output() {
+        let lines = "fn main() {\n    one();\n    two();\n    three();\n    four();\n}\n";
+        let mut objects = Objects::default();
+        let old = objects.tree(&[
+            ("README", "hello\nworld\n"),
+            ("src/lib.rs", lines),
+            ("obsolete.txt", "gone\n"),
+        ]);
+        let new = objects.tree(&[
+            ("README", "hello\neveryone\n"),
+            ("src/main.rs", &lines.replace("four", "FOUR")),
+            ("docs/guide.md", "# Guide\n"),
+        ]);
+
+        let changes =
+            diff_trees(Some(&old), Some(&new), |id| objects.trees.get(id).cloned()).unwrap();
+        let changes = detect_renames(changes, &RenameOptions::default(), |id| {
+            objects.blobs.get(id).cloned()
+        })
+        .unwrap();
+        let content = |entry: Option<&crate::diff::tree::DiffEntry>| {
+            entry.map_or(Vec::new(), |entry| objects.blobs[&entry.id].clone())
+        };
+        let files: Vec<FileDiff> = changes
+            .into_iter()
+            .map(|change| {
+                let (a, b) = (content(change.old_entry()), content(change.new_entry()));
+                FileDiff::new(change, &a, &b, 3)
+            })
+            .collect();
+        let patch = format_patch(&commit(), &files, None);
+
+        let patches = parse_patch(&patch).unwrap();
+        assert_eq!(patches.len(), 4);
+        let applied = objects.apply(&old, &patches).unwrap();
+        assert_eq!(applied.tree_id, new.id);
+        assert_eq!(applied.trees.last().unwrap().id, new.id);
+        assert_eq!(applied.blobs.len(), 3);
+
+        // The same patch doesn't apply twice
+        objects
+            .trees
+            .extend(applied.trees.into_iter().map(|tree| (tree.id, tree)));
+        objects
+            .blobs
+            .extend(applied.blobs.into_iter().map(|blob| (blob.id, blob.data)));
+        assert!(matches!(
+            objects.apply(&new, &patches),
+            Err(GitError::PatchConflict(_))
+        ));
+    }
+
+    #[test]
+    fn test_apply_hunks_with_offset() {
+        let patch = "--- a/list\n+++ b/list\n@@ -2,3 +2,3 @@\n b\n-c\n+C\n d\n\
+                     @@ -8,2 +8,2 @@\n h\n-i\n\\ No newline at end of file\n+I\n";
+        let patches = parse_patch(patch.as_bytes()).unwrap();
+        assert_eq!(patches[0].old_path.as_deref(), Some("list"));
+        assert_eq!(patches[0].hunks.len(), 2);
+
+        // Two lines were inserted at the top since the patch was made
+        let content = b"new\nnew\na\nb\nc\nd\ne\nf\ng\nh\ni";
+        assert_eq!(
+            apply_hunks(content, &patches[0].hunks).unwrap(),
+            b"new\nnew\na\nb\nC\nd\ne\nf\ng\nh\nI\n"
+        );
+        assert!(matches!(
+            apply_hunks(b"a\nb\nx\nd\n", &patches[0].hunks),
+            Err(GitError::PatchConflict(_))
+        ));
+    }
+
+    #[test]
+    fn test_apply_patch_not_utf8() {
+        // Latin-1 content without a final newline goes through format_patch and back unchanged
+        let (old, new) = (b"caf\xe9\nna\xefve".as_slice(), b"caf\xe8\nna\xefve".as_slice());
+        let entry = |content: &[u8]| crate::diff::tree::DiffEntry {
+            path: "menu.txt".to_string(),
+            mode: TreeItemMode::Blob,
+            id: Blob::from_content_bytes(content.to_vec()).id,
+        };
+        let change = crate::diff::tree::TreeChange::Modified {
+            old: entry(old),
+            new: entry(new),
+        };
+        let patch = format_patch(&commit(), &[FileDiff::new(change, old, new, 3)], None);
+        assert!(patch.windows(5).any(|line| line == b"-caf\xe9"));
+
+        let patches = parse_patch(&patch).unwrap();
+        assert_eq!(apply_hunks(old, &patches[0].hunks).unwrap(), new);
+    }
+
+    #[test]
+    fn test_apply_submodule_update() {
+        let old_commit = "1111111111111111111111111111111111111111";
+        let new_commit = "2222222222222222222222222222222222222222";
+        let mut objects = Objects::default();
+        let readme = objects.tree(&[("README", "hello\n")]);
+        let tree_with = |objects: &mut Objects, commit: &str| {
+            let mut items = readme.tree_items.clone();
+            items.push(TreeItem::new(
+                TreeItemMode::Commit,
+                SHA1::from_str(commit).unwrap(),
+                "vendor".to_string(),
+            ));
+            let tree = Tree::from_tree_items(items).unwrap();
+            objects.trees.insert(tree.id, tree.clone());
+            tree
+        };
+        let old = tree_with(&mut objects, old_commit);
+        let new = tree_with(&mut objects, new_commit);
+
+        let patch = format!(
+            "diff --git a/vendor b/vendor\nindex 1111111..2222222 160000\n--- a/vendor\n\
+             +++ b/vendor\n@@ -1 +1 @@\n-Subproject commit {old_commit}\n\
+             +Subproject commit {new_commit}\n"
+        );
+        let applied = objects.apply(&old, &parse_patch(patch.as_bytes()).unwrap()).unwrap();
+        assert_eq!(applied.tree_id, new.id);
+        assert!(applied.blobs.is_empty());
+    }
+
+    #[test]
+    fn test_parse_patch_errors() {
+        assert!(matches!(
+            parse_patch(
+                b"diff --git a/x b/x\nindex 1234567..89abcde 100644\n\
+                  Binary files a/x and b/x differ\n"
+            ),
+            Err(GitError::InvalidPatch(_))
+        ));
+        assert!(matches!(
+            parse_patch(b"--- a/x\n+++ b/x\n@@ -1,2 +1,2 @@\n a\n"),
+            Err(GitError::InvalidPatch(_))
+        ));
+    }
+}
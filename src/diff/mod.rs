@@ -0,0 +1,12 @@
+//! Diffs between trees and blobs, the building blocks of commit views.
+//!
+//! - `tree`: the entries added, deleted or modified between two trees, recursing into subtrees.
+//! - `rename`: similarity-based rename and copy detection over a tree diff, like `git diff -M -C`.
+//! - `text`: unified line diffs of blobs, with git's binary detection.
+//! - `apply`: parsing unified diffs and mail patches, and applying them to a tree like `git apply`.
+//! - `patch`: `git format-patch` mbox rendering of a commit with diffstat and per-file diffs.
+pub mod apply;
+pub mod patch;
+pub mod rename;
+pub mod text;
+pub mod tree;
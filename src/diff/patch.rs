@@ -0,0 +1,562 @@
+//! `git format-patch` style rendering of a commit.
+//!
+//! [`format_patch`] turns a commit and the [`FileDiff`]s of its changes into one mbox message:
+//! mail headers from the author and the message, a diffstat with a change summary, and a
+//! `diff --git` section per file. Concatenated messages form an mbox of a whole series.
+//!
+//! Messages are bytes: file content goes into the diffs as it is, whatever its encoding. Non-ASCII
+//! names and subjects are RFC 2047 encoded like git does, and message lines starting with
+//! `From ` are escaped the mboxrd way, so mail readers and `git am` split the mbox correctly.
+use std::fmt::Write;
+use std::io::Write as _;
+
+use chrono::{DateTime, FixedOffset};
+
+use crate::hash::SHA1;
+use crate::internal::object::commit::Commit;
+use crate::internal::object::signature::Signature;
+use crate::internal::object::tree::TreeItemMode;
+
+use super::rename::RenameOptions;
+use super::text::{BlobDiff, DEFAULT_CONTEXT_LINES, DiffLine, diff_blobs};
+use super::tree::{DiffEntry, TreeChange};
+
+/// Width of the diffstat graph, the `+`/`-` bars are scaled down to fit.
+const STAT_GRAPH_WIDTH: usize = 50;
+/// Length of abbreviated object ids on `index` lines.
+const ABBREV_LEN: usize = 7;
+/// Longest RFC 2047 encoded word, including its `=?UTF-8?q?` and `?=` delimiters.
+const ENCODED_WORD_LEN: usize = 75;
+
+/// Options of patch generation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchOptions {
+    /// Context lines around each change (`-U`).
+    pub context: usize,
+    /// Rename detection, on by default like git's `diff.renames`.
+    pub renames: Option<RenameOptions>,
+}
+
+impl Default for PatchOptions {
+    fn default() -> Self {
+        Self {
+            context: DEFAULT_CONTEXT_LINES,
+            renames: Some(RenameOptions::default()),
+        }
+    }
+}
+
+/// A changed file with the diff of its content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileDiff {
+    pub change: TreeChange,
+    pub diff: BlobDiff,
+    pub old_size: usize,
+    pub new_size: usize,
+}
+
+impl FileDiff {
+    /// Diff the content of both sides of `change`; `old` / `new` are empty for a missing side.
+    pub fn new(change: TreeChange, old: &[u8], new: &[u8], context: usize) -> Self {
+        Self {
+            change,
+            diff: diff_blobs(old, new, context),
+            old_size: old.len(),
+            new_size: new.len(),
+        }
+    }
+
+    /// Number of added and deleted lines.
+    pub fn line_counts(&self) -> (usize, usize) {
+        match &self.diff {
+            BlobDiff::Binary => (0, 0),
+            BlobDiff::Text(hunks) => {
+                hunks
+                    .iter()
+                    .flat_map(|hunk| &hunk.lines)
+                    .fold((0, 0), |(added, deleted), line| match line {
+                        DiffLine::Added(_) => (added + 1, deleted),
+                        DiffLine::Deleted(_) => (added, deleted + 1),
+                        DiffLine::Context(_) => (added, deleted),
+                    })
+            }
+        }
+    }
+}
+
+/// Render `commit` as an mbox message. `number` is `(n, total)` for `[PATCH n/total]` subjects
+/// of a series, `None` for a lone `[PATCH]`.
+pub fn format_patch(
+    commit: &Commit,
+    files: &[FileDiff],
+    number: Option<(usize, usize)>,
+) -> Vec<u8> {
+    let mut out = Vec::new();
+    let (subject, body) = split_message(commit.message_body());
+    let prefix = match number {
+        Some((n, total)) => format!("[PATCH {n}/{total}]"),
+        None => "[PATCH]".to_string(),
+    };
+    let _ = writeln!(out, "From {} Mon Sep 17 00:00:00 2001", commit.id);
+    let _ = writeln!(
+        out,
+        "From: {} <{}>",
+        encode_name(&commit.author.name),
+        commit.author.email
+    );
+    let _ = writeln!(out, "Date: {}", rfc2822_date(&commit.author));
+    let _ = writeln!(out, "Subject: {prefix} {}", encode_header(&subject, false));
+    out.push(b'\n');
+    for line in body.split_inclusive('\n') {
+        // mboxrd: `From ` lines, already quoted or not, get one more `>`
+        if line.trim_start_matches('>').starts_with("From ") {
+            out.push(b'>');
+        }
+        out.extend_from_slice(line.as_bytes());
+    }
+    out.extend_from_slice(b"---\n");
+    out.extend_from_slice(diffstat(files).as_bytes());
+    out.push(b'\n');
+    for file in files {
+        write_file_diff(&mut out, file);
+    }
+    let _ = write!(out, "-- \ngit-internal {}\n\n", env!("CARGO_PKG_VERSION"));
+    out
+}
+
+/// Subject (first paragraph on one line) and the rest of the message, like git's `%s` / `%b`.
+fn split_message(message: &str) -> (String, &str) {
+    let message = message.trim_start_matches('\n');
+    let (first, body) = message.split_once("\n\n").unwrap_or((message, ""));
+    let subject = first.lines().map(str::trim).collect::<Vec<_>>().join(" ");
+    (subject, body.trim_start_matches('\n'))
+}
+
+/// A display name for a `From:` header: RFC 2047 encoded when it isn't ASCII, quoted when it
+/// has RFC 822 specials, like git's `pp_user_info`.
+fn encode_name(name: &str) -> String {
+    if needs_rfc2047(name) {
+        return encode_header(name, true);
+    }
+    if !name.contains(|c| "()<>@,;:\\\".[]".contains(c)) {
+        return name.to_string();
+    }
+    let mut quoted = String::with_capacity(name.len() + 2);
+    quoted.push('"');
+    for c in name.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn needs_rfc2047(text: &str) -> bool {
+    !text.is_ascii() || text.contains("=?")
+}
+
+/// `text` as RFC 2047 `=?UTF-8?q?...?=` words when it needs encoding, folded onto continuation
+/// lines before a word grows past [`ENCODED_WORD_LEN`]. An `address` is a display name, where
+/// fewer characters may appear unencoded. Spaces are `=20` rather than `_`, which git prefers
+/// because many readers leave the underscore in place.
+fn encode_header(text: &str, address: bool) -> String {
+    const OPEN: &str = "=?UTF-8?q?";
+    if !needs_rfc2047(text) {
+        return text.to_string();
+    }
+    let mut encoded = String::from(OPEN);
+    let mut word_len = OPEN.len();
+    let mut buf = [0; 4];
+    for c in text.chars() {
+        let special = !c.is_ascii_graphic()
+            || matches!(c, '=' | '?' | '_')
+            || (address && !c.is_ascii_alphanumeric() && !"!*+-/".contains(c));
+        let mut piece = String::new();
+        if special {
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                let _ = write!(piece, "={byte:02X}");
+            }
+        } else {
+            piece.push(c);
+        }
+        if word_len + piece.len() + 2 > ENCODED_WORD_LEN {
+            encoded.push_str("?=\n ");
+            encoded.push_str(OPEN);
+            word_len = OPEN.len();
+        }
+        encoded.push_str(&piece);
+        word_len += piece.len();
+    }
+    encoded.push_str("?=");
+    encoded
+}
+
+fn rfc2822_date(signature: &Signature) -> String {
+    let tz = signature.timezone.as_bytes();
+    let offset = match (
+        tz.first(),
+        signature.timezone.get(1..3),
+        signature.timezone.get(3..5),
+    ) {
+        (Some(sign), Some(hours), Some(minutes)) => {
+            let seconds =
+                hours.parse::<i32>().unwrap_or(0) * 3600 + minutes.parse::<i32>().unwrap_or(0) * 60;
+            if *sign == b'-' { -seconds } else { seconds }
+        }
+        _ => 0,
+    };
+    let offset = FixedOffset::east_opt(offset).unwrap_or_else(|| FixedOffset::east_opt(0).unwrap());
+    DateTime::from_timestamp(signature.timestamp as i64, 0)
+        .unwrap_or_default()
+        .with_timezone(&offset)
+        .to_rfc2822()
+}
+
+fn mode(entry: &DiffEntry) -> String {
+    String::from_utf8_lossy(entry.mode.to_bytes()).to_string()
+}
+
+fn abbrev(id: Option<&SHA1>) -> String {
+    match id {
+        Some(id) => id.to_string()[..ABBREV_LEN].to_string(),
+        None => "0".repeat(ABBREV_LEN),
+    }
+}
+
+/// The path shown in the diffstat, `old => new` for renames and copies.
+fn stat_name(change: &TreeChange) -> String {
+    match change {
+        TreeChange::Renamed { old, new, .. } | TreeChange::Copied { old, new, .. } => {
+            format!("{} => {}", old.path, new.path)
+        }
+        _ => change.path().to_string(),
+    }
+}
+
+fn plural(count: usize, word: &str) -> String {
+    if count == 1 {
+        format!("{count} {word}")
+    } else {
+        format!("{count} {word}s")
+    }
+}
+
+/// The diffstat and `--summary` lines of `git format-patch`.
+pub fn diffstat(files: &[FileDiff]) -> String {
+    let names: Vec<String> = files.iter().map(|file| stat_name(&file.change)).collect();
+    let counts: Vec<(usize, usize)> = files.iter().map(FileDiff::line_counts).collect();
+    let name_width = names.iter().map(|name| name.len()).max().unwrap_or(0);
+    let max_total = counts.iter().map(|(a, d)| a + d).max().unwrap_or(0);
+    let count_width = max_total.to_string().len();
+    let scale = |n: usize| match max_total {
+        total if total > STAT_GRAPH_WIDTH => (n * STAT_GRAPH_WIDTH).div_ceil(total),
+        _ => n,
+    };
+
+    let mut out = String::new();
+    for ((file, name), (added, deleted)) in files.iter().zip(&names).zip(&counts) {
+        match file.diff {
+            BlobDiff::Binary => {
+                let _ = writeln!(
+                    out,
+                    " {name:<name_width$} | Bin {} -> {} bytes",
+                    file.old_size, file.new_size
+                );
+            }
+            BlobDiff::Text(_) => {
+                let graph = "+".repeat(scale(*added)) + &"-".repeat(scale(*deleted));
+                let total = added + deleted;
+                let line = format!(" {name:<name_width$} | {total:>count_width$} {graph}");
+                let _ = writeln!(out, "{}", line.trim_end());
+            }
+        }
+    }
+
+    let (added, deleted) = counts
+        .iter()
+        .fold((0, 0), |(a, d), (added, deleted)| (a + added, d + deleted));
+    let _ = write!(out, " {} changed", plural(files.len(), "file"));
+    if added > 0 || deleted == 0 {
+        let _ = write!(out, ", {}(+)", plural(added, "insertion"));
+    }
+    if deleted > 0 {
+        let _ = write!(out, ", {}(-)", plural(deleted, "deletion"));
+    }
+    out.push('\n');
+
+    for file in files {
+        match &file.change {
+            TreeChange::Added(new) => {
+                let _ = writeln!(out, " create mode {} {}", mode(new), new.path);
+            }
+            TreeChange::Deleted(old) => {
+                let _ = writeln!(out, " delete mode {} {}", mode(old), old.path);
+            }
+            TreeChange::Renamed {
+                old,
+                new,
+                similarity,
+            } => {
+                let _ = writeln!(out, " rename {} => {} ({similarity}%)", old.path, new.path);
+            }
+            TreeChange::Copied {
+                old,
+                new,
+                similarity,
+            } => {
+                let _ = writeln!(out, " copy {} => {} ({similarity}%)", old.path, new.path);
+            }
+            TreeChange::Modified { old, new } if old.mode != new.mode => {
+                let _ = writeln!(
+                    out,
+                    " mode change {} => {} {}",
+                    mode(old),
+                    mode(new),
+                    new.path
+                );
+            }
+            TreeChange::Modified { .. } => {}
+        }
+    }
+    out
+}
+
+/// The `diff --git` section of one file.
+fn write_file_diff(out: &mut Vec<u8>, file: &FileDiff) {
+    let change = &file.change;
+    let old = change.old_entry();
+    let new = change.new_entry();
+    let old_path = old
+        .or(new)
+        .map(|entry| entry.path.as_str())
+        .unwrap_or_default();
+    let new_path = new
+        .or(old)
+        .map(|entry| entry.path.as_str())
+        .unwrap_or_default();
+    let _ = writeln!(out, "diff --git a/{old_path} b/{new_path}");
+
+    match change {
+        TreeChange::Added(new) => {
+            let _ = writeln!(out, "new file mode {}", mode(new));
+        }
+        TreeChange::Deleted(old) => {
+            let _ = writeln!(out, "deleted file mode {}", mode(old));
+        }
+        _ => {}
+    }
+    if let (Some(old), Some(new)) = (old, new)
+        && old.mode != new.mode
+    {
+        let _ = write!(out, "old mode {}\nnew mode {}\n", mode(old), mode(new));
+    }
+    match change {
+        TreeChange::Renamed { similarity, .. } => {
+            let _ = write!(
+                out,
+                "similarity index {similarity}%\nrename from {old_path}\nrename to {new_path}\n"
+            );
+        }
+        TreeChange::Copied { similarity, .. } => {
+            let _ = write!(
+                out,
+                "similarity index {similarity}%\ncopy from {old_path}\ncopy to {new_path}\n"
+            );
+        }
+        _ => {}
+    }
+
+    let old_id = old.map(|entry| &entry.id);
+    let new_id = new.map(|entry| &entry.id);
+    if old_id == new_id {
+        return;
+    }
+    let _ = write!(out, "index {}..{}", abbrev(old_id), abbrev(new_id));
+    match (old, new) {
+        (Some(old), Some(new)) if old.mode == new.mode => {
+            let _ = writeln!(out, " {}", mode(new));
+        }
+        _ => out.push(b'\n'),
+    }
+
+    let a = old.map_or("/dev/null".to_string(), |_| format!("a/{old_path}"));
+    let b = new.map_or("/dev/null".to_string(), |_| format!("b/{new_path}"));
+    match &file.diff {
+        BlobDiff::Binary => {
+            let _ = writeln!(out, "Binary files {a} and {b} differ");
+        }
+        BlobDiff::Text(hunks) if !hunks.is_empty() => {
+            let _ = write!(out, "--- {a}\n+++ {b}\n");
+            for hunk in hunks {
+                hunk.write_to(out);
+            }
+        }
+        BlobDiff::Text(_) => {}
+    }
+}
+
+/// Content shown for an entry that isn't a blob: git diffs submodules as their commit id.
+pub fn submodule_content(entry: &DiffEntry) -> Option<Vec<u8>> {
+    (entry.mode == TreeItemMode::Commit)
+        .then(|| format!("Subproject commit {}\n", entry.id).into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::object::types::ObjectType;
+
+    fn entry(path: &str, content: &str) -> DiffEntry {
+        DiffEntry {
+            path: path.to_string(),
+            mode: TreeItemMode::Blob,
+            id: SHA1::from_type_and_data(ObjectType::Blob, content.as_bytes()),
+        }
+    }
+
+    #[test]
+    fn test_format_patch() {
+        let author =
+            Signature::from_data(b"author Tester <tester@example.com> 1700000000 +0800".to_vec())
+                .unwrap();
+        let committer = Signature::from_data(
+            b"committer Tester <tester@example.com> 1700000000 +0800".to_vec(),
+        )
+        .unwrap();
+        let commit = Commit::new(
+            author,
+            committer,
+            SHA1::default(),
+            vec![],
+            "\nFix greeting\n\nSay hello to everyone.\n",
+        );
+
+        let files = [
+            FileDiff::new(
+                TreeChange::Modified {
+                    old: entry("src/hello.txt", "hello\nworld\n"),
+                    new: entry("src/hello.txt", "hello\neveryone\n"),
+                },
+                b"hello\nworld\n",
+                b"hello\neveryone\n",
+                3,
+            ),
+            FileDiff::new(
+                TreeChange::Added(entry("NEWS", "news\n")),
+                b"",
+                b"news\n",
+                3,
+            ),
+        ];
+        let patch = format_patch(&commit, &files, Some((1, 2)));
+        let expected = format!(
+            "From {} Mon Sep 17 00:00:00 2001
+From: Tester <tester@example.com>
+Date: Wed, 15 Nov 2023 06:13:20 +0800
+Subject: [PATCH 1/2] Fix greeting
+
+Say hello to everyone.
+---
+ src/hello.txt | 2 +-
+ NEWS          | 1 +
+ 2 files changed, 2 insertions(+), 1 deletion(-)
+ create mode 100644 NEWS
+
+diff --git a/src/hello.txt b/src/hello.txt
+index {}..{} 100644
+--- a/src/hello.txt
++++ b/src/hello.txt
+@@ -1,2 +1,2 @@
+ hello
+-world
++everyone
+diff --git a/NEWS b/NEWS
+new file mode 100644
+index 0000000..{}
+--- /dev/null
++++ b/NEWS
+@@ -0,0 +1 @@
++news
+--\x20
+git-internal {}
+
+",
+            commit.id,
+            abbrev(Some(&files[0].change.old_entry().unwrap().id)),
+            abbrev(Some(&files[0].change.new_entry().unwrap().id)),
+            abbrev(Some(&files[1].change.new_entry().unwrap().id)),
+            env!("CARGO_PKG_VERSION"),
+        );
+        assert_eq!(String::from_utf8(patch).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_patch_headers_and_from_lines() {
+        let signature = |kind: &str, name: &str| {
+            Signature::from_data(
+                format!("{kind} {name} <jd@example.com> 1700000000 +0000").into_bytes(),
+            )
+            .unwrap()
+        };
+        let message = "\nCorrige le café\n\nFrom the start:\n>From a reply\nNot From here\n";
+        let commit = |name: &str| {
+            Commit::new(
+                signature("author", name),
+                signature("committer", name),
+                SHA1::default(),
+                vec![],
+                message,
+            )
+        };
+        let patch = String::from_utf8(format_patch(&commit("Jörg Doe"), &[], None)).unwrap();
+        assert!(patch.contains("\nFrom: =?UTF-8?q?J=C3=B6rg=20Doe?= <jd@example.com>\n"));
+        assert!(patch.contains("\nSubject: [PATCH] =?UTF-8?q?Corrige=20le=20caf=C3=A9?=\n"));
+        assert!(patch.contains("\n\n>From the start:\n>>From a reply\nNot From here\n---\n"));
+
+        let patch = String::from_utf8(format_patch(&commit("Doe, J. \"JD\""), &[], None)).unwrap();
+        assert!(patch.contains("\nFrom: \"Doe, J. \\\"JD\\\"\" <jd@example.com>\n"));
+
+        let long = encode_header(&"é".repeat(30), false);
+        let lines: Vec<&str> = long.split('\n').collect();
+        assert_eq!(lines.len(), 3);
+        assert!(
+            lines
+                .iter()
+                .all(|line| line.trim_start().len() <= ENCODED_WORD_LEN)
+        );
+        assert!(lines[1].starts_with(" =?UTF-8?q?=C3=A9") && lines[1].ends_with("?="));
+        assert_eq!(encode_header("Plain subject", false), "Plain subject");
+    }
+
+    #[test]
+    fn test_diffstat_rename_and_binary() {
+        let files = [
+            FileDiff::new(
+                TreeChange::Renamed {
+                    old: entry("a.txt", "same\n"),
+                    new: entry("b.txt", "same\n"),
+                    similarity: 100,
+                },
+                b"same\n",
+                b"same\n",
+                3,
+            ),
+            FileDiff::new(
+                TreeChange::Modified {
+                    old: entry("logo.png", "\0old"),
+                    new: entry("logo.png", "\0new!"),
+                },
+                b"\0old",
+                b"\0new!",
+                3,
+            ),
+        ];
+        assert_eq!(
+            diffstat(&files),
+            " a.txt => b.txt | 0\n logo.png       | Bin 4 -> 5 bytes\n \
+             2 files changed, 0 insertions(+)\n rename a.txt => b.txt (100%)\n"
+        );
+    }
+}
@@ -0,0 +1,314 @@
+//! Rename and copy detection, like `git diff -M` / `git diff -C`.
+//!
+//! [`detect_renames`] pairs the additions of a [tree diff](super::tree::diff_trees) with deleted
+//! (and, for copies, modified) files. Identical blobs are paired first, then the remaining ones by
+//! content similarity. Similarity follows git's estimate: contents are cut into chunks ending at a
+//! newline or after 64 bytes, and the score is the number of bytes in chunks common to both files
+//! relative to the larger file.
+use std::collections::HashMap;
+
+use crate::errors::GitError;
+use crate::hash::SHA1;
+use crate::internal::object::tree::TreeItemMode;
+
+use super::tree::{DiffEntry, TreeChange};
+
+/// Longest chunk used by the similarity estimate, as in git's `diffcore-delta.c`.
+const MAX_CHUNK_LEN: usize = 64;
+
+/// Options of [`detect_renames`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenameOptions {
+    /// Minimum similarity percentage for a pair to be reported, `-M50%` by default.
+    pub threshold: u8,
+    /// Also look for copies of deleted and modified files (`-C`).
+    pub copies: bool,
+}
+
+impl Default for RenameOptions {
+    fn default() -> Self {
+        Self {
+            threshold: 50,
+            copies: false,
+        }
+    }
+}
+
+impl RenameOptions {
+    /// Equivalent of `-M<threshold>%`.
+    pub fn threshold(mut self, threshold: u8) -> Self {
+        self.threshold = threshold.min(100);
+        self
+    }
+
+    /// Equivalent of `-C`.
+    pub fn copies(mut self) -> Self {
+        self.copies = true;
+        self
+    }
+}
+
+/// Similarity of `old` and `new` as a percentage, see the module documentation.
+pub fn similarity(old: &[u8], new: &[u8]) -> u8 {
+    let larger = old.len().max(new.len());
+    if larger == 0 {
+        return 100;
+    }
+    let mut old_chunks: HashMap<&[u8], usize> = HashMap::new();
+    for chunk in chunks(old) {
+        *old_chunks.entry(chunk).or_default() += 1;
+    }
+    let mut common = 0;
+    for chunk in chunks(new) {
+        if let Some(count) = old_chunks.get_mut(chunk)
+            && *count > 0
+        {
+            *count -= 1;
+            common += chunk.len();
+        }
+    }
+    (common * 100 / larger) as u8
+}
+
+fn chunks(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = data;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let limit = rest.len().min(MAX_CHUNK_LEN);
+        let len = memchr::memchr(b'\n', &rest[..limit]).map_or(limit, |pos| pos + 1);
+        let (chunk, tail) = rest.split_at(len);
+        rest = tail;
+        Some(chunk)
+    })
+}
+
+/// Whether git would pair the two entries: regular files with regular files, symlinks with
+/// symlinks, never submodules.
+fn same_kind(old: &DiffEntry, new: &DiffEntry) -> bool {
+    let kind = |mode| match mode {
+        TreeItemMode::Blob | TreeItemMode::BlobExecutable => Some(true),
+        TreeItemMode::Link => Some(false),
+        TreeItemMode::Tree | TreeItemMode::Commit => None,
+    };
+    kind(old.mode).is_some() && kind(old.mode) == kind(new.mode)
+}
+
+/// Replace additions paired with a deleted file by [`TreeChange::Renamed`], and, with
+/// [`RenameOptions::copies`], additions paired with any deleted or modified file by
+/// [`TreeChange::Copied`].
+///
+/// `lookup` loads blob content by id; a missing blob is an error. Each deleted file is the source
+/// of at most one rename; further additions similar to it are reported as copies. The result is
+/// sorted by path like the input of [`diff_trees`](super::tree::diff_trees).
+pub fn detect_renames<F>(
+    changes: Vec<TreeChange>,
+    options: &RenameOptions,
+    mut lookup: F,
+) -> Result<Vec<TreeChange>, GitError>
+where
+    F: FnMut(&SHA1) -> Option<Vec<u8>>,
+{
+    let mut result = Vec::with_capacity(changes.len());
+    let mut deleted: Vec<Option<DiffEntry>> = Vec::new();
+    let mut modified = Vec::new();
+    let mut added = Vec::new();
+    for change in changes {
+        match change {
+            TreeChange::Deleted(old) => deleted.push(Some(old)),
+            TreeChange::Added(new) => added.push(new),
+            TreeChange::Modified { ref old, .. } => {
+                modified.push(old.clone());
+                result.push(change);
+            }
+            change => result.push(change),
+        }
+    }
+
+    let mut contents: HashMap<SHA1, Vec<u8>> = HashMap::new();
+    let mut load = |id: &SHA1| -> Result<Vec<u8>, GitError> {
+        if !contents.contains_key(id) {
+            let data = lookup(id).ok_or_else(|| GitError::ObjectNotFound(id.to_string()))?;
+            contents.insert(*id, data);
+        }
+        Ok(contents[id].clone())
+    };
+
+    let mut renamed_sources = Vec::new();
+    let mut unpaired = Vec::new();
+    for new in added {
+        // Exact renames first, they need no content
+        let exact = deleted.iter().position(|old| {
+            old.as_ref()
+                .is_some_and(|old| old.id == new.id && same_kind(old, &new))
+        });
+        let best = match exact {
+            Some(index) => Some((index, 100)),
+            None => {
+                let new_data = load(&new.id)?;
+                let mut best: Option<(usize, u8)> = None;
+                for (index, old) in deleted.iter().enumerate() {
+                    let Some(old) = old.as_ref().filter(|old| same_kind(old, &new)) else {
+                        continue;
+                    };
+                    let score = similarity(&load(&old.id)?, &new_data);
+                    if score >= options.threshold && best.is_none_or(|(_, best)| score > best) {
+                        best = Some((index, score));
+                    }
+                }
+                best
+            }
+        };
+        match best {
+            Some((index, similarity)) => {
+                let old = deleted[index].take().expect("unpaired source");
+                renamed_sources.push(old.clone());
+                result.push(TreeChange::Renamed {
+                    old,
+                    new,
+                    similarity,
+                });
+            }
+            None => unpaired.push(new),
+        }
+    }
+
+    for new in unpaired {
+        let mut best: Option<(&DiffEntry, u8)> = None;
+        if options.copies {
+            let new_data = load(&new.id)?;
+            let sources = renamed_sources
+                .iter()
+                .chain(modified.iter())
+                .chain(deleted.iter().flatten());
+            for old in sources.filter(|old| same_kind(old, &new)) {
+                let score = if old.id == new.id {
+                    100
+                } else {
+                    similarity(&load(&old.id)?, &new_data)
+                };
+                if score >= options.threshold && best.is_none_or(|(_, best)| score > best) {
+                    best = Some((old, score));
+                }
+            }
+        }
+        match best {
+            Some((old, similarity)) => result.push(TreeChange::Copied {
+                old: old.clone(),
+                new,
+                similarity,
+            }),
+            None => result.push(TreeChange::Added(new)),
+        }
+    }
+    result.extend(deleted.into_iter().flatten().map(TreeChange::Deleted));
+    result.sort_by(|a, b| a.path().cmp(b.path()));
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::object::types::ObjectType;
+
+    fn entry(path: &str, content: &str) -> (DiffEntry, Vec<u8>) {
+        let id = SHA1::from_type_and_data(ObjectType::Blob, content.as_bytes());
+        let entry = DiffEntry {
+            path: path.to_string(),
+            mode: TreeItemMode::Blob,
+            id,
+        };
+        (entry, content.as_bytes().to_vec())
+    }
+
+    fn run(
+        changes: Vec<TreeChange>,
+        blobs: &[(DiffEntry, Vec<u8>)],
+        options: RenameOptions,
+    ) -> Vec<String> {
+        let blobs: HashMap<SHA1, Vec<u8>> = blobs
+            .iter()
+            .map(|(entry, data)| (entry.id, data.clone()))
+            .collect();
+        detect_renames(changes, &options, |id| blobs.get(id).cloned())
+            .unwrap()
+            .iter()
+            .map(|change| change.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_similarity() {
+        assert_eq!(similarity(b"", b""), 100);
+        assert_eq!(similarity(b"a\nb\n", b"a\nb\n"), 100);
+        assert_eq!(similarity(b"a\nb\n", b"c\nd\n"), 0);
+        assert_eq!(similarity(b"a\nb\nc\nd\n", b"a\nb\nc\nx\n"), 75);
+    }
+
+    #[test]
+    fn test_detect_renames() {
+        let lines = "one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\nnine\nten\n";
+        let moved = entry("old/exact.rs", "unchanged\n");
+        let moved_to = entry("new/exact.rs", "unchanged\n");
+        let edited = entry("old/edited.rs", lines);
+        let edited_to = entry("new/edited.rs", &lines.replace("ten", "TEN"));
+        let unrelated = entry("new/unrelated.rs", "something else entirely\n");
+        let blobs = [
+            moved.clone(),
+            moved_to.clone(),
+            edited.clone(),
+            edited_to.clone(),
+            unrelated.clone(),
+        ];
+        let changes = vec![
+            TreeChange::Added(edited_to.0),
+            TreeChange::Added(moved_to.0),
+            TreeChange::Added(unrelated.0),
+            TreeChange::Deleted(edited.0),
+            TreeChange::Deleted(moved.0),
+        ];
+
+        assert_eq!(
+            run(changes.clone(), &blobs, RenameOptions::default()),
+            [
+                "R091 old/edited.rs -> new/edited.rs",
+                "R100 old/exact.rs -> new/exact.rs",
+                "A new/unrelated.rs",
+            ]
+        );
+        assert_eq!(
+            run(changes, &blobs, RenameOptions::default().threshold(95)),
+            [
+                "A new/edited.rs",
+                "R100 old/exact.rs -> new/exact.rs",
+                "A new/unrelated.rs",
+                "D old/edited.rs",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_detect_copies() {
+        let (source, data) = entry("src/a.rs", "fn a() {}\n");
+        let (source_v2, data_v2) = entry("src/a.rs", "fn a() {}\nfn b() {}\n");
+        let (copy, _) = entry("src/copy.rs", "fn a() {}\n");
+        let blobs = [(source.clone(), data), (source_v2.clone(), data_v2)];
+        let changes = vec![
+            TreeChange::Modified {
+                old: source,
+                new: source_v2,
+            },
+            TreeChange::Added(copy),
+        ];
+
+        assert_eq!(
+            run(changes.clone(), &blobs, RenameOptions::default()),
+            ["M src/a.rs", "A src/copy.rs"]
+        );
+        assert_eq!(
+            run(changes, &blobs, RenameOptions::default().copies()),
+            ["M src/a.rs", "C100 src/a.rs -> src/copy.rs"]
+        );
+    }
+}
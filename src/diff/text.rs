@@ -0,0 +1,299 @@
+//! Line diff of two blobs, rendered as unified hunks.
+//!
+//! [`diff_blobs`] runs Myers' algorithm over the lines of two blobs and groups the changes into
+//! hunks with `context` lines around them, the body of `git diff -U<context>` for one file.
+//! Blobs that git would consider binary are reported as [`BlobDiff::Binary`] instead.
+//!
+//! Lines are kept as bytes, so a diff of text in any encoding is written back as it was by
+//! `write_to`; the `Display` implementations are for showing a diff only.
+use std::fmt::{self, Display};
+
+use bstr::ByteSlice;
+use diffs::{Diff, myers};
+
+/// Number of context lines git shows by default.
+pub const DEFAULT_CONTEXT_LINES: usize = 3;
+
+/// How much of a blob git inspects to decide whether it is binary.
+const BINARY_CHECK_LEN: usize = 8000;
+
+/// git's heuristic (`buffer_is_binary`): a NUL byte within the first 8000 bytes.
+pub fn is_binary(data: &[u8]) -> bool {
+    data[..data.len().min(BINARY_CHECK_LEN)].contains(&0)
+}
+
+/// A line of a hunk, including its line terminator when it has one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffLine {
+    Context(Vec<u8>),
+    Deleted(Vec<u8>),
+    Added(Vec<u8>),
+}
+
+impl DiffLine {
+    /// The content of the line, without its prefix.
+    pub fn text(&self) -> &[u8] {
+        match self {
+            DiffLine::Context(line) | DiffLine::Deleted(line) | DiffLine::Added(line) => line,
+        }
+    }
+
+    /// Append the line as a patch spells it: prefixed, and followed by a marker when it has no
+    /// line terminator.
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        let prefix = match self {
+            DiffLine::Context(_) => b' ',
+            DiffLine::Deleted(_) => b'-',
+            DiffLine::Added(_) => b'+',
+        };
+        out.push(prefix);
+        out.extend_from_slice(self.text());
+        if !self.text().ends_with(b"\n") {
+            out.extend_from_slice(b"\n\\ No newline at end of file\n");
+        }
+    }
+}
+
+/// Show `write_to` output, with invalid UTF-8 replaced.
+fn display_bytes(f: &mut fmt::Formatter<'_>, write_to: impl FnOnce(&mut Vec<u8>)) -> fmt::Result {
+    let mut out = Vec::new();
+    write_to(&mut out);
+    f.write_str(&String::from_utf8_lossy(&out))
+}
+
+impl Display for DiffLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        display_bytes(f, |out| self.write_to(out))
+    }
+}
+
+/// A unified diff hunk. Line numbers are 1-based; for an empty side the start is the line
+/// after which the change happens, as in git's output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hunk {
+    pub old_start: usize,
+    pub old_lines: usize,
+    pub new_start: usize,
+    pub new_lines: usize,
+    pub lines: Vec<DiffLine>,
+}
+
+impl Hunk {
+    /// Append the hunk as a patch spells it, its header and lines.
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        let range = |start: usize, lines: usize| match lines {
+            1 => start.to_string(),
+            _ => format!("{start},{lines}"),
+        };
+        let header = format!(
+            "@@ -{} +{} @@\n",
+            range(self.old_start, self.old_lines),
+            range(self.new_start, self.new_lines)
+        );
+        out.extend_from_slice(header.as_bytes());
+        for line in &self.lines {
+            line.write_to(out);
+        }
+    }
+}
+
+impl Display for Hunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        display_bytes(f, |out| self.write_to(out))
+    }
+}
+
+/// The difference between two blobs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlobDiff {
+    /// At least one side is binary.
+    Binary,
+    /// Hunks of a text diff, empty when the blobs are equal.
+    Text(Vec<Hunk>),
+}
+
+impl BlobDiff {
+    /// Append the diff as a patch spells it, the hunks of a text diff.
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        match self {
+            BlobDiff::Binary => out.extend_from_slice(b"Binary files differ\n"),
+            BlobDiff::Text(hunks) => hunks.iter().for_each(|hunk| hunk.write_to(out)),
+        }
+    }
+}
+
+impl Display for BlobDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        display_bytes(f, |out| self.write_to(out))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Edit {
+    /// Line present on both sides, by its index in the old blob.
+    Equal(usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+/// Collects Myers' output as one edit per line.
+#[derive(Default)]
+struct LineEdits(Vec<Edit>);
+
+impl Diff for LineEdits {
+    type Error = ();
+
+    fn equal(&mut self, old: usize, _new: usize, len: usize) -> Result<(), ()> {
+        self.0.extend((old..old + len).map(Edit::Equal));
+        Ok(())
+    }
+
+    fn delete(&mut self, old: usize, len: usize, _new: usize) -> Result<(), ()> {
+        self.0.extend((old..old + len).map(Edit::Delete));
+        Ok(())
+    }
+
+    fn insert(&mut self, _old: usize, new: usize, new_len: usize) -> Result<(), ()> {
+        self.0.extend((new..new + new_len).map(Edit::Insert));
+        Ok(())
+    }
+}
+
+/// Diff two blobs with `context` lines around each change.
+pub fn diff_blobs(old: &[u8], new: &[u8], context: usize) -> BlobDiff {
+    if is_binary(old) || is_binary(new) {
+        return BlobDiff::Binary;
+    }
+    let old_lines: Vec<&[u8]> = old.lines_with_terminator().collect();
+    let new_lines: Vec<&[u8]> = new.lines_with_terminator().collect();
+    let mut edits = LineEdits::default();
+    myers::diff(
+        &mut edits,
+        &old_lines,
+        0,
+        old_lines.len(),
+        &new_lines,
+        0,
+        new_lines.len(),
+    )
+    .expect("collecting edits can't fail");
+    let edits = edits.0;
+
+    let changes: Vec<usize> = (0..edits.len())
+        .filter(|&i| !matches!(edits[i], Edit::Equal(_)))
+        .collect();
+    let mut hunks = Vec::new();
+    let mut group_start = 0;
+    while group_start < changes.len() {
+        // Changes separated by at most 2 * context equal lines share a hunk
+        let mut group_end = group_start;
+        while group_end + 1 < changes.len()
+            && changes[group_end + 1] - changes[group_end] <= 2 * context + 1
+        {
+            group_end += 1;
+        }
+        let start = changes[group_start].saturating_sub(context);
+        let end = (changes[group_end] + context + 1).min(edits.len());
+        hunks.push(build_hunk(&edits[..end], start, &old_lines, &new_lines));
+        group_start = group_end + 1;
+    }
+    BlobDiff::Text(hunks)
+}
+
+/// Build the hunk covering `edits[start..]`.
+fn build_hunk(edits: &[Edit], start: usize, old_lines: &[&[u8]], new_lines: &[&[u8]]) -> Hunk {
+    let text = |line: &[u8]| line.to_vec();
+    let (mut old_before, mut new_before) = (0, 0);
+    for edit in &edits[..start] {
+        match edit {
+            Edit::Equal(_) => {
+                old_before += 1;
+                new_before += 1;
+            }
+            Edit::Delete(_) => old_before += 1,
+            Edit::Insert(_) => new_before += 1,
+        }
+    }
+
+    let mut hunk = Hunk {
+        old_start: old_before,
+        old_lines: 0,
+        new_start: new_before,
+        new_lines: 0,
+        lines: Vec::new(),
+    };
+    for edit in &edits[start..] {
+        match *edit {
+            Edit::Equal(old) => {
+                hunk.old_lines += 1;
+                hunk.new_lines += 1;
+                hunk.lines.push(DiffLine::Context(text(old_lines[old])));
+            }
+            Edit::Delete(old) => {
+                hunk.old_lines += 1;
+                hunk.lines.push(DiffLine::Deleted(text(old_lines[old])));
+            }
+            Edit::Insert(new) => {
+                hunk.new_lines += 1;
+                hunk.lines.push(DiffLine::Added(text(new_lines[new])));
+            }
+        }
+    }
+    if hunk.old_lines > 0 {
+        hunk.old_start += 1;
+    }
+    if hunk.new_lines > 0 {
+        hunk.new_start += 1;
+    }
+    hunk
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_binary() {
+        assert!(!is_binary(b"plain text\n"));
+        assert!(is_binary(b"PNG\0\x01"));
+        let mut late_nul = vec![b'a'; BINARY_CHECK_LEN];
+        late_nul.push(0);
+        assert!(!is_binary(&late_nul));
+        assert_eq!(
+            diff_blobs(b"text\n", &late_nul[7999..], 3),
+            BlobDiff::Binary
+        );
+    }
+
+    #[test]
+    fn test_diff_blobs() {
+        let old = b"1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n14\n15\n";
+        let new = b"1\n2\nthree\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n14\n15\n16\n";
+        assert_eq!(
+            diff_blobs(old, new, DEFAULT_CONTEXT_LINES).to_string(),
+            "@@ -1,6 +1,6 @@\n 1\n 2\n-3\n+three\n 4\n 5\n 6\n\
+             @@ -13,3 +13,4 @@\n 13\n 14\n 15\n+16\n"
+        );
+        assert_eq!(
+            diff_blobs(b"a\nb\n", b"a\nb", 1).to_string(),
+            "@@ -1,2 +1,2 @@\n a\n-b\n+b\n\\ No newline at end of file\n"
+        );
+        assert_eq!(
+            diff_blobs(b"", b"new\n", 3).to_string(),
+            "@@ -0,0 +1 @@\n+new\n"
+        );
+        assert_eq!(diff_blobs(old, old, 3), BlobDiff::Text(vec![]));
+    }
+
+    #[test]
+    fn test_diff_blobs_not_utf8() {
+        // Latin-1 text is written back byte for byte
+        let mut out = Vec::new();
+        diff_blobs(b"caf\xe9\n", b"caf\xe8\n", 3).write_to(&mut out);
+        assert_eq!(out, b"@@ -1 +1 @@\n-caf\xe9\n+caf\xe8\n");
+        assert_eq!(
+            diff_blobs(b"caf\xe9\n", b"cafe\n", 3).to_string(),
+            "@@ -1 +1 @@\n-caf\u{fffd}\n+cafe\n"
+        );
+    }
+}
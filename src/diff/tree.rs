@@ -0,0 +1,248 @@
+//! Recursive diff of two trees.
+//!
+//! [`diff_trees`] compares two trees entry by entry, descending into subtrees that changed, and
+//! reports every blob-level change with its full path, sorted by path like `git diff-tree -r`.
+//! Trees are loaded through a lookup function, so it works over any object storage.
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+
+use crate::errors::GitError;
+use crate::hash::SHA1;
+use crate::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+
+/// One side of a change: a non-tree entry and its full path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffEntry {
+    pub path: String,
+    pub mode: TreeItemMode,
+    pub id: SHA1,
+}
+
+/// A change between two trees.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeChange {
+    Added(DiffEntry),
+    Deleted(DiffEntry),
+    /// Same path, different content or mode.
+    Modified {
+        old: DiffEntry,
+        new: DiffEntry,
+    },
+    /// `old` was moved to `new`; `similarity` is a percentage.
+    Renamed {
+        old: DiffEntry,
+        new: DiffEntry,
+        similarity: u8,
+    },
+    /// `new` was created as a copy of `old`, which is kept; `similarity` is a percentage.
+    Copied {
+        old: DiffEntry,
+        new: DiffEntry,
+        similarity: u8,
+    },
+}
+
+impl TreeChange {
+    /// The status letter of `git diff --name-status`, with the similarity for renames and copies
+    /// (`A`, `D`, `M`, `R100`, `C075`).
+    pub fn status(&self) -> String {
+        match self {
+            TreeChange::Added(_) => "A".to_string(),
+            TreeChange::Deleted(_) => "D".to_string(),
+            TreeChange::Modified { .. } => "M".to_string(),
+            TreeChange::Renamed { similarity, .. } => format!("R{similarity:03}"),
+            TreeChange::Copied { similarity, .. } => format!("C{similarity:03}"),
+        }
+    }
+
+    /// The entry before the change, `None` for additions.
+    pub fn old_entry(&self) -> Option<&DiffEntry> {
+        match self {
+            TreeChange::Added(_) => None,
+            TreeChange::Deleted(old)
+            | TreeChange::Modified { old, .. }
+            | TreeChange::Renamed { old, .. }
+            | TreeChange::Copied { old, .. } => Some(old),
+        }
+    }
+
+    /// The entry after the change, `None` for deletions.
+    pub fn new_entry(&self) -> Option<&DiffEntry> {
+        match self {
+            TreeChange::Deleted(_) => None,
+            TreeChange::Added(new)
+            | TreeChange::Modified { new, .. }
+            | TreeChange::Renamed { new, .. }
+            | TreeChange::Copied { new, .. } => Some(new),
+        }
+    }
+
+    /// The path shown for the change, the new one unless the entry was deleted.
+    pub fn path(&self) -> &str {
+        match self.new_entry().or(self.old_entry()) {
+            Some(entry) => &entry.path,
+            None => unreachable!("every change has at least one side"),
+        }
+    }
+}
+
+/// Formats like `git diff --name-status`, with `->` between the paths of renames and copies.
+impl Display for TreeChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TreeChange::Renamed { old, new, .. } | TreeChange::Copied { old, new, .. } => {
+                write!(f, "{} {} -> {}", self.status(), old.path, new.path)
+            }
+            _ => write!(f, "{} {}", self.status(), self.path()),
+        }
+    }
+}
+
+/// Diff two trees, either of which may be absent (e.g. the parent of a root commit).
+///
+/// `lookup` loads a subtree by id; a missing subtree is an error.
+pub fn diff_trees<F>(
+    old: Option<&Tree>,
+    new: Option<&Tree>,
+    mut lookup: F,
+) -> Result<Vec<TreeChange>, GitError>
+where
+    F: FnMut(&SHA1) -> Option<Tree>,
+{
+    let mut changes = Vec::new();
+    diff_level(old, new, "", &mut lookup, &mut changes)?;
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+    Ok(changes)
+}
+
+fn diff_level<F>(
+    old: Option<&Tree>,
+    new: Option<&Tree>,
+    prefix: &str,
+    lookup: &mut F,
+    changes: &mut Vec<TreeChange>,
+) -> Result<(), GitError>
+where
+    F: FnMut(&SHA1) -> Option<Tree>,
+{
+    let mut entries: BTreeMap<&str, (Option<&TreeItem>, Option<&TreeItem>)> = BTreeMap::new();
+    for item in old.iter().flat_map(|tree| &tree.tree_items) {
+        entries.entry(&item.name).or_default().0 = Some(item);
+    }
+    for item in new.iter().flat_map(|tree| &tree.tree_items) {
+        entries.entry(&item.name).or_default().1 = Some(item);
+    }
+
+    for (name, (old_item, new_item)) in entries {
+        if let (Some(o), Some(n)) = (old_item, new_item)
+            && o.id == n.id
+            && o.mode == n.mode
+        {
+            continue;
+        }
+        let path = format!("{prefix}{name}");
+        let old_tree = old_item.filter(|item| item.is_tree());
+        let new_tree = new_item.filter(|item| item.is_tree());
+        if old_tree.is_some() || new_tree.is_some() {
+            let load = |item: Option<&TreeItem>, lookup: &mut F| {
+                item.map(|item| {
+                    lookup(&item.id).ok_or_else(|| GitError::ObjectNotFound(item.id.to_string()))
+                })
+                .transpose()
+            };
+            let old_subtree = load(old_tree, lookup)?;
+            let new_subtree = load(new_tree, lookup)?;
+            diff_level(
+                old_subtree.as_ref(),
+                new_subtree.as_ref(),
+                &format!("{path}/"),
+                lookup,
+                changes,
+            )?;
+        }
+
+        let entry = |item: &TreeItem| DiffEntry {
+            path: path.clone(),
+            mode: item.mode,
+            id: item.id,
+        };
+        let old_blob = old_item.filter(|item| !item.is_tree()).map(entry);
+        let new_blob = new_item.filter(|item| !item.is_tree()).map(entry);
+        match (old_blob, new_blob) {
+            (Some(old), Some(new)) => changes.push(TreeChange::Modified { old, new }),
+            (Some(old), None) => changes.push(TreeChange::Deleted(old)),
+            (None, Some(new)) => changes.push(TreeChange::Added(new)),
+            (None, None) => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::internal::object::types::ObjectType;
+
+    fn blob_id(content: &str) -> SHA1 {
+        SHA1::from_type_and_data(ObjectType::Blob, content.as_bytes())
+    }
+
+    fn tree(items: Vec<(TreeItemMode, &str, SHA1)>) -> Tree {
+        Tree::from_tree_items(
+            items
+                .into_iter()
+                .map(|(mode, name, id)| TreeItem::new(mode, id, name.to_string()))
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_diff_trees() {
+        let old_src = tree(vec![
+            (TreeItemMode::Blob, "lib.rs", blob_id("lib")),
+            (TreeItemMode::Blob, "main.rs", blob_id("main")),
+        ]);
+        let new_src = tree(vec![
+            (TreeItemMode::Blob, "lib.rs", blob_id("lib v2")),
+            (TreeItemMode::Blob, "main.rs", blob_id("main")),
+        ]);
+        let old = tree(vec![
+            (TreeItemMode::Blob, "README", blob_id("readme")),
+            (TreeItemMode::Blob, "docs", blob_id("docs")),
+            (TreeItemMode::Tree, "src", old_src.id),
+        ]);
+        let new = tree(vec![
+            (TreeItemMode::Blob, "LICENSE", blob_id("license")),
+            (TreeItemMode::Blob, "README", blob_id("readme")),
+            (TreeItemMode::Tree, "docs", new_src.id),
+            (TreeItemMode::Tree, "src", new_src.id),
+        ]);
+        let trees: HashMap<SHA1, Tree> = [old_src, new_src]
+            .into_iter()
+            .map(|tree| (tree.id, tree))
+            .collect();
+
+        let changes = diff_trees(Some(&old), Some(&new), |id| trees.get(id).cloned()).unwrap();
+        let lines: Vec<String> = changes.iter().map(|change| change.to_string()).collect();
+        assert_eq!(
+            lines,
+            [
+                "A LICENSE",
+                "D docs",
+                "A docs/lib.rs",
+                "A docs/main.rs",
+                "M src/lib.rs"
+            ]
+        );
+
+        let root = diff_trees(None, Some(&new), |id| trees.get(id).cloned()).unwrap();
+        assert_eq!(root.len(), 6);
+        assert!(matches!(
+            diff_trees(None, Some(&new), |_| None),
+            Err(GitError::ObjectNotFound(_))
+        ));
+    }
+}
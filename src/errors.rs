@@ -38,6 +38,10 @@ pub enum GitError {
     #[error("`{0}`.")]
     EmptyTreeItems(String),
 
+    /// Tree entries out of git's canonical order, or with duplicate names.
+    #[error("Tree entries are not in canonical order: {0}")]
+    InvalidTreeOrder(String),
+
     /// Invalid commit signature type.
     #[error("The `{0}` is not a valid git commit signature.")]
     InvalidSignatureType(String),
@@ -62,10 +66,30 @@ pub enum GitError {
     #[error("The `{0}` is not a valid idx file.")]
     InvalidIdxFile(String),
 
+    /// Malformed or unsupported commit-graph file.
+    #[error("The `{0}` is not a valid commit-graph file.")]
+    InvalidCommitGraph(String),
+
+    /// Malformed or unsupported multi-pack-index file.
+    #[error("The `{0}` is not a valid multi-pack-index file.")]
+    InvalidMultiPackIndex(String),
+
+    /// Malformed or unsupported pack bitmap (.bitmap) file.
+    #[error("The `{0}` is not a valid bitmap file.")]
+    InvalidBitmapFile(String),
+
+    /// Malformed or unsupported cruft pack modification times (.mtimes) file.
+    #[error("The `{0}` is not a valid mtimes file.")]
+    InvalidPackMtimes(String),
+
     /// Malformed or unsupported pack file.
     #[error("The `{0}` is not a valid pack file.")]
     InvalidPackFile(String),
 
+    /// The trailer of a pack file is missing or is not the hash of the content before it.
+    #[error("The pack checksum does not match: {0}")]
+    PackChecksumMismatch(String),
+
     /// Invalid pack header magic or version.
     #[error("The `{0}` is not a valid pack header.")]
     InvalidPackHeader(String),
@@ -138,6 +162,43 @@ pub enum GitError {
     #[error("Network Error: {0}")]
     NetworkError(String),
 
+    /// The hardened SHA-1 implementation detected a collision attack.
+    #[error("SHA-1 collision attack detected for object {0}")]
+    HashCollision(String),
+
+    /// Another maintenance run holds the repository lock.
+    #[error("Repository is locked by another maintenance run: {0}")]
+    RepositoryLocked(String),
+
+    /// A reference file, `packed-refs` or `gitdir` file could not be parsed.
+    #[error("The `{0}` is not a valid reference.")]
+    InvalidReference(String),
+
+    /// A reference name breaks git's `check-ref-format` rules.
+    #[error("Invalid reference name `{0}`: {1}")]
+    InvalidRefName(String, String),
+
+    /// A refspec could not be parsed.
+    #[error("Invalid refspec `{0}`: {1}")]
+    InvalidRefspec(String, String),
+
+    /// Another writer holds the `.lock` file of a reference, of `packed-refs` or of another
+    /// file replaced whole, like the commit-graph.
+    #[error("Locked by another writer: {0}")]
+    RefLocked(String),
+
+    /// A reference no longer has the value a compare-and-swap update expected.
+    #[error("Reference `{0}` was updated concurrently")]
+    StaleReference(String),
+
+    /// A patch could not be parsed, or uses a feature that isn't supported.
+    #[error("Invalid patch: {0}")]
+    InvalidPatch(String),
+
+    /// A patch does not apply to the tree it was applied to.
+    #[error("Patch does not apply: {0}")]
+    PatchConflict(String),
+
     /// Generic custom error for miscellaneous failures.
     #[error("{0}")]
     CustomError(String),
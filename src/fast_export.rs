@@ -0,0 +1,526 @@
+//! `git fast-export`-style streams of a repository, to migrate it out of custom storage.
+//!
+//! [`fast_export`] walks the history of the refs of a [`RepositoryAccess`] and writes the
+//! stream `git fast-import` and the tools speaking its format read: a `blob` command per file
+//! content, a `commit` command per commit with its parents and the changes from its first
+//! parent, a `tag` command per annotated tag, and a `reset` per ref, pointing it at its tip.
+//! Commits come parents first, each on the ref that reaches it first; every object is given a
+//! mark, so the stream names no object id unless `show_original_ids` is set.
+//!
+//! Like `git fast-export --signed-tags=strip`, the signatures of commits and tags are left out,
+//! since their objects change once the stream is imported elsewhere; the other extra headers of
+//! commits are dropped but `encoding`.
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+use bytes::Bytes;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::hash::SHA1;
+use crate::internal::object::ObjectTrait;
+use crate::internal::object::signature::Signature;
+use crate::internal::object::tag::Tag;
+use crate::internal::object::tree::TreeItemMode;
+use crate::internal::object::types::ObjectType;
+use crate::protocol::RepositoryAccess;
+use crate::protocol::pack::load_object;
+use crate::protocol::types::{ProtocolError, ProtocolStream};
+use crate::revwalk::{CommitWalker, WalkOrder};
+
+/// What [`fast_export`] exports, and how.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FastExportOptions {
+    /// The refs to export by full name, every ref under `refs/` when empty
+    pub refs: Vec<String>,
+    /// Write an `original-oid` line with the id of each object, like `--show-original-ids`
+    pub show_original_ids: bool,
+}
+
+/// A change of a commit from its first parent.
+#[derive(Debug, Clone, PartialEq)]
+enum FileChange {
+    Modify {
+        path: String,
+        mode: TreeItemMode,
+        id: SHA1,
+    },
+    Delete {
+        path: String,
+    },
+}
+
+/// Stream the history of the refs of `repo`, see the [module documentation](self).
+///
+/// The refs are resolved and their history walked before the stream starts, so those errors
+/// come first; failures to read trees and blobs end the stream.
+pub async fn fast_export<R: RepositoryAccess + 'static>(
+    repo: &R,
+    options: &FastExportOptions,
+) -> Result<ProtocolStream, ProtocolError> {
+    let mut refs: Vec<(String, SHA1)> = Vec::new();
+    for (name, hash) in repo.get_repository_refs().await? {
+        let selected = match options.refs.is_empty() {
+            true => name.starts_with("refs/"),
+            false => options.refs.contains(&name),
+        };
+        if selected && !refs.iter().any(|(known, _)| *known == name) {
+            let id = hash.parse().map_err(|_| {
+                ProtocolError::repository_error(format!("Invalid hash {hash} of {name}"))
+            })?;
+            refs.push((name, id));
+        }
+    }
+    refs.sort();
+    if let Some(missing) = options
+        .refs
+        .iter()
+        .find(|name| !refs.iter().any(|(known, _)| known == *name))
+    {
+        return Err(ProtocolError::invalid_request(&format!(
+            "No such ref: {missing}"
+        )));
+    }
+
+    // The commit each ref ends at, through the annotated tags of `refs/tags`
+    let mut tips = Vec::new();
+    let mut tags = Vec::new();
+    for (name, id) in &refs {
+        let (tag, commit) = resolve_ref(repo, *id).await?;
+        match (tag, commit) {
+            (Some(tag), Some(commit)) if name.starts_with("refs/tags/") => {
+                tags.push((name.clone(), tag, commit));
+            }
+            (_, Some(commit)) => tips.push((name.clone(), commit)),
+            _ => tracing::warn!("Skipping {name}, which doesn't point to a commit"),
+        }
+    }
+
+    // Parents first, the commits of each ref before those another ref adds
+    let mut walker = CommitWalker::new(repo).order(WalkOrder::Topological);
+    for id in tips
+        .iter()
+        .map(|(_, id)| id)
+        .chain(tags.iter().map(|(_, _, id)| id))
+    {
+        walker = walker.push(*id);
+    }
+    let mut commits = walker.collect().await?;
+    commits.reverse();
+    let index: HashMap<SHA1, usize> = commits.iter().enumerate().map(|(i, c)| (c.id, i)).collect();
+    let mut labels: Vec<Option<String>> = vec![None; commits.len()];
+    let starts = tips.iter().map(|(name, id)| (name, id));
+    for (name, tip) in starts.chain(tags.iter().map(|(name, _, id)| (name, id))) {
+        let mut stack = vec![*tip];
+        while let Some(id) = stack.pop() {
+            let Some(&i) = index.get(&id) else { continue };
+            if labels[i].is_none() {
+                labels[i] = Some(name.clone());
+                stack.extend(&commits[i].parent_commit_ids);
+            }
+        }
+    }
+
+    let show_original_ids = options.show_original_ids;
+    let repo = repo.clone();
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    tokio::spawn(async move {
+        let written = async {
+            let mut marks = Marks::default();
+            for (commit, label) in commits.iter().zip(labels) {
+                let label = label.unwrap_or_default();
+                let mut out = String::new();
+                let mut data = Vec::new();
+                let parent_tree = match commit.parent_commit_ids.first() {
+                    Some(parent) => match index.get(parent) {
+                        Some(&i) => Some(commits[i].tree_id),
+                        None => Some(repo.get_commit(&parent.to_string()).await?.tree_id),
+                    },
+                    None => None,
+                };
+                let mut changes = Vec::new();
+                diff_trees(&repo, parent_tree, Some(commit.tree_id), "", &mut changes).await?;
+
+                for change in &changes {
+                    if let FileChange::Modify { mode, id, .. } = change
+                        && *mode != TreeItemMode::Commit
+                        && !marks.blobs.contains_key(id)
+                    {
+                        let content = repo.get_object(&id.to_string()).await?;
+                        let mark = marks.next(id, true);
+                        write!(out, "blob\nmark :{mark}\n").unwrap();
+                        if show_original_ids {
+                            writeln!(out, "original-oid {id}").unwrap();
+                        }
+                        writeln!(out, "data {}", content.len()).unwrap();
+                        data.push((std::mem::take(&mut out), content));
+                    }
+                }
+
+                if commit.parent_commit_ids.is_empty() {
+                    writeln!(out, "reset {label}").unwrap();
+                }
+                let mark = marks.next(&commit.id, false);
+                write!(out, "commit {label}\nmark :{mark}\n").unwrap();
+                if show_original_ids {
+                    writeln!(out, "original-oid {}", commit.id).unwrap();
+                }
+                writeln!(out, "{}", signature_line(&commit.author)?).unwrap();
+                writeln!(out, "{}", signature_line(&commit.committer)?).unwrap();
+                if let Some(encoding) = commit.extra_header("encoding") {
+                    writeln!(out, "encoding {encoding}").unwrap();
+                }
+                let message = commit.message_body();
+                write!(out, "data {}\n{message}\n", message.len()).unwrap();
+                for (i, parent) in commit.parent_commit_ids.iter().enumerate() {
+                    let command = if i == 0 { "from" } else { "merge" };
+                    writeln!(out, "{command} {}", marks.commit_ref(parent)).unwrap();
+                }
+                for change in changes {
+                    match change {
+                        FileChange::Delete { path } => writeln!(out, "D {}", quote_path(&path)),
+                        FileChange::Modify { path, mode, id } => {
+                            let mode = String::from_utf8_lossy(mode.to_bytes());
+                            let object = match marks.blobs.get(&id) {
+                                Some(mark) => format!(":{mark}"),
+                                None => id.to_string(),
+                            };
+                            writeln!(out, "M {mode} {object} {}", quote_path(&path))
+                        }
+                    }
+                    .unwrap();
+                }
+                out.push('\n');
+
+                let mut chunk = Vec::new();
+                for (command, content) in data {
+                    chunk.extend_from_slice(command.as_bytes());
+                    chunk.extend_from_slice(&content);
+                    chunk.push(b'\n');
+                }
+                chunk.extend_from_slice(out.as_bytes());
+                if tx.send(Ok(Bytes::from(chunk))).await.is_err() {
+                    // The reader is gone
+                    return Ok(());
+                }
+            }
+
+            let mut out = String::new();
+            for (name, tag, commit) in &tags {
+                let name = name.strip_prefix("refs/tags/").unwrap_or(name);
+                let mark = marks.next(&tag.id, false);
+                write!(out, "tag {name}\nmark :{mark}\n").unwrap();
+                writeln!(out, "from {}", marks.commit_ref(commit)).unwrap();
+                if show_original_ids {
+                    writeln!(out, "original-oid {}", tag.id).unwrap();
+                }
+                writeln!(out, "{}", signature_line(&tag.tagger)?).unwrap();
+                let message = strip_signature(&tag.message);
+                write!(out, "data {}\n{message}\n", message.len()).unwrap();
+            }
+            for (name, commit) in &tips {
+                write!(out, "reset {name}\nfrom {}\n\n", marks.commit_ref(commit)).unwrap();
+            }
+            let _ = tx.send(Ok(Bytes::from(out))).await;
+            Ok(())
+        }
+        .await;
+        if let Err(e) = written {
+            let _ = tx.send(Err(e)).await;
+        }
+    });
+    Ok(Box::pin(ReceiverStream::new(rx)))
+}
+
+/// The marks given to the objects written so far.
+#[derive(Default)]
+struct Marks {
+    blobs: HashMap<SHA1, usize>,
+    commits: HashMap<SHA1, usize>,
+    last: usize,
+}
+
+impl Marks {
+    fn next(&mut self, id: &SHA1, blob: bool) -> usize {
+        self.last += 1;
+        match blob {
+            true => self.blobs.insert(*id, self.last),
+            false => self.commits.insert(*id, self.last),
+        };
+        self.last
+    }
+
+    /// The mark of a commit written before, or its id for one outside the stream.
+    fn commit_ref(&self, id: &SHA1) -> String {
+        match self.commits.get(id) {
+            Some(mark) => format!(":{mark}"),
+            None => id.to_string(),
+        }
+    }
+}
+
+/// The annotated tag `id` is, if any, and the commit it ends at.
+async fn resolve_ref<R: RepositoryAccess>(
+    repo: &R,
+    id: SHA1,
+) -> Result<(Option<Tag>, Option<SHA1>), ProtocolError> {
+    let invalid = |e: &dyn std::fmt::Display| ProtocolError::corrupt_object(id, e);
+    match load_object(repo, &id).await? {
+        Some((ObjectType::Commit, _)) => Ok((None, Some(id))),
+        Some((ObjectType::Tag, data)) => {
+            let tag = Tag::from_bytes(&data, id).map_err(|e| invalid(&e))?;
+            // Tags of tags and of other objects can't be imported as tags
+            let commit = (tag.object_type == ObjectType::Commit).then_some(tag.object_hash);
+            Ok((Some(tag), commit))
+        }
+        Some(_) => Ok((None, None)),
+        None => Err(ProtocolError::ObjectNotFound(id.to_string())),
+    }
+}
+
+/// The changes from the tree `old` to the tree `new` below `prefix`, deletions first.
+async fn diff_trees<R: RepositoryAccess>(
+    repo: &R,
+    old: Option<SHA1>,
+    new: Option<SHA1>,
+    prefix: &str,
+    changes: &mut Vec<FileChange>,
+) -> Result<(), ProtocolError> {
+    if old == new {
+        return Ok(());
+    }
+    let load = |id: Option<SHA1>| async move {
+        match id {
+            Some(id) => Ok::<_, ProtocolError>(repo.get_tree(&id.to_string()).await?.tree_items),
+            None => Ok(Vec::new()),
+        }
+    };
+    let old_items = load(old).await?;
+    let new_items = load(new).await?;
+    let is_tree = |mode: TreeItemMode| mode == TreeItemMode::Tree;
+    // Entries that stay trees are compared below, those that stay files replaced in place
+    for item in &old_items {
+        let path = format!("{prefix}{}", item.name);
+        match new_items.iter().find(|new| new.name == item.name) {
+            Some(new) if is_tree(new.mode) && is_tree(item.mode) => {
+                let prefix = format!("{path}/");
+                Box::pin(diff_trees(
+                    repo,
+                    Some(item.id),
+                    Some(new.id),
+                    &prefix,
+                    changes,
+                ))
+                .await?;
+            }
+            Some(new) if !is_tree(new.mode) && !is_tree(item.mode) => {}
+            _ => changes.push(FileChange::Delete { path }),
+        }
+    }
+    for item in &new_items {
+        let path = format!("{prefix}{}", item.name);
+        let old = old_items.iter().find(|old| old.name == item.name);
+        if is_tree(item.mode) {
+            if !old.is_some_and(|old| is_tree(old.mode)) {
+                let prefix = format!("{path}/");
+                Box::pin(diff_trees(repo, None, Some(item.id), &prefix, changes)).await?;
+            }
+        } else if old.is_none_or(|old| old.id != item.id || old.mode != item.mode) {
+            changes.push(FileChange::Modify {
+                path,
+                mode: item.mode,
+                id: item.id,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// `author`, `committer` or `tagger` line of a signature, without its newline.
+fn signature_line(signature: &Signature) -> Result<String, ProtocolError> {
+    let data = signature
+        .to_data()
+        .map_err(|e| ProtocolError::repository_error(format!("Invalid signature: {e}")))?;
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
+
+/// A tag message without its trailing signature.
+fn strip_signature(message: &str) -> &str {
+    [
+        "-----BEGIN PGP SIGNATURE-----",
+        "-----BEGIN SSH SIGNATURE-----",
+    ]
+    .iter()
+    .filter_map(|armor| message.find(armor))
+    .min()
+    .map_or(message, |start| &message[..start])
+}
+
+/// A path as fast-import reads it, C-quoted when it starts with a quote or holds a newline,
+/// backslash or other control character.
+pub fn quote_path(path: &str) -> String {
+    if !path.starts_with('"') && !path.chars().any(|c| c == '\\' || c.is_control()) {
+        return path.to_string();
+    }
+    let mut quoted = String::from("\"");
+    for c in path.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => write!(quoted, "\\{:03o}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::internal::object::blob::Blob;
+    use crate::internal::object::commit::Commit;
+    use crate::internal::object::signature::SignatureType;
+    use crate::internal::object::tree::{Tree, TreeItem};
+    use crate::protocol::memory::MemoryRepository;
+
+    fn signature(kind: SignatureType) -> Signature {
+        Signature::new(kind, "t".to_string(), "t@example.com".to_string())
+    }
+
+    /// Store the tree of `files`, `(path, mode, content)` with paths at most one directory
+    /// deep, and return its id.
+    fn tree(repo: &MemoryRepository, files: &[(&str, TreeItemMode, &str)]) -> SHA1 {
+        let mut items = Vec::new();
+        let mut dirs: Vec<(&str, Vec<TreeItem>)> = Vec::new();
+        for (path, mode, content) in files {
+            let blob = Blob::from_content(content);
+            repo.insert_object(&blob).unwrap();
+            match path.split_once('/') {
+                Some((dir, name)) => {
+                    let item = TreeItem::new(*mode, blob.id, name.to_string());
+                    match dirs.iter_mut().find(|(known, _)| known == &dir) {
+                        Some((_, entries)) => entries.push(item),
+                        None => dirs.push((dir, vec![item])),
+                    }
+                }
+                None => items.push(TreeItem::new(*mode, blob.id, path.to_string())),
+            }
+        }
+        for (dir, entries) in dirs {
+            let subtree = Tree::from_tree_items(entries).unwrap();
+            repo.insert_object(&subtree).unwrap();
+            items.push(TreeItem::new(TreeItemMode::Tree, subtree.id, dir.to_string()));
+        }
+        let tree = Tree::from_tree_items(items).unwrap();
+        repo.insert_object(&tree).unwrap();
+        tree.id
+    }
+
+    fn commit(repo: &MemoryRepository, tree: SHA1, parents: Vec<SHA1>, message: &str) -> SHA1 {
+        let commit = Commit::new(
+            signature(SignatureType::Author),
+            signature(SignatureType::Committer),
+            tree,
+            parents,
+            &format!("\n{message}"),
+        );
+        repo.insert_object(&commit).unwrap();
+        commit.id
+    }
+
+    async fn export(repo: &MemoryRepository, options: &FastExportOptions) -> String {
+        let stream = fast_export(repo, options).await.unwrap();
+        let chunks: Vec<Bytes> = stream.map(Result::unwrap).collect().await;
+        String::from_utf8(chunks.concat()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_fast_export() {
+        let repo = MemoryRepository::new();
+        let first = tree(
+            &repo,
+            &[
+                ("README", TreeItemMode::Blob, "hello\n"),
+                ("run.sh", TreeItemMode::BlobExecutable, "#!/bin/sh\n"),
+                ("link", TreeItemMode::Link, "README"),
+            ],
+        );
+        let root = commit(&repo, first, vec![], "first");
+        let second = tree(
+            &repo,
+            &[
+                ("README", TreeItemMode::Blob, "hello, world\n"),
+                ("run.sh", TreeItemMode::BlobExecutable, "#!/bin/sh\n"),
+                ("src/a\tb.rs", TreeItemMode::Blob, "fn main() {}\n"),
+            ],
+        );
+        let main = commit(&repo, second, vec![root], "second");
+        let feature_tree = tree(
+            &repo,
+            &[
+                ("README", TreeItemMode::Blob, "hello\n"),
+                ("run.sh", TreeItemMode::Blob, "#!/bin/sh\n"),
+            ],
+        );
+        let feature = commit(&repo, feature_tree, vec![root], "feature");
+        repo.set_ref("refs/heads/main", main);
+        repo.set_ref("refs/heads/feature", feature);
+        let tag = Tag::new(
+            main,
+            ObjectType::Commit,
+            "v1".to_string(),
+            signature(SignatureType::Tagger),
+            "release\n".to_string(),
+        );
+        repo.insert_object(&tag).unwrap();
+        repo.set_ref("refs/tags/v1", tag.id);
+
+        let stream = export(&repo, &FastExportOptions::default()).await;
+        let lines: Vec<&str> = stream.lines().collect();
+        // The root commit is on the first ref reaching it and comes first
+        let start = lines.iter().position(|l| *l == "reset refs/heads/feature").unwrap();
+        assert_eq!(lines[start + 1], "commit refs/heads/feature");
+        assert_eq!(&lines[..3], ["blob", "mark :1", "data 6"]);
+        assert!(stream.contains("data 5\nfirst\n"));
+        assert!(stream.contains("M 100644 :1 README\n"));
+        assert!(stream.contains("M 120000 :2 link\n"));
+        assert!(stream.contains("M 100755 :3 run.sh\n"));
+        // The second commit replaces a file, deletes the link and adds a quoted path
+        let changes = stream.split("data 6\nsecond\n").nth(1).unwrap();
+        assert!(changes.starts_with("from :4\nD link\nM 100644 :5 README\n"));
+        assert!(changes.contains("M 100644 :6 \"src/a\\tb.rs\"\n"));
+        // The feature commit only changes modes, writing no blobs
+        let feature_commit = stream.split("data 7\nfeature\n").nth(1).unwrap();
+        assert!(feature_commit.starts_with("from :4\nD link\nM 100644 :3 run.sh\n\n"));
+        assert_eq!(stream.matches("blob\n").count(), 5);
+        assert!(stream.contains("tag v1\nmark :"));
+        assert!(stream.contains("data 8\nrelease\n"));
+        assert!(stream.ends_with("reset refs/heads/main\nfrom :7\n\n"));
+        assert!(!stream.contains("original-oid"));
+
+        let options = FastExportOptions {
+            refs: vec!["refs/heads/feature".to_string()],
+            show_original_ids: true,
+        };
+        let stream = export(&repo, &options).await;
+        assert!(stream.contains(&format!("original-oid {feature}\n")));
+        assert!(!stream.contains("second") && !stream.contains("tag v1"));
+        let options = FastExportOptions {
+            refs: vec!["refs/heads/missing".to_string()],
+            show_original_ids: false,
+        };
+        assert!(fast_export(&repo, &options).await.is_err());
+    }
+
+    #[test]
+    fn test_quote_path() {
+        assert_eq!(quote_path("a b/c.txt"), "a b/c.txt");
+        assert_eq!(quote_path("\"a"), "\"\\\"a\"");
+        assert_eq!(quote_path("a\nb\\c\x01"), "\"a\\nb\\\\c\\001\"");
+        assert_eq!(quote_path("sub/a\"b"), "sub/a\"b");
+    }
+}
@@ -0,0 +1,1073 @@
+//! `git fast-import`-style streams read into a repository, for bulk imports without a work tree.
+//!
+//! [`fast_import`] parses the commands of a stream, as written by [`fast_export`] or by the
+//! exporters of other version control systems, and stores the objects they describe through a
+//! [`RepositoryAccess`] as it goes: `blob` stores a file content, `commit` a commit whose tree is
+//! its parent's with the `M`, `D`, `C`, `R` and `deleteall` changes applied, `tag` an annotated
+//! tag, and `reset` moves a branch. Objects are named by the `mark` given to them or by id.
+//!
+//! The refs are updated at each `checkpoint` and once the stream ends; like `git fast-import`
+//! without `--force`, updates losing commits of a branch are refused. The marks of a previous
+//! import can be given back, and the marks of this one are returned, in the marks file format of
+//! [`parse_marks`] and [`format_marks`], so a history can be imported in several runs.
+//!
+//! Only the `raw` date format is read; `ls`, `cat-blob`, `get-mark` and notes, which need a way
+//! to answer the frontend or a notes ref, are refused.
+//!
+//! [`fast_export`]: crate::fast_export::fast_export
+use std::collections::{BTreeMap, HashMap};
+
+use bytes::BytesMut;
+use futures::StreamExt;
+
+use crate::hash::SHA1;
+use crate::internal::object::blob::Blob;
+use crate::internal::object::commit::Commit;
+use crate::internal::object::signature::{Signature, SignatureType};
+use crate::internal::object::tag::Tag;
+use crate::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+use crate::internal::object::types::ObjectType;
+use crate::protocol::RepositoryAccess;
+use crate::protocol::pack::load_object;
+use crate::protocol::types::{ProtocolError, ProtocolStream, RefUpdate};
+use crate::revwalk::{GenerationNumbers, is_ancestor};
+
+/// How [`fast_import`] imports a stream.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FastImportOptions {
+    /// Update refs even when commits of their old tip aren't in the new one, like `--force`
+    pub force: bool,
+    /// Marks of a previous import, like `--import-marks`
+    pub marks: BTreeMap<u64, SHA1>,
+}
+
+/// What [`fast_import`] imported.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FastImportSummary {
+    /// Every mark known at the end of the stream, including those of the options
+    pub marks: BTreeMap<u64, SHA1>,
+    /// The refs updated, by name, with their new value
+    pub refs: Vec<(String, SHA1)>,
+    /// The refs left alone because the update would lose commits
+    pub rejected: Vec<String>,
+}
+
+/// Parse a marks file, a `:<mark> <id>` line per mark.
+pub fn parse_marks(marks: &str) -> Result<BTreeMap<u64, SHA1>, ProtocolError> {
+    let invalid =
+        |line: &str| ProtocolError::invalid_request(&format!("Invalid marks line '{line}'"));
+    let mut parsed = BTreeMap::new();
+    for line in marks.lines().filter(|line| !line.is_empty()) {
+        let (mark, id) = line
+            .strip_prefix(':')
+            .and_then(|line| line.split_once(' '))
+            .ok_or_else(|| invalid(line))?;
+        let mark = mark.parse().map_err(|_| invalid(line))?;
+        parsed.insert(mark, id.parse().map_err(|_| invalid(line))?);
+    }
+    Ok(parsed)
+}
+
+/// Write marks in the marks file format read by [`parse_marks`].
+pub fn format_marks(marks: &BTreeMap<u64, SHA1>) -> String {
+    marks
+        .iter()
+        .map(|(mark, id)| format!(":{mark} {id}\n"))
+        .collect()
+}
+
+/// Import a fast-import stream into `repo`, see the [module documentation](self).
+///
+/// Objects are stored as their command is read, so a failing stream leaves those before the
+/// failure in the repository, but no ref is updated past the last `checkpoint`.
+pub async fn fast_import<R: RepositoryAccess>(
+    repo: &R,
+    stream: ProtocolStream,
+    options: &FastImportOptions,
+) -> Result<FastImportSummary, ProtocolError> {
+    let mut importer = Importer {
+        repo,
+        input: Input {
+            stream,
+            buffer: BytesMut::new(),
+            pending: None,
+        },
+        force: options.force,
+        marks: options.marks.clone(),
+        branches: HashMap::new(),
+        trees: HashMap::new(),
+        refs: repo.get_repository_refs().await?.into_iter().collect(),
+        updated: BTreeMap::new(),
+        summary: FastImportSummary::default(),
+    };
+    let mut done_required = false;
+    loop {
+        let Some(line) = importer.input.line().await? else {
+            if done_required {
+                return Err(ProtocolError::invalid_request(
+                    "Stream ended without the done command",
+                ));
+            }
+            break;
+        };
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (command, argument) = line.split_once(' ').unwrap_or((&line, ""));
+        match command {
+            "blob" => importer.blob().await?,
+            "commit" => importer.commit(argument).await?,
+            "tag" => importer.tag(argument).await?,
+            "reset" => importer.reset(argument).await?,
+            "checkpoint" => importer.update_refs().await?,
+            "progress" => tracing::info!("fast-import: {argument}"),
+            "done" => break,
+            "feature" => match argument {
+                "done" => done_required = true,
+                "date-format=raw" | "date-format=raw-permissive" | "force" => {}
+                feature if feature.starts_with("import-marks") => {}
+                feature => {
+                    return Err(ProtocolError::invalid_request(&format!(
+                        "Unsupported fast-import feature '{feature}'"
+                    )));
+                }
+            },
+            // Options of other importers, or settings of the command line
+            "option" => {}
+            "alias" => {
+                let mark = importer.mark().await?;
+                let target = importer.expect("to ").await?;
+                let id = importer.commitish(&target)?;
+                if let Some(mark) = mark {
+                    importer.marks.insert(mark, id);
+                }
+            }
+            _ => {
+                return Err(ProtocolError::invalid_request(&format!(
+                    "Unsupported fast-import command '{line}'"
+                )));
+            }
+        }
+    }
+    importer.update_refs().await?;
+    let mut summary = importer.summary;
+    summary.marks = importer.marks;
+    Ok(summary)
+}
+
+/// The lines and data of a stream.
+struct Input {
+    stream: ProtocolStream,
+    buffer: BytesMut,
+    /// A line read ahead by an optional command that wasn't there
+    pending: Option<String>,
+}
+
+impl Input {
+    /// Whether at least `len` bytes are buffered, reading chunks until then.
+    async fn fill(&mut self, len: usize) -> Result<bool, ProtocolError> {
+        while self.buffer.len() < len {
+            match self.stream.next().await {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk?),
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    /// The next line without its newline, `None` at the end of the stream.
+    async fn line(&mut self) -> Result<Option<String>, ProtocolError> {
+        if let Some(line) = self.pending.take() {
+            return Ok(Some(line));
+        }
+        let mut searched = 0;
+        let end = loop {
+            if let Some(i) = self.buffer[searched..].iter().position(|b| *b == b'\n') {
+                break searched + i;
+            }
+            searched = self.buffer.len();
+            if !self.fill(searched + 1).await? {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                break self.buffer.len();
+            }
+        };
+        let line = self.buffer.split_to(end);
+        if !self.buffer.is_empty() {
+            let _ = self.buffer.split_to(1);
+        }
+        String::from_utf8(line.to_vec())
+            .map(Some)
+            .map_err(|_| ProtocolError::invalid_request("Invalid UTF-8 in fast-import command"))
+    }
+
+    fn unread(&mut self, line: String) {
+        self.pending = Some(line);
+    }
+
+    /// The content of the `data` command `line`, and the newline optionally following it.
+    async fn data(&mut self, line: &str) -> Result<Vec<u8>, ProtocolError> {
+        let Some(argument) = line.strip_prefix("data ") else {
+            return Err(ProtocolError::invalid_request(&format!(
+                "Expected data command, got '{line}'"
+            )));
+        };
+        if let Some(delimiter) = argument.strip_prefix("<<") {
+            let mut data = Vec::new();
+            loop {
+                match self.line().await? {
+                    Some(line) if line == delimiter => return Ok(data),
+                    Some(line) => {
+                        data.extend_from_slice(line.as_bytes());
+                        data.push(b'\n');
+                    }
+                    None => {
+                        return Err(ProtocolError::invalid_request(&format!(
+                            "Stream ended before the data delimiter {delimiter}"
+                        )));
+                    }
+                }
+            }
+        }
+        let len: usize = argument.parse().map_err(|_| {
+            ProtocolError::invalid_request(&format!("Invalid data length '{argument}'"))
+        })?;
+        if !self.fill(len).await? {
+            return Err(ProtocolError::invalid_request(
+                "Stream ended in the middle of data",
+            ));
+        }
+        let data = self.buffer.split_to(len).to_vec();
+        if self.fill(1).await? && self.buffer[0] == b'\n' {
+            let _ = self.buffer.split_to(1);
+        }
+        Ok(data)
+    }
+}
+
+/// An entry of a tree being built.
+#[derive(Debug, Clone)]
+enum Entry {
+    File { mode: TreeItemMode, id: SHA1 },
+    Dir(Dir),
+}
+
+/// A tree being built, read from the repository when first changed.
+#[derive(Debug, Clone, Default)]
+struct Dir {
+    /// The stored tree, until the directory is changed
+    id: Option<SHA1>,
+    entries: Option<BTreeMap<String, Entry>>,
+}
+
+impl Dir {
+    fn stored(id: SHA1) -> Self {
+        Self {
+            id: Some(id),
+            entries: None,
+        }
+    }
+
+    fn empty() -> Self {
+        Self {
+            id: None,
+            entries: Some(BTreeMap::new()),
+        }
+    }
+
+    /// The entries of the directory, read when first needed, and forgetting its stored tree
+    /// when `change`.
+    async fn entries<R: RepositoryAccess>(
+        &mut self,
+        repo: &R,
+        change: bool,
+    ) -> Result<&mut BTreeMap<String, Entry>, ProtocolError> {
+        if self.entries.is_none() {
+            let mut entries = BTreeMap::new();
+            if let Some(id) = self.id {
+                for item in repo.get_tree(&id.to_string()).await?.tree_items {
+                    let entry = match item.mode {
+                        TreeItemMode::Tree => Entry::Dir(Dir::stored(item.id)),
+                        mode => Entry::File { mode, id: item.id },
+                    };
+                    entries.insert(item.name, entry);
+                }
+            }
+            self.entries = Some(entries);
+        }
+        if change {
+            self.id = None;
+        }
+        Ok(self.entries.as_mut().unwrap())
+    }
+
+    /// The directory holding `path` and the name of `path` in it, creating the directories
+    /// on the way when `create`, and marking them changed when `change`.
+    async fn parent<'a, 'p, R: RepositoryAccess>(
+        &'a mut self,
+        repo: &R,
+        path: &'p str,
+        create: bool,
+        change: bool,
+    ) -> Result<Option<(&'a mut Dir, &'p str)>, ProtocolError> {
+        let (dirs, name) = path
+            .rsplit_once('/')
+            .map_or((None, path), |(d, n)| (Some(d), n));
+        let mut dir = self;
+        for component in dirs.into_iter().flat_map(|dirs| dirs.split('/')) {
+            let entries = dir.entries(repo, change).await?;
+            if create && !matches!(entries.get(component), Some(Entry::Dir(_))) {
+                entries.insert(component.to_string(), Entry::Dir(Dir::empty()));
+            }
+            match entries.get_mut(component) {
+                Some(Entry::Dir(child)) => dir = child,
+                _ => return Ok(None),
+            }
+        }
+        Ok(Some((dir, name)))
+    }
+
+    async fn set<R: RepositoryAccess>(
+        &mut self,
+        repo: &R,
+        path: &str,
+        entry: Entry,
+    ) -> Result<(), ProtocolError> {
+        if let Some((dir, name)) = self.parent(repo, path, true, true).await? {
+            dir.entries(repo, true)
+                .await?
+                .insert(name.to_string(), entry);
+        }
+        Ok(())
+    }
+
+    async fn remove<R: RepositoryAccess>(
+        &mut self,
+        repo: &R,
+        path: &str,
+    ) -> Result<Option<Entry>, ProtocolError> {
+        match self.parent(repo, path, false, true).await? {
+            Some((dir, name)) => Ok(dir.entries(repo, true).await?.remove(name)),
+            None => Ok(None),
+        }
+    }
+
+    async fn get<R: RepositoryAccess>(
+        &mut self,
+        repo: &R,
+        path: &str,
+    ) -> Result<Option<Entry>, ProtocolError> {
+        match self.parent(repo, path, false, false).await? {
+            Some((dir, name)) => Ok(dir.entries(repo, false).await?.get(name).cloned()),
+            None => Ok(None),
+        }
+    }
+
+    /// Store the changed trees below this one, returning its id, `None` when it's empty.
+    async fn write<R: RepositoryAccess>(
+        &mut self,
+        repo: &R,
+    ) -> Result<Option<SHA1>, ProtocolError> {
+        if let Some(id) = self.id {
+            return Ok(Some(id));
+        }
+        let mut items = Vec::new();
+        for (name, entry) in self.entries.get_or_insert_default() {
+            match entry {
+                Entry::File { mode, id } => items.push(TreeItem::new(*mode, *id, name.clone())),
+                Entry::Dir(dir) => {
+                    if let Some(id) = Box::pin(dir.write(repo)).await? {
+                        items.push(TreeItem::new(TreeItemMode::Tree, id, name.clone()));
+                    }
+                }
+            }
+        }
+        if items.is_empty() {
+            return Ok(None);
+        }
+        let tree = Tree::from_tree_items(items)
+            .map_err(|e| ProtocolError::invalid_request(&format!("Invalid tree: {e}")))?;
+        let id = tree.id;
+        repo.handle_pack_objects(vec![], vec![tree], vec![], vec![], None)
+            .await?;
+        self.id = Some(id);
+        Ok(Some(id))
+    }
+}
+
+/// A branch of the stream.
+#[derive(Default)]
+struct Branch {
+    tip: Option<SHA1>,
+    tree: Dir,
+}
+
+struct Importer<'r, R> {
+    repo: &'r R,
+    input: Input,
+    force: bool,
+    marks: BTreeMap<u64, SHA1>,
+    branches: HashMap<String, Branch>,
+    /// The tree of each commit of the stream
+    trees: HashMap<SHA1, SHA1>,
+    /// The refs of the repository, as last updated
+    refs: HashMap<String, String>,
+    /// The refs changed since they were last updated
+    updated: BTreeMap<String, SHA1>,
+    summary: FastImportSummary,
+}
+
+impl<R: RepositoryAccess> Importer<'_, R> {
+    /// The argument of the command `prefix` on the next line, failing when it's another.
+    async fn expect(&mut self, prefix: &str) -> Result<String, ProtocolError> {
+        match self.optional(prefix).await? {
+            Some(argument) => Ok(argument),
+            None => Err(ProtocolError::invalid_request(&format!(
+                "Expected {} command",
+                prefix.trim_end()
+            ))),
+        }
+    }
+
+    /// The argument of the command `prefix` on the next line, if it's that command.
+    async fn optional(&mut self, prefix: &str) -> Result<Option<String>, ProtocolError> {
+        match self.input.line().await? {
+            Some(line) => match line.strip_prefix(prefix) {
+                Some(argument) => Ok(Some(argument.to_string())),
+                None => {
+                    self.input.unread(line);
+                    Ok(None)
+                }
+            },
+            None => Ok(None),
+        }
+    }
+
+    async fn mark(&mut self) -> Result<Option<u64>, ProtocolError> {
+        let Some(mark) = self.optional("mark :").await? else {
+            return Ok(None);
+        };
+        let mark = mark
+            .parse()
+            .map_err(|_| ProtocolError::invalid_request(&format!("Invalid mark :{mark}")))?;
+        Ok(Some(mark))
+    }
+
+    async fn data(&mut self) -> Result<Vec<u8>, ProtocolError> {
+        let line = self.input.line().await?.unwrap_or_default();
+        self.input.data(&line).await
+    }
+
+    /// The object a `:<mark>` or an id names.
+    fn dataref(&self, reference: &str) -> Result<SHA1, ProtocolError> {
+        match reference.strip_prefix(':') {
+            Some(mark) => mark
+                .parse()
+                .ok()
+                .and_then(|mark: u64| self.marks.get(&mark).copied())
+                .ok_or_else(|| {
+                    ProtocolError::invalid_request(&format!("Unknown mark {reference}"))
+                }),
+            None => reference.parse().map_err(|_| {
+                ProtocolError::invalid_request(&format!("Invalid object reference '{reference}'"))
+            }),
+        }
+    }
+
+    /// The commit a `from`, `merge` or `alias` names: a mark, an id, a branch of the stream or
+    /// a ref of the repository, the latter usually as `<ref>^0` to continue an existing branch.
+    fn commitish(&self, reference: &str) -> Result<SHA1, ProtocolError> {
+        if let Some(branch) = self.branches.get(reference) {
+            return branch.tip.ok_or_else(|| {
+                ProtocolError::invalid_request(&format!("Branch {reference} has no commits"))
+            });
+        }
+        if let Some(id) = self
+            .refs
+            .get(reference.strip_suffix("^0").unwrap_or(reference))
+        {
+            return id.parse().map_err(|_| {
+                ProtocolError::repository_error(format!("Invalid hash {id} of {reference}"))
+            });
+        }
+        self.dataref(reference)
+    }
+
+    /// The tree of `commit`.
+    async fn tree_of(&self, commit: SHA1) -> Result<SHA1, ProtocolError> {
+        match self.trees.get(&commit) {
+            Some(tree) => Ok(*tree),
+            None => Ok(self.repo.get_commit(&commit.to_string()).await?.tree_id),
+        }
+    }
+
+    /// Start `branch` from the commit `reference` names, or empty for the null id.
+    async fn start_branch(&mut self, branch: &str, reference: &str) -> Result<(), ProtocolError> {
+        let tip = self.commitish(reference)?;
+        let start = match tip == SHA1::default() {
+            true => Branch {
+                tip: None,
+                tree: Dir::empty(),
+            },
+            false => Branch {
+                tip: Some(tip),
+                tree: Dir::stored(self.tree_of(tip).await?),
+            },
+        };
+        self.branches.insert(branch.to_string(), start);
+        Ok(())
+    }
+
+    async fn blob(&mut self) -> Result<(), ProtocolError> {
+        let mark = self.mark().await?;
+        self.optional("original-oid ").await?;
+        let blob = Blob::from_content_bytes(self.data().await?);
+        let id = blob.id;
+        self.repo
+            .handle_pack_objects(vec![], vec![], vec![blob], vec![], None)
+            .await?;
+        if let Some(mark) = mark {
+            self.marks.insert(mark, id);
+        }
+        Ok(())
+    }
+
+    async fn commit(&mut self, name: &str) -> Result<(), ProtocolError> {
+        let mark = self.mark().await?;
+        self.optional("original-oid ").await?;
+        let author = match self.optional("author ").await? {
+            Some(ident) => Some(parse_ident(SignatureType::Author, &ident)?),
+            None => None,
+        };
+        let committer = parse_ident(SignatureType::Committer, &self.expect("committer ").await?)?;
+        let author = author.unwrap_or_else(|| Signature {
+            signature_type: SignatureType::Author,
+            ..committer.clone()
+        });
+        // Signatures don't survive the new ids of a rewritten history
+        self.optional("gpgsig ").await?;
+        let encoding = self.optional("encoding ").await?;
+        let message = String::from_utf8(self.data().await?)
+            .map_err(|_| ProtocolError::invalid_request("Commit message is not UTF-8"))?;
+
+        if let Some(from) = self.optional("from ").await? {
+            self.start_branch(name, &from).await?;
+        }
+        let mut branch = self.branches.remove(name).unwrap_or_default();
+        let mut parents: Vec<SHA1> = branch.tip.into_iter().collect();
+        while let Some(merge) = self.optional("merge ").await? {
+            parents.push(self.commitish(&merge)?);
+        }
+        while let Some(line) = self.input.line().await? {
+            if !self.file_change(&mut branch.tree, &line).await? {
+                self.input.unread(line);
+                break;
+            }
+        }
+
+        let tree = match branch.tree.write(self.repo).await? {
+            Some(id) => id,
+            None => {
+                let tree = Tree {
+                    id: SHA1::from_type_and_data(ObjectType::Tree, &[]),
+                    tree_items: vec![],
+                };
+                let id = tree.id;
+                self.repo
+                    .handle_pack_objects(vec![], vec![tree], vec![], vec![], None)
+                    .await?;
+                id
+            }
+        };
+        let commit = match encoding {
+            Some(encoding) => Commit::with_extra_headers(
+                author,
+                committer,
+                tree,
+                parents,
+                &[("encoding".to_string(), encoding)],
+                &message,
+            ),
+            None => Commit::new(author, committer, tree, parents, &format!("\n{message}")),
+        };
+        let id = commit.id;
+        self.repo
+            .handle_pack_objects(vec![commit], vec![], vec![], vec![], None)
+            .await?;
+        if let Some(mark) = mark {
+            self.marks.insert(mark, id);
+        }
+        self.trees.insert(id, tree);
+        branch.tip = Some(id);
+        self.branches.insert(name.to_string(), branch);
+        self.updated.insert(name.to_string(), id);
+        Ok(())
+    }
+
+    /// Apply the file change `line` to `tree`, `false` when it's another command.
+    async fn file_change(&mut self, tree: &mut Dir, line: &str) -> Result<bool, ProtocolError> {
+        let repo = self.repo;
+        if line == "deleteall" {
+            *tree = Dir::empty();
+        } else if let Some(change) = line.strip_prefix("M ") {
+            let invalid = || ProtocolError::invalid_request(&format!("Invalid change '{line}'"));
+            let (mode, rest) = change.split_once(' ').ok_or_else(invalid)?;
+            let (reference, path) = rest.split_once(' ').ok_or_else(invalid)?;
+            let mode = match mode {
+                "100644" | "644" => TreeItemMode::Blob,
+                "100755" | "755" => TreeItemMode::BlobExecutable,
+                "120000" => TreeItemMode::Link,
+                "160000" => TreeItemMode::Commit,
+                "040000" | "40000" => TreeItemMode::Tree,
+                _ => return Err(invalid()),
+            };
+            let path = unquote_path(path)?;
+            let id = match reference {
+                "inline" => {
+                    let blob = Blob::from_content_bytes(self.data().await?);
+                    let id = blob.id;
+                    repo.handle_pack_objects(vec![], vec![], vec![blob], vec![], None)
+                        .await?;
+                    id
+                }
+                reference => self.dataref(reference)?,
+            };
+            let entry = match mode {
+                TreeItemMode::Tree => Entry::Dir(Dir::stored(id)),
+                mode => Entry::File { mode, id },
+            };
+            match path.is_empty() && mode == TreeItemMode::Tree {
+                // The whole tree is replaced
+                true => *tree = Dir::stored(id),
+                false => tree.set(repo, &path, entry).await?,
+            }
+        } else if let Some(path) = line.strip_prefix("D ") {
+            tree.remove(repo, &unquote_path(path)?).await?;
+        } else if let Some((rename, paths)) = line
+            .strip_prefix("C ")
+            .map(|paths| (false, paths))
+            .or_else(|| line.strip_prefix("R ").map(|paths| (true, paths)))
+        {
+            let (source, destination) = split_paths(paths)?;
+            let entry = match rename {
+                true => tree.remove(repo, &source).await?,
+                false => tree.get(repo, &source).await?,
+            };
+            let entry = entry.ok_or_else(|| {
+                ProtocolError::invalid_request(&format!("Path {source} not in branch"))
+            })?;
+            tree.set(repo, &destination, entry).await?;
+        } else if line.starts_with("N ") {
+            return Err(ProtocolError::invalid_request(
+                "Notes aren't supported by fast-import",
+            ));
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    async fn tag(&mut self, name: &str) -> Result<(), ProtocolError> {
+        let mark = self.mark().await?;
+        let from = self.expect("from ").await?;
+        let object = self.commitish(&from)?;
+        self.optional("original-oid ").await?;
+        let tagger = match self.optional("tagger ").await? {
+            Some(ident) => parse_ident(SignatureType::Tagger, &ident)?,
+            None => {
+                return Err(ProtocolError::invalid_request(&format!(
+                    "Tag {name} has no tagger"
+                )));
+            }
+        };
+        let message = String::from_utf8(self.data().await?)
+            .map_err(|_| ProtocolError::invalid_request("Tag message is not UTF-8"))?;
+        let object_type = match load_object(self.repo, &object).await? {
+            Some((object_type, _)) => object_type,
+            None => return Err(ProtocolError::ObjectNotFound(object.to_string())),
+        };
+        let tag = Tag::new(object, object_type, name.to_string(), tagger, message);
+        let id = tag.id;
+        self.repo
+            .handle_pack_objects(vec![], vec![], vec![], vec![tag], None)
+            .await?;
+        if let Some(mark) = mark {
+            self.marks.insert(mark, id);
+        }
+        self.updated.insert(format!("refs/tags/{name}"), id);
+        Ok(())
+    }
+
+    async fn reset(&mut self, name: &str) -> Result<(), ProtocolError> {
+        match self.optional("from ").await? {
+            Some(from) => {
+                self.start_branch(name, &from).await?;
+                if let Some(tip) = self.branches[name].tip {
+                    self.updated.insert(name.to_string(), tip);
+                }
+            }
+            None => {
+                self.branches.insert(name.to_string(), Branch::default());
+            }
+        }
+        Ok(())
+    }
+
+    /// Update the refs changed since the last update, refusing those losing commits.
+    async fn update_refs(&mut self) -> Result<(), ProtocolError> {
+        let mut updates = Vec::new();
+        let mut generations = GenerationNumbers::new();
+        for (name, id) in std::mem::take(&mut self.updated) {
+            let old = self.refs.get(&name).cloned();
+            let new_hash = id.to_string();
+            if old.as_deref() == Some(new_hash.as_str()) {
+                continue;
+            }
+            if let Some(old) = &old
+                && !self.force
+                && let Ok(old_id) = old.parse::<SHA1>()
+                && matches!(
+                    load_object(self.repo, &old_id).await?,
+                    Some((ObjectType::Commit, _))
+                )
+                && !is_ancestor(self.repo, old_id, id, &mut generations).await?
+            {
+                tracing::warn!("Not updating {name}, {id} doesn't contain {old}");
+                self.summary.rejected.push(name);
+                continue;
+            }
+            updates.push(RefUpdate {
+                ref_name: name,
+                old_hash: old,
+                new_hash,
+            });
+        }
+        let results = self.repo.update_references(&updates).await?;
+        for (update, result) in updates.into_iter().zip(results) {
+            result?;
+            let id = update.new_hash.parse().unwrap();
+            self.refs.insert(update.ref_name.clone(), update.new_hash);
+            self.summary
+                .refs
+                .retain(|(name, _)| *name != update.ref_name);
+            self.summary.refs.push((update.ref_name, id));
+        }
+        Ok(())
+    }
+}
+
+/// A `Name <email> <time> <offset>` identity in the `raw` date format.
+fn parse_ident(signature_type: SignatureType, ident: &str) -> Result<Signature, ProtocolError> {
+    let invalid = || ProtocolError::invalid_request(&format!("Invalid identity '{ident}'"));
+    let (name, rest) = ident.split_once('<').ok_or_else(invalid)?;
+    let (email, date) = rest.split_once('>').ok_or_else(invalid)?;
+    let (timestamp, timezone) = date.trim_start().split_once(' ').ok_or_else(invalid)?;
+    Ok(Signature {
+        signature_type,
+        name: name.trim_end().to_string(),
+        email: email.to_string(),
+        timestamp: timestamp.parse().map_err(|_| invalid())?,
+        timezone: timezone.to_string(),
+    })
+}
+
+/// The source and destination of a `C` or `R` change, the source C-quoted when it holds a space.
+fn split_paths(paths: &str) -> Result<(String, String), ProtocolError> {
+    let invalid = || ProtocolError::invalid_request(&format!("Invalid paths '{paths}'"));
+    let split = match paths.starts_with('"') {
+        true => {
+            let mut escaped = false;
+            let end = paths[1..]
+                .char_indices()
+                .find(|&(_, c)| {
+                    let end = c == '"' && !escaped;
+                    escaped = c == '\\' && !escaped;
+                    end
+                })
+                .ok_or_else(invalid)?
+                .0;
+            end + 2
+        }
+        false => paths.find(' ').ok_or_else(invalid)?,
+    };
+    let (source, destination) = paths.split_at(split);
+    let destination = destination.strip_prefix(' ').ok_or_else(invalid)?;
+    Ok((unquote_path(source)?, unquote_path(destination)?))
+}
+
+/// A path of a file change, unquoting the C-quoted ones.
+pub fn unquote_path(path: &str) -> Result<String, ProtocolError> {
+    let invalid = || ProtocolError::invalid_request(&format!("Invalid quoted path {path}"));
+    let Some(quoted) = path.strip_prefix('"') else {
+        return Ok(path.to_string());
+    };
+    let quoted = quoted.strip_suffix('"').ok_or_else(invalid)?;
+    let mut bytes = Vec::new();
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut encoded = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut encoded).as_bytes());
+            continue;
+        }
+        let byte = match chars.next().ok_or_else(invalid)? {
+            'n' => b'\n',
+            't' => b'\t',
+            'r' => b'\r',
+            'a' => 0x07,
+            'b' => 0x08,
+            'f' => 0x0c,
+            'v' => 0x0b,
+            '"' => b'"',
+            '\\' => b'\\',
+            digit @ '0'..='3' => {
+                let digits: String = [Some(digit), chars.next(), chars.next()]
+                    .into_iter()
+                    .collect::<Option<_>>()
+                    .ok_or_else(invalid)?;
+                u8::from_str_radix(&digits, 8).map_err(|_| invalid())?
+            }
+            _ => return Err(invalid()),
+        };
+        bytes.push(byte);
+    }
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::fast_export::{FastExportOptions, fast_export};
+    use crate::internal::object::tree::TreeItem;
+    use crate::protocol::memory::MemoryRepository;
+
+    fn stream(data: &[u8]) -> ProtocolStream {
+        // Split, so commands and data span several chunks
+        let chunks: Vec<Result<Bytes, ProtocolError>> = data
+            .chunks(5)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        Box::pin(futures::stream::iter(chunks))
+    }
+
+    async fn files(repo: &MemoryRepository, tree: SHA1, prefix: &str) -> Vec<(String, String)> {
+        let mut found = Vec::new();
+        for item in repo.get_tree(&tree.to_string()).await.unwrap().tree_items {
+            let path = format!("{prefix}{}", item.name);
+            match item.mode {
+                TreeItemMode::Tree => {
+                    found.extend(Box::pin(files(repo, item.id, &format!("{path}/"))).await)
+                }
+                _ => {
+                    let content = repo.get_object(&item.id.to_string()).await.unwrap();
+                    found.push((path, String::from_utf8(content).unwrap()));
+                }
+            }
+        }
+        found
+    }
+
+    async fn tip(repo: &MemoryRepository, name: &str) -> Commit {
+        let refs = repo.get_repository_refs().await.unwrap();
+        let (_, id) = refs.iter().find(|(known, _)| known == name).unwrap();
+        repo.get_commit(id).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_fast_import() {
+        let repo = MemoryRepository::new();
+        let input = b"feature done\n\
+blob\nmark :1\ndata 6\nhello\n\n\
+commit refs/heads/main\nmark :2\n\
+author A U Thor <author@example.com> 1700000000 +0100\n\
+committer C O Mitter <committer@example.com> 1700000100 +0000\n\
+data <<EOF\nfirst\nEOF\n\
+M 100644 :1 README\nM 100755 inline \"bin/run \\\"me\\\"\"\ndata 3\nrun\n\n\
+commit refs/heads/main\nmark :3\n\
+committer C O Mitter <committer@example.com> 1700000200 +0000\n\
+data 7\nsecond\n\
+C README docs/README\nR \"bin/run \\\"me\\\"\" run\nD missing\n\n\
+reset refs/heads/old\nfrom :2\n\n\
+tag v1\nfrom :3\ntagger T <t@example.com> 1700000300 +0000\ndata 8\nrelease\n\
+progress imported\ndone\n";
+        let summary = fast_import(&repo, stream(input), &FastImportOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(summary.marks.len(), 3);
+        assert!(summary.rejected.is_empty());
+        let names: Vec<&str> = summary.refs.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["refs/heads/main", "refs/heads/old", "refs/tags/v1"]);
+
+        let main = tip(&repo, "refs/heads/main").await;
+        assert_eq!(main.id, summary.marks[&3]);
+        assert_eq!(main.parent_commit_ids, [summary.marks[&2]]);
+        assert_eq!(main.message_body(), "second\n");
+        // Without an author line the committer is the author
+        assert_eq!(main.author.name, "C O Mitter");
+        assert_eq!(
+            files(&repo, main.tree_id, "").await,
+            [
+                ("README".to_string(), "hello\n".to_string()),
+                ("docs/README".to_string(), "hello\n".to_string()),
+                ("run".to_string(), "run".to_string()),
+            ]
+        );
+        let first = repo
+            .get_commit(&summary.marks[&2].to_string())
+            .await
+            .unwrap();
+        let author = &first.author;
+        assert_eq!(
+            (author.name.as_str(), author.email.as_str()),
+            ("A U Thor", "author@example.com")
+        );
+        assert_eq!(
+            (author.timestamp, author.timezone.as_str()),
+            (1700000000, "+0100")
+        );
+        let tree = repo.get_tree(&first.tree_id.to_string()).await.unwrap();
+        let bin = tree
+            .tree_items
+            .iter()
+            .find(|item| item.name == "bin")
+            .unwrap();
+        let run = repo.get_tree(&bin.id.to_string()).await.unwrap();
+        assert_eq!(run.tree_items[0].name, "run \"me\"");
+        assert_eq!(run.tree_items[0].mode, TreeItemMode::BlobExecutable);
+        assert_eq!(tip(&repo, "refs/heads/old").await.id, first.id);
+        let tag = repo.get_tag(&summary.refs[2].1.to_string()).await.unwrap();
+        assert_eq!(
+            (tag.object_hash, tag.message.as_str()),
+            (main.id, "release\n")
+        );
+
+        // A later import continues from the marks of this one, and can't rewind a branch
+        let options = FastImportOptions {
+            force: false,
+            marks: parse_marks(&format_marks(&summary.marks)).unwrap(),
+        };
+        let rewind = b"commit refs/heads/main\n\
+committer C <c@example.com> 1700000400 +0000\ndata 7\nrewind\nfrom :2\ndeleteall\n\n\
+commit refs/heads/next\n\
+committer C <c@example.com> 1700000500 +0000\ndata 5\nnext\nfrom refs/heads/main^0\n\
+M 100644 inline docs/NEWS\ndata 4\nnew\n\n";
+        let summary = fast_import(&repo, stream(rewind), &options).await.unwrap();
+        assert_eq!(summary.rejected, ["refs/heads/main"]);
+        assert_eq!(tip(&repo, "refs/heads/main").await.id, main.id);
+        let next = tip(&repo, "refs/heads/next").await;
+        assert_eq!(next.parent_commit_ids, [main.id]);
+        // Changing the stored tree of the branch keeps the files around the change
+        let paths: Vec<String> = files(&repo, next.tree_id, "")
+            .await
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(paths, ["README", "docs/NEWS", "docs/README", "run"]);
+        let empty = SHA1::from_type_and_data(ObjectType::Tree, &[]);
+        let force = FastImportOptions {
+            force: true,
+            ..options
+        };
+        fast_import(&repo, stream(rewind), &force).await.unwrap();
+        assert_eq!(tip(&repo, "refs/heads/main").await.tree_id, empty);
+
+        for invalid in [
+            &b"feature done\nblob\ndata 1\na\n"[..],
+            b"blob\ndata 10\nshort",
+            b"commit refs/heads/x\ncommitter C <c@example.com> 1 +0000\ndata 0\nM 100644 :9 a\n",
+            b"ls \"a\"\n",
+        ] {
+            let result = fast_import(&repo, stream(invalid), &FastImportOptions::default()).await;
+            assert!(result.is_err(), "{}", String::from_utf8_lossy(invalid));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fast_export_round_trip() {
+        let source = MemoryRepository::new();
+        let blob = |content: &str| {
+            let blob = Blob::from_content(content);
+            source.insert_object(&blob).unwrap();
+            blob.id
+        };
+        let sub = Tree::from_tree_items(vec![TreeItem::new(
+            TreeItemMode::Blob,
+            blob("nested\n"),
+            "a\tb.txt".to_string(),
+        )])
+        .unwrap();
+        source.insert_object(&sub).unwrap();
+        let tree = |items: Vec<TreeItem>| {
+            let tree = Tree::from_tree_items(items).unwrap();
+            source.insert_object(&tree).unwrap();
+            tree.id
+        };
+        let first = tree(vec![
+            TreeItem::new(TreeItemMode::Blob, blob("one\n"), "file".to_string()),
+            TreeItem::new(TreeItemMode::Link, blob("file"), "link".to_string()),
+        ]);
+        let second = tree(vec![
+            TreeItem::new(
+                TreeItemMode::BlobExecutable,
+                blob("two\n"),
+                "file".to_string(),
+            ),
+            TreeItem::new(TreeItemMode::Tree, sub.id, "sub".to_string()),
+        ]);
+        let signature = |signature_type, timestamp| Signature {
+            signature_type,
+            name: "T".to_string(),
+            email: "t@example.com".to_string(),
+            timestamp,
+            timezone: "+0200".to_string(),
+        };
+        let commit = |tree, parents, time, message: &str| {
+            // The merge keeps the encoding header of a reencoded message
+            let headers = match time {
+                3 => vec![("encoding".to_string(), "ISO-8859-1".to_string())],
+                _ => vec![],
+            };
+            let commit = Commit::with_extra_headers(
+                signature(SignatureType::Author, time),
+                signature(SignatureType::Committer, time),
+                tree,
+                parents,
+                &headers,
+                message,
+            );
+            source.insert_object(&commit).unwrap();
+            commit.id
+        };
+        let root = commit(first, vec![], 1, "root\n");
+        let side = commit(second, vec![root], 2, "side\n");
+        let merge = commit(second, vec![root, side], 3, "merge\n\nbody\n");
+        source.set_ref("refs/heads/main", merge);
+        source.set_ref("refs/heads/side", side);
+        let tag = Tag::new(
+            side,
+            ObjectType::Commit,
+            "v1".to_string(),
+            signature(SignatureType::Tagger, 4),
+            "tag\n".to_string(),
+        );
+        source.insert_object(&tag).unwrap();
+        source.set_ref("refs/tags/v1", tag.id);
+
+        let exported = fast_export(&source, &FastExportOptions::default())
+            .await
+            .unwrap();
+        let target = MemoryRepository::new();
+        fast_import(&target, exported, &FastImportOptions::default())
+            .await
+            .unwrap();
+        // Unsigned objects come back with the same ids
+        let mut refs = target.get_repository_refs().await.unwrap();
+        let mut expected = source.get_repository_refs().await.unwrap();
+        refs.sort();
+        expected.sort();
+        assert_eq!(refs, expected);
+    }
+}
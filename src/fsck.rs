@@ -0,0 +1,1371 @@
+//! `git fsck`-style validation of object contents.
+//!
+//! Parsing an object only tells whether the crate can read it; [`Fsck`] checks what git itself
+//! refuses with `receive.fsckObjects` or reports in `git fsck`: tree entries with unusual modes,
+//! names such as `.git`, `..` or `a/b`, duplicate or unsorted entries, commit and tag headers out
+//! of their required order, malformed identities and timezones, and links to objects that are
+//! neither checked nor present in the repository.
+//!
+//! Like git, names that NTFS or HFS+ would open as `.git` count as `.git` too: `git~1`, `.git.`
+//! or `.git::$INDEX_ALLOCATION` on Windows and `.g\u{200c}it` on macOS. The `.gitmodules`
+//! blobs of checked trees are parsed, rejecting the submodule names, urls, paths and `update`
+//! commands that made git run commands or write outside of the repository on clone.
+//!
+//! Every problem has a [`FsckMsgId`], named after git's message ids, and a [`FsckSeverity`]
+//! taken from [`FsckOptions`], so a host can map its `fsck.<msg-id>` configuration onto the
+//! checks. Problems at [`FsckSeverity::Error`] make
+//! [`SmartProtocol`](crate::protocol::smart::SmartProtocol) reject a push when it has
+//! `fsck_objects` set; it checks pushes in [strict](FsckOptions::strict) mode, as git does.
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use crate::errors::GitError;
+use crate::hash::SHA1;
+use crate::internal::object::types::ObjectType;
+use crate::internal::refs::validate_ref_name;
+
+/// How a problem found by [`Fsck`] is treated, from the most to the least severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FsckSeverity {
+    /// The object is rejected.
+    Error,
+    Warn,
+    Info,
+    /// The problem is not reported.
+    Ignore,
+}
+
+impl Display for FsckSeverity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FsckSeverity::Error => "error",
+            FsckSeverity::Warn => "warning",
+            FsckSeverity::Info => "info",
+            FsckSeverity::Ignore => "ignore",
+        })
+    }
+}
+
+impl FromStr for FsckSeverity {
+    type Err = GitError;
+
+    /// Parse the value of a `fsck.<msg-id>` setting.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(FsckSeverity::Error),
+            "warn" | "warning" => Ok(FsckSeverity::Warn),
+            "info" => Ok(FsckSeverity::Info),
+            "ignore" => Ok(FsckSeverity::Ignore),
+            _ => Err(GitError::InvalidArgument(format!(
+                "unknown fsck severity `{s}`"
+            ))),
+        }
+    }
+}
+
+/// Declares the message ids with their git names and default severities.
+macro_rules! fsck_msg_ids {
+    ($($(#[$doc:meta])* $id:ident = $name:literal, $severity:ident;)*) => {
+        /// A kind of problem found by [`Fsck`], see [`FsckMsgId::name`] for git's name of it.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum FsckMsgId {
+            $($(#[$doc])* $id,)*
+        }
+
+        impl FsckMsgId {
+            /// Every message id.
+            pub const ALL: &[FsckMsgId] = &[$(FsckMsgId::$id,)*];
+
+            /// The name of the id in git's configuration and messages, e.g. `duplicateEntries`.
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(FsckMsgId::$id => $name,)*
+                }
+            }
+
+            /// The severity of the id when [`FsckOptions`] doesn't override it, git's default.
+            pub fn default_severity(&self) -> FsckSeverity {
+                match self {
+                    $(FsckMsgId::$id => FsckSeverity::$severity,)*
+                }
+            }
+        }
+    };
+}
+
+fsck_msg_ids! {
+    /// A tree entry can't be parsed.
+    BadTree = "badTree", Error;
+    /// A tree entry has a mode git doesn't write.
+    BadFilemode = "badFilemode", Info;
+    /// A tree entry mode has a leading zero.
+    ZeroPaddedFilemode = "zeroPaddedFilemode", Warn;
+    /// A tree entry has the null object id.
+    NullSha1 = "nullSha1", Warn;
+    /// A tree entry name holds a slash.
+    FullPathname = "fullPathname", Warn;
+    /// A tree entry name is empty.
+    EmptyName = "emptyName", Warn;
+    /// A tree entry is named `.`.
+    HasDot = "hasDot", Warn;
+    /// A tree entry is named `..`.
+    HasDotdot = "hasDotdot", Warn;
+    /// A tree entry is named `.git`, in any case.
+    HasDotgit = "hasDotgit", Warn;
+    /// Two entries of a tree have the same name.
+    DuplicateEntries = "duplicateEntries", Error;
+    /// The entries of a tree are not in git's canonical order.
+    TreeNotSorted = "treeNotSorted", Error;
+    /// A commit or tag header holds a NUL byte.
+    NulInHeader = "nulInHeader", Error;
+    /// The last header of a commit or tag without a message doesn't end with a newline.
+    UnterminatedHeader = "unterminatedHeader", Error;
+    /// A commit doesn't start with its `tree` header.
+    MissingTree = "missingTree", Error;
+    /// The `tree` header of a commit isn't an object id.
+    BadTreeSha1 = "badTreeSha1", Error;
+    /// A `parent` header of a commit isn't an object id.
+    BadParentSha1 = "badParentSha1", Error;
+    /// The `author` header doesn't follow the `tree` and `parent` headers.
+    MissingAuthor = "missingAuthor", Error;
+    /// A commit has more than one `author` header.
+    MultipleAuthors = "multipleAuthors", Error;
+    /// The `committer` header doesn't follow the `author` header.
+    MissingCommitter = "missingCommitter", Error;
+    /// An identity has no name before its email.
+    MissingNameBeforeEmail = "missingNameBeforeEmail", Error;
+    /// An identity has no `<email>`.
+    MissingEmail = "missingEmail", Error;
+    /// The name of an identity isn't followed by a space.
+    MissingSpaceBeforeEmail = "missingSpaceBeforeEmail", Error;
+    /// An identity has a `>` in its name.
+    BadName = "badName", Error;
+    /// The email of an identity isn't terminated by a `>`.
+    BadEmail = "badEmail", Error;
+    /// The email of an identity isn't followed by a space.
+    MissingSpaceBeforeDate = "missingSpaceBeforeDate", Error;
+    /// The timestamp of an identity has a leading zero.
+    ZeroPaddedDate = "zeroPaddedDate", Error;
+    /// The timestamp of an identity doesn't fit in 64 bits.
+    BadDateOverflow = "badDateOverflow", Error;
+    /// The timestamp of an identity isn't a number followed by a space.
+    BadDate = "badDate", Error;
+    /// The timezone of an identity isn't a sign followed by four digits.
+    BadTimezone = "badTimezone", Error;
+    /// A tag doesn't start with its `object` header.
+    MissingObject = "missingObject", Error;
+    /// The `object` header of a tag isn't an object id.
+    BadObjectSha1 = "badObjectSha1", Error;
+    /// The `object` header of a tag isn't followed by a `type` header.
+    MissingTypeEntry = "missingTypeEntry", Error;
+    /// The `type` header of a tag isn't an object type.
+    BadType = "badType", Error;
+    /// The `type` header of a tag isn't followed by a `tag` header.
+    MissingTagEntry = "missingTagEntry", Error;
+    /// The name of a tag isn't a valid reference name.
+    BadTagName = "badTagName", Info;
+    /// The `tag` header of a tag isn't followed by a `tagger` header.
+    MissingTaggerEntry = "missingTaggerEntry", Info;
+    /// An object links to an object that is neither checked nor present.
+    BrokenLink = "brokenLink", Error;
+    /// An object links to an object of another type than the link requires.
+    BadLinkType = "badLinkType", Error;
+    /// A `.gitmodules` entry of a tree is a symbolic link.
+    GitmodulesSymlink = "gitmodulesSymlink", Error;
+    /// A `.gitmodules` entry of a tree is a tree or a submodule.
+    GitmodulesBlob = "gitmodulesBlob", Error;
+    /// A `.gitmodules` blob isn't valid git configuration.
+    GitmodulesParse = "gitmodulesParse", Info;
+    /// A submodule name is empty or has a `..` component.
+    GitmodulesName = "gitmodulesName", Error;
+    /// A submodule url looks like a command line option or holds a newline.
+    GitmodulesUrl = "gitmodulesUrl", Error;
+    /// A submodule path looks like a command line option.
+    GitmodulesPath = "gitmodulesPath", Error;
+    /// A submodule `update` setting is a `!command`.
+    GitmodulesUpdate = "gitmodulesUpdate", Error;
+}
+
+impl Display for FsckMsgId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for FsckMsgId {
+    type Err = GitError;
+
+    /// Parse a message id by its git name, ignoring case as git does.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FsckMsgId::ALL
+            .iter()
+            .find(|id| id.name().eq_ignore_ascii_case(s))
+            .copied()
+            .ok_or_else(|| GitError::InvalidArgument(format!("unknown fsck message id `{s}`")))
+    }
+}
+
+/// Severities and exceptions of the checks of [`Fsck`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsckOptions {
+    /// Severities replacing the default severities of their ids
+    pub severities: HashMap<FsckMsgId, FsckSeverity>,
+    /// Objects accepted without being checked, like `fsck.skipList`
+    pub skip: HashSet<SHA1>,
+    /// Report the problems that are warnings by default as errors, like `git fsck --strict`
+    /// and `receive.fsckObjects`; severities set explicitly still apply
+    pub strict: bool,
+}
+
+impl FsckOptions {
+    /// Treat the problems of kind `id` with `severity`.
+    pub fn set_severity(&mut self, id: FsckMsgId, severity: FsckSeverity) {
+        self.severities.insert(id, severity);
+    }
+
+    /// The severity of the problems of kind `id`.
+    pub fn severity(&self, id: FsckMsgId) -> FsckSeverity {
+        self.severities
+            .get(&id)
+            .copied()
+            .unwrap_or_else(|| match id.default_severity() {
+                FsckSeverity::Warn if self.strict => FsckSeverity::Error,
+                severity => severity,
+            })
+    }
+}
+
+/// A problem found in an object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsckProblem {
+    pub object: SHA1,
+    pub obj_type: ObjectType,
+    pub id: FsckMsgId,
+    pub severity: FsckSeverity,
+    /// What is wrong, in the words of `git fsck` where it has them.
+    pub message: String,
+}
+
+impl Display for FsckProblem {
+    /// As `git fsck` prints it: `error in tree <id>: duplicateEntries: contains duplicate file
+    /// entries`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} in {} {}: {}: {}",
+            self.severity, self.obj_type, self.object, self.id, self.message
+        )
+    }
+}
+
+/// A link from a checked object to another object.
+#[derive(Debug)]
+struct Link {
+    from: (ObjectType, SHA1),
+    to: (ObjectType, SHA1),
+}
+
+/// Checks objects one by one, then the links between them.
+///
+/// Hand every object to [`Fsck::check_object`], look up the ids of
+/// [`Fsck::unresolved_links`] in the repository, then call [`Fsck::check_links`] with the
+/// ones that exist.
+///
+/// A `.gitmodules` blob is checked when both it and the tree naming it are handed over, in any
+/// order; blobs checked before their tree are kept until then when they mention `submodule`,
+/// as the others can't fail the checks.
+#[derive(Debug)]
+pub struct Fsck {
+    options: FsckOptions,
+    problems: Vec<FsckProblem>,
+    checked: HashMap<SHA1, ObjectType>,
+    links: Vec<Link>,
+    /// Blobs named `.gitmodules` by the trees checked so far.
+    gitmodules: HashSet<SHA1>,
+    /// Checked blobs mentioning `submodule`, not named `.gitmodules` yet.
+    submodule_blobs: HashMap<SHA1, Vec<u8>>,
+}
+
+impl Fsck {
+    pub fn new(options: FsckOptions) -> Self {
+        Self {
+            options,
+            problems: Vec::new(),
+            checked: HashMap::new(),
+            links: Vec::new(),
+            gitmodules: HashSet::new(),
+            submodule_blobs: HashMap::new(),
+        }
+    }
+
+    /// Check the content of the object `id`, recording its problems and links.
+    pub fn check_object(&mut self, obj_type: ObjectType, id: SHA1, data: &[u8]) {
+        self.checked.insert(id, obj_type);
+        if self.options.skip.contains(&id) {
+            return;
+        }
+        let mut check = ObjectCheck {
+            fsck: self,
+            obj_type,
+            id,
+        };
+        match obj_type {
+            ObjectType::Tree => check.tree(data),
+            ObjectType::Commit => check.commit(data),
+            ObjectType::Tag => check.tag(data),
+            ObjectType::Blob => check.blob(data),
+            _ => {}
+        }
+    }
+
+    /// Record an object whose content is checked elsewhere, e.g. a blob stored while it was
+    /// received, so that links to it resolve.
+    pub fn add_checked(&mut self, obj_type: ObjectType, id: SHA1) {
+        self.checked.insert(id, obj_type);
+    }
+
+    /// The objects linked to by checked objects but not checked themselves, sorted. Submodule
+    /// commits are left out.
+    pub fn unresolved_links(&self) -> Vec<SHA1> {
+        let mut ids: Vec<SHA1> = self
+            .links
+            .iter()
+            .map(|link| link.to.1)
+            .filter(|id| !self.checked.contains_key(id))
+            .collect();
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+
+    /// Check the links of the checked objects: a link must point at a checked object of the
+    /// right type, or at an object for which `exists` returns `true`.
+    pub fn check_links(&mut self, exists: impl Fn(&SHA1) -> bool) {
+        for link in std::mem::take(&mut self.links) {
+            let ((from_type, from), (to_type, to)) = (link.from, link.to);
+            let problem = match self.checked.get(&to) {
+                Some(&found) if found != to_type => Some((
+                    FsckMsgId::BadLinkType,
+                    format!("links to {found} {to} as a {to_type}"),
+                )),
+                Some(_) => None,
+                None if exists(&to) => None,
+                None => Some((
+                    FsckMsgId::BrokenLink,
+                    format!("broken link to missing {to_type} {to}"),
+                )),
+            };
+            if let Some((id, message)) = problem {
+                self.report(from_type, from, id, message);
+            }
+        }
+    }
+
+    /// Every problem found so far, in the order found.
+    pub fn problems(&self) -> &[FsckProblem] {
+        &self.problems
+    }
+
+    /// The first problem at [`FsckSeverity::Error`], which rejects the objects.
+    pub fn first_error(&self) -> Option<&FsckProblem> {
+        self.problems
+            .iter()
+            .find(|problem| problem.severity == FsckSeverity::Error)
+    }
+
+    pub fn into_problems(self) -> Vec<FsckProblem> {
+        self.problems
+    }
+
+    /// Record a problem, returning whether it is an error.
+    fn report(
+        &mut self,
+        obj_type: ObjectType,
+        object: SHA1,
+        id: FsckMsgId,
+        message: String,
+    ) -> bool {
+        let severity = self.options.severity(id);
+        if severity != FsckSeverity::Ignore {
+            self.problems.push(FsckProblem {
+                object,
+                obj_type,
+                id,
+                severity,
+                message,
+            });
+        }
+        severity == FsckSeverity::Error
+    }
+}
+
+/// The checks of a single object; like git, they stop at the first error of a commit or tag.
+struct ObjectCheck<'a> {
+    fsck: &'a mut Fsck,
+    obj_type: ObjectType,
+    id: SHA1,
+}
+
+/// Result of a check, `Err` once an error stops the checks of the object.
+type CheckResult = Result<(), ()>;
+
+impl ObjectCheck<'_> {
+    fn report(&mut self, id: FsckMsgId, message: impl Into<String>) -> CheckResult {
+        match self.fsck.report(self.obj_type, self.id, id, message.into()) {
+            true => Err(()),
+            false => Ok(()),
+        }
+    }
+
+    fn link(&mut self, obj_type: ObjectType, id: SHA1) {
+        self.fsck.links.push(Link {
+            from: (self.obj_type, self.id),
+            to: (obj_type, id),
+        });
+    }
+
+    fn tree(&mut self, data: &[u8]) {
+        let entries = match parse_tree_entries(data) {
+            Ok(entries) => entries,
+            Err(message) => {
+                let _ = self.report(FsckMsgId::BadTree, message);
+                return;
+            }
+        };
+
+        // Each kind of problem is reported once per tree, naming its first entry
+        let mut found: Vec<(FsckMsgId, String)> = Vec::new();
+        let mut flag = |id: FsckMsgId, message: String| {
+            if !found.iter().any(|(seen, _)| *seen == id) {
+                found.push((id, message));
+            }
+        };
+        let mut names = HashSet::new();
+        let mut gitmodules = Vec::new();
+        for (i, entry) in entries.iter().enumerate() {
+            let name = String::from_utf8_lossy(entry.name);
+            if entry.id == SHA1::default() {
+                flag(
+                    FsckMsgId::NullSha1,
+                    format!("contains entries pointing to null sha1, `{name}`"),
+                );
+            }
+            if entry.name.contains(&b'/') {
+                flag(
+                    FsckMsgId::FullPathname,
+                    format!("contains full pathnames, `{name}`"),
+                );
+            }
+            match entry.name {
+                b"" => flag(FsckMsgId::EmptyName, "contains empty pathname".to_string()),
+                b"." => flag(FsckMsgId::HasDot, "contains '.'".to_string()),
+                b".." => flag(FsckMsgId::HasDotdot, "contains '..'".to_string()),
+                name if is_hfs_dot(name, b"git") || is_ntfs_dotgit(name) => {
+                    flag(FsckMsgId::HasDotgit, "contains '.git'".to_string())
+                }
+                name if is_hfs_dot(name, b"gitmodules")
+                    || is_ntfs_dot_generic(name, b"gitmodules", b"gi7eba") =>
+                {
+                    match entry.mode & 0o170000 {
+                        0o120000 => flag(
+                            FsckMsgId::GitmodulesSymlink,
+                            ".gitmodules is a symbolic link".to_string(),
+                        ),
+                        0o040000 | 0o160000 => flag(
+                            FsckMsgId::GitmodulesBlob,
+                            "non-blob found at .gitmodules".to_string(),
+                        ),
+                        _ => gitmodules.push(entry.id),
+                    }
+                }
+                _ => {}
+            }
+            if entry.mode_bytes.starts_with(b"0") {
+                flag(
+                    FsckMsgId::ZeroPaddedFilemode,
+                    format!("contains zero-padded file modes, `{name}`"),
+                );
+            }
+            if !matches!(
+                entry.mode,
+                0o100644 | 0o100755 | 0o100664 | 0o120000 | 0o040000 | 0o160000
+            ) {
+                flag(
+                    FsckMsgId::BadFilemode,
+                    format!("contains bad file modes, `{name}` is {:o}", entry.mode),
+                );
+            }
+            if !names.insert(entry.name) {
+                flag(
+                    FsckMsgId::DuplicateEntries,
+                    format!("contains duplicate file entries, `{name}`"),
+                );
+            } else if i > 0 && entry.sort_cmp(&entries[i - 1]).is_lt() {
+                flag(
+                    FsckMsgId::TreeNotSorted,
+                    format!("not properly sorted, `{name}`"),
+                );
+            }
+        }
+        for (id, message) in found {
+            let _ = self.report(id, message);
+        }
+        for id in gitmodules {
+            self.fsck.gitmodules.insert(id);
+            if let Some(data) = self.fsck.submodule_blobs.remove(&id) {
+                let mut check = ObjectCheck {
+                    fsck: &mut *self.fsck,
+                    obj_type: ObjectType::Blob,
+                    id,
+                };
+                let _ = check.gitmodules(&data);
+            }
+        }
+
+        for entry in &entries {
+            match entry.mode & 0o170000 {
+                0o040000 => self.link(ObjectType::Tree, entry.id),
+                // Submodule commits live in other repositories
+                0o160000 => {}
+                _ => self.link(ObjectType::Blob, entry.id),
+            }
+        }
+    }
+
+    fn commit(&mut self, data: &[u8]) {
+        let _ = self.commit_headers(data);
+    }
+
+    fn blob(&mut self, data: &[u8]) {
+        if self.fsck.gitmodules.contains(&self.id) {
+            let _ = self.gitmodules(data);
+        } else if data
+            .windows(b"submodule".len())
+            .any(|word| word.eq_ignore_ascii_case(b"submodule"))
+        {
+            self.fsck.submodule_blobs.insert(self.id, data.to_vec());
+        }
+    }
+
+    /// Check the submodules of a `.gitmodules` blob like git's `fsck_gitmodules_fn`.
+    fn gitmodules(&mut self, data: &[u8]) -> CheckResult {
+        let entries = match std::str::from_utf8(data).ok().and_then(parse_gitmodules) {
+            Some(entries) => entries,
+            None => {
+                return self.report(
+                    FsckMsgId::GitmodulesParse,
+                    "could not parse gitmodules blob",
+                );
+            }
+        };
+        let mut names = HashSet::new();
+        for (name, key, value) in entries {
+            let bad_name = name.is_empty() || name.split(['/', '\\']).any(|part| part == "..");
+            if bad_name && names.insert(name.clone()) {
+                self.report(
+                    FsckMsgId::GitmodulesName,
+                    format!("disallowed submodule name: {name}"),
+                )?;
+            }
+            match key.as_str() {
+                "url" if value.starts_with('-') || value.contains('\n') => self.report(
+                    FsckMsgId::GitmodulesUrl,
+                    format!("disallowed submodule url: {value}"),
+                )?,
+                "path" if value.starts_with('-') => self.report(
+                    FsckMsgId::GitmodulesPath,
+                    format!("disallowed submodule path: {value}"),
+                )?,
+                "update" if value.starts_with('!') => self.report(
+                    FsckMsgId::GitmodulesUpdate,
+                    format!("disallowed submodule update setting: {value}"),
+                )?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn commit_headers(&mut self, data: &[u8]) -> CheckResult {
+        let headers = self.headers(data)?;
+        let mut lines = headers.iter().copied().peekable();
+
+        let Some(tree) = lines.next_if(|line| line.starts_with(b"tree ")) else {
+            return self.report(
+                FsckMsgId::MissingTree,
+                "invalid format - expected 'tree' line",
+            );
+        };
+        match parse_id(&tree[5..]) {
+            Some(tree) => self.link(ObjectType::Tree, tree),
+            None => {
+                return self.report(
+                    FsckMsgId::BadTreeSha1,
+                    "invalid 'tree' line format - bad sha1",
+                );
+            }
+        }
+        while let Some(parent) = lines.next_if(|line| line.starts_with(b"parent ")) {
+            match parse_id(&parent[7..]) {
+                Some(parent) => self.link(ObjectType::Commit, parent),
+                None => self.report(
+                    FsckMsgId::BadParentSha1,
+                    "invalid 'parent' line format - bad sha1",
+                )?,
+            }
+        }
+
+        let Some(author) = lines.next_if(|line| line.starts_with(b"author ")) else {
+            return self.report(
+                FsckMsgId::MissingAuthor,
+                "invalid format - expected 'author' line",
+            );
+        };
+        self.ident(&author[7..])?;
+        while lines.next_if(|line| line.starts_with(b"author ")).is_some() {
+            self.report(
+                FsckMsgId::MultipleAuthors,
+                "invalid format - multiple 'author' lines",
+            )?;
+        }
+        let Some(committer) = lines.next_if(|line| line.starts_with(b"committer ")) else {
+            return self.report(
+                FsckMsgId::MissingCommitter,
+                "invalid format - expected 'committer' line",
+            );
+        };
+        self.ident(&committer[10..])
+    }
+
+    fn tag(&mut self, data: &[u8]) {
+        let _ = self.tag_headers(data);
+    }
+
+    fn tag_headers(&mut self, data: &[u8]) -> CheckResult {
+        let headers = self.headers(data)?;
+        let mut lines = headers.iter().copied().peekable();
+
+        let Some(object) = lines.next_if(|line| line.starts_with(b"object ")) else {
+            return self.report(
+                FsckMsgId::MissingObject,
+                "invalid format - expected 'object' line",
+            );
+        };
+        let Some(object) = parse_id(&object[7..]) else {
+            return self.report(
+                FsckMsgId::BadObjectSha1,
+                "invalid 'object' line format - bad sha1",
+            );
+        };
+        let Some(type_line) = lines.next_if(|line| line.starts_with(b"type ")) else {
+            return self.report(
+                FsckMsgId::MissingTypeEntry,
+                "invalid format - expected 'type' line",
+            );
+        };
+        match std::str::from_utf8(&type_line[5..]).map(ObjectType::from_string) {
+            Ok(Ok(obj_type)) => self.link(obj_type, object),
+            _ => return self.report(FsckMsgId::BadType, "invalid 'type' value"),
+        }
+        let Some(tag) = lines.next_if(|line| line.starts_with(b"tag ")) else {
+            return self.report(
+                FsckMsgId::MissingTagEntry,
+                "invalid format - expected 'tag' line",
+            );
+        };
+        let name = String::from_utf8_lossy(&tag[4..]);
+        if validate_ref_name(&format!("refs/tags/{name}")).is_err() {
+            self.report(FsckMsgId::BadTagName, format!("invalid 'tag' name: {name}"))?;
+        }
+        match lines.next_if(|line| line.starts_with(b"tagger ")) {
+            Some(tagger) => self.ident(&tagger[7..]),
+            None => self.report(
+                FsckMsgId::MissingTaggerEntry,
+                "invalid format - expected 'tagger' line",
+            ),
+        }
+    }
+
+    /// The header lines of a commit or tag, without their newlines.
+    fn headers<'d>(&mut self, data: &'d [u8]) -> Result<Vec<&'d [u8]>, ()> {
+        let end = match memchr::memmem::find(data, b"\n\n") {
+            Some(end) => end,
+            // An object without a message still ends its last header with a newline
+            None if data.ends_with(b"\n") => data.len() - 1,
+            None => {
+                self.report(FsckMsgId::UnterminatedHeader, "unterminated header")?;
+                data.len()
+            }
+        };
+        let headers = &data[..end];
+        if let Some(offset) = memchr::memchr(0, headers) {
+            self.report(
+                FsckMsgId::NulInHeader,
+                format!("unterminated header: NUL at offset {offset}"),
+            )?;
+        }
+        Ok(headers.split(|&b| b == b'\n').collect())
+    }
+
+    /// Check an identity: `Name <email> timestamp timezone`.
+    fn ident(&mut self, ident: &[u8]) -> CheckResult {
+        if ident.starts_with(b"<") {
+            return self.report(
+                FsckMsgId::MissingNameBeforeEmail,
+                "invalid author/committer line - missing space before email",
+            );
+        }
+        let Some(open) = ident.iter().position(|&b| b == b'<' || b == b'>') else {
+            return self.report(
+                FsckMsgId::MissingEmail,
+                "invalid author/committer line - missing email",
+            );
+        };
+        if ident[open] == b'>' {
+            return self.report(
+                FsckMsgId::BadName,
+                "invalid author/committer line - bad name",
+            );
+        }
+        if ident[open - 1] != b' ' {
+            return self.report(
+                FsckMsgId::MissingSpaceBeforeEmail,
+                "invalid author/committer line - missing space before email",
+            );
+        }
+        let rest = &ident[open + 1..];
+        let Some(close) = rest.iter().position(|&b| b == b'<' || b == b'>') else {
+            return self.report(
+                FsckMsgId::BadEmail,
+                "invalid author/committer line - bad email",
+            );
+        };
+        if rest[close] != b'>' {
+            return self.report(
+                FsckMsgId::BadEmail,
+                "invalid author/committer line - bad email",
+            );
+        }
+        let Some(date) = rest[close + 1..].strip_prefix(b" ") else {
+            return self.report(
+                FsckMsgId::MissingSpaceBeforeDate,
+                "invalid author/committer line - missing space before date",
+            );
+        };
+        if date.first() == Some(&b'0') && date.get(1) != Some(&b' ') {
+            return self.report(
+                FsckMsgId::ZeroPaddedDate,
+                "invalid author/committer line - zero-padded date",
+            );
+        }
+        let digits = date.iter().take_while(|b| b.is_ascii_digit()).count();
+        let timestamp = std::str::from_utf8(&date[..digits]).unwrap();
+        if digits > 0 && timestamp.parse::<u64>().is_err() {
+            return self.report(
+                FsckMsgId::BadDateOverflow,
+                "invalid author/committer line - date causes integer overflow",
+            );
+        }
+        let Some(timezone) = date[digits..].strip_prefix(b" ").filter(|_| digits > 0) else {
+            return self.report(
+                FsckMsgId::BadDate,
+                "invalid author/committer line - bad date",
+            );
+        };
+        let valid_timezone = timezone.len() == 5
+            && matches!(timezone[0], b'+' | b'-')
+            && timezone[1..].iter().all(u8::is_ascii_digit);
+        if !valid_timezone {
+            return self.report(
+                FsckMsgId::BadTimezone,
+                "invalid author/committer line - bad time zone",
+            );
+        }
+        Ok(())
+    }
+}
+
+/// A tree entry as stored, before any normalization of its mode or name.
+struct RawTreeEntry<'a> {
+    mode_bytes: &'a [u8],
+    mode: u32,
+    name: &'a [u8],
+    id: SHA1,
+}
+
+impl RawTreeEntry<'_> {
+    /// Git's order of tree entries: by name, as if trees had a trailing `/`.
+    fn sort_cmp(&self, other: &Self) -> std::cmp::Ordering {
+        let key = |entry: &Self| {
+            let is_tree = entry.mode & 0o170000 == 0o040000;
+            entry.name.iter().copied().chain(is_tree.then_some(b'/'))
+        };
+        key(self).cmp(key(other))
+    }
+}
+
+fn parse_tree_entries(data: &[u8]) -> Result<Vec<RawTreeEntry<'_>>, String> {
+    let mut entries = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let space = memchr::memchr(b' ', rest).ok_or("cannot be parsed as a tree, missing mode")?;
+        let mode_bytes = &rest[..space];
+        let mode = std::str::from_utf8(mode_bytes)
+            .ok()
+            .filter(|mode| !mode.is_empty() && mode.bytes().all(|b| (b'0'..=b'7').contains(&b)))
+            .and_then(|mode| u32::from_str_radix(mode, 8).ok())
+            .ok_or("cannot be parsed as a tree, bad mode")?;
+        let nul = memchr::memchr(0, &rest[space..])
+            .ok_or("cannot be parsed as a tree, missing name")?
+            + space;
+        if rest.len() < nul + 1 + SHA1::SIZE {
+            return Err("cannot be parsed as a tree, truncated entry".to_string());
+        }
+        entries.push(RawTreeEntry {
+            mode_bytes,
+            mode,
+            name: &rest[space + 1..nul],
+            id: SHA1::from_bytes(&rest[nul + 1..nul + 1 + SHA1::SIZE]),
+        });
+        rest = &rest[nul + 1 + SHA1::SIZE..];
+    }
+    Ok(entries)
+}
+
+/// Code points HFS+ ignores in file names, so that `.g\u{200c}it` opens `.git`.
+fn is_hfs_ignorable(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200c}'..='\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{206a}'..='\u{206f}' | '\u{feff}'
+    )
+}
+
+/// Whether HFS+ opens the entry `name` as `.<dot_name>`: the same name ignoring ASCII case and
+/// ignorable code points, like git's `is_hfs_dot_generic`.
+fn is_hfs_dot(name: &[u8], dot_name: &[u8]) -> bool {
+    let Ok(name) = std::str::from_utf8(name) else {
+        return false;
+    };
+    let mut chars = name.chars().filter(|&c| !is_hfs_ignorable(c));
+    chars.next() == Some('.')
+        && chars
+            .map(|c| c.to_ascii_lowercase())
+            .eq(dot_name.iter().map(|&b| b as char))
+}
+
+/// Whether the rest of an NTFS name is ignored: trailing spaces and periods, then the end of
+/// the name or an alternate data stream after a `:`.
+fn is_ntfs_ignored(rest: &[u8]) -> bool {
+    rest.iter()
+        .take_while(|&&c| c != b':')
+        .all(|&c| c == b' ' || c == b'.')
+}
+
+/// Whether NTFS opens the entry `name` as `.git`, through its `git~1` short name too, like
+/// git's `is_ntfs_dotgit`.
+fn is_ntfs_dotgit(name: &[u8]) -> bool {
+    let rest = match name {
+        [b'.', g, i, t, rest @ ..] if [*g, *i, *t].eq_ignore_ascii_case(b"git") => rest,
+        [g, i, t, b'~', b'1', rest @ ..] if [*g, *i, *t].eq_ignore_ascii_case(b"git") => rest,
+        _ => return false,
+    };
+    // A backslash separates directories on Windows
+    let end = rest.iter().position(|&c| c == b'\\').unwrap_or(rest.len());
+    is_ntfs_ignored(&rest[..end])
+}
+
+/// Whether NTFS opens the entry `name` as `.<dot_name>`: matching ignoring case, the 8.3 short
+/// name of its first six characters and `~1` to `~4`, or the hashed short name starting with
+/// `short_prefix` that Windows falls back to, like git's `is_ntfs_dot_generic`.
+fn is_ntfs_dot_generic(name: &[u8], dot_name: &[u8], short_prefix: &[u8; 6]) -> bool {
+    if name.len() > dot_name.len()
+        && name[0] == b'.'
+        && name[1..=dot_name.len()].eq_ignore_ascii_case(dot_name)
+    {
+        return is_ntfs_ignored(&name[dot_name.len() + 1..]);
+    }
+    if name.len() >= 8
+        && name[..6].eq_ignore_ascii_case(&dot_name[..6])
+        && name[6] == b'~'
+        && (b'1'..=b'4').contains(&name[7])
+    {
+        return is_ntfs_ignored(&name[8..]);
+    }
+    if name.len() < 8 {
+        return false;
+    }
+    let (mut i, mut saw_tilde) = (0, false);
+    while i < 8 {
+        let c = name[i];
+        if saw_tilde {
+            if !c.is_ascii_digit() {
+                return false;
+            }
+        } else if c == b'~' {
+            i += 1;
+            if !matches!(name.get(i), Some(b'1'..=b'9')) {
+                return false;
+            }
+            saw_tilde = true;
+        } else if i >= 6 || !c.is_ascii() || c.to_ascii_lowercase() != short_prefix[i] {
+            return false;
+        }
+        i += 1;
+    }
+    is_ntfs_ignored(&name[i..])
+}
+
+/// The `(name, key, value)` settings of the `submodule.<name>` sections of a `.gitmodules`
+/// file, keys in lower case, or `None` when it isn't valid git configuration.
+fn parse_gitmodules(text: &str) -> Option<Vec<(String, String, String)>> {
+    let mut settings = Vec::new();
+    let mut section: Option<(String, Option<String>)> = None;
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        let mut line = line.trim_start();
+        if let Some(header) = line.strip_prefix('[') {
+            let (name, rest) = parse_section_header(header)?;
+            section = Some(name);
+            line = rest.trim_start();
+        }
+        if line.is_empty() || line.starts_with(['#', ';']) {
+            continue;
+        }
+        let key_len = line
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '-')
+            .unwrap_or(line.len());
+        if !line.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            return None;
+        }
+        let key = line[..key_len].to_ascii_lowercase();
+        let rest = line[key_len..].trim_start();
+        let value = match rest.strip_prefix('=') {
+            Some(value) => parse_config_value(value, &mut lines)?,
+            // A key without a value is true
+            None if rest.is_empty() || rest.starts_with(['#', ';']) => "true".to_string(),
+            None => return None,
+        };
+        if let Some((kind, Some(name))) = &section
+            && kind == "submodule"
+        {
+            settings.push((name.clone(), key, value));
+        }
+    }
+    Some(settings)
+}
+
+/// The section and subsection of a `[section "subsection"]` or old-style `[section.subsection]`
+/// header after its `[`, and what follows the `]` on the line.
+fn parse_section_header(header: &str) -> Option<((String, Option<String>), &str)> {
+    let name_len = header
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '-' && c != '.')
+        .unwrap_or(header.len());
+    let name = header[..name_len].to_ascii_lowercase();
+    let rest = &header[name_len..];
+    if let Some(rest) = rest.strip_prefix(']') {
+        return Some(match name.split_once('.') {
+            Some((section, subsection)) => {
+                ((section.to_string(), Some(subsection.to_string())), rest)
+            }
+            None => ((name, None), rest),
+        });
+    }
+    if !rest.starts_with([' ', '\t']) {
+        return None;
+    }
+    let mut chars = rest.trim_start().strip_prefix('"')?.char_indices();
+    let mut subsection = String::new();
+    while let Some((_, c)) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => subsection.push(chars.next()?.1),
+            '\n' => return None,
+            c => subsection.push(c),
+        }
+    }
+    let rest = chars.as_str().strip_prefix(']')?;
+    Some(((name, Some(subsection)), rest))
+}
+
+/// A configuration value after its `=`: quotes removed, escapes resolved, comments and
+/// unquoted trailing whitespace dropped, continued on the next lines after a `\`.
+fn parse_config_value<'a>(
+    value: &'a str,
+    lines: &mut impl Iterator<Item = &'a str>,
+) -> Option<String> {
+    let mut parsed = String::new();
+    // The length of `parsed` without trailing unquoted whitespace
+    let mut end = 0;
+    let mut quoted = false;
+    let mut chars = value.trim_start().chars();
+    loop {
+        let Some(c) = chars.next() else {
+            if quoted {
+                return None;
+            }
+            break;
+        };
+        match c {
+            '"' => quoted = !quoted,
+            '#' | ';' if !quoted => break,
+            '\\' => {
+                let escaped = match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('b') => '\u{8}',
+                    Some(c @ ('"' | '\\')) => c,
+                    // Continued on the next line
+                    None => {
+                        chars = lines.next()?.chars();
+                        continue;
+                    }
+                    Some(_) => return None,
+                };
+                parsed.push(escaped);
+                end = parsed.len();
+                continue;
+            }
+            c if c.is_whitespace() && !quoted => {
+                parsed.push(c);
+                continue;
+            }
+            c => parsed.push(c),
+        }
+        end = parsed.len();
+    }
+    parsed.truncate(end);
+    Some(parsed)
+}
+
+/// A hex object id, the whole of `hex`.
+fn parse_id(hex: &[u8]) -> Option<SHA1> {
+    if hex.len() != SHA1::SIZE * 2 {
+        return None;
+    }
+    std::str::from_utf8(hex).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree_entry(mode: &str, name: &str, id: SHA1) -> Vec<u8> {
+        [mode.as_bytes(), b" ", name.as_bytes(), b"\0", &id.0].concat()
+    }
+
+    fn fsck(obj_type: ObjectType, data: &[u8], options: FsckOptions) -> Vec<FsckProblem> {
+        let mut fsck = Fsck::new(options);
+        fsck.check_object(obj_type, SHA1::from_type_and_data(obj_type, data), data);
+        fsck.into_problems()
+    }
+
+    fn ids(problems: &[FsckProblem]) -> Vec<FsckMsgId> {
+        problems.iter().map(|problem| problem.id).collect()
+    }
+
+    const COMMIT: &str = "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+        author A U Thor <author@example.com> 1700000000 +0100\n\
+        committer C O Mitter <committer@example.com> 1700000000 -0230\n\
+        \n\
+        message\n";
+
+    #[test]
+    fn test_fsck_tree() {
+        let blob = SHA1::new(b"blob");
+        let good = [
+            tree_entry("100644", "a.c", blob),
+            tree_entry("40000", "a", blob),
+            tree_entry("100644", "a0", blob),
+            tree_entry("160000", "sub", blob),
+        ]
+        .concat();
+        assert_eq!(
+            fsck(ObjectType::Tree, &good, FsckOptions::default()),
+            vec![]
+        );
+
+        let bad = [
+            tree_entry("100644", "b", blob),
+            tree_entry("040000", ".Git", blob),
+            tree_entry("100600", "a/b", SHA1::default()),
+            tree_entry("100644", "b", blob),
+            tree_entry("100644", "..", blob),
+        ]
+        .concat();
+        let problems = fsck(ObjectType::Tree, &bad, FsckOptions::default());
+        assert_eq!(
+            ids(&problems),
+            vec![
+                FsckMsgId::HasDotgit,
+                FsckMsgId::ZeroPaddedFilemode,
+                FsckMsgId::TreeNotSorted,
+                FsckMsgId::NullSha1,
+                FsckMsgId::FullPathname,
+                FsckMsgId::BadFilemode,
+                FsckMsgId::DuplicateEntries,
+                FsckMsgId::HasDotdot,
+            ]
+        );
+        assert_eq!(
+            problems[6].to_string(),
+            format!(
+                "error in tree {}: duplicateEntries: contains duplicate file entries, `b`",
+                problems[6].object
+            )
+        );
+
+        let problems = fsck(
+            ObjectType::Tree,
+            b"100644 trunc\0abc",
+            FsckOptions::default(),
+        );
+        assert_eq!(ids(&problems), vec![FsckMsgId::BadTree]);
+    }
+
+    #[test]
+    fn test_fsck_dotgit_spellings() {
+        let blob = SHA1::new(b"blob");
+        for name in [
+            ".GIT",
+            "git~1",
+            "GIT~1",
+            ".git.",
+            ".git . ",
+            ".git::$INDEX_ALLOCATION",
+            ".g\u{200c}it",
+            "\u{feff}.GI\u{206a}T",
+        ] {
+            let data = tree_entry("40000", name, blob);
+            let problems = fsck(ObjectType::Tree, &data, FsckOptions::default());
+            assert_eq!(ids(&problems), vec![FsckMsgId::HasDotgit], "{name:?}");
+        }
+        for name in [".gitignore", "git~2", ".git-x", "git", ".g\u{e9}it"] {
+            let data = tree_entry("100644", name, blob);
+            assert_eq!(
+                fsck(ObjectType::Tree, &data, FsckOptions::default()),
+                vec![],
+                "{name:?}"
+            );
+        }
+
+        assert!(is_ntfs_dot_generic(
+            b".GITMODULES ",
+            b"gitmodules",
+            b"gi7eba"
+        ));
+        assert!(is_ntfs_dot_generic(b"gitmod~4", b"gitmodules", b"gi7eba"));
+        assert!(is_ntfs_dot_generic(b"GI7EBA~1", b"gitmodules", b"gi7eba"));
+        assert!(is_ntfs_dot_generic(b"gi7eb~15", b"gitmodules", b"gi7eba"));
+        assert!(!is_ntfs_dot_generic(b"gitmod~5", b"gitmodules", b"gi7eba"));
+        assert!(!is_ntfs_dot_generic(b"gi7eba~a", b"gitmodules", b"gi7eba"));
+        assert!(!is_ntfs_dot_generic(
+            b".gitmodulesx",
+            b"gitmodules",
+            b"gi7eba"
+        ));
+    }
+
+    #[test]
+    fn test_fsck_gitmodules() {
+        let check = |gitmodules: &str, blob_first: bool| {
+            let blob = SHA1::from_type_and_data(ObjectType::Blob, gitmodules.as_bytes());
+            let tree = tree_entry("100644", ".gitmodules", blob);
+            let tree_id = SHA1::from_type_and_data(ObjectType::Tree, &tree);
+            let mut fsck = Fsck::new(FsckOptions::default());
+            if blob_first {
+                fsck.check_object(ObjectType::Blob, blob, gitmodules.as_bytes());
+                fsck.check_object(ObjectType::Tree, tree_id, &tree);
+            } else {
+                fsck.check_object(ObjectType::Tree, tree_id, &tree);
+                fsck.check_object(ObjectType::Blob, blob, gitmodules.as_bytes());
+            }
+            fsck.into_problems()
+        };
+
+        let good = "[submodule \"lib\"]\n\tpath = lib\n\turl = https://example.com/lib.git\n";
+        assert_eq!(check(good, false), vec![]);
+        for blob_first in [false, true] {
+            let problems = check("[submodule \"../../hooks\"]\n\tpath = x\n", blob_first);
+            assert_eq!(ids(&problems), vec![FsckMsgId::GitmodulesName]);
+            assert_eq!(problems[0].obj_type, ObjectType::Blob);
+            assert_eq!(
+                problems[0].message,
+                "disallowed submodule name: ../../hooks"
+            );
+        }
+        let url = "[Submodule \"x\"]\n  URL = \"--upload-pack=touch /tmp/pwned\" # comment\n";
+        let problems = check(url, true);
+        assert_eq!(ids(&problems), vec![FsckMsgId::GitmodulesUrl]);
+        assert_eq!(
+            problems[0].message,
+            "disallowed submodule url: --upload-pack=touch /tmp/pwned"
+        );
+        let newline = "[submodule \"x\"]\n\turl = https://example.com/\\nrepo\n";
+        assert_eq!(ids(&check(newline, false)), vec![FsckMsgId::GitmodulesUrl]);
+        let path = "[submodule.x]\n\tpath = -x\n";
+        assert_eq!(ids(&check(path, false)), vec![FsckMsgId::GitmodulesPath]);
+        let update = "[submodule \"x\"]\n\tupdate = \\\n!rm -rf /\n";
+        assert_eq!(
+            ids(&check(update, false)),
+            vec![FsckMsgId::GitmodulesUpdate]
+        );
+        let unparsable = "[submodule \"x\"\n\tpath = x\n";
+        let problems = check(unparsable, false);
+        assert_eq!(ids(&problems), vec![FsckMsgId::GitmodulesParse]);
+        assert_eq!(problems[0].severity, FsckSeverity::Info);
+
+        let blob = SHA1::new(b"blob");
+        let problems = fsck(
+            ObjectType::Tree,
+            &tree_entry("120000", ".GITMODULES", blob),
+            FsckOptions::default(),
+        );
+        assert_eq!(ids(&problems), vec![FsckMsgId::GitmodulesSymlink]);
+        let problems = fsck(
+            ObjectType::Tree,
+            &tree_entry("40000", "gitmod~1", blob),
+            FsckOptions::default(),
+        );
+        assert_eq!(ids(&problems), vec![FsckMsgId::GitmodulesBlob]);
+    }
+
+    #[test]
+    fn test_fsck_commit() {
+        assert_eq!(
+            fsck(
+                ObjectType::Commit,
+                COMMIT.as_bytes(),
+                FsckOptions::default()
+            ),
+            vec![]
+        );
+        let cases = [
+            (
+                COMMIT.replace("author", "committer"),
+                FsckMsgId::MissingAuthor,
+            ),
+            (COMMIT.replace("tree 4b", "tree xx"), FsckMsgId::BadTreeSha1),
+            (COMMIT.replace("-0230", "-023"), FsckMsgId::BadTimezone),
+            (
+                COMMIT.replace(" 1700000000 +", " 01700000000 +"),
+                FsckMsgId::ZeroPaddedDate,
+            ),
+            (
+                COMMIT.replace("A U Thor ", ""),
+                FsckMsgId::MissingNameBeforeEmail,
+            ),
+            (
+                COMMIT.replace(" <author@example.com>", ""),
+                FsckMsgId::MissingEmail,
+            ),
+            (
+                COMMIT.replace("com> 17", "com>17"),
+                FsckMsgId::MissingSpaceBeforeDate,
+            ),
+            (
+                COMMIT.replace("1700000000 -", "99999999999999999999 -"),
+                FsckMsgId::BadDateOverflow,
+            ),
+            (COMMIT.replace("U Thor", "U\0Thor"), FsckMsgId::NulInHeader),
+            (
+                COMMIT.replace("\n\nmessage\n", ""),
+                FsckMsgId::UnterminatedHeader,
+            ),
+        ];
+        for (commit, expected) in cases {
+            let problems = fsck(
+                ObjectType::Commit,
+                commit.as_bytes(),
+                FsckOptions::default(),
+            );
+            assert_eq!(ids(&problems), vec![expected], "{commit}");
+        }
+
+        // Authors out of order stop the checks, unless the problem is ignored
+        let unordered =
+            COMMIT
+                .replacen("author", "committer", 1)
+                .replacen("committer C", "author C", 1);
+        let problems = fsck(
+            ObjectType::Commit,
+            unordered.as_bytes(),
+            FsckOptions::default(),
+        );
+        assert_eq!(problems.len(), 1);
+        let mut options = FsckOptions::default();
+        options.set_severity(FsckMsgId::MissingAuthor, FsckSeverity::Ignore);
+        assert_eq!(
+            fsck(ObjectType::Commit, unordered.as_bytes(), options),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_fsck_tag_and_links() {
+        let commit_id = SHA1::from_type_and_data(ObjectType::Commit, COMMIT.as_bytes());
+        let tag = format!(
+            "object {commit_id}\ntype commit\ntag v1..0\n\
+            tagger T Agger <tagger@example.com> 1700000000 +0000\n\nrelease\n"
+        );
+        let problems = fsck(ObjectType::Tag, tag.as_bytes(), FsckOptions::default());
+        assert_eq!(ids(&problems), vec![FsckMsgId::BadTagName]);
+        assert_eq!(problems[0].severity, FsckSeverity::Info);
+
+        let untyped = tag.replace("type commit\n", "");
+        let problems = fsck(ObjectType::Tag, untyped.as_bytes(), FsckOptions::default());
+        assert_eq!(ids(&problems), vec![FsckMsgId::MissingTypeEntry]);
+
+        // The tag and its commit are checked, the commit's tree is looked up
+        let tag = tag.replace("v1..0", "v1.0");
+        let tag_id = SHA1::from_type_and_data(ObjectType::Tag, tag.as_bytes());
+        let mut fsck = Fsck::new(FsckOptions::default());
+        fsck.check_object(ObjectType::Tag, tag_id, tag.as_bytes());
+        fsck.check_object(ObjectType::Commit, commit_id, COMMIT.as_bytes());
+        let empty_tree = SHA1::from_type_and_data(ObjectType::Tree, b"");
+        assert_eq!(fsck.unresolved_links(), vec![empty_tree]);
+        fsck.check_links(|_| false);
+        assert_eq!(ids(fsck.problems()), vec![FsckMsgId::BrokenLink]);
+        let error = fsck.first_error().unwrap();
+        assert_eq!(error.object, commit_id);
+        assert_eq!(
+            error.message,
+            format!("broken link to missing tree {empty_tree}")
+        );
+
+        // A commit checked where the tree is expected
+        let mut fsck = Fsck::new(FsckOptions::default());
+        let commit = COMMIT.replace(&empty_tree.to_string(), &commit_id.to_string());
+        fsck.check_object(ObjectType::Commit, commit_id, commit.as_bytes());
+        fsck.check_links(|_| true);
+        assert_eq!(ids(fsck.problems()), vec![FsckMsgId::BadLinkType]);
+    }
+
+    #[test]
+    fn test_fsck_options_from_config() {
+        assert_eq!(
+            "DUPLICATEENTRIES".parse::<FsckMsgId>().unwrap(),
+            FsckMsgId::DuplicateEntries
+        );
+        assert!("noSuchCheck".parse::<FsckMsgId>().is_err());
+        assert_eq!("warn".parse::<FsckSeverity>().unwrap(), FsckSeverity::Warn);
+        let mut options = FsckOptions::default();
+        assert_eq!(options.severity(FsckMsgId::BadTagName), FsckSeverity::Info);
+        options.set_severity(FsckMsgId::BadTagName, FsckSeverity::Error);
+        assert_eq!(options.severity(FsckMsgId::BadTagName), FsckSeverity::Error);
+
+        // Strict mode turns warnings into errors, unless set otherwise
+        options.strict = true;
+        assert_eq!(options.severity(FsckMsgId::HasDotgit), FsckSeverity::Error);
+        assert_eq!(options.severity(FsckMsgId::BadFilemode), FsckSeverity::Info);
+        options.set_severity(FsckMsgId::HasDotgit, FsckSeverity::Warn);
+        assert_eq!(options.severity(FsckMsgId::HasDotgit), FsckSeverity::Warn);
+
+        // Skipped objects are not checked
+        let data = tree_entry("100644", ".git", SHA1::new(b"blob"));
+        options
+            .skip
+            .insert(SHA1::from_type_and_data(ObjectType::Tree, &data));
+        assert_eq!(fsck(ObjectType::Tree, &data, options), vec![]);
+    }
+}
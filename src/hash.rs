@@ -8,8 +8,10 @@ use std::{fmt::Display, io};
 use bincode::{Decode, Encode};
 use colored::Colorize;
 use serde::{Deserialize, Serialize};
+#[cfg(not(feature = "sha1dc"))]
 use sha1::Digest;
 
+use crate::errors::GitError;
 use crate::internal::object::types::ObjectType;
 
 /// The [`SHA1`] struct, encapsulating a `[u8; 20]` array, is specifically designed to represent Git hash IDs.
@@ -106,24 +108,54 @@ impl SHA1 {
     pub const SIZE: usize = 20;
 
     /// Calculate the SHA-1 hash of the byte slice, then create a Hash value
+    ///
+    /// With the `sha1dc` feature a detected collision attack is logged and the hardened
+    /// digest is returned; use [`SHA1::try_new`] to reject such input instead.
     pub fn new(data: &[u8]) -> SHA1 {
-        let h = sha1::Sha1::digest(data);
-        SHA1::from_bytes(h.as_ref())
+        SHA1::digest_parts(&[data]).0
     }
+
+    /// Like [`SHA1::new`], but fails with [`GitError::HashCollision`] when the `sha1dc`
+    /// feature detects a collision attack. Without the feature this never fails.
+    pub fn try_new(data: &[u8]) -> Result<SHA1, GitError> {
+        SHA1::checked(SHA1::digest_parts(&[data]))
+    }
+
     /// Create a Hash from the object type and data
     /// This function is used to create a SHA1 hash from the object type and data.
-    /// It constructs a byte vector that includes the object type, the size of the data,
-    /// and the data itself, and then computes the SHA1 hash of this byte vector.
+    /// It hashes a header made of the object type and the size of the data, followed by
+    /// the data itself.
     ///  
     ///  Hash compute <- {Object Type}+{ }+{Object Size（before compress）}+{\x00}+{Object Content(before compress)}
     pub fn from_type_and_data(object_type: ObjectType, data: &[u8]) -> SHA1 {
-        let mut d: Vec<u8> = Vec::new();
-        d.extend(object_type.to_data().unwrap());
-        d.push(b' ');
-        d.extend(data.len().to_string().as_bytes());
-        d.push(b'\x00');
-        d.extend(data);
-        SHA1::new(&d)
+        SHA1::digest_object(object_type, data).0
+    }
+
+    /// Like [`SHA1::from_type_and_data`], but fails with [`GitError::HashCollision`] when
+    /// the `sha1dc` feature detects a collision attack.
+    pub fn try_from_type_and_data(object_type: ObjectType, data: &[u8]) -> Result<SHA1, GitError> {
+        SHA1::checked(SHA1::digest_object(object_type, data))
+    }
+
+    fn digest_object(object_type: ObjectType, data: &[u8]) -> (SHA1, bool) {
+        let size = data.len().to_string();
+        SHA1::digest_parts(&[object_type.to_bytes(), b" ", size.as_bytes(), b"\x00", data])
+    }
+
+    fn checked((hash, collision): (SHA1, bool)) -> Result<SHA1, GitError> {
+        if collision {
+            return Err(GitError::HashCollision(hash.to_string()));
+        }
+        Ok(hash)
+    }
+
+    /// Hash the concatenation of `parts`, also reporting whether a collision attack was detected.
+    fn digest_parts(parts: &[&[u8]]) -> (SHA1, bool) {
+        let mut hasher = Hasher::default();
+        for part in parts {
+            hasher.update(part);
+        }
+        hasher.digest()
     }
 
     /// Create Hash from a byte array, which is a 20-byte array already calculated
@@ -158,6 +190,84 @@ impl SHA1 {
     }
 }
 
+/// Computes [`SHA1::try_from_type_and_data`] from the content of an object read piece by piece,
+/// for objects too large to hold in memory
+pub struct ObjectHasher {
+    hasher: Hasher,
+    size: usize,
+    hashed: usize,
+}
+
+impl ObjectHasher {
+    /// Start hashing an object of the given type whose content is `size` bytes long
+    pub fn new(object_type: ObjectType, size: usize) -> Self {
+        let mut hasher = Hasher::default();
+        let size_str = size.to_string();
+        for part in [object_type.to_bytes(), b" ", size_str.as_bytes(), b"\x00"] {
+            hasher.update(part);
+        }
+        ObjectHasher {
+            hasher,
+            size,
+            hashed: 0,
+        }
+    }
+
+    /// Hash the next piece of the content
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+        self.hashed += data.len();
+    }
+
+    /// The id of the object, fails when the content was not as long as announced or with
+    /// [`GitError::HashCollision`] like [`SHA1::try_from_type_and_data`]
+    pub fn finish(self) -> Result<SHA1, GitError> {
+        if self.hashed != self.size {
+            return Err(GitError::InvalidObjectInfo(format!(
+                "The object size {} does not match the expected size {}",
+                self.hashed, self.size
+            )));
+        }
+        SHA1::checked(self.hasher.digest())
+    }
+}
+
+/// SHA-1 hasher, collision-detecting with the `sha1dc` feature
+#[derive(Default)]
+struct Hasher {
+    #[cfg(not(feature = "sha1dc"))]
+    inner: sha1::Sha1,
+    #[cfg(feature = "sha1dc")]
+    inner: sha1collisiondetection::Sha1CD,
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    /// The digest, also reporting whether a collision attack was detected.
+    #[cfg(not(feature = "sha1dc"))]
+    fn digest(self) -> (SHA1, bool) {
+        (SHA1(self.inner.finalize().into()), false)
+    }
+
+    /// The digest, also reporting whether a collision attack was detected.
+    ///
+    /// On collision the returned digest is the hardened one computed by sha1dc, which differs
+    /// from the attacker-controlled plain SHA-1 value.
+    #[cfg(feature = "sha1dc")]
+    fn digest(mut self) -> (SHA1, bool) {
+        let mut out = sha1collisiondetection::Output::default();
+        let collision = self.inner.finalize_into_dirty_cd(&mut out).is_err();
+        let hash = SHA1::from_bytes(out.as_slice());
+        if collision {
+            tracing::error!("SHA-1 collision attack detected, hardened hash {}", hash);
+        }
+        (hash, collision)
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -168,7 +278,8 @@ mod tests {
     use std::str::FromStr;
     use std::{env, path::PathBuf};
 
-    use crate::hash::SHA1;
+    use crate::hash::{ObjectHasher, SHA1};
+    use crate::internal::object::types::ObjectType;
 
     #[test]
     fn test_sha1_new() {
@@ -184,6 +295,20 @@ mod tests {
         assert_eq!(sha1.to_string(), expected_sha1_hash);
     }
 
+    #[test]
+    fn test_sha1_from_type_and_data() {
+        // `echo -n "hello" | git hash-object --stdin`
+        let expected = "b6fc4c620b67d95f953a5c1c1230aaab5db5a1b0";
+        let hash = SHA1::from_type_and_data(ObjectType::Blob, b"hello");
+        assert_eq!(hash.to_string(), expected);
+        let checked = SHA1::try_from_type_and_data(ObjectType::Blob, b"hello").unwrap();
+        assert_eq!(checked, hash);
+        assert_eq!(
+            SHA1::try_new(b"Hello, world!").unwrap(),
+            SHA1::new(b"Hello, world!")
+        );
+    }
+
     #[test]
     fn test_signature_without_delta() {
         let mut source = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -202,6 +327,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_object_hasher() {
+        let data = b"some content hashed in pieces";
+        let mut hasher = ObjectHasher::new(ObjectType::Blob, data.len());
+        for piece in data.chunks(4) {
+            hasher.update(piece);
+        }
+        let expected = SHA1::from_type_and_data(ObjectType::Blob, data);
+        assert_eq!(hasher.finish().unwrap(), expected);
+
+        let mut short = ObjectHasher::new(ObjectType::Blob, data.len());
+        short.update(&data[1..]);
+        assert!(short.finish().is_err());
+    }
+
     #[test]
     fn test_sha1_from_bytes() {
         let sha1 = SHA1::from_bytes(&[
@@ -0,0 +1,214 @@
+//! The chunked layout shared by the commit-graph and the multi-pack-index, git's
+//! `chunk-format.c`.
+//!
+//! After a header of its own, such a file has a table of contents of `(chunk id, offset)`
+//! pairs terminated by a zero id and the end offset, the chunks, and a SHA-1 of everything
+//! before it. Both files list sorted object ids in an `OIDL` chunk, found through the `OIDF`
+//! fanout on their first byte.
+use std::collections::HashMap;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::errors::GitError;
+use crate::hash::SHA1;
+
+const CHUNK_ENTRY_SIZE: usize = 12;
+
+pub(crate) const OID_FANOUT: u32 = u32::from_be_bytes(*b"OIDF");
+pub(crate) const OID_LOOKUP: u32 = u32::from_be_bytes(*b"OIDL");
+
+const FANOUT_SIZE: usize = 256 * 4;
+
+/// The smallest file with a header of `header_size` bytes: an empty table and the checksum.
+pub(crate) fn min_file_size(header_size: usize) -> usize {
+    header_size + CHUNK_ENTRY_SIZE + SHA1::SIZE
+}
+
+/// The chunks of a file, by id.
+pub(crate) struct ChunkTable {
+    chunks: HashMap<u32, (usize, usize)>,
+    /// The error of the file kind, e.g. `GitError::InvalidCommitGraph`
+    invalid: fn(String) -> GitError,
+}
+
+impl ChunkTable {
+    /// Verify the checksum of `data` and read its table of `chunk_count` chunks, which follows
+    /// a header of `header_size` bytes. `data` is at least [`min_file_size`] long.
+    pub(crate) fn read(
+        data: &[u8],
+        header_size: usize,
+        chunk_count: usize,
+        invalid: fn(String) -> GitError,
+    ) -> Result<Self, GitError> {
+        let trailer = data.len() - SHA1::SIZE;
+        if SHA1::new(&data[..trailer]) != SHA1::from_bytes(&data[trailer..]) {
+            return Err(invalid("checksum mismatch".to_string()));
+        }
+        let table_end = header_size + (chunk_count + 1) * CHUNK_ENTRY_SIZE;
+        if table_end > trailer {
+            return Err(invalid("truncated chunk table".to_string()));
+        }
+        let mut chunks = HashMap::new();
+        for i in 0..chunk_count {
+            let entry = header_size + i * CHUNK_ENTRY_SIZE;
+            let next = entry + CHUNK_ENTRY_SIZE;
+            let id = BigEndian::read_u32(&data[entry..entry + 4]);
+            let start = BigEndian::read_u64(&data[entry + 4..next]) as usize;
+            let end = BigEndian::read_u64(&data[next + 4..next + CHUNK_ENTRY_SIZE]) as usize;
+            if start < table_end || start > end || end > trailer {
+                return Err(invalid("chunk offsets out of bounds".to_string()));
+            }
+            chunks.insert(id, (start, end - start));
+        }
+        Ok(Self { chunks, invalid })
+    }
+
+    /// The offset and size of chunk `id`, `None` if the file has none.
+    pub(crate) fn get(&self, id: u32) -> Option<(usize, usize)> {
+        self.chunks.get(&id).copied()
+    }
+
+    /// The offset and size of chunk `id`, which the file must have.
+    pub(crate) fn require(&self, id: u32) -> Result<(usize, usize), GitError> {
+        self.get(id)
+            .ok_or_else(|| (self.invalid)(format!("missing {} chunk", chunk_name(id))))
+    }
+
+    /// The offset of chunk `id`, which must hold `count` entries of `entry_size` bytes.
+    pub(crate) fn sized(
+        &self,
+        id: u32,
+        count: usize,
+        entry_size: usize,
+    ) -> Result<usize, GitError> {
+        let (start, size) = self.require(id)?;
+        if size != count * entry_size {
+            return Err(self.wrong_size(id));
+        }
+        Ok(start)
+    }
+
+    /// The offset of the `OIDF` chunk of `data` and the number of ids it counts.
+    pub(crate) fn fanout(&self, data: &[u8]) -> Result<(usize, usize), GitError> {
+        let (fanout, size) = self.require(OID_FANOUT)?;
+        if size != FANOUT_SIZE {
+            return Err(self.wrong_size(OID_FANOUT));
+        }
+        let fanout_at = |i: usize| BigEndian::read_u32(&data[fanout + i * 4..]) as usize;
+        if (1..256).any(|i| fanout_at(i) < fanout_at(i - 1)) {
+            return Err((self.invalid)("OIDF chunk is not sorted".to_string()));
+        }
+        Ok((fanout, fanout_at(255)))
+    }
+
+    fn wrong_size(&self, id: u32) -> GitError {
+        (self.invalid)(format!("{} chunk has the wrong size", chunk_name(id)))
+    }
+}
+
+fn chunk_name(id: u32) -> String {
+    String::from_utf8_lossy(&id.to_be_bytes()).into_owned()
+}
+
+/// Complete a file from its `header`: the table of contents of `chunks`, the chunks in order
+/// and the checksum.
+pub(crate) fn write_chunks(header: Vec<u8>, chunks: Vec<(u32, Vec<u8>)>) -> Vec<u8> {
+    let mut data = header;
+    let mut offset = (data.len() + (chunks.len() + 1) * CHUNK_ENTRY_SIZE) as u64;
+    for (id, chunk) in &chunks {
+        data.extend_from_slice(&id.to_be_bytes());
+        data.extend_from_slice(&offset.to_be_bytes());
+        offset += chunk.len() as u64;
+    }
+    data.extend_from_slice(&0u32.to_be_bytes());
+    data.extend_from_slice(&offset.to_be_bytes());
+    for (_, chunk) in chunks {
+        data.extend_from_slice(&chunk);
+    }
+    let checksum = SHA1::new(&data);
+    data.extend_from_slice(&checksum.0);
+    data
+}
+
+/// The `OIDF` chunk of sorted `ids`: for every byte value, the number of ids whose first byte
+/// is at most that value.
+pub(crate) fn fanout_chunk<'a>(ids: impl IntoIterator<Item = &'a SHA1>) -> Vec<u8> {
+    let mut counts = [0u32; 256];
+    for id in ids {
+        counts[id.0[0] as usize] += 1;
+    }
+    let mut fanout = Vec::with_capacity(FANOUT_SIZE);
+    let mut below = 0;
+    for count in counts {
+        below += count;
+        fanout.extend_from_slice(&below.to_be_bytes());
+    }
+    fanout
+}
+
+/// The position of `id` among the `count` sorted ids of the `OIDL` chunk at `lookup` in
+/// `data`, searched between the bounds the `OIDF` chunk at `fanout` gives for its first byte.
+pub(crate) fn find_id(
+    data: &[u8],
+    fanout: usize,
+    lookup: usize,
+    count: usize,
+    id: &SHA1,
+) -> Option<usize> {
+    let first = id.0[0] as usize;
+    let fanout_at = |i: usize| BigEndian::read_u32(&data[fanout + i * 4..]) as usize;
+    let end = fanout_at(first).min(count);
+    let start = if first == 0 { 0 } else { fanout_at(first - 1) };
+    let id_at = |position: usize| &data[lookup + position * SHA1::SIZE..][..SHA1::SIZE];
+    let (mut low, mut high) = (start.min(end), end);
+    while low < high {
+        let mid = (low + high) / 2;
+        match id_at(mid).cmp(&id.0[..]) {
+            std::cmp::Ordering::Less => low = mid + 1,
+            std::cmp::Ordering::Greater => high = mid,
+            std::cmp::Ordering::Equal => return Some(mid),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_chunks_round_trip() {
+        let ids: Vec<SHA1> = ["00", "0a", "7f", "7f", "ff"]
+            .iter()
+            .enumerate()
+            .map(|(i, first)| SHA1::from_str(&format!("{first}{i:038}")).unwrap())
+            .collect();
+        let lookup: Vec<u8> = ids.iter().flat_map(|id| id.0).collect();
+        let data = write_chunks(
+            b"TEST".to_vec(),
+            vec![(OID_FANOUT, fanout_chunk(&ids)), (OID_LOOKUP, lookup)],
+        );
+
+        let table = ChunkTable::read(&data, 4, 2, GitError::InvalidCommitGraph).unwrap();
+        let (fanout, count) = table.fanout(&data).unwrap();
+        assert_eq!(count, ids.len());
+        let lookup = table.sized(OID_LOOKUP, count, SHA1::SIZE).unwrap();
+        for (position, id) in ids.iter().enumerate() {
+            assert_eq!(find_id(&data, fanout, lookup, count, id), Some(position));
+        }
+        let missing = SHA1::from_str(&format!("7e{:038}", 0)).unwrap();
+        assert_eq!(find_id(&data, fanout, lookup, count, &missing), None);
+
+        assert!(table.get(u32::from_be_bytes(*b"NONE")).is_none());
+        let err = table.sized(OID_LOOKUP, count + 1, SHA1::SIZE).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            GitError::InvalidCommitGraph("OIDL chunk has the wrong size".to_string()).to_string()
+        );
+
+        let mut corrupt = data.clone();
+        corrupt[10] ^= 1;
+        assert!(ChunkTable::read(&corrupt, 4, 2, GitError::InvalidMultiPackIndex).is_err());
+    }
+}
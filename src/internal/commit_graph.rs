@@ -0,0 +1,719 @@
+//! Reader and writer of git's commit-graph file, `objects/info/commit-graph`.
+//!
+//! The file lists commits sorted by id, with their tree, parents, commit time and generation
+//! numbers, so history walks can follow parents without loading and parsing commit objects:
+//!
+//! - an 8 byte header: `CGPH`, version 1, hash version 1 (SHA-1), the number of chunks and the
+//!   number of base graphs;
+//! - a table of contents of `(chunk id, offset)` pairs, terminated by a zero id and the end
+//!   offset;
+//! - the chunks: `OIDF` (fanout on the first byte of the ids), `OIDL` (sorted ids), `CDAT` (tree,
+//!   the positions of two parents, topological level and commit time), `EDGE` (the other parents
+//!   of octopus merges), `GDA2`/`GDO2` (corrected commit date offsets) and `BIDX`/`BDAT` (the
+//!   changed-path Bloom filters, see [`ChangedPathFilter`]);
+//! - a SHA-1 of everything before it.
+//!
+//! Split graphs (`commit-graphs/` chains) are not supported; unknown chunks are ignored.
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::errors::GitError;
+use crate::hash::SHA1;
+use crate::internal::chunk_format::{
+    ChunkTable, OID_FANOUT, OID_LOOKUP, fanout_chunk, find_id, min_file_size, write_chunks,
+};
+use crate::internal::object::commit::Commit;
+use crate::internal::refs::LockFile;
+
+const SIGNATURE: &[u8; 4] = b"CGPH";
+const VERSION: u8 = 1;
+/// Hash version of SHA-1.
+const HASH_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 8;
+
+const COMMIT_DATA: u32 = u32::from_be_bytes(*b"CDAT");
+const GENERATION_DATA: u32 = u32::from_be_bytes(*b"GDA2");
+const GENERATION_DATA_OVERFLOW: u32 = u32::from_be_bytes(*b"GDO2");
+const EXTRA_EDGES: u32 = u32::from_be_bytes(*b"EDGE");
+const BLOOM_INDEXES: u32 = u32::from_be_bytes(*b"BIDX");
+const BLOOM_DATA: u32 = u32::from_be_bytes(*b"BDAT");
+
+/// Tree id, two parent positions and the 8 byte generation/date field.
+const COMMIT_DATA_SIZE: usize = SHA1::SIZE + 16;
+
+/// Parent position of a missing parent.
+const PARENT_NONE: u32 = 0x7000_0000;
+/// Set on the second parent position of an octopus merge, whose low bits index `EDGE`, and on
+/// the last parent of an `EDGE` list.
+const EDGE_FLAG: u32 = 0x8000_0000;
+/// Largest topological level that fits in the 30 bits `CDAT` has for it.
+const TOPO_LEVEL_MAX: u32 = 0x3fff_ffff;
+/// Commit times are stored on 34 bits.
+const COMMIT_TIME_MAX: u64 = (1 << 34) - 1;
+/// Set on a `GDA2` offset too large for 31 bits, whose low bits index `GDO2`.
+const OFFSET_OVERFLOW: u32 = 0x8000_0000;
+
+/// `BDAT` header: hash version, number of hashes and bits per entry.
+const BLOOM_HEADER_SIZE: usize = 12;
+/// Murmur3 seeds of the two hashes every key of a changed-path filter is derived from.
+const BLOOM_SEEDS: [u32; 2] = [0x293a_e76f, 0x7e64_6e2c];
+/// Commits changing more paths get a filter matching every path, as git writes them.
+const MAX_CHANGED_PATHS: usize = 512;
+
+/// A commit as recorded in a commit-graph.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphCommit {
+    pub id: SHA1,
+    pub tree_id: SHA1,
+    pub parents: Vec<SHA1>,
+    /// Committer date, in seconds since the epoch.
+    pub commit_time: u64,
+    /// Generation number v1, `0` when the writer didn't compute it.
+    pub topo_level: u32,
+    /// Generation number v2, `None` when the file has no `GDA2` chunk.
+    pub corrected_date: Option<u64>,
+}
+
+/// A parsed commit-graph file.
+#[derive(Debug, Clone)]
+pub struct CommitGraph {
+    data: Vec<u8>,
+    count: usize,
+    fanout: usize,
+    lookup: usize,
+    commit_data: usize,
+    generation_data: Option<usize>,
+    generation_overflow: Option<(usize, usize)>,
+    extra_edges: Option<(usize, usize)>,
+    /// `BIDX` offset and the `BDAT` filter settings and data offset.
+    bloom: Option<(usize, BloomSettings, usize, usize)>,
+}
+
+impl CommitGraph {
+    /// Read the commit-graph at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, GitError> {
+        Self::from_bytes(fs::read(path)?)
+    }
+
+    /// Parse the content of a commit-graph file, verifying its layout and checksum.
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, GitError> {
+        let invalid = |reason: &str| GitError::InvalidCommitGraph(reason.to_string());
+        if data.len() < min_file_size(HEADER_SIZE) {
+            return Err(invalid("truncated commit-graph"));
+        }
+        if &data[..4] != SIGNATURE {
+            return Err(invalid("missing CGPH signature"));
+        }
+        if data[4] != VERSION || data[5] != HASH_VERSION {
+            return Err(invalid("unsupported commit-graph or hash version"));
+        }
+        if data[7] != 0 {
+            return Err(invalid("split commit-graph chains are not supported"));
+        }
+        let chunks = ChunkTable::read(
+            &data,
+            HEADER_SIZE,
+            data[6] as usize,
+            GitError::InvalidCommitGraph,
+        )?;
+        let (fanout, count) = chunks.fanout(&data)?;
+        let sized = |id: u32, entry_size: usize| chunks.sized(id, count, entry_size);
+        let lookup = sized(OID_LOOKUP, SHA1::SIZE)?;
+        let commit_data = sized(COMMIT_DATA, COMMIT_DATA_SIZE)?;
+        let generation_data = match chunks.get(GENERATION_DATA) {
+            Some(_) => Some(sized(GENERATION_DATA, 4)?),
+            None => None,
+        };
+        let bloom = match (chunks.get(BLOOM_DATA), chunks.get(BLOOM_INDEXES)) {
+            (Some((start, size)), Some(_)) => {
+                let indexes = sized(BLOOM_INDEXES, 4)?;
+                if size < BLOOM_HEADER_SIZE {
+                    return Err(invalid("BDAT chunk is truncated"));
+                }
+                let word = |i: usize| BigEndian::read_u32(&data[start + i * 4..]);
+                let settings = BloomSettings {
+                    hash_version: word(0),
+                    num_hashes: word(1),
+                    bits_per_entry: word(2),
+                };
+                // Filters of unknown versions are ignored, as git does
+                matches!(settings.hash_version, 1 | 2).then_some((
+                    indexes,
+                    settings,
+                    start + BLOOM_HEADER_SIZE,
+                    size - BLOOM_HEADER_SIZE,
+                ))
+            }
+            _ => None,
+        };
+        Ok(Self {
+            data,
+            count,
+            fanout,
+            lookup,
+            commit_data,
+            generation_data,
+            generation_overflow: chunks.get(GENERATION_DATA_OVERFLOW),
+            extra_edges: chunks.get(EXTRA_EDGES),
+            bloom,
+        })
+    }
+
+    /// Build the commit-graph of `commits`, computing their generation numbers. Every parent of
+    /// a commit has to be in `commits` as well.
+    pub fn from_commits<'a>(
+        commits: impl IntoIterator<Item = &'a Commit>,
+    ) -> Result<Self, GitError> {
+        Self::build(commits.into_iter().collect(), None)
+    }
+
+    /// [`CommitGraph::from_commits`] with changed-path Bloom filters. `changed_paths` lists the
+    /// files a commit changed compared to its first parent (or to the empty tree for a root
+    /// commit), e.g. the paths of [`diff_trees`](crate::diff::tree::diff_trees).
+    pub fn from_commits_with_changed_paths<'a, F>(
+        commits: impl IntoIterator<Item = &'a Commit>,
+        mut changed_paths: F,
+    ) -> Result<Self, GitError>
+    where
+        F: FnMut(&Commit) -> Result<Vec<String>, GitError>,
+    {
+        Self::build(commits.into_iter().collect(), Some(&mut changed_paths))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn build(
+        mut commits: Vec<&Commit>,
+        changed_paths: Option<&mut dyn FnMut(&Commit) -> Result<Vec<String>, GitError>>,
+    ) -> Result<Self, GitError> {
+        commits.sort_by_key(|commit| commit.id);
+        commits.dedup_by_key(|commit| commit.id);
+        let positions: HashMap<SHA1, u32> = commits
+            .iter()
+            .enumerate()
+            .map(|(position, commit)| (commit.id, position as u32))
+            .collect();
+        let mut parents = Vec::with_capacity(commits.len());
+        for commit in &commits {
+            let mut commit_parents = Vec::with_capacity(commit.parent_commit_ids.len());
+            for parent in &commit.parent_commit_ids {
+                let position = positions.get(parent).ok_or_else(|| {
+                    GitError::InvalidCommitGraph(format!(
+                        "parent {parent} of {} is not in the graph",
+                        commit.id
+                    ))
+                })?;
+                commit_parents.push(*position as usize);
+            }
+            parents.push(commit_parents);
+        }
+        let times: Vec<u64> = commits
+            .iter()
+            .map(|commit| (commit.committer.timestamp as u64).min(COMMIT_TIME_MAX))
+            .collect();
+        let (levels, corrected) = number_commits(&parents, &times)?;
+
+        let fanout = fanout_chunk(commits.iter().map(|commit| &commit.id));
+        let mut lookup = Vec::with_capacity(commits.len() * SHA1::SIZE);
+        let mut commit_data = Vec::with_capacity(commits.len() * COMMIT_DATA_SIZE);
+        let mut generation_data = Vec::with_capacity(commits.len() * 4);
+        let mut generation_overflow = Vec::new();
+        let mut extra_edges: Vec<u8> = Vec::new();
+        for (position, commit) in commits.iter().enumerate() {
+            lookup.extend_from_slice(&commit.id.0);
+            commit_data.extend_from_slice(&commit.tree_id.0);
+            let parent = |i: usize| {
+                parents[position]
+                    .get(i)
+                    .map_or(PARENT_NONE, |&parent| parent as u32)
+            };
+            commit_data.extend_from_slice(&parent(0).to_be_bytes());
+            if parents[position].len() > 2 {
+                let edge = (extra_edges.len() / 4) as u32;
+                commit_data.extend_from_slice(&(EDGE_FLAG | edge).to_be_bytes());
+                let others = &parents[position][1..];
+                for (i, &other) in others.iter().enumerate() {
+                    let last = if i + 1 == others.len() { EDGE_FLAG } else { 0 };
+                    extra_edges.extend_from_slice(&(other as u32 | last).to_be_bytes());
+                }
+            } else {
+                commit_data.extend_from_slice(&parent(1).to_be_bytes());
+            }
+            let level = levels[position].min(TOPO_LEVEL_MAX);
+            let time = times[position];
+            commit_data.extend_from_slice(&(level << 2 | (time >> 32) as u32).to_be_bytes());
+            commit_data.extend_from_slice(&(time as u32).to_be_bytes());
+
+            let offset = corrected[position] - time;
+            if offset < OFFSET_OVERFLOW as u64 {
+                generation_data.extend_from_slice(&(offset as u32).to_be_bytes());
+            } else {
+                let index = (generation_overflow.len() / 8) as u32;
+                generation_data.extend_from_slice(&(OFFSET_OVERFLOW | index).to_be_bytes());
+                generation_overflow.extend_from_slice(&offset.to_be_bytes());
+            }
+        }
+
+        let mut chunks = vec![
+            (OID_FANOUT, fanout),
+            (OID_LOOKUP, lookup),
+            (COMMIT_DATA, commit_data),
+            (GENERATION_DATA, generation_data),
+        ];
+        if !generation_overflow.is_empty() {
+            chunks.push((GENERATION_DATA_OVERFLOW, generation_overflow));
+        }
+        if !extra_edges.is_empty() {
+            chunks.push((EXTRA_EDGES, extra_edges));
+        }
+        if let Some(changed_paths) = changed_paths {
+            let settings = BloomSettings::default();
+            let mut indexes = Vec::with_capacity(commits.len() * 4);
+            let mut filters = Vec::with_capacity(BLOOM_HEADER_SIZE);
+            for word in [
+                settings.hash_version,
+                settings.num_hashes,
+                settings.bits_per_entry,
+            ] {
+                filters.extend_from_slice(&word.to_be_bytes());
+            }
+            for commit in &commits {
+                let filter = ChangedPathFilter::new(&changed_paths(commit)?);
+                filters.extend_from_slice(&filter.data);
+                let end = (filters.len() - BLOOM_HEADER_SIZE) as u32;
+                indexes.extend_from_slice(&end.to_be_bytes());
+            }
+            chunks.push((BLOOM_INDEXES, indexes));
+            chunks.push((BLOOM_DATA, filters));
+        }
+        let mut header = SIGNATURE.to_vec();
+        header.extend_from_slice(&[VERSION, HASH_VERSION, chunks.len() as u8, 0]);
+        let data = write_chunks(header, chunks);
+        Self::from_bytes(data)
+    }
+
+    /// The content of the file.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Write the graph to `path` through a `.lock` file, so readers never see a partial file.
+    pub fn write_to(&self, path: impl AsRef<Path>) -> Result<(), GitError> {
+        LockFile::acquire(path.as_ref())?.commit(&self.data)
+    }
+
+    /// Number of commits in the graph.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// The id of the commit at `position`, ids being sorted.
+    pub fn id_at(&self, position: usize) -> SHA1 {
+        let start = self.lookup + position * SHA1::SIZE;
+        SHA1::from_bytes(&self.data[start..start + SHA1::SIZE])
+    }
+
+    /// The position of `id` in the graph, `None` if it isn't in it.
+    pub fn position(&self, id: &SHA1) -> Option<usize> {
+        find_id(&self.data, self.fanout, self.lookup, self.count, id)
+    }
+
+    pub fn contains(&self, id: &SHA1) -> bool {
+        self.position(id).is_some()
+    }
+
+    /// The commit `id`, `None` if it isn't in the graph.
+    pub fn get(&self, id: &SHA1) -> Option<GraphCommit> {
+        self.commit_at(self.position(id)?)
+    }
+
+    /// The commit at `position`, `None` if its parent positions don't point into the graph.
+    pub fn commit_at(&self, position: usize) -> Option<GraphCommit> {
+        if position >= self.count {
+            return None;
+        }
+        let start = self.commit_data + position * COMMIT_DATA_SIZE;
+        let entry = &self.data[start..start + COMMIT_DATA_SIZE];
+        let word = |i: usize| BigEndian::read_u32(&entry[SHA1::SIZE + i * 4..]);
+
+        let mut parents = Vec::new();
+        for (i, parent) in [word(0), word(1)].into_iter().enumerate() {
+            if parent == PARENT_NONE {
+                break;
+            }
+            if i == 1 && parent & EDGE_FLAG != 0 {
+                parents.extend(self.extra_edges(parent & !EDGE_FLAG)?);
+                break;
+            }
+            parents.push(self.checked_id(parent)?);
+        }
+
+        let commit_time = ((word(2) & 0x3) as u64) << 32 | word(3) as u64;
+        let corrected_date = match self.generation_data {
+            Some(generation_data) => {
+                let start = generation_data + position * 4;
+                let offset = BigEndian::read_u32(&self.data[start..start + 4]);
+                let offset = if offset & OFFSET_OVERFLOW != 0 {
+                    let (overflow, size) = self.generation_overflow?;
+                    let start = ((offset & !OFFSET_OVERFLOW) as usize) * 8;
+                    if start + 8 > size {
+                        return None;
+                    }
+                    BigEndian::read_u64(&self.data[overflow + start..])
+                } else {
+                    offset as u64
+                };
+                Some(commit_time + offset)
+            }
+            None => None,
+        };
+        Some(GraphCommit {
+            id: self.id_at(position),
+            tree_id: SHA1::from_bytes(&entry[..SHA1::SIZE]),
+            parents,
+            commit_time,
+            topo_level: word(2) >> 2,
+            corrected_date,
+        })
+    }
+
+    /// Every commit of the graph, by id.
+    pub fn commits(&self) -> impl Iterator<Item = GraphCommit> + '_ {
+        (0..self.count).filter_map(|position| self.commit_at(position))
+    }
+
+    /// The changed-path filter of `id`, `None` if the graph has no filter for it.
+    pub fn changed_path_filter(&self, id: &SHA1) -> Option<ChangedPathFilter> {
+        let (indexes, settings, start, size) = self.bloom?;
+        let position = self.position(id)?;
+        let end_of =
+            |position: usize| BigEndian::read_u32(&self.data[indexes + position * 4..]) as usize;
+        let begin = if position == 0 {
+            0
+        } else {
+            end_of(position - 1)
+        };
+        let end = end_of(position);
+        // An empty range is a commit whose filter wasn't computed
+        if begin >= end || end > size {
+            return None;
+        }
+        Some(ChangedPathFilter {
+            data: self.data[start + begin..start + end].to_vec(),
+            settings,
+        })
+    }
+
+    fn checked_id(&self, position: u32) -> Option<SHA1> {
+        ((position as usize) < self.count).then(|| self.id_at(position as usize))
+    }
+
+    /// The parents listed in `EDGE` from `index` on.
+    fn extra_edges(&self, index: u32) -> Option<Vec<SHA1>> {
+        let (start, size) = self.extra_edges?;
+        let mut parents = Vec::new();
+        let mut offset = index as usize * 4;
+        loop {
+            if offset + 4 > size {
+                return None;
+            }
+            let edge = BigEndian::read_u32(&self.data[start + offset..]);
+            parents.push(self.checked_id(edge & !EDGE_FLAG)?);
+            if edge & EDGE_FLAG != 0 {
+                return Some(parents);
+            }
+            offset += 4;
+        }
+    }
+}
+
+/// Parameters of the changed-path filters of a graph, from the `BDAT` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BloomSettings {
+    /// 1 for filters hashed with git's original murmur3, which sign-extends bytes above 0x7f,
+    /// 2 for the correct one.
+    hash_version: u32,
+    num_hashes: u32,
+    bits_per_entry: u32,
+}
+
+impl Default for BloomSettings {
+    fn default() -> Self {
+        Self {
+            hash_version: 2,
+            num_hashes: 7,
+            bits_per_entry: 10,
+        }
+    }
+}
+
+/// A changed-path Bloom filter: which paths a commit changed compared to its first parent, with
+/// every leading directory of a changed file counted as changed too.
+///
+/// A negative answer is definite, so `git log -- <path>` skips commits whose filter doesn't
+/// contain the path without loading their trees; a positive one has to be confirmed with a diff.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangedPathFilter {
+    data: Vec<u8>,
+    settings: BloomSettings,
+}
+
+impl ChangedPathFilter {
+    /// The filter of a commit changing the files `paths`, with git's default settings (version
+    /// 2 hashes, 7 hashes and 10 bits per path). Past 512 paths the filter matches every path.
+    pub fn new(paths: &[String]) -> Self {
+        let settings = BloomSettings::default();
+        let mut keys: Vec<&str> = Vec::new();
+        for path in paths {
+            let mut path = path.trim_end_matches('/');
+            while !path.is_empty() {
+                keys.push(path);
+                path = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+            }
+        }
+        keys.sort_unstable();
+        keys.dedup();
+        if keys.len() > MAX_CHANGED_PATHS {
+            return Self {
+                data: vec![0xff],
+                settings,
+            };
+        }
+        let len = (keys.len() * settings.bits_per_entry as usize)
+            .div_ceil(8)
+            .max(1);
+        let mut filter = Self {
+            data: vec![0; len],
+            settings,
+        };
+        for key in keys {
+            for bit in filter.bit_positions(key) {
+                filter.data[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    /// `false` if the commit definitely didn't change `path`, `true` if it may have.
+    pub fn may_contain(&self, path: &str) -> bool {
+        let path = path.trim_end_matches('/');
+        self.bit_positions(path)
+            .all(|bit| self.data[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Whether the filter was written for a commit changing too many paths and matches anything.
+    pub fn is_truncated(&self) -> bool {
+        self.data == [0xff]
+    }
+
+    fn bit_positions(&self, key: &str) -> impl Iterator<Item = usize> + use<> {
+        let signed = self.settings.hash_version == 1;
+        let [h0, h1] = BLOOM_SEEDS.map(|seed| murmur3_32(seed, key.as_bytes(), signed));
+        let bits = (self.data.len() * 8) as u32;
+        (0..self.settings.num_hashes)
+            .map(move |i| (h0.wrapping_add(i.wrapping_mul(h1)) % bits) as usize)
+    }
+}
+
+/// 32-bit murmur3. `signed` reproduces version 1 of git's filters, which read bytes as signed
+/// chars.
+fn murmur3_32(seed: u32, data: &[u8], signed: bool) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+    let byte = |b: u8| if signed { b as i8 as u32 } else { b as u32 };
+    let mix = |k: u32| k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+
+    let mut hash = seed;
+    let mut blocks = data.chunks_exact(4);
+    for block in &mut blocks {
+        let k = byte(block[0]) | byte(block[1]) << 8 | byte(block[2]) << 16 | byte(block[3]) << 24;
+        hash ^= mix(k);
+        hash = hash
+            .rotate_left(13)
+            .wrapping_mul(5)
+            .wrapping_add(0xe654_6b64);
+    }
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        let k = tail
+            .iter()
+            .enumerate()
+            .fold(0, |k, (i, &b)| k ^ byte(b) << (8 * i));
+        hash ^= mix(k);
+    }
+
+    hash ^= data.len() as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^ (hash >> 16)
+}
+
+/// Topological levels and corrected commit dates of commits given by parent positions, parents
+/// numbered before children through an explicit stack so long histories don't overflow the call
+/// stack.
+fn number_commits(parents: &[Vec<usize>], times: &[u64]) -> Result<(Vec<u32>, Vec<u64>), GitError> {
+    let mut levels = vec![0u32; parents.len()];
+    let mut corrected = vec![0u64; parents.len()];
+    // Commits whose parents are being numbered; reaching one of them again is a cycle
+    let mut open = vec![false; parents.len()];
+    for root in 0..parents.len() {
+        let mut stack = vec![root];
+        while let Some(&top) = stack.last() {
+            if levels[top] != 0 {
+                stack.pop();
+            } else if open[top] {
+                levels[top] = 1 + parents[top].iter().map(|&p| levels[p]).max().unwrap_or(0);
+                corrected[top] = parents[top]
+                    .iter()
+                    .map(|&p| corrected[p] + 1)
+                    .fold(times[top], u64::max);
+                open[top] = false;
+                stack.pop();
+            } else {
+                if parents[top].iter().any(|&parent| open[parent]) {
+                    return Err(GitError::InvalidCommitGraph(
+                        "commits form a cycle".to_string(),
+                    ));
+                }
+                open[top] = true;
+                stack.extend(parents[top].iter().filter(|&&parent| levels[parent] == 0));
+            }
+        }
+    }
+    Ok((levels, corrected))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::revwalk::tests::commit;
+
+    #[test]
+    fn test_commit_graph_roundtrip() {
+        let a = commit(vec![], 100, "a");
+        let b = commit(vec![a.id], 50, "b");
+        let c = commit(vec![a.id], 200, "c");
+        let d = commit(vec![b.id], 300, "d");
+        let octopus = commit(vec![d.id, b.id, c.id], 400, "octopus");
+        let commits = [&octopus, &a, &b, &c, &d];
+
+        let graph = CommitGraph::from_commits(commits).unwrap();
+        assert_eq!(graph.len(), 5);
+        let reread = CommitGraph::from_bytes(graph.as_bytes().to_vec()).unwrap();
+        let ids: Vec<SHA1> = reread.commits().map(|commit| commit.id).collect();
+        let mut sorted = ids.clone();
+        sorted.sort();
+        assert_eq!(ids, sorted);
+
+        let node = reread.get(&octopus.id).unwrap();
+        assert_eq!(node.parents, [d.id, b.id, c.id]);
+        assert_eq!(node.topo_level, 4);
+        assert_eq!(node.commit_time, 400);
+        // `b` is older than its parent, so its corrected date is raised past `a`
+        let b = reread.get(&b.id).unwrap();
+        assert_eq!(
+            (b.topo_level, b.commit_time, b.corrected_date),
+            (2, 50, Some(101))
+        );
+        assert_eq!(reread.get(&a.id).unwrap().parents, []);
+        assert!(reread.get(&SHA1::new(b"not a commit")).is_none());
+
+        let dir = std::env::temp_dir().join(format!("commit-graph-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("commit-graph");
+        graph.write_to(&path).unwrap();
+        assert_eq!(CommitGraph::open(&path).unwrap().len(), 5);
+        // Another writer holds the lock
+        fs::write(dir.join("commit-graph.lock"), b"").unwrap();
+        assert!(matches!(graph.write_to(&path), Err(GitError::RefLocked(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_murmur3() {
+        assert_eq!(murmur3_32(0, b"", false), 0);
+        assert_eq!(murmur3_32(0, b"Hello world!", false), 0x627b_0c2c);
+        assert_eq!(
+            murmur3_32(0, b"The quick brown fox jumps over the lazy dog", false),
+            0x2e4f_f723
+        );
+        // Version 1 only differs on bytes above 0x7f
+        assert_eq!(murmur3_32(0, b"abc", true), murmur3_32(0, b"abc", false));
+        assert_ne!(
+            murmur3_32(0, "caf\u{e9}".as_bytes(), true),
+            murmur3_32(0, "caf\u{e9}".as_bytes(), false)
+        );
+    }
+
+    #[test]
+    fn test_changed_path_filters() {
+        let a = commit(vec![], 1, "a");
+        let b = commit(vec![a.id], 2, "b");
+        let c = commit(vec![b.id], 3, "c");
+        let graph = CommitGraph::from_commits_with_changed_paths([&a, &b, &c], |commit| {
+            Ok(match commit.id {
+                id if id == a.id => vec!["README.md".to_string(), "src/lib.rs".to_string()],
+                id if id == b.id => vec!["src/diff/tree.rs".to_string()],
+                _ => (0..600).map(|i| format!("generated/{i}.rs")).collect(),
+            })
+        })
+        .unwrap();
+        let graph = CommitGraph::from_bytes(graph.as_bytes().to_vec()).unwrap();
+
+        let filter = graph.changed_path_filter(&b.id).unwrap();
+        assert!(filter.may_contain("src/diff/tree.rs"));
+        assert!(filter.may_contain("src/diff"));
+        assert!(filter.may_contain("src/"));
+        assert!(!filter.may_contain("README.md"));
+        assert!(!filter.may_contain("src/lib.rs"));
+        assert!(
+            graph
+                .changed_path_filter(&a.id)
+                .unwrap()
+                .may_contain("README.md")
+        );
+
+        let truncated = graph.changed_path_filter(&c.id).unwrap();
+        assert!(truncated.is_truncated());
+        assert!(truncated.may_contain("README.md"));
+        assert!(
+            CommitGraph::from_commits([&a])
+                .unwrap()
+                .changed_path_filter(&a.id)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_commit_graph_rejects_invalid_files() {
+        let a = commit(vec![], 1, "a");
+        let b = commit(vec![a.id], 2, "b");
+        assert!(matches!(
+            CommitGraph::from_commits([&b]),
+            Err(GitError::InvalidCommitGraph(_))
+        ));
+
+        let mut data = CommitGraph::from_commits([&a, &b])
+            .unwrap()
+            .as_bytes()
+            .to_vec();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        assert!(matches!(
+            CommitGraph::from_bytes(data.clone()),
+            Err(GitError::InvalidCommitGraph(_))
+        ));
+        assert!(matches!(
+            CommitGraph::from_bytes(data[..20].to_vec()),
+            Err(GitError::InvalidCommitGraph(_))
+        ));
+    }
+}
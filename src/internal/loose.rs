@@ -0,0 +1,388 @@
+//! Loose objects, the `objects/<xx>/<38 hex digits>` files of a git directory.
+//!
+//! A loose object is the zlib stream of a `<type> <size>\0` header followed by the content of
+//! the object. It is named by the hex id of the object, whose first two digits are a fan-out
+//! directory keeping directories small. Git writes new objects this way until a repack moves
+//! them into a pack.
+//!
+//! [`encode_loose_object`] and [`decode_loose_object`] convert between an object and the content
+//! of its file; [`LooseObjectStore`] reads and writes the files of an `objects` directory, so a
+//! filesystem backend can serve a standard `.git` directory next to its packs.
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+use crate::errors::GitError;
+use crate::hash::SHA1;
+use crate::internal::object::ObjectTrait;
+use crate::internal::object::types::ObjectType;
+use crate::internal::worktree::GitDir;
+
+/// The longest header git accepts, `<type> <size>\0`.
+const MAX_HEADER_LEN: usize = 32;
+
+/// The most content reserved before inflating, the size of the header being untrusted.
+const MAX_PREALLOCATION: usize = 1 << 20;
+
+/// How many levels of alternates of alternates are followed, like git.
+pub const MAX_ALTERNATES_DEPTH: usize = 5;
+
+/// The zlib level of new loose objects, like git's default `core.looseCompression`.
+pub const DEFAULT_LOOSE_COMPRESSION: u32 = 1;
+
+/// The content of the loose object file of an object of type `obj_type` holding `data`.
+pub fn encode_loose_object(obj_type: ObjectType, data: &[u8], level: u32) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level));
+    let header = format!("{} {}\0", obj_type, data.len());
+    encoder
+        .write_all(header.as_bytes())
+        .and_then(|_| encoder.write_all(data))
+        .expect("zlib compress should never failed");
+    encoder.finish().expect("zlib compress should never failed")
+}
+
+/// The type and content of the object stored in the loose object file `file`.
+pub fn decode_loose_object(file: &[u8]) -> Result<(ObjectType, Vec<u8>), GitError> {
+    let mut decoder = ZlibDecoder::new(file);
+    let (obj_type, size) = read_header(&mut decoder)?;
+    let mut data = Vec::with_capacity(size.min(MAX_PREALLOCATION));
+    // One byte more than announced is enough to tell the content is too long
+    decoder
+        .take((size as u64).saturating_add(1))
+        .read_to_end(&mut data)
+        .map_err(|e| GitError::InvalidObjectInfo(format!("corrupt loose object: {e}")))?;
+    if data.len() > size {
+        return Err(GitError::InvalidObjectInfo(format!(
+            "loose object has more content than its header announces, {size} bytes"
+        )));
+    }
+    if data.len() < size {
+        return Err(GitError::InvalidObjectInfo(format!(
+            "loose object has {} bytes of content, its header announces {size}",
+            data.len()
+        )));
+    }
+    Ok((obj_type, data))
+}
+
+/// Read the `<type> <size>\0` header of an inflated loose object.
+fn read_header(reader: &mut impl Read) -> Result<(ObjectType, usize), GitError> {
+    let invalid = |reason: String| GitError::InvalidObjectInfo(reason);
+    let mut header = Vec::new();
+    let mut byte = [0u8];
+    while header.len() < MAX_HEADER_LEN {
+        reader
+            .read_exact(&mut byte)
+            .map_err(|e| invalid(format!("corrupt loose object header: {e}")))?;
+        if byte[0] == 0 {
+            let header = String::from_utf8_lossy(&header);
+            let (obj_type, size) = header
+                .split_once(' ')
+                .ok_or_else(|| invalid(format!("bad loose object header `{header}`")))?;
+            let size = size
+                .parse()
+                .map_err(|_| invalid(format!("bad loose object size `{size}`")))?;
+            return Ok((ObjectType::from_string(obj_type)?, size));
+        }
+        header.push(byte[0]);
+    }
+    Err(invalid("loose object header is too long".to_string()))
+}
+
+/// The loose objects of an `objects` directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LooseObjectStore {
+    objects_dir: PathBuf,
+    compression: u32,
+}
+
+impl LooseObjectStore {
+    /// The loose objects under `objects_dir`, e.g. `.git/objects`.
+    pub fn new(objects_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            objects_dir: objects_dir.into(),
+            compression: DEFAULT_LOOSE_COMPRESSION,
+        }
+    }
+
+    /// The loose objects of a repository, in the `objects` directory its worktrees share.
+    pub fn from_git_dir(git_dir: &GitDir) -> Self {
+        Self::new(git_dir.common_dir.join("objects"))
+    }
+
+    /// Set the zlib level (0-9) of the objects written from now on.
+    pub fn set_compression_level(&mut self, level: u32) {
+        self.compression = level.min(9);
+    }
+
+    pub fn objects_dir(&self) -> &Path {
+        &self.objects_dir
+    }
+
+    /// The path of the file of `id`, whether it exists or not.
+    pub fn path(&self, id: &SHA1) -> PathBuf {
+        let hex = id.to_string();
+        self.objects_dir.join(&hex[..2]).join(&hex[2..])
+    }
+
+    pub fn contains(&self, id: &SHA1) -> bool {
+        self.path(id).is_file()
+    }
+
+    /// Read the object `id`, checking that its content hashes to `id`.
+    pub fn read(&self, id: &SHA1) -> Result<(ObjectType, Vec<u8>), GitError> {
+        let (obj_type, data) = decode_loose_object(&self.read_file(id)?)?;
+        let actual = SHA1::from_type_and_data(obj_type, &data);
+        if actual != *id {
+            return Err(GitError::InvalidObjectInfo(format!(
+                "loose object {id} hashes to {actual}"
+            )));
+        }
+        Ok((obj_type, data))
+    }
+
+    /// Read the type and size of the object `id`, inflating its header only.
+    pub fn read_header(&self, id: &SHA1) -> Result<(ObjectType, usize), GitError> {
+        read_header(&mut ZlibDecoder::new(&self.read_file(id)?[..]))
+    }
+
+    fn read_file(&self, id: &SHA1) -> Result<Vec<u8>, GitError> {
+        fs::read(self.path(id)).map_err(|e| match e.kind() {
+            ErrorKind::NotFound => GitError::ObjectNotFound(id.to_string()),
+            _ => GitError::IOError(e),
+        })
+    }
+
+    /// Write an object of type `obj_type` holding `data`, returning its id.
+    ///
+    /// The file is written under a temporary name in its fan-out directory and renamed into
+    /// place, so readers never see a partial object; an object that already exists is left
+    /// untouched, like git does.
+    pub fn write(&self, obj_type: ObjectType, data: &[u8]) -> Result<SHA1, GitError> {
+        let id = SHA1::try_from_type_and_data(obj_type, data)?;
+        let path = self.path(&id);
+        if path.is_file() {
+            return Ok(id);
+        }
+        let dir = path
+            .parent()
+            .expect("loose objects are in a fan-out directory");
+        fs::create_dir_all(dir)?;
+        let temp = dir.join(format!("tmp_obj_{}", uuid::Uuid::new_v4().simple()));
+        fs::write(&temp, encode_loose_object(obj_type, data, self.compression))?;
+        if let Err(e) = fs::rename(&temp, &path) {
+            let _ = fs::remove_file(&temp);
+            return Err(e.into());
+        }
+        Ok(id)
+    }
+
+    /// Write a parsed object, see [`LooseObjectStore::write`].
+    pub fn write_object(&self, object: &impl ObjectTrait) -> Result<SHA1, GitError> {
+        self.write(object.get_type(), &object.to_data()?)
+    }
+
+    /// Delete the file of `id`, e.g. once a repack has packed it. Missing objects are ignored.
+    pub fn remove(&self, id: &SHA1) -> Result<(), GitError> {
+        match fs::remove_file(self.path(id)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The `objects` directories listed in `info/alternates`, and in turn their own alternates,
+    /// in search order.
+    ///
+    /// Each line of the file is a path, relative ones being relative to this objects directory;
+    /// blank lines and `#` comments are skipped. Like git, the chain is followed
+    /// [`MAX_ALTERNATES_DEPTH`] levels deep, directories that don't exist are skipped and each
+    /// directory is listed once.
+    pub fn alternates(&self) -> Result<Vec<PathBuf>, GitError> {
+        let mut dirs = Vec::new();
+        collect_alternates(&self.objects_dir, 0, &mut dirs)?;
+        let own = self
+            .objects_dir
+            .canonicalize()
+            .unwrap_or_else(|_| self.objects_dir.clone());
+        dirs.retain(|dir| *dir != own);
+        Ok(dirs)
+    }
+
+    /// The ids of every loose object, sorted. Other files, such as `pack/` and temporary files,
+    /// are skipped.
+    pub fn list(&self) -> Result<Vec<SHA1>, GitError> {
+        let mut ids = Vec::new();
+        let fanout = match fs::read_dir(&self.objects_dir) {
+            Ok(fanout) => fanout,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(ids),
+            Err(e) => return Err(e.into()),
+        };
+        for dir in fanout {
+            let dir = dir?;
+            let prefix = dir.file_name().to_string_lossy().into_owned();
+            if prefix.len() != 2 || !dir.file_type()?.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(dir.path())? {
+                let name = file?.file_name().to_string_lossy().into_owned();
+                if name.len() == 38
+                    && let Ok(id) = SHA1::from_str(&format!("{prefix}{name}"))
+                {
+                    ids.push(id);
+                }
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+}
+
+/// Append the alternates of `objects_dir`, found `depth` levels down the chain, to `dirs`.
+fn collect_alternates(
+    objects_dir: &Path,
+    depth: usize,
+    dirs: &mut Vec<PathBuf>,
+) -> Result<(), GitError> {
+    let content = match fs::read_to_string(objects_dir.join("info/alternates")) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for line in content.lines() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let dir = objects_dir.join(line);
+        let Ok(dir) = dir.canonicalize() else {
+            continue;
+        };
+        if !dir.is_dir() || dirs.contains(&dir) {
+            continue;
+        }
+        dirs.push(dir.clone());
+        if depth < MAX_ALTERNATES_DEPTH {
+            collect_alternates(&dir, depth + 1, dirs)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::object::blob::Blob;
+
+    #[test]
+    fn test_loose_object_codec() {
+        let blob = Blob::from_content("hello\n");
+        let file = encode_loose_object(ObjectType::Blob, &blob.data, 1);
+        let mut inflated = Vec::new();
+        ZlibDecoder::new(&file[..])
+            .read_to_end(&mut inflated)
+            .unwrap();
+        assert_eq!(inflated, b"blob 6\0hello\n");
+        assert_eq!(
+            decode_loose_object(&file).unwrap(),
+            (ObjectType::Blob, blob.data)
+        );
+
+        for (content, reason) in [
+            (&b"blob 7\0hello\n"[..], "announces 7"),
+            (b"blob 5\0hello\n", "more content"),
+            (b"blob 18446744073709551615\0hello\n", "announces 18446744073709551615"),
+            (b"blob six\0hello\n", "bad loose object size"),
+            (b"blob 6hello\n", "header"),
+        ] {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(content).unwrap();
+            let err = decode_loose_object(&encoder.finish().unwrap()).unwrap_err();
+            assert!(err.to_string().contains(reason), "{err}");
+        }
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"blub 6\0hello\n").unwrap();
+        assert!(matches!(
+            decode_loose_object(&encoder.finish().unwrap()),
+            Err(GitError::InvalidObjectType(_))
+        ));
+    }
+
+    #[test]
+    fn test_loose_object_store() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        let store = LooseObjectStore::new(root.join("objects"));
+        assert_eq!(store.list().unwrap(), vec![]);
+
+        let blob = Blob::from_content("hello\n");
+        let id = store.write_object(&blob).unwrap();
+        assert_eq!(id, blob.id);
+        assert_eq!(
+            store.path(&id),
+            root.join("objects/ce/013625030ba8dba906f756967f9e9ca394464a")
+        );
+        assert!(store.contains(&id));
+        assert_eq!(
+            store.read(&id).unwrap(),
+            (ObjectType::Blob, blob.data.clone())
+        );
+        assert_eq!(store.read_header(&id).unwrap(), (ObjectType::Blob, 6));
+        // Writing it again keeps the file
+        assert_eq!(store.write(ObjectType::Blob, &blob.data).unwrap(), id);
+
+        let other = store.write(ObjectType::Blob, b"other").unwrap();
+        fs::write(root.join("objects/ce/tmp_obj_partial"), b"").unwrap();
+        fs::create_dir_all(root.join("objects/pack")).unwrap();
+        let mut ids = vec![id, other];
+        ids.sort();
+        assert_eq!(store.list().unwrap(), ids);
+
+        // A file whose content is another object is corrupt
+        fs::copy(store.path(&other), store.path(&id)).unwrap();
+        let err = store.read(&id).unwrap_err();
+        assert!(err.to_string().contains("hashes to"), "{err}");
+
+        store.remove(&id).unwrap();
+        store.remove(&id).unwrap();
+        assert!(matches!(store.read(&id), Err(GitError::ObjectNotFound(_))));
+        assert_eq!(store.list().unwrap(), vec![other]);
+    }
+
+    #[test]
+    fn test_loose_object_store_alternates() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        let fork = root.join("fork/objects");
+        let pool = root.join("pool/objects");
+        let base = root.join("base/objects");
+        for dir in [&fork, &pool, &base] {
+            fs::create_dir_all(dir.join("info")).unwrap();
+        }
+        let store = LooseObjectStore::new(&fork);
+        assert_eq!(store.alternates().unwrap(), Vec::<PathBuf>::new());
+
+        fs::write(
+            fork.join("info/alternates"),
+            format!(
+                "# shared history\n../../pool/objects\n\n{}\n../../missing/objects\n",
+                base.display()
+            ),
+        )
+        .unwrap();
+        // The pool borrows from the base too, and from the fork, which are skipped
+        fs::write(
+            pool.join("info/alternates"),
+            "../../base/objects\n../../fork/objects\n",
+        )
+        .unwrap();
+        assert_eq!(
+            store.alternates().unwrap(),
+            vec![pool.canonicalize().unwrap(), base.canonicalize().unwrap()]
+        );
+    }
+}
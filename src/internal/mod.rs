@@ -1,4 +1,9 @@
+pub(crate) mod chunk_format;
+pub mod commit_graph;
 pub mod index;
+pub mod loose;
 pub mod object;
 pub mod pack;
+pub mod refs;
+pub mod worktree;
 pub mod zlib;
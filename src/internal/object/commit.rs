@@ -19,6 +19,7 @@ use crate::hash::SHA1;
 use crate::internal::object::ObjectTrait;
 use crate::internal::object::ObjectType;
 use crate::internal::object::signature::Signature;
+use crate::internal::object::signing::strip_signature_headers;
 use bincode::{Decode, Encode};
 use bstr::ByteSlice;
 use serde::Deserialize;
@@ -34,6 +35,8 @@ use serde::Serialize;
 ///   history of a repository with a single commit object at its root.
 /// - The author and committer fields contain the name, email address, timestamp and timezone.
 /// - The message field contains the commit message, which maybe include signed or DCO.
+/// - Extra headers after the committer (`gpgsig`, `mergetag`, `encoding`, ...) are kept in order at
+///   the start of the message, see [`Commit::extra_headers`].
 #[derive(Eq, Debug, Clone, Serialize, Deserialize, Decode, Encode)]
 pub struct Commit {
     pub id: SHA1,
@@ -117,6 +120,88 @@ impl Commit {
         Commit::new(author, committer, tree_id, parent_commit_ids, message)
     }
 
+    /// The `gpgsig` header of a signed commit, without the header name and with continuation
+    /// lines unfolded, i.e. the armored signature exactly as `git verify-commit` passes it on.
+    pub fn gpg_signature(&self) -> Option<String> {
+        self.extra_header("gpgsig")
+            .map(|signature| signature + "\n")
+    }
+
+    /// The `gpgsig-sha256` header, present on commits signed for a SHA-256 repository.
+    pub fn gpg_signature_sha256(&self) -> Option<String> {
+        self.extra_header("gpgsig-sha256")
+            .map(|signature| signature + "\n")
+    }
+
+    /// The bytes covered by the signature: the serialized commit with every `gpgsig` and
+    /// `gpgsig-sha256` header removed.
+    pub fn signed_content(&self) -> Result<Vec<u8>, GitError> {
+        Ok(strip_signature_headers(&self.to_data()?))
+    }
+
+    /// Headers after `committer` (`gpgsig`, `encoding`, `mergetag`, ...), in order.
+    ///
+    /// They are kept at the start of `message` so the commit re-serializes byte-for-byte;
+    /// values are returned with continuation lines unfolded and without the final newline.
+    pub fn extra_headers(&self) -> Vec<(String, String)> {
+        let mut headers: Vec<(String, String)> = Vec::new();
+        for line in self.message.split_inclusive('\n') {
+            if line == "\n" {
+                break;
+            }
+            if let Some(continuation) = line.strip_prefix(' ') {
+                if let Some((_, value)) = headers.last_mut() {
+                    value.push('\n');
+                    value.push_str(continuation.strip_suffix('\n').unwrap_or(continuation));
+                }
+                continue;
+            }
+            let line = line.strip_suffix('\n').unwrap_or(line);
+            let (name, value) = line.split_once(' ').unwrap_or((line, ""));
+            headers.push((name.to_string(), value.to_string()));
+        }
+        headers
+    }
+
+    /// The value of the first extra header called `name`, see [`Commit::extra_headers`].
+    pub fn extra_header(&self, name: &str) -> Option<String> {
+        self.extra_headers()
+            .into_iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value)
+    }
+
+    /// The commit message without the extra headers, see [`Commit::extra_headers`].
+    pub fn message_body(&self) -> &str {
+        match self.message.strip_prefix('\n') {
+            Some(body) => body,
+            None => self.message.split_once("\n\n").map_or("", |(_, body)| body),
+        }
+    }
+
+    /// Creates a commit carrying extra headers after `committer`, e.g. the `mergetag` git adds
+    /// when merging a signed tag, or `encoding`. Multi-line values are folded the way git does.
+    /// `message` is the commit message body.
+    pub fn with_extra_headers(
+        author: Signature,
+        committer: Signature,
+        tree_id: SHA1,
+        parent_commit_ids: Vec<SHA1>,
+        extra_headers: &[(String, String)],
+        message: &str,
+    ) -> Commit {
+        let mut raw_message = String::new();
+        for (name, value) in extra_headers {
+            raw_message.push_str(name);
+            raw_message.push(' ');
+            raw_message.push_str(&value.replace('\n', "\n "));
+            raw_message.push('\n');
+        }
+        raw_message.push('\n');
+        raw_message.push_str(message);
+        Commit::new(author, committer, tree_id, parent_commit_ids, &raw_message)
+    }
+
     /// Formats the commit message by extracting the first meaningful line.
     ///
     /// If the message contains a PGP signature, it returns the first non-empty line
@@ -249,6 +334,7 @@ impl ObjectTrait for Commit {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::internal::pack::entry::Entry;
     use std::str::FromStr;
 
     fn basic_commit() -> Commit {
@@ -305,6 +391,111 @@ test parse commit from bytes
         assert!(commit.message.contains("test parse commit from bytes"));
     }
 
+    #[test]
+    fn test_signed_commit_round_trip() {
+        // `git hash-object -t commit` of a commit signed for both SHA-1 and SHA-256
+        let raw = b"tree 341e54913a3a43069f2927cc0f703e5a9f730df1
+author tester <tester@example.com> 1700000000 +0800
+committer tester <tester@example.com> 1700000000 +0800
+gpgsig -----BEGIN PGP SIGNATURE-----
+ \n iQEzBAABCAAdFiEE
+ =UeLf
+ -----END PGP SIGNATURE-----
+gpgsig-sha256 -----BEGIN SSH SIGNATURE-----
+ c2hhMjU2
+ -----END SSH SIGNATURE-----
+encoding UTF-8
+
+body
+";
+        let id = SHA1::from_str("2ac1ab4b3d2306c2792b80c4c6ced70c3da264c8").unwrap();
+        let commit = Commit::from_bytes(raw, id).unwrap();
+        let data = commit.to_data().unwrap();
+        assert_eq!(data, raw.to_vec());
+        assert_eq!(SHA1::from_type_and_data(ObjectType::Commit, &data), id);
+
+        assert_eq!(
+            commit.gpg_signature().unwrap(),
+            "-----BEGIN PGP SIGNATURE-----\n\niQEzBAABCAAdFiEE\n=UeLf\n-----END PGP SIGNATURE-----\n"
+        );
+        assert_eq!(
+            commit.gpg_signature_sha256().unwrap(),
+            "-----BEGIN SSH SIGNATURE-----\nc2hhMjU2\n-----END SSH SIGNATURE-----\n"
+        );
+        assert_eq!(
+            commit.signed_content().unwrap(),
+            b"tree 341e54913a3a43069f2927cc0f703e5a9f730df1
+author tester <tester@example.com> 1700000000 +0800
+committer tester <tester@example.com> 1700000000 +0800
+encoding UTF-8
+
+body
+"
+        );
+
+        let unsigned = Commit::from_bytes(&commit.signed_content().unwrap(), id).unwrap();
+        assert!(unsigned.gpg_signature().is_none());
+        assert_eq!(
+            unsigned.signed_content().unwrap(),
+            unsigned.to_data().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_commit_with_mergetag() {
+        // `git hash-object -t commit` of a merge of a signed tag
+        let raw = b"tree 341e54913a3a43069f2927cc0f703e5a9f730df1
+parent 1111111111111111111111111111111111111111
+parent 2222222222222222222222222222222222222222
+author tester <tester@example.com> 1700000000 +0800
+committer tester <tester@example.com> 1700000000 +0800
+mergetag object 2222222222222222222222222222222222222222
+ type commit
+ tag v1.0
+ tagger tester <tester@example.com> 1700000000 +0800
+ \n release 1.0
+ -----BEGIN PGP SIGNATURE-----
+ \n iQEzBAABCAAdFiEE
+ -----END PGP SIGNATURE-----
+encoding ISO-8859-1
+
+Merge tag 'v1.0'
+";
+        let id = SHA1::from_str("49d14cec1b83c5562eca44d6da5ce3946b8513c7").unwrap();
+        let commit = Commit::from_bytes(raw, id).unwrap();
+        assert_eq!(commit.parent_commit_ids.len(), 2);
+        assert_eq!(commit.to_data().unwrap(), raw.to_vec());
+
+        let headers = commit.extra_headers();
+        let mergetag = "object 2222222222222222222222222222222222222222\ntype commit\ntag v1.0\n\
+                        tagger tester <tester@example.com> 1700000000 +0800\n\nrelease 1.0\n\
+                        -----BEGIN PGP SIGNATURE-----\n\niQEzBAABCAAdFiEE\n-----END PGP SIGNATURE-----";
+        assert_eq!(
+            headers,
+            vec![
+                ("mergetag".to_string(), mergetag.to_string()),
+                ("encoding".to_string(), "ISO-8859-1".to_string()),
+            ]
+        );
+        assert_eq!(
+            commit.extra_header("encoding").as_deref(),
+            Some("ISO-8859-1")
+        );
+        assert!(commit.gpg_signature().is_none());
+        assert_eq!(commit.message_body(), "Merge tag 'v1.0'\n");
+
+        let rebuilt = Commit::with_extra_headers(
+            commit.author.clone(),
+            commit.committer.clone(),
+            commit.tree_id,
+            commit.parent_commit_ids.clone(),
+            &headers,
+            "Merge tag 'v1.0'\n",
+        );
+        assert_eq!(rebuilt.id, id);
+        assert_eq!(Entry::from(rebuilt).hash, id);
+    }
+
     #[test]
     fn test_format_message_with_pgp_signature() {
         let commit = basic_commit();
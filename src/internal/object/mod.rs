@@ -2,6 +2,7 @@ pub mod blob;
 pub mod commit;
 pub mod note;
 pub mod signature;
+pub mod signing;
 pub mod tag;
 pub mod tree;
 pub mod types;
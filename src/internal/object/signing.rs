@@ -0,0 +1,373 @@
+//! Signatures of signed commits and annotated tags.
+//!
+//! Git stores a commit signature in a `gpgsig` header whose continuation lines start with a
+//! space, and a tag signature as an armored block appended to the tag message. In both cases the
+//! signed payload is the object data with the signature removed, which is what `git verify-commit`
+//! and `git verify-tag` hand to `gpg`, `ssh-keygen -Y verify` or `gpgsm`.
+//!
+//! Objects can also carry a signature of their SHA-256 form in a `gpgsig-sha256` header; the
+//! `*_data` constructors select a signature by [`HashAlgorithm`] and work on raw object data, so
+//! external verification services can check either without going through the object types.
+//!
+//! This module only extracts the signature and payload; checking them against a keyring is left
+//! to a host provided [`SignatureVerifier`], so forges can plug in their own key storage.
+use async_trait::async_trait;
+use bstr::ByteSlice;
+
+use crate::errors::GitError;
+use crate::internal::object::ObjectTrait;
+use crate::internal::object::commit::Commit;
+use crate::internal::object::signature::Signature;
+use crate::internal::object::tag::Tag;
+
+/// Armor begin markers and the format they denote, as recognised by git.
+const SIGNATURE_MARKERS: [(&str, SignatureFormat); 3] = [
+    ("-----BEGIN PGP SIGNATURE-----", SignatureFormat::Gpg),
+    ("-----BEGIN SSH SIGNATURE-----", SignatureFormat::Ssh),
+    ("-----BEGIN SIGNED MESSAGE-----", SignatureFormat::X509),
+];
+
+/// The kind of signature, mirroring git's `gpg.format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureFormat {
+    Gpg,
+    Ssh,
+    X509,
+}
+
+impl SignatureFormat {
+    /// Detect the format from the first line of an armored signature.
+    pub fn from_armor(signature: &str) -> Option<Self> {
+        SIGNATURE_MARKERS
+            .iter()
+            .find(|(marker, _)| signature.starts_with(marker))
+            .map(|(_, format)| *format)
+    }
+}
+
+/// A signature block and the exact bytes it signs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectSignature {
+    pub format: SignatureFormat,
+    /// The armored signature, including the `-----BEGIN`/`-----END` lines.
+    pub signature: String,
+    /// The object data with the signature removed.
+    pub payload: Vec<u8>,
+}
+
+/// Hash algorithm a signature was made for.
+///
+/// Objects written with `git -c extensions.compatObjectFormat` carry one signature per object
+/// format: the one of the repository's own format is stored like in any other repository, the
+/// other one in a `gpgsig` / `gpgsig-sha256` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// The header holding a signature made for this algorithm.
+    pub fn signature_header(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha1 => "gpgsig",
+            HashAlgorithm::Sha256 => "gpgsig-sha256",
+        }
+    }
+}
+
+impl ObjectSignature {
+    /// Extract the `gpgsig` header of a commit, `None` if the commit is not signed.
+    pub fn from_commit(commit: &Commit) -> Result<Option<Self>, GitError> {
+        Ok(Self::from_commit_data(
+            &commit.to_data()?,
+            HashAlgorithm::Sha1,
+        ))
+    }
+
+    /// Extract the signature appended to a tag message, `None` if the tag is not signed.
+    pub fn from_tag(tag: &Tag) -> Result<Option<Self>, GitError> {
+        Ok(Self::from_tag_data(&tag.to_data()?, HashAlgorithm::Sha1))
+    }
+
+    /// Extract the signature made for `algorithm` from raw commit data, as stored in the object
+    /// database.
+    ///
+    /// The payload is the commit with every signature header removed, for both algorithms.
+    pub fn from_commit_data(data: &[u8], algorithm: HashAlgorithm) -> Option<Self> {
+        let signature = find_header(data, algorithm.signature_header())? + "\n";
+        Some(Self {
+            format: SignatureFormat::from_armor(&signature)?,
+            signature,
+            payload: strip_signature_headers(data),
+        })
+    }
+
+    /// Extract the signature made for `algorithm` from raw tag data, as stored in the object
+    /// database.
+    ///
+    /// The SHA-1 signature is the armored block appended to the message; like git, the last line
+    /// starting with a known armor marker begins it. The SHA-256 signature of a tag in a SHA-1
+    /// repository is a `gpgsig-sha256` header. Either way, the payload is the tag with the
+    /// appended signature and every signature header removed.
+    ///
+    /// The payload is in this crate's (SHA-1) object format; a SHA-256 signature covers the
+    /// same object with its object ids converted, which is up to the caller.
+    pub fn from_tag_data(data: &[u8], algorithm: HashAlgorithm) -> Option<Self> {
+        let message_start = data.find(b"\n\n").map(|pos| pos + 2).unwrap_or(data.len());
+
+        let mut appended = None;
+        let mut pos = message_start;
+        for line in data[message_start..].lines_with_terminator() {
+            if let Ok(text) = line.to_str()
+                && let Some(format) = SignatureFormat::from_armor(text)
+            {
+                appended = Some((pos, format));
+            }
+            pos += line.len();
+        }
+
+        let signed_end = appended.map(|(start, _)| start).unwrap_or(data.len());
+        let payload = strip_signature_headers(&data[..signed_end]);
+        match algorithm {
+            HashAlgorithm::Sha1 => appended.map(|(start, format)| Self {
+                format,
+                signature: String::from_utf8_lossy(&data[start..]).to_string(),
+                payload,
+            }),
+            HashAlgorithm::Sha256 => {
+                let signature = find_header(data, algorithm.signature_header())? + "\n";
+                Some(Self {
+                    format: SignatureFormat::from_armor(&signature)?,
+                    signature,
+                    payload,
+                })
+            }
+        }
+    }
+}
+
+/// The value of header `name` in object `data`, with continuation lines unfolded.
+fn find_header(data: &[u8], name: &str) -> Option<String> {
+    let headers_end = data.find(b"\n\n").unwrap_or(data.len());
+    let mut value: Option<Vec<u8>> = None;
+    for line in data[..headers_end].lines() {
+        match value.as_mut() {
+            Some(value) if line.starts_with(b" ") => {
+                value.push(b'\n');
+                value.extend_from_slice(&line[1..]);
+            }
+            Some(_) => break,
+            None => {
+                if let Some(rest) = line.strip_prefix(name.as_bytes())
+                    && let Some(rest) = rest.strip_prefix(b" ")
+                {
+                    value = Some(rest.to_vec());
+                }
+            }
+        }
+    }
+    value.map(|value| String::from_utf8_lossy(&value).to_string())
+}
+
+/// Object `data` with every `gpgsig` and `gpgsig-sha256` header removed, as git does before
+/// handing a payload to the signing program.
+pub(crate) fn strip_signature_headers(data: &[u8]) -> Vec<u8> {
+    let headers_end = data.find(b"\n\n").map(|pos| pos + 1).unwrap_or(data.len());
+    let is_signature = |line: &[u8]| {
+        [HashAlgorithm::Sha1, HashAlgorithm::Sha256]
+            .iter()
+            .any(|algorithm| {
+                line.strip_prefix(algorithm.signature_header().as_bytes())
+                    .is_some_and(|rest| rest.starts_with(b" "))
+            })
+    };
+
+    let mut content = Vec::with_capacity(data.len());
+    let mut in_signature = false;
+    for line in data[..headers_end].lines_with_terminator() {
+        if in_signature && line.starts_with(b" ") {
+            continue;
+        }
+        in_signature = is_signature(line);
+        if !in_signature {
+            content.extend_from_slice(line);
+        }
+    }
+    content.extend_from_slice(&data[headers_end..]);
+    content
+}
+
+/// Outcome of checking an [`ObjectSignature`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verification {
+    /// The signature is valid and made by a key trusted for the signer.
+    Good { key_id: String },
+    /// The signature does not match the payload, or the key is not allowed for the signer.
+    Bad(String),
+    /// The signing key is not known to the verifier.
+    UnknownKey(String),
+}
+
+impl Verification {
+    pub fn is_verified(&self) -> bool {
+        matches!(self, Verification::Good { .. })
+    }
+}
+
+/// Pluggable signature checking, implemented by the host on top of its key storage.
+#[async_trait]
+pub trait SignatureVerifier: Send + Sync {
+    /// Check `signature` made by `signer` (the committer or tagger of the object).
+    async fn verify(
+        &self,
+        signature: &ObjectSignature,
+        signer: &Signature,
+    ) -> Result<Verification, GitError>;
+
+    /// Verify a commit, `None` if it is not signed.
+    async fn verify_commit(&self, commit: &Commit) -> Result<Option<Verification>, GitError> {
+        match ObjectSignature::from_commit(commit)? {
+            Some(signature) => Ok(Some(self.verify(&signature, &commit.committer).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Verify an annotated tag, `None` if it is not signed.
+    async fn verify_tag(&self, tag: &Tag) -> Result<Option<Verification>, GitError> {
+        match ObjectSignature::from_tag(tag)? {
+            Some(signature) => Ok(Some(self.verify(&signature, &tag.tagger).await?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::hash::SHA1;
+    use crate::internal::object::types::ObjectType;
+
+    const SIGNED_COMMIT: &[u8] = b"tree 341e54913a3a43069f2927cc0f703e5a9f730df1
+author tester <tester@example.com> 1700000000 +0800
+committer tester <tester@example.com> 1700000000 +0800
+gpgsig -----BEGIN SSH SIGNATURE-----
+ U1NIU0lHAAAAAQ==
+ -----END SSH SIGNATURE-----
+
+signed commit
+";
+
+    #[test]
+    fn test_commit_signature() {
+        let commit = Commit::from_bytes(SIGNED_COMMIT, SHA1::default()).unwrap();
+        let signature = ObjectSignature::from_commit(&commit).unwrap().unwrap();
+        assert_eq!(signature.format, SignatureFormat::Ssh);
+        assert_eq!(
+            signature.signature,
+            "-----BEGIN SSH SIGNATURE-----\nU1NIU0lHAAAAAQ==\n-----END SSH SIGNATURE-----\n"
+        );
+        assert_eq!(
+            signature.payload,
+            b"tree 341e54913a3a43069f2927cc0f703e5a9f730df1
+author tester <tester@example.com> 1700000000 +0800
+committer tester <tester@example.com> 1700000000 +0800
+
+signed commit
+"
+        );
+
+        let unsigned = Commit::from_bytes(&signature.payload, SHA1::default()).unwrap();
+        assert!(ObjectSignature::from_commit(&unsigned).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_tag_signature() {
+        let tagger =
+            Signature::from_data(b"tagger tester <tester@example.com> 1700000000 +0800".to_vec())
+                .unwrap();
+        let armored =
+            "-----BEGIN PGP SIGNATURE-----\n\niQEzBAABCAAdFiEE\n-----END PGP SIGNATURE-----\n";
+        let tag = Tag::new(
+            SHA1::from_str("dbc33f0dd12390c30e49b706e562d9116807c43f").unwrap(),
+            ObjectType::Commit,
+            "v1.0".to_string(),
+            tagger,
+            format!("release 1.0\n{armored}"),
+        );
+        let signature = ObjectSignature::from_tag(&tag).unwrap().unwrap();
+        assert_eq!(signature.format, SignatureFormat::Gpg);
+        assert_eq!(signature.signature, armored);
+        assert!(signature.payload.ends_with(b"\n\nrelease 1.0\n"));
+
+        let mut unsigned = tag.clone();
+        unsigned.message = "release 1.0\n".to_string();
+        assert!(ObjectSignature::from_tag(&unsigned).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_commit_signature_sha256() {
+        let data = b"tree 341e54913a3a43069f2927cc0f703e5a9f730df1
+author tester <tester@example.com> 1700000000 +0800
+committer tester <tester@example.com> 1700000000 +0800
+gpgsig -----BEGIN PGP SIGNATURE-----
+ 
+ iQEzBAABCAAdFiEE
+ -----END PGP SIGNATURE-----
+gpgsig-sha256 -----BEGIN SSH SIGNATURE-----
+ U1NIU0lHAAAAAQ==
+ -----END SSH SIGNATURE-----
+
+dual signed commit
+";
+        let sha1 = ObjectSignature::from_commit_data(data, HashAlgorithm::Sha1).unwrap();
+        let sha256 = ObjectSignature::from_commit_data(data, HashAlgorithm::Sha256).unwrap();
+        assert_eq!(sha1.format, SignatureFormat::Gpg);
+        assert_eq!(
+            sha1.signature,
+            "-----BEGIN PGP SIGNATURE-----\n\niQEzBAABCAAdFiEE\n-----END PGP SIGNATURE-----\n"
+        );
+        assert_eq!(sha256.format, SignatureFormat::Ssh);
+        assert_eq!(sha1.payload, sha256.payload);
+        assert!(sha1.payload.ends_with(b"+0800\n\ndual signed commit\n"));
+        assert!(!sha1.payload.contains_str("gpgsig"));
+    }
+
+    #[test]
+    fn test_tag_signature_sha256() {
+        let data = b"object dbc33f0dd12390c30e49b706e562d9116807c43f
+type commit
+tag v1.0
+tagger tester <tester@example.com> 1700000000 +0800
+gpgsig-sha256 -----BEGIN SSH SIGNATURE-----
+ U1NIU0lHAAAAAQ==
+ -----END SSH SIGNATURE-----
+
+release 1.0
+-----BEGIN PGP SIGNATURE-----
+
+iQEzBAABCAAdFiEE
+-----END PGP SIGNATURE-----
+";
+        let payload = b"object dbc33f0dd12390c30e49b706e562d9116807c43f
+type commit
+tag v1.0
+tagger tester <tester@example.com> 1700000000 +0800
+
+release 1.0
+";
+        let sha1 = ObjectSignature::from_tag_data(data, HashAlgorithm::Sha1).unwrap();
+        assert_eq!(sha1.format, SignatureFormat::Gpg);
+        assert_eq!(sha1.payload, payload);
+
+        let sha256 = ObjectSignature::from_tag_data(data, HashAlgorithm::Sha256).unwrap();
+        assert_eq!(sha256.format, SignatureFormat::Ssh);
+        assert_eq!(
+            sha256.signature,
+            "-----BEGIN SSH SIGNATURE-----\nU1NIU0lHAAAAAQ==\n-----END SSH SIGNATURE-----\n"
+        );
+        assert_eq!(sha256.payload, payload);
+    }
+}
@@ -40,6 +40,7 @@ use std::fmt::Display;
 use std::str::FromStr;
 
 use bstr::ByteSlice;
+use serde::{Deserialize, Serialize};
 
 use crate::errors::GitError;
 use crate::hash::SHA1;
@@ -48,7 +49,7 @@ use crate::internal::object::ObjectType;
 use crate::internal::object::signature::Signature;
 
 /// The tag object is used to Annotated tag
-#[derive(Eq, Debug, Clone)]
+#[derive(Eq, Debug, Clone, Serialize, Deserialize)]
 pub struct Tag {
     pub id: SHA1,
     pub object_hash: SHA1,
@@ -91,21 +92,17 @@ impl Tag {
         tagger: Signature,
         message: String,
     ) -> Self {
-        // Serialize the tag data to calculate its hash
-        let data = format!(
-            "object {}\ntype {}\ntag {}\ntagger {}\n\n{}",
-            object_hash, object_type, tag_name, tagger, message
-        );
-        let id = SHA1::from_type_and_data(ObjectType::Tag, data.as_bytes());
-
-        Self {
-            id,
+        let mut tag = Self {
+            id: SHA1::default(),
             object_hash,
             object_type,
             tag_name,
             tagger,
             message,
-        }
+        };
+        // The hash is calculated from the serialized tag object, the same bytes `to_data` produces
+        tag.id = SHA1::from_type_and_data(ObjectType::Tag, &tag.to_data().unwrap());
+        tag
     }
 }
 
@@ -218,3 +215,33 @@ impl ObjectTrait for Tag {
         Ok(data)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag_matches_git() {
+        // `git tag -a v1.0 -m "release 1.0"` on a commit made at 1700000000 +0800
+        let tagger =
+            Signature::from_data(b"tagger tester <tester@example.com> 1700000000 +0800".to_vec())
+                .unwrap();
+        let tag = Tag::new(
+            SHA1::from_str("dbc33f0dd12390c30e49b706e562d9116807c43f").unwrap(),
+            ObjectType::Commit,
+            "v1.0".to_string(),
+            tagger,
+            "release 1.0\n".to_string(),
+        );
+        assert_eq!(
+            tag.id.to_string(),
+            "89c0428080c23c7c13d800c84f1f35001e25d330"
+        );
+
+        let parsed = Tag::from_bytes(&tag.to_data().unwrap(), tag.id).unwrap();
+        assert_eq!(parsed.tag_name, "v1.0");
+        assert_eq!(parsed.object_type, ObjectType::Commit);
+        assert_eq!(parsed.message, "release 1.0\n");
+        assert_eq!(parsed.to_data().unwrap(), tag.to_data().unwrap());
+    }
+}
@@ -23,6 +23,8 @@ use colored::Colorize;
 use encoding_rs::GBK;
 use serde::Deserialize;
 use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::HashSet;
 use std::fmt::Display;
 
 /// In Git, the mode field in a tree object's entry specifies the type of the object represented by
@@ -221,6 +223,27 @@ impl TreeItem {
     pub fn is_blob(&self) -> bool {
         self.mode == TreeItemMode::Blob
     }
+
+    /// Git's canonical order of tree entries: by name bytes, with directory names compared as if
+    /// they ended with `/` (so `foo.txt` sorts before the directory `foo`, which sorts before
+    /// `foo0`).
+    pub fn canonical_cmp(&self, other: &Self) -> Ordering {
+        let (a, b) = (self.name.as_bytes(), other.name.as_bytes());
+        let common = a.len().min(b.len());
+        a[..common].cmp(&b[..common]).then_with(|| {
+            let terminator = |item: &Self, name: &[u8]| match name.get(common) {
+                Some(&byte) => byte,
+                None if item.is_tree() => b'/',
+                None => 0,
+            };
+            terminator(self, a).cmp(&terminator(other, b))
+        })
+    }
+
+    /// Whether the entry is a gitlink, a submodule pinned to a commit of another repository.
+    pub fn is_submodule(&self) -> bool {
+        self.mode == TreeItemMode::Commit
+    }
 }
 
 /// A tree object is a Git object that represents a directory. It contains a list of entries, one
@@ -247,8 +270,19 @@ impl Display for Tree {
     }
 }
 
+/// Entries reordered by [`Tree::from_bytes_lenient`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeFixup {
+    /// Id of the tree as stored, which no longer matches the reordered tree.
+    pub original_id: SHA1,
+    /// Names of the entries that were out of order, in stored order.
+    pub out_of_order: Vec<String>,
+}
+
 impl Tree {
-    pub fn from_tree_items(tree_items: Vec<TreeItem>) -> Result<Self, GitError> {
+    /// Build a tree from its entries, sorted into git's canonical order (see
+    /// [`TreeItem::canonical_cmp`]). Fails on an empty list or duplicate names.
+    pub fn from_tree_items(mut tree_items: Vec<TreeItem>) -> Result<Self, GitError> {
         if tree_items.is_empty() {
             return Err(GitError::EmptyTreeItems(
                 "When export tree object to meta, the items is empty"
@@ -256,6 +290,15 @@ impl Tree {
                     .unwrap(),
             ));
         }
+        tree_items.sort_by(TreeItem::canonical_cmp);
+        // A blob and a tree of the same name don't sort next to each other, e.g. around `a.txt`
+        let mut names = HashSet::new();
+        if let Some(item) = tree_items.iter().find(|item| !names.insert(&item.name)) {
+            return Err(GitError::InvalidTreeOrder(format!(
+                "duplicate entry `{}`",
+                item.name
+            )));
+        }
         let mut data = Vec::new();
         for item in &tree_items {
             data.extend_from_slice(item.to_data().as_slice());
@@ -267,6 +310,47 @@ impl Tree {
         })
     }
 
+    /// Parse a tree whose entries may be out of canonical order, as written by some old or buggy
+    /// tools. `from_bytes` keeps them as stored, here such a tree is put back in order and
+    /// rehashed, and the fix-up is reported; a well-formed tree is returned as-is with `None`.
+    pub fn from_bytes_lenient(
+        data: &[u8],
+        hash: SHA1,
+    ) -> Result<(Self, Option<TreeFixup>), GitError> {
+        let mut tree = Tree {
+            id: hash,
+            tree_items: parse_tree_items(data)?,
+        };
+        let out_of_order: Vec<String> = tree
+            .tree_items
+            .iter()
+            .enumerate()
+            .filter(|(i, item)| {
+                let after_previous = i
+                    .checked_sub(1)
+                    .is_none_or(|prev| tree.tree_items[prev].canonical_cmp(item).is_lt());
+                let before_next = tree
+                    .tree_items
+                    .get(i + 1)
+                    .is_none_or(|next| item.canonical_cmp(next).is_lt());
+                !(after_previous && before_next)
+            })
+            .map(|(_, item)| item.name.clone())
+            .collect();
+        if out_of_order.is_empty() {
+            return Ok((tree, None));
+        }
+        tree.tree_items.sort_by(TreeItem::canonical_cmp);
+        tree.rehash();
+        Ok((
+            tree,
+            Some(TreeFixup {
+                original_id: hash,
+                out_of_order,
+            }),
+        ))
+    }
+
     /// After the subdirectory is changed, the hash value of the tree is recalculated.
     pub fn rehash(&mut self) {
         let mut data = Vec::new();
@@ -284,32 +368,41 @@ impl TryFrom<&[u8]> for Tree {
         Tree::from_bytes(data, h)
     }
 }
-impl ObjectTrait for Tree {
-    fn from_bytes(data: &[u8], hash: SHA1) -> Result<Self, GitError>
-    where
-        Self: Sized,
-    {
-        let mut tree_items = Vec::new();
-        let mut i = 0;
-        while i < data.len() {
-            // Find the position of the null byte (0x00)
-            if let Some(index) = memchr::memchr(0x00, &data[i..]) {
-                // Calculate the next position
-                let next = i + index + 21;
+/// The entries of a tree object, in stored order.
+fn parse_tree_items(data: &[u8]) -> Result<Vec<TreeItem>, GitError> {
+    let mut tree_items = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        // Find the position of the null byte (0x00)
+        if let Some(index) = memchr::memchr(0x00, &data[i..]) {
+            // Calculate the next position
+            let next = i + index + 21;
+            if next > data.len() {
+                return Err(GitError::InvalidTreeObject);
+            }
 
-                // Extract the bytes and create a TreeItem
-                let item_data = &data[i..next];
-                let tree_item = TreeItem::from_bytes(item_data)?;
+            // Extract the bytes and create a TreeItem
+            let item_data = &data[i..next];
+            let tree_item = TreeItem::from_bytes(item_data)?;
 
-                tree_items.push(tree_item);
+            tree_items.push(tree_item);
 
-                i = next;
-            } else {
-                // If no null byte is found, return an error
-                return Err(GitError::InvalidTreeObject);
-            }
+            i = next;
+        } else {
+            // If no null byte is found, return an error
+            return Err(GitError::InvalidTreeObject);
         }
+    }
+    Ok(tree_items)
+}
 
+impl ObjectTrait for Tree {
+    fn from_bytes(data: &[u8], hash: SHA1) -> Result<Self, GitError>
+    where
+        Self: Sized,
+    {
+        // Kept in stored order, misordered trees of old tools are reported by fsck
+        let tree_items = parse_tree_items(data)?;
         Ok(Tree {
             id: hash,
             tree_items,
@@ -341,7 +434,10 @@ mod tests {
 
     use std::str::FromStr;
 
+    use crate::errors::GitError;
     use crate::hash::SHA1;
+    use crate::internal::object::ObjectTrait;
+    use crate::internal::object::ObjectType;
     use crate::internal::object::tree::{Tree, TreeItem, TreeItemMode};
 
     #[test]
@@ -393,6 +489,82 @@ mod tests {
         assert_eq!(tree_item.id.to_string(), item.id.to_string());
     }
 
+    #[test]
+    fn test_tree_with_submodule_roundtrip() {
+        let commit = SHA1::from_str("8ab686eafeb1f44702738c8b0f24f2567c36da6d").unwrap();
+        let mut data = b"100644 README\0".to_vec();
+        data.extend_from_slice(&[0x11; 20]);
+        data.extend_from_slice(b"160000 vendor\0");
+        data.extend_from_slice(&commit.0);
+
+        let tree = Tree::try_from(data.as_slice()).unwrap();
+        assert!(tree.tree_items[1].is_submodule());
+        assert_eq!(tree.tree_items[1].id, commit);
+        assert_eq!(tree.to_data().unwrap(), data);
+        assert_eq!(
+            Tree::from_tree_items(tree.tree_items.clone()).unwrap().id,
+            tree.id
+        );
+    }
+
+    #[test]
+    fn test_canonical_order() {
+        let id = SHA1::from_str("8ab686eafeb1f44702738c8b0f24f2567c36da6d").unwrap();
+        let item = |mode, name: &str| TreeItem::new(mode, id, name.to_string());
+        let tree = Tree::from_tree_items(vec![
+            item(TreeItemMode::Blob, "foo0"),
+            item(TreeItemMode::Tree, "foo"),
+            item(TreeItemMode::Blob, "foo.txt"),
+            item(TreeItemMode::Blob, "Zebra"),
+        ])
+        .unwrap();
+        let names: Vec<&str> = tree.tree_items.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, ["Zebra", "foo.txt", "foo", "foo0"]);
+        assert!(matches!(
+            Tree::from_tree_items(vec![
+                item(TreeItemMode::Blob, "a"),
+                item(TreeItemMode::Blob, "a")
+            ]),
+            Err(GitError::InvalidTreeOrder(_))
+        ));
+        assert!(matches!(
+            Tree::from_tree_items(vec![
+                item(TreeItemMode::Blob, "a"),
+                item(TreeItemMode::Blob, "a.txt"),
+                item(TreeItemMode::Tree, "a")
+            ]),
+            Err(GitError::InvalidTreeOrder(_))
+        ));
+    }
+
+    #[test]
+    fn test_unsorted_tree_parsing() {
+        let id = SHA1::from_str("8ab686eafeb1f44702738c8b0f24f2567c36da6d").unwrap();
+        let mut data = Vec::new();
+        for name in ["b", "a", "c"] {
+            data.extend(TreeItem::new(TreeItemMode::Blob, id, name.to_string()).to_data());
+        }
+        // Read as stored, so the id still matches
+        let stored = SHA1::from_type_and_data(ObjectType::Tree, &data);
+        let tree = Tree::try_from(data.as_slice()).unwrap();
+        assert_eq!(tree.id, stored);
+        assert_eq!(tree.to_data().unwrap(), data);
+
+        let (tree, fixup) = Tree::from_bytes_lenient(&data, stored).unwrap();
+        let fixup = fixup.unwrap();
+        assert_eq!(fixup.original_id, stored);
+        assert_eq!(fixup.out_of_order, ["b", "a"]);
+        assert_ne!(tree.id, stored);
+        assert_eq!(
+            tree.id,
+            Tree::from_tree_items(tree.tree_items.clone()).unwrap().id
+        );
+
+        let (sorted, fixup) = Tree::from_bytes_lenient(&tree.to_data().unwrap(), tree.id).unwrap();
+        assert!(fixup.is_none());
+        assert_eq!(sorted.id, tree.id);
+    }
+
     #[test]
     fn test_from_tree_items() {
         let item = TreeItem::new(
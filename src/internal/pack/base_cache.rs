@@ -0,0 +1,159 @@
+//! A cache of inflated delta bases, like git's `core.deltaBaseCacheLimit`.
+//!
+//! Reading an object stored as a delta inflates every object of its delta chain. The objects
+//! near the bottom of the chains are shared by many others, so serving a fetch, or many fetches
+//! of the same pack, inflates them over and over. [`DeltaBaseCache`] keeps the recently used
+//! bases of any number of packs, up to a total size, for
+//! [`PackIndex::read_object_with_cache`](super::idx::PackIndex::read_object_with_cache) to resume
+//! from.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use lru_mem::{HeapSize, LruCache};
+
+use crate::hash::SHA1;
+use crate::internal::pack::cache_object::CacheObject;
+
+/// The size limit of a new cache, git's default `core.deltaBaseCacheLimit` of 96 MiB.
+pub const DEFAULT_DELTA_BASE_CACHE_LIMIT: usize = 96 * 1024 * 1024;
+
+/// A cached base, sized by its inflated content.
+struct CachedBase(Arc<CacheObject>);
+
+impl HeapSize for CachedBase {
+    fn heap_size(&self) -> usize {
+        self.0.heap_size()
+    }
+}
+
+/// The least recently used delta bases of packs, by pack hash and offset. Clones share the
+/// same cache, so one cache can serve every reader of a repository.
+#[derive(Clone)]
+pub struct DeltaBaseCache {
+    bases: Arc<Mutex<LruCache<(SHA1, u64), CachedBase>>>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+impl Default for DeltaBaseCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_DELTA_BASE_CACHE_LIMIT)
+    }
+}
+
+impl std::fmt::Debug for DeltaBaseCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DeltaBaseCache")
+            .field("len", &self.len())
+            .field("memory_used", &self.memory_used())
+            .field("hits", &self.hits())
+            .field("misses", &self.misses())
+            .finish()
+    }
+}
+
+impl DeltaBaseCache {
+    /// A cache of at most `limit` bytes of bases. A base larger than the whole cache is never
+    /// kept.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            bases: Arc::new(Mutex::new(LruCache::new(limit))),
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// The base at `offset` in the pack `pack_hash`, if cached.
+    pub fn get(&self, pack_hash: &SHA1, offset: u64) -> Option<Arc<CacheObject>> {
+        let base = self
+            .bases
+            .lock()
+            .unwrap()
+            .get(&(*pack_hash, offset))
+            .map(|base| base.0.clone());
+        let counter = if base.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        base
+    }
+
+    /// Keep `base`, the object at `offset` in the pack `pack_hash`, evicting the least recently
+    /// used bases as needed.
+    pub fn insert(&self, pack_hash: &SHA1, offset: u64, base: Arc<CacheObject>) {
+        // Too large for the cache: leave it out
+        let _ = self
+            .bases
+            .lock()
+            .unwrap()
+            .insert((*pack_hash, offset), CachedBase(base));
+    }
+
+    /// Number of cached bases.
+    pub fn len(&self) -> usize {
+        self.bases.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes held by the cached bases, at most the limit of the cache.
+    pub fn memory_used(&self) -> usize {
+        self.bases.lock().unwrap().current_size()
+    }
+
+    /// Number of lookups that found their base.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Number of lookups that didn't find their base.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Drop every cached base, e.g. once the packs they come from are removed.
+    pub fn clear(&self) {
+        self.bases.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::object::types::ObjectType;
+
+    #[test]
+    fn test_delta_base_cache_eviction() {
+        let pack = SHA1::new(b"pack");
+        let base = |size: usize| {
+            Arc::new(CacheObject::new_for_undeltified(
+                ObjectType::Blob,
+                vec![0; size],
+                0,
+            ))
+        };
+        let cache = DeltaBaseCache::new(10_000);
+        cache.insert(&pack, 12, base(4000));
+        cache.insert(&pack, 100, base(4000));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.memory_used() <= 10_000);
+
+        // Using the first base makes the second one the least recently used
+        assert!(cache.get(&pack, 12).is_some());
+        cache.insert(&pack, 200, base(4000));
+        assert!(cache.get(&pack, 100).is_none());
+        assert!(cache.get(&pack, 12).is_some());
+        assert!(cache.get(&SHA1::new(b"other pack"), 12).is_none());
+        assert_eq!((cache.hits(), cache.misses()), (2, 2));
+
+        // Larger than the whole cache
+        cache.insert(&pack, 300, base(20_000));
+        assert!(cache.get(&pack, 300).is_none());
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,629 @@
+//! Reachability bitmaps of a pack, git's `.bitmap` files.
+//!
+//! A bitmap has one bit per object of the pack, in pack order (by offset): the set bits of a
+//! commit's bitmap are the objects reachable from it. With bitmaps for the branch tips, the
+//! objects of a clone are the union of a few bitmaps instead of a walk of every commit and tree.
+//!
+//! The file is laid out as:
+//!
+//! - a 32 byte header: `BITM`, version 1, flags (`FULL_DAG` always, optionally the name-hash
+//!   cache and lookup table that follow the entries), the number of entries and the checksum of
+//!   the pack;
+//! - four bitmaps of the commits, trees, blobs and tags of the pack;
+//! - the entries: position of the commit in the `.idx`, the distance to an earlier entry whose
+//!   bitmap this one is XORed with (0 for none), flags and the bitmap;
+//! - a SHA-1 of everything before it.
+//!
+//! Bitmaps are serialized with EWAH run-length compression, see [`Bitmap::read_ewah`].
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::errors::GitError;
+use crate::hash::SHA1;
+use crate::internal::object::commit::Commit;
+use crate::internal::object::tag::Tag;
+use crate::internal::object::tree::{Tree, TreeItemMode};
+use crate::internal::object::types::ObjectType;
+use crate::internal::pack::idx::PackIndex;
+use crate::internal::refs::LockFile;
+
+const SIGNATURE: &[u8; 4] = b"BITM";
+const VERSION: u16 = 1;
+const HEADER_SIZE: usize = 12 + SHA1::SIZE;
+/// Bitmaps are closed under reachability, the only kind git writes and reads.
+const FULL_DAG: u16 = 0x1;
+/// Largest XOR distance git accepts.
+const MAX_XOR_OFFSET: usize = 160;
+/// Order of the type bitmaps in the file.
+const TYPES: [ObjectType; 4] = [
+    ObjectType::Commit,
+    ObjectType::Tree,
+    ObjectType::Blob,
+    ObjectType::Tag,
+];
+
+/// A set of object positions, stored uncompressed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitmap {
+    words: Vec<u64>,
+}
+
+impl Bitmap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, bit: usize) {
+        let word = bit / 64;
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+        self.words[word] |= 1 << (bit % 64);
+    }
+
+    pub fn contains(&self, bit: usize) -> bool {
+        self.words
+            .get(bit / 64)
+            .is_some_and(|word| word & (1 << (bit % 64)) != 0)
+    }
+
+    /// Add the bits of `other`.
+    pub fn union_with(&mut self, other: &Bitmap) {
+        if other.words.len() > self.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word |= other;
+        }
+    }
+
+    /// Remove the bits of `other`.
+    pub fn subtract(&mut self, other: &Bitmap) {
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word &= !other;
+        }
+    }
+
+    fn xor_with(&mut self, other: &Bitmap) {
+        if other.words.len() > self.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word ^= other;
+        }
+    }
+
+    /// Number of set bits.
+    pub fn count(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|&word| word == 0)
+    }
+
+    /// The set bits, in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(i, &word)| {
+            (0..64)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| i * 64 + bit)
+        })
+    }
+
+    /// Parse an EWAH bitmap at the start of `data`, returning it and the bytes it took.
+    ///
+    /// EWAH stores the number of bits, the number of 64 bit words, the words and the position of
+    /// the last marker word. Words alternate between a marker, whose bit 0 is the value of a run
+    /// of identical words, bits 1 to 32 the length of that run and bits 33 to 63 the number of
+    /// literal words that follow it, and those literal words.
+    pub fn read_ewah(data: &[u8]) -> Result<(Self, usize), GitError> {
+        let invalid =
+            || GitError::InvalidBitmapFile("truncated or corrupt EWAH bitmap".to_string());
+        if data.len() < 8 {
+            return Err(invalid());
+        }
+        let bits = BigEndian::read_u32(&data[0..4]) as usize;
+        let word_count = BigEndian::read_u32(&data[4..8]) as usize;
+        let size = 8 + word_count * 8 + 4;
+        if data.len() < size {
+            return Err(invalid());
+        }
+        let word = |i: usize| BigEndian::read_u64(&data[8 + i * 8..]);
+        let len = bits.div_ceil(64);
+        let mut words = Vec::with_capacity(len);
+        let mut i = 0;
+        while i < word_count {
+            let marker = word(i);
+            let run = ((marker >> 1) & 0xffff_ffff) as usize;
+            let literals = (marker >> 33) as usize;
+            if i + 1 + literals > word_count {
+                return Err(invalid());
+            }
+            // Words past the bit count carry nothing, don't let a long run allocate them
+            let fill = if marker & 1 == 1 { u64::MAX } else { 0 };
+            words.resize((words.len() + run).min(len), fill);
+            words.extend((i + 1..i + 1 + literals).map(word));
+            i += 1 + literals;
+        }
+        words.resize(len, 0);
+        if !bits.is_multiple_of(64)
+            && let Some(last) = words.last_mut()
+        {
+            *last &= (1 << (bits % 64)) - 1;
+        }
+        Ok((Self { words }, size))
+    }
+
+    /// Append the EWAH serialization of the first `bits` bits, see [`Bitmap::read_ewah`].
+    pub fn write_ewah(&self, bits: usize, out: &mut Vec<u8>) {
+        const MAX_RUN: usize = 0xffff_ffff;
+        const MAX_LITERALS: usize = 0x7fff_ffff;
+        let len = bits.div_ceil(64);
+        let word = |i: usize| {
+            let word = self.words.get(i).copied().unwrap_or(0);
+            match i + 1 == len && !bits.is_multiple_of(64) {
+                true => word & ((1 << (bits % 64)) - 1),
+                false => word,
+            }
+        };
+        let mut encoded: Vec<u64> = Vec::new();
+        let mut last_marker = 0;
+        let mut i = 0;
+        while i < len || encoded.is_empty() {
+            last_marker = encoded.len();
+            encoded.push(0);
+            let fill = if i < len && word(i) == u64::MAX {
+                u64::MAX
+            } else {
+                0
+            };
+            let mut run = 0;
+            while i < len && word(i) == fill && run < MAX_RUN {
+                run += 1;
+                i += 1;
+            }
+            let mut literals = 0;
+            while i < len && word(i) != 0 && word(i) != u64::MAX && literals < MAX_LITERALS {
+                encoded.push(word(i));
+                literals += 1;
+                i += 1;
+            }
+            encoded[last_marker] = (fill & 1) | (run as u64) << 1 | (literals as u64) << 33;
+        }
+        out.extend_from_slice(&(bits as u32).to_be_bytes());
+        out.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+        for word in encoded {
+            out.extend_from_slice(&word.to_be_bytes());
+        }
+        out.extend_from_slice(&(last_marker as u32).to_be_bytes());
+    }
+}
+
+/// The reachability bitmaps of a pack, resolved against its index.
+#[derive(Debug, Clone)]
+pub struct PackBitmap {
+    data: Vec<u8>,
+    /// The objects of the pack in pack order, bit `i` of a bitmap stands for `objects[i]`.
+    objects: Vec<SHA1>,
+    positions: HashMap<SHA1, usize>,
+    /// Bitmaps of the commits, trees, blobs and tags, in the order of [`TYPES`].
+    types: [Bitmap; 4],
+    commits: HashMap<SHA1, Bitmap>,
+}
+
+impl PackBitmap {
+    /// Read the bitmap at `path` of the pack indexed by `index`.
+    pub fn open(path: impl AsRef<Path>, index: &PackIndex) -> Result<Self, GitError> {
+        Self::from_bytes(fs::read(path)?, index)
+    }
+
+    /// Parse the content of a bitmap file of the pack indexed by `index`, verifying its
+    /// checksum and that it belongs to that pack.
+    pub fn from_bytes(data: Vec<u8>, index: &PackIndex) -> Result<Self, GitError> {
+        let invalid = |reason: &str| GitError::InvalidBitmapFile(reason.to_string());
+        if data.len() < HEADER_SIZE + SHA1::SIZE {
+            return Err(invalid("truncated bitmap"));
+        }
+        if &data[..4] != SIGNATURE {
+            return Err(invalid("missing BITM signature"));
+        }
+        if BigEndian::read_u16(&data[4..6]) != VERSION {
+            return Err(invalid("unsupported bitmap version"));
+        }
+        if BigEndian::read_u16(&data[6..8]) & FULL_DAG == 0 {
+            return Err(invalid("bitmaps are not closed under reachability"));
+        }
+        let trailer = data.len() - SHA1::SIZE;
+        if SHA1::new(&data[..trailer]) != SHA1::from_bytes(&data[trailer..]) {
+            return Err(invalid("checksum mismatch"));
+        }
+        if SHA1::from_bytes(&data[12..HEADER_SIZE]) != index.pack_hash() {
+            return Err(invalid("bitmap belongs to another pack"));
+        }
+
+        let entry_count = BigEndian::read_u32(&data[8..12]) as usize;
+        let mut offset = HEADER_SIZE;
+        let read = |offset: &mut usize| -> Result<Bitmap, GitError> {
+            let (bitmap, size) = Bitmap::read_ewah(&data[*offset..trailer])?;
+            *offset += size;
+            Ok(bitmap)
+        };
+        let types = [
+            read(&mut offset)?,
+            read(&mut offset)?,
+            read(&mut offset)?,
+            read(&mut offset)?,
+        ];
+        let mut entries: Vec<(SHA1, Bitmap)> = Vec::with_capacity(entry_count);
+        for _ in 0..entry_count {
+            if offset + 6 > trailer {
+                return Err(invalid("truncated entry"));
+            }
+            let position = BigEndian::read_u32(&data[offset..]) as usize;
+            let xor_offset = data[offset + 4] as usize;
+            offset += 6;
+            if position >= index.len() {
+                return Err(invalid("entry refers to a missing object"));
+            }
+            if xor_offset > MAX_XOR_OFFSET || xor_offset > entries.len() {
+                return Err(invalid("XOR offset out of bounds"));
+            }
+            let mut bitmap = read(&mut offset)?;
+            if xor_offset > 0 {
+                bitmap.xor_with(&entries[entries.len() - xor_offset].1);
+            }
+            entries.push((index.id_at(position), bitmap));
+        }
+
+        let mut objects: Vec<(u64, SHA1)> =
+            index.entries().map(|(id, offset)| (offset, id)).collect();
+        objects.sort_unstable();
+        let objects: Vec<SHA1> = objects.into_iter().map(|(_, id)| id).collect();
+        let positions = objects.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+        Ok(Self {
+            data,
+            objects,
+            positions,
+            types,
+            commits: entries.into_iter().collect(),
+        })
+    }
+
+    /// Build the bitmaps of `selected` commits for the pack indexed by `index`, which holds
+    /// `commits`, `trees` and `tags`; its other objects are taken as blobs.
+    ///
+    /// The pack must be self-contained: everything reachable from a selected commit has to be
+    /// in it, gitlinks (submodule commits) excepted.
+    pub fn build(
+        index: &PackIndex,
+        commits: &[Commit],
+        trees: &[Tree],
+        tags: &[Tag],
+        selected: &[SHA1],
+    ) -> Result<Self, GitError> {
+        let mut objects: Vec<(u64, SHA1)> =
+            index.entries().map(|(id, offset)| (offset, id)).collect();
+        objects.sort_unstable();
+        let positions: HashMap<SHA1, usize> = objects
+            .iter()
+            .enumerate()
+            .map(|(i, (_, id))| (*id, i))
+            .collect();
+        let position = |id: &SHA1| {
+            positions.get(id).copied().ok_or_else(|| {
+                GitError::InvalidBitmapFile(format!("object {id} is not in the pack"))
+            })
+        };
+
+        let commits: HashMap<SHA1, &Commit> = commits.iter().map(|c| (c.id, c)).collect();
+        let trees: HashMap<SHA1, &Tree> = trees.iter().map(|t| (t.id, t)).collect();
+        let tags: HashMap<SHA1, &Tag> = tags.iter().map(|t| (t.id, t)).collect();
+        let mut types: [Bitmap; 4] = Default::default();
+        for (_, id) in &objects {
+            let kind = if commits.contains_key(id) {
+                0
+            } else if trees.contains_key(id) {
+                1
+            } else if tags.contains_key(id) {
+                3
+            } else {
+                2
+            };
+            types[kind].set(positions[id]);
+        }
+
+        // Older commits first, so newer ones reuse the bitmaps of their selected ancestors
+        let mut selected: Vec<&Commit> = selected
+            .iter()
+            .map(|id| {
+                commits.get(id).copied().ok_or_else(|| {
+                    GitError::InvalidBitmapFile(format!("selected commit {id} is not in the pack"))
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        selected.sort_by_key(|commit| (commit.committer.timestamp, commit.id));
+        selected.dedup_by_key(|commit| commit.id);
+        let mut built: Vec<(SHA1, Bitmap)> = Vec::with_capacity(selected.len());
+        let mut bitmaps: HashMap<SHA1, usize> = HashMap::new();
+        for commit in selected {
+            let mut bitmap = Bitmap::new();
+            let mut pending = vec![commit.id];
+            while let Some(id) = pending.pop() {
+                let bit = position(&id)?;
+                if bitmap.contains(bit) {
+                    continue;
+                }
+                if let Some(&done) = bitmaps.get(&id) {
+                    bitmap.union_with(&built[done].1);
+                    continue;
+                }
+                bitmap.set(bit);
+                if let Some(commit) = commits.get(&id) {
+                    pending.push(commit.tree_id);
+                    pending.extend(&commit.parent_commit_ids);
+                } else if let Some(tree) = trees.get(&id) {
+                    pending.extend(
+                        tree.tree_items
+                            .iter()
+                            .filter(|item| item.mode != TreeItemMode::Commit)
+                            .map(|item| item.id),
+                    );
+                } else if let Some(tag) = tags.get(&id) {
+                    pending.push(tag.object_hash);
+                }
+            }
+            bitmaps.insert(commit.id, built.len());
+            built.push((commit.id, bitmap));
+        }
+
+        let bits = objects.len();
+        let mut data = SIGNATURE.to_vec();
+        data.extend_from_slice(&VERSION.to_be_bytes());
+        data.extend_from_slice(&FULL_DAG.to_be_bytes());
+        data.extend_from_slice(&(built.len() as u32).to_be_bytes());
+        data.extend_from_slice(&index.pack_hash().0);
+        for bitmap in &types {
+            bitmap.write_ewah(bits, &mut data);
+        }
+        for (id, bitmap) in &built {
+            let position = index.position(id).unwrap() as u32;
+            data.extend_from_slice(&position.to_be_bytes());
+            data.extend_from_slice(&[0, 0]);
+            bitmap.write_ewah(bits, &mut data);
+        }
+        let checksum = SHA1::new(&data);
+        data.extend_from_slice(&checksum.0);
+        Self::from_bytes(data, index)
+    }
+
+    /// The content of the bitmap file.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Write the bitmap to `path` through a `.lock` file, so readers never see a partial file.
+    pub fn write_to(&self, path: impl AsRef<Path>) -> Result<(), GitError> {
+        LockFile::acquire(path.as_ref())?.commit(&self.data)
+    }
+
+    /// Number of objects in the pack.
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// The bit standing for `id`, `None` if it isn't in the pack.
+    pub fn position(&self, id: &SHA1) -> Option<usize> {
+        self.positions.get(id).copied()
+    }
+
+    /// The type of the object `id`, `None` if it isn't in the pack.
+    pub fn object_type(&self, id: &SHA1) -> Option<ObjectType> {
+        let bit = self.position(id)?;
+        TYPES
+            .into_iter()
+            .zip(&self.types)
+            .find_map(|(kind, bitmap)| bitmap.contains(bit).then_some(kind))
+    }
+
+    /// The objects reachable from the commit `id`, `None` if it has no bitmap.
+    pub fn commit_bitmap(&self, id: &SHA1) -> Option<&Bitmap> {
+        self.commits.get(id)
+    }
+
+    /// The objects reachable from any of `tips`, `None` if one of them has no bitmap.
+    pub fn reachable<'a>(&self, tips: impl IntoIterator<Item = &'a SHA1>) -> Option<Bitmap> {
+        let mut reachable = Bitmap::new();
+        for tip in tips {
+            reachable.union_with(self.commit_bitmap(tip)?);
+        }
+        Some(reachable)
+    }
+
+    /// The objects of `bitmap` with their types, in pack order.
+    pub fn objects<'a>(
+        &'a self,
+        bitmap: &'a Bitmap,
+    ) -> impl Iterator<Item = (SHA1, ObjectType)> + 'a {
+        bitmap
+            .iter()
+            .take_while(|&bit| bit < self.objects.len())
+            .filter_map(|bit| {
+                let kind = TYPES
+                    .into_iter()
+                    .zip(&self.types)
+                    .find_map(|(kind, types)| types.contains(bit).then_some(kind))?;
+                Some((self.objects[bit], kind))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::object::signature::{Signature, SignatureType};
+    use crate::internal::object::tree::TreeItem;
+    use crate::internal::pack::idx::{IdxEntry, build_idx_v2};
+
+    #[test]
+    fn test_ewah_roundtrip() {
+        let mut bitmap = Bitmap::new();
+        // A literal word, a run of ones, a run of zeros, then a partial last word
+        for bit in [1, 5, 63] {
+            bitmap.set(bit);
+        }
+        (64..64 * 4).for_each(|bit| bitmap.set(bit));
+        for bit in [64 * 10 + 3, 64 * 10 + 6] {
+            bitmap.set(bit);
+        }
+        let bits = 64 * 10 + 7;
+        let mut data = Vec::new();
+        bitmap.write_ewah(bits, &mut data);
+        // Markers for the literal, for the ones and the zeros, then the last literal
+        assert_eq!(BigEndian::read_u32(&data[4..8]), 5);
+        data.extend_from_slice(b"next");
+        let (read, size) = Bitmap::read_ewah(&data).unwrap();
+        assert_eq!(size, data.len() - 4);
+        assert_eq!(read, bitmap);
+        assert_eq!(read.count(), 3 + 64 * 3 + 2);
+        assert_eq!(read.iter().take(4).collect::<Vec<_>>(), [1, 5, 63, 64]);
+
+        let mut empty = Vec::new();
+        Bitmap::new().write_ewah(0, &mut empty);
+        assert!(Bitmap::read_ewah(&empty).unwrap().0.is_empty());
+        assert!(Bitmap::read_ewah(&data[..20]).is_err());
+    }
+
+    #[test]
+    fn test_pack_bitmap_build() {
+        let signature =
+            |kind| Signature::new(kind, "tester".to_string(), "t@example.com".to_string());
+        let blobs = [
+            SHA1::from_type_and_data(ObjectType::Blob, b"one"),
+            SHA1::from_type_and_data(ObjectType::Blob, b"two"),
+        ];
+        let tree = |items: &[SHA1]| {
+            let items = items
+                .iter()
+                .enumerate()
+                .map(|(i, id)| TreeItem::new(TreeItemMode::Blob, *id, format!("file{i}")))
+                .collect();
+            Tree::from_tree_items(items).unwrap()
+        };
+        let trees = [tree(&blobs[..1]), tree(&blobs)];
+        let commit = |tree: &Tree, parents: Vec<SHA1>| {
+            let (author, committer) = (SignatureType::Author, SignatureType::Committer);
+            Commit::new(
+                signature(author),
+                signature(committer),
+                tree.id,
+                parents,
+                "c",
+            )
+        };
+        let first = commit(&trees[0], vec![]);
+        let second = commit(&trees[1], vec![first.id]);
+        let tag = Tag::new(
+            second.id,
+            ObjectType::Commit,
+            "v1".to_string(),
+            signature(SignatureType::Tagger),
+            "release".to_string(),
+        );
+
+        // Pack order: commits, tags, trees, blobs
+        let ids = [
+            first.id,
+            second.id,
+            tag.id,
+            trees[0].id,
+            trees[1].id,
+            blobs[0],
+            blobs[1],
+        ];
+        let entries: Vec<IdxEntry> = ids
+            .iter()
+            .enumerate()
+            .map(|(i, &hash)| IdxEntry {
+                hash,
+                offset: 12 + i as u64 * 10,
+                crc32: 0,
+            })
+            .collect();
+        let index = PackIndex::from_bytes(build_idx_v2(&entries, &SHA1::new(b"pack"))).unwrap();
+        let commits = [first.clone(), second.clone()];
+        let selected = [second.id, first.id];
+        let bitmap = PackBitmap::build(
+            &index,
+            &commits,
+            &trees,
+            std::slice::from_ref(&tag),
+            &selected,
+        )
+        .unwrap();
+
+        let reachable = |id: &SHA1| -> Vec<SHA1> {
+            let bits = bitmap.commit_bitmap(id).unwrap();
+            bitmap.objects(bits).map(|(id, _)| id).collect()
+        };
+        assert_eq!(reachable(&first.id), [first.id, trees[0].id, blobs[0]]);
+        assert_eq!(
+            reachable(&second.id),
+            [
+                first.id,
+                second.id,
+                trees[0].id,
+                trees[1].id,
+                blobs[0],
+                blobs[1]
+            ]
+        );
+        assert!(bitmap.commit_bitmap(&tag.id).is_none());
+        assert_eq!(bitmap.object_type(&tag.id), Some(ObjectType::Tag));
+        assert_eq!(bitmap.object_type(&blobs[1]), Some(ObjectType::Blob));
+        assert_eq!(
+            bitmap.reachable([&first.id, &second.id]).unwrap().count(),
+            6
+        );
+        assert!(bitmap.reachable([&tag.id]).is_none());
+
+        let reread = PackBitmap::from_bytes(bitmap.as_bytes().to_vec(), &index).unwrap();
+        assert_eq!(
+            reread.commit_bitmap(&second.id),
+            bitmap.commit_bitmap(&second.id)
+        );
+        let other = PackIndex::from_bytes(build_idx_v2(&entries, &SHA1::new(b"other"))).unwrap();
+        assert!(PackBitmap::from_bytes(bitmap.as_bytes().to_vec(), &other).is_err());
+
+        // The pack must hold everything the selected commits reach
+        let missing = tree(&[SHA1::new(b"missing")]);
+        let orphan = commit(&missing, vec![]);
+        let mut entries = entries;
+        entries.push(IdxEntry {
+            hash: orphan.id,
+            offset: 500,
+            crc32: 0,
+        });
+        let index = PackIndex::from_bytes(build_idx_v2(&entries, &SHA1::new(b"pack"))).unwrap();
+        assert!(matches!(
+            PackBitmap::build(
+                &index,
+                std::slice::from_ref(&orphan),
+                &[],
+                &[],
+                &[orphan.id]
+            ),
+            Err(GitError::InvalidBitmapFile(_))
+        ));
+    }
+}
@@ -0,0 +1,206 @@
+//! Bloom filter over the object ids of a repository.
+//!
+//! Protocol code asks the backend whether objects exist for every `have` line of a fetch and
+//! while walking history during a push. Most of these lookups are for objects the server has
+//! never seen, so an [`ObjectFilter`] built from the pack `.idx` files answers them from memory:
+//! a negative answer is definite, a positive one still has to be confirmed by the backend.
+//!
+//! Insertion only needs `&self`, so one filter can be shared behind an `Arc` and kept up to date
+//! with pushed objects while it is being read.
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::errors::GitError;
+use crate::hash::SHA1;
+use crate::internal::pack::idx::{IDX_V2_MAGIC, PackIndex};
+
+/// Size of the fanout table, 256 big-endian u32.
+const FANOUT_SIZE: usize = 256 * 4;
+
+/// A Bloom filter keyed by object id.
+#[derive(Debug)]
+pub struct ObjectFilter {
+    bits: Vec<AtomicU64>,
+    hashes: u32,
+    count: AtomicUsize,
+}
+
+impl ObjectFilter {
+    /// An empty filter sized for `expected_objects` ids at the given false positive rate
+    /// (e.g. `0.01`).
+    pub fn new(expected_objects: usize, false_positive_rate: f64) -> Self {
+        let n = expected_objects.max(1) as f64;
+        let p = false_positive_rate.clamp(1e-9, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-n * p.ln() / (ln2 * ln2)).ceil().max(64.0) as usize;
+        let hashes = ((bits as f64 / n) * ln2).round().clamp(1.0, 32.0) as u32;
+        Self {
+            bits: (0..bits.div_ceil(64)).map(|_| AtomicU64::new(0)).collect(),
+            hashes,
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    /// Build a filter holding every object listed in the given pack idx files (version 1 or 2).
+    pub fn from_idx_files<P: AsRef<Path>>(
+        paths: &[P],
+        false_positive_rate: f64,
+    ) -> Result<Self, GitError> {
+        let idx_files = paths
+            .iter()
+            .map(|path| fs::read(path).map_err(GitError::from))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut total = 0;
+        for (path, data) in paths.iter().zip(&idx_files) {
+            total += idx_object_ids(data)
+                .map_err(|_| GitError::InvalidIdxFile(path.as_ref().display().to_string()))?
+                .count();
+        }
+
+        let filter = Self::new(total, false_positive_rate);
+        for data in &idx_files {
+            filter.add_idx(data)?;
+        }
+        Ok(filter)
+    }
+
+    /// Add every object listed in the content of an idx file.
+    pub fn add_idx(&self, data: &[u8]) -> Result<(), GitError> {
+        if data.starts_with(&IDX_V2_MAGIC) && data.get(4..8) == Some(&3u32.to_be_bytes()[..]) {
+            // Version 3 has no fanout table, its ids are found through the header
+            let index = PackIndex::from_bytes(data.to_vec())?;
+            (0..index.len()).for_each(|i| self.insert(&index.id_at(i)));
+            return Ok(());
+        }
+        for id in idx_object_ids(data)? {
+            self.insert(&id);
+        }
+        Ok(())
+    }
+
+    pub fn insert(&self, id: &SHA1) {
+        for bit in self.bit_positions(id) {
+            self.bits[bit / 64].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `false` if `id` is definitely not in the repository, `true` if it may be.
+    pub fn may_contain(&self, id: &SHA1) -> bool {
+        self.bit_positions(id)
+            .all(|bit| self.bits[bit / 64].load(Ordering::Relaxed) & (1 << (bit % 64)) != 0)
+    }
+
+    /// Number of ids inserted so far, counting duplicates.
+    pub fn len(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Object ids are uniformly distributed already, so two words of the id seed the
+    /// double hashing scheme instead of a hash function.
+    fn bit_positions(&self, id: &SHA1) -> impl Iterator<Item = usize> {
+        let h1 = BigEndian::read_u64(&id.0[0..8]);
+        let h2 = BigEndian::read_u64(&id.0[8..16]) | 1;
+        let bits = (self.bits.len() * 64) as u64;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+}
+
+/// The object ids listed in an idx file, in order.
+fn idx_object_ids(data: &[u8]) -> Result<impl Iterator<Item = SHA1> + '_, GitError> {
+    let invalid = || GitError::InvalidIdxFile("truncated or corrupt idx data".to_string());
+    let (fanout_start, stride, id_offset) = if data.starts_with(&IDX_V2_MAGIC) {
+        if data.len() < 8 || BigEndian::read_u32(&data[4..8]) != 2 {
+            return Err(GitError::InvalidIdxFile(
+                "unsupported idx version".to_string(),
+            ));
+        }
+        (8, SHA1::SIZE, 0)
+    } else {
+        // Version 1: the fanout table comes first, then (offset, id) pairs
+        (0, 4 + SHA1::SIZE, 4)
+    };
+    let table_start = fanout_start + FANOUT_SIZE;
+    if data.len() < table_start {
+        return Err(invalid());
+    }
+    let count = BigEndian::read_u32(&data[table_start - 4..table_start]) as usize;
+    if data.len() < table_start + count * stride {
+        return Err(invalid());
+    }
+    Ok((0..count).map(move |i| {
+        let start = table_start + i * stride + id_offset;
+        SHA1::from_bytes(&data[start..start + SHA1::SIZE])
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::object::types::ObjectType;
+
+    fn ids(count: usize) -> Vec<SHA1> {
+        (0..count)
+            .map(|i| SHA1::from_type_and_data(ObjectType::Blob, i.to_string().as_bytes()))
+            .collect()
+    }
+
+    /// A minimal version 2 idx listing `ids`; offsets and checksums are not read by the filter.
+    fn idx_v2(ids: &[SHA1]) -> Vec<u8> {
+        let mut ids = ids.to_vec();
+        ids.sort();
+        let mut data = IDX_V2_MAGIC.to_vec();
+        data.extend_from_slice(&2u32.to_be_bytes());
+        for byte in 0..=255u8 {
+            let below = ids.iter().filter(|id| id.0[0] <= byte).count() as u32;
+            data.extend_from_slice(&below.to_be_bytes());
+        }
+        for id in &ids {
+            data.extend_from_slice(&id.0);
+        }
+        data
+    }
+
+    #[test]
+    fn test_filter_membership() {
+        let present = ids(1000);
+        let filter = ObjectFilter::new(present.len(), 0.01);
+        present.iter().for_each(|id| filter.insert(id));
+        assert!(present.iter().all(|id| filter.may_contain(id)));
+
+        let absent: Vec<SHA1> = (1000..11000)
+            .map(|i| SHA1::from_type_and_data(ObjectType::Commit, i.to_string().as_bytes()))
+            .collect();
+        let false_positives = absent.iter().filter(|id| filter.may_contain(id)).count();
+        assert!(false_positives < 300, "{false_positives} false positives");
+    }
+
+    #[test]
+    fn test_filter_from_idx_files() {
+        let objects = ids(50);
+        let dir = std::env::temp_dir().join(format!("bloom-idx-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let first = dir.join("pack-1.idx");
+        let second = dir.join("pack-2.idx");
+        fs::write(&first, idx_v2(&objects[..20])).unwrap();
+        fs::write(&second, idx_v2(&objects[20..])).unwrap();
+
+        let filter = ObjectFilter::from_idx_files(&[&first, &second], 0.01).unwrap();
+        assert_eq!(filter.len(), 50);
+        assert!(objects.iter().all(|id| filter.may_contain(id)));
+
+        fs::write(&second, &idx_v2(&objects)[..100]).unwrap();
+        assert!(matches!(
+            ObjectFilter::from_idx_files(&[&second], 0.01),
+            Err(GitError::InvalidIdxFile(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -9,6 +9,7 @@ use lru_mem::{HeapSize, MemSize};
 use serde::{Deserialize, Serialize};
 use threadpool::ThreadPool;
 
+use crate::errors::GitError;
 use crate::internal::pack::entry::Entry;
 use crate::internal::pack::utils;
 use crate::{hash::SHA1, internal::object::types::ObjectType};
@@ -188,6 +189,22 @@ impl CacheObject {
         }
     }
 
+    /// Like [`CacheObject::new_for_undeltified`], but fails with [`GitError::HashCollision`]
+    /// when the `sha1dc` feature detects a collision attack in the object.
+    pub fn try_new_for_undeltified(
+        obj_type: ObjectType,
+        data: Vec<u8>,
+        offset: usize,
+    ) -> Result<Self, GitError> {
+        let hash = SHA1::try_from_type_and_data(obj_type, &data)?;
+        Ok(CacheObject {
+            info: CacheObjectInfo::BaseObject(obj_type, hash),
+            offset,
+            data_decompressed: data,
+            mem_recorder: None,
+        })
+    }
+
     /// Get the [`ObjectType`] of the object.
     pub fn object_type(&self) -> ObjectType {
         self.info.object_type()
@@ -2,14 +2,33 @@ use std::io::{self};
 use std::io::{BufRead, Read};
 use std::sync::mpsc::Receiver;
 
+/// Channel the chunks of a [`StreamBufReader`] come from, `None` once it is closed
+pub(crate) trait ChunkReceiver {
+    fn recv_chunk(&mut self) -> Option<Vec<u8>>;
+}
+
+impl ChunkReceiver for Receiver<Vec<u8>> {
+    fn recv_chunk(&mut self) -> Option<Vec<u8>> {
+        self.recv().ok()
+    }
+}
+
+/// Bounded channel, so the sender waits for the reader instead of queueing the whole stream.
+/// Blocks the thread: read it outside the async runtime, e.g. in `spawn_blocking`.
+impl ChunkReceiver for tokio::sync::mpsc::Receiver<Vec<u8>> {
+    fn recv_chunk(&mut self) -> Option<Vec<u8>> {
+        self.blocking_recv()
+    }
+}
+
 /// Custom BufRead implementation that reads from the channel
-pub(crate) struct StreamBufReader {
-    receiver: Receiver<Vec<u8>>,
+pub(crate) struct StreamBufReader<R: ChunkReceiver = Receiver<Vec<u8>>> {
+    receiver: R,
     buffer: io::Cursor<Vec<u8>>,
 }
 
-impl StreamBufReader {
-    pub(crate) fn new(receiver: Receiver<Vec<u8>>) -> Self {
+impl<R: ChunkReceiver> StreamBufReader<R> {
+    pub(crate) fn new(receiver: R) -> Self {
         StreamBufReader {
             receiver,
             buffer: io::Cursor::new(Vec::new()),
@@ -17,29 +36,29 @@ impl StreamBufReader {
     }
 }
 
-impl Read for StreamBufReader {
+impl<R: ChunkReceiver> Read for StreamBufReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         if self.buffer.position() as usize == self.buffer.get_ref().len() {
             // buffer has been read completely
-            match self.receiver.recv() {
-                Ok(data) => {
+            match self.receiver.recv_chunk() {
+                Some(data) => {
                     self.buffer = io::Cursor::new(data);
                 }
-                Err(_) => return Ok(0), // Channel is closed
+                None => return Ok(0), // Channel is closed
             }
         }
         self.buffer.read(buf)
     }
 }
 
-impl BufRead for StreamBufReader {
+impl<R: ChunkReceiver> BufRead for StreamBufReader<R> {
     fn fill_buf(&mut self) -> io::Result<&[u8]> {
         if self.buffer.position() as usize == self.buffer.get_ref().len() {
-            match self.receiver.recv() {
-                Ok(data) => {
+            match self.receiver.recv_chunk() {
+                Some(data) => {
                     self.buffer = io::Cursor::new(data);
                 }
-                Err(_) => return Ok(&[]), // Channel is closed
+                None => return Ok(&[]), // Channel is closed
             }
         }
         self.buffer.fill_buf()
@@ -0,0 +1,352 @@
+//! Cruft packs: unreachable objects kept until they expire, with their modification times.
+//!
+//! Deleting unreachable objects at once races with operations about to reference them, e.g. a
+//! push whose objects are stored before its refs are updated. Like `git repack --cruft`,
+//! [`build_cruft_pack`] sweeps the unreachable objects modified since a cutoff into one pack,
+//! records the modification time of each in a `.mtimes` file, and reports the older ones as
+//! safe to delete. Objects a recent one refers to are kept whatever their own age, so the recent
+//! object stays whole if it is referenced again.
+//!
+//! A `.mtimes` file is laid out as:
+//!
+//! - a 12 byte header: `MTME`, version 1 and hash version 1 (SHA-1);
+//! - the modification time of every object of the pack, in seconds since the epoch on 4 bytes,
+//!   in `.idx` order (sorted by id);
+//! - the pack checksum and a SHA-1 of everything before it.
+//!
+//! The next sweep reads the times back through [`PackMtimes`], so objects of a cruft pack expire
+//! by the time they were last written rather than the time of the pack.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder};
+use flate2::{Compression, Crc};
+
+use crate::errors::GitError;
+use crate::hash::SHA1;
+use crate::internal::object::ObjectTrait;
+use crate::internal::object::commit::Commit;
+use crate::internal::object::tag::Tag;
+use crate::internal::object::tree::{Tree, TreeItemMode};
+use crate::internal::object::types::ObjectType;
+use crate::internal::pack::encode::{encode_header, encode_one_object};
+use crate::internal::pack::entry::Entry;
+use crate::internal::pack::idx::{IdxEntry, PackIndex, build_idx_v2};
+use crate::internal::refs::LockFile;
+
+const SIGNATURE: &[u8; 4] = b"MTME";
+const VERSION: u32 = 1;
+/// Hash version of SHA-1.
+const HASH_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 12;
+
+/// A parsed `.mtimes` file, the modification times of the objects of a cruft pack.
+#[derive(Debug, Clone)]
+pub struct PackMtimes {
+    data: Vec<u8>,
+    count: usize,
+}
+
+impl PackMtimes {
+    /// Read the `.mtimes` file at `path` of the pack indexed by `index`.
+    pub fn open(path: impl AsRef<Path>, index: &PackIndex) -> Result<Self, GitError> {
+        Self::from_bytes(fs::read(path)?, index)
+    }
+
+    /// Parse the content of the `.mtimes` file of the pack indexed by `index`, verifying its
+    /// layout, its checksum and that it belongs to the pack.
+    pub fn from_bytes(data: Vec<u8>, index: &PackIndex) -> Result<Self, GitError> {
+        let invalid = |reason: &str| GitError::InvalidPackMtimes(reason.to_string());
+        let count = index.len();
+        if data.len() != HEADER_SIZE + count * 4 + 2 * SHA1::SIZE {
+            return Err(invalid("size does not match the pack index"));
+        }
+        if &data[..4] != SIGNATURE {
+            return Err(invalid("missing MTME signature"));
+        }
+        if BigEndian::read_u32(&data[4..8]) != VERSION
+            || BigEndian::read_u32(&data[8..12]) != HASH_VERSION
+        {
+            return Err(invalid("unsupported mtimes or hash version"));
+        }
+        let trailer = data.len() - SHA1::SIZE;
+        if SHA1::new(&data[..trailer]) != SHA1::from_bytes(&data[trailer..]) {
+            return Err(invalid("checksum mismatch"));
+        }
+        let mtimes = Self { data, count };
+        if mtimes.pack_hash() != index.pack_hash() {
+            return Err(invalid("pack checksum does not match the pack index"));
+        }
+        Ok(mtimes)
+    }
+
+    /// Build the `.mtimes` file of a pack whose trailer checksum is `pack_hash`, from the times
+    /// of its objects in `.idx` order.
+    pub fn build(mtimes: &[u32], pack_hash: &SHA1) -> Self {
+        let mut data = Vec::with_capacity(HEADER_SIZE + mtimes.len() * 4 + 2 * SHA1::SIZE);
+        data.extend_from_slice(SIGNATURE);
+        data.extend_from_slice(&VERSION.to_be_bytes());
+        data.extend_from_slice(&HASH_VERSION.to_be_bytes());
+        for mtime in mtimes {
+            data.extend_from_slice(&mtime.to_be_bytes());
+        }
+        data.extend_from_slice(&pack_hash.0);
+        let checksum = SHA1::new(&data);
+        data.extend_from_slice(&checksum.0);
+        Self {
+            data,
+            count: mtimes.len(),
+        }
+    }
+
+    /// The content of the `.mtimes` file.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Write the `.mtimes` file to `path` through a `.lock` file, so readers never see a partial
+    /// file.
+    pub fn write_to(&self, path: impl AsRef<Path>) -> Result<(), GitError> {
+        LockFile::acquire(path.as_ref())?.commit(&self.data)
+    }
+
+    /// Number of objects with a time.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// The checksum of the pack the times belong to.
+    pub fn pack_hash(&self) -> SHA1 {
+        let start = HEADER_SIZE + self.count * 4;
+        SHA1::from_bytes(&self.data[start..start + SHA1::SIZE])
+    }
+
+    /// The time of the object at `position` in the `.idx`.
+    pub fn mtime_at(&self, position: usize) -> u32 {
+        BigEndian::read_u32(&self.data[HEADER_SIZE + position * 4..])
+    }
+
+    /// The time of `id`, looked up in the `.idx` of the pack.
+    pub fn mtime(&self, index: &PackIndex, id: &SHA1) -> Option<u32> {
+        index.position(id).map(|position| self.mtime_at(position))
+    }
+}
+
+/// A cruft pack with its `.idx` and `.mtimes` files.
+#[derive(Debug, Clone)]
+pub struct CruftPack {
+    pub pack: Vec<u8>,
+    pub idx: Vec<u8>,
+    pub mtimes: PackMtimes,
+    /// The trailer checksum of the pack, which names the three files.
+    pub pack_hash: SHA1,
+}
+
+/// Outcome of [`build_cruft_pack`].
+#[derive(Debug, Clone, Default)]
+pub struct CruftSweep {
+    /// The pack of the objects to keep, `None` when every object expired.
+    pub pack: Option<CruftPack>,
+    /// The objects that expired and that no kept object refers to, safe to delete.
+    pub expired: Vec<SHA1>,
+}
+
+/// Sweep the unreachable `objects`, with their modification times in seconds since the epoch,
+/// into a cruft pack.
+///
+/// Objects modified at `expire_before` or later are kept, along with every object they refer to
+/// through commits, trees and tags among `objects`; the others are listed as expired. Kept
+/// objects are stored whole, cruft packs are rarely read. Objects that fail to parse are kept
+/// or expired by their own time, their links cannot be followed.
+pub fn build_cruft_pack(
+    objects: Vec<(Entry, u32)>,
+    expire_before: u32,
+) -> Result<CruftSweep, GitError> {
+    let kept = recent_objects(&objects, expire_before);
+    let (kept, expired): (Vec<_>, Vec<_>) = objects
+        .into_iter()
+        .partition(|(entry, _)| kept.contains(&entry.hash));
+    let mut expired: Vec<SHA1> = expired
+        .into_iter()
+        .map(|(entry, _): (Entry, u32)| entry.hash)
+        .collect();
+    expired.sort_unstable();
+    expired.dedup();
+    if kept.is_empty() {
+        return Ok(CruftSweep {
+            pack: None,
+            expired,
+        });
+    }
+
+    // An object listed twice is packed once, with its latest time
+    let mut latest: HashMap<SHA1, (Entry, u32)> = HashMap::new();
+    for (entry, mtime) in kept {
+        match latest.get_mut(&entry.hash) {
+            Some(known) => known.1 = known.1.max(mtime),
+            None => {
+                latest.insert(entry.hash, (entry, mtime));
+            }
+        }
+    }
+    let mut kept: Vec<(Entry, u32)> = latest.into_values().collect();
+    kept.sort_unstable_by_key(|(entry, _)| entry.hash);
+
+    let mut pack = encode_header(kept.len());
+    let mut idx_entries = Vec::with_capacity(kept.len());
+    for (entry, _) in &kept {
+        let encoded = encode_one_object(entry, None, Compression::default())?;
+        let mut crc = Crc::new();
+        crc.update(&encoded);
+        idx_entries.push(IdxEntry {
+            hash: entry.hash,
+            offset: pack.len() as u64,
+            crc32: crc.sum(),
+        });
+        pack.extend(encoded);
+    }
+    let pack_hash = SHA1::new(&pack);
+    pack.extend_from_slice(&pack_hash.0);
+
+    // Sorted by id, the objects are in `.idx` order
+    let mtimes: Vec<u32> = kept.iter().map(|(_, mtime)| *mtime).collect();
+    Ok(CruftSweep {
+        pack: Some(CruftPack {
+            pack,
+            idx: build_idx_v2(&idx_entries, &pack_hash),
+            mtimes: PackMtimes::build(&mtimes, &pack_hash),
+            pack_hash,
+        }),
+        expired,
+    })
+}
+
+/// The `objects` modified at `expire_before` or later, and every object among `objects` they
+/// refer to through commits, trees and tags.
+pub(crate) fn recent_objects(objects: &[(Entry, u32)], expire_before: u32) -> HashSet<SHA1> {
+    let positions: HashMap<SHA1, usize> = objects
+        .iter()
+        .enumerate()
+        .map(|(i, (entry, _))| (entry.hash, i))
+        .collect();
+    let mut kept: HashSet<SHA1> = HashSet::new();
+    let mut queue: VecDeque<usize> = VecDeque::new();
+    for (i, (entry, mtime)) in objects.iter().enumerate() {
+        if *mtime >= expire_before && kept.insert(entry.hash) {
+            queue.push_back(i);
+        }
+    }
+    while let Some(i) = queue.pop_front() {
+        for link in links(&objects[i].0) {
+            if let Some(&position) = positions.get(&link)
+                && kept.insert(link)
+            {
+                queue.push_back(position);
+            }
+        }
+    }
+    kept
+}
+
+/// The ids `entry` refers to: tree and parents of a commit, entries of a tree (except
+/// submodule commits) and the target of a tag.
+fn links(entry: &Entry) -> Vec<SHA1> {
+    match entry.obj_type {
+        ObjectType::Commit => Commit::from_bytes(&entry.data, entry.hash)
+            .map(|commit| [vec![commit.tree_id], commit.parent_commit_ids].concat())
+            .unwrap_or_default(),
+        ObjectType::Tree => <Tree as ObjectTrait>::from_bytes(&entry.data, entry.hash)
+            .map(|tree| {
+                tree.tree_items
+                    .into_iter()
+                    .filter(|item| item.mode != TreeItemMode::Commit)
+                    .map(|item| item.id)
+                    .collect()
+            })
+            .unwrap_or_default(),
+        ObjectType::Tag => Tag::from_bytes(&entry.data, entry.hash)
+            .map(|tag| vec![tag.object_hash])
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::object::blob::Blob;
+    use crate::internal::object::signature::{Signature, SignatureType};
+    use crate::internal::object::tree::TreeItem;
+    use crate::internal::pack::verify::verify_pack;
+
+    #[test]
+    fn test_build_cruft_pack() {
+        let blob = Blob::from_content("kept by the tree");
+        let orphan = Blob::from_content("orphan");
+        let recent = Blob::from_content("recent");
+        let item = TreeItem::new(TreeItemMode::Blob, blob.id, "file".to_string());
+        let tree = Tree::from_tree_items(vec![item]).unwrap();
+        let signature = |kind| Signature::new(kind, "t".to_string(), "t@example.com".to_string());
+        let commit = Commit::new(
+            signature(SignatureType::Author),
+            signature(SignatureType::Committer),
+            tree.id,
+            vec![],
+            "\nunreachable",
+        );
+        let ids = (blob.id, orphan.id, recent.id, tree.id, commit.id);
+        let objects = vec![
+            (Entry::from(blob), 100),
+            (Entry::from(orphan), 100),
+            (Entry::from(recent), 2000),
+            (Entry::from(tree), 100),
+            (Entry::from(commit), 1000),
+        ];
+
+        // The old blob and tree are kept for the recent commit
+        let sweep = build_cruft_pack(objects.clone(), 1000).unwrap();
+        assert_eq!(sweep.expired, vec![ids.1]);
+        let cruft = sweep.pack.unwrap();
+        let index = PackIndex::from_bytes(cruft.idx.clone()).unwrap();
+        let verification = verify_pack(&cruft.pack, &index).unwrap();
+        assert_eq!(verification.pack_hash, cruft.pack_hash);
+        assert_eq!(verification.objects.len(), 4);
+
+        let mtimes = PackMtimes::from_bytes(cruft.mtimes.as_bytes().to_vec(), &index).unwrap();
+        assert_eq!(mtimes.len(), 4);
+        for (id, mtime) in [(ids.0, 100), (ids.2, 2000), (ids.3, 100), (ids.4, 1000)] {
+            assert_eq!(mtimes.mtime(&index, &id), Some(mtime));
+        }
+        assert_eq!(mtimes.mtime(&index, &ids.1), None);
+
+        let sweep = build_cruft_pack(objects, 3000).unwrap();
+        assert!(sweep.pack.is_none());
+        assert_eq!(sweep.expired.len(), 5);
+    }
+
+    #[test]
+    fn test_pack_mtimes_corruption() {
+        let objects = vec![(Entry::from(Blob::from_content("a")), 1)];
+        let cruft = build_cruft_pack(objects, 0).unwrap().pack.unwrap();
+        let index = PackIndex::from_bytes(cruft.idx).unwrap();
+        let data = cruft.mtimes.as_bytes().to_vec();
+
+        let mut flipped = data.clone();
+        flipped[HEADER_SIZE] ^= 1;
+        let other = PackMtimes::build(&[1], &SHA1::new(b"other"));
+        for (bad, reason) in [
+            (data[..data.len() - 1].to_vec(), "size"),
+            (flipped, "checksum mismatch"),
+            (other.as_bytes().to_vec(), "pack checksum"),
+        ] {
+            let err = PackMtimes::from_bytes(bad, &index).unwrap_err();
+            assert!(err.to_string().contains(reason), "{err}");
+        }
+    }
+}
@@ -1,12 +1,13 @@
 use std::io::{self, BufRead, Cursor, ErrorKind, Read};
 use std::path::PathBuf;
-use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
 use std::time::Instant;
 
 use axum::Error;
 use bytes::Bytes;
+use dashmap::DashMap;
 use flate2::bufread::ZlibDecoder;
 use futures_util::{Stream, StreamExt};
 use threadpool::ThreadPool;
@@ -14,7 +15,7 @@ use tokio::sync::mpsc::UnboundedSender;
 use uuid::Uuid;
 
 use crate::errors::GitError;
-use crate::hash::SHA1;
+use crate::hash::{ObjectHasher, SHA1};
 use crate::zstdelta;
 use crate::internal::object::types::ObjectType;
 
@@ -23,10 +24,12 @@ use crate::internal::pack::cache::_Cache;
 use crate::internal::pack::cache::Caches;
 use crate::internal::pack::cache_object::{CacheObject, MemSizeRecorder};
 use crate::internal::pack::channel_reader::StreamBufReader;
+use crate::internal::pack::diagnostics::{DeltaBase, DeltaFailure, DeltaStats};
 use crate::internal::pack::entry::Entry;
+use crate::internal::pack::idx::{IdxEntry, IdxVersion, build_idx};
 use crate::internal::pack::waitlist::Waitlist;
 use crate::internal::pack::wrapper::Wrapper;
-use crate::internal::pack::{DEFAULT_TMP_DIR, Pack, utils};
+use crate::internal::pack::{DEFAULT_TMP_DIR, EntryCallback, LargeObjectSink, Pack, utils};
 use crate::utils::CountingReader;
 
 /// For the convenience of passing parameters
@@ -36,6 +39,37 @@ struct SharedParams {
     pub caches: Arc<Caches>,
     pub cache_objs_mem_size: Arc<AtomicUsize>,
     pub callback: Arc<dyn Fn(Entry, usize) + Sync + Send>,
+    pub delta_failures: Arc<Mutex<Vec<DeltaFailure>>>,
+    pub hash_collision: Arc<Mutex<Option<String>>>,
+    pub chain_depths: Arc<DashMap<usize, usize>>,
+    pub max_chain_len: Arc<AtomicUsize>,
+}
+
+/// Hashes the content of an object while it is read, see [`Pack::set_large_object_sink`]
+struct HashingReader<R> {
+    inner: R,
+    hasher: ObjectHasher,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R, obj_type: ObjectType, size: usize) -> Self {
+        HashingReader {
+            inner,
+            hasher: ObjectHasher::new(obj_type, size),
+        }
+    }
+
+    fn finish(self) -> Result<SHA1, GitError> {
+        self.hasher.finish()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
 }
 
 impl Drop for Pack {
@@ -53,7 +87,8 @@ impl Pack {
     /// - `mem_limit`: The maximum size of the memory cache in bytes, or None for unlimited.
     ///   The 80% of it will be used for [Caches]  <br>
     ///   ​**Not very accurate, because of memory alignment and other reasons, overuse about 15%** <br>
-    /// - `temp_path`: The path to a directory for temporary files, default is "./.cache_temp" <br>
+    /// - `temp_path`: The path to a directory for temporary files, default is "git-internal-cache"
+    ///   in [`std::env::temp_dir`] <br>
     ///   For example, thread_num = 4 will use up to 8 threads (4 for decoding and 4 for cache) <br>
     /// - `clean_tmp`: whether to remove temp directory when Pack is dropped
     pub fn new(
@@ -62,7 +97,7 @@ impl Pack {
         temp_path: Option<PathBuf>,
         clean_tmp: bool,
     ) -> Self {
-        let mut temp_path = temp_path.unwrap_or(PathBuf::from(DEFAULT_TMP_DIR));
+        let mut temp_path = temp_path.unwrap_or_else(|| std::env::temp_dir().join(DEFAULT_TMP_DIR));
         // add 8 random characters as subdirectory, check if the directory exists
         loop {
             let sub_dir = Uuid::new_v4().to_string()[..8].to_string();
@@ -84,9 +119,145 @@ impl Pack {
             mem_limit,
             cache_objs_mem: Arc::new(AtomicUsize::default()),
             clean_tmp,
+            salvage: false,
+            delta_stats: DeltaStats::default(),
+            build_idx: false,
+            idx_version: IdxVersion::default(),
+            idx_crcs: Vec::new(),
+            idx_hashes: Arc::new(Mutex::new(Vec::new())),
+            idx: None,
+            thin: false,
+            stop_at_trailer: false,
+            thin_pending: None,
+            delta_failures: Arc::new(Mutex::new(Vec::new())),
+            hash_collision: Arc::new(Mutex::new(None)),
+            chain_depths: Arc::new(DashMap::new()),
+            max_chain_len: Arc::new(AtomicUsize::new(0)),
+            large_objects: None,
+            streamed: Vec::new(),
+        }
+    }
+
+    /// Enable salvage mode: unresolvable delta entries no longer fail [`Pack::decode`],
+    /// every other object is still passed to the callback and the failures are available
+    /// from [`Pack::delta_failures`] afterwards.
+    pub fn set_salvage(&mut self, salvage: bool) {
+        self.salvage = salvage;
+    }
+
+    /// Delta entries that could not be resolved during the last decode.
+    pub fn delta_failures(&self) -> Vec<DeltaFailure> {
+        self.delta_failures.lock().unwrap().clone()
+    }
+
+    /// Also build the `.idx` of the pack while decoding, so the pack can be stored as-is and
+    /// read at random through it. The idx is available from [`Pack::idx`] after a decode in
+    /// which every object was resolved.
+    pub fn set_build_idx(&mut self, build_idx: bool) {
+        self.build_idx = build_idx;
+    }
+
+    /// The version of the `.idx` built while decoding, version 2 by default like git.
+    pub fn set_idx_version(&mut self, version: IdxVersion) {
+        self.idx_version = version;
+    }
+
+    /// Accept thin packs: when deltas refer to bases that are not in the pack, [`Pack::decode`]
+    /// returns with them waiting instead of failing. The caller reads the objects named by
+    /// [`Pack::missing_bases`] from its repository and hands them to [`Pack::resolve_thin`],
+    /// which resolves the deltas and finishes the decode.
+    pub fn set_thin(&mut self, thin: bool) {
+        self.thin = thin;
+    }
+
+    /// Return from [`Pack::decode`] once the trailer is read, instead of checking that the
+    /// input ends there, for packs read from a connection the peer keeps open, like `git://`.
+    pub fn set_stop_at_trailer(&mut self, stop_at_trailer: bool) {
+        self.stop_at_trailer = stop_at_trailer;
+    }
+
+    /// Hand the content of whole blobs larger than the given size to the sink as it is inflated,
+    /// instead of keeping them in memory until the decode ends. These blobs are not passed to
+    /// the callback of [`Pack::decode`] and are no delta bases: deltas on them stay unresolved,
+    /// like deltas on a base missing from the pack. `None` keeps every object in memory.
+    pub fn set_large_object_sink(&mut self, large_objects: Option<(usize, LargeObjectSink)>) {
+        self.large_objects = large_objects;
+    }
+
+    /// The ids of the objects handed to the large object sink during the last decode.
+    pub fn streamed_objects(&self) -> &[SHA1] {
+        &self.streamed
+    }
+
+    /// The bases missing from the pack of a decode waiting for [`Pack::resolve_thin`].
+    pub fn missing_bases(&self) -> Vec<SHA1> {
+        match self.thin_pending {
+            Some(_) => self.waitlist.map_ref.iter().map(|e| *e.key()).collect(),
+            None => Vec::new(),
         }
     }
 
+    /// Resolve the deltas of a thin pack against `bases`, the `(type, content)` of objects named
+    /// by [`Pack::missing_bases`], then finish the decode as [`Pack::decode`] does: deltas still
+    /// unresolved are failures. The bases are not passed to the callback, they are not part of
+    /// the pack.
+    pub fn resolve_thin(
+        &mut self,
+        bases: impl IntoIterator<Item = (ObjectType, Vec<u8>)>,
+    ) -> Result<(), GitError> {
+        let Some((callback, time)) = self.thin_pending.take() else {
+            return Ok(());
+        };
+        let params = self.shared_params(callback);
+        for (obj_type, data) in bases {
+            // No entry of the pack is at this offset, only the deltas waiting for its hash match
+            let base = CacheObject::try_new_for_undeltified(obj_type, data, usize::MAX)?;
+            Self::process_waitlist(params.clone(), Arc::new(base));
+        }
+        self.pool.join();
+        self.finish_decode(time)
+    }
+
+    fn shared_params(&self, callback: EntryCallback) -> Arc<SharedParams> {
+        Arc::new(SharedParams {
+            pool: self.pool.clone(),
+            waitlist: self.waitlist.clone(),
+            caches: self.caches.clone(),
+            cache_objs_mem_size: self.cache_objs_mem.clone(),
+            callback,
+            delta_failures: self.delta_failures.clone(),
+            hash_collision: self.hash_collision.clone(),
+            chain_depths: self.chain_depths.clone(),
+            max_chain_len: self.max_chain_len.clone(),
+        })
+    }
+
+    /// The `.idx` built by the last decode, see [`Pack::set_build_idx`].
+    pub fn idx(&self) -> Option<&[u8]> {
+        self.idx.as_deref()
+    }
+
+    /// Pair the CRC32s recorded while reading with the ids of the decoded objects.
+    fn finish_idx(&mut self) -> Vec<u8> {
+        let mut hashes = std::mem::take(&mut *self.idx_hashes.lock().unwrap());
+        hashes.sort_unstable_by_key(|(offset, _)| *offset);
+        let crcs = std::mem::take(&mut self.idx_crcs);
+        debug_assert_eq!(crcs.len(), hashes.len());
+        let entries: Vec<IdxEntry> = crcs
+            .into_iter()
+            .zip(hashes)
+            .map(|((offset, crc32), (hash_offset, hash))| {
+                debug_assert_eq!(offset, hash_offset);
+                IdxEntry {
+                    hash,
+                    offset: offset as u64,
+                    crc32,
+                }
+            })
+            .collect();
+        build_idx(&entries, &self.signature, self.idx_version)
+    }
+
     /// Checks and reads the header of a Git pack file.
     ///
     /// This function reads the first 12 bytes of a pack file, which include the b"PACK" magic identifier,
@@ -263,12 +434,22 @@ impl Pack {
 
         // Check if the object type is valid
         let t = ObjectType::from_u8(type_bits)?;
+        Pack::decode_pack_object_body(pack, t, size, init_offset, offset)
+    }
 
+    /// Decodes the rest of a pack object once its type and size have been read.
+    fn decode_pack_object_body(
+        pack: &mut (impl BufRead + Send),
+        t: ObjectType,
+        size: usize,
+        init_offset: usize,
+        offset: &mut usize,
+    ) -> Result<CacheObject, GitError> {
         match t {
             ObjectType::Commit | ObjectType::Tree | ObjectType::Blob | ObjectType::Tag => {
                 let (data, raw_size) = Pack::decompress_data(pack, size)?;
                 *offset += raw_size;
-                Ok(CacheObject::new_for_undeltified(t, data, init_offset))
+                CacheObject::try_new_for_undeltified(t, data, init_offset)
             }
             ObjectType::OffsetDelta | ObjectType::OffsetZstdelta => {
                 let (delta_offset, bytes) = utils::read_offset_encoding(pack).unwrap();
@@ -326,6 +507,46 @@ impl Pack {
         }
     }
 
+    /// Decodes the next object of the pack, or streams it to the large object sink and returns
+    /// `None` when it is a blob above the threshold.
+    fn decode_or_stream_object(
+        &mut self,
+        pack: &mut (impl BufRead + Send),
+        offset: &mut usize,
+    ) -> Result<Option<CacheObject>, GitError> {
+        let init_offset = *offset;
+        let (type_bits, size) = utils::read_type_and_varint_size(pack, offset)
+            .map_err(|e| GitError::InvalidPackFile(format!("Read error: {e}")))?;
+        let t = ObjectType::from_u8(type_bits)?;
+        let sink = match &self.large_objects {
+            Some((threshold, sink)) if t == ObjectType::Blob && size > *threshold => sink.clone(),
+            _ => {
+                return Pack::decode_pack_object_body(pack, t, size, init_offset, offset).map(Some);
+            }
+        };
+
+        // Hash the content on its way to the sink, which may stop reading early
+        let mut counting_reader = CountingReader::new(pack);
+        let mut content = HashingReader::new(ZlibDecoder::new(&mut counting_reader), t, size);
+        let stored = sink(t, size, &mut content)?;
+        io::copy(&mut content, &mut io::sink())
+            .map_err(|e| GitError::InvalidPackFile(format!("Decompression error: {e}")))?;
+        let hash = content.finish()?;
+        *offset += counting_reader.bytes_read as usize;
+        if stored != hash {
+            return Err(GitError::InvalidPackFile(format!(
+                "The object {hash} at offset {init_offset} was stored as {stored}"
+            )));
+        }
+
+        self.delta_stats.base_objects += 1;
+        self.streamed.push(hash);
+        if self.build_idx {
+            self.idx_hashes.lock().unwrap().push((init_offset, hash));
+        }
+        Ok(None)
+    }
+
     /// Decodes a pack file from a given Read and BufRead source, for each object in the pack,
     /// it decodes the object and processes it using the provided callback function.
     pub fn decode<F>(
@@ -349,10 +570,26 @@ impl Pack {
                 pack.caches.memory_used() / 1024 / 1024
             );
         };
-        let callback = Arc::new(callback);
+        let callback: Arc<dyn Fn(Entry, usize) + Sync + Send> = if self.build_idx {
+            let hashes = self.idx_hashes.clone();
+            Arc::new(move |entry: Entry, offset: usize| {
+                hashes.lock().unwrap().push((offset, entry.hash));
+                callback(entry, offset);
+            })
+        } else {
+            Arc::new(callback)
+        };
+        self.idx = None;
+        self.thin_pending = None;
+        self.idx_crcs.clear();
+        self.idx_hashes.lock().unwrap().clear();
+        self.streamed.clear();
 
         let caches = self.caches.clone();
         let mut reader = Wrapper::new(io::BufReader::new(pack));
+        if self.build_idx {
+            reader.enable_crc();
+        }
 
         let result = Pack::check_header(&mut reader);
         match result {
@@ -364,7 +601,13 @@ impl Pack {
             }
         }
         tracing::info!("The pack file has {} objects", self.number);
+        self.delta_stats = DeltaStats::default();
+        self.delta_failures.lock().unwrap().clear();
+        *self.hash_collision.lock().unwrap() = None;
+        self.chain_depths.clear();
+        self.max_chain_len.store(0, Ordering::Release);
         let mut offset: usize = 12;
+        reader.take_crc(); // the CRC32s of the idx cover the entries only
         let mut i = 0;
         while i < self.number {
             // log per 1000 objects and 1 second
@@ -385,21 +628,26 @@ impl Pack {
             {
                 thread::yield_now();
             }
-            let r: Result<CacheObject, GitError> =
-                Pack::decode_pack_object(&mut reader, &mut offset);
+            let entry_offset = offset;
+            let r = self.decode_or_stream_object(&mut reader, &mut offset);
+            if self.build_idx {
+                self.idx_crcs.push((entry_offset, reader.take_crc()));
+            }
             match r {
-                Ok(mut obj) => {
+                // streamed to the large object sink, nothing to resolve
+                Ok(None) => {}
+                Ok(Some(mut obj)) => {
                     obj.set_mem_recorder(self.cache_objs_mem.clone());
                     obj.record_mem_size();
+                    match obj.info {
+                        CacheObjectInfo::BaseObject(_, _) => self.delta_stats.base_objects += 1,
+                        CacheObjectInfo::OffsetDelta(_, _) => self.delta_stats.offset_deltas += 1,
+                        CacheObjectInfo::OffsetZstdelta(_, _) => self.delta_stats.zstd_deltas += 1,
+                        CacheObjectInfo::HashDelta(_, _) => self.delta_stats.ref_deltas += 1,
+                    }
 
                     // Wrapper of Arc Params, for convenience to pass
-                    let params = Arc::new(SharedParams {
-                        pool: self.pool.clone(),
-                        waitlist: self.waitlist.clone(),
-                        caches: self.caches.clone(),
-                        cache_objs_mem_size: self.cache_objs_mem.clone(),
-                        callback: callback.clone(),
-                    });
+                    let params = self.shared_params(callback.clone());
 
                     let caches = caches.clone();
                     let waitlist = self.waitlist.clone();
@@ -443,16 +691,18 @@ impl Pack {
         }
         log_info(i, self);
         let render_hash = reader.final_hash();
-        self.signature = SHA1::from_stream(&mut reader).unwrap();
+        self.signature = SHA1::from_stream(&mut reader).map_err(|e| {
+            GitError::PackChecksumMismatch(format!("The pack file trailer is missing: {e}"))
+        })?;
 
         if render_hash != self.signature {
-            return Err(GitError::InvalidPackFile(format!(
+            return Err(GitError::PackChecksumMismatch(format!(
                 "The pack file hash {} does not match the trailer hash {}",
                 render_hash, self.signature
             )));
         }
 
-        let end = utils::is_eof(&mut reader);
+        let end = self.stop_at_trailer || utils::is_eof(&mut reader);
         if !end {
             return Err(GitError::InvalidPackFile(
                 "The pack file is not at the end".to_string(),
@@ -460,18 +710,62 @@ impl Pack {
         }
 
         self.pool.join(); // wait for all threads to finish
+        if self.thin && !self.waitlist.map_ref.is_empty() {
+            // The bases of a thin pack come from the repository, see `resolve_thin`
+            self.thin_pending = Some((callback, time));
+            return Ok(());
+        }
+        self.finish_decode(time)
+    }
+
+    /// Report the deltas left unresolved, release the caches and build the idx.
+    fn finish_decode(&mut self, time: Instant) -> Result<(), GitError> {
+        // Deltas still waiting have a base that never resolved: missing, or a failed delta itself
+        self.drain_waitlist();
+        let failures = self.delta_failures.lock().unwrap().clone();
+        self.delta_stats.failed = failures.len();
+        self.delta_stats.max_chain_len = self.max_chain_len.load(Ordering::Acquire);
+        self.chain_depths.clear();
         // !Attention: Caches threadpool may not stop, but it's not a problem (garbage file data)
         // So that files != self.number
-        assert_eq!(self.waitlist.map_offset.len(), 0);
-        assert_eq!(self.waitlist.map_ref.len(), 0);
-        assert_eq!(self.number, caches.total_inserted());
-        tracing::info!(
-            "The pack file has been decoded successfully, takes: [ {:?} ]",
-            time.elapsed()
-        );
+        if failures.is_empty() {
+            assert_eq!(
+                self.number,
+                self.caches.total_inserted() + self.streamed.len()
+            );
+        }
         self.caches.clear(); // clear cached objects & stop threads
         assert_eq!(self.cache_objs_mem_used(), 0); // all the objs should be dropped until here
 
+        // Not salvaged: the id would name other content than the object the pack was made from
+        if let Some(id) = self.hash_collision.lock().unwrap().take() {
+            return Err(GitError::HashCollision(id));
+        }
+        if let Some(first) = failures.first() {
+            if !self.salvage {
+                return Err(GitError::DeltaObjectError(format!(
+                    "{} delta object(s) could not be resolved, first: {}",
+                    failures.len(),
+                    first
+                )));
+            }
+            tracing::warn!(
+                "Salvaged {} of {} objects, {} delta object(s) could not be resolved",
+                self.number - failures.len(),
+                self.number,
+                failures.len()
+            );
+        } else {
+            tracing::info!(
+                "The pack file has been decoded successfully, takes: [ {:?} ]",
+                time.elapsed()
+            );
+        }
+
+        if self.build_idx && failures.is_empty() {
+            self.idx = Some(self.finish_idx());
+        }
+
         // impl in Drop Trait
         // if self.clean_tmp {
         //     self.caches.remove_tmp_dir();
@@ -549,22 +843,87 @@ impl Pack {
         base_obj: Arc<CacheObject>,
     ) {
         shared_params.pool.clone().execute(move || {
-            let mut new_obj = match delta_obj.info {
+            let depth = shared_params
+                .chain_depths
+                .get(&base_obj.offset)
+                .map(|depth| *depth)
+                .unwrap_or(0)
+                + 1;
+            let (delta_offset, delta_info) = (delta_obj.offset, delta_obj.info.clone());
+            let result = match delta_obj.info {
                 CacheObjectInfo::OffsetDelta(_, _) | CacheObjectInfo::HashDelta(_, _) => {
-                    Pack::rebuild_delta(delta_obj, base_obj)
+                    Pack::rebuild_delta(delta_obj, base_obj.clone())
                 }
                 CacheObjectInfo::OffsetZstdelta(_, _) => {
-                    Pack::rebuild_zstdelta(delta_obj, base_obj)
+                    Pack::rebuild_zstdelta(delta_obj, base_obj.clone())
                 }
                 _ => unreachable!(),
             };
 
-            new_obj.set_mem_recorder(shared_params.cache_objs_mem_size.clone());
-            new_obj.record_mem_size();
-            Self::cache_obj_and_process_waitlist(shared_params, new_obj); //Indirect Recursion
+            match result {
+                Ok(mut new_obj) => {
+                    shared_params.chain_depths.insert(new_obj.offset, depth);
+                    shared_params
+                        .max_chain_len
+                        .fetch_max(depth, Ordering::AcqRel);
+                    new_obj.set_mem_recorder(shared_params.cache_objs_mem_size.clone());
+                    new_obj.record_mem_size();
+                    Self::cache_obj_and_process_waitlist(shared_params, new_obj); //Indirect Recursion
+                }
+                Err(e) => {
+                    if let GitError::HashCollision(id) = &e {
+                        let mut collision = shared_params.hash_collision.lock().unwrap();
+                        collision.get_or_insert_with(|| id.clone());
+                    }
+                    // Objects waiting for this one stay in the waitlist and are reported at the end
+                    let failure = delta_failure(
+                        delta_offset,
+                        &delta_info,
+                        base_obj.base_object_hash(),
+                        Some(depth),
+                        e.to_string(),
+                    );
+                    tracing::error!("Failed to rebuild delta: {}", failure);
+                    shared_params.delta_failures.lock().unwrap().push(failure);
+                }
+            }
         });
     }
 
+    /// Report every delta left in the waitlist as unresolved and release it.
+    fn drain_waitlist(&self) {
+        let mut failures = self.delta_failures.lock().unwrap();
+        let offsets: Vec<usize> = self.waitlist.map_offset.iter().map(|e| *e.key()).collect();
+        for base_offset in offsets {
+            if let Some((_, objs)) = self.waitlist.map_offset.remove(&base_offset) {
+                for obj in objs {
+                    failures.push(delta_failure(
+                        obj.offset,
+                        &obj.info,
+                        None,
+                        None,
+                        format!("base object at offset {base_offset} could not be resolved"),
+                    ));
+                }
+            }
+        }
+        let hashes: Vec<SHA1> = self.waitlist.map_ref.iter().map(|e| *e.key()).collect();
+        for base_hash in hashes {
+            if let Some((_, objs)) = self.waitlist.map_ref.remove(&base_hash) {
+                for obj in objs {
+                    failures.push(delta_failure(
+                        obj.offset,
+                        &obj.info,
+                        None,
+                        None,
+                        format!("base object {base_hash} not found in pack"),
+                    ));
+                }
+            }
+        }
+        failures.sort_by_key(|failure| failure.offset);
+    }
+
     /// Cache the new object & process the objects waiting for it (in multi-threading).
     fn cache_obj_and_process_waitlist(shared_params: Arc<SharedParams>, new_obj: CacheObject) {
         (shared_params.callback)(new_obj.to_entry(), new_obj.offset);
@@ -588,7 +947,10 @@ impl Pack {
 
     /// Reconstruct the Delta Object based on the "base object"
     /// and return the new object.
-    pub fn rebuild_delta(delta_obj: CacheObject, base_obj: Arc<CacheObject>) -> CacheObject {
+    pub fn rebuild_delta(
+        delta_obj: CacheObject,
+        base_obj: Arc<CacheObject>,
+    ) -> Result<CacheObject, GitError> {
         const COPY_INSTRUCTION_FLAG: u8 = 1 << 7;
         const COPY_OFFSET_BYTES: u8 = 4;
         const COPY_SIZE_BYTES: u8 = 3;
@@ -598,11 +960,16 @@ impl Pack {
 
         // Read the base object size
         // (Size Encoding)
-        let (base_size, result_size) = utils::read_delta_object_size(&mut stream).unwrap();
+        let (base_size, result_size) = utils::read_delta_object_size(&mut stream)?;
 
         // Get the base object data
         let base_info = &base_obj.data_decompressed;
-        assert_eq!(base_info.len(), base_size, "Base object size mismatch");
+        if base_info.len() != base_size {
+            return Err(GitError::DeltaObjectError(format!(
+                "Base object size mismatch: expected {base_size}, found {}",
+                base_info.len()
+            )));
+        }
 
         let mut result = Vec::with_capacity(result_size);
 
@@ -612,10 +979,9 @@ impl Pack {
                 Ok([instruction]) => instruction,
                 Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
                 Err(err) => {
-                    panic!(
-                        "{}",
-                        GitError::DeltaObjectError(format!("Wrong instruction in delta :{err}"))
-                    );
+                    return Err(GitError::DeltaObjectError(format!(
+                        "Wrong instruction in delta :{err}"
+                    )));
                 }
             };
 
@@ -623,15 +989,16 @@ impl Pack {
                 // Data instruction; the instruction byte specifies the number of data bytes
                 if instruction == 0 {
                     // Appending 0 bytes doesn't make sense, so git disallows it
-                    panic!(
-                        "{}",
-                        GitError::DeltaObjectError(String::from("Invalid data instruction"))
-                    );
+                    return Err(GitError::DeltaObjectError(String::from(
+                        "Invalid data instruction",
+                    )));
                 }
 
                 // Append the provided bytes
                 let mut data = vec![0; instruction as usize];
-                stream.read_exact(&mut data).unwrap();
+                stream.read_exact(&mut data).map_err(|e| {
+                    GitError::DeltaObjectError(format!("Truncated data instruction: {e}"))
+                })?;
                 result.extend_from_slice(&data);
             } else {
                 // Copy instruction
@@ -640,67 +1007,100 @@ impl Pack {
                 // +----------+---------+---------+---------+---------+-------+-------+-------+
                 let mut nonzero_bytes = instruction;
                 let offset =
-                    utils::read_partial_int(&mut stream, COPY_OFFSET_BYTES, &mut nonzero_bytes)
-                        .unwrap();
+                    utils::read_partial_int(&mut stream, COPY_OFFSET_BYTES, &mut nonzero_bytes)?;
                 let mut size =
-                    utils::read_partial_int(&mut stream, COPY_SIZE_BYTES, &mut nonzero_bytes)
-                        .unwrap();
+                    utils::read_partial_int(&mut stream, COPY_SIZE_BYTES, &mut nonzero_bytes)?;
                 if size == 0 {
                     // Copying 0 bytes doesn't make sense, so git assumes a different size
                     size = COPY_ZERO_SIZE;
                 }
                 // Copy bytes from the base object
-                let base_data = base_info.get(offset..(offset + size)).ok_or_else(|| {
-                    GitError::DeltaObjectError("Invalid copy instruction".to_string())
-                });
-
-                match base_data {
-                    Ok(data) => result.extend_from_slice(data),
-                    Err(e) => panic!("{}", e),
-                }
+                let data = base_info.get(offset..(offset + size)).ok_or_else(|| {
+                    GitError::DeltaObjectError(format!(
+                        "Invalid copy instruction: {offset}+{size} exceeds base size {base_size}"
+                    ))
+                })?;
+                result.extend_from_slice(data);
             }
         }
-        assert_eq!(result_size, result.len(), "Result size mismatch");
+        if result_size != result.len() {
+            return Err(GitError::DeltaObjectError(format!(
+                "Result size mismatch: expected {result_size}, rebuilt {}",
+                result.len()
+            )));
+        }
 
-        let hash = utils::calculate_object_hash(base_obj.object_type(), &result);
+        let hash = SHA1::try_from_type_and_data(base_obj.object_type(), &result)?;
         // create new obj from `delta_obj` & `result` instead of modifying `delta_obj` for heap-size recording
-        CacheObject {
+        Ok(CacheObject {
             info: CacheObjectInfo::BaseObject(base_obj.object_type(), hash),
             offset: delta_obj.offset,
             data_decompressed: result,
             mem_recorder: None,
-        } // Canonical form (Complete Object)
+        }) // Canonical form (Complete Object)
         // Memory recording will happen after this function returns. See `process_delta`
     }
-    pub fn rebuild_zstdelta(delta_obj: CacheObject, base_obj: Arc<CacheObject>) -> CacheObject {
+    pub fn rebuild_zstdelta(
+        delta_obj: CacheObject,
+        base_obj: Arc<CacheObject>,
+    ) -> Result<CacheObject, GitError> {
         let result = zstdelta::apply(&base_obj.data_decompressed, &delta_obj.data_decompressed)
-            .expect("Failed to apply zstdelta");
-        let hash = utils::calculate_object_hash(base_obj.object_type(), &result);
-        CacheObject {
+            .map_err(|e| GitError::DeltaObjectError(format!("Failed to apply zstdelta: {e}")))?;
+        let hash = SHA1::try_from_type_and_data(base_obj.object_type(), &result)?;
+        Ok(CacheObject {
             info: CacheObjectInfo::BaseObject(base_obj.object_type(), hash),
             offset: delta_obj.offset,
             data_decompressed: result,
             mem_recorder: None,
-        } // Canonical form (Complete Object)
+        }) // Canonical form (Complete Object)
         // Memory recording will happen after this function returns. See `process_delta`
     }
 }
 
+/// Describe a delta entry that could not be resolved.
+fn delta_failure(
+    offset: usize,
+    info: &CacheObjectInfo,
+    base_hash: Option<SHA1>,
+    chain_position: Option<usize>,
+    reason: String,
+) -> DeltaFailure {
+    let base = match info {
+        CacheObjectInfo::HashDelta(hash, _) => DeltaBase::Hash(*hash),
+        CacheObjectInfo::OffsetDelta(base_offset, _)
+        | CacheObjectInfo::OffsetZstdelta(base_offset, _) => DeltaBase::Offset(*base_offset),
+        CacheObjectInfo::BaseObject(_, _) => unreachable!("base objects are never deltas"),
+    };
+    DeltaFailure {
+        offset,
+        obj_type: info.object_type(),
+        base,
+        base_hash,
+        chain_position,
+        reason,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs;
     use std::io::BufReader;
     use std::io::Cursor;
     use std::io::prelude::*;
-    use std::sync::Arc;
     use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
     use std::{env, path::PathBuf};
 
     use flate2::Compression;
     use flate2::write::ZlibEncoder;
     use tokio_util::io::ReaderStream;
 
-    use crate::internal::pack::Pack;
+    use crate::errors::GitError;
+    use crate::hash::SHA1;
+    use crate::internal::object::types::ObjectType;
+    use crate::internal::pack::{LargeObjectSink, Pack};
+    use crate::internal::pack::bloom::ObjectFilter;
+    use crate::internal::pack::diagnostics::{DeltaBase, DeltaStats};
     use crate::internal::pack::tests::init_logger;
     use futures_util::TryStreamExt;
 
@@ -739,6 +1139,260 @@ mod tests {
         }
     }
 
+    /// Build a pack from `(type, payload, ofs-delta base entry index)` entries. The payload of a
+    /// ref delta (type 7) starts with the id of its base.
+    fn build_pack(entries: &[(u8, &[u8], Option<usize>)]) -> Vec<u8> {
+        let mut pack = b"PACK".to_vec();
+        pack.extend_from_slice(&2u32.to_be_bytes());
+        pack.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+        let mut offsets = Vec::new();
+        for (obj_type, data, base) in entries {
+            let offset = pack.len();
+            offsets.push(offset);
+            let (base_id, data) = match obj_type {
+                7 => data.split_at(SHA1::SIZE),
+                _ => (&[][..], *data),
+            };
+            let mut size = data.len();
+            let mut byte = (obj_type << 4) | (size & 0x0f) as u8;
+            size >>= 4;
+            while size > 0 {
+                pack.push(byte | 0x80);
+                byte = (size & 0x7f) as u8;
+                size >>= 7;
+            }
+            pack.push(byte);
+            if let Some(base) = base {
+                let distance = offset - offsets[*base];
+                assert!(distance < 0x80);
+                pack.push(distance as u8);
+            }
+            pack.extend_from_slice(base_id);
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data).unwrap();
+            pack.extend(encoder.finish().unwrap());
+        }
+        let checksum = SHA1::new(&pack);
+        pack.extend_from_slice(&checksum.0);
+        pack
+    }
+
+    /// A pack with a blob, a valid delta on it, a broken delta on it and a delta on the broken one.
+    fn build_corrupt_delta_pack() -> Vec<u8> {
+        // base size 5, result size 11: copy "hello", insert " world"
+        let good = [&[0x05, 0x0b, 0x90, 0x05, 0x06][..], b" world"].concat();
+        // base size 5, result size 100: copy 100 bytes from a 5 byte base
+        let bad = [0x05, 0x64, 0x91, 0x00, 0x64];
+        // base size 100, result size 1: insert "x"
+        let dependent = [0x64, 0x01, 0x01, b'x'];
+        build_pack(&[
+            (3, b"hello", None),
+            (6, &good, Some(0)),
+            (6, &bad, Some(0)),
+            (6, &dependent, Some(2)),
+        ])
+    }
+
+    #[test]
+    fn test_pack_decode_reports_bad_delta() {
+        let data = build_corrupt_delta_pack();
+        let mut p = Pack::new(Some(2), None, Some(PathBuf::from("/tmp/.cache_temp")), true);
+        let err = p.decode(&mut Cursor::new(data), |_, _| {}).unwrap_err();
+        match err {
+            GitError::DeltaObjectError(msg) => {
+                assert!(msg.starts_with("2 delta object(s)"), "{msg}");
+                assert!(msg.contains("base offset 12"), "{msg}");
+                assert!(msg.contains("chain position 1"), "{msg}");
+            }
+            e => panic!("unexpected error: {e:?}"),
+        }
+        let failures = p.delta_failures();
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures[0].base, DeltaBase::Offset(12));
+        // blob "hello"
+        assert_eq!(
+            failures[0].base_hash.unwrap().to_string(),
+            "b6fc4c620b67d95f953a5c1c1230aaab5db5a1b0"
+        );
+        assert!(failures[0].reason.contains("Invalid copy instruction"));
+        assert_eq!(failures[1].base, DeltaBase::Offset(failures[0].offset));
+        assert_eq!(failures[1].chain_position, None);
+    }
+
+    #[test]
+    fn test_pack_decode_checksum_mismatch() {
+        let mut data = build_pack(&[(3, b"hello", None)]);
+        *data.last_mut().unwrap() ^= 0xff;
+        let mut p = Pack::new(Some(2), None, Some(PathBuf::from("/tmp/.cache_temp")), true);
+        let err = p
+            .decode(&mut Cursor::new(data.clone()), |_, _| {})
+            .unwrap_err();
+        assert!(matches!(err, GitError::PackChecksumMismatch(_)), "{err:?}");
+
+        // A truncated trailer is reported instead of panicking
+        data.truncate(data.len() - 10);
+        let mut p = Pack::new(Some(2), None, Some(PathBuf::from("/tmp/.cache_temp")), true);
+        let err = p.decode(&mut Cursor::new(data), |_, _| {}).unwrap_err();
+        assert!(matches!(err, GitError::PackChecksumMismatch(_)), "{err:?}");
+    }
+
+    #[test]
+    fn test_pack_decode_salvage() {
+        let data = build_corrupt_delta_pack();
+        let mut p = Pack::new(Some(2), None, Some(PathBuf::from("/tmp/.cache_temp")), true);
+        p.set_salvage(true);
+        let recovered = Arc::new(Mutex::new(Vec::new()));
+        let recovered_c = recovered.clone();
+        p.decode(&mut Cursor::new(data), move |entry, _| {
+            recovered_c.lock().unwrap().push(entry.data);
+        })
+        .unwrap();
+
+        let mut recovered = recovered.lock().unwrap().clone();
+        recovered.sort();
+        assert_eq!(recovered, vec![b"hello".to_vec(), b"hello world".to_vec()]);
+        assert_eq!(
+            p.delta_stats,
+            DeltaStats {
+                base_objects: 1,
+                offset_deltas: 3,
+                ref_deltas: 0,
+                zstd_deltas: 0,
+                max_chain_len: 1,
+                failed: 2,
+            }
+        );
+        assert_eq!(p.delta_failures().len(), 2);
+    }
+
+    #[test]
+    fn test_pack_decode_builds_idx() {
+        let good = [&[0x05, 0x0b, 0x90, 0x05, 0x06][..], b" world"].concat();
+        let data = build_pack(&[
+            (3, b"hello", None),
+            (6, &good, Some(0)),
+            (3, b"other", None),
+        ]);
+        let mut p = Pack::new(Some(2), None, Some(PathBuf::from("/tmp/.cache_temp")), true);
+        p.set_build_idx(true);
+        p.decode(&mut Cursor::new(data.clone()), |_, _| {}).unwrap();
+        let idx = p.idx().unwrap().to_vec();
+
+        let filter = ObjectFilter::new(3, 0.01);
+        filter.add_idx(&idx).unwrap();
+        let hello = SHA1::from_type_and_data(ObjectType::Blob, b"hello");
+        let world = SHA1::from_type_and_data(ObjectType::Blob, b"hello world");
+        assert!(filter.may_contain(&hello) && filter.may_contain(&world));
+        assert_eq!(&idx[idx.len() - 40..idx.len() - 20], &p.signature.0);
+
+        // Each CRC32 covers the raw entry, up to the next entry or the pack trailer
+        let (crcs, offsets) = (8 + 1024 + 3 * 20, 8 + 1024 + 3 * 24);
+        let mut entries: Vec<(usize, u32)> = (0..3)
+            .map(|i| {
+                let word = |start: usize| {
+                    u32::from_be_bytes(idx[start + i * 4..start + i * 4 + 4].try_into().unwrap())
+                };
+                (word(offsets) as usize, word(crcs))
+            })
+            .collect();
+        entries.sort();
+        assert_eq!(entries[0].0, 12);
+        let ends: Vec<usize> = entries[1..]
+            .iter()
+            .map(|e| e.0)
+            .chain([data.len() - 20])
+            .collect();
+        for ((offset, crc32), end) in entries.into_iter().zip(ends) {
+            let mut crc = flate2::Crc::new();
+            crc.update(&data[offset..end]);
+            assert_eq!(crc.sum(), crc32);
+        }
+
+        p.set_build_idx(false);
+        p.decode(&mut Cursor::new(data), |_, _| {}).unwrap();
+        assert!(p.idx().is_none());
+    }
+
+    #[test]
+    fn test_pack_decode_large_object_sink() {
+        let large = b"large content ".repeat(100);
+        let hello = SHA1::from_type_and_data(ObjectType::Blob, b"hello");
+        // base size 5, result size 11: copy "hello", insert " world"
+        let world = [&[0x05, 0x0b, 0x90, 0x05, 0x06][..], b" world"].concat();
+        let data = build_pack(&[(3, &large, None), (3, b"hello", None), (6, &world, Some(1))]);
+
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let store = stored.clone();
+        let sink: LargeObjectSink = Arc::new(move |obj_type, size, content| {
+            let mut data = Vec::new();
+            content.read_to_end(&mut data)?;
+            assert_eq!(size, data.len());
+            let id = SHA1::from_type_and_data(obj_type, &data);
+            store.lock().unwrap().push(data);
+            Ok(id)
+        });
+        let decoded = Arc::new(Mutex::new(Vec::new()));
+        let callback_decoded = decoded.clone();
+        let mut p = Pack::new(Some(2), None, Some(PathBuf::from("/tmp/.cache_temp")), true);
+        p.set_build_idx(true);
+        p.set_large_object_sink(Some((100, sink)));
+        p.decode(&mut Cursor::new(data.clone()), move |entry, _| {
+            callback_decoded.lock().unwrap().push(entry.data)
+        })
+        .unwrap();
+        let mut decoded = decoded.lock().unwrap().clone();
+        decoded.sort();
+        assert_eq!(decoded, [&b"hello"[..], b"hello world"]);
+        assert_eq!(*stored.lock().unwrap(), std::slice::from_ref(&large));
+        assert_eq!(
+            p.streamed_objects(),
+            [SHA1::from_type_and_data(ObjectType::Blob, &large)]
+        );
+        assert!(p.idx().is_some());
+        assert_eq!(p.delta_stats.base_objects, 2);
+
+        // The sink must store the object under its id
+        p.set_large_object_sink(Some((100, Arc::new(move |_, _, _| Ok(hello)))));
+        assert!(matches!(
+            p.decode(&mut Cursor::new(data), |_, _| {}),
+            Err(GitError::InvalidPackFile(_))
+        ));
+    }
+
+    #[test]
+    fn test_pack_decode_thin() {
+        let hello = SHA1::from_type_and_data(ObjectType::Blob, b"hello");
+        let world = [&[0x05, 0x0b, 0x90, 0x05, 0x06][..], b" world"].concat();
+        let thin = [&hello.0[..], &world].concat();
+        // base size 11, result size 12: copy "hello world", insert "!"
+        let bang = [0x0b, 0x0c, 0x90, 0x0b, 0x01, b'!'];
+        let data = build_pack(&[(3, b"other", None), (7, &thin, None), (6, &bang, Some(1))]);
+
+        let decoded = Arc::new(Mutex::new(Vec::new()));
+        let sink = decoded.clone();
+        let mut p = Pack::new(Some(2), None, Some(PathBuf::from("/tmp/.cache_temp")), true);
+        p.set_thin(true);
+        p.decode(&mut Cursor::new(data.clone()), move |entry, _| {
+            sink.lock().unwrap().push(entry.data)
+        })
+        .unwrap();
+        assert_eq!(p.missing_bases(), [hello]);
+        assert_eq!(decoded.lock().unwrap().len(), 1);
+        p.resolve_thin([(ObjectType::Blob, b"hello".to_vec())]).unwrap();
+        let mut decoded = decoded.lock().unwrap().clone();
+        decoded.sort();
+        assert_eq!(decoded, [&b"hello world"[..], b"hello world!", b"other"]);
+        assert!(p.missing_bases().is_empty());
+
+        // Without the base the deltas are reported as unresolved
+        p.decode(&mut Cursor::new(data.clone()), |_, _| {}).unwrap();
+        assert!(matches!(p.resolve_thin([]), Err(GitError::DeltaObjectError(_))));
+        assert_eq!(p.delta_failures().len(), 2);
+
+        p.set_thin(false);
+        assert!(p.decode(&mut Cursor::new(data), |_, _| {}).is_err());
+    }
+
     #[test]
     fn test_pack_decode_without_delta() {
         let mut source = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -0,0 +1,75 @@
+//! Delta statistics and diagnostics collected while decoding a pack.
+//!
+//! When a delta object can't be rebuilt, [`Pack::decode`](super::Pack::decode) records a
+//! [`DeltaFailure`] describing the entry instead of aborting the whole decode thread, so callers
+//! get the offset, the base it referred to and its position in the delta chain. In salvage mode
+//! (see [`Pack::set_salvage`](super::Pack::set_salvage)) every resolvable object is still handed
+//! to the callback and the failures are only reported afterwards.
+use std::fmt::{self, Display};
+
+use crate::hash::SHA1;
+use crate::internal::object::types::ObjectType;
+
+/// How a delta entry refers to its base object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeltaBase {
+    /// Base located at this offset in the same pack (`ofs-delta` / zstd delta).
+    Offset(usize),
+    /// Base named by its object id (`ref-delta`).
+    Hash(SHA1),
+}
+
+/// A delta entry that could not be resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeltaFailure {
+    /// Offset of the delta entry in the pack.
+    pub offset: usize,
+    /// Entry type: [`ObjectType::OffsetDelta`], [`ObjectType::OffsetZstdelta`] or [`ObjectType::HashDelta`].
+    pub obj_type: ObjectType,
+    /// The base object the delta refers to.
+    pub base: DeltaBase,
+    /// Object id of the base, when the base itself was resolved.
+    pub base_hash: Option<SHA1>,
+    /// Depth of this entry in its delta chain (1 = delta on a plain object), when known.
+    pub chain_position: Option<usize>,
+    /// Why the entry could not be resolved.
+    pub reason: String,
+}
+
+impl Display for DeltaFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at offset {}", self.obj_type, self.offset)?;
+        match self.base {
+            DeltaBase::Offset(offset) => write!(f, ", base offset {offset}")?,
+            DeltaBase::Hash(hash) => write!(f, ", base {hash}")?,
+        }
+        if let (DeltaBase::Offset(_), Some(hash)) = (self.base, self.base_hash) {
+            write!(f, " ({hash})")?;
+        }
+        if let Some(position) = self.chain_position {
+            write!(f, ", chain position {position}")?;
+        }
+        write!(f, ": {}", self.reason)
+    }
+}
+
+/// Counters describing the delta structure of a decoded pack.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeltaStats {
+    /// Undeltified commits, trees, blobs and tags.
+    pub base_objects: usize,
+    pub offset_deltas: usize,
+    pub ref_deltas: usize,
+    pub zstd_deltas: usize,
+    /// Longest delta chain that was rebuilt.
+    pub max_chain_len: usize,
+    /// Number of delta entries that could not be resolved.
+    pub failed: usize,
+}
+
+impl DeltaStats {
+    /// Total number of delta entries in the pack.
+    pub fn deltas(&self) -> usize {
+        self.offset_deltas + self.ref_deltas + self.zstd_deltas
+    }
+}
@@ -1,6 +1,7 @@
 use std::cmp::Ordering;
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::Write;
+use std::sync::{Arc, LazyLock, Mutex};
 
 use crate::delta;
 use crate::zstdelta;
@@ -8,6 +9,7 @@ use crate::internal::object::types::ObjectType;
 use crate::time_it;
 use crate::{errors::GitError, hash::SHA1, internal::pack::entry::Entry};
 use ahash::AHasher;
+use flate2::Compression;
 use flate2::write::ZlibEncoder;
 use rayon::prelude::*;
 use sha1::{Digest, Sha1};
@@ -16,7 +18,16 @@ use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 
 const MAX_CHAIN_LEN: usize = 50;
+/// Number of objects deltified together, in one window, by a worker of the pool
+const DELTA_CHUNK_SIZE: usize = 512;
+/// Objects larger than this are stored whole by default, like git's `core.bigFileThreshold`
+pub const DEFAULT_BIG_FILE_THRESHOLD: usize = 512 * 1024 * 1024;
 const MIN_DELTA_RATE: f64 = 0.5; // minimum delta rate
+
+/// Worker pools of the encoders by number of threads, started by the first encode that needs one
+/// and shared by all later ones
+static THREAD_POOLS: LazyLock<Mutex<HashMap<usize, Arc<rayon::ThreadPool>>>> =
+    LazyLock::new(Default::default);
 //const MAX_ZSTDELTA_CHAIN_LEN: usize = 50;
 
 /// A encoder for generating pack files with delta objects.
@@ -24,17 +35,65 @@ pub struct PackEncoder {
     object_number: usize,
     process_index: usize,
     window_size: usize,
+    /// Longest delta chain a new delta may extend
+    depth: usize,
+    /// Objects larger than this are neither deltified nor used as delta bases
+    big_file_threshold: usize,
     // window: VecDeque<(Entry, usize)>, // entry and offset
     sender: Option<mpsc::Sender<Vec<u8>>>,
     inner_offset: usize, // offset of current entry
     inner_hash: Sha1,    // Not SHA1 because need update trait
     final_hash: Option<SHA1>,
     start_encoding: bool,
+    /// [`name_hash`] of the path each object was found at, see [`PackEncoder::set_name_hashes`]
+    name_hashes: HashMap<SHA1, u32>,
+    /// Deltas of an existing pack to copy, see [`PackEncoder::set_reused_deltas`]
+    reused_deltas: HashMap<SHA1, PackedDelta>,
+    compression: CompressionLevels,
+    /// Size of the worker pool, 0 for one thread per core
+    threads: usize,
+    /// Pool supplied by the caller, see [`PackEncoder::set_thread_pool`]
+    thread_pool: Option<Arc<rayon::ThreadPool>>,
+    /// Refer to delta bases by offset, see [`PackEncoder::set_ofs_delta`]
+    ofs_delta: bool,
+}
+
+/// A delta as stored in an existing pack, which can be copied into a new pack without inflating
+/// it: only its header and base reference are rewritten
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackedDelta {
+    /// Object the delta applies to
+    pub base: SHA1,
+    /// Size of the inflated delta
+    pub size: usize,
+    /// Zlib stream of the delta, as found after its entry header and base reference
+    pub data: Vec<u8>,
+}
+
+/// Delta search settings shared by the buckets of an encode
+#[derive(Clone)]
+struct DeltaSearch {
+    window_size: usize,
+    depth: usize,
+    big_file_threshold: usize,
+    enable_zstdelta: bool,
+    ofs_delta: bool,
+    reused_deltas: Arc<HashMap<SHA1, PackedDelta>>,
+    compression: CompressionLevels,
+}
+
+/// Where a delta entry finds its base
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BaseRef {
+    /// The object this many bytes before the delta, for OFS_DELTA entries
+    Offset(usize),
+    /// The object with this id, for REF_DELTA entries
+    Hash(SHA1),
 }
 
 /// Encode header of pack file (12 byte)<br>
 /// Content: 'PACK', Version(2), number of objects
-fn encode_header(object_number: usize) -> Vec<u8> {
+pub(crate) fn encode_header(object_number: usize) -> Vec<u8> {
     let mut result: Vec<u8> = vec![
         b'P', b'A', b'C', b'K', // The logotype of the Pack File
         0, 0, 0, 2, // generates version 2 only.
@@ -63,17 +122,8 @@ fn encode_offset(mut value: usize) -> Vec<u8> {
     bytes
 }
 
-/// Encode one object, and update the hash
-/// @offset: offset of this object if it's a delta object. For other object, it's None
-fn encode_one_object(entry: &Entry, offset: Option<usize>) -> Result<Vec<u8>, GitError> {
-    // try encode as delta
-    let obj_data = &entry.data;
-    let obj_data_len = obj_data.len();
-    let obj_type_number = entry.obj_type.to_u8();
-
-    let mut encoded_data = Vec::new();
-
-    // **header** encoding
+/// Encode the type and size header of a pack entry
+fn encode_type_and_size(obj_type_number: u8, obj_data_len: usize) -> Vec<u8> {
     let mut header_data = vec![(0x80 | (obj_type_number << 4)) + (obj_data_len & 0x0f) as u8];
     let mut size = obj_data_len >> 4; // 4 bit has been used in first byte
     if size > 0 {
@@ -89,18 +139,56 @@ fn encode_one_object(entry: &Entry, offset: Option<usize>) -> Result<Vec<u8>, Gi
     } else {
         header_data.push(0);
     }
-    encoded_data.extend(header_data);
+    header_data
+}
 
-    // **offset** encoding
-    if entry.obj_type == ObjectType::OffsetDelta || entry.obj_type == ObjectType::OffsetZstdelta {
-        let offset_data = encode_offset(offset.unwrap());
-        encoded_data.extend(offset_data);
-    } else if entry.obj_type == ObjectType::HashDelta {
-        unreachable!("unsupported type")
+/// Encode a reused delta as an offset or ref delta on `base`
+fn encode_reused_delta(delta: &PackedDelta, base: BaseRef) -> Vec<u8> {
+    let obj_type = match base {
+        BaseRef::Offset(_) => ObjectType::OffsetDelta,
+        BaseRef::Hash(_) => ObjectType::HashDelta,
+    };
+    let mut encoded_data = encode_type_and_size(obj_type.to_u8(), delta.size);
+    encode_base(&mut encoded_data, base);
+    encoded_data.extend_from_slice(&delta.data);
+    encoded_data
+}
+
+/// Append the base reference of a delta entry: its offset, or its 20-byte id
+fn encode_base(encoded_data: &mut Vec<u8>, base: BaseRef) {
+    match base {
+        BaseRef::Offset(offset) => encoded_data.extend(encode_offset(offset)),
+        BaseRef::Hash(hash) => encoded_data.extend_from_slice(hash.as_ref()),
+    }
+}
+
+/// Encode one object, and update the hash
+/// @base: base of this object if it's a delta object. For other object, it's None
+pub(crate) fn encode_one_object(
+    entry: &Entry,
+    base: Option<BaseRef>,
+    level: Compression,
+) -> Result<Vec<u8>, GitError> {
+    // try encode as delta
+    let obj_data = &entry.data;
+    let obj_data_len = obj_data.len();
+    let obj_type_number = entry.obj_type.to_u8();
+
+    let mut encoded_data = Vec::new();
+
+    // **header** encoding
+    encoded_data.extend(encode_type_and_size(obj_type_number, obj_data_len));
+
+    // **offset** or base hash encoding
+    if matches!(
+        entry.obj_type,
+        ObjectType::OffsetDelta | ObjectType::OffsetZstdelta | ObjectType::HashDelta
+    ) {
+        encode_base(&mut encoded_data, base.expect("delta entries have a base"));
     }
 
     // **data** encoding, need zlib compress
-    let mut inflate = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    let mut inflate = ZlibEncoder::new(Vec::new(), level);
     inflate
         .write_all(obj_data)
         .expect("zlib compress should never failed");
@@ -111,16 +199,94 @@ fn encode_one_object(entry: &Entry, offset: Option<usize>) -> Result<Vec<u8>, Gi
     Ok(encoded_data)
 }
 
-fn magic_sort(a: &Entry, b: &Entry) -> Ordering {
+/// Whether `data` starts like a compressed archive, image, audio or video file, which zlib can't
+/// shrink any further
+fn is_compressed(data: &[u8]) -> bool {
+    const MAGIC: &[&[u8]] = &[
+        b"\x1f\x8b",           // gzip
+        b"PK\x03\x04",         // zip, jar, docx...
+        b"\x28\xb5\x2f\xfd",   // zstd
+        b"\xfd7zXZ\x00",       // xz
+        b"BZh",                // bzip2
+        b"7z\xbc\xaf\x27\x1c", // 7z
+        b"\x89PNG",            // png
+        b"\xff\xd8\xff",       // jpeg
+        b"GIF8",               // gif
+        b"OggS",               // ogg
+        b"ID3",                // mp3
+    ];
+    MAGIC.iter().any(|magic| data.starts_with(magic))
+        || (data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP"))
+        || data.get(4..8) == Some(b"ftyp") // mp4, mov, heic
+}
+
+/// zlib levels of the objects of a pack
+#[derive(Debug, Clone, Copy)]
+struct CompressionLevels {
+    default: Compression,
+    /// Level of blobs whose content is already compressed, see [`is_compressed`]
+    compressed_blobs: Option<Compression>,
+}
+
+impl CompressionLevels {
+    /// The level to compress `entry` with, checked before it is deltified
+    fn for_entry(&self, entry: &Entry) -> Compression {
+        match self.compressed_blobs {
+            Some(level) if entry.obj_type == ObjectType::Blob && is_compressed(&entry.data) => {
+                level
+            }
+            _ => self.default,
+        }
+    }
+}
+
+/// Split a sorted bucket into the chunks the workers deltify independently
+///
+/// As in git's threaded delta search, a chunk grows past [`DELTA_CHUNK_SIZE`] until the path
+/// changes, so the versions of one file share a window instead of losing their deltas at the
+/// edge of two chunks.
+fn into_chunks(bucket: Vec<Entry>, name_hashes: &HashMap<SHA1, u32>) -> Vec<Vec<Entry>> {
+    let name_hash = |e: &Entry| name_hashes.get(&e.hash).copied().unwrap_or(0);
+    let mut chunks = Vec::new();
+    let mut chunk: Vec<Entry> = Vec::with_capacity(DELTA_CHUNK_SIZE);
+    for entry in bucket {
+        if chunk.len() >= DELTA_CHUNK_SIZE {
+            let last = name_hash(&chunk[chunk.len() - 1]);
+            if last == 0 || last != name_hash(&entry) {
+                chunks.push(std::mem::replace(
+                    &mut chunk,
+                    Vec::with_capacity(DELTA_CHUNK_SIZE),
+                ));
+            }
+        }
+        chunk.push(entry);
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// Hash of an object's path, like git's `pack_name_hash`: the last characters weigh the most,
+/// so objects with the same file name or extension sort next to each other
+pub fn name_hash(name: &str) -> u32 {
+    name.bytes()
+        .filter(|c| !c.is_ascii_whitespace())
+        .fold(0u32, |hash, c| (hash >> 2).wrapping_add((c as u32) << 24))
+}
+
+fn magic_sort(a: &Entry, b: &Entry, name_hashes: &HashMap<SHA1, u32>) -> Ordering {
     // let ord = b.obj_type.to_u8().cmp(&a.obj_type.to_u8());
     // if ord != Ordering::Equal {
     //     return ord;
     // }
 
-    // the hash should be file hash not content hash
-    // todo the feature need larger refactor
-    // let ord = b.hash.cmp(&a.hash);
-    // if ord != Ordering::Equal { return ord; }
+    // versions of the same path are the best delta bases for each other
+    let name_hash = |e: &Entry| name_hashes.get(&e.hash).copied().unwrap_or(0);
+    let ord = name_hash(a).cmp(&name_hash(b));
+    if ord != Ordering::Equal {
+        return ord;
+    }
 
     let ord = b.data.len().cmp(&a.data.len());
     if ord != Ordering::Equal {
@@ -150,6 +316,8 @@ impl PackEncoder {
         PackEncoder {
             object_number,
             window_size,
+            depth: MAX_CHAIN_LEN,
+            big_file_threshold: DEFAULT_BIG_FILE_THRESHOLD,
             process_index: 0,
             // window: VecDeque::with_capacity(window_size),
             sender: Some(sender),
@@ -157,7 +325,87 @@ impl PackEncoder {
             inner_hash: Sha1::new(),
             final_hash: None,
             start_encoding: false,
+            name_hashes: HashMap::new(),
+            reused_deltas: HashMap::new(),
+            compression: CompressionLevels {
+                default: Compression::default(),
+                compressed_blobs: None,
+            },
+            threads: 0,
+            thread_pool: None,
+            ofs_delta: true,
+        }
+    }
+
+    /// Refer to delta bases by their offset in the pack (OFS_DELTA, the default), or by their
+    /// id (REF_DELTA) for readers without the `ofs-delta` capability
+    pub fn set_ofs_delta(&mut self, ofs_delta: bool) {
+        self.ofs_delta = ofs_delta;
+    }
+
+    /// Set the number of threads that deltify and compress objects, 0 (the default) for one per
+    /// core. The pack is the same whatever the number.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads;
+    }
+
+    /// Deltify and compress objects on `pool` instead of the pool shared by the encoders of
+    /// the same number of threads, `set_threads` is then ignored
+    pub fn set_thread_pool(&mut self, pool: Arc<rayon::ThreadPool>) {
+        self.thread_pool = Some(pool);
+    }
+
+    /// Worker pool of the encode
+    fn thread_pool(&self) -> Result<Arc<rayon::ThreadPool>, GitError> {
+        if let Some(pool) = &self.thread_pool {
+            return Ok(pool.clone());
         }
+        let mut pools = THREAD_POOLS.lock().unwrap();
+        if let Some(pool) = pools.get(&self.threads) {
+            return Ok(pool.clone());
+        }
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()
+            .map_err(|e| GitError::PackEncodeError(format!("Failed to start workers: {e}")))?;
+        let pool = Arc::new(pool);
+        pools.insert(self.threads, pool.clone());
+        Ok(pool)
+    }
+
+    /// Set the zlib level of the objects, from 0 (stored as-is, fastest) to 9 (smallest); higher
+    /// values count as 9. Defaults to zlib's own default of 6.
+    pub fn set_compression_level(&mut self, level: u32) {
+        self.compression.default = Compression::new(level.min(9));
+    }
+
+    /// Set the zlib level of blobs whose content is already compressed, like archives and
+    /// images, which recompressing hardly shrinks. `None` uses the level of other objects.
+    pub fn set_compressed_blob_level(&mut self, level: Option<u32>) {
+        self.compression.compressed_blobs = level.map(|level| Compression::new(level.min(9)));
+    }
+
+    /// Set the longest delta chain of the pack, 0 stores every object whole. Shallow chains are
+    /// cheaper to read back, deep ones make smaller packs. Defaults to 50.
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+    }
+
+    /// Set the size above which objects are stored whole, see [`DEFAULT_BIG_FILE_THRESHOLD`]
+    pub fn set_big_file_threshold(&mut self, big_file_threshold: usize) {
+        self.big_file_threshold = big_file_threshold;
+    }
+
+    /// Set the [`name_hash`] of the path of objects, candidates are then sorted by path before
+    /// size so that the delta window holds earlier versions of the same file
+    pub fn set_name_hashes(&mut self, name_hashes: HashMap<SHA1, u32>) {
+        self.name_hashes = name_hashes;
+    }
+
+    /// Offer deltas of existing packs to copy as-is. A delta is reused when its base is in the
+    /// new pack too and the chain stays within the depth, objects are deltified again otherwise.
+    pub fn set_reused_deltas(&mut self, reused_deltas: HashMap<SHA1, PackedDelta>) {
+        self.reused_deltas = reused_deltas;
     }
 
     pub fn drop_sender(&mut self) {
@@ -233,10 +481,11 @@ impl PackEncoder {
             }
         }
 
-        commits.sort_by(magic_sort);
-        trees.sort_by(magic_sort);
-        blobs.sort_by(magic_sort);
-        tags.sort_by(magic_sort);
+        let name_hashes = &self.name_hashes;
+        commits.sort_by(|a, b| magic_sort(a, b, name_hashes));
+        trees.sort_by(|a, b| magic_sort(a, b, name_hashes));
+        blobs.sort_by(|a, b| magic_sort(a, b, name_hashes));
+        tags.sort_by(|a, b| magic_sort(a, b, name_hashes));
         tracing::info!(
             "numbers :  commits: {:?} trees: {:?} blobs:{:?} tag :{:?}",
             commits.len(),
@@ -245,33 +494,37 @@ impl PackEncoder {
             tags.len()
         );
 
-        // parallel encoding vec with different object_type
-        let (commit_results, tree_results, blob_results, tag_results) = tokio::try_join!(
-            tokio::task::spawn_blocking(move || {
-                Self::try_as_offset_delta(commits, 10, enable_zstdelta)
-            }),
-            tokio::task::spawn_blocking(move || {
-                Self::try_as_offset_delta(trees, 10, enable_zstdelta)
-            }),
-            tokio::task::spawn_blocking(move || {
-                Self::try_as_offset_delta(blobs, 10, enable_zstdelta)
-            }),
-            tokio::task::spawn_blocking(move || {
-                Self::try_as_offset_delta(tags, 10, enable_zstdelta)
-            }),
-        )
-        .map_err(|e| GitError::PackEncodeError(format!("Task join error: {e}")))?;
-
-        let all_encoded_data = [
-            commit_results
-                .map_err(|e| GitError::PackEncodeError(format!("Commit encoding error: {e}")))?,
-            tree_results
-                .map_err(|e| GitError::PackEncodeError(format!("Tree encoding error: {e}")))?,
-            blob_results
-                .map_err(|e| GitError::PackEncodeError(format!("Blob encoding error: {e}")))?,
-            tag_results
-                .map_err(|e| GitError::PackEncodeError(format!("Tag encoding error: {e}")))?,
-        ]
+        // deltify and compress chunks of each type on the worker pool, each with its own window;
+        // the chunks don't depend on the number of threads, so neither does the pack
+        let search = DeltaSearch {
+            window_size: self.window_size,
+            depth: self.depth,
+            big_file_threshold: self.big_file_threshold,
+            enable_zstdelta,
+            ofs_delta: self.ofs_delta,
+            reused_deltas: Arc::new(std::mem::take(&mut self.reused_deltas)),
+            compression: self.compression,
+        };
+        let chunks: Vec<Vec<Entry>> = [commits, trees, blobs, tags]
+            .into_iter()
+            .flat_map(|bucket| into_chunks(bucket, name_hashes))
+            .collect();
+        let pool = self.thread_pool()?;
+        let all_encoded_data = tokio::task::spawn_blocking(move || {
+            pool.install(|| {
+                chunks
+                    .into_par_iter()
+                    .map(|chunk| {
+                        let obj_type = chunk[0].obj_type;
+                        Self::try_as_offset_delta(chunk, &search).map_err(|e| {
+                            GitError::PackEncodeError(format!("{obj_type} encoding error: {e}"))
+                        })
+                    })
+                    .collect::<Result<Vec<_>, GitError>>()
+            })
+        })
+        .await
+        .map_err(|e| GitError::PackEncodeError(format!("Task join error: {e}")))??
         .concat();
 
         for data in all_encoded_data {
@@ -294,118 +547,196 @@ impl PackEncoder {
     /// # Returns
     /// - Return (Vec<Vec<u8>) if success make delta
     /// - Return (None) if didn't delta,
+    ///
+    /// Reused deltas are copied once their base has been written, when it is in the bucket.
     fn try_as_offset_delta(
-        mut bucket: Vec<Entry>,
-        window_size: usize,
-        enable_zstdelta: bool,
+        bucket: Vec<Entry>,
+        search: &DeltaSearch,
     ) -> Result<Vec<Vec<u8>>, GitError> {
+        let DeltaSearch {
+            window_size,
+            depth,
+            big_file_threshold,
+            ..
+        } = *search;
         let mut current_offset = 0usize;
         let mut window: VecDeque<(Entry, usize)> = VecDeque::with_capacity(window_size);
         let mut res: Vec<Vec<u8>> = Vec::new();
 
-        for entry in bucket.iter_mut() {
-            //let entry_for_window = entry.clone();
-            // 每次循环重置最佳基对象选择
-            let mut best_base: Option<&(Entry, usize)> = None;
-            let mut best_rate: f64 = 0.0;
-            let tie_epsilon: f64 = 0.15;
-
-            let candidates: Vec<_> = window
-                .par_iter()
-                .with_min_len(3)
-                .filter_map(|try_base| {
-                    if try_base.0.obj_type != entry.obj_type {
-                        return None;
-                    }
+        let in_bucket: HashSet<SHA1> = bucket.iter().map(|e| e.hash).collect();
+        // offset and delta chain length of the objects written so far
+        let mut written: HashMap<SHA1, (usize, usize)> = HashMap::new();
+        // reused deltas waiting for their base, and those whose base just got written
+        let mut waiting: HashMap<SHA1, Vec<Entry>> = HashMap::new();
+        let mut ready: Vec<Entry> = Vec::new();
+        let mut bucket = bucket.into_iter();
 
-                    if try_base.0.chain_len >= MAX_CHAIN_LEN {
-                        return None;
-                    }
+        loop {
+            let (mut entry, reuse) = match ready.pop().or_else(|| bucket.next()) {
+                Some(entry) => (entry, true),
+                // the rest wait on each other, break the cycle by searching a base for one
+                None => match waiting.values_mut().find_map(|entries| entries.pop()) {
+                    Some(entry) => (entry, false),
+                    None => break,
+                },
+            };
+            let reused = search
+                .reused_deltas
+                .get(&entry.hash)
+                .filter(|reused| reuse && in_bucket.contains(&reused.base));
+            if let Some(reused) = reused
+                && !written.contains_key(&reused.base)
+            {
+                waiting.entry(reused.base).or_default().push(entry);
+                continue;
+            }
+            let reused = reused
+                .map(|reused| (reused, written[&reused.base]))
+                .filter(|(_, (_, base_chain_len))| *base_chain_len < depth);
+
+            let hash = entry.hash;
+            let (obj_data, entry_for_window) = if entry.data.len() > big_file_threshold {
+                // big objects are stored whole and kept out of the window
+                let level = search.compression.for_entry(&entry);
+                (encode_one_object(&entry, None, level)?, None)
+            } else if let Some((reused, (base_offset, base_chain_len))) = reused {
+                entry.chain_len = base_chain_len + 1;
+                let base = match search.ofs_delta {
+                    true => BaseRef::Offset(current_offset - base_offset),
+                    false => BaseRef::Hash(reused.base),
+                };
+                (encode_reused_delta(reused, base), Some(entry))
+            } else {
+                let (obj_data, entry_for_window) =
+                    Self::search_delta(&mut entry, &window, current_offset, search)?;
+                (obj_data, Some(entry_for_window))
+            };
+
+            let chain_len = entry_for_window.as_ref().map_or(0, |e| e.chain_len);
+            written.insert(hash, (current_offset, chain_len));
+            if let Some(entry_for_window) = entry_for_window {
+                window.push_back((entry_for_window, current_offset));
+                if window.len() > window_size {
+                    window.pop_front();
+                }
+            }
+            ready.extend(waiting.remove(&hash).unwrap_or_default());
+            current_offset += obj_data.len();
+            res.push(obj_data);
+        }
+        Ok(res)
+    }
 
-                    if try_base.0.hash == entry.hash {
-                        return None;
-                    }
+    /// Deltify `entry` against the best base of the window and encode it, returns the encoded
+    /// entry and what to keep of it in the window
+    fn search_delta(
+        entry: &mut Entry,
+        window: &VecDeque<(Entry, usize)>,
+        current_offset: usize,
+        search: &DeltaSearch,
+    ) -> Result<(Vec<u8>, Entry), GitError> {
+        let (depth, enable_zstdelta) = (search.depth, search.enable_zstdelta);
+        let level = search.compression.for_entry(entry);
+        //let entry_for_window = entry.clone();
+        // 每次循环重置最佳基对象选择
+        let mut best_base: Option<&(Entry, usize)> = None;
+        let mut best_rate: f64 = 0.0;
+        let tie_epsilon: f64 = 0.15;
+
+        let candidates: Vec<_> = window
+            .par_iter()
+            .with_min_len(3)
+            .filter_map(|try_base| {
+                if try_base.0.obj_type != entry.obj_type {
+                    return None;
+                }
 
-                    let sym_ratio = (try_base.0.data.len().min(entry.data.len()) as f64)
-                        / (try_base.0.data.len().max(entry.data.len()) as f64);
-                    if sym_ratio < 0.5 {
-                        return None;
-                    }
+                if try_base.0.chain_len >= depth {
+                    return None;
+                }
 
-                    if !cheap_similar(&try_base.0.data, &entry.data) {
-                        return None;
-                    }
+                if try_base.0.hash == entry.hash {
+                    return None;
+                }
 
-                    let rate = if (try_base.0.data.len() + entry.data.len()) / 2 > 64 {
-                        delta::heuristic_encode_rate_parallel(&try_base.0.data, &entry.data)
-                    } else {
-                        delta::encode_rate(&try_base.0.data, &entry.data)
-                        // let try_delta_obj = zstdelta::diff(&try_base.0.data, &entry.data).unwrap();
-                        // 1.0 - try_delta_obj.len() as f64 / entry.data.len() as f64
-                    };
+                let sym_ratio = (try_base.0.data.len().min(entry.data.len()) as f64)
+                    / (try_base.0.data.len().max(entry.data.len()) as f64);
+                if sym_ratio < 0.5 {
+                    return None;
+                }
+
+                if !cheap_similar(&try_base.0.data, &entry.data) {
+                    return None;
+                }
+
+                let rate = if (try_base.0.data.len() + entry.data.len()) / 2 > 64 {
+                    delta::heuristic_encode_rate_parallel(&try_base.0.data, &entry.data)
+                } else {
+                    delta::encode_rate(&try_base.0.data, &entry.data)
+                    // let try_delta_obj = zstdelta::diff(&try_base.0.data, &entry.data).unwrap();
+                    // 1.0 - try_delta_obj.len() as f64 / entry.data.len() as f64
+                };
 
-                    if rate > MIN_DELTA_RATE {
-                        Some((rate, try_base))
+                if rate > MIN_DELTA_RATE {
+                    Some((rate, try_base))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        for (rate, try_base) in candidates {
+            match best_base {
+                None => {
+                    best_rate = rate;
+                    //best_base_offset = current_offset - try_base.1;
+                    best_base = Some(try_base);
+                }
+                Some(best_base_ref) => {
+                    let is_better = if rate > best_rate + tie_epsilon {
+                        true
+                    } else if (rate - best_rate).abs() <= tie_epsilon {
+                        try_base.0.chain_len > best_base_ref.0.chain_len
                     } else {
-                        None
-                    }
-                })
-                .collect();
+                        false
+                    };
 
-            for (rate, try_base) in candidates {
-                match best_base {
-                    None => {
+                    if is_better {
                         best_rate = rate;
-                        //best_base_offset = current_offset - try_base.1;
                         best_base = Some(try_base);
                     }
-                    Some(best_base_ref) => {
-                        let is_better = if rate > best_rate + tie_epsilon {
-                            true
-                        } else if (rate - best_rate).abs() <= tie_epsilon {
-                            try_base.0.chain_len > best_base_ref.0.chain_len
-                        } else {
-                            false
-                        };
-
-                        if is_better {
-                            best_rate = rate;
-                            best_base = Some(try_base);
-                        }
-                    }
                 }
             }
+        }
 
-            let mut entry_for_window = entry.clone();
-
-            let offset = best_base.map(|best_base| {
-                let delta = if enable_zstdelta {
-                    entry.obj_type = ObjectType::OffsetZstdelta;
-                    zstdelta::diff(&best_base.0.data, &entry.data)
-                        .map_err(|e| {
-                            GitError::DeltaObjectError(format!("zstdelta diff failed: {e}"))
-                        })
-                        .unwrap()
-                } else {
-                    entry.obj_type = ObjectType::OffsetDelta;
-                    delta::encode(&best_base.0.data, &entry.data)
-                };
-                //entry.obj_type = ObjectType::OffsetDelta;
-                entry.data = delta;
-                entry.chain_len = best_base.0.chain_len + 1;
-                current_offset - best_base.1
-            });
-
-            entry_for_window.chain_len = entry.chain_len;
-            let obj_data = encode_one_object(entry, offset)?;
-            window.push_back((entry_for_window, current_offset));
-            if window.len() > window_size {
-                window.pop_front();
+        let mut entry_for_window = entry.clone();
+
+        let base = best_base.map(|best_base| {
+            // zstdelta has no ref variant, readers without ofs-delta get plain deltas
+            let delta = if !search.ofs_delta {
+                entry.obj_type = ObjectType::HashDelta;
+                delta::encode(&best_base.0.data, &entry.data)
+            } else if enable_zstdelta {
+                entry.obj_type = ObjectType::OffsetZstdelta;
+                zstdelta::diff(&best_base.0.data, &entry.data)
+                    .map_err(|e| GitError::DeltaObjectError(format!("zstdelta diff failed: {e}")))
+                    .unwrap()
+            } else {
+                entry.obj_type = ObjectType::OffsetDelta;
+                delta::encode(&best_base.0.data, &entry.data)
+            };
+            //entry.obj_type = ObjectType::OffsetDelta;
+            entry.data = delta;
+            entry.chain_len = best_base.0.chain_len + 1;
+            match search.ofs_delta {
+                true => BaseRef::Offset(current_offset - best_base.1),
+                false => BaseRef::Hash(best_base.0.hash),
             }
-            current_offset += obj_data.len();
-            res.push(obj_data);
-        }
-        Ok(res)
+        });
+
+        entry_for_window.chain_len = entry.chain_len;
+        let obj_data = encode_one_object(entry, base, level)?;
+        Ok((obj_data, entry_for_window))
     }
 
     /// Parallel encode with rayon, only works when window_size == 0 (no delta)
@@ -430,6 +761,7 @@ impl PackEncoder {
             ));
         }
 
+        let pool = self.thread_pool()?;
         let batch_size = usize::max(1000, entry_rx.max_capacity() / 10); // A temporary value, not optimized
         tracing::info!("encode with batch size: {}", batch_size);
         loop {
@@ -451,11 +783,17 @@ impl PackEncoder {
             }
 
             // use `collect` will return result in order, refs: https://github.com/rayon-rs/rayon/issues/551#issuecomment-371657900
+            let compression = self.compression;
             let batch_result: Vec<Vec<u8>> = time_it!("parallel encode: encode batch", {
-                batch_entries
-                    .par_iter()
-                    .map(|entry| encode_one_object(entry, None).unwrap())
-                    .collect()
+                pool.install(|| {
+                    batch_entries
+                        .par_iter()
+                        .map(|entry| {
+                            let level = compression.for_entry(entry);
+                            encode_one_object(entry, None, level).unwrap()
+                        })
+                        .collect()
+                })
             });
 
             time_it!("parallel encode: write batch", {
@@ -580,6 +918,165 @@ mod tests {
         check_format(&pack_with_delta);
     }
 
+    #[test]
+    fn test_is_compressed() {
+        assert!(is_compressed(b"\x1f\x8b\x08\x00"));
+        assert!(is_compressed(b"\x89PNG\r\n\x1a\n"));
+        assert!(is_compressed(b"RIFF\0\0\0\0WEBPVP8 "));
+        assert!(is_compressed(b"\0\0\0\x18ftypmp42"));
+        assert!(!is_compressed(b"RIFF\0\0\0\0WAVEfmt "));
+        assert!(!is_compressed(b"fn main() {}"));
+        assert!(!is_compressed(b""));
+    }
+
+    #[tokio::test]
+    async fn test_pack_encoder_compression_levels() {
+        async fn encode_once(blobs: &[Blob], level: u32, compressed_level: Option<u32>) -> usize {
+            let (tx, mut rx) = mpsc::channel(100);
+            let (entry_tx, entry_rx) = mpsc::channel::<Entry>(blobs.len());
+            let mut encoder = PackEncoder::new(blobs.len(), 0, tx);
+            encoder.set_compression_level(level);
+            encoder.set_compressed_blob_level(compressed_level);
+            for blob in blobs {
+                entry_tx.send(blob.clone().into()).await.unwrap();
+            }
+            drop(entry_tx);
+            encoder.encode_async(entry_rx).await.unwrap();
+            let mut result = Vec::new();
+            while let Some(chunk) = rx.recv().await {
+                result.extend(chunk);
+            }
+            check_format(&result);
+            result.len()
+        }
+
+        let text = Blob::from_content(&"a very compressible line\n".repeat(100));
+        let gzip = Blob::from_content_bytes([&b"\x1f\x8b"[..], &text.data].concat());
+        let best = encode_once(std::slice::from_ref(&text), 9, None).await;
+        assert!(encode_once(std::slice::from_ref(&text), 0, None).await > best);
+
+        // Only the blob that looks compressed is stored as-is
+        let both = [text.clone(), gzip];
+        let compressed_stored = encode_once(&both, 9, Some(0)).await;
+        assert!(compressed_stored > encode_once(&both, 9, None).await);
+        assert!(compressed_stored < encode_once(&both, 0, None).await);
+    }
+
+    #[tokio::test]
+    async fn test_pack_encoder_threads() {
+        async fn encode_once(blobs: &[Blob], threads: usize) -> Vec<u8> {
+            let (tx, mut rx) = mpsc::channel(100);
+            let (entry_tx, entry_rx) = mpsc::channel::<Entry>(blobs.len());
+            let mut encoder = PackEncoder::new(blobs.len(), 10, tx);
+            encoder.set_threads(threads);
+            for blob in blobs {
+                entry_tx.send(blob.clone().into()).await.unwrap();
+            }
+            drop(entry_tx);
+            encoder.encode_async(entry_rx).await.unwrap();
+            let mut result = Vec::new();
+            while let Some(chunk) = rx.recv().await {
+                result.extend(chunk);
+            }
+            result
+        }
+
+        // More blobs than one chunk holds, so several chunks are deltified at once
+        let text = "a line shared by every blob\n".repeat(20);
+        let blobs: Vec<Blob> = (0..DELTA_CHUNK_SIZE * 2 + 100)
+            .map(|i| Blob::from_content(&format!("{text}{i}\n")))
+            .collect();
+        let single = encode_once(&blobs, 1).await;
+        assert_eq!(single, encode_once(&blobs, 4).await);
+        check_format(&single);
+    }
+
+    #[test]
+    fn test_into_chunks() {
+        let entries: Vec<Entry> = (0..DELTA_CHUNK_SIZE + 100)
+            .map(|i| Blob::from_content(&format!("blob {i}")).into())
+            .collect();
+        let sizes = |chunks: Vec<Vec<Entry>>| chunks.iter().map(Vec::len).collect::<Vec<_>>();
+        let no_paths = HashMap::new();
+        assert_eq!(
+            sizes(into_chunks(entries.clone(), &no_paths)),
+            [DELTA_CHUNK_SIZE, 100]
+        );
+
+        // The versions of a file stay in one chunk, the next file starts a new one
+        let split = DELTA_CHUNK_SIZE + 20;
+        let name_hashes: HashMap<SHA1, u32> = entries
+            .iter()
+            .enumerate()
+            .map(|(i, e)| (e.hash, if i < split { 1 } else { 2 }))
+            .collect();
+        assert_eq!(sizes(into_chunks(entries, &name_hashes)), [split, 80]);
+    }
+
+    #[test]
+    fn test_thread_pool_is_shared() {
+        let (tx, _rx) = mpsc::channel(1);
+        let mut first = PackEncoder::new(1, 10, tx.clone());
+        let mut second = PackEncoder::new(1, 10, tx.clone());
+        first.set_threads(3);
+        second.set_threads(3);
+        let pool = first.thread_pool().unwrap();
+        assert!(Arc::ptr_eq(&pool, &second.thread_pool().unwrap()));
+        assert_eq!(pool.current_num_threads(), 3);
+
+        let own = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+        second.set_thread_pool(own.clone());
+        assert!(Arc::ptr_eq(&own, &second.thread_pool().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_pack_encoder_reused_delta_cycle() {
+        let text = "some line\n".repeat(30);
+        let first: Entry = Blob::from_content(&text).into();
+        let second: Entry = Blob::from_content(&format!("{text}one more\n")).into();
+        let packed = |base: &Entry, target: &Entry| {
+            let delta = delta::encode(&base.data, &target.data);
+            let mut zlib = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            zlib.write_all(&delta).unwrap();
+            PackedDelta {
+                base: base.hash,
+                size: delta.len(),
+                data: zlib.finish().unwrap(),
+            }
+        };
+
+        // Each blob is offered as a delta on the other, one of them has to be stored whole
+        let (tx, mut rx) = mpsc::channel(100);
+        let (entry_tx, entry_rx) = mpsc::channel::<Entry>(2);
+        let mut encoder = PackEncoder::new(2, 0, tx);
+        encoder.set_reused_deltas(HashMap::from([
+            (first.hash, packed(&second, &first)),
+            (second.hash, packed(&first, &second)),
+        ]));
+        entry_tx.send(first.clone()).await.unwrap();
+        entry_tx.send(second.clone()).await.unwrap();
+        drop(entry_tx);
+        encoder.encode(entry_rx).await.unwrap();
+        let mut result = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            result.extend(chunk);
+        }
+
+        let mut p = Pack::new(None, None, None, true);
+        let decoded = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = decoded.clone();
+        p.decode(&mut Cursor::new(result), move |entry, _| {
+            sink.lock().unwrap().push(entry.hash)
+        })
+        .unwrap();
+        assert_eq!(p.delta_stats.offset_deltas, 1);
+        let mut decoded = decoded.lock().unwrap().clone();
+        decoded.sort();
+        let mut expected = vec![first.hash, second.hash];
+        expected.sort();
+        assert_eq!(decoded, expected);
+    }
+
     async fn get_entries_for_test() -> Arc<Mutex<Vec<Entry>>> {
         let source = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
             .join("tests/data/packs/pack-f8bbb573cef7d851957caceb491c073ee8e8de41.pack");
@@ -778,6 +1275,18 @@ mod tests {
         check_format(&result);
     }
 
+    #[test]
+    fn test_name_hash() {
+        assert_eq!(name_hash(""), 0);
+        assert_eq!(name_hash("a b"), name_hash("ab"));
+        // the last character weighs the most
+        assert_eq!(
+            name_hash("ab"),
+            ((b'a' as u32) << 22) + ((b'b' as u32) << 24)
+        );
+        assert!(name_hash("a.c") < name_hash("a.h"));
+    }
+
     #[test]
     fn test_encode_offset() {
         // let value = 11013;
@@ -0,0 +1,906 @@
+//! Reader and writer of pack index (`.idx`) files.
+//!
+//! An idx lets a pack be read at random: it lists every object id of the pack, sorted, with the
+//! CRC32 of the object's raw entry and its offset in the pack:
+//!
+//! - the magic `\xfftOc` and version 2;
+//! - a fanout table of 256 counts, the number of ids whose first byte is at most the index;
+//! - the sorted ids, then their CRC32s, then their offsets on 4 bytes; offsets of 2 GiB and more
+//!   have the high bit set and index a table of 8 byte offsets that follows;
+//! - the pack checksum and the SHA-1 of everything before it.
+//!
+//! Version 1 files, still read by [`PackIndex`], have no magic and list `(offset, id)` pairs of
+//! 4 and 20 bytes right after the fanout table, without CRC32s. Version 3, from git's hash
+//! function transition plan, drops the fanout table and can index several object formats; only
+//! its `sha1` format is read and written here, see [`build_idx_v3`].
+//!
+//! [`Pack::set_build_idx`](super::Pack::set_build_idx) collects the [`IdxEntry`]s while decoding,
+//! so a received pack can be stored as-is alongside its index; [`PackIndex::read_object`] then
+//! serves single objects from it, and [`PackIndex::read_object_with_cache`] resumes their delta
+//! chains from the bases of a [`DeltaBaseCache`].
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, Cursor};
+use std::path::Path;
+use std::sync::Arc;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::errors::GitError;
+use crate::hash::SHA1;
+use crate::internal::object::types::ObjectType;
+use crate::internal::pack::Pack;
+use crate::internal::pack::base_cache::DeltaBaseCache;
+use crate::internal::pack::cache_object::CacheObjectInfo;
+use crate::internal::pack::encode::PackedDelta;
+use crate::internal::pack::utils;
+
+/// Magic number opening version 2 and 3 idx files.
+pub const IDX_V2_MAGIC: [u8; 4] = [0xff, b't', b'O', b'c'];
+/// Set on a 4 byte offset that indexes the large offset table.
+const LARGE_OFFSET: u32 = 0x8000_0000;
+const FANOUT_SIZE: usize = 256 * 4;
+/// Identifier of the SHA-1 object format in a version 3 header.
+const SHA1_FORMAT: &[u8; 4] = b"sha1";
+
+/// An object of a pack as listed in its index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdxEntry {
+    pub hash: SHA1,
+    /// Offset of the object's entry in the pack.
+    pub offset: u64,
+    /// CRC32 of the object's raw entry: header, delta base and compressed data.
+    pub crc32: u32,
+}
+
+/// The version of the idx files written by [`build_idx`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdxVersion {
+    /// What git writes by default (`pack.indexVersion`), read by every version of git
+    #[default]
+    V2,
+    /// The format of git's hash function transition plan, for readers indexing several object
+    /// formats; git itself reads versions 1 and 2 only
+    V3,
+}
+
+/// The idx of version `version` of a pack holding `entries`, whose trailer checksum is
+/// `pack_hash`.
+pub fn build_idx(entries: &[IdxEntry], pack_hash: &SHA1, version: IdxVersion) -> Vec<u8> {
+    match version {
+        IdxVersion::V2 => build_idx_v2(entries, pack_hash),
+        IdxVersion::V3 => build_idx_v3(entries, pack_hash),
+    }
+}
+
+/// The version 2 idx of a pack holding `entries`, whose trailer checksum is `pack_hash`.
+pub fn build_idx_v2(entries: &[IdxEntry], pack_hash: &SHA1) -> Vec<u8> {
+    let mut entries = entries.to_vec();
+    entries.sort_by_key(|entry| entry.hash);
+
+    let size = 8 + 256 * 4 + entries.len() * (SHA1::SIZE + 8) + 2 * SHA1::SIZE;
+    let mut data = Vec::with_capacity(size);
+    data.extend_from_slice(&IDX_V2_MAGIC);
+    data.extend_from_slice(&2u32.to_be_bytes());
+    let mut below = 0;
+    for byte in 0..=255u8 {
+        below += entries[below..]
+            .iter()
+            .take_while(|entry| entry.hash.0[0] == byte)
+            .count();
+        data.extend_from_slice(&(below as u32).to_be_bytes());
+    }
+    for entry in &entries {
+        data.extend_from_slice(&entry.hash.0);
+    }
+    for entry in &entries {
+        data.extend_from_slice(&entry.crc32.to_be_bytes());
+    }
+    push_offsets(&mut data, entries.iter().map(|entry| entry.offset));
+    data.extend_from_slice(&pack_hash.0);
+    let checksum = SHA1::new(&data);
+    data.extend_from_slice(&checksum.0);
+    data
+}
+
+/// The version 3 idx of a pack holding `entries`, whose trailer checksum is `pack_hash`.
+///
+/// Version 3 has no fanout table; it lists the `sha1` object format only, with ids shortened to
+/// the fewest leading bytes that keep them unambiguous, the full ids in pack order and the map
+/// from sorted to pack order. CRC32s are in pack order, offsets in sorted order.
+pub fn build_idx_v3(entries: &[IdxEntry], pack_hash: &SHA1) -> Vec<u8> {
+    let mut entries = entries.to_vec();
+    entries.sort_by_key(|entry| entry.offset);
+    let mut sorted: Vec<usize> = (0..entries.len()).collect();
+    sorted.sort_by_key(|&i| entries[i].hash);
+    let name_len = sorted
+        .windows(2)
+        .map(|pair| {
+            let (a, b) = (&entries[pair[0]].hash.0, &entries[pair[1]].hash.0);
+            a.iter().zip(b).take_while(|(a, b)| a == b).count() + 1
+        })
+        .max()
+        .unwrap_or(1)
+        .min(SHA1::SIZE);
+
+    let header_len = 20 + 12 + 4;
+    let mut data = Vec::with_capacity(header_len + entries.len() * (name_len + 32));
+    data.extend_from_slice(&IDX_V2_MAGIC);
+    for value in [3, header_len, entries.len(), 1] {
+        data.extend_from_slice(&(value as u32).to_be_bytes());
+    }
+    data.extend_from_slice(SHA1_FORMAT);
+    data.extend_from_slice(&(name_len as u32).to_be_bytes());
+    data.extend_from_slice(&(header_len as u32).to_be_bytes());
+    // Offset of the trailer, known once the tables are written
+    data.extend_from_slice(&[0; 4]);
+    for &i in &sorted {
+        data.extend_from_slice(&entries[i].hash.0[..name_len]);
+    }
+    for entry in &entries {
+        data.extend_from_slice(&entry.hash.0);
+    }
+    for &i in &sorted {
+        data.extend_from_slice(&(i as u32).to_be_bytes());
+    }
+    for entry in &entries {
+        data.extend_from_slice(&entry.crc32.to_be_bytes());
+    }
+    push_offsets(&mut data, sorted.iter().map(|&i| entries[i].offset));
+    let trailer = data.len() as u32;
+    data[header_len - 4..header_len].copy_from_slice(&trailer.to_be_bytes());
+    data.extend_from_slice(&pack_hash.0);
+    let checksum = SHA1::new(&data);
+    data.extend_from_slice(&checksum.0);
+    data
+}
+
+/// Append the 4 byte offset table for `offsets`, then the 8 byte table of those that don't fit.
+fn push_offsets(data: &mut Vec<u8>, offsets: impl Iterator<Item = u64>) {
+    let mut large_offsets = Vec::new();
+    for offset in offsets {
+        let offset = if offset < LARGE_OFFSET as u64 {
+            offset as u32
+        } else {
+            large_offsets.push(offset);
+            LARGE_OFFSET | (large_offsets.len() - 1) as u32
+        };
+        data.extend_from_slice(&offset.to_be_bytes());
+    }
+    for offset in large_offsets {
+        data.extend_from_slice(&offset.to_be_bytes());
+    }
+}
+
+/// The content of a pack objects are read from: in memory, or mapped by a
+/// [`MappedPack`](super::mmap::MappedPack).
+pub trait PackData {
+    type Reader<'a>: BufRead + Send
+    where
+        Self: 'a;
+
+    /// Size of the pack in bytes.
+    fn len(&self) -> u64;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// A reader of the pack from `offset` on.
+    fn reader_at(&self, offset: u64) -> Self::Reader<'_>;
+}
+
+impl PackData for Vec<u8> {
+    type Reader<'a> = Cursor<&'a [u8]>;
+
+    fn len(&self) -> u64 {
+        self.as_slice().len() as u64
+    }
+
+    fn reader_at(&self, offset: u64) -> Self::Reader<'_> {
+        self.as_slice().reader_at(offset)
+    }
+}
+
+impl PackData for [u8] {
+    type Reader<'a> = Cursor<&'a [u8]>;
+
+    fn len(&self) -> u64 {
+        <[u8]>::len(self) as u64
+    }
+
+    fn reader_at(&self, offset: u64) -> Self::Reader<'_> {
+        Cursor::new(self.get(offset as usize..).unwrap_or_default())
+    }
+}
+
+/// A parsed pack index, mapping the object ids of a pack to their offsets in it.
+///
+/// Versions 1, 2 and 3 are told apart from the content; the positions below locate the tables
+/// of any of them.
+#[derive(Debug, Clone)]
+pub struct PackIndex {
+    data: Vec<u8>,
+    version: u32,
+    count: usize,
+    /// Start of the fanout table, version 3 has none.
+    fanout: Option<usize>,
+    /// Start of the sorted ids, the distance between two of them and the bytes kept of each.
+    names: (usize, usize, usize),
+    /// Version 3: start of the full ids in pack order and of the map from sorted to pack order.
+    pack_order: Option<(usize, usize)>,
+    /// Start of the CRC32s, version 1 has none.
+    crcs: Option<usize>,
+    /// Start of the 4 byte offsets and the distance between two of them.
+    offsets: (usize, usize),
+    /// Start of the 8 byte offsets, ending at the trailer.
+    large_offsets: usize,
+    /// Start of the trailer: the pack checksum, then the idx checksum.
+    trailer: usize,
+}
+
+impl PackIndex {
+    /// Read the idx at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, GitError> {
+        Self::from_bytes(fs::read(path)?)
+    }
+
+    /// Parse the content of a version 1, 2 or 3 idx file, verifying its layout and checksum.
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, GitError> {
+        let invalid = |reason: &str| GitError::InvalidIdxFile(reason.to_string());
+        if data.len() < 8 + 2 * SHA1::SIZE {
+            return Err(invalid("truncated idx"));
+        }
+        let checksum = data.len() - SHA1::SIZE;
+        if SHA1::new(&data[..checksum]) != SHA1::from_bytes(&data[checksum..]) {
+            return Err(invalid("checksum mismatch"));
+        }
+        let version = match data.starts_with(&IDX_V2_MAGIC) {
+            true => BigEndian::read_u32(&data[4..8]),
+            false => 1,
+        };
+        let index = match version {
+            1 | 2 => Self::parse_fanout_layout(data, version)?,
+            3 => Self::parse_v3(data)?,
+            _ => return Err(invalid("unsupported idx version")),
+        };
+        if let Some((_, order)) = index.pack_order
+            && (0..index.count).any(|i| index.word(order, i) as usize >= index.count)
+        {
+            return Err(invalid("pack order out of bounds"));
+        }
+        Ok(index)
+    }
+
+    /// Version 1 and 2 files: a fanout table, then tables whose sizes follow from its count.
+    fn parse_fanout_layout(data: Vec<u8>, version: u32) -> Result<Self, GitError> {
+        let invalid = |reason: &str| GitError::InvalidIdxFile(reason.to_string());
+        let fanout = if version == 2 { 8 } else { 0 };
+        if data.len() < fanout + FANOUT_SIZE + 2 * SHA1::SIZE {
+            return Err(invalid("truncated idx"));
+        }
+        let mut count = 0;
+        for i in 0..256 {
+            let below = BigEndian::read_u32(&data[fanout + i * 4..]) as usize;
+            if below < count {
+                return Err(invalid("fanout table is not sorted"));
+            }
+            count = below;
+        }
+        let trailer = data.len() - 2 * SHA1::SIZE;
+        let tables = fanout + FANOUT_SIZE;
+        let index = if version == 1 {
+            if trailer - tables != count * (4 + SHA1::SIZE) {
+                return Err(invalid("size does not match the object count"));
+            }
+            PackIndex {
+                version,
+                count,
+                fanout: Some(fanout),
+                names: (tables + 4, 4 + SHA1::SIZE, SHA1::SIZE),
+                pack_order: None,
+                crcs: None,
+                offsets: (tables, 4 + SHA1::SIZE),
+                large_offsets: trailer,
+                trailer,
+                data,
+            }
+        } else {
+            let large = (trailer - tables).checked_sub(count * (SHA1::SIZE + 8));
+            if !large.is_some_and(|large| large % 8 == 0 && large / 8 <= count) {
+                return Err(invalid("size does not match the object count"));
+            }
+            let crcs = tables + count * SHA1::SIZE;
+            PackIndex {
+                version,
+                count,
+                fanout: Some(fanout),
+                names: (tables, SHA1::SIZE, SHA1::SIZE),
+                pack_order: None,
+                crcs: Some(crcs),
+                offsets: (crcs + count * 4, 4),
+                large_offsets: crcs + count * 8,
+                trailer,
+                data,
+            }
+        };
+        Ok(index)
+    }
+
+    /// Version 3 files: a header locating the tables of each object format, the first of which
+    /// must be `sha1`; the tables of other formats are ignored.
+    fn parse_v3(data: Vec<u8>) -> Result<Self, GitError> {
+        let invalid = |reason: &str| GitError::InvalidIdxFile(reason.to_string());
+        let word = |at: usize| BigEndian::read_u32(&data[at..]) as usize;
+        if data.len() < 20 + 2 * SHA1::SIZE {
+            return Err(invalid("truncated idx"));
+        }
+        let (header_len, count, formats) = (word(8), word(12), word(16));
+        let header_end = 20 + formats * 12 + 4;
+        if formats == 0 || header_end > header_len || header_len > data.len() - 2 * SHA1::SIZE {
+            return Err(invalid("truncated idx header"));
+        }
+        if &data[20..24] != SHA1_FORMAT {
+            return Err(invalid("unsupported object format"));
+        }
+        let (name_len, tables) = (word(24), word(28));
+        let trailer = word(header_end - 4);
+        if !(1..=SHA1::SIZE).contains(&name_len) {
+            return Err(invalid("invalid shortened id length"));
+        }
+        if trailer != data.len() - 2 * SHA1::SIZE {
+            return Err(invalid("trailer offset does not match the file size"));
+        }
+        let full = tables + count * name_len;
+        let order = full + count * SHA1::SIZE;
+        let crcs = order + count * 4;
+        let offsets = crcs + count * 4;
+        let large_offsets = offsets + count * 4;
+        if tables < header_len || large_offsets > trailer {
+            return Err(invalid("tables out of bounds"));
+        }
+        Ok(PackIndex {
+            version: 3,
+            count,
+            fanout: None,
+            names: (tables, name_len, name_len),
+            pack_order: Some((full, order)),
+            crcs: Some(crcs),
+            offsets: (offsets, 4),
+            large_offsets,
+            trailer,
+            data,
+        })
+    }
+
+    /// The idx format version, 1, 2 or 3.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Number of objects in the pack.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Checksum of the pack this idx belongs to, the last 20 bytes of the pack.
+    pub fn pack_hash(&self) -> SHA1 {
+        SHA1::from_bytes(&self.data[self.trailer..self.trailer + SHA1::SIZE])
+    }
+
+    /// The id at `index` in sorted order.
+    pub fn id_at(&self, index: usize) -> SHA1 {
+        let start = match self.pack_order {
+            Some((full, _)) => full + self.pack_position(index) * SHA1::SIZE,
+            None => self.names.0 + index * self.names.1,
+        };
+        SHA1::from_bytes(&self.data[start..start + SHA1::SIZE])
+    }
+
+    /// The pack offset of the object at `index`, `None` if a large offset is out of the table.
+    pub fn offset_at(&self, index: usize) -> Option<u64> {
+        let (offsets, stride) = self.offsets;
+        let offset = BigEndian::read_u32(&self.data[offsets + index * stride..]);
+        if self.version == 1 || offset & LARGE_OFFSET == 0 {
+            return Some(offset as u64);
+        }
+        let large = self.large_offsets + (offset & !LARGE_OFFSET) as usize * 8;
+        (large + 8 <= self.trailer).then(|| BigEndian::read_u64(&self.data[large..]))
+    }
+
+    /// The CRC32 of the raw entry of the object at `index`; version 1 files don't record it.
+    pub fn crc32_at(&self, index: usize) -> Option<u32> {
+        let position = match self.pack_order {
+            Some(_) => self.pack_position(index),
+            None => index,
+        };
+        Some(self.word(self.crcs?, position))
+    }
+
+    /// Index of `id` in sorted order, found through the fanout table when there is one.
+    pub fn position(&self, id: &SHA1) -> Option<usize> {
+        let (mut low, mut high) = match self.fanout {
+            Some(fanout) => {
+                let first = id.0[0] as usize;
+                let below = |i: usize| self.word(fanout, i) as usize;
+                (if first == 0 { 0 } else { below(first - 1) }, below(first))
+            }
+            None => (0, self.count),
+        };
+        let (names, stride, name_len) = self.names;
+        let key = &id.0[..name_len];
+        while low < high {
+            let mid = (low + high) / 2;
+            let name = &self.data[names + mid * stride..names + mid * stride + name_len];
+            match name.cmp(key) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                // Shortened ids are unambiguous among the pack's objects, not among all ids
+                std::cmp::Ordering::Equal => return (self.id_at(mid) == *id).then_some(mid),
+            }
+        }
+        None
+    }
+
+    pub fn contains(&self, id: &SHA1) -> bool {
+        self.position(id).is_some()
+    }
+
+    /// Offset of the object `id` in the pack, `None` if it isn't in the pack.
+    pub fn find_offset(&self, id: &SHA1) -> Option<u64> {
+        self.offset_at(self.position(id)?)
+    }
+
+    /// Every object as `(id, offset)`, in id order.
+    pub fn entries(&self) -> impl Iterator<Item = (SHA1, u64)> + '_ {
+        (0..self.count).filter_map(|i| Some((self.id_at(i), self.offset_at(i)?)))
+    }
+
+    /// Version 3: the position in the pack of the object at `index` in sorted order.
+    fn pack_position(&self, index: usize) -> usize {
+        let (_, order) = self.pack_order.unwrap();
+        self.word(order, index) as usize
+    }
+
+    fn word(&self, table: usize, index: usize) -> u32 {
+        BigEndian::read_u32(&self.data[table + index * 4..])
+    }
+
+    /// Read the object `id` from `pack`, the content of the pack this idx belongs to.
+    ///
+    /// Deltas are resolved against their bases in the same pack. Returns `None` if the object
+    /// isn't in the pack.
+    pub fn read_object(
+        &self,
+        pack: &[u8],
+        id: &SHA1,
+    ) -> Result<Option<(ObjectType, Vec<u8>)>, GitError> {
+        self.read_object_from(pack, id, None)
+    }
+
+    /// The deltas of `pack` by object id, to copy into new packs with
+    /// [`PackEncoder::set_reused_deltas`](super::encode::PackEncoder::set_reused_deltas).
+    /// Zstdelta entries are left out, as git can't read them.
+    pub fn packed_deltas(&self, pack: &[u8]) -> Result<HashMap<SHA1, PackedDelta>, GitError> {
+        let ids: HashMap<u64, SHA1> = self.entries().map(|(id, offset)| (offset, id)).collect();
+        let invalid = |e: std::io::Error| GitError::InvalidPackFile(format!("Read error: {e}"));
+        let mut deltas = HashMap::new();
+        for (&offset, &id) in &ids {
+            let entry = pack.get(offset as usize..).ok_or_else(|| {
+                GitError::InvalidPackFile(format!("object {id} is past the end of the pack"))
+            })?;
+            let mut cursor = Cursor::new(entry);
+            let mut end = 0;
+            let (type_bits, size) =
+                utils::read_type_and_varint_size(&mut cursor, &mut end).map_err(invalid)?;
+            let base = match ObjectType::from_u8(type_bits)? {
+                ObjectType::OffsetDelta => {
+                    let (distance, _) =
+                        utils::read_offset_encoding(&mut cursor).map_err(invalid)?;
+                    offset
+                        .checked_sub(distance)
+                        .and_then(|base_offset| ids.get(&base_offset).copied())
+                        .ok_or_else(|| {
+                            GitError::InvalidPackFile(format!("delta base of {id} is not indexed"))
+                        })?
+                }
+                ObjectType::HashDelta => SHA1::from_stream(&mut cursor).map_err(invalid)?,
+                _ => continue,
+            };
+            let start = cursor.position() as usize;
+            let (_, len) = Pack::decompress_data(&mut cursor, size)?;
+            let data = entry[start..start + len].to_vec();
+            deltas.insert(id, PackedDelta { base, size, data });
+        }
+        Ok(deltas)
+    }
+
+    /// The offset of the delta base of `entry`, the pack entry at `offset`, `None` when it
+    /// holds a base object. Only the entry header is read.
+    pub(crate) fn delta_base_offset(
+        &self,
+        entry: &[u8],
+        offset: u64,
+    ) -> Result<Option<u64>, GitError> {
+        let invalid = |e: std::io::Error| GitError::InvalidPackFile(format!("Read error: {e}"));
+        let mut cursor = Cursor::new(entry);
+        let mut end = 0;
+        let (type_bits, _) =
+            utils::read_type_and_varint_size(&mut cursor, &mut end).map_err(invalid)?;
+        let base_offset = match ObjectType::from_u8(type_bits)? {
+            ObjectType::OffsetDelta | ObjectType::OffsetZstdelta => {
+                let (distance, _) = utils::read_offset_encoding(&mut cursor).map_err(invalid)?;
+                offset.checked_sub(distance)
+            }
+            ObjectType::HashDelta => {
+                let base = SHA1::from_stream(&mut cursor).map_err(invalid)?;
+                self.find_offset(&base)
+            }
+            _ => return Ok(None),
+        };
+        base_offset.map(Some).ok_or_else(|| {
+            GitError::InvalidPackFile(format!("delta base of offset {offset} is not in the pack"))
+        })
+    }
+
+    /// Read the object whose entry starts at `offset` in `pack`, with its id.
+    pub(crate) fn read_object_at(
+        &self,
+        pack: &[u8],
+        offset: usize,
+    ) -> Result<(ObjectType, Vec<u8>, SHA1), GitError> {
+        self.resolve_object_at(pack, offset, None)
+    }
+
+    /// Read the object `id` from `pack` like [`PackIndex::read_object`], resolving its delta
+    /// chain from the deepest base found in `cache` and keeping the bases inflated on the way
+    /// there.
+    pub fn read_object_with_cache(
+        &self,
+        pack: &[u8],
+        id: &SHA1,
+        cache: &DeltaBaseCache,
+    ) -> Result<Option<(ObjectType, Vec<u8>)>, GitError> {
+        self.read_object_from(pack, id, Some(cache))
+    }
+
+    /// Read the object `id` from `pack`, the pack this idx belongs to, see
+    /// [`PackIndex::read_object_with_cache`].
+    pub(crate) fn read_object_from<P: PackData + ?Sized>(
+        &self,
+        pack: &P,
+        id: &SHA1,
+        cache: Option<&DeltaBaseCache>,
+    ) -> Result<Option<(ObjectType, Vec<u8>)>, GitError> {
+        let Some(offset) = self.find_offset(id) else {
+            return Ok(None);
+        };
+        let (obj_type, data, hash) = self.resolve_object_at(pack, offset as usize, cache)?;
+        if hash != *id {
+            return Err(GitError::InvalidPackFile(format!(
+                "object at offset {offset} is {hash}, the idx lists {id}"
+            )));
+        }
+        Ok(Some((obj_type, data)))
+    }
+
+    /// Read the object whose entry starts at `offset` in `pack`, with its id.
+    pub(crate) fn resolve_object_at<P: PackData + ?Sized>(
+        &self,
+        pack: &P,
+        mut offset: usize,
+        cache: Option<&DeltaBaseCache>,
+    ) -> Result<(ObjectType, Vec<u8>, SHA1), GitError> {
+        let pack_hash = self.pack_hash();
+        // Follow the delta chain down to a base object, then apply the deltas back up
+        let mut deltas = Vec::new();
+        let mut object = loop {
+            if let Some(base) = cache.and_then(|cache| cache.get(&pack_hash, offset as u64)) {
+                break base;
+            }
+            if offset as u64 >= pack.len() || deltas.len() > self.count {
+                return Err(GitError::InvalidPackFile(format!(
+                    "broken delta chain at offset {offset}"
+                )));
+            }
+            let mut reader = pack.reader_at(offset as u64);
+            let mut end = offset;
+            let object = Pack::decode_pack_object(&mut reader, &mut end)?;
+            let base_offset = match object.info {
+                CacheObjectInfo::BaseObject(..) => break Arc::new(object),
+                CacheObjectInfo::OffsetDelta(base, _)
+                | CacheObjectInfo::OffsetZstdelta(base, _) => base,
+                CacheObjectInfo::HashDelta(base, _) => self.find_offset(&base).ok_or_else(|| {
+                    GitError::InvalidPackFile(format!("delta base {base} is not in the pack"))
+                })? as usize,
+            };
+            deltas.push((offset, object));
+            offset = base_offset;
+        };
+        while let Some((delta_offset, delta)) = deltas.pop() {
+            if let Some(cache) = cache {
+                cache.insert(&pack_hash, offset as u64, object.clone());
+            }
+            object = Arc::new(match delta.info {
+                CacheObjectInfo::OffsetZstdelta(..) => Pack::rebuild_zstdelta(delta, object)?,
+                _ => Pack::rebuild_delta(delta, object)?,
+            });
+            offset = delta_offset;
+        }
+        let hash = object.base_object_hash().unwrap();
+        let obj_type = object.object_type();
+        let data = match Arc::try_unwrap(object) {
+            Ok(mut object) => std::mem::take(&mut object.data_decompressed),
+            Err(shared) => shared.data_decompressed.clone(),
+        };
+        Ok((obj_type, data, hash))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::{Read, Write};
+
+    use flate2::Compression;
+    use flate2::write::ZlibEncoder;
+
+    use super::*;
+    use crate::internal::object::types::ObjectType;
+    use crate::internal::pack::bloom::ObjectFilter;
+
+    #[test]
+    fn test_build_idx_v2() {
+        let entries: Vec<IdxEntry> = [12u64, 40, 3 << 31]
+            .into_iter()
+            .enumerate()
+            .map(|(i, offset)| IdxEntry {
+                hash: SHA1::from_type_and_data(ObjectType::Blob, &[i as u8]),
+                offset,
+                crc32: i as u32 + 1,
+            })
+            .collect();
+        let pack_hash = SHA1::new(b"pack");
+        let idx = build_idx_v2(&entries, &pack_hash);
+
+        let count = entries.len();
+        assert_eq!(idx.len(), 8 + 1024 + count * 28 + 8 + 40);
+        assert_eq!(BigEndian::read_u32(&idx[8 + 255 * 4..]), count as u32);
+        let trailer = idx.len() - SHA1::SIZE;
+        assert_eq!(
+            SHA1::from_bytes(&idx[trailer..]),
+            SHA1::new(&idx[..trailer])
+        );
+        assert_eq!(SHA1::from_bytes(&idx[trailer - 20..trailer]), pack_hash);
+
+        let mut sorted = entries.clone();
+        sorted.sort_by_key(|entry| entry.hash);
+        let ids = 8 + 1024;
+        let (crcs, offsets) = (ids + count * 20, ids + count * 24);
+        for (i, entry) in sorted.iter().enumerate() {
+            assert_eq!(
+                SHA1::from_bytes(&idx[ids + i * 20..ids + i * 20 + 20]),
+                entry.hash
+            );
+            assert_eq!(BigEndian::read_u32(&idx[crcs + i * 4..]), entry.crc32);
+            let offset = BigEndian::read_u32(&idx[offsets + i * 4..]);
+            if entry.offset >= LARGE_OFFSET as u64 {
+                assert_eq!(offset, LARGE_OFFSET);
+                assert_eq!(
+                    BigEndian::read_u64(&idx[offsets + count * 4..]),
+                    entry.offset
+                );
+            } else {
+                assert_eq!(offset as u64, entry.offset);
+            }
+        }
+
+        let filter = ObjectFilter::new(count, 0.01);
+        filter.add_idx(&idx).unwrap();
+        assert!(entries.iter().all(|entry| filter.may_contain(&entry.hash)));
+
+        let index = PackIndex::from_bytes(idx.clone()).unwrap();
+        assert_eq!((index.version(), index.len()), (2, count));
+        assert_eq!(index.pack_hash(), pack_hash);
+        for entry in &entries {
+            assert_eq!(index.find_offset(&entry.hash), Some(entry.offset));
+            let position = index.position(&entry.hash).unwrap();
+            assert_eq!(index.crc32_at(position), Some(entry.crc32));
+        }
+        assert!(!index.contains(&SHA1::new(b"missing")));
+
+        let mut corrupt = idx;
+        corrupt[8 + 1024] ^= 1;
+        assert!(PackIndex::from_bytes(corrupt).is_err());
+    }
+
+    /// A pack of a blob, an offset delta and a ref delta on it, with its version 2 idx.
+    pub(crate) fn delta_pack() -> (Vec<u8>, Vec<u8>) {
+        let hello = SHA1::from_type_and_data(ObjectType::Blob, b"hello");
+        // base size 5, result size 11: copy "hello", insert " world" / " there"
+        let world = [&[0x05, 0x0b, 0x90, 0x05, 0x06][..], b" world"].concat();
+        let there = [&[0x05, 0x0b, 0x90, 0x05, 0x06][..], b" there"].concat();
+        let mut pack = b"PACK".to_vec();
+        pack.extend_from_slice(&2u32.to_be_bytes());
+        pack.extend_from_slice(&3u32.to_be_bytes());
+        for (obj_type, data) in [(3u8, &b"hello"[..]), (6, &world), (7, &there)] {
+            let offset = pack.len();
+            pack.push((obj_type << 4) | data.len() as u8);
+            match obj_type {
+                6 => pack.push((offset - 12) as u8),
+                7 => pack.extend_from_slice(&hello.0),
+                _ => {}
+            }
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data).unwrap();
+            pack.extend(encoder.finish().unwrap());
+        }
+        let checksum = SHA1::new(&pack);
+        pack.extend_from_slice(&checksum.0);
+
+        let mut decoder = Pack::new(Some(2), None, None, true);
+        decoder.set_build_idx(true);
+        decoder
+            .decode(&mut Cursor::new(pack.clone()), |_, _| {})
+            .unwrap();
+        let idx = decoder.idx().unwrap().to_vec();
+        (pack, idx)
+    }
+
+    #[test]
+    fn test_pack_index_packed_deltas() {
+        let (pack, idx) = delta_pack();
+        let index = PackIndex::from_bytes(idx).unwrap();
+        let deltas = index.packed_deltas(&pack).unwrap();
+        let hello = SHA1::from_type_and_data(ObjectType::Blob, b"hello");
+        assert_eq!(deltas.len(), 2);
+        for content in [&b"hello world"[..], b"hello there"] {
+            let delta = &deltas[&SHA1::from_type_and_data(ObjectType::Blob, content)];
+            assert_eq!((delta.base, delta.size), (hello, 11));
+            let mut inflated = Vec::new();
+            flate2::read::ZlibDecoder::new(&delta.data[..])
+                .read_to_end(&mut inflated)
+                .unwrap();
+            assert_eq!(&inflated[5..], &content[5..]);
+        }
+    }
+
+    #[test]
+    fn test_pack_index_read_object() {
+        let (pack, idx) = delta_pack();
+        let index = PackIndex::from_bytes(idx).unwrap();
+        assert_eq!(
+            index.pack_hash(),
+            SHA1::from_bytes(&pack[pack.len() - 20..])
+        );
+        for content in [&b"hello"[..], b"hello world", b"hello there"] {
+            let id = SHA1::from_type_and_data(ObjectType::Blob, content);
+            let (obj_type, data) = index.read_object(&pack, &id).unwrap().unwrap();
+            assert_eq!((obj_type, data.as_slice()), (ObjectType::Blob, content));
+        }
+        let missing = SHA1::from_type_and_data(ObjectType::Blob, b"missing");
+        assert!(index.read_object(&pack, &missing).unwrap().is_none());
+
+        // The deltas resume from their cached base
+        let cache = DeltaBaseCache::default();
+        for content in [&b"hello world"[..], b"hello there", b"hello"] {
+            let id = SHA1::from_type_and_data(ObjectType::Blob, content);
+            let (_, data) = index
+                .read_object_with_cache(&pack, &id, &cache)
+                .unwrap()
+                .unwrap();
+            assert_eq!(data, content);
+        }
+        assert_eq!(cache.len(), 1);
+        assert_eq!((cache.hits(), cache.misses()), (2, 3));
+        assert!(
+            index
+                .read_object_with_cache(&pack, &missing, &cache)
+                .unwrap()
+                .is_none()
+        );
+
+        // The same objects through a version 1 idx: fanout, then (offset, id) pairs
+        let mut v1 = idx_fanout(&index);
+        for (id, offset) in index.entries() {
+            v1.extend_from_slice(&(offset as u32).to_be_bytes());
+            v1.extend_from_slice(&id.0);
+        }
+        v1.extend_from_slice(&index.pack_hash().0);
+        let checksum = SHA1::new(&v1);
+        v1.extend_from_slice(&checksum.0);
+        let v1 = PackIndex::from_bytes(v1).unwrap();
+        assert_eq!((v1.version(), v1.len()), (1, 3));
+        assert_eq!(
+            v1.entries().collect::<Vec<_>>(),
+            index.entries().collect::<Vec<_>>()
+        );
+        assert_eq!(v1.crc32_at(0), None);
+        let id = SHA1::from_type_and_data(ObjectType::Blob, b"hello there");
+        assert_eq!(
+            v1.read_object(&pack, &id).unwrap().unwrap().1,
+            b"hello there"
+        );
+    }
+
+    #[test]
+    fn test_build_idx_v3() {
+        // Two ids share their first two bytes, so three are kept of each
+        let mut close = [0xab; 20];
+        close[2] = 0x01;
+        let entries: Vec<IdxEntry> = [SHA1([0xab; 20]), SHA1(close), SHA1([0x10; 20])]
+            .into_iter()
+            .zip([40u64, 3 << 31, 12])
+            .enumerate()
+            .map(|(i, (hash, offset))| IdxEntry {
+                hash,
+                offset,
+                crc32: i as u32 + 1,
+            })
+            .collect();
+        let pack_hash = SHA1::new(b"pack");
+        let idx = build_idx_v3(&entries, &pack_hash);
+        assert_eq!(&idx[..8], b"\xfftOc\0\0\0\x03");
+        assert_eq!(&idx[20..28], b"sha1\0\0\0\x03");
+
+        let index = PackIndex::from_bytes(idx.clone()).unwrap();
+        assert_eq!((index.version(), index.len()), (3, 3));
+        assert_eq!(index.pack_hash(), pack_hash);
+        for entry in &entries {
+            assert_eq!(index.find_offset(&entry.hash), Some(entry.offset));
+            let position = index.position(&entry.hash).unwrap();
+            assert_eq!(index.crc32_at(position), Some(entry.crc32));
+        }
+        // Same shortened id as an object of the pack, different full id
+        let mut lookalike = [0xab; 20];
+        lookalike[19] = 0;
+        assert!(!index.contains(&SHA1(lookalike)));
+
+        let filter = ObjectFilter::new(3, 0.01);
+        filter.add_idx(&idx).unwrap();
+        assert!(entries.iter().all(|entry| filter.may_contain(&entry.hash)));
+
+        // Objects of the pack read through its version 3 idx
+        let (pack, idx) = delta_pack();
+        let v2 = PackIndex::from_bytes(idx).unwrap();
+        let entries: Vec<IdxEntry> = (0..v2.len())
+            .map(|i| IdxEntry {
+                hash: v2.id_at(i),
+                offset: v2.offset_at(i).unwrap(),
+                crc32: v2.crc32_at(i).unwrap(),
+            })
+            .collect();
+        let v3 = PackIndex::from_bytes(build_idx_v3(&entries, &v2.pack_hash())).unwrap();
+        assert_eq!(
+            v3.entries().collect::<Vec<_>>(),
+            v2.entries().collect::<Vec<_>>()
+        );
+        let id = SHA1::from_type_and_data(ObjectType::Blob, b"hello world");
+        assert_eq!(
+            v3.read_object(&pack, &id).unwrap().unwrap().1,
+            b"hello world"
+        );
+
+        let mut sha256 = build_idx_v3(&entries, &v2.pack_hash());
+        sha256[20..24].copy_from_slice(b"s256");
+        let trailer = sha256.len() - 20;
+        let checksum = SHA1::new(&sha256[..trailer]);
+        sha256[trailer..].copy_from_slice(&checksum.0);
+        assert!(matches!(
+            PackIndex::from_bytes(sha256),
+            Err(GitError::InvalidIdxFile(_))
+        ));
+    }
+
+    fn idx_fanout(index: &PackIndex) -> Vec<u8> {
+        (0..=255u8)
+            .flat_map(|byte| {
+                let below = index.entries().filter(|(id, _)| id.0[0] <= byte).count();
+                (below as u32).to_be_bytes()
+            })
+            .collect()
+    }
+}
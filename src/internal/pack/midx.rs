@@ -0,0 +1,341 @@
+//! Reader and writer of git's multi-pack-index file, `objects/pack/multi-pack-index`.
+//!
+//! A repository with many packs needs one lookup per `.idx` to find an object; the
+//! multi-pack-index lists the objects of all of them, sorted by id, with the pack holding each
+//! and its offset there:
+//!
+//! - a 12 byte header: `MIDX`, version 1, hash version 1 (SHA-1), the number of chunks, the
+//!   number of base files and the number of packs;
+//! - a table of contents of `(chunk id, offset)` pairs, terminated by a zero id and the end
+//!   offset;
+//! - the chunks: `PNAM` (the sorted, NUL terminated `.idx` names of the packs, padded to 4
+//!   bytes), `OIDF` (fanout on the first byte of the ids), `OIDL` (sorted ids), `OOFF` (pack
+//!   position and 4 byte offset of each object) and `LOFF` (8 byte offsets, indexed by the low
+//!   bits of an `OOFF` offset with the high bit set);
+//! - a SHA-1 of everything before it.
+//!
+//! An object stored in several packs is listed once. Incremental chains of files are not
+//! supported; unknown chunks, such as reverse indexes and bitmaps, are ignored.
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::errors::GitError;
+use crate::hash::SHA1;
+use crate::internal::chunk_format::{
+    ChunkTable, OID_FANOUT, OID_LOOKUP, fanout_chunk, find_id, min_file_size, write_chunks,
+};
+use crate::internal::pack::idx::PackIndex;
+use crate::internal::refs::LockFile;
+
+const SIGNATURE: &[u8; 4] = b"MIDX";
+const VERSION: u8 = 1;
+/// Hash version of SHA-1.
+const HASH_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 12;
+
+const PACK_NAMES: u32 = u32::from_be_bytes(*b"PNAM");
+const OBJECT_OFFSETS: u32 = u32::from_be_bytes(*b"OOFF");
+const LARGE_OFFSETS: u32 = u32::from_be_bytes(*b"LOFF");
+
+/// Set on an `OOFF` offset that indexes `LOFF`.
+const LARGE_OFFSET: u32 = 0x8000_0000;
+
+/// A parsed multi-pack-index, mapping object ids to the pack and offset that store them.
+#[derive(Debug, Clone)]
+pub struct MultiPackIndex {
+    data: Vec<u8>,
+    pack_names: Vec<String>,
+    count: usize,
+    fanout: usize,
+    lookup: usize,
+    offsets: usize,
+    large_offsets: Option<(usize, usize)>,
+}
+
+impl MultiPackIndex {
+    /// Read the multi-pack-index at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, GitError> {
+        Self::from_bytes(fs::read(path)?)
+    }
+
+    /// Parse the content of a multi-pack-index file, verifying its layout and checksum.
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, GitError> {
+        let invalid = |reason: &str| GitError::InvalidMultiPackIndex(reason.to_string());
+        if data.len() < min_file_size(HEADER_SIZE) {
+            return Err(invalid("truncated multi-pack-index"));
+        }
+        if &data[..4] != SIGNATURE {
+            return Err(invalid("missing MIDX signature"));
+        }
+        if data[4] != VERSION || data[5] != HASH_VERSION {
+            return Err(invalid("unsupported multi-pack-index or hash version"));
+        }
+        if data[7] != 0 {
+            return Err(invalid(
+                "incremental multi-pack-index chains are not supported",
+            ));
+        }
+        let pack_count = BigEndian::read_u32(&data[8..12]) as usize;
+        let chunks = ChunkTable::read(
+            &data,
+            HEADER_SIZE,
+            data[6] as usize,
+            GitError::InvalidMultiPackIndex,
+        )?;
+        let (names, names_size) = chunks.require(PACK_NAMES)?;
+        let pack_names: Vec<String> = data[names..names + names_size]
+            .split(|&byte| byte == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect();
+        if pack_names.len() != pack_count {
+            return Err(invalid("PNAM chunk does not match the pack count"));
+        }
+        if pack_names.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(invalid("pack names are not sorted"));
+        }
+
+        let (fanout, count) = chunks.fanout(&data)?;
+        let lookup = chunks.sized(OID_LOOKUP, count, SHA1::SIZE)?;
+        let offsets = chunks.sized(OBJECT_OFFSETS, count, 8)?;
+        if (0..count).any(|i| BigEndian::read_u32(&data[offsets + i * 8..]) as usize >= pack_count)
+        {
+            return Err(invalid("OOFF chunk refers to a missing pack"));
+        }
+        Ok(Self {
+            data,
+            pack_names,
+            count,
+            fanout,
+            lookup,
+            offsets,
+            large_offsets: chunks.get(LARGE_OFFSETS),
+        })
+    }
+
+    /// Build the multi-pack-index of `packs`, given as the name of their `.idx` file, e.g.
+    /// `pack-<hash>.idx`, and their parsed index.
+    ///
+    /// An object found in several packs is attributed to the first of them in `packs`, so list
+    /// the preferred packs, usually the newest, first.
+    pub fn from_pack_indexes<'a>(
+        packs: impl IntoIterator<Item = (&'a str, &'a PackIndex)>,
+    ) -> Result<Self, GitError> {
+        let packs: Vec<(&str, &PackIndex)> = packs.into_iter().collect();
+        let mut names: Vec<&str> = packs.iter().map(|(name, _)| *name).collect();
+        names.sort_unstable();
+        if names.windows(2).any(|pair| pair[0] == pair[1]) {
+            return Err(GitError::InvalidMultiPackIndex(
+                "pack names are not unique".to_string(),
+            ));
+        }
+        if let Some(name) = names
+            .iter()
+            .find(|name| name.is_empty() || name.contains('\0'))
+        {
+            return Err(GitError::InvalidMultiPackIndex(format!(
+                "invalid pack name {name:?}"
+            )));
+        }
+
+        let mut objects: HashMap<SHA1, (u32, u64)> = HashMap::new();
+        for (name, index) in &packs {
+            let pack = names.binary_search(name).unwrap() as u32;
+            for (id, offset) in index.entries() {
+                objects.entry(id).or_insert((pack, offset));
+            }
+        }
+        let mut objects: Vec<(SHA1, (u32, u64))> = objects.into_iter().collect();
+        objects.sort_unstable_by_key(|(id, _)| *id);
+
+        let mut pack_names = Vec::new();
+        for name in &names {
+            pack_names.extend_from_slice(name.as_bytes());
+            pack_names.push(0);
+        }
+        pack_names.resize(pack_names.len().next_multiple_of(4), 0);
+        let fanout = fanout_chunk(objects.iter().map(|(id, _)| id));
+        let mut lookup = Vec::with_capacity(objects.len() * SHA1::SIZE);
+        let mut offsets = Vec::with_capacity(objects.len() * 8);
+        let mut large_offsets = Vec::new();
+        for (id, (pack, offset)) in &objects {
+            lookup.extend_from_slice(&id.0);
+            offsets.extend_from_slice(&pack.to_be_bytes());
+            let offset = if *offset < LARGE_OFFSET as u64 {
+                *offset as u32
+            } else {
+                let index = (large_offsets.len() / 8) as u32;
+                large_offsets.extend_from_slice(&offset.to_be_bytes());
+                LARGE_OFFSET | index
+            };
+            offsets.extend_from_slice(&offset.to_be_bytes());
+        }
+
+        let mut chunks = vec![
+            (PACK_NAMES, pack_names),
+            (OID_FANOUT, fanout),
+            (OID_LOOKUP, lookup),
+            (OBJECT_OFFSETS, offsets),
+        ];
+        if !large_offsets.is_empty() {
+            chunks.push((LARGE_OFFSETS, large_offsets));
+        }
+        let mut header = SIGNATURE.to_vec();
+        header.extend_from_slice(&[VERSION, HASH_VERSION, chunks.len() as u8, 0]);
+        header.extend_from_slice(&(names.len() as u32).to_be_bytes());
+        let data = write_chunks(header, chunks);
+        Self::from_bytes(data)
+    }
+
+    /// The content of the multi-pack-index file.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Write the multi-pack-index to `path` through a `.lock` file, so readers never see a
+    /// partial file.
+    pub fn write_to(&self, path: impl AsRef<Path>) -> Result<(), GitError> {
+        LockFile::acquire(path.as_ref())?.commit(&self.data)
+    }
+
+    /// The `.idx` names of the indexed packs, sorted; objects refer to packs by position here.
+    pub fn pack_names(&self) -> &[String] {
+        &self.pack_names
+    }
+
+    /// Number of distinct objects in the packs.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// The id of the object at `position`, ids being sorted.
+    pub fn id_at(&self, position: usize) -> SHA1 {
+        let start = self.lookup + position * SHA1::SIZE;
+        SHA1::from_bytes(&self.data[start..start + SHA1::SIZE])
+    }
+
+    /// The position of `id` in the sorted ids, found through the fanout table.
+    pub fn position(&self, id: &SHA1) -> Option<usize> {
+        find_id(&self.data, self.fanout, self.lookup, self.count, id)
+    }
+
+    pub fn contains(&self, id: &SHA1) -> bool {
+        self.position(id).is_some()
+    }
+
+    /// The pack, as a position in [`MultiPackIndex::pack_names`], and the offset there of the
+    /// object at `position`; `None` if its large offset is out of the `LOFF` chunk.
+    pub fn object_at(&self, position: usize) -> Option<(usize, u64)> {
+        let entry = self.offsets + position * 8;
+        let pack = BigEndian::read_u32(&self.data[entry..]) as usize;
+        let offset = BigEndian::read_u32(&self.data[entry + 4..]);
+        match self.large_offsets {
+            Some((start, size)) if offset & LARGE_OFFSET != 0 => {
+                let index = (offset & !LARGE_OFFSET) as usize * 8;
+                (index + 8 <= size)
+                    .then(|| (pack, BigEndian::read_u64(&self.data[start + index..])))
+            }
+            _ => Some((pack, offset as u64)),
+        }
+    }
+
+    /// The `.idx` name of the pack storing `id` and the object's offset in it, `None` if `id`
+    /// isn't in any of the packs.
+    pub fn find_object(&self, id: &SHA1) -> Option<(&str, u64)> {
+        let (pack, offset) = self.object_at(self.position(id)?)?;
+        Some((self.pack_names[pack].as_str(), offset))
+    }
+
+    /// Every object as `(id, pack position, offset)`, in id order.
+    pub fn entries(&self) -> impl Iterator<Item = (SHA1, usize, u64)> + '_ {
+        (0..self.count).filter_map(|i| {
+            let (pack, offset) = self.object_at(i)?;
+            Some((self.id_at(i), pack, offset))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::object::types::ObjectType;
+    use crate::internal::pack::idx::{IdxEntry, build_idx_v2};
+
+    fn blob(i: usize) -> SHA1 {
+        SHA1::from_type_and_data(ObjectType::Blob, i.to_string().as_bytes())
+    }
+
+    fn pack_index(objects: &[(SHA1, u64)]) -> PackIndex {
+        let entries: Vec<IdxEntry> = objects
+            .iter()
+            .map(|&(hash, offset)| IdxEntry {
+                hash,
+                offset,
+                crc32: 0,
+            })
+            .collect();
+        PackIndex::from_bytes(build_idx_v2(&entries, &SHA1::new(b"pack"))).unwrap()
+    }
+
+    #[test]
+    fn test_multi_pack_index_roundtrip() {
+        let old = pack_index(&[(blob(0), 12), (blob(1), 40), (blob(2), 80)]);
+        let new = pack_index(&[(blob(2), 12), (blob(3), 5 << 30)]);
+        let midx = MultiPackIndex::from_pack_indexes([("pack-b.idx", &new), ("pack-a.idx", &old)])
+            .unwrap();
+        assert_eq!(midx.pack_names(), ["pack-a.idx", "pack-b.idx"]);
+        assert_eq!(midx.len(), 4);
+        assert_eq!(midx.find_object(&blob(0)), Some(("pack-a.idx", 12)));
+        assert_eq!(midx.find_object(&blob(1)), Some(("pack-a.idx", 40)));
+        // Listed first, the new pack wins for the object both packs hold
+        assert_eq!(midx.find_object(&blob(2)), Some(("pack-b.idx", 12)));
+        assert_eq!(midx.find_object(&blob(3)), Some(("pack-b.idx", 5 << 30)));
+        assert!(!midx.contains(&blob(4)));
+
+        let ids: Vec<SHA1> = midx.entries().map(|(id, _, _)| id).collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let dir = std::env::temp_dir().join(format!("midx-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("multi-pack-index");
+        midx.write_to(&path).unwrap();
+        let reread = MultiPackIndex::open(&path).unwrap();
+        assert_eq!(reread.as_bytes(), midx.as_bytes());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(
+            MultiPackIndex::from_pack_indexes([("pack-a.idx", &old), ("pack-a.idx", &new)]),
+            Err(GitError::InvalidMultiPackIndex(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_multi_pack_index() {
+        let index = pack_index(&[(blob(0), 12)]);
+        let midx = MultiPackIndex::from_pack_indexes([("pack-a.idx", &index)]).unwrap();
+        let data = midx.as_bytes().to_vec();
+
+        let mut corrupt = data.clone();
+        corrupt[HEADER_SIZE + 1] ^= 1;
+        assert!(MultiPackIndex::from_bytes(corrupt).is_err());
+
+        // A pack count that disagrees with PNAM, with a valid checksum
+        let mut packs = data.clone();
+        packs[11] = 2;
+        let trailer = packs.len() - SHA1::SIZE;
+        let checksum = SHA1::new(&packs[..trailer]);
+        packs[trailer..].copy_from_slice(&checksum.0);
+        assert!(matches!(
+            MultiPackIndex::from_bytes(packs),
+            Err(GitError::InvalidMultiPackIndex(_))
+        ));
+        assert!(MultiPackIndex::from_bytes(data[..20].to_vec()).is_err());
+    }
+}
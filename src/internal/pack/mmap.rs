@@ -0,0 +1,413 @@
+//! Memory-mapped pack files, read through lazily mapped windows.
+//!
+//! [`MappedPack`] serves objects of a `.pack` file without reading the whole file: like git's
+//! `core.packedGitWindowSize` and `core.packedGitLimit`, the file is mapped a window at a time
+//! when an object in it is read, at most a total size of windows stays mapped, and the least
+//! recently used windows are unmapped beyond that. Mapped pages belong to the page cache, so
+//! the kernel drops them under memory pressure instead of the process holding them.
+//!
+//! Objects spanning windows are read across them, however large. Without `mmap` (non-unix
+//! targets) windows are read into memory instead, with the same limits.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, Read};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::errors::GitError;
+use crate::hash::SHA1;
+use crate::internal::object::types::ObjectType;
+use crate::internal::pack::base_cache::DeltaBaseCache;
+use crate::internal::pack::idx::{PackData, PackIndex};
+
+/// The size of a window of a new pack, git's default `core.packedGitWindowSize`.
+#[cfg(target_pointer_width = "64")]
+pub const DEFAULT_WINDOW_SIZE: usize = 1 << 30;
+#[cfg(not(target_pointer_width = "64"))]
+pub const DEFAULT_WINDOW_SIZE: usize = 32 << 20;
+
+/// The total size of the windows a new pack keeps mapped, git's default `core.packedGitLimit`.
+#[cfg(target_pointer_width = "64")]
+pub const DEFAULT_MAPPED_LIMIT: usize = 8 << 30;
+#[cfg(not(target_pointer_width = "64"))]
+pub const DEFAULT_MAPPED_LIMIT: usize = 256 << 20;
+
+/// A mapped region of a file, unmapped on drop.
+#[cfg(unix)]
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is read-only and owned by the struct.
+#[cfg(unix)]
+unsafe impl Send for Mapping {}
+#[cfg(unix)]
+unsafe impl Sync for Mapping {}
+
+#[cfg(unix)]
+impl Mapping {
+    /// Map `len` bytes of `file` from `offset`, a multiple of the page size.
+    fn new(file: &File, offset: u64, len: usize) -> io::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        // SAFETY: a fresh read-only private mapping of an open file, checked for failure.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                offset as libc::off_t,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr, len })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        // SAFETY: the mapping is `len` bytes long and lives as long as `self`.
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+
+    /// The alignment of window offsets.
+    fn granularity() -> usize {
+        // SAFETY: sysconf has no preconditions.
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        usize::try_from(page).unwrap_or(4096).max(1)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` and `len` are those of a mapping made by `Mapping::new`.
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+/// A region of a file read into memory, where `mmap` is unavailable.
+#[cfg(not(unix))]
+struct Mapping(Vec<u8>);
+
+#[cfg(not(unix))]
+impl Mapping {
+    fn new(file: &File, offset: u64, len: usize) -> io::Result<Self> {
+        use std::io::{Seek, SeekFrom};
+
+        let mut file = file;
+        let mut data = vec![0; len];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut data)?;
+        Ok(Self(data))
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn granularity() -> usize {
+        64 * 1024
+    }
+}
+
+#[derive(Default)]
+struct Windows {
+    /// The mapped windows by index, with the tick of their last use.
+    mapped: HashMap<u64, (Arc<Mapping>, u64)>,
+    mapped_bytes: usize,
+    tick: u64,
+}
+
+/// A pack file read through memory-mapped windows, see the [module documentation](self).
+pub struct MappedPack {
+    file: File,
+    len: u64,
+    window_size: usize,
+    mapped_limit: usize,
+    windows: Mutex<Windows>,
+}
+
+impl std::fmt::Debug for MappedPack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MappedPack")
+            .field("len", &self.len)
+            .field("window_size", &self.window_size)
+            .field("mapped_limit", &self.mapped_limit)
+            .field("mapped_bytes", &self.mapped_bytes())
+            .finish()
+    }
+}
+
+impl MappedPack {
+    /// Open the pack at `path`, checking its header. Nothing is mapped until an object is read.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, GitError> {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let mut header = [0; 12];
+        file.read_exact(&mut header)
+            .map_err(|_| GitError::InvalidPackFile(format!("pack of {len} bytes is too short")))?;
+        if &header[..4] != b"PACK" {
+            return Err(GitError::InvalidPackHeader(format!("{:?}", &header[..4])));
+        }
+        let version = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        if version != 2 && version != 3 {
+            return Err(GitError::InvalidPackFile(format!(
+                "unsupported pack version {version}"
+            )));
+        }
+        if len < 12 + SHA1::SIZE as u64 {
+            return Err(GitError::InvalidPackFile(format!(
+                "pack of {len} bytes is too short"
+            )));
+        }
+        Ok(Self {
+            file,
+            len,
+            window_size: Self::aligned(DEFAULT_WINDOW_SIZE),
+            mapped_limit: DEFAULT_MAPPED_LIMIT,
+            windows: Mutex::default(),
+        })
+    }
+
+    /// Round `size` up to a multiple of the page size, so windows can be mapped at multiples
+    /// of it.
+    fn aligned(size: usize) -> usize {
+        size.max(1).div_ceil(Mapping::granularity()) * Mapping::granularity()
+    }
+
+    /// Set the size of the windows mapped from now on, rounded up to a multiple of the page
+    /// size. The windows mapped so far are unmapped.
+    pub fn set_window_size(&mut self, size: usize) {
+        self.window_size = Self::aligned(size);
+        *self.windows.get_mut().unwrap() = Windows::default();
+    }
+
+    /// Set the total size of the windows kept mapped. The last window used is kept even when
+    /// it alone exceeds the limit.
+    pub fn set_mapped_limit(&mut self, limit: usize) {
+        self.mapped_limit = limit;
+    }
+
+    /// Size of the pack file in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of windows currently mapped.
+    pub fn mapped_windows(&self) -> usize {
+        self.windows.lock().unwrap().mapped.len()
+    }
+
+    /// Bytes of the pack currently mapped.
+    pub fn mapped_bytes(&self) -> usize {
+        self.windows.lock().unwrap().mapped_bytes
+    }
+
+    /// The trailer checksum of the pack, which names its files.
+    pub fn pack_hash(&self) -> Result<SHA1, GitError> {
+        let mut trailer = [0; SHA1::SIZE];
+        let mut reader = self.reader_at(self.len - SHA1::SIZE as u64);
+        reader.read_exact(&mut trailer)?;
+        Ok(SHA1::from_bytes(&trailer))
+    }
+
+    /// Read the object `id`, `idx` being the index of this pack. Returns `None` if the object
+    /// isn't in the pack.
+    pub fn read_object(
+        &self,
+        idx: &PackIndex,
+        id: &SHA1,
+    ) -> Result<Option<(ObjectType, Vec<u8>)>, GitError> {
+        idx.read_object_from(self, id, None)
+    }
+
+    /// Read the object `id` like [`MappedPack::read_object`], resuming its delta chain from
+    /// the bases of `cache`.
+    pub fn read_object_with_cache(
+        &self,
+        idx: &PackIndex,
+        id: &SHA1,
+        cache: &DeltaBaseCache,
+    ) -> Result<Option<(ObjectType, Vec<u8>)>, GitError> {
+        idx.read_object_from(self, id, Some(cache))
+    }
+
+    /// A reader of the pack from `offset` to its end, mapping windows as it goes.
+    pub fn reader_at(&self, offset: u64) -> MappedReader<'_> {
+        MappedReader {
+            pack: self,
+            window: None,
+            position: offset,
+        }
+    }
+
+    /// The window holding `offset` with the offset it starts at, mapping it if needed.
+    fn window(&self, offset: u64) -> io::Result<(Arc<Mapping>, u64)> {
+        let index = offset / self.window_size as u64;
+        let start = index * self.window_size as u64;
+        let mut windows = self.windows.lock().unwrap();
+        windows.tick += 1;
+        let tick = windows.tick;
+        if let Some((mapping, last_used)) = windows.mapped.get_mut(&index) {
+            *last_used = tick;
+            return Ok((mapping.clone(), start));
+        }
+
+        let len = (self.len - start).min(self.window_size as u64) as usize;
+        while !windows.mapped.is_empty() && windows.mapped_bytes + len > self.mapped_limit {
+            let lru = *windows
+                .mapped
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .unwrap()
+                .0;
+            // Readers still on the window keep it mapped until they are done
+            if let Some((mapping, _)) = windows.mapped.remove(&lru) {
+                windows.mapped_bytes -= mapping.as_slice().len();
+            }
+        }
+        let mapping = Arc::new(Mapping::new(&self.file, start, len)?);
+        windows.mapped_bytes += len;
+        windows.mapped.insert(index, (mapping.clone(), tick));
+        Ok((mapping, start))
+    }
+}
+
+impl PackData for MappedPack {
+    type Reader<'a> = MappedReader<'a>;
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn reader_at(&self, offset: u64) -> Self::Reader<'_> {
+        MappedPack::reader_at(self, offset)
+    }
+}
+
+/// A reader of a [`MappedPack`] going from window to window.
+pub struct MappedReader<'a> {
+    pack: &'a MappedPack,
+    /// The current window and the offset it starts at.
+    window: Option<(Arc<Mapping>, u64)>,
+    position: u64,
+}
+
+impl BufRead for MappedReader<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.position >= self.pack.len {
+            return Ok(&[]);
+        }
+        let current = self.window.as_ref().is_some_and(|(mapping, start)| {
+            self.position >= *start && self.position < start + mapping.as_slice().len() as u64
+        });
+        if !current {
+            self.window = Some(self.pack.window(self.position)?);
+        }
+        let (mapping, start) = self.window.as_ref().unwrap();
+        Ok(&mapping.as_slice()[(self.position - start) as usize..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position += amount as u64;
+    }
+}
+
+impl Read for MappedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let amount = available.len().min(buf.len());
+        buf[..amount].copy_from_slice(&available[..amount]);
+        self.consume(amount);
+        Ok(amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::object::blob::Blob;
+    use crate::internal::pack::cruft::build_cruft_pack;
+    use crate::internal::pack::entry::Entry;
+    use crate::internal::pack::idx::tests::delta_pack;
+
+    fn write_pack(name: &str, pack: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("{name}-{}.pack", std::process::id()));
+        std::fs::write(&path, pack).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_mapped_pack_windows() {
+        // Incompressible blobs, so the pack spans many windows
+        let mut state = 7u32;
+        let blobs: Vec<Blob> = (0..8)
+            .map(|_| {
+                let content: Vec<u8> = (0..6000)
+                    .map(|_| {
+                        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                        (state >> 16) as u8
+                    })
+                    .collect();
+                Blob::from_content_bytes(content)
+            })
+            .collect();
+        let entries = blobs
+            .iter()
+            .map(|blob| (Entry::from(blob.clone()), 1))
+            .collect();
+        let cruft = build_cruft_pack(entries, 0).unwrap().pack.unwrap();
+        let idx = PackIndex::from_bytes(cruft.idx).unwrap();
+        let path = write_pack("mapped-windows", &cruft.pack);
+
+        let mut pack = MappedPack::open(&path).unwrap();
+        pack.set_window_size(1);
+        let window = Mapping::granularity();
+        pack.set_mapped_limit(2 * window);
+        assert_eq!(pack.len(), cruft.pack.len() as u64);
+        assert_eq!(pack.mapped_windows(), 0);
+        assert_eq!(pack.pack_hash().unwrap(), cruft.pack_hash);
+        for blob in &blobs {
+            let (obj_type, data) = pack.read_object(&idx, &blob.id).unwrap().unwrap();
+            assert_eq!((obj_type, data), (ObjectType::Blob, blob.data.clone()));
+            assert!(pack.mapped_bytes() <= 2 * window, "{pack:?}");
+        }
+        let missing = Blob::from_content("missing").id;
+        assert!(pack.read_object(&idx, &missing).unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_mapped_pack_deltas() {
+        let (pack, idx) = delta_pack();
+        let idx = PackIndex::from_bytes(idx).unwrap();
+        let path = write_pack("mapped-deltas", &pack);
+        let mapped = MappedPack::open(&path).unwrap();
+        let cache = DeltaBaseCache::default();
+        for content in [&b"hello world"[..], b"hello there", b"hello"] {
+            let id = SHA1::from_type_and_data(ObjectType::Blob, content);
+            let (_, data) = mapped
+                .read_object_with_cache(&idx, &id, &cache)
+                .unwrap()
+                .unwrap();
+            assert_eq!(data, content);
+        }
+        assert_eq!(cache.hits(), 2);
+        assert_eq!(mapped.mapped_windows(), 1);
+
+        std::fs::write(&path, b"PACK\0\0\0\x04\0\0\0\0").unwrap();
+        let err = MappedPack::open(&path).unwrap_err();
+        assert!(err.to_string().contains("version 4"), "{err}");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -2,26 +2,62 @@
 //! ## Reference
 //! 1. Git Pack-Format [Introduce](https://git-scm.com/docs/pack-format)
 //!
+pub mod base_cache;
+pub mod bitmap;
+pub mod bloom;
+#[doc(hidden)]
 pub mod cache;
+#[doc(hidden)]
 pub mod cache_object;
+#[doc(hidden)]
 pub mod channel_reader;
+pub mod cruft;
 pub mod decode;
+pub mod diagnostics;
 pub mod encode;
 pub mod entry;
+pub mod idx;
+pub mod midx;
+pub mod mmap;
+pub mod reader;
+#[doc(hidden)]
 pub mod utils;
+pub mod verify;
+#[doc(hidden)]
 pub mod waitlist;
+#[doc(hidden)]
 pub mod wrapper;
 
-use std::sync::Arc;
+use std::io::Read;
 use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use dashmap::DashMap;
 use threadpool::ThreadPool;
 
+use crate::errors::GitError;
 use crate::hash::SHA1;
 use crate::internal::object::ObjectTrait;
+use crate::internal::object::types::ObjectType;
 use crate::internal::pack::cache::Caches;
+use crate::internal::pack::diagnostics::{DeltaFailure, DeltaStats};
+use crate::internal::pack::idx::IdxVersion;
+use crate::internal::pack::entry::Entry;
 use crate::internal::pack::waitlist::Waitlist;
 
-const DEFAULT_TMP_DIR: &str = "./.cache_temp";
+/// Directory of the spilled delta bases in the system temp dir, unless another one is given
+const DEFAULT_TMP_DIR: &str = "git-internal-cache";
+
+/// Callback receiving each decoded entry with its offset in the pack
+pub(crate) type EntryCallback = Arc<dyn Fn(Entry, usize) + Sync + Send>;
+
+/// Consumer of the content of a large object as it is inflated, see
+/// [`Pack::set_large_object_sink`]. Given the type, size and content of the object, it returns
+/// the id the object was stored under.
+pub type LargeObjectSink =
+    Arc<dyn Fn(ObjectType, usize, &mut dyn Read) -> Result<SHA1, GitError> + Sync + Send>;
+
 pub struct Pack {
     pub number: usize,
     pub signature: SHA1,
@@ -32,6 +68,34 @@ pub struct Pack {
     pub mem_limit: Option<usize>,
     pub cache_objs_mem: Arc<AtomicUsize>,
     pub clean_tmp: bool,
+    /// Keep decoding past unresolvable deltas, see [`Pack::set_salvage`]
+    pub salvage: bool,
+    /// Delta statistics of the last decode
+    pub delta_stats: DeltaStats,
+    /// Collect the idx of the pack while decoding, see [`Pack::set_build_idx`]
+    pub build_idx: bool,
+    /// The version of the idx built while decoding, see [`Pack::set_idx_version`]
+    idx_version: IdxVersion,
+    /// The `(offset, CRC32)` of every entry of the last decode, when building the idx
+    idx_crcs: Vec<(usize, u32)>,
+    /// The ids of the decoded objects by offset, when building the idx
+    idx_hashes: Arc<Mutex<Vec<(usize, SHA1)>>>,
+    idx: Option<Vec<u8>>,
+    /// Keep deltas whose bases are missing from the pack, see [`Pack::set_thin`]
+    pub thin: bool,
+    /// Stop reading at the trailer of the pack, see [`Pack::set_stop_at_trailer`]
+    pub stop_at_trailer: bool,
+    /// The callback and start of a decode waiting for [`Pack::resolve_thin`]
+    thin_pending: Option<(EntryCallback, Instant)>,
+    delta_failures: Arc<Mutex<Vec<DeltaFailure>>>,
+    /// The id of the first object rebuilt from a delta that is a collision attack
+    hash_collision: Arc<Mutex<Option<String>>>,
+    chain_depths: Arc<DashMap<usize, usize>>,
+    max_chain_len: Arc<AtomicUsize>,
+    /// Size above which whole blobs go to the sink instead of memory
+    large_objects: Option<(usize, LargeObjectSink)>,
+    /// The ids of the objects handed to the large object sink during the last decode
+    streamed: Vec<SHA1>,
 }
 
 #[cfg(test)]
@@ -0,0 +1,147 @@
+//! Random access to the objects of a pack through its idx.
+//!
+//! [`PackReader`] pairs a pack, in memory or a [`MappedPack`], with its [`PackIndex`] and reads
+//! single objects by id or by offset, resolving their delta chains within the pack, so a
+//! `get_object` implementation can serve objects straight from stored packs instead of
+//! unpacking them eagerly. An optional [`DeltaBaseCache`] keeps the bases inflated along the
+//! way for the next reads.
+use std::io::Read;
+use std::path::Path;
+
+use crate::errors::GitError;
+use crate::hash::SHA1;
+use crate::internal::object::types::ObjectType;
+use crate::internal::pack::base_cache::DeltaBaseCache;
+use crate::internal::pack::idx::{PackData, PackIndex};
+use crate::internal::pack::mmap::MappedPack;
+
+/// A pack with its index, see the [module documentation](self).
+#[derive(Debug)]
+pub struct PackReader<P = Vec<u8>> {
+    pack: P,
+    idx: PackIndex,
+    cache: Option<DeltaBaseCache>,
+}
+
+impl PackReader<Vec<u8>> {
+    /// A reader of the pack `pack` held in memory, `idx` being the content of its `.idx`.
+    pub fn from_bytes(pack: Vec<u8>, idx: Vec<u8>) -> Result<Self, GitError> {
+        Self::new(pack, PackIndex::from_bytes(idx)?)
+    }
+}
+
+impl PackReader<MappedPack> {
+    /// A reader of the pack file at `path`, mapped as it is read, with the `.idx` next to it.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, GitError> {
+        let path = path.as_ref();
+        let idx = PackIndex::open(path.with_extension("idx"))?;
+        Self::new(MappedPack::open(path)?, idx)
+    }
+}
+
+impl<P: PackData> PackReader<P> {
+    /// A reader of `pack`, checking that `idx` is the index of this pack.
+    pub fn new(pack: P, idx: PackIndex) -> Result<Self, GitError> {
+        let len = pack.len();
+        if len < 12 + SHA1::SIZE as u64 {
+            return Err(GitError::InvalidPackFile(format!(
+                "pack of {len} bytes is too short"
+            )));
+        }
+        let mut trailer = [0; SHA1::SIZE];
+        pack.reader_at(len - SHA1::SIZE as u64)
+            .read_exact(&mut trailer)?;
+        let pack_hash = SHA1::from_bytes(&trailer);
+        if pack_hash != idx.pack_hash() {
+            return Err(GitError::InvalidPackFile(format!(
+                "pack {pack_hash} doesn't match its idx, which is of pack {}",
+                idx.pack_hash()
+            )));
+        }
+        Ok(Self {
+            pack,
+            idx,
+            cache: None,
+        })
+    }
+
+    /// Resolve deltas from the bases of `cache`, e.g. one shared by every pack of a repository.
+    pub fn set_delta_base_cache(&mut self, cache: DeltaBaseCache) {
+        self.cache = Some(cache);
+    }
+
+    pub fn pack(&self) -> &P {
+        &self.pack
+    }
+
+    pub fn index(&self) -> &PackIndex {
+        &self.idx
+    }
+
+    /// The trailer checksum of the pack, which names its files.
+    pub fn pack_hash(&self) -> SHA1 {
+        self.idx.pack_hash()
+    }
+
+    pub fn contains(&self, id: &SHA1) -> bool {
+        self.idx.contains(id)
+    }
+
+    /// Read the object `id`, `None` if it isn't in the pack.
+    pub fn read_object(&self, id: &SHA1) -> Result<Option<(ObjectType, Vec<u8>)>, GitError> {
+        self.idx
+            .read_object_from(&self.pack, id, self.cache.as_ref())
+    }
+
+    /// Read the object whose entry starts at `offset`, e.g. an offset of
+    /// [`PackIndex::entries`], with its id.
+    pub fn read_object_at(&self, offset: u64) -> Result<(ObjectType, Vec<u8>, SHA1), GitError> {
+        self.idx
+            .resolve_object_at(&self.pack, offset as usize, self.cache.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::pack::idx::tests::delta_pack;
+
+    #[test]
+    fn test_pack_reader() {
+        let (pack, idx) = delta_pack();
+        let mut reader = PackReader::from_bytes(pack.clone(), idx.clone()).unwrap();
+        reader.set_delta_base_cache(DeltaBaseCache::default());
+        let entries: Vec<(SHA1, u64)> = reader.index().entries().collect();
+        assert_eq!(entries.len(), 3);
+        for (id, offset) in entries {
+            let (obj_type, data, hash) = reader.read_object_at(offset).unwrap();
+            assert_eq!((obj_type, hash), (ObjectType::Blob, id));
+            assert_eq!(reader.read_object(&id).unwrap(), Some((obj_type, data)));
+        }
+        let missing = SHA1::from_type_and_data(ObjectType::Blob, b"missing");
+        assert!(!reader.contains(&missing));
+        assert_eq!(reader.read_object(&missing).unwrap(), None);
+        assert!(reader.read_object_at(pack.len() as u64).is_err());
+
+        // The same pack through its files
+        let dir = std::env::temp_dir().join(format!("pack-reader-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("pack-{}.pack", reader.pack_hash()));
+        std::fs::write(&path, &pack).unwrap();
+        std::fs::write(path.with_extension("idx"), &idx).unwrap();
+        let mapped = PackReader::open(&path).unwrap();
+        let hello = SHA1::from_type_and_data(ObjectType::Blob, b"hello there");
+        assert_eq!(
+            mapped.read_object(&hello).unwrap().unwrap().1,
+            b"hello there"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // An idx of another pack
+        let mut other = pack.clone();
+        let last = other.len() - 1;
+        other[last] ^= 1;
+        let err = PackReader::from_bytes(other, idx).unwrap_err();
+        assert!(err.to_string().contains("doesn't match its idx"), "{err}");
+    }
+}
@@ -1,4 +1,3 @@
-use sha1::{Digest, Sha1};
 use std::fs;
 use std::io::{self, Read};
 use std::path::Path;
@@ -258,19 +257,8 @@ pub fn read_delta_object_size<R: Read>(stream: &mut R) -> io::Result<(usize, usi
 /// Calculate the SHA1 hash of the given object.
 /// <br> "`<type> <size>\0<content>`"
 /// <br> data: The decompressed content of the object
-pub fn calculate_object_hash(obj_type: ObjectType, data: &Vec<u8>) -> SHA1 {
-    let mut hash = Sha1::new();
-    // Header: "<type> <size>\0"
-    hash.update(obj_type.to_bytes());
-    hash.update(b" ");
-    hash.update(data.len().to_string());
-    hash.update(b"\0");
-
-    // Decompressed data(raw content)
-    hash.update(data);
-
-    let re: [u8; 20] = hash.finalize().into();
-    SHA1(re)
+pub fn calculate_object_hash(obj_type: ObjectType, data: &[u8]) -> SHA1 {
+    SHA1::from_type_and_data(obj_type, data)
 }
 /// Create an empty directory or clear the existing directory.
 pub fn create_empty_dir<P: AsRef<Path>>(path: P) -> io::Result<()> {
@@ -323,7 +311,7 @@ mod tests {
 
     #[test]
     fn test_calc_obj_hash() {
-        let hash = calculate_object_hash(ObjectType::Blob, &b"a".to_vec());
+        let hash = calculate_object_hash(ObjectType::Blob, b"a");
         assert_eq!(hash.to_string(), "2e65efe2a145dda7ee51d1741299f848e5bf752e");
     }
 
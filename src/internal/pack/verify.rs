@@ -0,0 +1,420 @@
+//! Integrity check of a pack against its index, like `git verify-pack`.
+//!
+//! [`verify_pack`] reads every entry of the pack at the offset the idx gives for it, checks the
+//! CRC32 of its raw bytes and that it inflates to exactly the space up to the next entry, rebuilds
+//! the deltas and compares each resulting id with the one the idx lists. The pack trailer must be
+//! the checksum of the content and match the pack checksum stored in the idx.
+//!
+//! Entries are visited in pack order, and a rebuilt object is only kept in memory until the last
+//! delta on it has been resolved, so a sweep over a large pack holds little more than its
+//! widest delta fan-out.
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::sync::Arc;
+
+use crate::errors::GitError;
+use crate::hash::SHA1;
+use crate::internal::object::types::ObjectType;
+use crate::internal::pack::Pack;
+use crate::internal::pack::cache_object::{CacheObject, CacheObjectInfo};
+use crate::internal::pack::idx::PackIndex;
+use crate::internal::pack::utils;
+
+/// Size of the pack header: magic, version and object count.
+const PACK_HEADER_SIZE: usize = 12;
+
+/// Statistics of an object of a verified pack, the columns of `git verify-pack -v`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedObject {
+    pub hash: SHA1,
+    /// Type of the rebuilt object, never a delta type.
+    pub obj_type: ObjectType,
+    /// Size of the rebuilt object.
+    pub size: usize,
+    /// Size of the raw entry in the pack: header, delta base and compressed data.
+    pub packed_size: usize,
+    /// Offset of the entry in the pack.
+    pub offset: u64,
+    /// Length of the delta chain down to a base object, 0 for undeltified objects.
+    pub depth: usize,
+    /// The object the entry is a delta on.
+    pub base: Option<SHA1>,
+}
+
+/// The result of [`verify_pack`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackVerification {
+    /// The pack checksum, found in both the pack trailer and the idx.
+    pub pack_hash: SHA1,
+    /// Every object of the pack, in pack order.
+    pub objects: Vec<VerifiedObject>,
+}
+
+impl PackVerification {
+    /// Number of objects stored as deltas.
+    pub fn deltas(&self) -> usize {
+        self.objects.iter().filter(|o| o.base.is_some()).count()
+    }
+
+    /// Longest delta chain of the pack.
+    pub fn max_depth(&self) -> usize {
+        self.objects.iter().map(|o| o.depth).max().unwrap_or(0)
+    }
+}
+
+/// An entry located through the idx, before being inflated.
+struct Located {
+    hash: SHA1,
+    offset: usize,
+    end: usize,
+    crc32: Option<u32>,
+    base: Option<usize>,
+}
+
+/// Verify `pack`, the content of a pack file, against its index `idx`.
+///
+/// Fails on the first corruption found: a bad header or trailer, an idx listing other objects
+/// than the pack holds, a CRC32 mismatch, an entry that doesn't inflate or a delta that doesn't
+/// rebuild to the listed id.
+pub fn verify_pack(pack: &[u8], idx: &PackIndex) -> Result<PackVerification, GitError> {
+    if pack.len() < PACK_HEADER_SIZE + SHA1::SIZE {
+        return Err(GitError::InvalidPackFile("truncated pack".to_string()));
+    }
+    let (count, _) = Pack::check_header(&mut Cursor::new(pack))?;
+    if count as usize != idx.len() {
+        return Err(GitError::InvalidPackFile(format!(
+            "the pack holds {count} objects, the idx lists {}",
+            idx.len()
+        )));
+    }
+    let content_end = pack.len() - SHA1::SIZE;
+    let pack_hash = SHA1::from_bytes(&pack[content_end..]);
+    let content_hash = SHA1::new(&pack[..content_end]);
+    if content_hash != pack_hash {
+        return Err(GitError::PackChecksumMismatch(format!(
+            "The pack file hash {content_hash} does not match the trailer hash {pack_hash}"
+        )));
+    }
+    if idx.pack_hash() != pack_hash {
+        return Err(GitError::PackChecksumMismatch(format!(
+            "The idx belongs to pack {}, not {pack_hash}",
+            idx.pack_hash()
+        )));
+    }
+
+    let entries = locate_entries(pack, idx, content_end)?;
+    let ids: HashMap<usize, SHA1> = entries.iter().map(|e| (e.offset, e.hash)).collect();
+    let depths = delta_depths(&entries)?;
+    let mut dependents: HashMap<usize, usize> = HashMap::new();
+    for base in entries.iter().filter_map(|e| e.base) {
+        *dependents.entry(base).or_default() += 1;
+    }
+
+    // Rebuilt objects that still have deltas to resolve, by offset
+    let mut resolved: HashMap<usize, Arc<CacheObject>> = HashMap::new();
+    let mut objects = Vec::with_capacity(entries.len());
+    for entry in &entries {
+        let raw = &pack[entry.offset..entry.end];
+        if let Some(expected) = entry.crc32 {
+            let mut crc = flate2::Crc::new();
+            crc.update(raw);
+            if crc.sum() != expected {
+                return Err(GitError::InvalidPackFile(format!(
+                    "CRC32 mismatch of {} at offset {}",
+                    entry.hash, entry.offset
+                )));
+            }
+        }
+
+        let mut next = entry.offset;
+        let object = Pack::decode_pack_object(&mut Cursor::new(raw), &mut next)?;
+        if next != entry.end {
+            return Err(GitError::InvalidPackFile(format!(
+                "entry of {} at offset {} ends at {next}, the next entry starts at {}",
+                entry.hash, entry.offset, entry.end
+            )));
+        }
+        let object = match (&object.info, entry.base) {
+            (CacheObjectInfo::BaseObject(..), _) => object,
+            (info, Some(base_offset)) => {
+                let base = match resolved.get(&base_offset) {
+                    Some(base) => base.clone(),
+                    // A hash delta on an object further in the pack
+                    None => {
+                        let (obj_type, data, _) = idx.read_object_at(pack, base_offset)?;
+                        Arc::new(CacheObject::try_new_for_undeltified(
+                            obj_type,
+                            data,
+                            base_offset,
+                        )?)
+                    }
+                };
+                let rebuilt = match info {
+                    CacheObjectInfo::OffsetZstdelta(..) => Pack::rebuild_zstdelta(object, base)?,
+                    _ => Pack::rebuild_delta(object, base)?,
+                };
+                let remaining = dependents.get_mut(&base_offset).unwrap();
+                *remaining -= 1;
+                if *remaining == 0 {
+                    resolved.remove(&base_offset);
+                }
+                rebuilt
+            }
+            (_, None) => unreachable!("deltas are located with their base"),
+        };
+
+        let hash = object.base_object_hash().unwrap();
+        if hash != entry.hash {
+            return Err(GitError::InvalidPackFile(format!(
+                "object at offset {} is {hash}, the idx lists {}",
+                entry.offset, entry.hash
+            )));
+        }
+        objects.push(VerifiedObject {
+            hash,
+            obj_type: object.object_type(),
+            size: object.data_decompressed.len(),
+            packed_size: entry.end - entry.offset,
+            offset: entry.offset as u64,
+            depth: depths[&entry.offset],
+            base: entry.base.map(|base| ids[&base]),
+        });
+        if dependents.get(&entry.offset).is_some_and(|&n| n > 0) {
+            resolved.insert(entry.offset, Arc::new(object));
+        }
+    }
+    Ok(PackVerification { pack_hash, objects })
+}
+
+/// The entries of the idx in pack order, with the extent and delta base of each read from the
+/// entry headers.
+fn locate_entries(
+    pack: &[u8],
+    idx: &PackIndex,
+    content_end: usize,
+) -> Result<Vec<Located>, GitError> {
+    let mut entries: Vec<Located> = (0..idx.len())
+        .map(|i| {
+            let offset = idx.offset_at(i).ok_or_else(|| {
+                GitError::InvalidIdxFile(format!("offset of {} is missing", idx.id_at(i)))
+            })? as usize;
+            Ok(Located {
+                hash: idx.id_at(i),
+                offset,
+                end: content_end,
+                crc32: idx.crc32_at(i),
+                base: None,
+            })
+        })
+        .collect::<Result<_, GitError>>()?;
+    entries.sort_by_key(|entry| entry.offset);
+    for i in 0..entries.len() {
+        let offset = entries[i].offset;
+        let end = entries.get(i + 1).map_or(content_end, |next| next.offset);
+        if offset < PACK_HEADER_SIZE || offset >= end {
+            return Err(GitError::InvalidIdxFile(format!(
+                "{} is listed at offset {offset}, outside of the pack or shared",
+                entries[i].hash
+            )));
+        }
+        entries[i].end = end;
+    }
+
+    let ids: HashMap<SHA1, usize> = entries.iter().map(|e| (e.hash, e.offset)).collect();
+    let offsets: HashSet<usize> = ids.values().copied().collect();
+    let invalid = |e: std::io::Error| GitError::InvalidPackFile(format!("Read error: {e}"));
+    for entry in &mut entries {
+        let mut cursor = Cursor::new(&pack[entry.offset..entry.end]);
+        let mut next = entry.offset;
+        let (type_bits, _) =
+            utils::read_type_and_varint_size(&mut cursor, &mut next).map_err(invalid)?;
+        entry.base = match ObjectType::from_u8(type_bits)? {
+            ObjectType::OffsetDelta | ObjectType::OffsetZstdelta => {
+                let (distance, _) = utils::read_offset_encoding(&mut cursor).map_err(invalid)?;
+                let base = entry.offset.checked_sub(distance as usize);
+                match base.filter(|base| offsets.contains(base)) {
+                    Some(base) => Some(base),
+                    None => {
+                        return Err(GitError::InvalidPackFile(format!(
+                            "delta base of {} is not an entry of the pack",
+                            entry.hash
+                        )));
+                    }
+                }
+            }
+            ObjectType::HashDelta => {
+                let base = SHA1::from_stream(&mut cursor).map_err(invalid)?;
+                match ids.get(&base) {
+                    Some(&offset) => Some(offset),
+                    None => {
+                        return Err(GitError::InvalidPackFile(format!(
+                            "delta base {base} of {} is not in the pack",
+                            entry.hash
+                        )));
+                    }
+                }
+            }
+            _ => None,
+        };
+    }
+    Ok(entries)
+}
+
+/// The delta chain length of every entry by offset, failing on cycles.
+fn delta_depths(entries: &[Located]) -> Result<HashMap<usize, usize>, GitError> {
+    let bases: HashMap<usize, Option<usize>> = entries.iter().map(|e| (e.offset, e.base)).collect();
+    let mut depths: HashMap<usize, usize> = HashMap::with_capacity(entries.len());
+    for entry in entries {
+        // Walk down to an object of known depth, then number the chain back up
+        let mut chain = Vec::new();
+        let mut offset = entry.offset;
+        let mut depth = loop {
+            if let Some(&depth) = depths.get(&offset) {
+                break depth;
+            }
+            match bases[&offset] {
+                None => break 0,
+                Some(base) => {
+                    if chain.len() > entries.len() {
+                        return Err(GitError::InvalidPackFile(format!(
+                            "delta chain of {} loops",
+                            entry.hash
+                        )));
+                    }
+                    chain.push(offset);
+                    offset = base;
+                }
+            }
+        };
+        depths.insert(offset, depth);
+        while let Some(offset) = chain.pop() {
+            depth += 1;
+            depths.insert(offset, depth);
+        }
+    }
+    Ok(depths)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::Compression;
+    use flate2::write::ZlibEncoder;
+
+    use super::*;
+    use crate::internal::pack::idx::{IdxEntry, build_idx_v2};
+
+    /// A pack of a blob, an offset delta on it and a hash delta on the delta, with its idx.
+    fn chain_pack() -> (Vec<u8>, PackIndex) {
+        let hello = SHA1::from_type_and_data(ObjectType::Blob, b"hello");
+        let world = SHA1::from_type_and_data(ObjectType::Blob, b"hello world");
+        // copy "hello", insert " world"; then copy "hello" of "hello world", insert " there"
+        let to_world = [&[0x05, 0x0b, 0x90, 0x05, 0x06][..], b" world"].concat();
+        let to_there = [&[0x0b, 0x0b, 0x90, 0x05, 0x06][..], b" there"].concat();
+        let mut pack = b"PACK".to_vec();
+        pack.extend_from_slice(&2u32.to_be_bytes());
+        pack.extend_from_slice(&3u32.to_be_bytes());
+        let mut entries = Vec::new();
+        for (obj_type, data, hash) in [
+            (3u8, &b"hello"[..], hello),
+            (6, &to_world, world),
+            (
+                7,
+                &to_there,
+                SHA1::from_type_and_data(ObjectType::Blob, b"hello there"),
+            ),
+        ] {
+            let offset = pack.len();
+            pack.push((obj_type << 4) | data.len() as u8);
+            match obj_type {
+                6 => pack.push((offset - 12) as u8),
+                7 => pack.extend_from_slice(&world.0),
+                _ => {}
+            }
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data).unwrap();
+            pack.extend(encoder.finish().unwrap());
+            let mut crc = flate2::Crc::new();
+            crc.update(&pack[offset..]);
+            entries.push(IdxEntry {
+                hash,
+                offset: offset as u64,
+                crc32: crc.sum(),
+            });
+        }
+        let checksum = SHA1::new(&pack);
+        pack.extend_from_slice(&checksum.0);
+        let idx = PackIndex::from_bytes(build_idx_v2(&entries, &checksum)).unwrap();
+        (pack, idx)
+    }
+
+    #[test]
+    fn test_verify_pack() {
+        let (pack, idx) = chain_pack();
+        let verification = verify_pack(&pack, &idx).unwrap();
+        assert_eq!(verification.pack_hash, idx.pack_hash());
+        let stats: Vec<_> = verification
+            .objects
+            .iter()
+            .map(|o| (o.obj_type, o.size, o.depth, o.base))
+            .collect();
+        let hello = SHA1::from_type_and_data(ObjectType::Blob, b"hello");
+        let world = SHA1::from_type_and_data(ObjectType::Blob, b"hello world");
+        assert_eq!(
+            stats,
+            vec![
+                (ObjectType::Blob, 5, 0, None),
+                (ObjectType::Blob, 11, 1, Some(hello)),
+                (ObjectType::Blob, 11, 2, Some(world)),
+            ]
+        );
+        assert_eq!(verification.objects[0].offset, 12);
+        let packed: usize = verification.objects.iter().map(|o| o.packed_size).sum();
+        assert_eq!(packed, pack.len() - 12 - SHA1::SIZE);
+        assert_eq!((verification.deltas(), verification.max_depth()), (2, 2));
+    }
+
+    #[test]
+    fn test_verify_pack_corruption() {
+        let (pack, idx) = chain_pack();
+
+        let mut corrupt = pack.clone();
+        corrupt[20] ^= 0xff;
+        assert!(matches!(
+            verify_pack(&corrupt, &idx),
+            Err(GitError::PackChecksumMismatch(_))
+        ));
+
+        // An idx with a wrong CRC32 for the second entry
+        let mut entries: Vec<IdxEntry> = (0..idx.len())
+            .map(|i| IdxEntry {
+                hash: idx.id_at(i),
+                offset: idx.offset_at(i).unwrap(),
+                crc32: idx.crc32_at(i).unwrap(),
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.offset);
+        entries[1].crc32 ^= 1;
+        let bad_crc = PackIndex::from_bytes(build_idx_v2(&entries, &idx.pack_hash())).unwrap();
+        match verify_pack(&pack, &bad_crc) {
+            Err(GitError::InvalidPackFile(msg)) => {
+                assert!(msg.starts_with("CRC32 mismatch"), "{msg}")
+            }
+            r => panic!("unexpected result: {r:?}"),
+        }
+
+        // An idx listing another object at the same offset
+        entries[1].crc32 ^= 1;
+        entries[2].hash = SHA1::new(b"other");
+        let wrong_id = PackIndex::from_bytes(build_idx_v2(&entries, &idx.pack_hash())).unwrap();
+        assert!(verify_pack(&pack, &wrong_id).is_err());
+
+        // The idx of another pack
+        let other = PackIndex::from_bytes(build_idx_v2(&entries, &SHA1::new(b"pack"))).unwrap();
+        assert!(matches!(
+            verify_pack(&pack, &other),
+            Err(GitError::PackChecksumMismatch(_))
+        ));
+    }
+}
@@ -1,5 +1,6 @@
 use std::io::{self, BufRead, Read};
 
+use flate2::Crc;
 use sha1::{Digest, Sha1};
 
 use crate::hash::SHA1;
@@ -12,10 +13,12 @@ use crate::hash::SHA1;
 /// * `inner`: The inner reader.
 /// * `hash`: The SHA1 hash state.
 /// * `count_hash`: A flag to indicate whether to compute the hash while reading.
+/// * `crc`: The CRC32 of the data read since the last [`Wrapper::take_crc`], when enabled.
 pub struct Wrapper<R> {
     inner: R,
     hash: Sha1,
     bytes_read: usize,
+    crc: Option<Crc>,
 }
 
 impl<R> Wrapper<R>
@@ -32,9 +35,25 @@ where
             inner,
             hash: Sha1::new(), // Initialize a new SHA1 hasher
             bytes_read: 0,
+            crc: None,
         }
     }
 
+    /// Also compute the CRC32 of the data read, e.g. of each pack entry for its idx.
+    pub fn enable_crc(&mut self) {
+        self.crc = Some(Crc::new());
+    }
+
+    /// Returns the CRC32 of the data read since the last call and starts over, `0` when the CRC
+    /// isn't enabled.
+    pub fn take_crc(&mut self) -> u32 {
+        self.crc.as_mut().map_or(0, |crc| {
+            let sum = crc.sum();
+            crc.reset();
+            sum
+        })
+    }
+
     pub fn bytes_read(&self) -> usize {
         self.bytes_read
     }
@@ -64,6 +83,9 @@ where
     fn consume(&mut self, amt: usize) {
         let buffer = self.inner.fill_buf().expect("Failed to fill buffer");
         self.hash.update(&buffer[..amt]); // Update hash with the data being consumed
+        if let Some(crc) = &mut self.crc {
+            crc.update(&buffer[..amt]);
+        }
         self.inner.consume(amt); // Consume the data from the inner reader
         self.bytes_read += amt;
     }
@@ -84,6 +106,9 @@ where
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let o = self.inner.read(buf)?; // Read data into the buffer
         self.hash.update(&buf[..o]); // Update hash with the data being read
+        if let Some(crc) = &mut self.crc {
+            crc.update(&buf[..o]);
+        }
         self.bytes_read += o;
         Ok(o) // Return the number of bytes read
     }
@@ -0,0 +1,824 @@
+//! Loose and packed refs of a git directory.
+//!
+//! git first stores each ref as a loose file under the git directory, e.g. `refs/heads/main`,
+//! until `git pack-refs` or `git gc` moves them into the single `packed-refs` file, which also
+//! records the commit each annotated tag peels to on a `^<id>` line. A loose ref overrides a
+//! packed ref of the same name.
+//!
+//! [`PackedRefs`] reads and writes the `packed-refs` format. [`RefStore`] merges the loose and
+//! packed refs of a directory and updates them with git's `.lock` file protocol, so it can
+//! share a bare repository with git itself. Updates made with
+//! [`RefStore::update_ref_logged`] are recorded in the reflog of the ref, `logs/<ref>`.
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::errors::GitError;
+use crate::hash::SHA1;
+use crate::protocol::types::{ReflogEntry, ZERO_ID};
+
+/// How deep symbolic refs are followed, like git's `SYMREF_MAXDEPTH`.
+pub(crate) const MAX_SYMREF_DEPTH: usize = 5;
+
+/// The content of a ref.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefValue {
+    /// An object id.
+    Direct(SHA1),
+    /// `ref: <target>`, e.g. `HEAD` pointing to a branch.
+    Symbolic(String),
+}
+
+/// Check `name` against git's `check-ref-format` rules, one-level names such as `HEAD`
+/// allowed:
+///
+/// - no component is empty, starts with `.` or ends with `.lock`, so the name doesn't start
+///   or end with `/` or contain `//`;
+/// - no `..`, no `@{`, and not the single `@`;
+/// - no ASCII control character, space, `~`, `^`, `:`, `?`, `*`, `[` or `\`;
+/// - no trailing `.`.
+pub fn validate_ref_name(name: &str) -> Result<(), GitError> {
+    let invalid = |reason: &str| Err(GitError::InvalidRefName(name.to_string(), reason.into()));
+    if name.is_empty() {
+        return invalid("empty name");
+    }
+    if name == "@" {
+        return invalid("`@` alone");
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|&c| c.is_ascii_control() || " ~^:?*[\\".contains(c))
+    {
+        return invalid(&format!("forbidden character {c:?}"));
+    }
+    if name.contains("..") {
+        return invalid("contains `..`");
+    }
+    if name.contains("@{") {
+        return invalid("contains `@{`");
+    }
+    if name.ends_with('.') {
+        return invalid("ends with `.`");
+    }
+    for component in name.split('/') {
+        if component.is_empty() {
+            return invalid("empty component");
+        }
+        if component.starts_with('.') {
+            return invalid("component starting with `.`");
+        }
+        if component.ends_with(".lock") {
+            return invalid("component ending with `.lock`");
+        }
+    }
+    Ok(())
+}
+
+/// A ref of `packed-refs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackedRef {
+    pub id: SHA1,
+    /// What `id` peels to when it is an annotated tag.
+    pub peeled: Option<SHA1>,
+}
+
+/// Which refs of a `packed-refs` file have their peeled line, from the traits of its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Peeling {
+    /// No ref does.
+    None,
+    /// The annotated tags under `refs/tags/` do (trait `peeled`).
+    Tags,
+    /// Every ref that peels does (trait `fully-peeled`).
+    Full,
+}
+
+/// The content of a `packed-refs` file, refs sorted by name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackedRefs {
+    refs: BTreeMap<String, PackedRef>,
+    peeling: Peeling,
+}
+
+impl Default for PackedRefs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PackedRefs {
+    /// No refs. The refs inserted are expected to come with their peeled id when they have one.
+    pub fn new() -> Self {
+        Self {
+            refs: BTreeMap::new(),
+            peeling: Peeling::Full,
+        }
+    }
+
+    /// Parse the content of a `packed-refs` file.
+    pub fn parse(content: &str) -> Result<Self, GitError> {
+        let invalid = |line: &str| GitError::InvalidReference(format!("packed-refs line `{line}`"));
+        let mut packed = Self {
+            refs: BTreeMap::new(),
+            peeling: Peeling::None,
+        };
+        let mut last: Option<&str> = None;
+        for line in content.lines() {
+            if let Some(header) = line.strip_prefix("# pack-refs with:") {
+                let traits: Vec<&str> = header.split_whitespace().collect();
+                packed.peeling = if traits.contains(&"fully-peeled") {
+                    Peeling::Full
+                } else if traits.contains(&"peeled") {
+                    Peeling::Tags
+                } else {
+                    Peeling::None
+                };
+            } else if line.starts_with('#') || line.is_empty() {
+                continue;
+            } else if let Some(peeled) = line.strip_prefix('^') {
+                // Peels the ref of the line above
+                let entry = last
+                    .take()
+                    .and_then(|name| packed.refs.get_mut(name))
+                    .ok_or_else(|| invalid(line))?;
+                entry.peeled = Some(SHA1::from_str(peeled).map_err(|_| invalid(line))?);
+            } else {
+                let (id, name) = line.split_once(' ').ok_or_else(|| invalid(line))?;
+                let id = SHA1::from_str(id).map_err(|_| invalid(line))?;
+                packed
+                    .refs
+                    .insert(name.to_string(), PackedRef { id, peeled: None });
+                last = Some(name);
+            }
+        }
+        Ok(packed)
+    }
+
+    /// Read `<dir>/packed-refs`, no refs if there is none.
+    pub fn read(dir: impl AsRef<Path>) -> Result<Self, GitError> {
+        match fs::read_to_string(dir.as_ref().join("packed-refs")) {
+            Ok(content) => Self::parse(&content),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replace `<dir>/packed-refs` with these refs, through `packed-refs.lock` like git.
+    pub fn write(&self, dir: impl AsRef<Path>) -> Result<(), GitError> {
+        LockFile::acquire(&dir.as_ref().join("packed-refs"))?.commit(&self.to_bytes())
+    }
+
+    /// The `packed-refs` file of these refs, with the header git writes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let traits = match self.peeling {
+            Peeling::None => "",
+            Peeling::Tags => " peeled",
+            Peeling::Full => " peeled fully-peeled",
+        };
+        let mut out = format!("# pack-refs with:{traits} sorted \n");
+        for (name, entry) in &self.refs {
+            out.push_str(&format!("{} {name}\n", entry.id));
+            if let Some(peeled) = entry.peeled {
+                out.push_str(&format!("^{peeled}\n"));
+            }
+        }
+        out.into_bytes()
+    }
+
+    /// Whether a ref without peeled id is known not to be an annotated tag. When it isn't,
+    /// the peeled id of a ref may be missing and has to be found by reading the object.
+    pub fn is_fully_peeled(&self) -> bool {
+        self.peeling == Peeling::Full
+    }
+
+    pub fn get(&self, name: &str) -> Option<&PackedRef> {
+        self.refs.get(name)
+    }
+
+    pub fn insert(&mut self, name: impl Into<String>, entry: PackedRef) -> Option<PackedRef> {
+        self.refs.insert(name.into(), entry)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<PackedRef> {
+        self.refs.remove(name)
+    }
+
+    /// The refs sorted by name.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &PackedRef)> {
+        self.refs.iter().map(|(name, entry)| (name.as_str(), entry))
+    }
+
+    pub fn len(&self) -> usize {
+        self.refs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.refs.is_empty()
+    }
+}
+
+/// The refs of a git directory, loose and packed. See the [module documentation](self).
+///
+/// A linked worktree splits its refs between two directories, which
+/// [`GitDir`](crate::internal::worktree::GitDir) dispatches between.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefStore {
+    dir: PathBuf,
+}
+
+impl RefStore {
+    /// The refs stored in the git directory `dir`.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn packed_refs(&self) -> Result<PackedRefs, GitError> {
+        PackedRefs::read(&self.dir)
+    }
+
+    /// Read `name` as stored, loose ref first, then `packed-refs`. `None` if it doesn't exist.
+    pub fn read_ref(&self, name: &str) -> Result<Option<RefValue>, GitError> {
+        if let Some(value) = self.read_loose(name)? {
+            return Ok(Some(value));
+        }
+        Ok(self
+            .packed_refs()?
+            .get(name)
+            .map(|entry| RefValue::Direct(entry.id)))
+    }
+
+    /// Follow symbolic refs from `name` to an object id, `None` for an unborn branch or a
+    /// missing ref.
+    pub fn resolve_ref(&self, name: &str) -> Result<Option<SHA1>, GitError> {
+        let mut current = name.to_string();
+        for _ in 0..=MAX_SYMREF_DEPTH {
+            match self.read_ref(&current)? {
+                Some(RefValue::Direct(id)) => return Ok(Some(id)),
+                Some(RefValue::Symbolic(target)) => current = target,
+                None => return Ok(None),
+            }
+        }
+        Err(GitError::InvalidReference(format!(
+            "{name}: symbolic ref chain too deep"
+        )))
+    }
+
+    /// What `name` peels to as recorded in `packed-refs`, `None` when it isn't packed, is
+    /// overridden by a loose ref or has no peeled line.
+    pub fn peeled(&self, name: &str) -> Result<Option<SHA1>, GitError> {
+        if self.read_loose(name)?.is_some() {
+            return Ok(None);
+        }
+        Ok(self.packed_refs()?.get(name).and_then(|entry| entry.peeled))
+    }
+
+    /// The names of the loose and packed refs under `refs/`, sorted.
+    pub fn ref_names(&self) -> Result<Vec<String>, GitError> {
+        let mut names: Vec<String> = self
+            .packed_refs()?
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .collect();
+        collect_loose_refs(&self.dir, "refs", &mut names)?;
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    /// The refs under `refs/` as stored, sorted by name.
+    pub fn list_refs(&self) -> Result<Vec<(String, RefValue)>, GitError> {
+        let packed = self.packed_refs()?;
+        let mut refs = Vec::new();
+        for name in self.ref_names()? {
+            let value = match self.read_loose(&name)? {
+                Some(value) => value,
+                None => match packed.get(&name) {
+                    Some(entry) => RefValue::Direct(entry.id),
+                    None => continue,
+                },
+            };
+            refs.push((name, value));
+        }
+        Ok(refs)
+    }
+
+    /// Set `name` to `value`, whatever it was.
+    pub fn write_ref(&self, name: &str, value: &RefValue) -> Result<(), GitError> {
+        self.lock_ref(name)?.commit(format_ref(value).as_bytes())
+    }
+
+    /// Set `name` from `old` to `new` if it still points to `old`, `None` for a ref that
+    /// doesn't exist: `old` of `None` creates the ref and `new` of `None` deletes it. Fails with
+    /// [`GitError::StaleReference`] when the ref has another value.
+    pub fn update_ref(
+        &self,
+        name: &str,
+        old: Option<SHA1>,
+        new: Option<SHA1>,
+    ) -> Result<(), GitError> {
+        self.update(name, old, new, None)
+    }
+
+    /// [`update_ref`](Self::update_ref), appending the update by `identity`, e.g.
+    /// `name <email>`, with `message` to the reflog of `name` while the ref is locked.
+    pub fn update_ref_logged(
+        &self,
+        name: &str,
+        old: Option<SHA1>,
+        new: Option<SHA1>,
+        identity: &str,
+        message: &str,
+    ) -> Result<(), GitError> {
+        let hex = |id: Option<SHA1>| id.map_or(ZERO_ID.to_string(), |id| id.to_string());
+        let entry = ReflogEntry::new(hex(old), hex(new), identity, message);
+        self.update(name, old, new, Some(entry))
+    }
+
+    /// The reflog of `name`, newest entry first, empty when it has none. A deleted ref keeps
+    /// its reflog.
+    pub fn read_reflog(&self, name: &str) -> Result<Vec<ReflogEntry>, GitError> {
+        let content = match fs::read_to_string(self.reflog_path(name)) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut entries = content
+            .lines()
+            .map(|line| {
+                ReflogEntry::from_line(line).ok_or_else(|| {
+                    GitError::InvalidReference(format!("{name} reflog line `{line}`"))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.reverse();
+        Ok(entries)
+    }
+
+    /// Append `entry` to the reflog of `name`, `logs/<name>`.
+    pub fn append_reflog(&self, name: &str, entry: &ReflogEntry) -> Result<(), GitError> {
+        validate_ref_name(name)?;
+        let path = self.reflog_path(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(format!("{}\n", entry.to_line()).as_bytes())?;
+        Ok(())
+    }
+
+    /// Delete `name`, loose and packed. Returns whether it existed.
+    pub fn delete_ref(&self, name: &str) -> Result<bool, GitError> {
+        let lock = self.lock_ref(name)?;
+        self.delete_locked(name, lock)
+    }
+
+    /// Move the loose refs holding an object id into `packed-refs`, like
+    /// `git pack-refs --all`, with the peeled id `peel` finds for each. Symbolic refs stay
+    /// loose. With `prune`, the loose files of the refs packed are removed. Returns the number
+    /// of refs packed.
+    pub fn pack_refs(
+        &self,
+        mut peel: impl FnMut(&SHA1) -> Result<Option<SHA1>, GitError>,
+        prune: bool,
+    ) -> Result<usize, GitError> {
+        let lock = LockFile::acquire(&self.dir.join("packed-refs"))?;
+        let mut packed = self.packed_refs()?;
+        let mut loose = Vec::new();
+        collect_loose_refs(&self.dir, "refs", &mut loose)?;
+        let mut pruned = Vec::new();
+        for name in loose {
+            if let Some(RefValue::Direct(id)) = self.read_loose(&name)? {
+                let peeled = peel(&id)?;
+                packed.insert(name.clone(), PackedRef { id, peeled });
+                pruned.push((name, id));
+            }
+        }
+        lock.commit(&packed.to_bytes())?;
+
+        if prune {
+            for (name, id) in &pruned {
+                let Ok(lock) = self.lock_ref(name) else {
+                    // Being updated, so it will be loose again
+                    continue;
+                };
+                if self.read_loose(name)? == Some(RefValue::Direct(*id)) {
+                    self.remove_loose(name, lock)?;
+                }
+            }
+        }
+        Ok(pruned.len())
+    }
+
+    fn reflog_path(&self, name: &str) -> PathBuf {
+        self.dir.join("logs").join(name)
+    }
+
+    fn update(
+        &self,
+        name: &str,
+        old: Option<SHA1>,
+        new: Option<SHA1>,
+        log: Option<ReflogEntry>,
+    ) -> Result<(), GitError> {
+        let lock = self.lock_ref(name)?;
+        let current = self.read_ref(name)?;
+        if current != old.map(RefValue::Direct) {
+            return Err(GitError::StaleReference(name.to_string()));
+        }
+        if let Some(entry) = log {
+            self.append_reflog(name, &entry)?;
+        }
+        match new {
+            Some(id) => lock.commit(format_ref(&RefValue::Direct(id)).as_bytes()),
+            None => self.delete_locked(name, lock).map(|_| ()),
+        }
+    }
+
+    fn read_loose(&self, name: &str) -> Result<Option<RefValue>, GitError> {
+        match fs::read_to_string(self.dir.join(name)) {
+            Ok(content) => parse_ref(name, &content).map(Some),
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::IsADirectory) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn lock_ref(&self, name: &str) -> Result<LockFile, GitError> {
+        validate_ref_name(name)?;
+        LockFile::acquire(&self.dir.join(name))
+    }
+
+    /// Delete `name`, whose lock is `ref_lock`.
+    fn delete_locked(&self, name: &str, ref_lock: LockFile) -> Result<bool, GitError> {
+        let lock = LockFile::acquire(&self.dir.join("packed-refs"))?;
+        let mut packed = self.packed_refs()?;
+        let was_packed = packed.remove(name).is_some();
+        if was_packed {
+            lock.commit(&packed.to_bytes())?;
+        } else {
+            drop(lock);
+        }
+        let was_loose = self.remove_loose(name, ref_lock)?;
+        Ok(was_packed || was_loose)
+    }
+
+    /// Remove the loose file of `name`, whose lock is `lock`, then the directories it leaves
+    /// empty below `refs/<kind>/`, like git.
+    fn remove_loose(&self, name: &str, lock: LockFile) -> Result<bool, GitError> {
+        let removed = match fs::remove_file(self.dir.join(name)) {
+            Ok(()) => true,
+            Err(e) if e.kind() == ErrorKind::NotFound => false,
+            Err(e) => return Err(e.into()),
+        };
+        drop(lock);
+        let mut parent = Path::new(name).parent();
+        while let Some(dir) = parent.filter(|dir| dir.components().count() > 2) {
+            if fs::remove_dir(self.dir.join(dir)).is_err() {
+                break;
+            }
+            parent = dir.parent();
+        }
+        Ok(removed)
+    }
+}
+
+/// An exclusive `<path>.lock` file, renamed over `path` on commit and removed if dropped
+/// before, git's protocol for updating refs, `packed-refs` and the files of `objects/` that
+/// are replaced whole (commit-graph, multi-pack-index, bitmaps and `.mtimes`).
+pub(crate) struct LockFile {
+    path: PathBuf,
+    lock: PathBuf,
+    file: Option<File>,
+    committed: bool,
+}
+
+impl LockFile {
+    /// Take the lock of `path`, failing with [`GitError::RefLocked`] if another writer holds
+    /// it.
+    pub(crate) fn acquire(path: &Path) -> Result<Self, GitError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut lock = path.as_os_str().to_owned();
+        lock.push(".lock");
+        let lock = PathBuf::from(lock);
+        match OpenOptions::new().write(true).create_new(true).open(&lock) {
+            Ok(file) => Ok(Self {
+                path: path.to_path_buf(),
+                lock,
+                file: Some(file),
+                committed: false,
+            }),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                Err(GitError::RefLocked(lock.display().to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replace the locked file with `content`, releasing the lock.
+    pub(crate) fn commit(mut self, content: &[u8]) -> Result<(), GitError> {
+        let mut file = self.file.take().expect("lock file written once");
+        file.write_all(content)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&self.lock, &self.path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.lock);
+        }
+    }
+}
+
+fn format_ref(value: &RefValue) -> String {
+    match value {
+        RefValue::Direct(id) => format!("{id}\n"),
+        RefValue::Symbolic(target) => format!("ref: {target}\n"),
+    }
+}
+
+pub(crate) fn parse_ref(ref_name: &str, content: &str) -> Result<RefValue, GitError> {
+    let content = content.trim_end();
+    if let Some(target) = content.strip_prefix("ref: ") {
+        return Ok(RefValue::Symbolic(target.to_string()));
+    }
+    SHA1::from_str(content)
+        .map(RefValue::Direct)
+        .map_err(|_| GitError::InvalidReference(ref_name.to_string()))
+}
+
+/// Append the names of the loose refs under `<dir>/<prefix>` to `names`.
+pub(crate) fn collect_loose_refs(
+    dir: &Path,
+    prefix: &str,
+    names: &mut Vec<String>,
+) -> Result<(), GitError> {
+    let entries = match fs::read_dir(dir.join(prefix)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let name = format!("{prefix}/{}", entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            collect_loose_refs(dir, &name, names)?;
+        } else if !name.ends_with(".lock") {
+            names.push(name);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAIN: &str = "1111111111111111111111111111111111111111";
+    const TAG: &str = "2222222222222222222222222222222222222222";
+    const FEATURE: &str = "3333333333333333333333333333333333333333";
+
+    fn id(hex: &str) -> SHA1 {
+        SHA1::from_str(hex).unwrap()
+    }
+
+    #[test]
+    fn test_packed_refs_format() {
+        let content = format!(
+            "# pack-refs with: peeled fully-peeled sorted \n{TAG} refs/tags/v1.0\n^{MAIN}\n{MAIN} refs/heads/main\n"
+        );
+        let packed = PackedRefs::parse(&content).unwrap();
+        assert!(packed.is_fully_peeled());
+        assert_eq!(packed.len(), 2);
+        assert_eq!(
+            packed.get("refs/tags/v1.0"),
+            Some(&PackedRef {
+                id: id(TAG),
+                peeled: Some(id(MAIN))
+            })
+        );
+        assert_eq!(packed.get("refs/heads/main").unwrap().peeled, None);
+
+        // Written back sorted
+        let expected = format!(
+            "# pack-refs with: peeled fully-peeled sorted \n{MAIN} refs/heads/main\n{TAG} refs/tags/v1.0\n^{MAIN}\n"
+        );
+        assert_eq!(String::from_utf8(packed.to_bytes()).unwrap(), expected);
+
+        // Without header nothing is known to be peeled
+        let bare = PackedRefs::parse(&format!("{MAIN} refs/heads/main\n")).unwrap();
+        assert!(!bare.is_fully_peeled());
+        assert!(
+            String::from_utf8(bare.to_bytes())
+                .unwrap()
+                .starts_with("# pack-refs with: sorted \n")
+        );
+
+        assert!(PackedRefs::parse(&format!("^{MAIN}\n")).is_err());
+        assert!(PackedRefs::parse("not a ref\n").is_err());
+    }
+
+    #[test]
+    fn test_ref_store() {
+        let dir = std::env::temp_dir().join(format!("ref-store-{}", std::process::id()));
+        let store = RefStore::new(&dir);
+        let mut packed = PackedRefs::new();
+        packed.insert(
+            "refs/heads/main",
+            PackedRef {
+                id: id(MAIN),
+                peeled: None,
+            },
+        );
+        packed.insert(
+            "refs/tags/v1.0",
+            PackedRef {
+                id: id(TAG),
+                peeled: Some(id(MAIN)),
+            },
+        );
+        packed.write(&dir).unwrap();
+        store
+            .write_ref("HEAD", &RefValue::Symbolic("refs/heads/main".to_string()))
+            .unwrap();
+        store
+            .write_ref("refs/heads/topic/feature", &RefValue::Direct(id(FEATURE)))
+            .unwrap();
+
+        assert_eq!(store.resolve_ref("HEAD").unwrap(), Some(id(MAIN)));
+        assert_eq!(store.peeled("refs/tags/v1.0").unwrap(), Some(id(MAIN)));
+        let names: Vec<String> = store
+            .list_refs()
+            .unwrap()
+            .into_iter()
+            .map(|r| r.0)
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "refs/heads/main",
+                "refs/heads/topic/feature",
+                "refs/tags/v1.0"
+            ]
+        );
+
+        // A loose ref overrides the packed one
+        store
+            .update_ref("refs/heads/main", Some(id(MAIN)), Some(id(FEATURE)))
+            .unwrap();
+        assert_eq!(
+            store.read_ref("refs/heads/main").unwrap(),
+            Some(RefValue::Direct(id(FEATURE)))
+        );
+        let err = store
+            .update_ref("refs/heads/main", Some(id(MAIN)), None)
+            .unwrap_err();
+        assert!(matches!(err, GitError::StaleReference(_)), "{err}");
+        assert!(
+            store
+                .update_ref("refs/heads/new", Some(id(MAIN)), None)
+                .is_err()
+        );
+        assert!(
+            store
+                .write_ref("refs/heads/../x", &RefValue::Direct(id(MAIN)))
+                .is_err()
+        );
+
+        // Locked by another writer
+        fs::write(dir.join("refs/heads/main.lock"), "").unwrap();
+        let err = store.delete_ref("refs/heads/main").unwrap_err();
+        assert!(matches!(err, GitError::RefLocked(_)), "{err}");
+        fs::remove_file(dir.join("refs/heads/main.lock")).unwrap();
+
+        // Packing keeps HEAD loose and prunes the others
+        let packed_count = store.pack_refs(|_| Ok(None), true).unwrap();
+        assert_eq!(packed_count, 2);
+        assert!(dir.join("HEAD").exists());
+        assert!(!dir.join("refs/heads/topic").exists());
+        let packed = store.packed_refs().unwrap();
+        assert_eq!(packed.get("refs/heads/main").unwrap().id, id(FEATURE));
+        assert_eq!(packed.get("refs/tags/v1.0").unwrap().peeled, Some(id(MAIN)));
+
+        // Deleting a packed ref rewrites packed-refs
+        assert!(store.delete_ref("refs/heads/topic/feature").unwrap());
+        assert!(!store.delete_ref("refs/heads/topic/feature").unwrap());
+        assert_eq!(store.packed_refs().unwrap().len(), 2);
+        assert!(!dir.join("packed-refs.lock").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_ref_name() {
+        for name in [
+            "HEAD",
+            "refs/heads/main",
+            "refs/heads/feature/x-1.2",
+            "refs/tags/v1.0@beta",
+            "refs/heads/ünïcode",
+        ] {
+            assert!(validate_ref_name(name).is_ok(), "{name}");
+        }
+        for name in [
+            "",
+            "@",
+            "refs/heads/a..b",
+            "refs/heads/a@{1}",
+            "refs/heads/",
+            "/refs/heads/main",
+            "refs//heads",
+            "refs/heads/.hidden",
+            "refs/heads/main.lock",
+            "refs/heads/main.",
+            "refs/heads/with space",
+            "refs/heads/tab\t",
+            "refs/heads/del\u{7f}",
+            "refs/heads/a~1",
+            "refs/heads/a^",
+            "refs/heads/a:b",
+            "refs/heads/a?",
+            "refs/heads/a*",
+            "refs/heads/a[b",
+            "refs\\heads",
+        ] {
+            let err = validate_ref_name(name).unwrap_err();
+            assert!(matches!(err, GitError::InvalidRefName(..)), "{name}: {err}");
+        }
+    }
+
+    #[test]
+    fn test_reflog() {
+        let dir = std::env::temp_dir().join(format!("reflog-{}", std::process::id()));
+        let store = RefStore::new(&dir);
+        let name = "refs/heads/main";
+        let identity = "Pusher <pusher@example.com>";
+        store
+            .update_ref_logged(name, None, Some(id(MAIN)), identity, "push")
+            .unwrap();
+        store
+            .update_ref_logged(
+                name,
+                Some(id(MAIN)),
+                Some(id(FEATURE)),
+                identity,
+                "push\nforced",
+            )
+            .unwrap();
+        assert!(
+            store
+                .update_ref_logged(name, Some(id(MAIN)), None, identity, "stale")
+                .is_err()
+        );
+        store
+            .update_ref_logged(name, Some(id(FEATURE)), None, identity, "delete")
+            .unwrap();
+
+        let reflog = store.read_reflog(name).unwrap();
+        let values: Vec<(&str, &str, &str)> = reflog
+            .iter()
+            .map(|e| (e.old_hash.as_str(), e.new_hash.as_str(), e.message.as_str()))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                (FEATURE, ZERO_ID, "delete"),
+                (MAIN, FEATURE, "push forced"),
+                (ZERO_ID, MAIN, "push")
+            ]
+        );
+        assert!(reflog.iter().all(|e| e.identity == identity));
+
+        // git's format
+        let content = fs::read_to_string(dir.join("logs/refs/heads/main")).unwrap();
+        let first = content.lines().next().unwrap();
+        assert!(
+            first.starts_with(&format!("{ZERO_ID} {MAIN} {identity} ")),
+            "{first}"
+        );
+        assert!(first.ends_with(" +0000\tpush"), "{first}");
+        let line = format!("{MAIN} {FEATURE} A U Thor <a@example.com> 1700000000 +0200\tcommit: x");
+        let entry = ReflogEntry::from_line(&line).unwrap();
+        assert_eq!(
+            (
+                entry.identity.as_str(),
+                entry.timestamp,
+                entry.message.as_str()
+            ),
+            ("A U Thor <a@example.com>", 1700000000, "commit: x")
+        );
+
+        assert!(store.read_reflog("refs/heads/none").unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+//! Repository layout on disk, including linked worktrees.
+//!
+//! A linked worktree (`git worktree add`) has a `.git` *file* containing `gitdir: <path>` that
+//! points to `<common dir>/worktrees/<name>`. That directory holds the worktree's own `HEAD` and
+//! per-worktree refs (`refs/worktree/*`, `refs/bisect/*`, `refs/rewritten/*`), and a `commondir`
+//! file pointing back to the repository shared by all worktrees, where every other ref and the
+//! objects live.
+//!
+//! [`GitDir`] resolves this layout so a filesystem backend serving a repository that developers
+//! also use locally reads `HEAD` and refs from the right place; `GitDirRepository` in
+//! `protocol::git_dir` serves its refs. Paths are resolved through symlinks, so a repository
+//! published as a symlink to a developer's checkout is the same `GitDir` as the checkout.
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::errors::GitError;
+use crate::hash::SHA1;
+pub use crate::internal::refs::RefValue;
+use crate::internal::refs::{MAX_SYMREF_DEPTH, RefStore};
+use crate::protocol::smart::DEFAULT_HEAD_FALLBACKS;
+
+/// Ref namespaces that belong to a single worktree, as defined by `git worktree`.
+const PER_WORKTREE_PREFIXES: [&str; 3] = ["refs/worktree/", "refs/bisect/", "refs/rewritten/"];
+
+/// The git directory of a repository or worktree, and the common directory it shares refs and
+/// objects with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitDir {
+    pub git_dir: PathBuf,
+    pub common_dir: PathBuf,
+}
+
+impl GitDir {
+    /// Resolve the git directory of `path`, which may be a bare repository, a working tree with
+    /// a `.git` directory, or a linked worktree with a `.git` file. Both directories are
+    /// canonical, with symlinks resolved.
+    pub fn discover(path: impl AsRef<Path>) -> Result<Self, GitError> {
+        let path = path.as_ref();
+        let dot_git = path.join(".git");
+        let git_dir = if dot_git.is_file() {
+            let content = fs::read_to_string(&dot_git)?;
+            let target = content
+                .trim_end()
+                .strip_prefix("gitdir: ")
+                .ok_or_else(|| GitError::InvalidReference(dot_git.display().to_string()))?;
+            path.join(target)
+        } else if dot_git.is_dir() {
+            dot_git
+        } else if path.join("HEAD").is_file() {
+            path.to_path_buf()
+        } else {
+            return Err(GitError::RepoNotFound);
+        };
+
+        let git_dir = fs::canonicalize(git_dir)?;
+        let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+            Ok(common) => fs::canonicalize(git_dir.join(common.trim_end()))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => git_dir.clone(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            git_dir,
+            common_dir,
+        })
+    }
+
+    /// Whether this is a linked worktree rather than the main repository.
+    pub fn is_linked_worktree(&self) -> bool {
+        self.git_dir != self.common_dir
+    }
+
+    /// Whether `ref_name` is private to each worktree: `HEAD` and the other pseudo refs at the
+    /// top of the git directory, and the per-worktree namespaces.
+    pub fn is_per_worktree_ref(ref_name: &str) -> bool {
+        !ref_name.starts_with("refs/")
+            || PER_WORKTREE_PREFIXES
+                .iter()
+                .any(|prefix| ref_name.starts_with(prefix))
+    }
+
+    /// The store of `ref_name`, the refs of this worktree or the shared ones.
+    pub fn ref_store(&self, ref_name: &str) -> RefStore {
+        if Self::is_per_worktree_ref(ref_name) {
+            RefStore::new(&self.git_dir)
+        } else {
+            RefStore::new(&self.common_dir)
+        }
+    }
+
+    /// Read `ref_name` as stored, loose ref first, then `packed-refs`. `None` if it doesn't exist.
+    pub fn read_ref(&self, ref_name: &str) -> Result<Option<RefValue>, GitError> {
+        self.ref_store(ref_name).read_ref(ref_name)
+    }
+
+    /// Follow symbolic refs from `ref_name` to an object id, `None` for an unborn branch or a
+    /// missing ref.
+    pub fn resolve_ref(&self, ref_name: &str) -> Result<Option<SHA1>, GitError> {
+        let mut name = ref_name.to_string();
+        for _ in 0..=MAX_SYMREF_DEPTH {
+            match self.read_ref(&name)? {
+                Some(RefValue::Direct(id)) => return Ok(Some(id)),
+                Some(RefValue::Symbolic(target)) => name = target,
+                None => return Ok(None),
+            }
+        }
+        Err(GitError::InvalidReference(format!(
+            "{ref_name}: symbolic ref chain too deep"
+        )))
+    }
+
+    /// The branch `HEAD` points to, `None` when `HEAD` is detached.
+    pub fn head_target(&self) -> Result<Option<String>, GitError> {
+        Ok(match self.read_ref("HEAD")? {
+            Some(RefValue::Symbolic(target)) => Some(target),
+            _ => None,
+        })
+    }
+
+    /// The branches to advertise as `HEAD` when this worktree's `HEAD` doesn't resolve, for
+    /// `SmartProtocol::set_head_fallbacks`: for a linked worktree on an unborn branch or a
+    /// detached `HEAD` that isn't served, the branch of the main worktree comes first, then
+    /// the default fallbacks `refs/heads/main` and `refs/heads/master`.
+    pub fn head_fallbacks(&self) -> Result<Vec<String>, GitError> {
+        let mut fallbacks = Vec::with_capacity(DEFAULT_HEAD_FALLBACKS.len() + 1);
+        let main = RefStore::new(&self.common_dir);
+        if self.is_linked_worktree()
+            && let Some(RefValue::Symbolic(target)) = main.read_ref("HEAD")?
+        {
+            fallbacks.push(target);
+        }
+        for branch in DEFAULT_HEAD_FALLBACKS {
+            if !fallbacks.iter().any(|fallback| fallback == branch) {
+                fallbacks.push(branch.to_string());
+            }
+        }
+        Ok(fallbacks)
+    }
+
+    /// Every ref visible from this worktree with its object id, sorted by name and preceded by
+    /// `HEAD` when it resolves: the shared refs of the common directory plus this worktree's
+    /// own per-worktree refs. Dangling symbolic refs are skipped.
+    pub fn list_refs(&self) -> Result<Vec<(String, SHA1)>, GitError> {
+        let mut names = RefStore::new(&self.common_dir).ref_names()?;
+        if self.is_linked_worktree() {
+            names.retain(|name| !Self::is_per_worktree_ref(name));
+            names.extend(RefStore::new(&self.git_dir).ref_names()?);
+            names.sort();
+            names.dedup();
+        }
+
+        let mut refs = Vec::with_capacity(names.len() + 1);
+        if let Some(head) = self.resolve_ref("HEAD")? {
+            refs.push(("HEAD".to_string(), head));
+        }
+        for name in names {
+            if let Some(id) = self.resolve_ref(&name)? {
+                refs.push((name, id));
+            }
+        }
+        Ok(refs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const MAIN: &str = "1111111111111111111111111111111111111111";
+    const FEATURE: &str = "2222222222222222222222222222222222222222";
+    const BISECT: &str = "3333333333333333333333333333333333333333";
+
+    fn write(path: PathBuf, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    /// A bare repository at `<root>/repo.git` with branches `main` (packed) and `feature`, and a
+    /// linked worktree at `<root>/wt` checked out on `feature` in the middle of a bisect.
+    fn setup(root: &Path) -> (PathBuf, PathBuf) {
+        let repo = root.join("repo.git");
+        write(repo.join("HEAD"), "ref: refs/heads/main\n");
+        write(
+            repo.join("packed-refs"),
+            &format!("# pack-refs with: peeled fully-peeled sorted\n{MAIN} refs/heads/main\n"),
+        );
+        write(repo.join("refs/heads/feature"), &format!("{FEATURE}\n"));
+
+        let worktree = root.join("wt");
+        let admin = repo.join("worktrees/wt");
+        write(worktree.join(".git"), "gitdir: ../repo.git/worktrees/wt\n");
+        write(admin.join("commondir"), "../..\n");
+        write(admin.join("HEAD"), "ref: refs/heads/feature\n");
+        write(admin.join("refs/bisect/bad"), &format!("{BISECT}\n"));
+        (repo, worktree)
+    }
+
+    #[test]
+    fn test_linked_worktree_refs() {
+        let root = tempfile::tempdir().unwrap();
+        let (repo, worktree) = setup(root.path());
+
+        let main = GitDir::discover(&repo).unwrap();
+        assert!(!main.is_linked_worktree());
+        assert_eq!(
+            main.head_target().unwrap().as_deref(),
+            Some("refs/heads/main")
+        );
+        let names: Vec<String> = main.list_refs().unwrap().into_iter().map(|r| r.0).collect();
+        assert_eq!(names, ["HEAD", "refs/heads/feature", "refs/heads/main"]);
+
+        let linked = GitDir::discover(&worktree).unwrap();
+        assert!(linked.is_linked_worktree());
+        assert_eq!(linked.common_dir, fs::canonicalize(&repo).unwrap());
+        let refs: Vec<(String, String)> = linked
+            .list_refs()
+            .unwrap()
+            .into_iter()
+            .map(|(name, id)| (name, id.to_string()))
+            .collect();
+        let expected = [
+            ("HEAD", FEATURE),
+            ("refs/bisect/bad", BISECT),
+            ("refs/heads/feature", FEATURE),
+            ("refs/heads/main", MAIN),
+        ];
+        assert_eq!(
+            refs,
+            expected.map(|(name, id)| (name.to_string(), id.to_string()))
+        );
+    }
+
+    #[test]
+    fn test_head_fallbacks() {
+        let root = tempfile::tempdir().unwrap();
+        let (repo, worktree) = setup(root.path());
+        let main = GitDir::discover(&repo).unwrap();
+        assert_eq!(
+            main.head_fallbacks().unwrap(),
+            ["refs/heads/main", "refs/heads/master"]
+        );
+
+        // A linked worktree on an unborn branch falls back to the branch of the main worktree
+        write(repo.join("HEAD"), "ref: refs/heads/trunk\n");
+        write(repo.join("worktrees/wt/HEAD"), "ref: refs/heads/unborn\n");
+        let linked = GitDir::discover(&worktree).unwrap();
+        assert_eq!(linked.resolve_ref("HEAD").unwrap(), None);
+        assert_eq!(
+            linked.head_fallbacks().unwrap(),
+            ["refs/heads/trunk", "refs/heads/main", "refs/heads/master"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_discover_through_symlink() {
+        let root = tempfile::tempdir().unwrap();
+        let (repo, worktree) = setup(root.path());
+        let served = root.path().join("served");
+        fs::create_dir(&served).unwrap();
+        std::os::unix::fs::symlink(&repo, served.join("repo.git")).unwrap();
+        std::os::unix::fs::symlink(&worktree, served.join("wt")).unwrap();
+
+        assert_eq!(
+            GitDir::discover(served.join("repo.git")).unwrap(),
+            GitDir::discover(&repo).unwrap()
+        );
+        let linked = GitDir::discover(served.join("wt")).unwrap();
+        assert_eq!(linked, GitDir::discover(&worktree).unwrap());
+        assert!(linked.is_linked_worktree());
+        assert_eq!(linked.common_dir, fs::canonicalize(&repo).unwrap());
+        assert_eq!(
+            linked.resolve_ref("refs/heads/main").unwrap(),
+            Some(SHA1::from_str(MAIN).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_per_worktree_refs() {
+        assert!(GitDir::is_per_worktree_ref("HEAD"));
+        assert!(GitDir::is_per_worktree_ref("refs/worktree/scratch"));
+        assert!(!GitDir::is_per_worktree_ref("refs/heads/main"));
+        assert!(!GitDir::is_per_worktree_ref("refs/tags/v1.0"));
+    }
+}
@@ -10,16 +10,48 @@
 //! - Decoding: base objects and delta objects (Ref-Delta, Offset-Delta, ZstdDelta).
 //! - Encoding: serial and parallel pipelines; offset deltas and Zstd-based deltas.
 //! - Streaming: `decode_stream` for `Stream<Bytes>`; `decode_async` decodes in a new thread and sends entries.
-//! - Caching & memory: LRU-based cache; `MemSizeRecorder` tracks heap usage; optional `mem_limit` to bound memory.
+//! - Caching & memory: LRU-based cache; `MemSizeRecorder` tracks heap usage; optional `mem_limit` to bound memory; large blobs can be streamed to a sink instead of memory.
 //! - Utilities: SHA1, zlib, delta, zstdelta toolkits.
+//! - Hardening: the optional `sha1dc` feature hashes objects with collision-detecting SHA-1.
+//! - Deflate: the `zlib` (default), `zlib-ng` and `miniz` features pick the zlib implementation.
 //!
 //! Modules
-//! - `internal::pack`: decode/encode, caches, waitlists, parallel pipelines, helpers.
+//! - `archive`: `archive`, streaming the tar, gzipped tar or zip of a tree through `RepositoryAccess`, with git's modes, the commit time and id, an optional prefix and paths, for upload-archive and "Download ZIP" endpoints.
+//! - `bundle`: `create_bundle` and `unbundle` of v2/v3 git bundles, a header of refs and prerequisite commits followed by a pack, full or incremental on the tips of a previous bundle, for backups and air-gapped transfers.
+//! - `client`: `ls_remote` listing the refs and capabilities at a URL, `fetch` from a remote repository into a `RepositoryAccess` over smart HTTP, SSH (with the `russh` feature) or `git://`, in protocol v0 or v2: ref discovery, want/have negotiation, indexing the received pack and updating remote-tracking refs; `push` of refs mapped by refspecs with a generated pack, reading report-status(-v2).
+//! - `internal::pack`: decode/encode, caches, waitlists, parallel pipelines, helpers, `.idx` reader and v2/v3 writers, multi-pack-index, reachability bitmaps, pack verification against its idx, an LRU delta-base cache for reads from packs, memory-mapped pack reading through lazily mapped windows, `PackReader` random access to single objects by id or offset, cruft packs with `.mtimes` for safe garbage collection.
 //! - `internal::object`: Blob/Tree/Commit/Tag/Note objects, type enum, object trait.
+//! - `internal::object::signing`: signature extraction for commits/tags and the `SignatureVerifier` hook.
+//! - `internal::commit_graph`: reader and writer of the `objects/info/commit-graph` file and its changed-path Bloom filters, used by history walks.
+//! - `internal::loose`: reading and writing loose objects of a `.git/objects` directory and its `info/alternates`.
+//! - `internal::worktree`: git directory discovery through symlinks and ref reading, including linked worktrees and their `HEAD` fallbacks.
+//! - `internal::refs`: reading and writing `packed-refs`, with peeled tags, and a ref store merging loose and packed refs under git's lock files, with reflogs.
 //! - `internal::zlib`: compression/decompression stream utilities.
 //! - `delta` and `zstdelta`: delta algorithms and rebuild helpers.
+//! - `diff`: tree diff with rename/copy detection, unified blob diffs, `format-patch` output and `git apply`.
 //! - `errors`: unified error types.
+//! - `fast_export`: `fast_export`, the `git fast-import` stream of the refs, commits, trees and blobs of a `RepositoryAccess`, to migrate a repository out of custom storage into any git tooling.
+//! - `fast_import`: `fast_import`, storing the blobs, commits and tags of a `git fast-import` stream through `RepositoryAccess` and updating its refs, with marks files for imports in several runs.
+//! - `fsck`: `git fsck`-style checks of tree entries, commit and tag headers, identities and links, with configurable severities.
 //! - `hash`: SHA1 helpers.
+//! - `protocol::alternates`: `AlternatesRepository`, a `RepositoryAccess` reading missing objects from alternate stores, e.g. a fork network's shared object pool.
+//! - `protocol::bundle_uri`: `BundleList`, the bundles `SmartProtocol` lists in answer to the protocol v2 `bundle-uri` command, for clients to clone from CDN-hosted bundles.
+//! - `protocol::codec`: `PktLineCodec`, a tokio-util `Decoder`/`Encoder` of pkt-lines, flush, delim and response-end packets, for servers reading an `AsyncRead` directly, and the `PktLineReader` stream of the pkt-lines of a chunked request.
+//! - `protocol::daemon`: `GitDaemon`, serving `git://` connections with per-service enable flags, and the `DaemonRequest` parser of their request line, with its virtual host and extra parameters.
+//! - `protocol::git_dir`: `GitDirRepository`, a `RepositoryAccess` serving the refs of a `GitDir`, with the per-worktree `HEAD` and refs of a linked worktree, over the objects of another repository.
+//! - `protocol::hidden_refs`: `HiddenRefs`, `transfer.hideRefs`-style patterns of refs left out of the advertisement and refused to fetches and pushes, and the `RefFilter` callback hiding refs per authenticated user.
+//! - `protocol::http::axum` (feature `axum`): `router` and the `info_refs`, `upload_pack` and `receive_pack` handlers serving the smart HTTP endpoints of the repositories opened by a `RepositoryResolver`.
+//! - `protocol::http::dumb`: `DumbHttp`, serving the `info/refs`, `HEAD`, `objects/info/packs`, loose object and pack files of the dumb HTTP protocol from a `RepositoryAccess`, for read-only mirrors and old clients.
+//! - `protocol::http::service` (feature `tower`): `GitHttpService`, a tower `Service` serving the smart HTTP endpoints of a repository to hyper and other tower-based servers.
+//! - `protocol::ssh::russh` (feature `russh`): `serve_channel`, running the git command executed on a russh session channel, from its `exec` request to its exit status.
+//! - `protocol::trace`: the `PacketTracer` hook receiving every pkt-line read and written by `SmartProtocol`, like `GIT_TRACE_PACKET`, and `LogPacketTracer` logging them.
+//! - `protocol::upload_archive`: `ArchiveRequest`, the arguments of `git archive --remote` read by the upload-archive service, which `serve_git_command` answers with the archive `RepositoryAccess::write_archive` writes for a tree-ish the client may see, by default with `archive`.
+//! - `protocol::memory`: `MemoryRepository`, an in-memory `RepositoryAccess` for tests and ephemeral repositories.
+//! - `protocol::object_store`: `ObjectStoreRepository`, a `RepositoryAccess` over an object store with CAS-updated refs; the `s3` feature adds `S3Storage` for S3-compatible stores.
+//! - `prelude`: re-exports of the stable API; prefer it over paths into `internal`.
+//! - `refspec`: `Refspec` parsing, matching and mapping of `[+]<src>:<dst>` refspecs, including negative refspecs.
+//! - `maintenance`: `git maintenance`-like task traits, a per-repository scheduler, and `repack` (with bitmaps and multi-pack-index), commit-graph writes, pack-refs and prune over `RepositoryAccess`.
+//! - `revwalk`: history walks (`rev_list`, the async `CommitWalker` with `--not` and `--topo-order`), merge bases, ahead/behind counts and generation numbers.
 //! - `utils`: common utilities (e.g., `CountingReader`).
 //!
 //! Typical Usage
@@ -30,10 +62,21 @@
 //! Test Data
 //! - Located under `tests/data/`, includes real pack files and object sets.
 
+pub mod archive;
+pub mod bundle;
+pub mod client;
+pub mod diff;
 pub mod errors;
+pub mod fast_export;
+pub mod fast_import;
+pub mod fsck;
 pub mod hash;
 pub mod internal;
+pub mod maintenance;
+pub mod prelude;
 pub mod protocol;
+pub mod refspec;
+pub mod revwalk;
 pub mod utils;
 
 mod delta;
@@ -41,5 +84,6 @@ mod zstdelta;
 
 // Core traits and types that external users need to implement/use
 pub use protocol::{
-    AuthenticationService, GitProtocol, ProtocolError, RepositoryAccess, ServiceType,
+    AuthenticationService, AuthorizationService, GitProtocol, ProtocolError, RepositoryAccess,
+    ServiceType,
 };
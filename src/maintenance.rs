@@ -0,0 +1,1432 @@
+//! In-process repository maintenance, modelled after `git maintenance`.
+//!
+//! A [`MaintenanceTask`] performs one housekeeping job (gc, repack, commit-graph write,
+//! pack-refs, prune) against a repository handle chosen by the embedder. The
+//! [`MaintenanceScheduler`] owns a set of tasks, runs them per repository, makes sure two
+//! runs never overlap on the same repository, and forwards progress to a
+//! [`MaintenanceProgress`] observer.
+//!
+//! The crate does not own an on-disk repository layout, so tasks are generic over the
+//! repository handle `R`; hosts implement the tasks on top of their storage and let the
+//! scheduler take care of ordering, locking, intervals and reporting. Repositories served by a
+//! [`RepositoryAccess`] get [`repack`], [`write_commit_graph`], [`pack_refs`] and [`prune`] for
+//! free, with their [`RepackTask`], [`CommitGraphTask`], [`PackRefsTask`] and [`PruneTask`],
+//! and [`GcTask`] running them all like `git gc`.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::StreamExt;
+
+use crate::errors::GitError;
+use crate::hash::SHA1;
+use crate::internal::commit_graph::CommitGraph;
+use crate::internal::object::ObjectTrait;
+use crate::internal::object::commit::Commit;
+use crate::internal::object::tag::Tag;
+use crate::internal::object::tree::{Tree, TreeItemMode};
+use crate::internal::object::types::ObjectType;
+use crate::internal::pack::bitmap::PackBitmap;
+use crate::internal::pack::cruft::{PackMtimes, build_cruft_pack, recent_objects};
+use crate::internal::pack::entry::Entry;
+use crate::internal::pack::idx::PackIndex;
+use crate::internal::pack::midx::MultiPackIndex;
+use crate::protocol::RepositoryAccess;
+use crate::protocol::pack::{DeltaOptions, IndexedPack, PackGenerator, PackObjects, load_object};
+use crate::protocol::types::{PackInfo, ProtocolError, ProtocolStream, ZERO_ID};
+use crate::revwalk::CommitWalker;
+
+/// Tags of tags followed when peeling a reference.
+const MAX_PEEL_DEPTH: usize = 32;
+
+/// How old unreachable loose objects must be for [`PruneTask`] to remove them, git's default
+/// `gc.pruneExpire` of two weeks.
+const DEFAULT_PRUNE_EXPIRE: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// The maintenance jobs known to the scheduler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TaskKind {
+    Gc,
+    Repack,
+    CommitGraph,
+    PackRefs,
+    Prune,
+}
+
+impl TaskKind {
+    /// Default interval between two runs, following the `git maintenance` schedule.
+    pub fn default_interval(&self) -> Duration {
+        const HOUR: u64 = 60 * 60;
+        match self {
+            TaskKind::CommitGraph => Duration::from_secs(HOUR),
+            TaskKind::Repack | TaskKind::PackRefs => Duration::from_secs(24 * HOUR),
+            TaskKind::Gc | TaskKind::Prune => Duration::from_secs(7 * 24 * HOUR),
+        }
+    }
+}
+
+impl fmt::Display for TaskKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            TaskKind::Gc => "gc",
+            TaskKind::Repack => "repack",
+            TaskKind::CommitGraph => "commit-graph",
+            TaskKind::PackRefs => "pack-refs",
+            TaskKind::Prune => "prune",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Receives progress notifications while maintenance runs. All methods default to no-ops.
+pub trait MaintenanceProgress: Send + Sync {
+    /// A task is about to start on `repo`.
+    fn task_started(&self, _repo: &str, _task: TaskKind) {}
+
+    /// A task reports how much work it has done; `total` is `None` when unknown.
+    fn task_progress(&self, _repo: &str, _task: TaskKind, _done: usize, _total: Option<usize>) {}
+
+    /// A task has finished, successfully or not, `should_run` included.
+    fn task_finished(&self, _repo: &str, _task: TaskKind, _result: &Result<(), GitError>) {}
+}
+
+/// A progress observer that discards every notification.
+pub struct NoProgress;
+
+impl MaintenanceProgress for NoProgress {}
+
+/// Progress handle passed to a running task, bound to its repository and kind.
+pub struct TaskProgress<'a> {
+    repo: &'a str,
+    task: TaskKind,
+    observer: &'a dyn MaintenanceProgress,
+}
+
+impl TaskProgress<'_> {
+    /// Report `done` units of work out of an optional `total`.
+    pub fn update(&self, done: usize, total: Option<usize>) {
+        self.observer
+            .task_progress(self.repo, self.task, done, total);
+    }
+}
+
+/// One maintenance job over repositories of type `R`.
+#[async_trait]
+pub trait MaintenanceTask<R: Sync>: Send + Sync {
+    /// Which job this task implements.
+    fn kind(&self) -> TaskKind;
+
+    /// Interval between two scheduled runs on the same repository.
+    fn interval(&self) -> Duration {
+        self.kind().default_interval()
+    }
+
+    /// Decide whether there is anything to do, e.g. too many loose objects or packs.
+    async fn should_run(&self, _repo: &R) -> Result<bool, GitError> {
+        Ok(true)
+    }
+
+    /// Perform the job.
+    async fn run(&self, repo: &R, progress: &TaskProgress<'_>) -> Result<(), GitError>;
+}
+
+/// Outcome of a scheduler run over one repository, in task order.
+#[derive(Debug)]
+pub struct MaintenanceReport {
+    pub repo: String,
+    /// Tasks that ran, with their result.
+    pub results: Vec<(TaskKind, Result<(), GitError>)>,
+    /// Tasks that were not due or reported nothing to do.
+    pub skipped: Vec<TaskKind>,
+}
+
+impl MaintenanceReport {
+    /// Whether every task that ran succeeded.
+    pub fn is_success(&self) -> bool {
+        self.results.iter().all(|(_, result)| result.is_ok())
+    }
+}
+
+/// Releases the per-repository lock when dropped, also on panic or early return.
+struct RepoLock<'a> {
+    repo: String,
+    locked: &'a Mutex<HashSet<String>>,
+}
+
+impl Drop for RepoLock<'_> {
+    fn drop(&mut self) {
+        self.locked.lock().unwrap().remove(&self.repo);
+    }
+}
+
+/// Runs a set of [`MaintenanceTask`]s per repository with locking and progress reporting.
+pub struct MaintenanceScheduler<R: Sync> {
+    tasks: Vec<Arc<dyn MaintenanceTask<R>>>,
+    progress: Arc<dyn MaintenanceProgress>,
+    locked: Mutex<HashSet<String>>,
+    last_run: Mutex<HashMap<(String, TaskKind), SystemTime>>,
+}
+
+impl<R: Sync> Default for MaintenanceScheduler<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R: Sync> MaintenanceScheduler<R> {
+    pub fn new() -> Self {
+        Self {
+            tasks: Vec::new(),
+            progress: Arc::new(NoProgress),
+            locked: Mutex::new(HashSet::new()),
+            last_run: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Register a task. Tasks run in registration order.
+    pub fn with_task(mut self, task: Arc<dyn MaintenanceTask<R>>) -> Self {
+        self.tasks.push(task);
+        self
+    }
+
+    /// Set the observer receiving progress notifications.
+    pub fn with_progress(mut self, progress: Arc<dyn MaintenanceProgress>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Record that `task` last ran on `repo` at `time`, e.g. when restoring persisted state.
+    pub fn set_last_run(&self, repo: &str, task: TaskKind, time: SystemTime) {
+        self.last_run
+            .lock()
+            .unwrap()
+            .insert((repo.to_string(), task), time);
+    }
+
+    /// When `task` last completed successfully on `repo`.
+    pub fn last_run(&self, repo: &str, task: TaskKind) -> Option<SystemTime> {
+        self.last_run
+            .lock()
+            .unwrap()
+            .get(&(repo.to_string(), task))
+            .copied()
+    }
+
+    /// Run every registered task on `repo`, ignoring intervals.
+    pub async fn run_all(&self, repo_id: &str, repo: &R) -> Result<MaintenanceReport, GitError> {
+        self.run_tasks(repo_id, repo, None).await
+    }
+
+    /// Run the tasks whose interval has elapsed since their last successful run at `now`.
+    pub async fn run_due(
+        &self,
+        repo_id: &str,
+        repo: &R,
+        now: SystemTime,
+    ) -> Result<MaintenanceReport, GitError> {
+        self.run_tasks(repo_id, repo, Some(now)).await
+    }
+
+    fn lock(&self, repo_id: &str) -> Result<RepoLock<'_>, GitError> {
+        let mut locked = self.locked.lock().unwrap();
+        if !locked.insert(repo_id.to_string()) {
+            return Err(GitError::RepositoryLocked(repo_id.to_string()));
+        }
+        Ok(RepoLock {
+            repo: repo_id.to_string(),
+            locked: &self.locked,
+        })
+    }
+
+    fn is_due(&self, repo_id: &str, task: &dyn MaintenanceTask<R>, now: SystemTime) -> bool {
+        match self.last_run(repo_id, task.kind()) {
+            Some(last) => now
+                .duration_since(last)
+                .map(|elapsed| elapsed >= task.interval())
+                .unwrap_or(false),
+            None => true,
+        }
+    }
+
+    async fn run_tasks(
+        &self,
+        repo_id: &str,
+        repo: &R,
+        now: Option<SystemTime>,
+    ) -> Result<MaintenanceReport, GitError> {
+        let _lock = self.lock(repo_id)?;
+        let mut report = MaintenanceReport {
+            repo: repo_id.to_string(),
+            results: Vec::new(),
+            skipped: Vec::new(),
+        };
+
+        for task in &self.tasks {
+            let kind = task.kind();
+            if let Some(now) = now
+                && !self.is_due(repo_id, task.as_ref(), now)
+            {
+                report.skipped.push(kind);
+                continue;
+            }
+            match task.should_run(repo).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    report.skipped.push(kind);
+                    continue;
+                }
+                Err(e) => {
+                    // Every task reported finished was reported started
+                    self.progress.task_started(repo_id, kind);
+                    self.progress
+                        .task_finished(repo_id, kind, &Err(clone_error(&e)));
+                    report.results.push((kind, Err(e)));
+                    continue;
+                }
+            }
+
+            tracing::debug!("maintenance: running {} on {}", kind, repo_id);
+            self.progress.task_started(repo_id, kind);
+            let progress = TaskProgress {
+                repo: repo_id,
+                task: kind,
+                observer: self.progress.as_ref(),
+            };
+            let result = task.run(repo, &progress).await;
+            match &result {
+                Ok(()) => self.set_last_run(repo_id, kind, now.unwrap_or_else(SystemTime::now)),
+                Err(e) => tracing::warn!("maintenance: {} failed on {}: {}", kind, repo_id, e),
+            }
+            self.progress.task_finished(repo_id, kind, &result);
+            report.results.push((kind, result));
+        }
+        Ok(report)
+    }
+}
+
+/// Settings of [`repack`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RepackOptions {
+    /// Delta window, depth and big file threshold of the new pack.
+    pub delta_options: DeltaOptions,
+    /// Also write the reachability bitmaps of the commits the references point at.
+    pub write_bitmap: bool,
+    /// Also write a multi-pack-index over the new pack and the kept packs.
+    pub write_midx: bool,
+    /// Also sweep the unreachable objects of the replaced packs and the loose ones into a cruft
+    /// pack, like `git repack --cruft`, keeping those written at this time or later and what
+    /// they refer to. The older ones go with their packs, or stay loose for [`prune`].
+    pub cruft_expire: Option<SystemTime>,
+}
+
+/// Outcome of a [`repack`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepackReport {
+    /// The trailer checksum of the new pack.
+    pub pack_hash: SHA1,
+    /// Number of objects in the new pack.
+    pub objects: usize,
+    /// The packs replaced by the new one.
+    pub removed_packs: Vec<SHA1>,
+    /// The trailer checksum of the cruft pack, when `cruft_expire` kept any object.
+    pub cruft_pack: Option<SHA1>,
+}
+
+/// Consolidate the objects reachable from the references of `repo` into one pack, like
+/// `git repack -a -d`, going through [`RepositoryAccess`] only.
+///
+/// The new pack is stored with `store_kept_pack` and stays kept until `remove_repacked` has
+/// dropped the packs it replaces, those of `list_packs` that are not kept, and the loose copies
+/// of its objects. Like `git pack-objects --all --reflog`, the new pack also holds the objects
+/// only reflog entries reach, which reverting a ref update may need. Unreachable objects of the
+/// replaced packs go with them, unless [`cruft_expire`](RepackOptions::cruft_expire) sweeps the
+/// recent ones into a cruft pack. Returns `None` when the repository has no reference.
+pub async fn repack<R: RepositoryAccess>(
+    repo: &R,
+    options: &RepackOptions,
+) -> Result<Option<RepackReport>, ProtocolError> {
+    let tips = ref_tips(repo).await?;
+    if tips.is_empty() {
+        return Ok(None);
+    }
+    let wants = reflog_tips(repo).await?;
+    // Listed first, packs stored while the new one is written are not removed
+    let packs = repo.list_packs().await?;
+
+    let mut generator = PackGenerator::new(repo);
+    generator.set_delta_options(options.delta_options);
+    let pack_stream: ProtocolStream = Box::pin(
+        generator
+            .generate_full_pack(wants)
+            .await?
+            .map(|chunk| Ok(Bytes::from(chunk))),
+    );
+    let keep = format!("repack {}", std::process::id());
+    // The new pack holds the objects, only the commits, trees and tags are needed below
+    let mut objects = PackObjects::default();
+    let store = |(commits, trees, _, tags): PackObjects| {
+        objects.0.extend(commits);
+        objects.1.extend(trees);
+        objects.3.extend(tags);
+        std::future::ready(Ok(()))
+    };
+    let mut indexed = generator
+        .index_pack_in_batches(pack_stream, Some(&keep), store)
+        .await?;
+    indexed.unpacked.objects = objects;
+    let pack_hash = indexed.pack_hash;
+    let report = finish_repack(repo, options, &tips, &packs, indexed).await;
+    let released = repo.release_pack_keep(&pack_hash, &keep).await;
+    let report = report?;
+    released?;
+    Ok(Some(report))
+}
+
+/// Write the bitmaps and multi-pack-index of a repack, then remove what the new pack replaces.
+async fn finish_repack<R: RepositoryAccess>(
+    repo: &R,
+    options: &RepackOptions,
+    tips: &[String],
+    packs: &[PackInfo],
+    indexed: IndexedPack,
+) -> Result<RepackReport, ProtocolError> {
+    let pack_hash = indexed.pack_hash;
+    let (commits, trees, _, tags) = indexed.unpacked.objects;
+    let idx = indexed.unpacked.idx.unwrap_or_default();
+    let index = PackIndex::from_bytes(idx).map_err(|e| {
+        ProtocolError::pack_corrupt(format!("Failed to read the new pack index: {e}"))
+    })?;
+
+    if options.write_bitmap {
+        let selected: Vec<SHA1> = commits
+            .iter()
+            .map(|commit| commit.id)
+            .filter(|id| tips.contains(&id.to_string()))
+            .collect();
+        let bitmap = PackBitmap::build(&index, &commits, &trees, &tags, &selected)
+            .map_err(|e| ProtocolError::pack_corrupt(format!("Failed to build bitmaps: {e}")))?;
+        repo.store_pack_bitmap(&pack_hash, bitmap.as_bytes())
+            .await?;
+    }
+
+    let (kept, replaced): (Vec<&PackInfo>, Vec<&PackInfo>) = packs
+        .iter()
+        .filter(|pack| pack.pack_hash != pack_hash)
+        .partition(|pack| pack.kept);
+    if options.write_midx {
+        let mut indexes = vec![(format!("pack-{pack_hash}.idx"), index.clone())];
+        for pack in kept {
+            match repo.get_pack_index(&pack.pack_hash).await? {
+                Some(data) => {
+                    let index = PackIndex::from_bytes(data).map_err(|e| {
+                        ProtocolError::pack_corrupt(format!(
+                            "Failed to read pack {}: {e}",
+                            pack.pack_hash
+                        ))
+                    })?;
+                    indexes.push((format!("pack-{}.idx", pack.pack_hash), index));
+                }
+                None => tracing::debug!("repack: no index of kept pack {}", pack.pack_hash),
+            }
+        }
+        let midx = MultiPackIndex::from_pack_indexes(
+            indexes.iter().map(|(name, index)| (name.as_str(), index)),
+        )
+        .map_err(|e| {
+            ProtocolError::pack_corrupt(format!("Failed to build multi-pack-index: {e}"))
+        })?;
+        repo.store_multi_pack_index(midx.as_bytes()).await?;
+    }
+
+    let removed_packs: Vec<SHA1> = replaced.iter().map(|pack| pack.pack_hash).collect();
+    let mut objects: Vec<SHA1> = index.entries().map(|(id, _)| id).collect();
+    let count = objects.len();
+    let mut cruft_pack = None;
+    if let Some(expire_before) = options.cruft_expire {
+        let packed: HashSet<SHA1> = objects.iter().copied().collect();
+        if let Some((hash, swept)) =
+            write_cruft_pack(repo, &replaced, &packed, expire_before).await?
+        {
+            objects.extend(swept);
+            cruft_pack = Some(hash);
+        }
+    }
+    repo.remove_repacked(&removed_packs, &objects).await?;
+    Ok(RepackReport {
+        pack_hash,
+        objects: count,
+        removed_packs,
+        cruft_pack,
+    })
+}
+
+/// Sweep the objects of the `replaced` packs and the loose objects that are not among the
+/// `packed` ones into a cruft pack, stored with `store_cruft_pack`, and return its checksum and
+/// objects; see [`RepackOptions::cruft_expire`].
+///
+/// Objects of a previous cruft pack keep the times of its `.mtimes`; those of other packs are
+/// taken for written now, as the repository tells no time for them.
+async fn write_cruft_pack<R: RepositoryAccess>(
+    repo: &R,
+    replaced: &[&PackInfo],
+    packed: &HashSet<SHA1>,
+    expire_before: SystemTime,
+) -> Result<Option<(SHA1, Vec<SHA1>)>, ProtocolError> {
+    let now = unix_time(SystemTime::now());
+    let mut mtimes: HashMap<SHA1, u32> = HashMap::new();
+    for (id, mtime) in repo.list_loose_objects().await? {
+        if !packed.contains(&id) {
+            mtimes.insert(id, unix_time(mtime));
+        }
+    }
+    for pack in replaced {
+        let Some(data) = repo.get_pack_index(&pack.pack_hash).await? else {
+            tracing::debug!(
+                "repack: no index of pack {}, nothing to sweep",
+                pack.pack_hash
+            );
+            continue;
+        };
+        let read_error = |e: GitError| {
+            ProtocolError::pack_corrupt(format!("Failed to read pack {}: {e}", pack.pack_hash))
+        };
+        let index = PackIndex::from_bytes(data).map_err(read_error)?;
+        let times = match repo.get_pack_mtimes(&pack.pack_hash).await? {
+            Some(data) => Some(PackMtimes::from_bytes(data, &index).map_err(read_error)?),
+            None => None,
+        };
+        for (id, _) in index.entries() {
+            if packed.contains(&id) {
+                continue;
+            }
+            let mtime = times
+                .as_ref()
+                .and_then(|times| times.mtime(&index, &id))
+                .unwrap_or(now);
+            let known = mtimes.entry(id).or_insert(mtime);
+            *known = (*known).max(mtime);
+        }
+    }
+
+    let mut unreachable = Vec::with_capacity(mtimes.len());
+    for (id, mtime) in mtimes {
+        if let Some((obj_type, data)) = load_object(repo, &id).await? {
+            let entry = Entry {
+                obj_type,
+                data,
+                hash: id,
+                chain_len: 0,
+            };
+            unreachable.push((entry, mtime));
+        }
+    }
+    let sweep = build_cruft_pack(unreachable, unix_time(expire_before))
+        .map_err(|e| ProtocolError::pack_corrupt(format!("Failed to build the cruft pack: {e}")))?;
+    let Some(cruft) = sweep.pack else {
+        return Ok(None);
+    };
+    repo.store_cruft_pack(&cruft).await?;
+    let index = PackIndex::from_bytes(cruft.idx.clone()).map_err(|e| {
+        ProtocolError::pack_corrupt(format!("Failed to read the cruft pack index: {e}"))
+    })?;
+    Ok(Some((
+        cruft.pack_hash,
+        index.entries().map(|(id, _)| id).collect(),
+    )))
+}
+
+/// The [`TaskKind::Repack`] job of repositories served by a [`RepositoryAccess`], see [`repack`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RepackTask {
+    pub options: RepackOptions,
+}
+
+#[async_trait]
+impl<R: RepositoryAccess> MaintenanceTask<R> for RepackTask {
+    fn kind(&self) -> TaskKind {
+        TaskKind::Repack
+    }
+
+    async fn run(&self, repo: &R, progress: &TaskProgress<'_>) -> Result<(), GitError> {
+        let report = repack(repo, &self.options)
+            .await
+            .map_err(|e| GitError::CustomError(format!("repack failed: {e}")))?;
+        if let Some(report) = report {
+            progress.update(report.objects, Some(report.objects));
+        }
+        Ok(())
+    }
+}
+
+/// Write the commit-graph of the commits reachable from the references of `repo`, like
+/// `git commit-graph write --reachable`, and store it with `store_commit_graph`. Returns the
+/// number of commits in the graph, `None` when no reference leads to a commit.
+pub async fn write_commit_graph<R: RepositoryAccess>(
+    repo: &R,
+) -> Result<Option<usize>, ProtocolError> {
+    let mut walker = CommitWalker::new(repo);
+    let mut has_tips = false;
+    for tip in ref_tips(repo).await? {
+        if let Some((id, ObjectType::Commit)) = peel(repo, parse_id(&tip)?).await? {
+            walker = walker.push(id);
+            has_tips = true;
+        }
+    }
+    if !has_tips {
+        return Ok(None);
+    }
+    let commits = walker.collect().await?;
+    let graph = CommitGraph::from_commits(&commits).map_err(|e| {
+        ProtocolError::repository_error(format!("Failed to build the commit-graph: {e}"))
+    })?;
+    repo.store_commit_graph(graph.as_bytes()).await?;
+    Ok(Some(graph.len()))
+}
+
+/// Pack the references of `repo` with its `pack_refs`, like `git pack-refs --all`, handing it
+/// the object each annotated tag they point to peels to. Returns the number of references
+/// packed.
+pub async fn pack_refs<R: RepositoryAccess>(repo: &R) -> Result<usize, ProtocolError> {
+    let mut peeled = HashMap::new();
+    for tip in ref_tips(repo).await? {
+        let id = parse_id(&tip)?;
+        if let Some((target, _)) = peel(repo, id).await?
+            && target != id
+        {
+            peeled.insert(id, target);
+        }
+    }
+    repo.pack_refs(&peeled).await
+}
+
+/// Remove the loose objects of `repo` that neither a reference nor a reflog entry reaches and
+/// that were last written before `expire_before`, like `git prune --expire`.
+///
+/// Unreachable objects a more recent one refers to are kept with it, as in a cruft pack. The
+/// expired objects are removed with `remove_repacked` and returned, sorted. Fails without
+/// removing anything when an object reachable from a reference is missing, since what it
+/// leads to can't be told apart from garbage.
+pub async fn prune<R: RepositoryAccess>(
+    repo: &R,
+    expire_before: SystemTime,
+) -> Result<Vec<SHA1>, ProtocolError> {
+    let loose = repo.list_loose_objects().await?;
+    if loose.is_empty() {
+        return Ok(Vec::new());
+    }
+    let tips = reflog_tips(repo).await?;
+    let tips = tips
+        .iter()
+        .map(|tip| parse_id(tip))
+        .collect::<Result<_, _>>()?;
+    let reachable = reachable_objects(repo, tips).await?;
+
+    let mut unreachable = Vec::new();
+    for (id, mtime) in loose {
+        if reachable.contains(&id) {
+            continue;
+        }
+        if let Some((obj_type, data)) = load_object(repo, &id).await? {
+            let entry = Entry {
+                obj_type,
+                data,
+                hash: id,
+                chain_len: 0,
+            };
+            unreachable.push((entry, unix_time(mtime)));
+        }
+    }
+    let kept = recent_objects(&unreachable, unix_time(expire_before));
+    let mut expired: Vec<SHA1> = unreachable
+        .iter()
+        .map(|(entry, _)| entry.hash)
+        .filter(|id| !kept.contains(id))
+        .collect();
+    expired.sort_unstable();
+    expired.dedup();
+    if !expired.is_empty() {
+        repo.remove_repacked(&[], &expired).await?;
+    }
+    Ok(expired)
+}
+
+/// The [`TaskKind::CommitGraph`] job of repositories served by a [`RepositoryAccess`], see
+/// [`write_commit_graph`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CommitGraphTask;
+
+#[async_trait]
+impl<R: RepositoryAccess> MaintenanceTask<R> for CommitGraphTask {
+    fn kind(&self) -> TaskKind {
+        TaskKind::CommitGraph
+    }
+
+    async fn run(&self, repo: &R, progress: &TaskProgress<'_>) -> Result<(), GitError> {
+        let commits = write_commit_graph(repo)
+            .await
+            .map_err(|e| GitError::CustomError(format!("commit-graph write failed: {e}")))?;
+        if let Some(commits) = commits {
+            progress.update(commits, Some(commits));
+        }
+        Ok(())
+    }
+}
+
+/// The [`TaskKind::PackRefs`] job of repositories served by a [`RepositoryAccess`], see
+/// [`pack_refs`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PackRefsTask;
+
+#[async_trait]
+impl<R: RepositoryAccess> MaintenanceTask<R> for PackRefsTask {
+    fn kind(&self) -> TaskKind {
+        TaskKind::PackRefs
+    }
+
+    async fn run(&self, repo: &R, progress: &TaskProgress<'_>) -> Result<(), GitError> {
+        let packed = pack_refs(repo)
+            .await
+            .map_err(|e| GitError::CustomError(format!("pack-refs failed: {e}")))?;
+        progress.update(packed, Some(packed));
+        Ok(())
+    }
+}
+
+/// The [`TaskKind::Prune`] job of repositories served by a [`RepositoryAccess`], see [`prune`].
+#[derive(Debug, Clone, Copy)]
+pub struct PruneTask {
+    /// How long unreachable loose objects are kept after they were last written.
+    pub expire: Duration,
+}
+
+impl Default for PruneTask {
+    fn default() -> Self {
+        Self {
+            expire: DEFAULT_PRUNE_EXPIRE,
+        }
+    }
+}
+
+#[async_trait]
+impl<R: RepositoryAccess> MaintenanceTask<R> for PruneTask {
+    fn kind(&self) -> TaskKind {
+        TaskKind::Prune
+    }
+
+    async fn run(&self, repo: &R, progress: &TaskProgress<'_>) -> Result<(), GitError> {
+        let expire_before = SystemTime::now()
+            .checked_sub(self.expire)
+            .unwrap_or(UNIX_EPOCH);
+        let pruned = prune(repo, expire_before)
+            .await
+            .map_err(|e| GitError::CustomError(format!("prune failed: {e}")))?;
+        progress.update(pruned.len(), Some(pruned.len()));
+        Ok(())
+    }
+}
+
+/// The [`TaskKind::Gc`] job of repositories served by a [`RepositoryAccess`], like `git gc`:
+/// [`pack_refs`], [`repack`] with a cruft pack of the recent unreachable objects, [`prune`] of
+/// the expired loose ones and [`write_commit_graph`], in that order.
+#[derive(Debug, Clone, Copy)]
+pub struct GcTask {
+    /// Settings of the repack, whose `cruft_expire` is set from `expire`.
+    pub repack: RepackOptions,
+    /// How long unreachable objects are kept after they were last written.
+    pub expire: Duration,
+}
+
+impl Default for GcTask {
+    fn default() -> Self {
+        Self {
+            repack: RepackOptions::default(),
+            expire: DEFAULT_PRUNE_EXPIRE,
+        }
+    }
+}
+
+#[async_trait]
+impl<R: RepositoryAccess> MaintenanceTask<R> for GcTask {
+    fn kind(&self) -> TaskKind {
+        TaskKind::Gc
+    }
+
+    async fn run(&self, repo: &R, progress: &TaskProgress<'_>) -> Result<(), GitError> {
+        const STEPS: usize = 4;
+        let failed =
+            |step: &str, e: ProtocolError| GitError::CustomError(format!("gc failed: {step}: {e}"));
+        let expire_before = SystemTime::now()
+            .checked_sub(self.expire)
+            .unwrap_or(UNIX_EPOCH);
+        pack_refs(repo).await.map_err(|e| failed("pack-refs", e))?;
+        progress.update(1, Some(STEPS));
+        let options = RepackOptions {
+            cruft_expire: Some(expire_before),
+            ..self.repack
+        };
+        repack(repo, &options)
+            .await
+            .map_err(|e| failed("repack", e))?;
+        progress.update(2, Some(STEPS));
+        prune(repo, expire_before)
+            .await
+            .map_err(|e| failed("prune", e))?;
+        progress.update(3, Some(STEPS));
+        write_commit_graph(repo)
+            .await
+            .map_err(|e| failed("commit-graph write", e))?;
+        progress.update(STEPS, Some(STEPS));
+        Ok(())
+    }
+}
+
+/// The values of the references of `repo`, sorted and once each.
+async fn ref_tips<R: RepositoryAccess>(repo: &R) -> Result<Vec<String>, ProtocolError> {
+    let mut tips: Vec<String> = repo
+        .get_repository_refs()
+        .await?
+        .into_iter()
+        .map(|(_, hash)| hash)
+        .collect();
+    tips.sort_unstable();
+    tips.dedup();
+    Ok(tips)
+}
+
+/// The values of the references of `repo` and the objects the entries of their reflogs name,
+/// sorted and once each.
+async fn reflog_tips<R: RepositoryAccess>(repo: &R) -> Result<Vec<String>, ProtocolError> {
+    let mut tips = Vec::new();
+    for (name, hash) in repo.get_repository_refs().await? {
+        for entry in repo.get_reflog(&name).await? {
+            for hash in [entry.old_hash, entry.new_hash] {
+                // Entries may point to objects pruned already
+                if hash != ZERO_ID && repo.has_object(&hash).await? {
+                    tips.push(hash);
+                }
+            }
+        }
+        tips.push(hash);
+    }
+    tips.sort_unstable();
+    tips.dedup();
+    Ok(tips)
+}
+
+fn parse_id(hash: &str) -> Result<SHA1, ProtocolError> {
+    SHA1::from_str(hash)
+        .map_err(|_| ProtocolError::repository_error(format!("Invalid object id {hash}")))
+}
+
+/// The object `id` names through annotated tags, with its type; `None` when one is missing.
+async fn peel<R: RepositoryAccess>(
+    repo: &R,
+    mut id: SHA1,
+) -> Result<Option<(SHA1, ObjectType)>, ProtocolError> {
+    for _ in 0..MAX_PEEL_DEPTH {
+        match load_object(repo, &id).await? {
+            Some((ObjectType::Tag, data)) => {
+                let tag =
+                    Tag::from_bytes(&data, id).map_err(|e| ProtocolError::corrupt_object(id, e))?;
+                id = tag.object_hash;
+            }
+            Some((obj_type, _)) => return Ok(Some((id, obj_type))),
+            None => return Ok(None),
+        }
+    }
+    Err(ProtocolError::corrupt_object(id, "too many tags of tags"))
+}
+
+/// The objects reachable from `tips` through tags, commits and trees, submodule commits
+/// excepted. Blobs are listed without being read.
+async fn reachable_objects<R: RepositoryAccess>(
+    repo: &R,
+    mut queue: Vec<SHA1>,
+) -> Result<HashSet<SHA1>, ProtocolError> {
+    let mut seen = HashSet::new();
+    while let Some(id) = queue.pop() {
+        if !seen.insert(id) {
+            continue;
+        }
+        let (obj_type, data) = load_object(repo, &id)
+            .await?
+            .ok_or_else(|| ProtocolError::ObjectNotFound(id.to_string()))?;
+        let parse_error = |e: GitError| ProtocolError::corrupt_object(id, e);
+        match obj_type {
+            ObjectType::Commit => {
+                let commit = Commit::from_bytes(&data, id).map_err(parse_error)?;
+                queue.push(commit.tree_id);
+                queue.extend(commit.parent_commit_ids);
+            }
+            ObjectType::Tree => {
+                let tree = <Tree as ObjectTrait>::from_bytes(&data, id).map_err(parse_error)?;
+                for item in tree.tree_items {
+                    match item.mode {
+                        TreeItemMode::Tree => queue.push(item.id),
+                        TreeItemMode::Commit => {}
+                        _ => {
+                            seen.insert(item.id);
+                        }
+                    }
+                }
+            }
+            ObjectType::Tag => {
+                queue.push(Tag::from_bytes(&data, id).map_err(parse_error)?.object_hash);
+            }
+            _ => {}
+        }
+    }
+    Ok(seen)
+}
+
+/// Seconds since the epoch, as cruft packs record modification times.
+fn unix_time(time: SystemTime) -> u32 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as u32)
+}
+
+/// `GitError` is not `Clone`; keep the message when it must be reported twice.
+fn clone_error(e: &GitError) -> GitError {
+    GitError::CustomError(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::object::blob::Blob;
+    use crate::internal::object::signature::{Signature, SignatureType};
+    use crate::internal::object::tree::TreeItem;
+    use crate::internal::pack::cruft::CruftPack;
+    use crate::internal::pack::idx::{IdxEntry, build_idx_v2};
+    use crate::protocol::memory::MemoryRepository;
+    use crate::protocol::types::ReflogEntry;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct CountingTask {
+        kind: TaskKind,
+        runs: AtomicUsize,
+        fail: bool,
+    }
+
+    impl CountingTask {
+        fn new(kind: TaskKind, fail: bool) -> Arc<Self> {
+            Arc::new(Self {
+                kind,
+                runs: AtomicUsize::new(0),
+                fail,
+            })
+        }
+    }
+
+    #[async_trait]
+    impl MaintenanceTask<()> for CountingTask {
+        fn kind(&self) -> TaskKind {
+            self.kind
+        }
+
+        async fn run(&self, _repo: &(), progress: &TaskProgress<'_>) -> Result<(), GitError> {
+            self.runs.fetch_add(1, Ordering::SeqCst);
+            progress.update(1, Some(1));
+            if self.fail {
+                return Err(GitError::CustomError("boom".to_string()));
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingProgress {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl MaintenanceProgress for RecordingProgress {
+        fn task_started(&self, repo: &str, task: TaskKind) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("start {repo} {task}"));
+        }
+
+        fn task_progress(&self, repo: &str, task: TaskKind, done: usize, _total: Option<usize>) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("progress {repo} {task} {done}"));
+        }
+
+        fn task_finished(&self, repo: &str, task: TaskKind, result: &Result<(), GitError>) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("finish {repo} {task} {}", result.is_ok()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_all_reports_progress() {
+        let gc = CountingTask::new(TaskKind::Gc, false);
+        let prune = CountingTask::new(TaskKind::Prune, true);
+        let progress = Arc::new(RecordingProgress::default());
+        let scheduler = MaintenanceScheduler::new()
+            .with_task(gc.clone())
+            .with_task(prune.clone())
+            .with_progress(progress.clone());
+
+        let report = scheduler.run_all("repo", &()).await.unwrap();
+        assert_eq!(report.results.len(), 2);
+        assert!(!report.is_success());
+        assert!(scheduler.last_run("repo", TaskKind::Gc).is_some());
+        assert!(scheduler.last_run("repo", TaskKind::Prune).is_none());
+        assert_eq!(
+            *progress.events.lock().unwrap(),
+            vec![
+                "start repo gc",
+                "progress repo gc 1",
+                "finish repo gc true",
+                "start repo prune",
+                "progress repo prune 1",
+                "finish repo prune false",
+            ]
+        );
+    }
+
+    /// A task that can't tell whether it has anything to do.
+    struct BrokenCheck;
+
+    #[async_trait]
+    impl MaintenanceTask<()> for BrokenCheck {
+        fn kind(&self) -> TaskKind {
+            TaskKind::Repack
+        }
+
+        async fn should_run(&self, _repo: &()) -> Result<bool, GitError> {
+            Err(GitError::CustomError("no access".to_string()))
+        }
+
+        async fn run(&self, _repo: &(), _progress: &TaskProgress<'_>) -> Result<(), GitError> {
+            unreachable!("should_run failed")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_should_run_error_reports_start() {
+        let progress = Arc::new(RecordingProgress::default());
+        let scheduler = MaintenanceScheduler::new()
+            .with_task(Arc::new(BrokenCheck))
+            .with_progress(progress.clone());
+
+        let report = scheduler.run_all("repo", &()).await.unwrap();
+        assert!(!report.is_success());
+        assert_eq!(
+            *progress.events.lock().unwrap(),
+            vec!["start repo repack", "finish repo repack false"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_due_respects_interval() {
+        let graph = CountingTask::new(TaskKind::CommitGraph, false);
+        let scheduler = MaintenanceScheduler::new().with_task(graph.clone());
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        scheduler.run_due("repo", &(), start).await.unwrap();
+        let report = scheduler
+            .run_due("repo", &(), start + Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(report.skipped, vec![TaskKind::CommitGraph]);
+        scheduler
+            .run_due("repo", &(), start + Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(graph.runs.load(Ordering::SeqCst), 2);
+    }
+
+    /// The packs and number of objects of each `remove_repacked` call
+    type RemovedPacks = Arc<Mutex<Vec<(Vec<SHA1>, usize)>>>;
+
+    /// A [`MemoryRepository`] of loose objects with `packs`, recording what a repack stores and
+    /// removes and what pack-refs is handed.
+    #[derive(Clone, Default)]
+    struct PackedRepo {
+        repo: MemoryRepository,
+        packs: Vec<PackInfo>,
+        kept_idx: Vec<u8>,
+        loose: Vec<(SHA1, SystemTime)>,
+        stored: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+        removed: RemovedPacks,
+        peeled: Arc<Mutex<HashMap<SHA1, SHA1>>>,
+    }
+
+    #[async_trait]
+    impl RepositoryAccess for PackedRepo {
+        async fn get_repository_refs(&self) -> Result<Vec<(String, String)>, ProtocolError> {
+            self.repo.get_repository_refs().await
+        }
+        async fn has_object(&self, object_hash: &str) -> Result<bool, ProtocolError> {
+            self.repo.has_object(object_hash).await
+        }
+        async fn get_object(&self, object_hash: &str) -> Result<Vec<u8>, ProtocolError> {
+            self.repo.get_object(object_hash).await
+        }
+        async fn store_pack_data(&self, pack_data: &[u8]) -> Result<(), ProtocolError> {
+            self.repo.store_pack_data(pack_data).await
+        }
+        async fn store_pack_index(
+            &self,
+            pack_data: &[u8],
+            idx_data: &[u8],
+        ) -> Result<(), ProtocolError> {
+            let mut stored = self.stored.lock().unwrap();
+            stored.insert("pack".to_string(), pack_data.to_vec());
+            stored.insert("idx".to_string(), idx_data.to_vec());
+            Ok(())
+        }
+        async fn store_cruft_pack(&self, cruft: &CruftPack) -> Result<(), ProtocolError> {
+            let mut stored = self.stored.lock().unwrap();
+            stored.insert("cruft".to_string(), cruft.idx.clone());
+            stored.insert("mtimes".to_string(), cruft.mtimes.as_bytes().to_vec());
+            Ok(())
+        }
+        async fn list_packs(&self) -> Result<Vec<PackInfo>, ProtocolError> {
+            Ok(self.packs.clone())
+        }
+        async fn get_pack_index(&self, pack_hash: &SHA1) -> Result<Option<Vec<u8>>, ProtocolError> {
+            let kept = self
+                .packs
+                .iter()
+                .any(|p| p.kept && p.pack_hash == *pack_hash);
+            Ok(kept.then(|| self.kept_idx.clone()))
+        }
+        async fn store_pack_bitmap(
+            &self,
+            _pack_hash: &SHA1,
+            bitmap_data: &[u8],
+        ) -> Result<(), ProtocolError> {
+            let mut stored = self.stored.lock().unwrap();
+            stored.insert("bitmap".to_string(), bitmap_data.to_vec());
+            Ok(())
+        }
+        async fn store_multi_pack_index(&self, midx_data: &[u8]) -> Result<(), ProtocolError> {
+            let mut stored = self.stored.lock().unwrap();
+            stored.insert("midx".to_string(), midx_data.to_vec());
+            Ok(())
+        }
+        async fn remove_repacked(
+            &self,
+            packs: &[SHA1],
+            objects: &[SHA1],
+        ) -> Result<(), ProtocolError> {
+            self.removed
+                .lock()
+                .unwrap()
+                .push((packs.to_vec(), objects.len()));
+            Ok(())
+        }
+        async fn pack_refs(&self, peeled: &HashMap<SHA1, SHA1>) -> Result<usize, ProtocolError> {
+            *self.peeled.lock().unwrap() = peeled.clone();
+            Ok(self.repo.get_repository_refs().await?.len())
+        }
+        async fn list_loose_objects(&self) -> Result<Vec<(SHA1, SystemTime)>, ProtocolError> {
+            Ok(self.loose.clone())
+        }
+        async fn get_reflog(&self, ref_name: &str) -> Result<Vec<ReflogEntry>, ProtocolError> {
+            self.repo.get_reflog(ref_name).await
+        }
+        async fn update_reference(
+            &self,
+            ref_name: &str,
+            old_hash: Option<&str>,
+            new_hash: &str,
+        ) -> Result<(), ProtocolError> {
+            self.repo
+                .update_reference(ref_name, old_hash, new_hash)
+                .await
+        }
+        async fn get_objects_for_pack(
+            &self,
+            wants: &[String],
+            haves: &[String],
+        ) -> Result<Vec<String>, ProtocolError> {
+            self.repo.get_objects_for_pack(wants, haves).await
+        }
+        async fn has_default_branch(&self) -> Result<bool, ProtocolError> {
+            self.repo.has_default_branch().await
+        }
+        async fn post_receive_hook(&self) -> Result<(), ProtocolError> {
+            Ok(())
+        }
+    }
+
+    /// Two commits on `refs/heads/main`, each adding a file, plus an unreachable blob.
+    fn packed_repo() -> PackedRepo {
+        let signature = |kind| Signature::new(kind, "t".to_string(), "t@example.com".to_string());
+        let repo = PackedRepo::default();
+        let mut items = Vec::new();
+        let mut parents = Vec::new();
+        for content in ["one", "two"] {
+            let blob = Blob::from_content(content);
+            items.push(TreeItem::new(
+                TreeItemMode::Blob,
+                blob.id,
+                content.to_string(),
+            ));
+            let tree = Tree::from_tree_items(items.clone()).unwrap();
+            let commit = Commit::new(
+                signature(SignatureType::Author),
+                signature(SignatureType::Committer),
+                tree.id,
+                parents,
+                &format!("\n{content}"),
+            );
+            parents = vec![commit.id];
+            repo.repo.insert_object(&blob).unwrap();
+            repo.repo.insert_object(&tree).unwrap();
+            repo.repo.insert_object(&commit).unwrap();
+        }
+        let unreachable = Blob::from_content("unreachable");
+        repo.repo.insert_object(&unreachable).unwrap();
+        repo.repo.set_ref("refs/heads/main", parents[0]);
+        repo
+    }
+
+    #[tokio::test]
+    async fn test_repack() {
+        let mut repo = packed_repo();
+        let old = SHA1::new(b"old pack");
+        let kept = SHA1::new(b"kept pack");
+        let kept_entry = IdxEntry {
+            hash: SHA1::new(b"kept object"),
+            offset: 12,
+            crc32: 0,
+        };
+        repo.kept_idx = build_idx_v2(&[kept_entry], &kept);
+        repo.packs = vec![
+            PackInfo {
+                pack_hash: old,
+                kept: false,
+            },
+            PackInfo {
+                pack_hash: kept,
+                kept: true,
+            },
+        ];
+        let options = RepackOptions {
+            write_bitmap: true,
+            write_midx: true,
+            ..Default::default()
+        };
+
+        let report = repack(&repo, &options).await.unwrap().unwrap();
+        assert_eq!(report.objects, 6);
+        assert_eq!(report.removed_packs, vec![old]);
+        assert_eq!(*repo.removed.lock().unwrap(), vec![(vec![old], 6)]);
+
+        let stored = repo.stored.lock().unwrap().clone();
+        let index = PackIndex::from_bytes(stored["idx"].clone()).unwrap();
+        assert_eq!(index.pack_hash(), report.pack_hash);
+        assert_eq!(
+            SHA1::new(&stored["pack"][..stored["pack"].len() - 20]),
+            report.pack_hash
+        );
+        let bitmap = PackBitmap::from_bytes(stored["bitmap"].clone(), &index).unwrap();
+        let tip = repo.repo.get_ref("refs/heads/main").unwrap();
+        assert_eq!(bitmap.reachable([&tip]).unwrap().count(), 6);
+        let midx = MultiPackIndex::from_bytes(stored["midx"].clone()).unwrap();
+        assert_eq!(midx.len(), 7);
+        assert_eq!(
+            midx.find_object(&kept_entry.hash),
+            Some((format!("pack-{kept}.idx").as_str(), 12))
+        );
+
+        let empty = PackedRepo::default();
+        assert_eq!(repack(&empty, &options).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_repack_keeps_reflog_objects() {
+        let repo = packed_repo();
+        let main = repo.repo.get_ref("refs/heads/main").unwrap();
+        let (_, data) = repo.repo.object(&main).unwrap();
+        let tip = Commit::from_bytes(&data, main).unwrap();
+        let signature = |kind| Signature::new(kind, "t".to_string(), "t@example.com".to_string());
+        let dropped = Commit::new(
+            signature(SignatureType::Author),
+            signature(SignatureType::Committer),
+            tip.tree_id,
+            vec![main],
+            "\ndropped",
+        );
+        repo.repo.insert_object(&dropped).unwrap();
+        let (main, dropped_id) = (main.to_string(), dropped.id.to_string());
+        repo.repo
+            .update_reference("refs/heads/main", Some(&main), &dropped_id)
+            .await
+            .unwrap();
+        repo.repo
+            .update_reference("refs/heads/main", Some(&dropped_id), &main)
+            .await
+            .unwrap();
+
+        let report = repack(&repo, &RepackOptions::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(report.objects, 7);
+        let stored = repo.stored.lock().unwrap().clone();
+        let index = PackIndex::from_bytes(stored["idx"].clone()).unwrap();
+        assert!(index.entries().any(|(id, _)| id == dropped.id));
+    }
+
+    #[tokio::test]
+    async fn test_repack_task() {
+        let repo = packed_repo();
+        let scheduler = MaintenanceScheduler::new().with_task(Arc::new(RepackTask::default()));
+        let report = scheduler.run_all("repo", &repo).await.unwrap();
+        assert!(report.is_success());
+        assert_eq!(repo.removed.lock().unwrap().len(), 1);
+        assert!(!repo.stored.lock().unwrap().contains_key("bitmap"));
+    }
+
+    #[tokio::test]
+    async fn test_gc_task() {
+        let mut repo = packed_repo();
+        let recent = Blob::from_content("unreachable");
+        let expired = Blob::from_content("expired");
+        repo.repo.insert_object(&expired).unwrap();
+        repo.loose = vec![
+            (recent.id, SystemTime::now()),
+            (expired.id, UNIX_EPOCH + Duration::from_secs(10)),
+        ];
+        let progress = Arc::new(RecordingProgress::default());
+        let scheduler = MaintenanceScheduler::new()
+            .with_task(Arc::new(GcTask::default()))
+            .with_progress(progress.clone());
+
+        let report = scheduler.run_all("repo", &repo).await.unwrap();
+        assert!(report.is_success());
+        // The repack removes the loose copies of its 6 objects and of the swept one, then the
+        // prune the expired one
+        assert_eq!(
+            *repo.removed.lock().unwrap(),
+            vec![(vec![], 7), (vec![], 1)]
+        );
+        let stored = repo.stored.lock().unwrap().clone();
+        let cruft = PackIndex::from_bytes(stored["cruft"].clone()).unwrap();
+        let swept: Vec<SHA1> = cruft.entries().map(|(id, _)| id).collect();
+        assert_eq!(swept, [recent.id]);
+        let mtimes = PackMtimes::from_bytes(stored["mtimes"].clone(), &cruft).unwrap();
+        assert!(mtimes.mtime(&cruft, &recent.id).unwrap() > 10);
+        assert!(
+            progress
+                .events
+                .lock()
+                .unwrap()
+                .contains(&"progress repo gc 4".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_commit_graph_task() {
+        let repo = packed_repo().repo;
+        let scheduler = MaintenanceScheduler::new().with_task(Arc::new(CommitGraphTask));
+        let report = scheduler.run_all("repo", &repo).await.unwrap();
+        assert!(report.is_success());
+        let graph = repo.get_commit_graph().await.unwrap().unwrap();
+        assert_eq!(graph.len(), 2);
+        let tip = repo.get_ref("refs/heads/main").unwrap();
+        let parent = graph.get(&tip).unwrap().parents[0];
+        assert_eq!(graph.get(&parent).unwrap().topo_level, 1);
+
+        let empty = MemoryRepository::new();
+        assert_eq!(write_commit_graph(&empty).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_pack_refs_task() {
+        let repo = packed_repo();
+        let tip = repo.repo.get_ref("refs/heads/main").unwrap();
+        let tagger = Signature::new(SignatureType::Tagger, "t".to_string(), String::new());
+        let tag = Tag::new(
+            tip,
+            ObjectType::Commit,
+            "v1".to_string(),
+            tagger,
+            String::new(),
+        );
+        repo.repo.insert_object(&tag).unwrap();
+        repo.repo.set_ref("refs/tags/v1", tag.id);
+
+        let scheduler = MaintenanceScheduler::new().with_task(Arc::new(PackRefsTask));
+        let report = scheduler.run_all("repo", &repo).await.unwrap();
+        assert!(report.is_success());
+        assert_eq!(*repo.peeled.lock().unwrap(), HashMap::from([(tag.id, tip)]));
+    }
+
+    #[tokio::test]
+    async fn test_prune() {
+        let mut repo = packed_repo();
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let (old, recent, expire_before) = (at(10), at(2000), at(1000));
+
+        // Only the reflog of main still reaches `dropped`
+        let main = repo.repo.get_ref("refs/heads/main").unwrap();
+        let (_, data) = repo.repo.object(&main).unwrap();
+        let tip = Commit::from_bytes(&data, main).unwrap();
+        let signature = |kind| Signature::new(kind, "t".to_string(), "t@example.com".to_string());
+        let dropped = Commit::new(
+            signature(SignatureType::Author),
+            signature(SignatureType::Committer),
+            tip.tree_id,
+            vec![main],
+            "\ndropped",
+        );
+        repo.repo.insert_object(&dropped).unwrap();
+        let (main, dropped_id) = (main.to_string(), dropped.id.to_string());
+        repo.repo
+            .update_reference("refs/heads/main", Some(&main), &dropped_id)
+            .await
+            .unwrap();
+        repo.repo
+            .update_reference("refs/heads/main", Some(&dropped_id), &main)
+            .await
+            .unwrap();
+
+        // A recent tree keeps the old blob it names
+        let named = Blob::from_content("named");
+        let item = TreeItem::new(TreeItemMode::Blob, named.id, "named".to_string());
+        let tree = Tree::from_tree_items(vec![item]).unwrap();
+        let expired = Blob::from_content("unreachable");
+        repo.repo.insert_object(&named).unwrap();
+        repo.repo.insert_object(&tree).unwrap();
+        repo.loose = vec![
+            (tip.id, old),
+            (dropped.id, old),
+            (named.id, old),
+            (tree.id, recent),
+            (expired.id, old),
+        ];
+
+        assert_eq!(prune(&repo, expire_before).await.unwrap(), [expired.id]);
+        assert_eq!(*repo.removed.lock().unwrap(), vec![(vec![], 1)]);
+        assert!(prune(&repo, old).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_repository_lock() {
+        let scheduler: MaintenanceScheduler<()> = MaintenanceScheduler::new();
+        let guard = scheduler.lock("repo").unwrap();
+        assert!(matches!(
+            scheduler.lock("repo"),
+            Err(GitError::RepositoryLocked(_))
+        ));
+        assert!(scheduler.lock("other").is_ok());
+        drop(guard);
+        assert!(scheduler.lock("repo").is_ok());
+    }
+}
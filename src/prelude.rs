@@ -0,0 +1,28 @@
+//! The stable public API, for `use git_internal::prelude::*;`.
+//!
+//! Everything re-exported here is covered by semver. Paths under `internal` that are not
+//! re-exported (caches, waitlists, reader wrappers, pack helpers) are implementation details of
+//! the decoder and encoder and may change in any release.
+pub use crate::errors::GitError;
+pub use crate::hash::SHA1;
+/// Object id of the repository's hash algorithm.
+pub use crate::hash::SHA1 as ObjectId;
+
+pub use crate::internal::object::ObjectTrait;
+pub use crate::internal::object::blob::Blob;
+pub use crate::internal::object::commit::Commit;
+pub use crate::internal::object::note::Note;
+pub use crate::internal::object::signature::{Signature, SignatureType};
+pub use crate::internal::object::signing::{ObjectSignature, SignatureVerifier, Verification};
+pub use crate::internal::object::tag::Tag;
+pub use crate::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+pub use crate::internal::object::types::ObjectType;
+
+pub use crate::internal::pack::Pack;
+pub use crate::internal::pack::encode::PackEncoder;
+pub use crate::internal::pack::entry::Entry;
+
+pub use crate::protocol::smart::SmartProtocol;
+pub use crate::protocol::{
+    AuthenticationService, GitProtocol, ProtocolError, RepositoryAccess, ServiceType,
+};
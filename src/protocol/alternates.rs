@@ -0,0 +1,389 @@
+//! Alternate object stores, the `objects/info/alternates` of a repository.
+//!
+//! A repository with alternates borrows the objects of other repositories: a read that misses
+//! its own objects falls back to each alternate in turn, while everything it writes stays its
+//! own. Forks of a repository typically list a shared object pool as their alternate, so the
+//! history they have in common is stored once for the whole fork network.
+//!
+//! [`AlternatesRepository`] gives those semantics to any [`RepositoryAccess`]: object reads go
+//! through the chain of stores, primary first, and references, pushes, packs and maintenance go
+//! to the primary only. `LooseObjectStore::alternates` reads the alternates file of an
+//! `objects` directory.
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+
+use crate::hash::SHA1;
+use crate::internal::commit_graph::CommitGraph;
+use crate::internal::object::blob::Blob;
+use crate::internal::object::commit::Commit;
+use crate::internal::object::tag::Tag;
+use crate::internal::object::tree::Tree;
+use crate::internal::object::types::ObjectType;
+use crate::internal::pack::bitmap::PackBitmap;
+use crate::internal::pack::cruft::CruftPack;
+use crate::internal::pack::encode::PackedDelta;
+use crate::protocol::core::RepositoryAccess;
+use crate::protocol::types::{
+    ObjectProvenance, PackInfo, ProtocolError, ProtocolStream, RefUpdate, ReflogEntry,
+};
+
+/// A repository reading missing objects from alternate stores, see the
+/// [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct AlternatesRepository<R> {
+    primary: R,
+    alternates: Arc<Vec<R>>,
+}
+
+impl<R: RepositoryAccess> AlternatesRepository<R> {
+    /// `primary` reading the objects it lacks from `alternates`, searched in order.
+    pub fn new(primary: R, alternates: Vec<R>) -> Self {
+        Self {
+            primary,
+            alternates: Arc::new(alternates),
+        }
+    }
+
+    /// The repository written to.
+    pub fn primary(&self) -> &R {
+        &self.primary
+    }
+
+    pub fn alternates(&self) -> &[R] {
+        &self.alternates
+    }
+
+    /// The first store of the chain holding `object_hash`.
+    async fn store_of(&self, object_hash: &str) -> Result<Option<&R>, ProtocolError> {
+        for store in std::iter::once(&self.primary).chain(self.alternates.iter()) {
+            if store.has_object(object_hash).await? {
+                return Ok(Some(store));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The store holding `object_hash`, or ObjectNotFound.
+    async fn require_store_of(&self, object_hash: &str) -> Result<&R, ProtocolError> {
+        self.store_of(object_hash)
+            .await?
+            .ok_or_else(|| ProtocolError::ObjectNotFound(object_hash.to_string()))
+    }
+}
+
+#[async_trait]
+impl<R: RepositoryAccess> RepositoryAccess for AlternatesRepository<R> {
+    async fn get_repository_refs(&self) -> Result<Vec<(String, String)>, ProtocolError> {
+        self.primary.get_repository_refs().await
+    }
+
+    async fn has_object(&self, object_hash: &str) -> Result<bool, ProtocolError> {
+        Ok(self.store_of(object_hash).await?.is_some())
+    }
+
+    async fn get_object(&self, object_hash: &str) -> Result<Vec<u8>, ProtocolError> {
+        self.require_store_of(object_hash)
+            .await?
+            .get_object(object_hash)
+            .await
+    }
+
+    async fn store_pack_data(&self, pack_data: &[u8]) -> Result<(), ProtocolError> {
+        self.primary.store_pack_data(pack_data).await
+    }
+
+    async fn store_pack_index(
+        &self,
+        pack_data: &[u8],
+        idx_data: &[u8],
+    ) -> Result<(), ProtocolError> {
+        self.primary.store_pack_index(pack_data, idx_data).await
+    }
+
+    async fn store_kept_pack(
+        &self,
+        pack_data: &[u8],
+        idx_data: &[u8],
+        keep: &str,
+    ) -> Result<(), ProtocolError> {
+        self.primary
+            .store_kept_pack(pack_data, idx_data, keep)
+            .await
+    }
+
+    async fn release_pack_keep(&self, pack_hash: &SHA1, keep: &str) -> Result<(), ProtocolError> {
+        self.primary.release_pack_keep(pack_hash, keep).await
+    }
+
+    async fn list_packs(&self) -> Result<Vec<PackInfo>, ProtocolError> {
+        self.primary.list_packs().await
+    }
+
+    async fn get_pack_index(&self, pack_hash: &SHA1) -> Result<Option<Vec<u8>>, ProtocolError> {
+        self.primary.get_pack_index(pack_hash).await
+    }
+
+    async fn store_cruft_pack(&self, cruft: &CruftPack) -> Result<(), ProtocolError> {
+        self.primary.store_cruft_pack(cruft).await
+    }
+
+    async fn get_pack_mtimes(&self, pack_hash: &SHA1) -> Result<Option<Vec<u8>>, ProtocolError> {
+        self.primary.get_pack_mtimes(pack_hash).await
+    }
+
+    async fn get_pack_data(&self, pack_hash: &SHA1) -> Result<Option<Vec<u8>>, ProtocolError> {
+        self.primary.get_pack_data(pack_hash).await
+    }
+
+    async fn store_pack_bitmap(
+        &self,
+        pack_hash: &SHA1,
+        bitmap_data: &[u8],
+    ) -> Result<(), ProtocolError> {
+        self.primary.store_pack_bitmap(pack_hash, bitmap_data).await
+    }
+
+    async fn store_multi_pack_index(&self, midx_data: &[u8]) -> Result<(), ProtocolError> {
+        self.primary.store_multi_pack_index(midx_data).await
+    }
+
+    async fn store_commit_graph(&self, graph_data: &[u8]) -> Result<(), ProtocolError> {
+        self.primary.store_commit_graph(graph_data).await
+    }
+
+    async fn pack_refs(&self, peeled: &HashMap<SHA1, SHA1>) -> Result<usize, ProtocolError> {
+        self.primary.pack_refs(peeled).await
+    }
+
+    /// The loose objects of the primary; those of the alternates are theirs to prune.
+    async fn list_loose_objects(&self) -> Result<Vec<(SHA1, SystemTime)>, ProtocolError> {
+        self.primary.list_loose_objects().await
+    }
+
+    async fn remove_repacked(&self, packs: &[SHA1], objects: &[SHA1]) -> Result<(), ProtocolError> {
+        self.primary.remove_repacked(packs, objects).await
+    }
+
+    async fn store_object_stream(
+        &self,
+        object_type: ObjectType,
+        size: usize,
+        data: ProtocolStream,
+    ) -> Result<SHA1, ProtocolError> {
+        self.primary
+            .store_object_stream(object_type, size, data)
+            .await
+    }
+
+    async fn update_reference(
+        &self,
+        ref_name: &str,
+        old_hash: Option<&str>,
+        new_hash: &str,
+    ) -> Result<(), ProtocolError> {
+        self.primary
+            .update_reference(ref_name, old_hash, new_hash)
+            .await
+    }
+
+    async fn update_references(
+        &self,
+        updates: &[RefUpdate],
+    ) -> Result<Vec<Result<(), ProtocolError>>, ProtocolError> {
+        self.primary.update_references(updates).await
+    }
+
+    async fn get_reflog(&self, ref_name: &str) -> Result<Vec<ReflogEntry>, ProtocolError> {
+        self.primary.get_reflog(ref_name).await
+    }
+
+    /// The commit-graph of the primary; one of an alternate would miss the primary's commits.
+    async fn get_commit_graph(&self) -> Result<Option<Arc<CommitGraph>>, ProtocolError> {
+        self.primary.get_commit_graph().await
+    }
+
+    async fn get_pack_bitmap(&self) -> Result<Option<Arc<PackBitmap>>, ProtocolError> {
+        self.primary.get_pack_bitmap().await
+    }
+
+    /// The deltas of the primary's packs, completed by those of the alternates for the objects
+    /// the primary doesn't have as a delta.
+    async fn get_packed_deltas(
+        &self,
+        object_hashes: &[String],
+    ) -> Result<HashMap<SHA1, PackedDelta>, ProtocolError> {
+        let mut deltas = self.primary.get_packed_deltas(object_hashes).await?;
+        for alternate in self.alternates.iter() {
+            let missing: Vec<String> = object_hashes
+                .iter()
+                .filter(|hash| SHA1::from_str(hash).is_ok_and(|id| !deltas.contains_key(&id)))
+                .cloned()
+                .collect();
+            if missing.is_empty() {
+                break;
+            }
+            for (id, delta) in alternate.get_packed_deltas(&missing).await? {
+                deltas.entry(id).or_insert(delta);
+            }
+        }
+        Ok(deltas)
+    }
+
+    async fn get_objects_for_pack(
+        &self,
+        wants: &[String],
+        haves: &[String],
+    ) -> Result<Vec<String>, ProtocolError> {
+        self.primary.get_objects_for_pack(wants, haves).await
+    }
+
+    async fn has_default_branch(&self) -> Result<bool, ProtocolError> {
+        self.primary.has_default_branch().await
+    }
+
+    async fn get_symbolic_ref(&self, ref_name: &str) -> Result<Option<String>, ProtocolError> {
+        self.primary.get_symbolic_ref(ref_name).await
+    }
+
+    async fn set_symbolic_ref(&self, ref_name: &str, target: &str) -> Result<(), ProtocolError> {
+        self.primary.set_symbolic_ref(ref_name, target).await
+    }
+
+    async fn default_branch(&self) -> Result<Option<String>, ProtocolError> {
+        self.primary.default_branch().await
+    }
+
+    async fn post_receive_hook(&self) -> Result<(), ProtocolError> {
+        self.primary.post_receive_hook().await
+    }
+
+    async fn get_blob(&self, object_hash: &str) -> Result<Blob, ProtocolError> {
+        self.require_store_of(object_hash)
+            .await?
+            .get_blob(object_hash)
+            .await
+    }
+
+    async fn get_commit(&self, commit_hash: &str) -> Result<Commit, ProtocolError> {
+        self.require_store_of(commit_hash)
+            .await?
+            .get_commit(commit_hash)
+            .await
+    }
+
+    async fn get_tree(&self, tree_hash: &str) -> Result<Tree, ProtocolError> {
+        self.require_store_of(tree_hash)
+            .await?
+            .get_tree(tree_hash)
+            .await
+    }
+
+    async fn get_tag(&self, tag_hash: &str) -> Result<Tag, ProtocolError> {
+        self.require_store_of(tag_hash)
+            .await?
+            .get_tag(tag_hash)
+            .await
+    }
+
+    async fn commit_exists(&self, commit_hash: &str) -> Result<bool, ProtocolError> {
+        match self.store_of(commit_hash).await? {
+            Some(store) => store.commit_exists(commit_hash).await,
+            None => Ok(false),
+        }
+    }
+
+    async fn record_provenance(
+        &self,
+        object_hash: &str,
+        provenance: &ObjectProvenance,
+    ) -> Result<(), ProtocolError> {
+        self.primary
+            .record_provenance(object_hash, provenance)
+            .await
+    }
+
+    async fn handle_pack_objects(
+        &self,
+        commits: Vec<Commit>,
+        trees: Vec<Tree>,
+        blobs: Vec<Blob>,
+        tags: Vec<Tag>,
+        provenance: Option<&ObjectProvenance>,
+    ) -> Result<(), ProtocolError> {
+        self.primary
+            .handle_pack_objects(commits, trees, blobs, tags, provenance)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::object::signature::{Signature, SignatureType};
+    use crate::internal::object::tree::{TreeItem, TreeItemMode};
+    use crate::protocol::memory::MemoryRepository;
+
+    #[tokio::test]
+    async fn test_alternates_repository() {
+        let pool = MemoryRepository::new();
+        let blob = Blob::from_content("shared");
+        let item = TreeItem::new(TreeItemMode::Blob, blob.id, "shared.txt".to_string());
+        let tree = Tree::from_tree_items(vec![item]).unwrap();
+        let signature = |kind| Signature::new(kind, "t".to_string(), "t@example.com".to_string());
+        let commit = Commit::new(
+            signature(SignatureType::Author),
+            signature(SignatureType::Committer),
+            tree.id,
+            vec![],
+            "\ninit",
+        );
+        pool.insert_object(&blob).unwrap();
+        pool.insert_object(&tree).unwrap();
+        pool.insert_object(&commit).unwrap();
+
+        let fork = MemoryRepository::new();
+        let repo =
+            AlternatesRepository::new(fork.clone(), vec![MemoryRepository::new(), pool.clone()]);
+        let main = commit.id.to_string();
+        assert!(repo.has_object(&main).await.unwrap());
+        assert!(!fork.has_object(&main).await.unwrap());
+        assert!(repo.commit_exists(&main).await.unwrap());
+        assert_eq!(repo.get_commit(&main).await.unwrap().tree_id, tree.id);
+        assert_eq!(repo.get_tree(&tree.id.to_string()).await.unwrap(), tree);
+        assert_eq!(
+            repo.get_object(&blob.id.to_string()).await.unwrap(),
+            b"shared"
+        );
+
+        // Missing everywhere
+        let missing = Blob::from_content("missing").id.to_string();
+        assert!(!repo.has_object(&missing).await.unwrap());
+        assert!(!repo.commit_exists(&missing).await.unwrap());
+        assert!(matches!(
+            repo.get_blob(&missing).await,
+            Err(ProtocolError::ObjectNotFound(_))
+        ));
+
+        // Writes and refs stay in the primary
+        let own = Blob::from_content("own");
+        repo.handle_pack_objects(vec![], vec![], vec![own.clone()], vec![], None)
+            .await
+            .unwrap();
+        repo.update_reference("refs/heads/main", None, &main)
+            .await
+            .unwrap();
+        assert!(fork.object(&own.id).is_some());
+        assert!(pool.object(&own.id).is_none());
+        assert_eq!(fork.get_ref("refs/heads/main"), Some(commit.id));
+        assert_eq!(pool.get_ref("refs/heads/main"), None);
+        assert_eq!(
+            repo.get_repository_refs().await.unwrap(),
+            fork.get_repository_refs().await.unwrap()
+        );
+        assert_eq!(repo.get_blob(&own.id.to_string()).await.unwrap(), own);
+    }
+}
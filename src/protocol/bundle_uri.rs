@@ -0,0 +1,197 @@
+//! The `bundle-uri` command of protocol v2, listing bundles clients download before fetching.
+//!
+//! A server advertising the `bundle-uri` capability answers the command with a
+//! [`BundleList`], one `key=value` pkt-line per setting in `git config` syntax: the
+//! `bundle.version` and `bundle.mode` of the list, then the `bundle.<id>.uri` of each bundle.
+//! Clients cloning with `--bundle-uri` or `transfer.bundleURI` download the bundles, usually
+//! from a CDN, unbundle them and only fetch what they miss from the server, which takes most of
+//! the initial clone traffic off the server for large repositories.
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use super::types::ProtocolError;
+
+/// Whether clients need every bundle of a list, or any one of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BundleListMode {
+    /// The bundles build on each other, e.g. a full bundle and incremental ones
+    #[default]
+    All,
+    /// Each bundle is complete, e.g. copies of the same bundle on several CDNs
+    Any,
+}
+
+impl Display for BundleListMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BundleListMode::All => write!(f, "all"),
+            BundleListMode::Any => write!(f, "any"),
+        }
+    }
+}
+
+impl FromStr for BundleListMode {
+    type Err = ProtocolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(BundleListMode::All),
+            "any" => Ok(BundleListMode::Any),
+            _ => Err(ProtocolError::invalid_request(&format!(
+                "Unknown bundle list mode '{s}'"
+            ))),
+        }
+    }
+}
+
+/// A bundle of a [`BundleList`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundleUri {
+    /// Names the bundle in the keys of the list
+    pub id: String,
+    /// Where clients download the bundle, absolute or relative to the URL of the repository
+    pub uri: String,
+    /// Orders the bundles with the `creationToken` heuristic, oldest first
+    pub creation_token: Option<u64>,
+}
+
+impl BundleUri {
+    /// A bundle `id` downloaded from `uri`, failing for ids other than alphanumeric characters
+    /// and `-`, and for URIs holding whitespace.
+    pub fn new(id: &str, uri: &str) -> Result<Self, ProtocolError> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(ProtocolError::invalid_request(&format!(
+                "Invalid bundle id '{id}'"
+            )));
+        }
+        if uri.is_empty() || uri.chars().any(char::is_whitespace) {
+            return Err(ProtocolError::invalid_request(&format!(
+                "Invalid bundle URI '{uri}'"
+            )));
+        }
+        Ok(Self {
+            id: id.to_string(),
+            uri: uri.to_string(),
+            creation_token: None,
+        })
+    }
+}
+
+/// The bundles listed by the `bundle-uri` command, see the [module documentation](self).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BundleList {
+    pub mode: BundleListMode,
+    /// Advertise `bundle.heuristic=creationToken`, letting clients that fetched bundles before
+    /// download only those with a greater creation token
+    pub creation_token_heuristic: bool,
+    pub bundles: Vec<BundleUri>,
+}
+
+impl BundleList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The `key=value` lines answering the command, without their newlines.
+    pub fn to_lines(&self) -> Vec<String> {
+        let mut lines = vec![
+            "bundle.version=1".to_string(),
+            format!("bundle.mode={}", self.mode),
+        ];
+        if self.creation_token_heuristic {
+            lines.push("bundle.heuristic=creationToken".to_string());
+        }
+        for bundle in &self.bundles {
+            lines.push(format!("bundle.{}.uri={}", bundle.id, bundle.uri));
+            if let Some(token) = bundle.creation_token {
+                lines.push(format!("bundle.{}.creationToken={token}", bundle.id));
+            }
+        }
+        lines
+    }
+
+    /// Parse the lines of a `bundle-uri` response, as a client reads them. Unknown keys are
+    /// ignored, like git.
+    pub fn parse<'a>(lines: impl IntoIterator<Item = &'a str>) -> Result<Self, ProtocolError> {
+        let mut list = Self::new();
+        for line in lines {
+            let line = line.strip_suffix('\n').unwrap_or(line);
+            let (key, value) = line.split_once('=').ok_or_else(|| {
+                ProtocolError::invalid_request(&format!("Invalid bundle list line '{line}'"))
+            })?;
+            let Some(key) = key.strip_prefix("bundle.") else {
+                continue;
+            };
+            match key {
+                "version" if value != "1" => {
+                    return Err(ProtocolError::invalid_request(&format!(
+                        "Unsupported bundle list version {value}"
+                    )));
+                }
+                "mode" => list.mode = value.parse()?,
+                "heuristic" => list.creation_token_heuristic = value == "creationToken",
+                _ => {
+                    let Some((id, field)) = key.rsplit_once('.') else {
+                        continue;
+                    };
+                    let position = list.bundles.iter().position(|bundle| bundle.id == id);
+                    let bundle = match position {
+                        Some(i) => &mut list.bundles[i],
+                        None => {
+                            list.bundles.push(BundleUri {
+                                id: id.to_string(),
+                                uri: String::new(),
+                                creation_token: None,
+                            });
+                            list.bundles.last_mut().unwrap()
+                        }
+                    };
+                    match field {
+                        "uri" => bundle.uri = value.to_string(),
+                        "creationToken" => bundle.creation_token = value.parse().ok(),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        list.bundles.retain(|bundle| !bundle.uri.is_empty());
+        Ok(list)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bundle_list() {
+        let mut full = BundleUri::new("full", "https://cdn.example.com/full.bundle").unwrap();
+        full.creation_token = Some(1);
+        let mut list = BundleList::new();
+        list.creation_token_heuristic = true;
+        list.bundles.push(full);
+        list.bundles
+            .push(BundleUri::new("daily-2", "bundles/daily-2.bundle").unwrap());
+        let lines = list.to_lines();
+        assert_eq!(
+            lines,
+            [
+                "bundle.version=1",
+                "bundle.mode=all",
+                "bundle.heuristic=creationToken",
+                "bundle.full.uri=https://cdn.example.com/full.bundle",
+                "bundle.full.creationToken=1",
+                "bundle.daily-2.uri=bundles/daily-2.bundle",
+            ]
+        );
+        assert_eq!(
+            BundleList::parse(lines.iter().map(String::as_str)).unwrap(),
+            list
+        );
+        assert!(BundleList::parse(["bundle.version=2"]).is_err());
+
+        for (id, uri) in [("a.b", "x"), ("", "x"), ("a", "has space"), ("a", "")] {
+            assert!(BundleUri::new(id, uri).is_err(), "{id} {uri}");
+        }
+    }
+}
@@ -0,0 +1,304 @@
+//! A [`tokio_util::codec`] for pkt-lines.
+//!
+//! [`PktLineCodec`] decodes and encodes [`PktLine`]s, so an SSH channel or a TCP socket can be
+//! wrapped in a [`Framed`](tokio_util::codec::Framed), or read with a
+//! [`FramedRead`](tokio_util::codec::FramedRead), instead of buffering the request into
+//! [`Bytes`](bytes::Bytes) by hand. Malformed input fails with the same [`PktLineError`]s as
+//! [`parse_pkt_line`](super::utils::parse_pkt_line).
+//!
+//! Transports that hand over the request as a [`ProtocolStream`] of chunks, e.g. an HTTP/2
+//! body, read it with a [`PktLineReader`], a `Stream` of pkt-lines that gives back the bytes
+//! following them, such as the pack of a push. Replies are written to an `AsyncWrite` through
+//! a [`PktLineWriter`] sink, or turned into a [`ProtocolStream`] with [`encode_pkt_lines`].
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+use std::time::SystemTime;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use tokio_util::codec::{Decoder, Encoder, FramedWrite};
+
+use super::trace::{PacketDirection, PacketTracer};
+use super::types::{ProtocolError, ProtocolStream};
+use super::utils::{
+    MAX_PKT_LINE_LENGTH, PktLine, PktLineError, parse_pkt_length, pkt_length_prefix,
+};
+
+/// Frames a byte stream into pkt-lines, see the [module documentation](self).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PktLineCodec;
+
+impl PktLineCodec {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Decoder for PktLineCodec {
+    type Item = PktLine;
+    type Error = ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<PktLine>, ProtocolError> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let pkt_length = parse_pkt_length(&src[..4])?;
+        let special = match pkt_length {
+            0 => PktLine::Flush,
+            1 => PktLine::Delim,
+            2 => PktLine::ResponseEnd,
+            3 => return Err(PktLineError::BadLength(format!("{pkt_length:04x}")).into()),
+            _ if pkt_length > MAX_PKT_LINE_LENGTH => {
+                return Err(PktLineError::Oversize(pkt_length).into());
+            }
+            _ if src.len() < pkt_length => {
+                src.reserve(pkt_length - src.len());
+                return Ok(None);
+            }
+            _ => {
+                src.advance(4);
+                return Ok(Some(PktLine::Data(src.split_to(pkt_length - 4).freeze())));
+            }
+        };
+        src.advance(4);
+        Ok(Some(special))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<PktLine>, ProtocolError> {
+        match self.decode(src)? {
+            Some(pkt_line) => Ok(Some(pkt_line)),
+            None if src.is_empty() => Ok(None),
+            // The stream ends within a pkt-line
+            None => {
+                let expected = parse_pkt_length(&src[..src.len().min(4)]).unwrap_or(4);
+                Err(PktLineError::Truncated {
+                    expected,
+                    available: src.len(),
+                }
+                .into())
+            }
+        }
+    }
+}
+
+impl Encoder<PktLine> for PktLineCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, pkt_line: PktLine, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+        match pkt_line {
+            PktLine::Flush => dst.put_slice(b"0000"),
+            PktLine::Delim => dst.put_slice(b"0001"),
+            PktLine::ResponseEnd => dst.put_slice(b"0002"),
+            PktLine::Data(data) => {
+                let pkt_length = data.len() + 4;
+                if pkt_length > MAX_PKT_LINE_LENGTH {
+                    return Err(PktLineError::Oversize(pkt_length).into());
+                }
+                dst.reserve(pkt_length);
+                dst.put_slice(&pkt_length_prefix(pkt_length));
+                dst.put_slice(&data);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A `Sink` of pkt-lines writing to `W`, created with `PktLineWriter::new(writer, PktLineCodec)`.
+pub type PktLineWriter<W> = FramedWrite<W, PktLineCodec>;
+
+/// The pkt-lines of a stream of chunks, see the [module documentation](self).
+///
+/// Only the incomplete pkt-line at the end of what was received is buffered.
+pub struct PktLineReader<S> {
+    inner: S,
+    buf: BytesMut,
+    eof: bool,
+    tracer: Option<Arc<dyn PacketTracer>>,
+}
+
+impl<S> PktLineReader<S>
+where
+    S: Stream<Item = Result<Bytes, ProtocolError>> + Unpin,
+{
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            buf: BytesMut::new(),
+            eof: false,
+            tracer: None,
+        }
+    }
+
+    /// Hand every pkt-line read to `tracer`, as an inbound packet.
+    pub fn set_packet_tracer(&mut self, tracer: Option<Arc<dyn PacketTracer>>) {
+        self.tracer = tracer;
+    }
+
+    /// The bytes received after the pkt-lines read so far.
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Receive until at least `len` bytes are buffered or the stream ends, e.g. to look for a
+    /// pack before reading any pkt-line.
+    pub async fn fill_buf(&mut self, len: usize) -> Result<&[u8], ProtocolError> {
+        while self.buf.len() < len && !self.eof {
+            match self.inner.next().await {
+                Some(chunk) => self.buf.extend_from_slice(&chunk?),
+                None => self.eof = true,
+            }
+        }
+        Ok(&self.buf)
+    }
+
+    /// The bytes received after the pkt-lines read so far, and the rest of the stream.
+    pub fn into_parts(self) -> (BytesMut, S) {
+        (self.buf, self.inner)
+    }
+}
+
+impl<S> Stream for PktLineReader<S>
+where
+    S: Stream<Item = Result<Bytes, ProtocolError>> + Unpin,
+{
+    type Item = Result<PktLine, ProtocolError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            let pkt_line = if this.eof {
+                PktLineCodec.decode_eof(&mut this.buf).transpose()
+            } else {
+                PktLineCodec.decode(&mut this.buf).transpose()
+            };
+            if let Some(Ok(pkt_line)) = &pkt_line
+                && let Some(tracer) = &this.tracer
+            {
+                let mut packet = BytesMut::new();
+                // Decoded lines always fit in a pkt-line
+                let _ = PktLineCodec.encode(pkt_line.clone(), &mut packet);
+                tracer.trace_packet(PacketDirection::Inbound, &packet, SystemTime::now());
+            }
+            if pkt_line.is_some() || this.eof {
+                return Poll::Ready(pkt_line);
+            }
+            match ready!(this.inner.poll_next_unpin(cx)) {
+                Some(Ok(chunk)) => this.buf.extend_from_slice(&chunk),
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => this.eof = true,
+            }
+        }
+    }
+}
+
+/// Encode `pkt_lines` into a [`ProtocolStream`], a chunk per pkt-line.
+pub fn encode_pkt_lines<S>(pkt_lines: S) -> ProtocolStream
+where
+    S: Stream<Item = Result<PktLine, ProtocolError>> + Send + 'static,
+{
+    Box::pin(pkt_lines.map(|pkt_line| {
+        let mut buf = BytesMut::new();
+        PktLineCodec.encode(pkt_line?, &mut buf)?;
+        Ok(buf.freeze())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::{SinkExt, StreamExt};
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    use super::*;
+    use crate::protocol::trace::tests::RecordingTracer;
+
+    #[tokio::test]
+    async fn test_pkt_line_codec() {
+        let lines = vec![
+            PktLine::Data(Bytes::from_static(b"command=ls-refs\n")),
+            PktLine::Delim,
+            PktLine::Data(Bytes::from_static(b"peel\n")),
+            PktLine::Flush,
+            PktLine::ResponseEnd,
+        ];
+        let mut encoded = Vec::new();
+        let mut sink = FramedWrite::new(&mut encoded, PktLineCodec::new());
+        for line in lines.clone() {
+            sink.send(line).await.unwrap();
+        }
+        assert_eq!(encoded, b"0014command=ls-refs\n00010009peel\n00000002");
+
+        // Lines split across reads
+        let (client, mut server) = tokio::io::duplex(3);
+        let writer = tokio::spawn(async move {
+            tokio::io::AsyncWriteExt::write_all(&mut server, &encoded)
+                .await
+                .unwrap();
+        });
+        let decoded: Vec<PktLine> = FramedRead::new(client, PktLineCodec::new())
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        writer.await.unwrap();
+        assert_eq!(decoded, lines);
+
+        let mut frames = FramedRead::new(&b"0009want\n000ahave"[..], PktLineCodec::new());
+        assert!(matches!(frames.next().await, Some(Ok(PktLine::Data(_)))));
+        let err = frames.next().await.unwrap().unwrap_err();
+        assert!(
+            err.to_string().contains("10 bytes announced, 8 available"),
+            "{err}"
+        );
+
+        let mut frames = FramedRead::new(&b"00zz"[..], PktLineCodec::new());
+        assert!(matches!(
+            frames.next().await,
+            Some(Err(ProtocolError::InvalidRequest(_)))
+        ));
+        let mut too_long = BytesMut::new();
+        let payload = Bytes::from(vec![b'x'; MAX_PKT_LINE_LENGTH]);
+        assert!(
+            PktLineCodec
+                .encode(PktLine::Data(payload), &mut too_long)
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pkt_line_reader() {
+        let chunks = ["0009", "want\n00", "00PA", "CK\0\0"];
+        let stream = futures::stream::iter(chunks.map(|chunk| Ok(Bytes::from(chunk))));
+        let mut reader = PktLineReader::new(stream);
+        let tracer = Arc::new(RecordingTracer::default());
+        reader.set_packet_tracer(Some(tracer.clone()));
+        assert_eq!(reader.fill_buf(6).await.unwrap(), b"0009want\n00");
+        assert_eq!(
+            reader.next().await.unwrap().unwrap(),
+            PktLine::Data(Bytes::from_static(b"want\n"))
+        );
+        assert_eq!(reader.next().await.unwrap().unwrap(), PktLine::Flush);
+        assert_eq!(reader.buffer(), b"PA");
+        let traced: Vec<String> = tracer
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, p)| p.clone())
+            .collect();
+        assert_eq!(traced, ["0009want\n", "0000"]);
+        let (buf, rest) = reader.into_parts();
+        let rest: Vec<_> = rest.map(Result::unwrap).collect().await;
+        assert_eq!(
+            (&buf[..], &rest[..]),
+            (&b"PA"[..], &[Bytes::from("CK\0\0")][..])
+        );
+
+        let lines = futures::stream::iter([
+            Ok(PktLine::Data(Bytes::from_static(b"ok refs/heads/main\n"))),
+            Ok(PktLine::Flush),
+        ]);
+        let chunks: Vec<_> = encode_pkt_lines(lines).map(Result::unwrap).collect().await;
+        assert_eq!(chunks, ["0017ok refs/heads/main\n", "0000"]);
+    }
+}
@@ -4,16 +4,26 @@
 //! that form the core interface of the git-internal library.
 use std::str::FromStr;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
 
 use bytes::Bytes;
 use async_trait::async_trait;
 use futures::stream::StreamExt;
 
 use crate::hash::SHA1;
+use crate::internal::commit_graph::CommitGraph;
+use crate::internal::pack::bitmap::PackBitmap;
+use crate::internal::pack::cruft::CruftPack;
+use crate::internal::pack::encode::PackedDelta;
 use crate::internal::object::ObjectTrait;
+use crate::internal::object::types::ObjectType;
 
 use crate::protocol::smart::SmartProtocol;
-use crate::protocol::types::{ProtocolError, ProtocolStream, ServiceType};
+use crate::protocol::upload_archive::ArchiveRequest;
+use crate::protocol::types::{
+    ObjectProvenance, PackInfo, ProtocolError, ProtocolStream, RefUpdate, ReflogEntry, ServiceType,
+};
 
 /// Repository access trait for storage operations
 ///
@@ -33,6 +43,164 @@ pub trait RepositoryAccess: Send + Sync + Clone {
     /// Store pack data in the repository
     async fn store_pack_data(&self, pack_data: &[u8]) -> Result<(), ProtocolError>;
 
+    /// Store a received pack as-is together with its `.idx`, version 2 unless
+    /// `UnpackOptions::idx_version` says otherwise
+    ///
+    /// Called by `PackGenerator::index_pack` without a keep token, and by the default
+    /// store_kept_pack; receive-pack stores a push this way when `SmartProtocol::write_pack_index`
+    /// is set, before its objects are handed to `handle_pack_objects`. Default implementation
+    /// does nothing; override it to keep `pack-<hash>.pack` and `pack-<hash>.idx` and serve
+    /// random access reads from them.
+    /// A thin pack is stored as received, so its deltas may need bases from other packs.
+    async fn store_pack_index(
+        &self,
+        _pack_data: &[u8],
+        _idx_data: &[u8],
+    ) -> Result<(), ProtocolError> {
+        Ok(())
+    }
+
+    /// Store a received pack with its `.idx` and mark it as kept by the token `keep`
+    ///
+    /// Called by `PackGenerator::index_pack` instead of store_pack_index when a keep token is
+    /// given, as receive-pack does while the references of a push are updated: like
+    /// `pack-<hash>.keep`, the mark stops a concurrent gc from repacking or pruning the objects
+    /// no reference points to yet. Default implementation calls store_pack_index without
+    /// keeping the pack; override it to also write the `.keep` file holding `keep`.
+    async fn store_kept_pack(
+        &self,
+        pack_data: &[u8],
+        idx_data: &[u8],
+        _keep: &str,
+    ) -> Result<(), ProtocolError> {
+        self.store_pack_index(pack_data, idx_data).await
+    }
+
+    /// Remove the mark left by store_kept_pack on the pack `pack_hash`, if it still holds `keep`
+    ///
+    /// Default implementation does nothing.
+    async fn release_pack_keep(&self, _pack_hash: &SHA1, _keep: &str) -> Result<(), ProtocolError> {
+        Ok(())
+    }
+
+    /// List the packs of the repository
+    ///
+    /// `maintenance::repack` replaces the packs listed here that are not kept. Default
+    /// implementation returns none, so a repack only adds a pack.
+    async fn list_packs(&self) -> Result<Vec<PackInfo>, ProtocolError> {
+        Ok(Vec::new())
+    }
+
+    /// Get the `.idx` of the pack `pack_hash`, if the repository has it
+    ///
+    /// The multi-pack-index written by `maintenance::repack` covers the kept packs through it.
+    /// Default implementation returns None.
+    async fn get_pack_index(&self, _pack_hash: &SHA1) -> Result<Option<Vec<u8>>, ProtocolError> {
+        Ok(None)
+    }
+
+    /// Store a cruft pack of unreachable objects with its `.idx` and `.mtimes`
+    ///
+    /// Called by `maintenance::repack` with `RepackOptions::cruft_expire`. Default
+    /// implementation stores the pack with store_pack_index and drops the times, so the next
+    /// sweep takes its objects for new ones; override it and get_pack_mtimes to let them expire.
+    async fn store_cruft_pack(&self, cruft: &CruftPack) -> Result<(), ProtocolError> {
+        self.store_pack_index(&cruft.pack, &cruft.idx).await
+    }
+
+    /// Get the `.mtimes` of the cruft pack `pack_hash`, if the repository has it
+    ///
+    /// Default implementation returns None.
+    async fn get_pack_mtimes(&self, _pack_hash: &SHA1) -> Result<Option<Vec<u8>>, ProtocolError> {
+        Ok(None)
+    }
+
+    /// Get the `.pack` of the pack `pack_hash`, if the repository has it
+    ///
+    /// Dumb HTTP serves it, with its `.idx`, to the clients of the packs list_packs returns.
+    /// Default implementation returns None.
+    async fn get_pack_data(&self, _pack_hash: &SHA1) -> Result<Option<Vec<u8>>, ProtocolError> {
+        Ok(None)
+    }
+
+    /// Store the `.bitmap` of the pack `pack_hash`
+    ///
+    /// Default implementation does nothing; override it to serve the bitmap from get_pack_bitmap.
+    async fn store_pack_bitmap(
+        &self,
+        _pack_hash: &SHA1,
+        _bitmap_data: &[u8],
+    ) -> Result<(), ProtocolError> {
+        Ok(())
+    }
+
+    /// Store the multi-pack-index of the repository, replacing the previous one
+    ///
+    /// Default implementation does nothing.
+    async fn store_multi_pack_index(&self, _midx_data: &[u8]) -> Result<(), ProtocolError> {
+        Ok(())
+    }
+
+    /// Store the commit-graph of the repository, replacing the previous one
+    ///
+    /// Called by `maintenance::write_commit_graph`. Default implementation does nothing; override
+    /// it to serve the graph from get_commit_graph, e.g. written with `CommitGraph::write_to`.
+    async fn store_commit_graph(&self, _graph_data: &[u8]) -> Result<(), ProtocolError> {
+        Ok(())
+    }
+
+    /// Move the references into the packed storage of the repository, like
+    /// `git pack-refs --all`, and return how many were packed
+    ///
+    /// Called by `maintenance::pack_refs` with the object each annotated tag a reference points
+    /// to peels to. Default implementation does nothing; a repository keeping its references in
+    /// a `RefStore` calls `RefStore::pack_refs` with `|id| Ok(peeled.get(id).copied())`.
+    async fn pack_refs(&self, _peeled: &HashMap<SHA1, SHA1>) -> Result<usize, ProtocolError> {
+        Ok(0)
+    }
+
+    /// List the loose objects of the repository with the time they were last written
+    ///
+    /// `maintenance::prune` removes the old ones no reference reaches. Default implementation
+    /// returns none, so nothing is pruned.
+    async fn list_loose_objects(&self) -> Result<Vec<(SHA1, SystemTime)>, ProtocolError> {
+        Ok(Vec::new())
+    }
+
+    /// Remove the packs replaced by a repack and the loose copies of the objects it packed
+    ///
+    /// Called by `maintenance::repack` once the new pack is stored, with the packs of list_packs
+    /// that are not kept and the objects of the new pack, and by `maintenance::prune` with the
+    /// loose objects it expires and no pack. Default implementation does nothing.
+    async fn remove_repacked(
+        &self,
+        _packs: &[SHA1],
+        _objects: &[SHA1],
+    ) -> Result<(), ProtocolError> {
+        Ok(())
+    }
+
+    /// Store a large object of a received pack while its content is inflated
+    ///
+    /// Called instead of `handle_pack_objects` for the whole blobs larger than
+    /// `UnpackOptions::large_object_threshold`, with their type, size and content. Returns the
+    /// id of the stored object, which must be its object id. Default implementation collects
+    /// the content and stores it with store_pack_data; override it to write the content as it
+    /// arrives.
+    async fn store_object_stream(
+        &self,
+        object_type: ObjectType,
+        _size: usize,
+        mut data: ProtocolStream,
+    ) -> Result<SHA1, ProtocolError> {
+        let mut content = Vec::new();
+        while let Some(chunk) = data.next().await {
+            content.extend_from_slice(&chunk?);
+        }
+        self.store_pack_data(&content).await?;
+        Ok(SHA1::from_type_and_data(object_type, &content))
+    }
+
     /// Update a single reference
     async fn update_reference(
         &self,
@@ -41,6 +209,97 @@ pub trait RepositoryAccess: Send + Sync + Clone {
         new_hash: &str,
     ) -> Result<(), ProtocolError>;
 
+    /// Update a batch of references
+    ///
+    /// Returns one result per update, in the same order. Receive-pack hands over the
+    /// commands of a push in batches, so a mirror push touching tens of thousands of refs
+    /// costs a few calls instead of one per ref.
+    /// Default implementation calls update_reference for each update; override it to
+    /// write a whole batch at once (e.g. in a single transaction).
+    async fn update_references(
+        &self,
+        updates: &[RefUpdate],
+    ) -> Result<Vec<Result<(), ProtocolError>>, ProtocolError> {
+        let mut results = Vec::with_capacity(updates.len());
+        for update in updates {
+            results.push(
+                self.update_reference(
+                    &update.ref_name,
+                    update.old_hash.as_deref(),
+                    &update.new_hash,
+                )
+                .await,
+            );
+        }
+        Ok(results)
+    }
+
+    /// Get the reflog of a reference, newest entry first
+    ///
+    /// Default implementation returns an empty reflog; override it if the host records one.
+    async fn get_reflog(&self, _ref_name: &str) -> Result<Vec<ReflogEntry>, ProtocolError> {
+        Ok(Vec::new())
+    }
+
+    /// Get the target of a symbolic reference, e.g. `refs/heads/main` for `HEAD`
+    ///
+    /// Returns None when the reference isn't symbolic or doesn't exist. The target of `HEAD` is
+    /// the default branch, which info refs advertises as `symref=HEAD:<target>` so clones check
+    /// it out.
+    /// Default implementation returns None; override it if the host stores symbolic refs.
+    async fn get_symbolic_ref(&self, _ref_name: &str) -> Result<Option<String>, ProtocolError> {
+        Ok(None)
+    }
+
+    /// Get the default branch of the repository, e.g. `refs/heads/main`
+    ///
+    /// Info refs advertises it as `HEAD`, ahead of a `HEAD` reported by get_repository_refs and
+    /// of the protocol's head fallbacks. Default implementation returns the target of the
+    /// symbolic ref `HEAD`; override it if the host keeps the default branch elsewhere, e.g. in
+    /// the settings of the repository.
+    async fn default_branch(&self) -> Result<Option<String>, ProtocolError> {
+        self.get_symbolic_ref("HEAD").await
+    }
+
+    /// Make a reference symbolic, pointing to `target`, e.g. `HEAD` to a new default branch
+    ///
+    /// Default implementation rejects the update; override it if the host stores symbolic refs.
+    async fn set_symbolic_ref(&self, ref_name: &str, _target: &str) -> Result<(), ProtocolError> {
+        Err(ProtocolError::invalid_request(&format!(
+            "{ref_name}: symbolic references are not supported"
+        )))
+    }
+
+    /// Get the commit-graph of the repository, if it keeps one
+    ///
+    /// History walks (`CommitWalker`, merge bases) read parents and generation numbers from it
+    /// instead of loading every commit. Default implementation returns None; override it to
+    /// serve `objects/info/commit-graph`, e.g. with `CommitGraph::open`.
+    async fn get_commit_graph(&self) -> Result<Option<Arc<CommitGraph>>, ProtocolError> {
+        Ok(None)
+    }
+
+    /// Get the reachability bitmaps of a pack holding the repository's history, if it keeps one
+    ///
+    /// Full packs for clones take the wanted objects from the bitmaps when every wanted tip has
+    /// one, instead of walking commits and trees. Default implementation returns None; override
+    /// it to serve a `.bitmap` file, e.g. with `PackBitmap::open`.
+    async fn get_pack_bitmap(&self) -> Result<Option<Arc<PackBitmap>>, ProtocolError> {
+        Ok(None)
+    }
+
+    /// Get the deltas the given objects are stored as in the repository's packs
+    ///
+    /// Generated packs copy a delta as-is when its base is in the pack too, instead of searching
+    /// for a delta and compressing it again. Default implementation returns none; override it to
+    /// serve them from pack files, e.g. with `PackIndex::packed_deltas`.
+    async fn get_packed_deltas(
+        &self,
+        _object_hashes: &[String],
+    ) -> Result<HashMap<SHA1, PackedDelta>, ProtocolError> {
+        Ok(HashMap::new())
+    }
+
     /// Get objects needed for pack generation
     async fn get_objects_for_pack(
         &self,
@@ -64,10 +323,10 @@ pub trait RepositoryAccess: Send + Sync + Clone {
     ) -> Result<crate::internal::object::blob::Blob, ProtocolError> {
         let data = self.get_object(object_hash).await?;
         let hash = SHA1::from_str(object_hash)
-            .map_err(|e| ProtocolError::repository_error(format!("Invalid hash format: {}", e)))?;
+            .map_err(|e| ProtocolError::invalid_request(&format!("Invalid hash format: {}", e)))?;
 
         crate::internal::object::blob::Blob::from_bytes(&data, hash)
-            .map_err(|e| ProtocolError::repository_error(format!("Failed to parse blob: {}", e)))
+            .map_err(|e| ProtocolError::corrupt_object(hash, e))
     }
 
     /// Get commit data by hash
@@ -80,10 +339,10 @@ pub trait RepositoryAccess: Send + Sync + Clone {
     ) -> Result<crate::internal::object::commit::Commit, ProtocolError> {
         let data = self.get_object(commit_hash).await?;
         let hash = SHA1::from_str(commit_hash)
-            .map_err(|e| ProtocolError::repository_error(format!("Invalid hash format: {}", e)))?;
+            .map_err(|e| ProtocolError::invalid_request(&format!("Invalid hash format: {}", e)))?;
 
         crate::internal::object::commit::Commit::from_bytes(&data, hash)
-            .map_err(|e| ProtocolError::repository_error(format!("Failed to parse commit: {}", e)))
+            .map_err(|e| ProtocolError::corrupt_object(hash, e))
     }
 
     /// Get tree data by hash
@@ -96,10 +355,43 @@ pub trait RepositoryAccess: Send + Sync + Clone {
     ) -> Result<crate::internal::object::tree::Tree, ProtocolError> {
         let data = self.get_object(tree_hash).await?;
         let hash = SHA1::from_str(tree_hash)
-            .map_err(|e| ProtocolError::repository_error(format!("Invalid hash format: {}", e)))?;
+            .map_err(|e| ProtocolError::invalid_request(&format!("Invalid hash format: {}", e)))?;
 
         crate::internal::object::tree::Tree::from_bytes(&data, hash)
-            .map_err(|e| ProtocolError::repository_error(format!("Failed to parse tree: {}", e)))
+            .map_err(|e| ProtocolError::corrupt_object(hash, e))
+    }
+
+    /// Get annotated tag data by hash
+    ///
+    /// Default implementation parses the object data using the internal object module.
+    /// Override this method if you need custom tag handling logic.
+    async fn get_tag(
+        &self,
+        tag_hash: &str,
+    ) -> Result<crate::internal::object::tag::Tag, ProtocolError> {
+        let data = self.get_object(tag_hash).await?;
+        let hash = SHA1::from_str(tag_hash)
+            .map_err(|e| ProtocolError::invalid_request(&format!("Invalid hash format: {}", e)))?;
+
+        crate::internal::object::tag::Tag::from_bytes(&data, hash)
+            .map_err(|e| ProtocolError::corrupt_object(hash, e))
+    }
+
+    /// Write the archive of the tree-ish `id`, a commit, tag or tree, for upload-archive
+    ///
+    /// `request` holds the format, prefix, compression level and paths the client asked for;
+    /// `id` is the tree-ish it names, which the client may see. Default implementation
+    /// generates it with [`archive`](crate::archive::archive); override it to serve cached
+    /// archives or refuse the request.
+    async fn write_archive(
+        &self,
+        id: &SHA1,
+        request: &ArchiveRequest,
+    ) -> Result<ProtocolStream, ProtocolError>
+    where
+        Self: 'static,
+    {
+        crate::archive::archive(self, id, &request.options).await
     }
 
     /// Check if a commit exists
@@ -123,15 +415,32 @@ pub trait RepositoryAccess: Send + Sync + Clone {
         }
     }
 
+    /// Record who introduced an object
+    ///
+    /// Called by the default `handle_pack_objects` after each object is stored.
+    /// Default implementation discards the provenance.
+    async fn record_provenance(
+        &self,
+        _object_hash: &str,
+        _provenance: &ObjectProvenance,
+    ) -> Result<(), ProtocolError> {
+        Ok(())
+    }
+
     /// Handle pack objects after unpacking
     ///
-    /// Default implementation stores each object individually using store_pack_data.
+    /// Receive-pack and fetch call it several times per pack, with batches of the objects as they
+    /// are unpacked, see `PackGenerator::unpack_received_in_batches`.
+    /// Default implementation stores each object individually using store_pack_data,
+    /// then hands `provenance` (when given) to record_provenance for that object.
     /// Override this method if you need batch processing or custom storage logic.
     async fn handle_pack_objects(
         &self,
         commits: Vec<crate::internal::object::commit::Commit>,
         trees: Vec<crate::internal::object::tree::Tree>,
         blobs: Vec<crate::internal::object::blob::Blob>,
+        tags: Vec<crate::internal::object::tag::Tag>,
+        provenance: Option<&ObjectProvenance>,
     ) -> Result<(), ProtocolError> {
         // Store blobs
         for blob in blobs {
@@ -141,6 +450,10 @@ pub trait RepositoryAccess: Send + Sync + Clone {
             self.store_pack_data(&data).await.map_err(|e| {
                 ProtocolError::repository_error(format!("Failed to store blob {}: {}", blob.id, e))
             })?;
+            if let Some(provenance) = provenance {
+                self.record_provenance(&blob.id.to_string(), provenance)
+                    .await?;
+            }
         }
 
         // Store trees
@@ -151,6 +464,10 @@ pub trait RepositoryAccess: Send + Sync + Clone {
             self.store_pack_data(&data).await.map_err(|e| {
                 ProtocolError::repository_error(format!("Failed to store tree {}: {}", tree.id, e))
             })?;
+            if let Some(provenance) = provenance {
+                self.record_provenance(&tree.id.to_string(), provenance)
+                    .await?;
+            }
         }
 
         // Store commits
@@ -164,6 +481,24 @@ pub trait RepositoryAccess: Send + Sync + Clone {
                     commit.id, e
                 ))
             })?;
+            if let Some(provenance) = provenance {
+                self.record_provenance(&commit.id.to_string(), provenance)
+                    .await?;
+            }
+        }
+
+        // Store annotated tags last, they point at the objects above
+        for tag in tags {
+            let data = tag.to_data().map_err(|e| {
+                ProtocolError::repository_error(format!("Failed to serialize tag: {}", e))
+            })?;
+            self.store_pack_data(&data).await.map_err(|e| {
+                ProtocolError::repository_error(format!("Failed to store tag {}: {}", tag.id, e))
+            })?;
+            if let Some(provenance) = provenance {
+                self.record_provenance(&tag.id.to_string(), provenance)
+                    .await?;
+            }
         }
 
         Ok(())
@@ -187,6 +522,33 @@ pub trait AuthenticationService: Send + Sync {
     ) -> Result<(), ProtocolError>;
 }
 
+/// The change a push command makes to a reference
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefOperation {
+    Create,
+    /// A fast-forward of the reference
+    Update,
+    Delete,
+    /// An update to a commit that doesn't descend from the old tip
+    Force,
+}
+
+/// Authorization service trait
+///
+/// Consulted by receive-pack for every reference a push updates, after the user is
+/// authenticated, so hosts can grant write access per branch or tag namespace.
+#[async_trait]
+pub trait AuthorizationService: Send + Sync {
+    /// Allow `user` (the pusher, `None` when anonymous) to apply `operation` to `ref_name`,
+    /// or reject that command with the returned error
+    async fn authorize_ref_update(
+        &self,
+        user: Option<&str>,
+        ref_name: &str,
+        operation: RefOperation,
+    ) -> Result<(), ProtocolError>;
+}
+
 /// Transport-agnostic Git smart protocol handler
 /// Main Git protocol handler
 ///
@@ -233,6 +595,35 @@ impl<R: RepositoryAccess, A: AuthenticationService> GitProtocol<R, A> {
         self.smart_protocol.set_transport_protocol(protocol);
     }
 
+    /// Set the authenticated user, recorded as the pusher of received objects
+    pub fn set_pusher(&mut self, pusher: &str) {
+        self.smart_protocol.set_pusher(pusher);
+    }
+
+    /// Authorize each reference update of a push with `authorization`
+    pub fn set_authorization_service(&mut self, authorization: Arc<dyn AuthorizationService>) {
+        self.smart_protocol.set_authorization_service(authorization);
+    }
+
+    /// Select the protocol version of the session from the transport hints, e.g. the
+    /// `Git-Protocol` header; see [`SmartProtocol::negotiate_protocol_version`]
+    pub fn negotiate_protocol_version(
+        &mut self,
+        git_protocol: Option<&str>,
+    ) -> super::types::ProtocolVersion {
+        self.smart_protocol.negotiate_protocol_version(git_protocol)
+    }
+
+    /// The protocol version selected for the session, version 0 unless negotiated
+    pub fn protocol_version(&self) -> super::types::ProtocolVersion {
+        self.smart_protocol.protocol_version
+    }
+
+    /// Hand every pkt-line exchanged with the client to `tracer`, like `GIT_TRACE_PACKET`
+    pub fn set_packet_tracer(&mut self, tracer: Arc<dyn super::trace::PacketTracer>) {
+        self.smart_protocol.set_packet_tracer(tracer);
+    }
+
     /// Handle git info-refs request
     pub async fn info_refs(&self, service: &str) -> Result<Vec<u8>, ProtocolError> {
         let service_type = match service {
@@ -245,26 +636,33 @@ impl<R: RepositoryAccess, A: AuthenticationService> GitProtocol<R, A> {
         Ok(bytes.to_vec())
     }
 
-    /// Handle git-upload-pack request (for clone/fetch)
+    /// Handle git-upload-pack request (for clone/fetch), a single command in protocol v2
     pub async fn upload_pack(
         &mut self,
         request_data: &[u8],
     ) -> Result<ProtocolStream, ProtocolError> {
         let request_bytes = bytes::Bytes::from(request_data.to_vec());
+        if self.smart_protocol.protocol_version == super::types::ProtocolVersion::V2 {
+            let request: ProtocolStream = Box::pin(futures::stream::iter([Ok(request_bytes)]));
+            let mut request = super::codec::PktLineReader::new(request);
+            return self.smart_protocol.git_v2_command(&mut request).await;
+        }
         let (stream, _) = self.smart_protocol.git_upload_pack(request_bytes).await?;
         Ok(Box::pin(stream.map(|data| Ok(Bytes::from(data)))))
     }
 
     /// Handle git-receive-pack request (for push)
+    ///
+    /// The status report is streamed back while the references are updated.
     pub async fn receive_pack(
         &mut self,
         request_stream: ProtocolStream,
-    ) -> Result<ProtocolStream, ProtocolError> {
-        let result_bytes = self
-            .smart_protocol
-            .git_receive_pack_stream(request_stream)
-            .await?;
-        // Return the report status as a single-chunk stream
-        Ok(Box::pin(futures::stream::once(async { Ok(result_bytes) })))
+    ) -> Result<ProtocolStream, ProtocolError>
+    where
+        R: 'static,
+    {
+        self.smart_protocol
+            .git_receive_pack_incremental(request_stream)
+            .await
     }
 }
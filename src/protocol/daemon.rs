@@ -0,0 +1,354 @@
+//! The native `git://` transport of `git daemon`.
+//!
+//! A client connects over TCP, port [`DEFAULT_PORT`] by default, and sends a single pkt-line
+//! naming the service and the repository, e.g. `git-upload-pack /project.git\0host=example.com\0`,
+//! optionally followed by extra parameters like `\0version=1\0`; the connection then carries the
+//! pkt-lines of the service, like the channel of an SSH command. [`DaemonRequest`] parses that
+//! line, and [`GitDaemon`] serves connections with the services it enables: upload-pack
+//! only by default, the transport being unauthenticated, like git's `daemon.receivepack` and
+//! `daemon.uploadarch`.
+//!
+//! Failures before the service starts are answered with an `ERR` pkt-line, which git prints as
+//! `remote error`.
+use std::future::Future;
+
+use futures::StreamExt;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+
+use super::codec::PktLineReader;
+use super::core::{AuthenticationService, RepositoryAccess};
+use super::smart::SmartProtocol;
+use super::ssh::serve_git_command;
+use super::types::{ProtocolError, ProtocolStream, ProtocolVersion, ServiceType};
+use super::utils::{PktLine, build_error_pkt_line};
+
+/// The port of `git daemon`.
+pub const DEFAULT_PORT: u16 = 9418;
+
+/// The request line opening a `git://` connection, see the [module documentation](self).
+#[derive(Debug, Clone, PartialEq)]
+pub struct DaemonRequest {
+    pub service: ServiceType,
+    /// The path of the repository in the URL, e.g. `/project.git` or `/~user/project.git`
+    pub repo_path: String,
+    /// The `host=` parameter, the host and port of the URL, for virtual hosting
+    pub host: Option<String>,
+    /// The extra parameters after the host, e.g. `version=1`
+    pub extra_parameters: Vec<String>,
+}
+
+impl DaemonRequest {
+    /// Parse the payload of the request pkt-line.
+    pub fn parse(line: &[u8]) -> Result<Self, ProtocolError> {
+        let line = std::str::from_utf8(line)
+            .map_err(|_| ProtocolError::invalid_request("Request line is not UTF-8"))?;
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        let (command, args) = line.split_once('\0').unwrap_or((line, ""));
+        let (service, repo_path) = command
+            .split_once(' ')
+            .ok_or_else(|| ProtocolError::invalid_request("Missing repository path"))?;
+        let service: ServiceType = service.parse()?;
+        if repo_path.is_empty() {
+            return Err(ProtocolError::invalid_request("Missing repository path"));
+        }
+
+        // The host comes first, the extra parameters after an empty one
+        let mut host = None;
+        let mut extra_parameters = Vec::new();
+        let mut args = args.split('\0');
+        for arg in args.by_ref() {
+            match arg.strip_prefix("host=") {
+                Some(value) => host = Some(value.to_string()),
+                None if arg.is_empty() => break,
+                None => {
+                    return Err(ProtocolError::invalid_request(&format!(
+                        "Invalid request parameter: {arg}"
+                    )));
+                }
+            }
+        }
+        extra_parameters.extend(args.filter(|arg| !arg.is_empty()).map(str::to_string));
+        Ok(Self {
+            service,
+            repo_path: repo_path.to_string(),
+            host,
+            extra_parameters,
+        })
+    }
+
+    /// The protocol version the extra parameters ask for.
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        ProtocolVersion::from_hints(self.extra_parameters.iter().map(String::as_str))
+    }
+
+    /// The payload of the request pkt-line, as a client sends it.
+    pub fn to_line(&self) -> String {
+        let mut line = format!("{} {}\0", self.service, self.repo_path);
+        if let Some(host) = &self.host {
+            line.push_str(&format!("host={host}\0"));
+        }
+        if !self.extra_parameters.is_empty() {
+            line.push('\0');
+            for parameter in &self.extra_parameters {
+                line.push_str(&format!("{parameter}\0"));
+            }
+        }
+        line
+    }
+}
+
+/// Serves `git://` connections, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct GitDaemon {
+    upload_pack: bool,
+    receive_pack: bool,
+    upload_archive: bool,
+}
+
+impl Default for GitDaemon {
+    fn default() -> Self {
+        Self {
+            upload_pack: true,
+            receive_pack: false,
+            upload_archive: false,
+        }
+    }
+}
+
+impl GitDaemon {
+    /// A daemon serving upload-pack only.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enable or disable `service`, e.g. receive-pack for pushes over an unauthenticated
+    /// transport on a trusted network.
+    pub fn set_service_enabled(&mut self, service: ServiceType, enabled: bool) {
+        match service {
+            ServiceType::UploadPack => self.upload_pack = enabled,
+            ServiceType::ReceivePack => self.receive_pack = enabled,
+            ServiceType::UploadArchive => self.upload_archive = enabled,
+        }
+    }
+
+    pub fn is_service_enabled(&self, service: ServiceType) -> bool {
+        match service {
+            ServiceType::UploadPack => self.upload_pack,
+            ServiceType::ReceivePack => self.receive_pack,
+            ServiceType::UploadArchive => self.upload_archive,
+        }
+    }
+
+    /// Serve the connection `stream`, e.g. a `TcpStream` just accepted.
+    ///
+    /// `open` returns the protocol serving the repository of the request, configured by the
+    /// host, or the error answered to the client, e.g. [`ProtocolError::RepositoryNotFound`]
+    /// for repositories that aren't exported.
+    pub async fn serve<S, R, A, F, Fut>(&self, stream: S, open: F) -> Result<(), ProtocolError>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+        R: RepositoryAccess + 'static,
+        A: AuthenticationService,
+        F: FnOnce(DaemonRequest) -> Fut,
+        Fut: Future<Output = Result<SmartProtocol<R, A>, ProtocolError>>,
+    {
+        let (input, mut output) = tokio::io::split(stream);
+        let input: ProtocolStream = Box::pin(ReaderStream::new(input).map(|chunk| {
+            chunk.map_err(|e| ProtocolError::invalid_request(&format!("Stream error: {e}")))
+        }));
+        let mut input = PktLineReader::new(input);
+        let result = async {
+            let request = match input.next().await.transpose()? {
+                Some(PktLine::Data(line)) => DaemonRequest::parse(&line)?,
+                _ => return Err(ProtocolError::invalid_request("Missing request line")),
+            };
+            if !self.is_service_enabled(request.service) {
+                return Err(ProtocolError::PolicyRejected(format!(
+                    "{}: service not enabled",
+                    request.repo_path
+                )));
+            }
+            let service = request.service;
+            let hints = request.extra_parameters.clone();
+            let mut smart = open(request).await?;
+            smart.negotiate_protocol_version(hints.iter().map(String::as_str));
+            Ok((smart, service))
+        }
+        .await;
+        let (mut smart, service) = match result {
+            Ok(opened) => opened,
+            Err(e) => {
+                output
+                    .write_all(&build_error_pkt_line(&e.client_message()))
+                    .await?;
+                return Err(e);
+            }
+        };
+
+        // The pkt-lines of the service follow the request line
+        let (buffered, rest) = input.into_parts();
+        let head = futures::stream::once(async move { Ok(buffered.freeze()) });
+        serve_git_command(&mut smart, service, Box::pin(head.chain(rest)), output).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::fetch::{FetchOptions, FetchStatus, fetch};
+    use crate::client::transport::ConnectionTransport;
+    use crate::internal::object::commit::Commit;
+    use crate::protocol::memory::MemoryRepository;
+    use crate::protocol::smart::tests::{TestAuth, build_test_pack};
+    use crate::protocol::types::{PKT_LINE_END_MARKER, TransportProtocol, ZERO_ID};
+    use crate::protocol::utils::{add_pkt_line_parts, add_pkt_line_string};
+    use bytes::{BufMut, BytesMut};
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_daemon_request() {
+        let request =
+            DaemonRequest::parse(b"git-upload-pack /project.git\0host=example.com:9418\0").unwrap();
+        assert_eq!(request.service, ServiceType::UploadPack);
+        assert_eq!(request.repo_path, "/project.git");
+        assert_eq!(request.host.as_deref(), Some("example.com:9418"));
+        assert_eq!(request.protocol_version(), ProtocolVersion::V0);
+
+        let request =
+            DaemonRequest::parse(b"git-receive-pack /~me/x.git\0host=h\0\0version=1\0").unwrap();
+        assert_eq!(request.extra_parameters, ["version=1"]);
+        assert_eq!(request.protocol_version(), ProtocolVersion::V1);
+        assert_eq!(
+            request.to_line(),
+            "git-receive-pack /~me/x.git\0host=h\0\0version=1\0"
+        );
+        let request = DaemonRequest::parse(b"git-upload-pack /x.git\n").unwrap();
+        assert_eq!((request.host, request.extra_parameters.len()), (None, 0));
+
+        let request = DaemonRequest::parse(b"git-upload-archive /x.git\0").unwrap();
+        assert_eq!(request.service, ServiceType::UploadArchive);
+        assert!(!GitDaemon::new().is_service_enabled(request.service));
+
+        for line in [
+            &b"git-upload-pack\0host=h\0"[..],
+            b"git-upload-pack /x.git\0user=me\0",
+        ] {
+            assert!(DaemonRequest::parse(line).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_git_daemon() {
+        let repo = MemoryRepository::new();
+        let (commit, pack) = build_test_pack().await;
+        let mut daemon = GitDaemon::new();
+        // Serve the request of the client, which doesn't close its end before the reply
+        let serve = |daemon: GitDaemon, request: BytesMut| {
+            let repo = repo.clone();
+            async move {
+                let (mut client, server) = tokio::io::duplex(1 << 20);
+                client.write_all(&request).await.unwrap();
+                let result = daemon
+                    .serve(server, |request| async move {
+                        match request.repo_path.as_str() {
+                            "/project.git" => {
+                                Ok(SmartProtocol::new(TransportProtocol::Git, repo, TestAuth))
+                            }
+                            path => Err(ProtocolError::RepositoryNotFound(path.to_string())),
+                        }
+                    })
+                    .await;
+                let mut reply = Vec::new();
+                client.read_to_end(&mut reply).await.unwrap();
+                (result, String::from_utf8_lossy(&reply).into_owned())
+            }
+        };
+
+        let mut request = BytesMut::new();
+        add_pkt_line_parts(
+            &mut request,
+            &[b"git-receive-pack /project.git\0host=localhost\0"],
+        );
+        let (result, reply) = serve(daemon.clone(), request.clone()).await;
+        assert!(matches!(result, Err(ProtocolError::PolicyRejected(_))));
+        assert!(
+            reply.ends_with("/project.git: service not enabled\n"),
+            "{reply}"
+        );
+
+        // A push once receive-pack is enabled
+        daemon.set_service_enabled(ServiceType::ReceivePack, true);
+        add_pkt_line_string(
+            &mut request,
+            format!("{ZERO_ID} {} refs/heads/main\0report-status\n", commit.id),
+        );
+        request.put(&PKT_LINE_END_MARKER[..]);
+        request.put(&pack[..]);
+        let (result, reply) = serve(daemon.clone(), request).await;
+        result.unwrap();
+        assert!(reply.contains("capabilities^{}"), "{reply}");
+        assert!(
+            reply.ends_with("000eunpack ok\n0016ok refs/heads/main0000"),
+            "{reply}"
+        );
+        assert!(repo.has_object(&commit.id.to_string()).await.unwrap());
+
+        let mut request = BytesMut::new();
+        add_pkt_line_parts(
+            &mut request,
+            &[b"git-upload-pack /missing.git\0host=localhost\0"],
+        );
+        let (result, reply) = serve(daemon, request).await;
+        assert!(matches!(result, Err(ProtocolError::RepositoryNotFound(_))));
+        assert!(reply.contains("ERR "), "{reply}");
+    }
+
+    #[tokio::test]
+    async fn test_git_daemon_protocol_v2() {
+        let remote = MemoryRepository::new();
+        let (root, pack) = build_test_pack().await;
+        remote.store_pack_data(&pack).await.unwrap();
+        remote.set_ref("refs/heads/main", root.id);
+        // Fetch over a `git://` connection asking for version 2, the default of the client
+        let fetch_from = |repo: MemoryRepository| {
+            let remote = remote.clone();
+            async move {
+                let (client, server) = tokio::io::duplex(1 << 16);
+                let daemon = GitDaemon::new();
+                let serve = daemon.serve(server, |_| async {
+                    Ok(SmartProtocol::new(TransportProtocol::Git, remote, TestAuth))
+                });
+                let (client_read, client_write) = tokio::io::split(client);
+                let mut transport = ConnectionTransport::new(client_read, client_write);
+                transport.set_daemon_request("/project.git", Some("localhost"));
+                let options = FetchOptions::default();
+                let fetched = fetch(&repo, &mut transport, &options);
+                let (served, fetched) = tokio::join!(serve, fetched);
+                served.unwrap();
+                fetched.unwrap()
+            }
+        };
+
+        let repo = MemoryRepository::new();
+        let result = fetch_from(repo.clone()).await;
+        assert_eq!(result.advertisement.version, ProtocolVersion::V2);
+        assert!(result.advertisement.has_capability("ls-refs"));
+        assert_eq!(repo.get_ref("refs/remotes/origin/main"), Some(root.id));
+        assert!(repo.has_object(&root.tree_id.to_string()).await.unwrap());
+
+        // The haves of the second fetch are acknowledged before the pack
+        let child = Commit::new(
+            root.author.clone(),
+            root.committer.clone(),
+            root.tree_id,
+            vec![root.id],
+            "child",
+        );
+        remote.insert_object(&child).unwrap();
+        remote.set_ref("refs/heads/main", child.id);
+        let result = fetch_from(repo.clone()).await;
+        assert_eq!(result.refs[0].status, FetchStatus::FastForward);
+        assert_eq!(repo.get_ref("refs/remotes/origin/main"), Some(child.id));
+    }
+}
@@ -0,0 +1,452 @@
+//! Serving the references of a repository on disk, including its linked worktrees.
+//!
+//! [`GitDirRepository`] reads and updates references through a [`GitDir`]: `HEAD` and the
+//! per-worktree refs come from the worktree's own git directory, every other ref from the
+//! common directory, loose refs first and then `packed-refs`. Objects, packs and maintenance
+//! go to the wrapped [`RepositoryAccess`], e.g. one over the `objects` directory the
+//! worktrees share.
+//!
+//! A worktree `HEAD` that doesn't resolve, on an unborn branch or detached at a commit that
+//! isn't served, is advertised through the branches of `GitDir::head_fallbacks`, which
+//! [`GitDirRepository::head_fallbacks`] returns for `SmartProtocol::set_head_fallbacks`.
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+
+use crate::errors::GitError;
+use crate::hash::SHA1;
+use crate::internal::commit_graph::CommitGraph;
+use crate::internal::object::blob::Blob;
+use crate::internal::object::commit::Commit;
+use crate::internal::object::tag::Tag;
+use crate::internal::object::tree::Tree;
+use crate::internal::object::types::ObjectType;
+use crate::internal::pack::bitmap::PackBitmap;
+use crate::internal::pack::cruft::CruftPack;
+use crate::internal::pack::encode::PackedDelta;
+use crate::internal::refs::{RefStore, RefValue};
+use crate::internal::worktree::GitDir;
+use crate::protocol::core::RepositoryAccess;
+use crate::protocol::types::{
+    ObjectProvenance, PackInfo, ProtocolError, ProtocolStream, ReflogEntry, ZERO_ID,
+    check_symbolic_ref,
+};
+
+/// A repository serving the references of a [`GitDir`] and the objects of another
+/// repository, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct GitDirRepository<R> {
+    git_dir: Arc<GitDir>,
+    objects: R,
+    reflog_identity: Option<String>,
+}
+
+impl<R: RepositoryAccess> GitDirRepository<R> {
+    /// The references of `git_dir` with the objects of `objects`.
+    pub fn new(git_dir: GitDir, objects: R) -> Self {
+        Self {
+            git_dir: Arc::new(git_dir),
+            objects,
+            reflog_identity: None,
+        }
+    }
+
+    pub fn git_dir(&self) -> &GitDir {
+        &self.git_dir
+    }
+
+    /// The repository objects are read from and written to.
+    pub fn objects(&self) -> &R {
+        &self.objects
+    }
+
+    /// Record the updates of pushes in the reflogs with `identity`, e.g. `name <email>`, like
+    /// `core.logAllRefUpdates`. Without one, reflogs aren't written.
+    pub fn set_reflog_identity(&mut self, identity: impl Into<String>) {
+        self.reflog_identity = Some(identity.into());
+    }
+
+    /// The branches to advertise as `HEAD` when the worktree's `HEAD` doesn't resolve, see
+    /// `GitDir::head_fallbacks`.
+    pub fn head_fallbacks(&self) -> Result<Vec<String>, ProtocolError> {
+        self.git_dir.head_fallbacks().map_err(ref_error)
+    }
+}
+
+/// A failed update of a ref that moved or is being updated is the client's to retry.
+fn ref_error(error: GitError) -> ProtocolError {
+    match error {
+        GitError::StaleReference(_) | GitError::RefLocked(_) => {
+            ProtocolError::InvalidRequest(error.to_string())
+        }
+        error => ProtocolError::repository_error(error.to_string()),
+    }
+}
+
+/// The id of the hex `hash`, `None` for [`ZERO_ID`].
+fn parse_hash(hash: &str) -> Result<Option<SHA1>, ProtocolError> {
+    if hash == ZERO_ID {
+        return Ok(None);
+    }
+    SHA1::from_str(hash)
+        .map(Some)
+        .map_err(|_| ProtocolError::invalid_request(&format!("Invalid object id {hash}")))
+}
+
+#[async_trait]
+impl<R: RepositoryAccess> RepositoryAccess for GitDirRepository<R> {
+    /// `HEAD` first when it resolves, then the refs visible from the worktree by name.
+    async fn get_repository_refs(&self) -> Result<Vec<(String, String)>, ProtocolError> {
+        let refs = self.git_dir.list_refs().map_err(ref_error)?;
+        Ok(refs
+            .into_iter()
+            .map(|(name, id)| (name, id.to_string()))
+            .collect())
+    }
+
+    async fn has_object(&self, object_hash: &str) -> Result<bool, ProtocolError> {
+        self.objects.has_object(object_hash).await
+    }
+
+    async fn get_object(&self, object_hash: &str) -> Result<Vec<u8>, ProtocolError> {
+        self.objects.get_object(object_hash).await
+    }
+
+    async fn store_pack_data(&self, pack_data: &[u8]) -> Result<(), ProtocolError> {
+        self.objects.store_pack_data(pack_data).await
+    }
+
+    async fn store_pack_index(
+        &self,
+        pack_data: &[u8],
+        idx_data: &[u8],
+    ) -> Result<(), ProtocolError> {
+        self.objects.store_pack_index(pack_data, idx_data).await
+    }
+
+    async fn store_kept_pack(
+        &self,
+        pack_data: &[u8],
+        idx_data: &[u8],
+        keep: &str,
+    ) -> Result<(), ProtocolError> {
+        self.objects
+            .store_kept_pack(pack_data, idx_data, keep)
+            .await
+    }
+
+    async fn release_pack_keep(&self, pack_hash: &SHA1, keep: &str) -> Result<(), ProtocolError> {
+        self.objects.release_pack_keep(pack_hash, keep).await
+    }
+
+    async fn list_packs(&self) -> Result<Vec<PackInfo>, ProtocolError> {
+        self.objects.list_packs().await
+    }
+
+    async fn get_pack_index(&self, pack_hash: &SHA1) -> Result<Option<Vec<u8>>, ProtocolError> {
+        self.objects.get_pack_index(pack_hash).await
+    }
+
+    async fn store_cruft_pack(&self, cruft: &CruftPack) -> Result<(), ProtocolError> {
+        self.objects.store_cruft_pack(cruft).await
+    }
+
+    async fn get_pack_mtimes(&self, pack_hash: &SHA1) -> Result<Option<Vec<u8>>, ProtocolError> {
+        self.objects.get_pack_mtimes(pack_hash).await
+    }
+
+    async fn get_pack_data(&self, pack_hash: &SHA1) -> Result<Option<Vec<u8>>, ProtocolError> {
+        self.objects.get_pack_data(pack_hash).await
+    }
+
+    async fn store_pack_bitmap(
+        &self,
+        pack_hash: &SHA1,
+        bitmap_data: &[u8],
+    ) -> Result<(), ProtocolError> {
+        self.objects.store_pack_bitmap(pack_hash, bitmap_data).await
+    }
+
+    async fn store_multi_pack_index(&self, midx_data: &[u8]) -> Result<(), ProtocolError> {
+        self.objects.store_multi_pack_index(midx_data).await
+    }
+
+    async fn store_commit_graph(&self, graph_data: &[u8]) -> Result<(), ProtocolError> {
+        self.objects.store_commit_graph(graph_data).await
+    }
+
+    /// Packs the shared refs of the common directory, removing their loose files.
+    async fn pack_refs(&self, peeled: &HashMap<SHA1, SHA1>) -> Result<usize, ProtocolError> {
+        RefStore::new(&self.git_dir.common_dir)
+            .pack_refs(|id| Ok(peeled.get(id).copied()), true)
+            .map_err(ref_error)
+    }
+
+    async fn list_loose_objects(&self) -> Result<Vec<(SHA1, SystemTime)>, ProtocolError> {
+        self.objects.list_loose_objects().await
+    }
+
+    async fn remove_repacked(&self, packs: &[SHA1], objects: &[SHA1]) -> Result<(), ProtocolError> {
+        self.objects.remove_repacked(packs, objects).await
+    }
+
+    async fn store_object_stream(
+        &self,
+        object_type: ObjectType,
+        size: usize,
+        data: ProtocolStream,
+    ) -> Result<SHA1, ProtocolError> {
+        self.objects
+            .store_object_stream(object_type, size, data)
+            .await
+    }
+
+    /// Compare-and-swap `ref_name` in its store while holding its lock file, like
+    /// `git update-ref`.
+    async fn update_reference(
+        &self,
+        ref_name: &str,
+        old_hash: Option<&str>,
+        new_hash: &str,
+    ) -> Result<(), ProtocolError> {
+        let old = match old_hash {
+            Some(hash) => parse_hash(hash)?,
+            None => None,
+        };
+        let new = parse_hash(new_hash)?;
+        let store = self.git_dir.ref_store(ref_name);
+        match &self.reflog_identity {
+            Some(identity) => {
+                store.update_ref_logged(ref_name, old, new, identity, "update by push")
+            }
+            None => store.update_ref(ref_name, old, new),
+        }
+        .map_err(ref_error)
+    }
+
+    async fn get_reflog(&self, ref_name: &str) -> Result<Vec<ReflogEntry>, ProtocolError> {
+        self.git_dir
+            .ref_store(ref_name)
+            .read_reflog(ref_name)
+            .map_err(ref_error)
+    }
+
+    async fn get_commit_graph(&self) -> Result<Option<Arc<CommitGraph>>, ProtocolError> {
+        self.objects.get_commit_graph().await
+    }
+
+    async fn get_pack_bitmap(&self) -> Result<Option<Arc<PackBitmap>>, ProtocolError> {
+        self.objects.get_pack_bitmap().await
+    }
+
+    async fn get_packed_deltas(
+        &self,
+        object_hashes: &[String],
+    ) -> Result<HashMap<SHA1, PackedDelta>, ProtocolError> {
+        self.objects.get_packed_deltas(object_hashes).await
+    }
+
+    async fn get_objects_for_pack(
+        &self,
+        wants: &[String],
+        haves: &[String],
+    ) -> Result<Vec<String>, ProtocolError> {
+        self.objects.get_objects_for_pack(wants, haves).await
+    }
+
+    /// Whether the worktree's `HEAD` resolves to a commit.
+    async fn has_default_branch(&self) -> Result<bool, ProtocolError> {
+        Ok(self
+            .git_dir
+            .resolve_ref("HEAD")
+            .map_err(ref_error)?
+            .is_some())
+    }
+
+    async fn get_symbolic_ref(&self, ref_name: &str) -> Result<Option<String>, ProtocolError> {
+        Ok(match self.git_dir.read_ref(ref_name).map_err(ref_error)? {
+            Some(RefValue::Symbolic(target)) => Some(target),
+            _ => None,
+        })
+    }
+
+    /// Points the worktree's `HEAD`, the only symbolic ref a push can set, to a branch.
+    async fn set_symbolic_ref(&self, ref_name: &str, target: &str) -> Result<(), ProtocolError> {
+        check_symbolic_ref(ref_name, target)?;
+        self.git_dir
+            .ref_store(ref_name)
+            .write_ref(ref_name, &RefValue::Symbolic(target.to_string()))
+            .map_err(ref_error)
+    }
+
+    /// The branch the worktree's `HEAD` points to, `None` when it's detached.
+    async fn default_branch(&self) -> Result<Option<String>, ProtocolError> {
+        self.git_dir.head_target().map_err(ref_error)
+    }
+
+    async fn post_receive_hook(&self) -> Result<(), ProtocolError> {
+        self.objects.post_receive_hook().await
+    }
+
+    async fn get_blob(&self, object_hash: &str) -> Result<Blob, ProtocolError> {
+        self.objects.get_blob(object_hash).await
+    }
+
+    async fn get_commit(&self, commit_hash: &str) -> Result<Commit, ProtocolError> {
+        self.objects.get_commit(commit_hash).await
+    }
+
+    async fn get_tree(&self, tree_hash: &str) -> Result<Tree, ProtocolError> {
+        self.objects.get_tree(tree_hash).await
+    }
+
+    async fn get_tag(&self, tag_hash: &str) -> Result<Tag, ProtocolError> {
+        self.objects.get_tag(tag_hash).await
+    }
+
+    async fn commit_exists(&self, commit_hash: &str) -> Result<bool, ProtocolError> {
+        self.objects.commit_exists(commit_hash).await
+    }
+
+    async fn record_provenance(
+        &self,
+        object_hash: &str,
+        provenance: &ObjectProvenance,
+    ) -> Result<(), ProtocolError> {
+        self.objects
+            .record_provenance(object_hash, provenance)
+            .await
+    }
+
+    async fn handle_pack_objects(
+        &self,
+        commits: Vec<Commit>,
+        trees: Vec<Tree>,
+        blobs: Vec<Blob>,
+        tags: Vec<Tag>,
+        provenance: Option<&ObjectProvenance>,
+    ) -> Result<(), ProtocolError> {
+        self.objects
+            .handle_pack_objects(commits, trees, blobs, tags, provenance)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::*;
+    use crate::internal::object::signature::{Signature, SignatureType};
+    use crate::internal::object::tree::{TreeItem, TreeItemMode};
+    use crate::protocol::memory::MemoryRepository;
+
+    fn write(path: &Path, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_git_dir_repository() {
+        let objects = MemoryRepository::new();
+        let blob = Blob::from_content("worktree");
+        let item = TreeItem::new(TreeItemMode::Blob, blob.id, "file.txt".to_string());
+        let tree = Tree::from_tree_items(vec![item]).unwrap();
+        let signature = |kind| Signature::new(kind, "t".to_string(), "t@example.com".to_string());
+        let commit = Commit::new(
+            signature(SignatureType::Author),
+            signature(SignatureType::Committer),
+            tree.id,
+            vec![],
+            "\ninit",
+        );
+        objects.insert_object(&blob).unwrap();
+        objects.insert_object(&tree).unwrap();
+        objects.insert_object(&commit).unwrap();
+        let main = commit.id.to_string();
+
+        // A bare repository on the unborn `trunk` and a linked worktree on the unborn `topic`
+        let root = tempfile::tempdir().unwrap();
+        let repo_dir = root.path().join("repo.git");
+        write(&repo_dir.join("HEAD"), "ref: refs/heads/trunk\n");
+        write(&repo_dir.join("refs/heads/main"), &format!("{main}\n"));
+        let admin = repo_dir.join("worktrees/wt");
+        write(
+            &root.path().join("wt/.git"),
+            "gitdir: ../repo.git/worktrees/wt\n",
+        );
+        write(&admin.join("commondir"), "../..\n");
+        write(&admin.join("HEAD"), "ref: refs/heads/topic\n");
+        write(&admin.join("refs/bisect/good"), &format!("{main}\n"));
+
+        let git_dir = GitDir::discover(root.path().join("wt")).unwrap();
+        let mut repo = GitDirRepository::new(git_dir, objects.clone());
+        repo.set_reflog_identity("Pusher <pusher@example.com>");
+        assert_eq!(
+            repo.get_repository_refs().await.unwrap(),
+            [
+                ("refs/bisect/good".to_string(), main.clone()),
+                ("refs/heads/main".to_string(), main.clone()),
+            ]
+        );
+        assert!(!repo.has_default_branch().await.unwrap());
+        assert_eq!(
+            repo.default_branch().await.unwrap().as_deref(),
+            Some("refs/heads/topic")
+        );
+        assert_eq!(
+            repo.head_fallbacks().unwrap(),
+            ["refs/heads/trunk", "refs/heads/main", "refs/heads/master"]
+        );
+        assert_eq!(repo.get_commit(&main).await.unwrap().tree_id, tree.id);
+
+        // Pushing the worktree's branch writes it to the common directory
+        repo.update_reference("refs/heads/topic", None, &main)
+            .await
+            .unwrap();
+        assert!(repo_dir.join("refs/heads/topic").is_file());
+        assert!(repo.has_default_branch().await.unwrap());
+        let err = repo
+            .update_reference("refs/heads/topic", None, &main)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ProtocolError::InvalidRequest(_)), "{err}");
+        let reflog = repo.get_reflog("refs/heads/topic").await.unwrap();
+        assert_eq!(reflog.len(), 1);
+        assert_eq!(
+            (reflog[0].old_hash.as_str(), reflog[0].new_hash.as_str()),
+            (ZERO_ID, main.as_str())
+        );
+        assert_eq!(reflog[0].identity, "Pusher <pusher@example.com>");
+
+        // `HEAD` stays per worktree
+        repo.set_symbolic_ref("HEAD", "refs/heads/main")
+            .await
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(admin.join("HEAD")).unwrap(),
+            "ref: refs/heads/main\n"
+        );
+        assert_eq!(
+            fs::read_to_string(repo_dir.join("HEAD")).unwrap(),
+            "ref: refs/heads/trunk\n"
+        );
+        assert!(repo.set_symbolic_ref("HEAD", "refs/tags/v1").await.is_err());
+
+        assert_eq!(repo.pack_refs(&HashMap::new()).await.unwrap(), 2);
+        assert!(!repo_dir.join("refs/heads/main").exists());
+        assert!(admin.join("refs/bisect/good").is_file());
+        assert_eq!(
+            repo.get_repository_refs().await.unwrap()[0],
+            ("HEAD".to_string(), main.clone())
+        );
+        repo.update_reference("refs/heads/topic", Some(&main), ZERO_ID)
+            .await
+            .unwrap();
+        assert_eq!(repo.get_repository_refs().await.unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,158 @@
+//! Hidden references, like git's `transfer.hideRefs`, `uploadpack.hideRefs` and
+//! `receive.hideRefs`.
+//!
+//! Hosts keep refs that aren't meant for clients next to the branches and tags, e.g. the
+//! `refs/pull/*/head` of pull requests or a `refs/internal/` namespace. [`HiddenRefs`] lists
+//! the patterns to hide, per service or for both, and
+//! [`SmartProtocol`](super::smart::SmartProtocol) leaves the matching refs out of the
+//! advertisement, refuses wants of their tips in upload-pack and rejects pushes to them.
+//!
+//! Multi-tenant hosts whose visibility depends on who is asking implement a [`RefFilter`]
+//! instead, consulted for each ref with the authenticated user, on top of the hidden refs.
+use async_trait::async_trait;
+
+use super::types::ServiceType;
+
+/// One `hideRefs` value.
+#[derive(Debug, Clone, PartialEq)]
+struct HideRule {
+    /// `None` for both services, like `transfer.hideRefs`.
+    service: Option<ServiceType>,
+    pattern: String,
+    /// `false` for a `!pattern` that shows again refs an earlier rule hides.
+    hide: bool,
+}
+
+/// Patterns of hidden references, see the [module documentation](self).
+///
+/// A pattern matches the refs it names and every ref below it, e.g. `refs/internal` hides
+/// `refs/internal` and `refs/internal/ci/1`. As an extension of git, a `*` matches any part of
+/// a single component, so `refs/pull/*/head` hides the heads of all pull requests but not their
+/// merge refs. A pattern starting with `!` shows the refs it matches, and the last rule matching
+/// a ref decides, like git.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HiddenRefs {
+    rules: Vec<HideRule>,
+}
+
+impl HiddenRefs {
+    /// No hidden refs.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hide the refs matching `pattern` from both services, like a `transfer.hideRefs` value.
+    pub fn hide(&mut self, pattern: &str) {
+        self.push(None, pattern);
+    }
+
+    /// Hide the refs matching `pattern` from `service` only, like an `uploadpack.hideRefs` or
+    /// `receive.hideRefs` value.
+    pub fn hide_for(&mut self, service: ServiceType, pattern: &str) {
+        self.push(Some(service), pattern);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether `ref_name` is hidden from `service`.
+    pub fn is_hidden(&self, service: ServiceType, ref_name: &str) -> bool {
+        self.rules
+            .iter()
+            .rev()
+            .filter(|rule| rule.service.is_none_or(|scope| scope == service))
+            .find(|rule| matches_prefix(&rule.pattern, ref_name))
+            .is_some_and(|rule| rule.hide)
+    }
+
+    fn push(&mut self, service: Option<ServiceType>, pattern: &str) {
+        let (hide, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (false, pattern),
+            None => (true, pattern),
+        };
+        self.rules.push(HideRule {
+            service,
+            pattern: pattern.trim_end_matches('/').to_string(),
+            hide,
+        });
+    }
+}
+
+/// Whether a client may see a ref, as decided by a [`RefFilter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+    Visible,
+    Hidden,
+}
+
+/// What a [`RefFilter`] knows of the request.
+#[derive(Debug, Clone, Copy)]
+pub struct RefFilterContext<'a> {
+    pub service: ServiceType,
+    /// The authenticated user, `None` for anonymous requests.
+    pub user: Option<&'a str>,
+}
+
+/// Pluggable ref visibility, implemented by the host on top of its permissions.
+///
+/// Hidden refs aren't advertised and, like [`HiddenRefs`], their tips can't be fetched and
+/// they can't be pushed to.
+#[async_trait]
+pub trait RefFilter: Send + Sync {
+    /// Whether `ref_name` is visible to the client of `ctx`.
+    async fn visibility(&self, ref_name: &str, ctx: &RefFilterContext<'_>) -> Visibility;
+}
+
+/// Whether the components of `pattern` match the first components of `ref_name`.
+fn matches_prefix(pattern: &str, ref_name: &str) -> bool {
+    let mut names = ref_name.split('/');
+    pattern.split('/').all(|part| {
+        names
+            .next()
+            .is_some_and(|name| matches_component(part, name))
+    })
+}
+
+/// Whether `name` matches `pattern`, in which a `*` matches any run of characters.
+fn matches_component(pattern: &str, name: &str) -> bool {
+    let Some((prefix, rest)) = pattern.split_once('*') else {
+        return pattern == name;
+    };
+    let Some(name) = name.strip_prefix(prefix) else {
+        return false;
+    };
+    // Try every split for the rest of the pattern
+    (0..=name.len())
+        .filter(|&at| name.is_char_boundary(at))
+        .any(|at| matches_component(rest, &name[at..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hidden_refs() {
+        let mut hidden = HiddenRefs::new();
+        assert!(!hidden.is_hidden(ServiceType::UploadPack, "refs/heads/main"));
+        hidden.hide("refs/internal/");
+        hidden.hide("refs/pull/*/head");
+        hidden.hide("!refs/internal/public");
+        hidden.hide_for(ServiceType::ReceivePack, "refs/tags");
+
+        let upload = |name| hidden.is_hidden(ServiceType::UploadPack, name);
+        assert!(upload("refs/internal"));
+        assert!(upload("refs/internal/ci/1"));
+        assert!(!upload("refs/internals"));
+        assert!(!upload("refs/internal/public/docs"));
+        assert!(upload("refs/pull/12/head"));
+        assert!(!upload("refs/pull/12/merge"));
+        assert!(!upload("refs/tags/v1.0"));
+        assert!(hidden.is_hidden(ServiceType::ReceivePack, "refs/tags/v1.0"));
+        assert!(hidden.is_hidden(ServiceType::ReceivePack, "refs/pull/1/head"));
+
+        assert!(matches_component("rel-*-rc*", "rel-1.2-rc3"));
+        assert!(!matches_component("rel-*-rc*", "rel-1.2"));
+    }
+}
@@ -1,5 +1,6 @@
 use super::core::{AuthenticationService, GitProtocol, RepositoryAccess};
-use super::types::{ProtocolError, ProtocolStream};
+use super::types::{ProtocolError, ProtocolStream, ProtocolVersion, TransportProtocol};
+use super::utils::{build_error_advertisement, build_error_pkt_line};
 /// HTTP transport adapter for Git protocol
 ///
 /// This module provides HTTP-specific handling for Git smart protocol operations.
@@ -8,6 +9,15 @@ use super::types::{ProtocolError, ProtocolStream};
 use serde::Deserialize;
 use std::collections::HashMap;
 
+#[cfg(feature = "axum")]
+pub mod axum;
+pub mod dumb;
+#[cfg(feature = "tower")]
+pub mod service;
+
+/// Header carrying the protocol version hints of git clients, e.g. `version=2`
+pub const GIT_PROTOCOL_HEADER: &str = "Git-Protocol";
+
 /// HTTP Git protocol handler
 pub struct HttpGitHandler<R: RepositoryAccess, A: AuthenticationService> {
     protocol: GitProtocol<R, A>,
@@ -30,6 +40,12 @@ impl<R: RepositoryAccess, A: AuthenticationService> HttpGitHandler<R, A> {
         self.protocol.authenticate_http(headers).await
     }
 
+    /// Select the protocol version from the value of the [`GIT_PROTOCOL_HEADER`] of the
+    /// request, call before the handle_* methods
+    pub fn negotiate_protocol_version(&mut self, git_protocol: Option<&str>) -> ProtocolVersion {
+        self.protocol.negotiate_protocol_version(git_protocol)
+    }
+
     /// Handle HTTP info/refs request
     ///
     /// Processes GET requests to /{repo}/info/refs?service=git-{service}
@@ -93,7 +109,10 @@ impl<R: RepositoryAccess, A: AuthenticationService> HttpGitHandler<R, A> {
         &mut self,
         request_path: &str,
         request_stream: ProtocolStream,
-    ) -> Result<(ProtocolStream, &'static str), ProtocolError> {
+    ) -> Result<(ProtocolStream, &'static str), ProtocolError>
+    where
+        R: 'static,
+    {
         // Validate repository path exists in request
         extract_repo_path(request_path)
             .ok_or_else(|| ProtocolError::InvalidRequest("Invalid repository path".to_string()))?;
@@ -122,6 +141,25 @@ pub fn get_content_type(service: &str) -> &'static str {
     }
 }
 
+/// Build the body and content type answering a failed request for `service` with an `ERR`
+/// pkt-line, so the git client prints `message` instead of a generic HTTP error.
+/// `advertisement` tells an info/refs request from an upload-pack or receive-pack one.
+pub fn error_response(
+    service: &str,
+    advertisement: bool,
+    message: &str,
+) -> (Vec<u8>, &'static str) {
+    if advertisement {
+        let body = build_error_advertisement(TransportProtocol::Http, service, message);
+        (body.to_vec(), get_advertisement_content_type(service))
+    } else {
+        (
+            build_error_pkt_line(message).to_vec(),
+            get_content_type(service),
+        )
+    }
+}
+
 /// Get content type for Git HTTP info/refs advertisement
 pub fn get_advertisement_content_type(service: &str) -> &'static str {
     match service {
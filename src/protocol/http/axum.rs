@@ -0,0 +1,529 @@
+//! Axum handlers for the smart HTTP endpoints, behind the `axum` feature.
+//!
+//! [`router`] serves `GET <repo>/info/refs`, `POST <repo>/git-upload-pack` and
+//! `POST <repo>/git-receive-pack` for every repository a [`RepositoryResolver`] opens, and the
+//! handlers can be mounted on their own routes as well. They authenticate the request, select
+//! the protocol version from the `Git-Protocol` header, stream the request bodies into
+//! [`SmartProtocol`], frame the replies in the negotiated side-band and set the content types
+//! git expects.
+//!
+//! Failures are answered with the status of [`ProtocolError::http_status`] and its
+//! [`client_message`](ProtocolError::client_message), `WWW-Authenticate` asking the client for
+//! credentials on a `401`. Policy rejections are answered with an `ERR` pkt-line instead, which
+//! git prints to the user.
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+
+use ::axum::Router;
+use ::axum::body::{Body, Bytes};
+use ::axum::extract::State;
+use ::axum::http::{HeaderMap, HeaderValue, StatusCode, Uri, header};
+use ::axum::response::{IntoResponse, Response};
+use ::axum::routing::get;
+use async_trait::async_trait;
+use futures::StreamExt;
+
+use super::{
+    GIT_PROTOCOL_HEADER, error_response, extract_repo_path, get_advertisement_content_type,
+    get_content_type, get_service_from_query,
+};
+use crate::protocol::codec::PktLineReader;
+use crate::protocol::core::{AuthenticationService, RepositoryAccess};
+use crate::protocol::smart::SmartProtocol;
+use crate::protocol::types::{
+    PKT_LINE_END_MARKER, ProtocolError, ProtocolStream, ProtocolVersion, ServiceType,
+};
+use crate::protocol::utils::{side_band_limit, side_band_packets};
+
+/// Largest gzipped request, both compressed and inflated, like the 10 MiB default of git
+/// http-backend's `GIT_HTTP_MAX_REQUEST_BUFFER`
+const MAX_GZIP_REQUEST: usize = 10 * 1024 * 1024;
+
+/// Opens the repositories served by the handlers.
+#[async_trait]
+pub trait RepositoryResolver: Send + Sync + 'static {
+    type Repo: RepositoryAccess + 'static;
+    type Auth: AuthenticationService + 'static;
+
+    /// The protocol serving the repository at `repo_path`, the part of the URL path before
+    /// `/info/refs` or the service, e.g. `/org/project.git`, configured with the hidden refs,
+    /// policies and hooks of the host. [`ProtocolError::RepositoryNotFound`] answers a `404`.
+    async fn open(
+        &self,
+        repo_path: &str,
+    ) -> Result<SmartProtocol<Self::Repo, Self::Auth>, ProtocolError>;
+}
+
+/// A router serving the smart HTTP endpoints of every repository of `resolver`.
+pub fn router<S: RepositoryResolver>(resolver: S) -> Router {
+    Router::new()
+        .route("/{*path}", get(info_refs::<S>).post(service_rpc::<S>))
+        .with_state(Arc::new(resolver))
+}
+
+/// `GET <repo>/info/refs?service=<service>`, the refs advertisement.
+pub async fn info_refs<S: RepositoryResolver>(
+    State(resolver): State<Arc<S>>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response {
+    let service = uri.query().and_then(get_service_from_query).unwrap_or("");
+    let result = async {
+        let service_type: ServiceType = service.parse()?;
+        let repo_path = repo_path(&uri)?;
+        let smart = open(resolver.as_ref(), repo_path, &headers).await?;
+        smart.git_info_refs(service_type).await
+    }
+    .await;
+    match result {
+        Ok(advertisement) => git_response(
+            get_advertisement_content_type(service),
+            Body::from(advertisement.freeze()),
+        ),
+        Err(e) => error_reply(service, true, &e),
+    }
+}
+
+/// `POST <repo>/git-upload-pack`, a fetch or one round of its negotiation, or a protocol v2
+/// command.
+pub async fn upload_pack<S: RepositoryResolver>(
+    State(resolver): State<Arc<S>>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let service = ServiceType::UploadPack.to_string();
+    let result = async {
+        let repo_path = repo_path(&uri)?;
+        let mut smart = open(resolver.as_ref(), repo_path, &headers).await?;
+        // Large negotiations are gzipped by git
+        let request: ProtocolStream = if is_gzip(&headers) {
+            gunzip_stream(body)
+        } else {
+            body_stream(body)
+        };
+        // A protocol v2 request is a single command
+        if smart.protocol_version == ProtocolVersion::V2 {
+            return smart.git_v2_command(&mut PktLineReader::new(request)).await;
+        }
+        let (pack, protocol_buf) = smart.git_upload_pack_stream(request).await?;
+        let pack = pack.map(Bytes::from);
+        let head = futures::stream::once(async move { protocol_buf.freeze() });
+        let reply: ProtocolStream = match side_band_limit(&smart.capabilities) {
+            // The pack follows the acknowledgments once the negotiation is done
+            Some(limit) if smart.negotiation.done => {
+                let pack = pack.map(move |chunk| side_band_packets(&chunk, limit));
+                let end = futures::stream::once(async { Bytes::from_static(PKT_LINE_END_MARKER) });
+                Box::pin(head.chain(pack).chain(end).map(Ok))
+            }
+            _ => Box::pin(head.chain(pack).map(Ok)),
+        };
+        Ok::<_, ProtocolError>(reply)
+    }
+    .await;
+    match result {
+        Ok(reply) => git_response(get_content_type(&service), Body::from_stream(reply)),
+        Err(e) => error_reply(&service, false, &e),
+    }
+}
+
+/// `POST <repo>/git-receive-pack`, a push.
+pub async fn receive_pack<S: RepositoryResolver>(
+    State(resolver): State<Arc<S>>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let service = ServiceType::ReceivePack.to_string();
+    let result = async {
+        let repo_path = repo_path(&uri)?;
+        let mut smart = open(resolver.as_ref(), repo_path, &headers).await?;
+        smart
+            .git_receive_pack_incremental(body_stream(body))
+            .await
+    }
+    .await;
+    match result {
+        Ok(reply) => git_response(get_content_type(&service), Body::from_stream(reply)),
+        Err(e) => error_reply(&service, false, &e),
+    }
+}
+
+/// Dispatch the POST requests of [`router`] by service.
+async fn service_rpc<S: RepositoryResolver>(
+    state: State<Arc<S>>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let path = uri.path();
+    if path.ends_with("/git-upload-pack") {
+        upload_pack(state, uri, headers, body).await
+    } else if path.ends_with("/git-receive-pack") {
+        receive_pack(state, uri, headers, body).await
+    } else {
+        StatusCode::NOT_FOUND.into_response()
+    }
+}
+
+/// Open the repository and authenticate the request.
+async fn open<S: RepositoryResolver>(
+    resolver: &S,
+    repo_path: &str,
+    headers: &HeaderMap,
+) -> Result<SmartProtocol<S::Repo, S::Auth>, ProtocolError> {
+    let mut smart = resolver.open(repo_path).await?;
+    let headers_map: HashMap<String, String> = headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    smart.authenticate_http(&headers_map).await?;
+    let git_protocol = headers
+        .get(GIT_PROTOCOL_HEADER)
+        .and_then(|value| value.to_str().ok());
+    smart.negotiate_protocol_version(git_protocol);
+    Ok(smart)
+}
+
+fn repo_path(uri: &Uri) -> Result<&str, ProtocolError> {
+    extract_repo_path(uri.path())
+        .ok_or_else(|| ProtocolError::RepositoryNotFound(uri.path().to_string()))
+}
+
+fn is_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_ENCODING)
+        .is_some_and(|encoding| encoding == "gzip" || encoding == "x-gzip")
+}
+
+/// The inflated bytes, refusing more than `limit` of them before they are buffered.
+struct LimitedBuffer {
+    data: Vec<u8>,
+    limit: usize,
+}
+
+impl Write for LimitedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.len() > self.limit {
+            return Err(std::io::Error::other(format!(
+                "more than {MAX_GZIP_REQUEST} bytes once inflated"
+            )));
+        }
+        self.limit -= buf.len();
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `body` inflated chunk by chunk as it arrives, an error once either the body or its
+/// inflated content exceeds [`MAX_GZIP_REQUEST`]
+fn gunzip_stream(body: Body) -> ProtocolStream {
+    let buffer = LimitedBuffer {
+        data: Vec::new(),
+        limit: MAX_GZIP_REQUEST,
+    };
+    let decoder = flate2::write::GzDecoder::new(buffer);
+    let state = Some((body_stream(body), decoder, 0));
+    Box::pin(futures::stream::unfold(state, |state| async move {
+        let (mut body, mut decoder, mut received) = state?;
+        let (inflated, finished) = match body.next().await {
+            Some(Ok(chunk)) => {
+                received += chunk.len();
+                if received > MAX_GZIP_REQUEST {
+                    let message = format!("Gzipped body larger than {MAX_GZIP_REQUEST} bytes");
+                    return Some((Err(ProtocolError::invalid_request(&message)), None));
+                }
+                (decoder.write_all(&chunk), false)
+            }
+            Some(Err(e)) => return Some((Err(e), None)),
+            None => (decoder.try_finish(), true),
+        };
+        match inflated {
+            Ok(()) => {
+                let data = std::mem::take(&mut decoder.get_mut().data);
+                let state = (!finished).then_some((body, decoder, received));
+                Some((Ok(Bytes::from(data)), state))
+            }
+            Err(e) => {
+                let message = format!("Bad gzip body: {e}");
+                Some((Err(ProtocolError::invalid_request(&message)), None))
+            }
+        }
+    }))
+}
+
+fn body_stream(body: Body) -> ProtocolStream {
+    Box::pin(body.into_data_stream().map(|chunk| {
+        chunk.map_err(|e| ProtocolError::invalid_request(&format!("Stream error: {e}")))
+    }))
+}
+
+fn git_response(content_type: &'static str, body: Body) -> Response {
+    let mut response = Response::new(body);
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    response
+}
+
+fn error_reply(service: &str, advertisement: bool, e: &ProtocolError) -> Response {
+    if let ProtocolError::PolicyRejected(_) = e
+        && service.parse::<ServiceType>().is_ok()
+    {
+        let (body, content_type) = error_response(service, advertisement, &e.client_message());
+        return git_response(content_type, Body::from(body));
+    }
+    let mut response = (e.http_status(), e.client_message()).into_response();
+    if e.http_status() == StatusCode::UNAUTHORIZED {
+        response.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm=\"git\""),
+        );
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::SHA1;
+    use crate::protocol::memory::MemoryRepository;
+    use crate::protocol::smart::tests::build_test_pack;
+    use crate::protocol::types::{PKT_LINE_DELIM_MARKER, TransportProtocol};
+    use crate::protocol::utils::add_pkt_line_string;
+    use bytes::{BufMut, BytesMut};
+    use std::str::FromStr;
+
+    async fn collect_body(response: Response) -> Vec<u8> {
+        let body = ::axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        body.to_vec()
+    }
+
+    #[derive(Clone)]
+    struct TokenAuth;
+
+    #[async_trait]
+    impl AuthenticationService for TokenAuth {
+        async fn authenticate_http(
+            &self,
+            headers: &HashMap<String, String>,
+        ) -> Result<(), ProtocolError> {
+            match headers.get("authorization").map(String::as_str) {
+                Some("Bearer secret") | None => Ok(()),
+                Some(_) => Err(ProtocolError::auth_failed(None, "bad token")),
+            }
+        }
+
+        async fn authenticate_ssh(&self, _: &str, _: &[u8]) -> Result<(), ProtocolError> {
+            Ok(())
+        }
+    }
+
+    struct Repos(MemoryRepository);
+
+    #[async_trait]
+    impl RepositoryResolver for Repos {
+        type Repo = MemoryRepository;
+        type Auth = TokenAuth;
+
+        async fn open(
+            &self,
+            repo_path: &str,
+        ) -> Result<SmartProtocol<MemoryRepository, TokenAuth>, ProtocolError> {
+            match repo_path {
+                "/project.git" => Ok(SmartProtocol::new(
+                    TransportProtocol::Http,
+                    self.0.clone(),
+                    TokenAuth,
+                )),
+                _ => Err(ProtocolError::RepositoryNotFound(repo_path.to_string())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_axum_handlers() {
+        let repo = MemoryRepository::new();
+        let main = SHA1::from_str(&"1".repeat(40)).unwrap();
+        repo.set_ref("refs/heads/main", main);
+        let resolver = Arc::new(Repos(repo));
+        let uri = |uri: &str| uri.parse::<Uri>().unwrap();
+
+        let response = info_refs(
+            State(resolver.clone()),
+            uri("/project.git/info/refs?service=git-upload-pack"),
+            HeaderMap::new(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/x-git-upload-pack-advertisement"
+        );
+        let body = collect_body(response).await;
+        assert!(body.starts_with(b"001e# service=git-upload-pack\n0000"));
+        assert!(body.ends_with(format!("{main} refs/heads/main\n0000").as_bytes()));
+
+        let response = info_refs(
+            State(resolver.clone()),
+            uri("/missing.git/info/refs?service=git-upload-pack"),
+            HeaderMap::new(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer x"));
+        let response = info_refs(
+            State(resolver.clone()),
+            uri("/project.git/info/refs?service=git-receive-pack"),
+            headers,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response.headers().contains_key(header::WWW_AUTHENTICATE));
+
+        // A refused want is answered in the protocol, a negotiation round without a pack
+        let mut request = BytesMut::new();
+        let missing = "2".repeat(40);
+        add_pkt_line_string(&mut request, format!("want {missing} side-band-64k\n"));
+        add_pkt_line_string(&mut request, "done\n".to_string());
+        let response = upload_pack(
+            State(resolver.clone()),
+            uri("/project.git/git-upload-pack"),
+            HeaderMap::new(),
+            Body::from(request.freeze()),
+        )
+        .await;
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/x-git-upload-pack-result"
+        );
+        let body = collect_body(response).await;
+        assert_eq!(
+            body,
+            format!("004aERR upload-pack: not our ref {missing}\n").as_bytes()
+        );
+
+        let mut request = BytesMut::new();
+        add_pkt_line_string(&mut request, format!("want {main} multi_ack_detailed\n"));
+        request.put(&PKT_LINE_END_MARKER[..]);
+        add_pkt_line_string(&mut request, format!("have {missing}\n"));
+        request.put(&PKT_LINE_END_MARKER[..]);
+        let mut gzipped = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        std::io::Write::write_all(&mut gzipped, &request).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        let response = upload_pack(
+            State(resolver),
+            uri("/project.git/git-upload-pack"),
+            headers,
+            Body::from(gzipped.finish().unwrap()),
+        )
+        .await;
+        assert_eq!(collect_body(response).await, b"0008NAK\n");
+    }
+
+    /// A protocol v2 `command` request with `arguments`
+    fn v2_request(command: &str, arguments: &[String]) -> Body {
+        let mut request = BytesMut::new();
+        add_pkt_line_string(&mut request, format!("command={command}\n"));
+        add_pkt_line_string(&mut request, "object-format=sha1\n".to_string());
+        request.put(&PKT_LINE_DELIM_MARKER[..]);
+        for argument in arguments {
+            add_pkt_line_string(&mut request, format!("{argument}\n"));
+        }
+        request.put(&PKT_LINE_END_MARKER[..]);
+        Body::from(request.freeze())
+    }
+
+    #[tokio::test]
+    async fn test_axum_protocol_v2() {
+        let repo = MemoryRepository::new();
+        let (commit, pack) = build_test_pack().await;
+        repo.store_pack_data(&pack).await.unwrap();
+        repo.set_ref("refs/heads/main", commit.id);
+        let resolver = Arc::new(Repos(repo));
+        let uri = |uri: &str| uri.parse::<Uri>().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(GIT_PROTOCOL_HEADER, HeaderValue::from_static("version=2"));
+
+        // No service header, the capabilities instead of the refs
+        let response = info_refs(
+            State(resolver.clone()),
+            uri("/project.git/info/refs?service=git-upload-pack"),
+            headers.clone(),
+        )
+        .await;
+        let body = collect_body(response).await;
+        assert!(body.starts_with(b"000eversion 2\n"));
+        assert!(body.windows(11).any(|line| line == b"000cls-refs"));
+
+        let upload_pack = |request: Body| {
+            upload_pack(
+                State(resolver.clone()),
+                uri("/project.git/git-upload-pack"),
+                headers.clone(),
+                request,
+            )
+        };
+        let arguments = ["symrefs".to_string(), "ref-prefix HEAD".to_string()];
+        let response = upload_pack(v2_request("ls-refs", &arguments)).await;
+        assert_eq!(
+            collect_body(response).await,
+            format!("0050{} HEAD symref-target:refs/heads/main\n0000", commit.id).as_bytes()
+        );
+
+        let arguments = [format!("want {}", commit.id), "done".to_string()];
+        let response = upload_pack(v2_request("fetch", &arguments)).await;
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/x-git-upload-pack-result"
+        );
+        let body = collect_body(response).await;
+        assert!(body.starts_with(b"000dpackfile\n"));
+        assert!(body.windows(5).any(|data| data == b"\x01PACK"));
+        assert!(body.ends_with(b"0000"));
+
+        let response = upload_pack(v2_request("object-info", &[])).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_gzip_request_limits() {
+        let gzip = |data: &[u8]| {
+            let mut gzipped = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+            gzipped.write_all(data).unwrap();
+            gzipped.finish().unwrap()
+        };
+        let inflate = |body: Vec<u8>| async move {
+            // Two chunks, the gzip header split between them
+            let (head, tail) = body.split_at(5);
+            let chunks = [head.to_vec(), tail.to_vec()].map(Ok::<_, std::io::Error>);
+            let stream = gunzip_stream(Body::from_stream(futures::stream::iter(chunks)));
+            let chunks: Vec<_> = stream.collect().await;
+            chunks.into_iter().collect::<Result<Vec<Bytes>, _>>()
+        };
+        assert_eq!(
+            inflate(gzip(b"0009done\n")).await.unwrap().concat(),
+            b"0009done\n"
+        );
+
+        // A small body that inflates past the limit is refused without being inflated whole
+        let bomb = gzip(&vec![b'0'; MAX_GZIP_REQUEST + 1]);
+        assert!(bomb.len() < MAX_GZIP_REQUEST / 100);
+        let error = inflate(bomb).await.unwrap_err().to_string();
+        assert!(error.contains("once inflated"), "{error}");
+
+        // And so is a body too large to be read at all
+        let error = inflate(vec![0; MAX_GZIP_REQUEST + 1]).await.unwrap_err();
+        assert!(error.to_string().contains("Gzipped body larger"), "{error}");
+        assert!(inflate(b"not gzip".to_vec()).await.is_err());
+    }
+}
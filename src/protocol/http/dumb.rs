@@ -0,0 +1,282 @@
+//! The dumb HTTP protocol, git's fallback for servers that can only serve files.
+//!
+//! A dumb client reads `info/refs`, then walks the history by downloading each object it is
+//! missing as the loose object `objects/<xx>/<38 hex digits>`, falling back to the packs of
+//! `objects/info/packs`, their `.idx` first. [`DumbHttp`] builds these files from a
+//! [`RepositoryAccess`], the way `git update-server-info` writes them, so read-only mirrors
+//! behind static-file-ish infrastructure and very old clients can be served without the smart
+//! protocol. Every object of the repository is served loose, packed or not, so clients only
+//! need the packs the backend keeps when they prefer downloading them whole.
+//!
+//! There is no negotiation: hidden refs are left out of `info/refs`, but the objects they point
+//! to can still be downloaded by id, and pushes aren't supported.
+use std::str::FromStr;
+
+use crate::hash::SHA1;
+use crate::internal::loose::{DEFAULT_LOOSE_COMPRESSION, encode_loose_object};
+use crate::internal::object::ObjectTrait;
+use crate::internal::object::tag::Tag;
+use crate::internal::object::types::ObjectType;
+use crate::protocol::core::RepositoryAccess;
+use crate::protocol::hidden_refs::HiddenRefs;
+use crate::protocol::pack::load_object;
+use crate::protocol::types::{ProtocolError, ServiceType};
+
+/// How many tags of tags are peeled before giving up, like git.
+const MAX_PEEL_DEPTH: usize = 32;
+
+/// A file of the dumb protocol, with the content type git's `http-backend` serves it with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DumbFile {
+    pub content_type: &'static str,
+    pub data: Vec<u8>,
+}
+
+/// The files of the dumb HTTP protocol of a repository, see the [module documentation](self).
+pub struct DumbHttp<R: RepositoryAccess> {
+    repo: R,
+    /// The refs left out of `info/refs`, those hidden from upload-pack.
+    pub hidden_refs: HiddenRefs,
+}
+
+impl<R: RepositoryAccess> DumbHttp<R> {
+    pub fn new(repo: R) -> Self {
+        Self {
+            repo,
+            hidden_refs: HiddenRefs::new(),
+        }
+    }
+
+    /// The file at `path` in the repository, e.g. `info/refs` or `objects/info/packs`, or None
+    /// when there is none, answered with a 404.
+    pub async fn serve(&self, path: &str) -> Result<Option<DumbFile>, ProtocolError> {
+        let path = path.trim_start_matches('/');
+        let file =
+            |content_type, data: Option<Vec<u8>>| data.map(|data| DumbFile { content_type, data });
+        match path {
+            "info/refs" => Ok(file(
+                "text/plain",
+                Some(self.info_refs().await?.into_bytes()),
+            )),
+            "HEAD" => Ok(file(
+                "text/plain",
+                self.head().await?.map(String::into_bytes),
+            )),
+            "objects/info/packs" => Ok(file(
+                "text/plain; charset=utf-8",
+                Some(self.info_packs().await?.into_bytes()),
+            )),
+            _ => {
+                let Some(name) = path.strip_prefix("objects/") else {
+                    return Ok(None);
+                };
+                if let Some(name) = name.strip_prefix("pack/pack-") {
+                    let pack_hash = |hash: &str| SHA1::from_str(hash).ok();
+                    if let Some(hash) = name.strip_suffix(".pack").and_then(pack_hash) {
+                        let data = self.repo.get_pack_data(&hash).await?;
+                        return Ok(file("application/x-git-packed-objects", data));
+                    }
+                    if let Some(hash) = name.strip_suffix(".idx").and_then(pack_hash) {
+                        let data = self.repo.get_pack_index(&hash).await?;
+                        return Ok(file("application/x-git-packed-objects-toc", data));
+                    }
+                    return Ok(None);
+                }
+                match name.split_once('/') {
+                    Some((dir, rest)) if dir.len() == 2 && rest.len() == 38 => {
+                        let Ok(id) = SHA1::from_str(&format!("{dir}{rest}")) else {
+                            return Ok(None);
+                        };
+                        let data = self.loose_object(&id).await?;
+                        Ok(file("application/x-git-loose-object", data))
+                    }
+                    _ => Ok(None),
+                }
+            }
+        }
+    }
+
+    /// `info/refs`: a `<id>\t<ref>` line per ref sorted by name, followed by a `<id>\t<ref>^{}`
+    /// line with the object an annotated tag points to.
+    pub async fn info_refs(&self) -> Result<String, ProtocolError> {
+        let mut refs = self.repo.get_repository_refs().await?;
+        refs.retain(|(name, _)| {
+            name.starts_with("refs/") && !self.hidden_refs.is_hidden(ServiceType::UploadPack, name)
+        });
+        refs.sort();
+        refs.dedup_by(|a, b| a.0 == b.0);
+        let mut info = String::new();
+        for (name, hash) in refs {
+            info.push_str(&format!("{hash}\t{name}\n"));
+            if let Ok(id) = SHA1::from_str(&hash)
+                && let Some(peeled) = self.peel(id).await?
+            {
+                info.push_str(&format!("{peeled}\t{name}^{{}}\n"));
+            }
+        }
+        Ok(info)
+    }
+
+    /// `HEAD`: `ref: <branch>` for the default branch, the id of a detached `HEAD`, or None when
+    /// the repository has neither.
+    pub async fn head(&self) -> Result<Option<String>, ProtocolError> {
+        if let Some(branch) = self.repo.default_branch().await? {
+            return Ok(Some(format!("ref: {branch}\n")));
+        }
+        let refs = self.repo.get_repository_refs().await?;
+        Ok(refs
+            .into_iter()
+            .find(|(name, _)| name == "HEAD")
+            .map(|(_, hash)| format!("{hash}\n")))
+    }
+
+    /// `objects/info/packs`: a `P pack-<hash>.pack` line per pack, then an empty line.
+    pub async fn info_packs(&self) -> Result<String, ProtocolError> {
+        let mut info = String::new();
+        for pack in self.repo.list_packs().await? {
+            info.push_str(&format!("P pack-{}.pack\n", pack.pack_hash));
+        }
+        info.push('\n');
+        Ok(info)
+    }
+
+    /// The loose object file of `id`, or None when the repository doesn't have the object.
+    pub async fn loose_object(&self, id: &SHA1) -> Result<Option<Vec<u8>>, ProtocolError> {
+        let object = load_object(&self.repo, id).await?;
+        Ok(object.map(|(obj_type, data)| {
+            encode_loose_object(obj_type, &data, DEFAULT_LOOSE_COMPRESSION)
+        }))
+    }
+
+    /// The object the annotated tag `id` points to, through tags of tags, or None when `id`
+    /// isn't a tag.
+    async fn peel(&self, mut id: SHA1) -> Result<Option<SHA1>, ProtocolError> {
+        let mut peeled = None;
+        for _ in 0..MAX_PEEL_DEPTH {
+            match load_object(&self.repo, &id).await? {
+                Some((ObjectType::Tag, data)) => {
+                    let tag = Tag::from_bytes(&data, id)
+                        .map_err(|e| ProtocolError::corrupt_object(id, e))?;
+                    id = tag.object_hash;
+                    peeled = Some(id);
+                }
+                _ => break,
+            }
+        }
+        Ok(peeled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::loose::decode_loose_object;
+    use crate::internal::object::blob::Blob;
+    use crate::internal::object::commit::Commit;
+    use crate::internal::object::signature::{Signature, SignatureType};
+    use crate::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+    use crate::internal::pack::idx::PackIndex;
+    use crate::maintenance::{RepackOptions, repack};
+    use crate::protocol::object_store::{MemoryStorage, ObjectStoreRepository};
+
+    #[tokio::test]
+    async fn test_dumb_http() {
+        let repo = ObjectStoreRepository::new(MemoryStorage::new(), "repos/test");
+        let blob = Blob::from_content("hello");
+        let item = TreeItem::new(TreeItemMode::Blob, blob.id, "hello.txt".to_string());
+        let tree = Tree::from_tree_items(vec![item]).unwrap();
+        let signature = |kind| Signature::new(kind, "t".to_string(), "t@example.com".to_string());
+        let commit = Commit::new(
+            signature(SignatureType::Author),
+            signature(SignatureType::Committer),
+            tree.id,
+            vec![],
+            "\ninit",
+        );
+        repo.handle_pack_objects(
+            vec![commit.clone()],
+            vec![tree.clone()],
+            vec![blob],
+            vec![],
+            None,
+        )
+        .await
+        .unwrap();
+        for name in ["refs/heads/main", "refs/internal/ci"] {
+            repo.update_reference(name, None, &commit.id.to_string())
+                .await
+                .unwrap();
+        }
+        let mut dumb = DumbHttp::new(repo.clone());
+        dumb.hidden_refs.hide("refs/internal");
+        let info_packs = dumb.serve("objects/info/packs").await.unwrap().unwrap();
+        assert_eq!(info_packs.data, b"\n");
+
+        let report = repack(&repo, &RepackOptions::default())
+            .await
+            .unwrap()
+            .unwrap();
+        let name = format!("pack-{}", report.pack_hash);
+        assert_eq!(
+            dumb.info_packs().await.unwrap(),
+            format!("P {name}.pack\n\n")
+        );
+        let idx = dumb
+            .serve(&format!("objects/pack/{name}.idx"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(idx.content_type, "application/x-git-packed-objects-toc");
+        let idx = PackIndex::from_bytes(idx.data).unwrap();
+        assert_eq!(idx.pack_hash(), report.pack_hash);
+        let pack = dumb
+            .serve(&format!("objects/pack/{name}.pack"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(pack.data.starts_with(b"PACK"));
+
+        // A tag stored loose after the repack
+        let tag = Tag::new(
+            commit.id,
+            ObjectType::Commit,
+            "v1".to_string(),
+            signature(SignatureType::Tagger),
+            "\nfirst".to_string(),
+        );
+        repo.handle_pack_objects(vec![], vec![], vec![], vec![tag.clone()], None)
+            .await
+            .unwrap();
+        repo.update_reference("refs/tags/v1", None, &tag.id.to_string())
+            .await
+            .unwrap();
+        let info_refs = dumb.serve("/info/refs").await.unwrap().unwrap();
+        assert_eq!(info_refs.content_type, "text/plain");
+        assert_eq!(
+            String::from_utf8(info_refs.data).unwrap(),
+            format!(
+                "{0}\trefs/heads/main\n{1}\trefs/tags/v1\n{0}\trefs/tags/v1^{{}}\n",
+                commit.id, tag.id
+            )
+        );
+        let head = dumb.serve("HEAD").await.unwrap().unwrap();
+        assert_eq!(head.data, b"ref: refs/heads/main\n");
+
+        // Packed objects are served loose too
+        let hex = tree.id.to_string();
+        let loose = dumb
+            .serve(&format!("objects/{}/{}", &hex[..2], &hex[2..]))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(loose.content_type, "application/x-git-loose-object");
+        assert_eq!(
+            decode_loose_object(&loose.data).unwrap(),
+            (ObjectType::Tree, tree.to_data().unwrap())
+        );
+        let missing = Blob::from_content("missing").id.to_string();
+        let path = format!("objects/{}/{}", &missing[..2], &missing[2..]);
+        assert_eq!(dumb.serve(&path).await.unwrap(), None);
+        assert_eq!(dumb.serve("objects/info/alternates").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,246 @@
+//! A tower [`Service`] of the smart HTTP endpoints, behind the `tower` feature.
+//!
+//! [`GitHttpService`] serves one repository to any server or framework taking tower services,
+//! e.g. a hyper connection through `hyper_util::service::TowerToHyperService`, or a route of a
+//! framework mounting services. It answers the requests like the [axum handlers](super::axum),
+//! whatever the path before `/info/refs`, `/git-upload-pack` or `/git-receive-pack`, so hosts
+//! mount it at the path of the repository; hosts serving many repositories from a single
+//! service use [`router`](super::axum::router) instead, itself a tower service.
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use ::axum::BoxError;
+use ::axum::body::{Body, Bytes};
+use ::axum::extract::State;
+use ::axum::http::{Method, Request, StatusCode};
+use ::axum::response::{IntoResponse, Response};
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use tower_service::Service;
+
+use super::axum::{RepositoryResolver, info_refs, receive_pack, upload_pack};
+use crate::protocol::core::{AuthenticationService, RepositoryAccess};
+use crate::protocol::smart::SmartProtocol;
+use crate::protocol::types::{ProtocolError, TransportProtocol};
+
+type Configure<R, A> = Arc<dyn Fn(&mut SmartProtocol<R, A>) + Send + Sync>;
+
+/// The smart HTTP endpoints of a repository, see the [module documentation](self).
+pub struct GitHttpService<R: RepositoryAccess, A: AuthenticationService> {
+    repo: R,
+    auth: A,
+    configure: Option<Configure<R, A>>,
+}
+
+impl<R, A> GitHttpService<R, A>
+where
+    R: RepositoryAccess + 'static,
+    A: AuthenticationService + Clone + 'static,
+{
+    /// Serve `repo`, authenticating the requests with `auth`.
+    pub fn new(repo: R, auth: A) -> Self {
+        Self {
+            repo,
+            auth,
+            configure: None,
+        }
+    }
+
+    /// Configure the protocol of each request, e.g. its hidden refs, policies and hooks.
+    pub fn set_configure(
+        &mut self,
+        configure: impl Fn(&mut SmartProtocol<R, A>) + Send + Sync + 'static,
+    ) {
+        self.configure = Some(Arc::new(configure));
+    }
+}
+
+impl<R: RepositoryAccess, A: AuthenticationService + Clone> Clone for GitHttpService<R, A> {
+    fn clone(&self) -> Self {
+        Self {
+            repo: self.repo.clone(),
+            auth: self.auth.clone(),
+            configure: self.configure.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl<R, A> RepositoryResolver for GitHttpService<R, A>
+where
+    R: RepositoryAccess + 'static,
+    A: AuthenticationService + Clone + 'static,
+{
+    type Repo = R;
+    type Auth = A;
+
+    /// The repository of the service, whatever `repo_path`.
+    async fn open(&self, _repo_path: &str) -> Result<SmartProtocol<R, A>, ProtocolError> {
+        let mut smart = SmartProtocol::new(
+            TransportProtocol::Http,
+            self.repo.clone(),
+            self.auth.clone(),
+        );
+        if let Some(configure) = &self.configure {
+            configure(&mut smart);
+        }
+        Ok(smart)
+    }
+}
+
+impl<R, A, B> Service<Request<B>> for GitHttpService<R, A>
+where
+    R: RepositoryAccess + 'static,
+    A: AuthenticationService + Clone + 'static,
+    B: http_body::Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        let state = State(Arc::new(self.clone()));
+        let (parts, body) = request.into_parts();
+        let body = Body::new(body);
+        Box::pin(async move {
+            let path = parts.uri.path();
+            let response = if parts.method == Method::GET && path.ends_with("/info/refs") {
+                info_refs(state, parts.uri, parts.headers).await
+            } else if parts.method == Method::POST && path.ends_with("/git-upload-pack") {
+                upload_pack(state, parts.uri, parts.headers, body).await
+            } else if parts.method == Method::POST && path.ends_with("/git-receive-pack") {
+                receive_pack(state, parts.uri, parts.headers, body).await
+            } else {
+                StatusCode::NOT_FOUND.into_response()
+            };
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::SHA1;
+    use crate::protocol::bundle_uri::{BundleList, BundleUri};
+    use crate::protocol::http::GIT_PROTOCOL_HEADER;
+    use crate::protocol::memory::MemoryRepository;
+    use crate::protocol::types::{PKT_LINE_DELIM_MARKER, PKT_LINE_END_MARKER, ServiceType};
+    use crate::protocol::utils::{PktLine, add_pkt_line_string, parse_pkt_line};
+    use bytes::{BufMut, BytesMut};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    #[derive(Clone)]
+    struct AllowAll;
+
+    #[async_trait]
+    impl AuthenticationService for AllowAll {
+        async fn authenticate_http(
+            &self,
+            _: &HashMap<String, String>,
+        ) -> Result<(), ProtocolError> {
+            Ok(())
+        }
+
+        async fn authenticate_ssh(&self, _: &str, _: &[u8]) -> Result<(), ProtocolError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_git_http_service() {
+        let repo = MemoryRepository::new();
+        let main = SHA1::from_str(&"1".repeat(40)).unwrap();
+        repo.set_ref("refs/heads/main", main);
+        repo.set_ref("refs/internal/ci", main);
+        let mut service = GitHttpService::new(repo, AllowAll);
+        service.set_configure(|smart| smart.hidden_refs.hide("refs/internal"));
+
+        let request = |method: Method, uri: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = service
+            .call(request(
+                Method::GET,
+                "/repo.git/info/refs?service=git-upload-pack",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = ::axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let advertisement = String::from_utf8_lossy(&body);
+        assert!(advertisement.contains("refs/heads/main"));
+        assert!(!advertisement.contains("refs/internal/ci"));
+
+        let response = service
+            .call(request(Method::GET, "/repo.git/git-upload-pack"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = service
+            .call(request(
+                Method::POST,
+                &format!("/repo.git/{}", ServiceType::ReceivePack),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers()[::axum::http::header::CONTENT_TYPE],
+            "application/x-git-receive-pack-result"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_git_http_service_bundle_uri() {
+        let mut bundle_list = BundleList::new();
+        bundle_list
+            .bundles
+            .push(BundleUri::new("full", "https://cdn.example.com/full.bundle").unwrap());
+        let mut service = GitHttpService::new(MemoryRepository::new(), AllowAll);
+        service.set_configure(move |smart| smart.bundle_list = Some(bundle_list.clone()));
+        let mut call = async |method: Method, uri: &str, body: Body| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(GIT_PROTOCOL_HEADER, "version=2")
+                .body(body)
+                .unwrap();
+            let response = service.call(request).await.unwrap();
+            ::axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap()
+        };
+
+        let info_refs = "/repo.git/info/refs?service=git-upload-pack";
+        let advertisement = call(Method::GET, info_refs, Body::empty()).await;
+        assert!(advertisement.starts_with(b"000eversion 2\n"));
+        assert!(advertisement.ends_with(b"000fbundle-uri\n0000"));
+
+        let mut request = BytesMut::new();
+        add_pkt_line_string(&mut request, "command=bundle-uri\n".to_string());
+        request.put(&PKT_LINE_DELIM_MARKER[..]);
+        request.put(&PKT_LINE_END_MARKER[..]);
+        let request = Body::from(request.freeze());
+        let mut reply = call(Method::POST, "/repo.git/git-upload-pack", request).await;
+        let mut lines = Vec::new();
+        while let Some(PktLine::Data(line)) = parse_pkt_line(&mut reply).unwrap() {
+            lines.push(String::from_utf8(line.to_vec()).unwrap());
+        }
+        let listed = BundleList::parse(lines.iter().map(String::as_str)).unwrap();
+        assert_eq!(listed.bundles[0].uri, "https://cdn.example.com/full.bundle");
+        assert!(reply.is_empty());
+    }
+}
@@ -0,0 +1,509 @@
+//! An in-memory repository, for tests and ephemeral repositories such as previews.
+//!
+//! [`MemoryRepository`] implements [`RepositoryAccess`] on maps of objects and references: the
+//! objects of a push are stored by type, references are updated with compare-and-swap
+//! semantics and recorded in a reflog, and `HEAD` is a symbolic reference to the default
+//! branch. Clones share the same repository, like handles on a real backend.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+
+use crate::hash::SHA1;
+use crate::internal::commit_graph::CommitGraph;
+use crate::internal::object::ObjectTrait;
+use crate::internal::object::blob::Blob;
+use crate::internal::object::commit::Commit;
+use crate::internal::object::tag::Tag;
+use crate::internal::object::tree::{Tree, TreeItemMode};
+use crate::internal::object::types::ObjectType;
+use crate::protocol::core::RepositoryAccess;
+use crate::protocol::pack::PackGenerator;
+use crate::protocol::types::{
+    ProtocolError, ProtocolStream, ReflogEntry, ZERO_ID, check_symbolic_ref,
+};
+
+/// The branch `HEAD` points to in a new repository.
+const DEFAULT_HEAD: &str = "refs/heads/main";
+
+#[derive(Debug, Default)]
+struct State {
+    objects: HashMap<SHA1, (ObjectType, Vec<u8>)>,
+    refs: BTreeMap<String, String>,
+    /// Newest entry last.
+    reflogs: HashMap<String, Vec<ReflogEntry>>,
+    /// Recorded as the identity of reflog entries.
+    reflog_identity: String,
+    head: String,
+    commit_graph: Option<Arc<CommitGraph>>,
+}
+
+/// A repository held in memory, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct MemoryRepository {
+    state: Arc<RwLock<State>>,
+}
+
+impl Default for MemoryRepository {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryRepository {
+    /// An empty repository whose `HEAD` points to `refs/heads/main`.
+    pub fn new() -> Self {
+        let state = State {
+            head: DEFAULT_HEAD.to_string(),
+            ..Default::default()
+        };
+        Self {
+            state: Arc::new(RwLock::new(state)),
+        }
+    }
+
+    /// Store an object of type `obj_type` holding `data`, returning its id.
+    pub fn insert(&self, obj_type: ObjectType, data: Vec<u8>) -> SHA1 {
+        let id = SHA1::from_type_and_data(obj_type, &data);
+        self.insert_with_id(id, obj_type, data);
+        id
+    }
+
+    fn insert_with_id(&self, id: SHA1, obj_type: ObjectType, data: Vec<u8>) {
+        let mut state = self.state.write().unwrap();
+        state.objects.entry(id).or_insert((obj_type, data));
+    }
+
+    /// Store a parsed object, see [`MemoryRepository::insert`].
+    pub fn insert_object(&self, object: &impl ObjectTrait) -> Result<SHA1, ProtocolError> {
+        let data = object.to_data().map_err(|e| {
+            ProtocolError::repository_error(format!("Failed to serialize object: {}", e))
+        })?;
+        Ok(self.insert(object.get_type(), data))
+    }
+
+    /// The type and content of the object `id`.
+    pub fn object(&self, id: &SHA1) -> Option<(ObjectType, Vec<u8>)> {
+        self.state.read().unwrap().objects.get(id).cloned()
+    }
+
+    /// Number of objects in the repository.
+    pub fn object_count(&self) -> usize {
+        self.state.read().unwrap().objects.len()
+    }
+
+    /// The value of the reference `ref_name`; `HEAD` is resolved to its branch.
+    pub fn get_ref(&self, ref_name: &str) -> Option<SHA1> {
+        let state = self.state.read().unwrap();
+        let ref_name = match ref_name {
+            "HEAD" => state.head.as_str(),
+            name => name,
+        };
+        let hash = state.refs.get(ref_name)?;
+        SHA1::from_str(hash).ok()
+    }
+
+    /// Point `ref_name` at `id` unconditionally, without a reflog entry, e.g. to seed a test.
+    pub fn set_ref(&self, ref_name: &str, id: SHA1) {
+        let mut state = self.state.write().unwrap();
+        state.refs.insert(ref_name.to_string(), id.to_string());
+    }
+
+    /// Record `identity`, e.g. `name <email>` of the user pushing, in the reflog entries of the
+    /// next reference updates.
+    pub fn set_reflog_identity(&self, identity: impl Into<String>) {
+        self.state.write().unwrap().reflog_identity = identity.into();
+    }
+
+    /// Make `HEAD` point to the branch `ref_name`, e.g. `refs/heads/master`.
+    pub fn set_head(&self, ref_name: &str) {
+        self.state.write().unwrap().head = ref_name.to_string();
+    }
+
+    /// The branch `HEAD` points to.
+    pub fn head(&self) -> String {
+        self.state.read().unwrap().head.clone()
+    }
+
+    /// Serve `graph` as the commit-graph of the repository, e.g. one built with
+    /// `CommitGraph::from_commits` for the commits it holds.
+    pub fn set_commit_graph(&self, graph: Arc<CommitGraph>) {
+        self.state.write().unwrap().commit_graph = Some(graph);
+    }
+
+    /// The objects of the repository reachable from `tips` through tags, commits and trees,
+    /// submodule commits excepted. Missing objects end the walk of their branch.
+    fn reachable(&self, tips: &[String]) -> Result<HashSet<SHA1>, ProtocolError> {
+        let state = self.state.read().unwrap();
+        let mut queue: Vec<SHA1> = tips
+            .iter()
+            .map(|tip| {
+                SHA1::from_str(tip)
+                    .map_err(|e| ProtocolError::invalid_request(&format!("Invalid hash: {}", e)))
+            })
+            .collect::<Result<_, _>>()?;
+        let mut seen = HashSet::new();
+        while let Some(id) = queue.pop() {
+            if seen.contains(&id) {
+                continue;
+            }
+            let Some((obj_type, data)) = state.objects.get(&id) else {
+                continue;
+            };
+            seen.insert(id);
+            let parse_error = |e: crate::errors::GitError| ProtocolError::corrupt_object(id, e);
+            match obj_type {
+                ObjectType::Commit => {
+                    let commit = Commit::from_bytes(data, id).map_err(parse_error)?;
+                    queue.push(commit.tree_id);
+                    queue.extend(commit.parent_commit_ids);
+                }
+                ObjectType::Tree => {
+                    let tree = <Tree as ObjectTrait>::from_bytes(data, id).map_err(parse_error)?;
+                    let items = tree.tree_items.into_iter();
+                    queue.extend(
+                        items
+                            .filter(|item| item.mode != TreeItemMode::Commit)
+                            .map(|item| item.id),
+                    );
+                }
+                ObjectType::Tag => {
+                    queue.push(Tag::from_bytes(data, id).map_err(parse_error)?.object_hash);
+                }
+                _ => {}
+            }
+        }
+        Ok(seen)
+    }
+}
+
+#[async_trait]
+impl RepositoryAccess for MemoryRepository {
+    /// `HEAD` first when its branch exists, then every reference sorted by name
+    async fn get_repository_refs(&self) -> Result<Vec<(String, String)>, ProtocolError> {
+        let state = self.state.read().unwrap();
+        let head = state.refs.get(&state.head);
+        let head = head.map(|hash| ("HEAD".to_string(), hash.clone()));
+        let refs = state
+            .refs
+            .iter()
+            .map(|(name, hash)| (name.clone(), hash.clone()));
+        Ok(head.into_iter().chain(refs).collect())
+    }
+
+    async fn has_object(&self, object_hash: &str) -> Result<bool, ProtocolError> {
+        let Ok(id) = SHA1::from_str(object_hash) else {
+            return Ok(false);
+        };
+        Ok(self.state.read().unwrap().objects.contains_key(&id))
+    }
+
+    async fn get_object(&self, object_hash: &str) -> Result<Vec<u8>, ProtocolError> {
+        SHA1::from_str(object_hash)
+            .ok()
+            .and_then(|id| self.object(&id))
+            .map(|(_, data)| data)
+            .ok_or_else(|| ProtocolError::ObjectNotFound(object_hash.to_string()))
+    }
+
+    /// Unpack the pack `pack_data` and store its objects
+    async fn store_pack_data(&self, pack_data: &[u8]) -> Result<(), ProtocolError> {
+        let generator = PackGenerator::new(self);
+        let pack = bytes::Bytes::copy_from_slice(pack_data);
+        let (commits, trees, blobs, tags) = generator.unpack_stream(pack).await?;
+        self.handle_pack_objects(commits, trees, blobs, tags, None)
+            .await
+    }
+
+    async fn store_object_stream(
+        &self,
+        object_type: ObjectType,
+        _size: usize,
+        mut data: ProtocolStream,
+    ) -> Result<SHA1, ProtocolError> {
+        let mut content = Vec::new();
+        while let Some(chunk) = data.next().await {
+            content.extend_from_slice(&chunk?);
+        }
+        Ok(self.insert(object_type, content))
+    }
+
+    /// Compare-and-swap: `old_hash` must be the current value, or `None` for a reference that
+    /// does not exist yet; a `new_hash` of zeros deletes the reference
+    async fn update_reference(
+        &self,
+        ref_name: &str,
+        old_hash: Option<&str>,
+        new_hash: &str,
+    ) -> Result<(), ProtocolError> {
+        let mut state = self.state.write().unwrap();
+        let current = state.refs.get(ref_name).map(String::as_str);
+        if current != old_hash {
+            return Err(ProtocolError::InvalidRequest(format!(
+                "{ref_name} is at {}, expected {}",
+                current.unwrap_or(ZERO_ID),
+                old_hash.unwrap_or(ZERO_ID)
+            )));
+        }
+        let entry = ReflogEntry::new(
+            old_hash.unwrap_or(ZERO_ID),
+            new_hash,
+            state.reflog_identity.clone(),
+            "update by push",
+        );
+        if new_hash == ZERO_ID {
+            state.refs.remove(ref_name);
+        } else {
+            state
+                .refs
+                .insert(ref_name.to_string(), new_hash.to_string());
+        }
+        state
+            .reflogs
+            .entry(ref_name.to_string())
+            .or_default()
+            .push(entry);
+        Ok(())
+    }
+
+    async fn get_reflog(&self, ref_name: &str) -> Result<Vec<ReflogEntry>, ProtocolError> {
+        let state = self.state.read().unwrap();
+        let reflog = state.reflogs.get(ref_name).into_iter().flatten();
+        Ok(reflog.rev().cloned().collect())
+    }
+
+    async fn get_objects_for_pack(
+        &self,
+        wants: &[String],
+        haves: &[String],
+    ) -> Result<Vec<String>, ProtocolError> {
+        let haves = self.reachable(haves)?;
+        let mut objects: Vec<SHA1> = self
+            .reachable(wants)?
+            .into_iter()
+            .filter(|id| !haves.contains(id))
+            .collect();
+        objects.sort_unstable();
+        Ok(objects.iter().map(SHA1::to_string).collect())
+    }
+
+    async fn has_default_branch(&self) -> Result<bool, ProtocolError> {
+        let state = self.state.read().unwrap();
+        Ok(state.refs.contains_key(&state.head))
+    }
+
+    /// Only `HEAD` is symbolic
+    async fn get_symbolic_ref(&self, ref_name: &str) -> Result<Option<String>, ProtocolError> {
+        Ok((ref_name == "HEAD").then(|| self.state.read().unwrap().head.clone()))
+    }
+
+    async fn set_symbolic_ref(&self, ref_name: &str, target: &str) -> Result<(), ProtocolError> {
+        check_symbolic_ref(ref_name, target)?;
+        self.set_head(target);
+        Ok(())
+    }
+
+    async fn get_commit_graph(&self) -> Result<Option<Arc<CommitGraph>>, ProtocolError> {
+        Ok(self.state.read().unwrap().commit_graph.clone())
+    }
+
+    async fn store_commit_graph(&self, graph_data: &[u8]) -> Result<(), ProtocolError> {
+        let graph = CommitGraph::from_bytes(graph_data.to_vec()).map_err(|e| {
+            ProtocolError::repository_error(format!("Invalid commit-graph: {e}"))
+        })?;
+        self.set_commit_graph(Arc::new(graph));
+        Ok(())
+    }
+
+    async fn post_receive_hook(&self) -> Result<(), ProtocolError> {
+        Ok(())
+    }
+
+    async fn handle_pack_objects(
+        &self,
+        commits: Vec<Commit>,
+        trees: Vec<Tree>,
+        blobs: Vec<Blob>,
+        tags: Vec<Tag>,
+        _provenance: Option<&crate::protocol::types::ObjectProvenance>,
+    ) -> Result<(), ProtocolError> {
+        let serialize_error = |e: crate::errors::GitError| {
+            ProtocolError::repository_error(format!("Failed to serialize object: {}", e))
+        };
+        for blob in blobs {
+            self.insert_with_id(blob.id, ObjectType::Blob, blob.data);
+        }
+        for tree in trees {
+            let data = tree.to_data().map_err(serialize_error)?;
+            self.insert_with_id(tree.id, ObjectType::Tree, data);
+        }
+        for commit in commits {
+            let data = commit.to_data().map_err(serialize_error)?;
+            self.insert_with_id(commit.id, ObjectType::Commit, data);
+        }
+        for tag in tags {
+            let data = tag.to_data().map_err(serialize_error)?;
+            self.insert_with_id(tag.id, ObjectType::Tag, data);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::object::signature::{Signature, SignatureType};
+    use crate::internal::object::tree::TreeItem;
+    use crate::protocol::core::AuthenticationService;
+    use crate::protocol::smart::SmartProtocol;
+    use crate::protocol::types::{RefCommand, ServiceType, TransportProtocol};
+    use crate::protocol::utils;
+    use bytes::Bytes;
+
+    struct NoAuth;
+
+    #[async_trait]
+    impl AuthenticationService for NoAuth {
+        async fn authenticate_http(
+            &self,
+            _headers: &HashMap<String, String>,
+        ) -> Result<(), ProtocolError> {
+            Ok(())
+        }
+
+        async fn authenticate_ssh(
+            &self,
+            _username: &str,
+            _public_key: &[u8],
+        ) -> Result<(), ProtocolError> {
+            Ok(())
+        }
+    }
+
+    /// A repository with one commit of one file on `refs/heads/main`.
+    fn seeded() -> (MemoryRepository, Commit) {
+        let repo = MemoryRepository::new();
+        let blob = Blob::from_content("hello");
+        let item = TreeItem::new(TreeItemMode::Blob, blob.id, "hello.txt".to_string());
+        let tree = Tree::from_tree_items(vec![item]).unwrap();
+        let signature = |kind| Signature::new(kind, "t".to_string(), "t@example.com".to_string());
+        let commit = Commit::new(
+            signature(SignatureType::Author),
+            signature(SignatureType::Committer),
+            tree.id,
+            vec![],
+            "\ninit",
+        );
+        for id in [
+            repo.insert_object(&blob).unwrap(),
+            repo.insert_object(&tree).unwrap(),
+            repo.insert_object(&commit).unwrap(),
+        ] {
+            assert!(repo.object(&id).is_some());
+        }
+        repo.set_ref("refs/heads/main", commit.id);
+        (repo, commit)
+    }
+
+    #[tokio::test]
+    async fn test_memory_repository_refs() {
+        let (repo, commit) = seeded();
+        let main = commit.id.to_string();
+        assert_eq!(repo.get_ref("HEAD"), Some(commit.id));
+        assert!(repo.has_default_branch().await.unwrap());
+        assert_eq!(
+            repo.get_repository_refs().await.unwrap(),
+            vec![
+                ("HEAD".to_string(), main.clone()),
+                ("refs/heads/main".to_string(), main.clone())
+            ]
+        );
+        assert_eq!(
+            repo.get_objects_for_pack(std::slice::from_ref(&main), &[])
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+        assert!(repo.commit_exists(&main).await.unwrap());
+
+        // Updates are compare-and-swap
+        let err = repo
+            .update_reference("refs/heads/dev", Some(&main), &main)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("refs/heads/dev is at 0000"),
+            "{err}"
+        );
+        repo.set_reflog_identity("Pusher <pusher@example.com>");
+        repo.update_reference("refs/heads/dev", None, &main)
+            .await
+            .unwrap();
+        assert!(
+            repo.update_reference("refs/heads/dev", None, &main)
+                .await
+                .is_err()
+        );
+        repo.update_reference("refs/heads/dev", Some(&main), ZERO_ID)
+            .await
+            .unwrap();
+        assert_eq!(repo.get_ref("refs/heads/dev"), None);
+        let reflog = repo.get_reflog("refs/heads/dev").await.unwrap();
+        let values: Vec<(&str, &str)> = reflog
+            .iter()
+            .map(|e| (e.old_hash.as_str(), e.new_hash.as_str()))
+            .collect();
+        assert_eq!(
+            values,
+            vec![(main.as_str(), ZERO_ID), (ZERO_ID, main.as_str())]
+        );
+        assert_eq!(reflog[0].identity, "Pusher <pusher@example.com>");
+
+        repo.set_head("refs/heads/master");
+        assert!(!repo.has_default_branch().await.unwrap());
+        assert_eq!(repo.get_repository_refs().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_memory_repository_push() {
+        let (origin, commit) = seeded();
+        let generator = PackGenerator::new(&origin);
+        let mut pack = Vec::new();
+        let mut chunks = generator
+            .generate_full_pack(vec![commit.id.to_string()])
+            .await
+            .unwrap();
+        while let Some(chunk) = chunks.next().await {
+            pack.extend(chunk);
+        }
+
+        let repo = MemoryRepository::new();
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo.clone(), NoAuth);
+        smart.command_list.push(RefCommand::new(
+            ZERO_ID.to_string(),
+            commit.id.to_string(),
+            "refs/heads/main".to_string(),
+        ));
+        let request = Box::pin(futures::stream::once(async { Ok(Bytes::from(pack)) }));
+        let mut out = smart.git_receive_pack_stream(request).await.unwrap();
+        assert_eq!(utils::read_pkt_line(&mut out).1, "unpack ok\n");
+        assert_eq!(utils::read_pkt_line(&mut out).1, "ok refs/heads/main");
+
+        assert_eq!(repo.object_count(), 3);
+        assert_eq!(repo.get_ref("HEAD"), Some(commit.id));
+        assert_eq!(repo.get_reflog("refs/heads/main").await.unwrap().len(), 1);
+        let advertised = smart.git_info_refs(ServiceType::UploadPack).await.unwrap();
+        let advertised = String::from_utf8_lossy(&advertised);
+        assert!(
+            advertised.contains(&format!("{} HEAD\0", commit.id)),
+            "{advertised}"
+        );
+
+        // The pushed objects are served back
+        let tree = repo.get_tree(&commit.tree_id.to_string()).await.unwrap();
+        assert_eq!(tree.tree_items[0].name, "hello.txt");
+    }
+}
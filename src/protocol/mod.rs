@@ -3,14 +3,30 @@
 /// This module provides a clean, minimal, and transport-agnostic Git smart protocol implementation.
 /// It abstracts away the complexities of different transport layers (HTTP, SSH) and provides
 /// a unified interface for Git operations.
+pub mod alternates;
+pub mod bundle_uri;
+pub mod codec;
 pub mod core;
+pub mod daemon;
+pub mod git_dir;
+pub mod hidden_refs;
 pub mod http;
+pub mod memory;
+pub mod object_store;
 pub mod pack;
+pub mod patch;
+pub mod policy;
+pub mod recovery;
 pub mod smart;
 pub mod ssh;
+pub mod trace;
 pub mod types;
+pub mod upload_archive;
 pub mod utils;
 
 // Re-export main interfaces
-pub use core::{AuthenticationService, GitProtocol, RepositoryAccess};
+pub use core::{
+    AuthenticationService, AuthorizationService, GitProtocol, RefOperation, RepositoryAccess,
+};
+pub use memory::MemoryRepository;
 pub use types::*;
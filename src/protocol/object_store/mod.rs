@@ -0,0 +1,1223 @@
+//! A repository kept in an object store, such as an S3 bucket.
+//!
+//! [`ObjectStoreRepository`] implements [`RepositoryAccess`] over the keys of an
+//! [`ObjectStorage`], so git can be served without a POSIX filesystem. Under its prefix the
+//! repository is laid out like a `.git` directory:
+//!
+//! - `objects/<xx>/<38 hex digits>`: the loose objects of pushes, in the loose object format;
+//! - `objects/pack/pack-<hash>.{pack,idx,keep,bitmap}`: packs kept by receive-pack or a repack;
+//! - `objects/pack/multi-pack-index`: the multi-pack-index of the last repack;
+//! - `refs.json`: the references and `HEAD`, a small document updated with compare-and-swap
+//!   writes, so concurrent pushes to the same repository can't lose an update.
+//!
+//! The `s3` feature provides [`s3::S3Storage`], an [`ObjectStorage`] over the S3 API of AWS and
+//! the compatible stores; [`MemoryStorage`] keeps the keys in memory, e.g. for tests.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Cursor;
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::hash::SHA1;
+use crate::internal::loose::{DEFAULT_LOOSE_COMPRESSION, decode_loose_object, encode_loose_object};
+use crate::internal::object::ObjectTrait;
+use crate::internal::object::blob::Blob;
+use crate::internal::object::commit::Commit;
+use crate::internal::object::tag::Tag;
+use crate::internal::object::tree::{Tree, TreeItemMode};
+use crate::internal::object::types::ObjectType;
+use crate::internal::pack::base_cache::DeltaBaseCache;
+use crate::internal::pack::cruft::CruftPack;
+use crate::internal::pack::idx::{PackData, PackIndex};
+use crate::protocol::core::RepositoryAccess;
+use crate::protocol::pack::PackGenerator;
+use crate::protocol::types::{
+    ObjectProvenance, PackInfo, ProtocolError, RefUpdate, ReflogEntry, ZERO_ID, check_symbolic_ref,
+};
+
+#[cfg(feature = "s3")]
+pub mod s3;
+
+/// The branch `HEAD` points to in a new repository.
+const DEFAULT_HEAD: &str = "refs/heads/main";
+
+/// The key of the references document, under the prefix of the repository.
+const REFS_KEY: &str = "refs.json";
+
+/// How many times a reference update is retried when another writer changed the references
+/// document in between.
+const MAX_REF_UPDATE_ATTEMPTS: usize = 10;
+
+/// How many objects of a push are uploaded at the same time.
+const CONCURRENT_UPLOADS: usize = 16;
+
+/// The condition an [`ObjectStorage::put`] is made on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PutCondition {
+    /// Always write.
+    None,
+    /// Only create the key, like `If-None-Match: *`.
+    IfAbsent,
+    /// Only replace the value whose entity tag is given, like `If-Match`.
+    IfMatch(String),
+}
+
+/// The value of a key with its entity tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredObject {
+    pub data: Vec<u8>,
+    pub etag: String,
+}
+
+/// A flat key-value object store with conditional writes, such as an S3 bucket.
+#[async_trait]
+pub trait ObjectStorage: Send + Sync + Clone {
+    /// The value of `key`, `None` if it doesn't exist.
+    async fn get(&self, key: &str) -> Result<Option<StoredObject>, ProtocolError>;
+
+    /// The bytes `range` of the value of `key`, fewer when the value ends first, `None` if it
+    /// doesn't exist. `u64::MAX` as the end reads to the end of the value.
+    ///
+    /// The default reads the whole value; stores that can read part of one, like S3 with a
+    /// `Range` header, should override it, packed objects being read this way.
+    async fn get_range(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> Result<Option<Vec<u8>>, ProtocolError> {
+        Ok(self
+            .get(key)
+            .await?
+            .map(|stored| slice_range(&stored.data, range)))
+    }
+
+    /// Whether `key` exists, without reading its value.
+    async fn exists(&self, key: &str) -> Result<bool, ProtocolError>;
+
+    /// Write `data` to `key` if `condition` holds. Returns false, leaving the key untouched,
+    /// when it doesn't.
+    async fn put(
+        &self,
+        key: &str,
+        data: Vec<u8>,
+        condition: PutCondition,
+    ) -> Result<bool, ProtocolError>;
+
+    /// Delete `key`. Missing keys are ignored.
+    async fn delete(&self, key: &str) -> Result<(), ProtocolError>;
+
+    /// The keys starting with `prefix`, sorted.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, ProtocolError>;
+}
+
+/// The bytes `range` of `data`, fewer when `data` ends first.
+pub(crate) fn slice_range(data: &[u8], range: Range<u64>) -> Vec<u8> {
+    let end = range.end.min(data.len() as u64);
+    let start = range.start.min(end);
+    data[start as usize..end as usize].to_vec()
+}
+
+/// An [`ObjectStorage`] held in memory. Clones share the same keys.
+///
+/// Entity tags are content hashes, like the ETag of an S3 object written in one go.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    keys: Arc<RwLock<BTreeMap<String, StoredObject>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl ObjectStorage for MemoryStorage {
+    async fn get(&self, key: &str) -> Result<Option<StoredObject>, ProtocolError> {
+        Ok(self.keys.read().unwrap().get(key).cloned())
+    }
+
+    async fn get_range(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> Result<Option<Vec<u8>>, ProtocolError> {
+        let keys = self.keys.read().unwrap();
+        Ok(keys.get(key).map(|stored| slice_range(&stored.data, range)))
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, ProtocolError> {
+        Ok(self.keys.read().unwrap().contains_key(key))
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        data: Vec<u8>,
+        condition: PutCondition,
+    ) -> Result<bool, ProtocolError> {
+        let mut keys = self.keys.write().unwrap();
+        let current = keys.get(key).map(|stored| stored.etag.as_str());
+        let holds = match &condition {
+            PutCondition::None => true,
+            PutCondition::IfAbsent => current.is_none(),
+            PutCondition::IfMatch(etag) => current == Some(etag.as_str()),
+        };
+        if holds {
+            let etag = SHA1::new(&data).to_string();
+            keys.insert(key.to_string(), StoredObject { data, etag });
+        }
+        Ok(holds)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), ProtocolError> {
+        self.keys.write().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, ProtocolError> {
+        let keys = self.keys.read().unwrap();
+        Ok(keys
+            .range(prefix.to_string()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+}
+
+/// The content of `refs.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RefsDocument {
+    head: String,
+    refs: BTreeMap<String, String>,
+}
+
+impl Default for RefsDocument {
+    fn default() -> Self {
+        Self {
+            head: DEFAULT_HEAD.to_string(),
+            refs: BTreeMap::new(),
+        }
+    }
+}
+
+/// The index of a stored pack, with the offsets of its entries in order.
+#[derive(Debug)]
+struct StoredPack {
+    idx: PackIndex,
+    offsets: Vec<u64>,
+}
+
+impl StoredPack {
+    fn new(idx: PackIndex) -> Self {
+        let mut offsets: Vec<u64> = idx.entries().map(|(_, offset)| offset).collect();
+        offsets.sort_unstable();
+        Self { idx, offsets }
+    }
+
+    /// The bytes of the entry at `offset`: up to the next entry, or to the end of the pack,
+    /// trailer included, for the last one.
+    fn entry_range(&self, offset: u64) -> Range<u64> {
+        let next = self.offsets.partition_point(|&start| start <= offset);
+        offset..self.offsets.get(next).copied().unwrap_or(u64::MAX)
+    }
+}
+
+/// The entries of a pack read so far, by offset, for [`PackIndex::read_object_from`].
+#[derive(Default)]
+struct PackEntries {
+    entries: BTreeMap<u64, Vec<u8>>,
+    /// Whether the chain read ends at a base kept in the delta base cache.
+    cached_base: bool,
+}
+
+impl PackData for PackEntries {
+    type Reader<'a> = Cursor<&'a [u8]>;
+
+    fn len(&self) -> u64 {
+        self.entries
+            .iter()
+            .next_back()
+            .map_or(0, |(offset, entry)| offset + entry.len() as u64)
+    }
+
+    /// Reads nothing at an offset outside of the entries read
+    fn reader_at(&self, offset: u64) -> Self::Reader<'_> {
+        let entry = self
+            .entries
+            .range(..=offset)
+            .next_back()
+            .and_then(|(start, entry)| entry.get((offset - start) as usize..));
+        Cursor::new(entry.unwrap_or_default())
+    }
+}
+
+/// A repository stored in an [`ObjectStorage`], see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct ObjectStoreRepository<S> {
+    storage: S,
+    prefix: String,
+    compression: u32,
+    /// The indexes of the packs seen so far, by pack hash.
+    packs: Arc<RwLock<HashMap<SHA1, Arc<StoredPack>>>>,
+    delta_bases: DeltaBaseCache,
+    reflog_identity: String,
+}
+
+impl<S: ObjectStorage> ObjectStoreRepository<S> {
+    /// The repository whose keys start with `prefix`, e.g. `repos/<owner>/<name>/`. A `/` is
+    /// appended to a prefix that doesn't end with one.
+    pub fn new(storage: S, prefix: &str) -> Self {
+        let mut prefix = prefix.to_string();
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        Self {
+            storage,
+            prefix,
+            compression: DEFAULT_LOOSE_COMPRESSION,
+            packs: Arc::new(RwLock::new(HashMap::new())),
+            delta_bases: DeltaBaseCache::default(),
+            reflog_identity: String::new(),
+        }
+    }
+
+    /// Set the zlib level (0-9) of the loose objects written from now on.
+    pub fn set_compression_level(&mut self, level: u32) {
+        self.compression = level.min(9);
+    }
+
+    /// Resolve packed deltas from `cache`, e.g. one shared by every repository of a server,
+    /// instead of a cache of the default size per repository.
+    pub fn set_delta_base_cache(&mut self, cache: DeltaBaseCache) {
+        self.delta_bases = cache;
+    }
+
+    /// Record `identity`, e.g. `name <email>` of the user pushing, in the reflog entries of the
+    /// next reference updates.
+    pub fn set_reflog_identity(&mut self, identity: impl Into<String>) {
+        self.reflog_identity = identity.into();
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// The key of the loose object `id`.
+    pub fn loose_key(&self, id: &SHA1) -> String {
+        let hex = id.to_string();
+        format!("{}objects/{}/{}", self.prefix, &hex[..2], &hex[2..])
+    }
+
+    /// The key of the `extension` file of the pack `pack_hash`, e.g. `idx`.
+    pub fn pack_key(&self, pack_hash: &SHA1, extension: &str) -> String {
+        format!("{}objects/pack/pack-{pack_hash}.{extension}", self.prefix)
+    }
+
+    /// The key of the reflog of `ref_name`, in the format of git's `logs/<ref>` files.
+    pub fn reflog_key(&self, ref_name: &str) -> String {
+        format!("{}logs/{ref_name}", self.prefix)
+    }
+
+    fn pack_dir(&self) -> String {
+        format!("{}objects/pack/", self.prefix)
+    }
+
+    /// Make `HEAD` point to the branch `ref_name`, e.g. `refs/heads/master`.
+    pub async fn set_head(&self, ref_name: &str) -> Result<(), ProtocolError> {
+        self.update_refs_document(|doc| {
+            doc.head = ref_name.to_string();
+            Ok(())
+        })
+        .await
+    }
+
+    /// The references document and its entity tag, `None` before the first update.
+    async fn read_refs_document(&self) -> Result<(RefsDocument, Option<String>), ProtocolError> {
+        let key = format!("{}{REFS_KEY}", self.prefix);
+        match self.storage.get(&key).await? {
+            Some(stored) => {
+                let doc = serde_json::from_slice(&stored.data).map_err(|e| {
+                    ProtocolError::repository_error(format!("Corrupt {key}: {}", e))
+                })?;
+                Ok((doc, Some(stored.etag)))
+            }
+            None => Ok((RefsDocument::default(), None)),
+        }
+    }
+
+    /// Apply `update` to the references document and write it back if unchanged in between,
+    /// starting over with a fresh copy otherwise.
+    async fn update_refs_document<T>(
+        &self,
+        mut update: impl FnMut(&mut RefsDocument) -> Result<T, ProtocolError> + Send,
+    ) -> Result<T, ProtocolError> {
+        let key = format!("{}{REFS_KEY}", self.prefix);
+        for _ in 0..MAX_REF_UPDATE_ATTEMPTS {
+            let (mut doc, etag) = self.read_refs_document().await?;
+            let result = update(&mut doc)?;
+            let data = serde_json::to_vec(&doc).map_err(|e| {
+                ProtocolError::repository_error(format!("Failed to serialize {key}: {}", e))
+            })?;
+            let condition = etag.map_or(PutCondition::IfAbsent, PutCondition::IfMatch);
+            if self.storage.put(&key, data, condition).await? {
+                return Ok(result);
+            }
+        }
+        Err(ProtocolError::repository_error(format!(
+            "{key} kept changing during {MAX_REF_UPDATE_ATTEMPTS} update attempts"
+        )))
+    }
+
+    /// Append an entry for the update `update` to the reflog of its reference.
+    async fn append_reflog(&self, update: &RefUpdate) -> Result<(), ProtocolError> {
+        let entry = ReflogEntry::new(
+            update.old_hash.as_deref().unwrap_or(ZERO_ID),
+            update.new_hash.as_str(),
+            self.reflog_identity.as_str(),
+            "update by push",
+        );
+        let key = self.reflog_key(&update.ref_name);
+        for _ in 0..MAX_REF_UPDATE_ATTEMPTS {
+            let (mut data, condition) = match self.storage.get(&key).await? {
+                Some(stored) => (stored.data, PutCondition::IfMatch(stored.etag)),
+                None => (Vec::new(), PutCondition::IfAbsent),
+            };
+            data.extend_from_slice(entry.to_line().as_bytes());
+            data.push(b'\n');
+            if self.storage.put(&key, data, condition).await? {
+                return Ok(());
+            }
+        }
+        Err(ProtocolError::repository_error(format!(
+            "{key} kept changing during {MAX_REF_UPDATE_ATTEMPTS} update attempts"
+        )))
+    }
+
+    /// Load the indexes of the packs stored since the last call, forgetting removed packs.
+    async fn refresh_packs(&self) -> Result<(), ProtocolError> {
+        let mut stored = HashSet::new();
+        for key in self.storage.list(&self.pack_dir()).await? {
+            let Some(hash) = key
+                .strip_prefix(&self.pack_dir())
+                .and_then(|name| name.strip_prefix("pack-"))
+                .and_then(|name| name.strip_suffix(".idx"))
+                .and_then(|hash| SHA1::from_str(hash).ok())
+            else {
+                continue;
+            };
+            stored.insert(hash);
+            if self.packs.read().unwrap().contains_key(&hash) {
+                continue;
+            }
+            let Some(idx) = self.storage.get(&key).await? else {
+                continue;
+            };
+            let idx = PackIndex::from_bytes(idx.data)
+                .map_err(|e| ProtocolError::pack_corrupt(format!("Corrupt {key}: {}", e)))?;
+            let pack = Arc::new(StoredPack::new(idx));
+            self.packs.write().unwrap().insert(hash, pack);
+        }
+        self.packs
+            .write()
+            .unwrap()
+            .retain(|hash, _| stored.contains(hash));
+        Ok(())
+    }
+
+    /// The pack holding `id` and its index, looking for packs stored by other writers when
+    /// none of the known ones has it.
+    async fn find_packed(
+        &self,
+        id: &SHA1,
+    ) -> Result<Option<(SHA1, Arc<StoredPack>)>, ProtocolError> {
+        let find = || {
+            let packs = self.packs.read().unwrap();
+            packs
+                .iter()
+                .find(|(_, pack)| pack.idx.contains(id))
+                .map(|(hash, pack)| (*hash, pack.clone()))
+        };
+        if let Some(found) = find() {
+            return Ok(Some(found));
+        }
+        self.refresh_packs().await?;
+        Ok(find())
+    }
+
+    /// The type and content of the object `id`, loose or packed.
+    async fn read(&self, id: &SHA1) -> Result<Option<(ObjectType, Vec<u8>)>, ProtocolError> {
+        let key = self.loose_key(id);
+        if let Some(stored) = self.storage.get(&key).await? {
+            let object = decode_loose_object(&stored.data)
+                .map_err(|e| ProtocolError::corrupt_object(*id, format!("{key}: {e}")))?;
+            return Ok(Some(object));
+        }
+        let Some((pack_hash, pack)) = self.find_packed(id).await? else {
+            return Ok(None);
+        };
+        let key = self.pack_key(&pack_hash, "pack");
+        let read = |entries: &PackEntries| {
+            pack.idx
+                .read_object_from(entries, id, Some(&self.delta_bases))
+                .map_err(|e| {
+                    ProtocolError::pack_corrupt(format!("Failed to read {id} from {key}: {}", e))
+                })
+        };
+        let entries = self.read_delta_chain(&key, &pack, id, true).await?;
+        match read(&entries) {
+            // A cached base evicted in between leaves a gap in the chain
+            Err(_) if entries.cached_base => {
+                read(&self.read_delta_chain(&key, &pack, id, false).await?)
+            }
+            result => result,
+        }
+    }
+
+    /// The entries of the delta chain of `id` in `pack`, the pack stored at `key`, each read
+    /// with a ranged request, down to its base object or, with `cached`, to the first base
+    /// found in the delta base cache.
+    async fn read_delta_chain(
+        &self,
+        key: &str,
+        pack: &StoredPack,
+        id: &SHA1,
+        cached: bool,
+    ) -> Result<PackEntries, ProtocolError> {
+        let invalid =
+            |e| ProtocolError::pack_corrupt(format!("Failed to read {id} from {key}: {}", e));
+        let pack_hash = pack.idx.pack_hash();
+        let mut entries = PackEntries::default();
+        let mut offset = pack.idx.find_offset(id);
+        while let Some(entry_offset) = offset {
+            if cached && self.delta_bases.get(&pack_hash, entry_offset).is_some() {
+                entries.cached_base = true;
+                break;
+            }
+            if entries.entries.contains_key(&entry_offset) {
+                break;
+            }
+            let entry = self
+                .storage
+                .get_range(key, pack.entry_range(entry_offset))
+                .await?
+                .ok_or_else(|| ProtocolError::pack_corrupt(format!("{key} is missing its pack")))?;
+            offset = pack
+                .idx
+                .delta_base_offset(&entry, entry_offset)
+                .map_err(invalid)?;
+            entries.entries.insert(entry_offset, entry);
+        }
+        Ok(entries)
+    }
+
+    /// Store the objects `objects` loose, a few at a time.
+    async fn write_loose(
+        &self,
+        objects: Vec<(SHA1, ObjectType, Vec<u8>)>,
+    ) -> Result<(), ProtocolError> {
+        futures::stream::iter(objects)
+            .map(|(id, obj_type, data)| async move {
+                let file = encode_loose_object(obj_type, &data, self.compression);
+                self.storage
+                    .put(&self.loose_key(&id), file, PutCondition::None)
+                    .await
+            })
+            .buffer_unordered(CONCURRENT_UPLOADS)
+            .try_collect::<Vec<_>>()
+            .await?;
+        Ok(())
+    }
+
+    /// The objects reachable from `tips` through tags, commits and trees, submodule commits
+    /// excepted. Missing objects end the walk of their branch.
+    async fn reachable(&self, tips: &[String]) -> Result<HashSet<SHA1>, ProtocolError> {
+        let mut queue: Vec<SHA1> = tips
+            .iter()
+            .map(|tip| {
+                SHA1::from_str(tip)
+                    .map_err(|e| ProtocolError::invalid_request(&format!("Invalid hash: {}", e)))
+            })
+            .collect::<Result<_, _>>()?;
+        let mut seen = HashSet::new();
+        while let Some(id) = queue.pop() {
+            if seen.contains(&id) {
+                continue;
+            }
+            let Some((obj_type, data)) = self.read(&id).await? else {
+                continue;
+            };
+            seen.insert(id);
+            let parse_error = |e: crate::errors::GitError| ProtocolError::corrupt_object(id, e);
+            match obj_type {
+                ObjectType::Commit => {
+                    let commit = Commit::from_bytes(&data, id).map_err(parse_error)?;
+                    queue.push(commit.tree_id);
+                    queue.extend(commit.parent_commit_ids);
+                }
+                ObjectType::Tree => {
+                    let tree = <Tree as ObjectTrait>::from_bytes(&data, id).map_err(parse_error)?;
+                    let items = tree.tree_items.into_iter();
+                    queue.extend(
+                        items
+                            .filter(|item| item.mode != TreeItemMode::Commit)
+                            .map(|item| item.id),
+                    );
+                }
+                ObjectType::Tag => {
+                    queue.push(Tag::from_bytes(&data, id).map_err(parse_error)?.object_hash);
+                }
+                _ => {}
+            }
+        }
+        Ok(seen)
+    }
+}
+
+/// Apply `update` to `doc`, compare-and-swap: `old_hash` must be the current value, or `None`
+/// for a reference that does not exist yet; a `new_hash` of zeros deletes the reference.
+fn apply_update(doc: &mut RefsDocument, update: &RefUpdate) -> Result<(), ProtocolError> {
+    let current = doc.refs.get(&update.ref_name).map(String::as_str);
+    if current != update.old_hash.as_deref() {
+        return Err(ProtocolError::InvalidRequest(format!(
+            "{} is at {}, expected {}",
+            update.ref_name,
+            current.unwrap_or(ZERO_ID),
+            update.old_hash.as_deref().unwrap_or(ZERO_ID)
+        )));
+    }
+    if update.new_hash == ZERO_ID {
+        doc.refs.remove(&update.ref_name);
+    } else {
+        doc.refs
+            .insert(update.ref_name.clone(), update.new_hash.clone());
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl<S: ObjectStorage> RepositoryAccess for ObjectStoreRepository<S> {
+    /// `HEAD` first when its branch exists, then every reference sorted by name
+    async fn get_repository_refs(&self) -> Result<Vec<(String, String)>, ProtocolError> {
+        let (doc, _) = self.read_refs_document().await?;
+        let head = doc.refs.get(&doc.head);
+        let head = head.map(|hash| ("HEAD".to_string(), hash.clone()));
+        Ok(head.into_iter().chain(doc.refs).collect())
+    }
+
+    async fn has_object(&self, object_hash: &str) -> Result<bool, ProtocolError> {
+        let Ok(id) = SHA1::from_str(object_hash) else {
+            return Ok(false);
+        };
+        Ok(self.storage.exists(&self.loose_key(&id)).await?
+            || self.find_packed(&id).await?.is_some())
+    }
+
+    async fn get_object(&self, object_hash: &str) -> Result<Vec<u8>, ProtocolError> {
+        let not_found = || ProtocolError::ObjectNotFound(object_hash.to_string());
+        let id = SHA1::from_str(object_hash).map_err(|_| not_found())?;
+        let (_, data) = self.read(&id).await?.ok_or_else(not_found)?;
+        Ok(data)
+    }
+
+    /// Unpack the pack `pack_data` and store its objects
+    async fn store_pack_data(&self, pack_data: &[u8]) -> Result<(), ProtocolError> {
+        let generator = PackGenerator::new(self);
+        let pack = bytes::Bytes::copy_from_slice(pack_data);
+        let (commits, trees, blobs, tags) = generator.unpack_stream(pack).await?;
+        self.handle_pack_objects(commits, trees, blobs, tags, None)
+            .await
+    }
+
+    /// Store the `.pack` before the `.idx`, so a listed index always has its pack
+    async fn store_pack_index(
+        &self,
+        pack_data: &[u8],
+        idx_data: &[u8],
+    ) -> Result<(), ProtocolError> {
+        let idx = PackIndex::from_bytes(idx_data.to_vec())
+            .map_err(|e| ProtocolError::pack_corrupt(format!("Invalid pack index: {}", e)))?;
+        let pack_hash = idx.pack_hash();
+        self.storage
+            .put(
+                &self.pack_key(&pack_hash, "pack"),
+                pack_data.to_vec(),
+                PutCondition::None,
+            )
+            .await?;
+        self.storage
+            .put(
+                &self.pack_key(&pack_hash, "idx"),
+                idx_data.to_vec(),
+                PutCondition::None,
+            )
+            .await?;
+        let pack = Arc::new(StoredPack::new(idx));
+        self.packs.write().unwrap().insert(pack_hash, pack);
+        Ok(())
+    }
+
+    /// Write the `.keep` first, so a concurrent repack never sees the pack unkept
+    async fn store_kept_pack(
+        &self,
+        pack_data: &[u8],
+        idx_data: &[u8],
+        keep: &str,
+    ) -> Result<(), ProtocolError> {
+        let pack_hash = PackIndex::from_bytes(idx_data.to_vec())
+            .map_err(|e| ProtocolError::pack_corrupt(format!("Invalid pack index: {}", e)))?
+            .pack_hash();
+        let keep_key = self.pack_key(&pack_hash, "keep");
+        self.storage
+            .put(&keep_key, keep.as_bytes().to_vec(), PutCondition::None)
+            .await?;
+        self.store_pack_index(pack_data, idx_data).await
+    }
+
+    async fn release_pack_keep(&self, pack_hash: &SHA1, keep: &str) -> Result<(), ProtocolError> {
+        let key = self.pack_key(pack_hash, "keep");
+        if let Some(stored) = self.storage.get(&key).await?
+            && stored.data == keep.as_bytes()
+        {
+            self.storage.delete(&key).await?;
+        }
+        Ok(())
+    }
+
+    async fn list_packs(&self) -> Result<Vec<PackInfo>, ProtocolError> {
+        let keys = self.storage.list(&self.pack_dir()).await?;
+        let names: HashSet<&str> = keys.iter().map(String::as_str).collect();
+        let mut packs = Vec::new();
+        for key in &keys {
+            let Some(pack_hash) = key
+                .strip_prefix(&self.pack_dir())
+                .and_then(|name| name.strip_prefix("pack-"))
+                .and_then(|name| name.strip_suffix(".pack"))
+                .and_then(|hash| SHA1::from_str(hash).ok())
+            else {
+                continue;
+            };
+            let kept = names.contains(self.pack_key(&pack_hash, "keep").as_str());
+            packs.push(PackInfo { pack_hash, kept });
+        }
+        Ok(packs)
+    }
+
+    async fn get_pack_index(&self, pack_hash: &SHA1) -> Result<Option<Vec<u8>>, ProtocolError> {
+        let stored = self.storage.get(&self.pack_key(pack_hash, "idx")).await?;
+        Ok(stored.map(|stored| stored.data))
+    }
+
+    /// The `.mtimes` goes before the pack, so a listed cruft pack always has its times
+    async fn store_cruft_pack(&self, cruft: &CruftPack) -> Result<(), ProtocolError> {
+        let key = self.pack_key(&cruft.pack_hash, "mtimes");
+        self.storage
+            .put(&key, cruft.mtimes.as_bytes().to_vec(), PutCondition::None)
+            .await?;
+        self.store_pack_index(&cruft.pack, &cruft.idx).await
+    }
+
+    async fn get_pack_mtimes(&self, pack_hash: &SHA1) -> Result<Option<Vec<u8>>, ProtocolError> {
+        let stored = self
+            .storage
+            .get(&self.pack_key(pack_hash, "mtimes"))
+            .await?;
+        Ok(stored.map(|stored| stored.data))
+    }
+
+    async fn get_pack_data(&self, pack_hash: &SHA1) -> Result<Option<Vec<u8>>, ProtocolError> {
+        let stored = self.storage.get(&self.pack_key(pack_hash, "pack")).await?;
+        Ok(stored.map(|stored| stored.data))
+    }
+
+    async fn store_pack_bitmap(
+        &self,
+        pack_hash: &SHA1,
+        bitmap_data: &[u8],
+    ) -> Result<(), ProtocolError> {
+        let key = self.pack_key(pack_hash, "bitmap");
+        self.storage
+            .put(&key, bitmap_data.to_vec(), PutCondition::None)
+            .await?;
+        Ok(())
+    }
+
+    async fn store_multi_pack_index(&self, midx_data: &[u8]) -> Result<(), ProtocolError> {
+        let key = format!("{}multi-pack-index", self.pack_dir());
+        self.storage
+            .put(&key, midx_data.to_vec(), PutCondition::None)
+            .await?;
+        Ok(())
+    }
+
+    /// The `.idx` of a removed pack goes first, so readers never find an index without its pack
+    async fn remove_repacked(&self, packs: &[SHA1], objects: &[SHA1]) -> Result<(), ProtocolError> {
+        for pack_hash in packs {
+            for extension in ["idx", "pack", "bitmap", "mtimes"] {
+                self.storage
+                    .delete(&self.pack_key(pack_hash, extension))
+                    .await?;
+            }
+            self.packs.write().unwrap().remove(pack_hash);
+        }
+        for id in objects {
+            self.storage.delete(&self.loose_key(id)).await?;
+        }
+        Ok(())
+    }
+
+    async fn store_object_stream(
+        &self,
+        object_type: ObjectType,
+        _size: usize,
+        mut data: crate::protocol::types::ProtocolStream,
+    ) -> Result<SHA1, ProtocolError> {
+        let mut content = Vec::new();
+        while let Some(chunk) = data.next().await {
+            content.extend_from_slice(&chunk?);
+        }
+        let id = SHA1::from_type_and_data(object_type, &content);
+        self.write_loose(vec![(id, object_type, content)]).await?;
+        Ok(id)
+    }
+
+    async fn update_reference(
+        &self,
+        ref_name: &str,
+        old_hash: Option<&str>,
+        new_hash: &str,
+    ) -> Result<(), ProtocolError> {
+        let update = RefUpdate {
+            ref_name: ref_name.to_string(),
+            old_hash: old_hash.map(str::to_string),
+            new_hash: new_hash.to_string(),
+        };
+        self.update_refs_document(|doc| apply_update(doc, &update))
+            .await?;
+        self.append_reflog(&update).await
+    }
+
+    /// The whole batch is written in a single update of `refs.json`
+    async fn update_references(
+        &self,
+        updates: &[RefUpdate],
+    ) -> Result<Vec<Result<(), ProtocolError>>, ProtocolError> {
+        let results: Vec<Result<(), ProtocolError>> = self
+            .update_refs_document(|doc| {
+                Ok(updates
+                    .iter()
+                    .map(|update| apply_update(doc, update))
+                    .collect())
+            })
+            .await?;
+        for (update, result) in updates.iter().zip(&results) {
+            if result.is_ok() {
+                self.append_reflog(update).await?;
+            }
+        }
+        Ok(results)
+    }
+
+    /// Read from `logs/<ref>`; the reflog is kept when the reference is deleted
+    async fn get_reflog(&self, ref_name: &str) -> Result<Vec<ReflogEntry>, ProtocolError> {
+        let key = self.reflog_key(ref_name);
+        let Some(stored) = self.storage.get(&key).await? else {
+            return Ok(Vec::new());
+        };
+        let content = String::from_utf8_lossy(&stored.data);
+        let mut entries = content
+            .lines()
+            .map(|line| {
+                ReflogEntry::from_line(line).ok_or_else(|| {
+                    ProtocolError::repository_error(format!("Corrupt {key} line `{line}`"))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.reverse();
+        Ok(entries)
+    }
+
+    async fn get_objects_for_pack(
+        &self,
+        wants: &[String],
+        haves: &[String],
+    ) -> Result<Vec<String>, ProtocolError> {
+        let haves = self.reachable(haves).await?;
+        let mut objects: Vec<SHA1> = self
+            .reachable(wants)
+            .await?
+            .into_iter()
+            .filter(|id| !haves.contains(id))
+            .collect();
+        objects.sort_unstable();
+        Ok(objects.iter().map(SHA1::to_string).collect())
+    }
+
+    async fn has_default_branch(&self) -> Result<bool, ProtocolError> {
+        let (doc, _) = self.read_refs_document().await?;
+        Ok(doc.refs.contains_key(&doc.head))
+    }
+
+    /// Only `HEAD` is symbolic
+    async fn get_symbolic_ref(&self, ref_name: &str) -> Result<Option<String>, ProtocolError> {
+        if ref_name != "HEAD" {
+            return Ok(None);
+        }
+        Ok(Some(self.read_refs_document().await?.0.head))
+    }
+
+    async fn set_symbolic_ref(&self, ref_name: &str, target: &str) -> Result<(), ProtocolError> {
+        check_symbolic_ref(ref_name, target)?;
+        self.set_head(target).await
+    }
+
+    async fn post_receive_hook(&self) -> Result<(), ProtocolError> {
+        Ok(())
+    }
+
+    async fn handle_pack_objects(
+        &self,
+        commits: Vec<Commit>,
+        trees: Vec<Tree>,
+        blobs: Vec<Blob>,
+        tags: Vec<Tag>,
+        _provenance: Option<&ObjectProvenance>,
+    ) -> Result<(), ProtocolError> {
+        let serialize_error = |e: crate::errors::GitError| {
+            ProtocolError::repository_error(format!("Failed to serialize object: {}", e))
+        };
+        let mut objects = Vec::new();
+        for blob in blobs {
+            objects.push((blob.id, ObjectType::Blob, blob.data));
+        }
+        for tree in trees {
+            let data = tree.to_data().map_err(serialize_error)?;
+            objects.push((tree.id, ObjectType::Tree, data));
+        }
+        for commit in commits {
+            let data = commit.to_data().map_err(serialize_error)?;
+            objects.push((commit.id, ObjectType::Commit, data));
+        }
+        for tag in tags {
+            let data = tag.to_data().map_err(serialize_error)?;
+            objects.push((tag.id, ObjectType::Tag, data));
+        }
+        self.write_loose(objects).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::object::signature::{Signature, SignatureType};
+    use crate::internal::object::tree::TreeItem;
+    use crate::maintenance::{RepackOptions, repack};
+
+    /// A key read, with its range for a ranged read.
+    type Read = (String, Option<Range<u64>>);
+
+    /// A [`MemoryStorage`] recording the values read.
+    #[derive(Debug, Clone, Default)]
+    struct RecordingStorage {
+        inner: MemoryStorage,
+        reads: Arc<RwLock<Vec<Read>>>,
+    }
+
+    impl RecordingStorage {
+        /// The reads of `key` since the last call.
+        fn take_reads(&self, key: &str) -> Vec<Option<Range<u64>>> {
+            let mut reads = self.reads.write().unwrap();
+            let taken = reads
+                .iter()
+                .filter(|(read, _)| read == key)
+                .map(|(_, range)| range.clone())
+                .collect();
+            reads.clear();
+            taken
+        }
+    }
+
+    #[async_trait]
+    impl ObjectStorage for RecordingStorage {
+        async fn get(&self, key: &str) -> Result<Option<StoredObject>, ProtocolError> {
+            self.reads.write().unwrap().push((key.to_string(), None));
+            self.inner.get(key).await
+        }
+
+        async fn get_range(
+            &self,
+            key: &str,
+            range: Range<u64>,
+        ) -> Result<Option<Vec<u8>>, ProtocolError> {
+            let read = (key.to_string(), Some(range.clone()));
+            self.reads.write().unwrap().push(read);
+            self.inner.get_range(key, range).await
+        }
+
+        async fn exists(&self, key: &str) -> Result<bool, ProtocolError> {
+            self.inner.exists(key).await
+        }
+
+        async fn put(
+            &self,
+            key: &str,
+            data: Vec<u8>,
+            condition: PutCondition,
+        ) -> Result<bool, ProtocolError> {
+            self.inner.put(key, data, condition).await
+        }
+
+        async fn delete(&self, key: &str) -> Result<(), ProtocolError> {
+            self.inner.delete(key).await
+        }
+
+        async fn list(&self, prefix: &str) -> Result<Vec<String>, ProtocolError> {
+            self.inner.list(prefix).await
+        }
+    }
+
+    /// A repository with one commit of one file on `refs/heads/main`, stored loose.
+    async fn seeded(storage: &MemoryStorage) -> (ObjectStoreRepository<MemoryStorage>, Commit) {
+        let repo = ObjectStoreRepository::new(storage.clone(), "repos/test");
+        let blob = Blob::from_content("hello");
+        let item = TreeItem::new(TreeItemMode::Blob, blob.id, "hello.txt".to_string());
+        let tree = Tree::from_tree_items(vec![item]).unwrap();
+        let signature = |kind| Signature::new(kind, "t".to_string(), "t@example.com".to_string());
+        let commit = Commit::new(
+            signature(SignatureType::Author),
+            signature(SignatureType::Committer),
+            tree.id,
+            vec![],
+            "\ninit",
+        );
+        repo.handle_pack_objects(vec![commit.clone()], vec![tree], vec![blob], vec![], None)
+            .await
+            .unwrap();
+        repo.update_reference("refs/heads/main", None, &commit.id.to_string())
+            .await
+            .unwrap();
+        (repo, commit)
+    }
+
+    #[tokio::test]
+    async fn test_object_store_repository_refs() {
+        let storage = MemoryStorage::new();
+        let (repo, commit) = seeded(&storage).await;
+        let main = commit.id.to_string();
+        assert_eq!(repo.prefix(), "repos/test/");
+        assert!(storage.exists(&repo.loose_key(&commit.id)).await.unwrap());
+        assert!(storage.exists("repos/test/refs.json").await.unwrap());
+        assert!(repo.has_default_branch().await.unwrap());
+        assert_eq!(
+            repo.get_repository_refs().await.unwrap(),
+            vec![
+                ("HEAD".to_string(), main.clone()),
+                ("refs/heads/main".to_string(), main.clone())
+            ]
+        );
+        assert_eq!(
+            repo.get_objects_for_pack(std::slice::from_ref(&main), &[])
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+
+        // A batch is applied in one write, each update checked against the current value
+        let updates = [
+            RefUpdate {
+                ref_name: "refs/heads/dev".to_string(),
+                old_hash: None,
+                new_hash: main.clone(),
+            },
+            RefUpdate {
+                ref_name: "refs/heads/main".to_string(),
+                old_hash: None,
+                new_hash: main.clone(),
+            },
+        ];
+        let results = repo.update_references(&updates).await.unwrap();
+        assert!(results[0].is_ok());
+        let err = results[1].as_ref().unwrap_err();
+        assert!(err.to_string().contains("refs/heads/main is at"), "{err}");
+        repo.update_reference("refs/heads/main", Some(&main), ZERO_ID)
+            .await
+            .unwrap();
+        assert!(!repo.has_default_branch().await.unwrap());
+
+        // Every update is logged, the rejected one excepted, and survives the deletion
+        let reflog = repo.get_reflog("refs/heads/main").await.unwrap();
+        let values: Vec<(&str, &str)> = reflog
+            .iter()
+            .map(|e| (e.old_hash.as_str(), e.new_hash.as_str()))
+            .collect();
+        assert_eq!(values, [(main.as_str(), ZERO_ID), (ZERO_ID, main.as_str())]);
+        assert_eq!(repo.get_reflog("refs/heads/dev").await.unwrap().len(), 1);
+        let log = storage
+            .get(&repo.reflog_key("refs/heads/main"))
+            .await
+            .unwrap();
+        let log = String::from_utf8(log.unwrap().data).unwrap();
+        assert!(log.starts_with(&format!("{ZERO_ID} {main}  ")), "{log}");
+        assert!(log.ends_with(" +0000\tupdate by push\n"), "{log}");
+        repo.set_head("refs/heads/dev").await.unwrap();
+        assert_eq!(
+            repo.get_repository_refs().await.unwrap(),
+            vec![
+                ("HEAD".to_string(), main.clone()),
+                ("refs/heads/dev".to_string(), main.clone())
+            ]
+        );
+
+        // Conditional writes of the storage
+        let stored = storage.get("repos/test/refs.json").await.unwrap().unwrap();
+        assert!(
+            !storage
+                .put("repos/test/refs.json", vec![], PutCondition::IfAbsent)
+                .await
+                .unwrap()
+        );
+        assert!(
+            storage
+                .put(
+                    "repos/test/refs.json",
+                    vec![],
+                    PutCondition::IfMatch(stored.etag.clone())
+                )
+                .await
+                .unwrap()
+        );
+        assert!(
+            !storage
+                .put(
+                    "repos/test/refs.json",
+                    vec![],
+                    PutCondition::IfMatch(stored.etag)
+                )
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_object_store_repository_repack() {
+        let storage = MemoryStorage::new();
+        let (repo, commit) = seeded(&storage).await;
+        let report = repack(&repo, &RepackOptions::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(report.objects, 3);
+        assert!(!storage.exists(&repo.loose_key(&commit.id)).await.unwrap());
+        assert_eq!(
+            storage.list("repos/test/objects/").await.unwrap(),
+            vec![
+                repo.pack_key(&report.pack_hash, "idx"),
+                repo.pack_key(&report.pack_hash, "pack"),
+            ]
+        );
+        assert_eq!(
+            repo.list_packs().await.unwrap(),
+            vec![PackInfo {
+                pack_hash: report.pack_hash,
+                kept: false
+            }]
+        );
+
+        // Another handle finds the objects in the pack
+        let other = ObjectStoreRepository::new(storage.clone(), "repos/test/");
+        let main = commit.id.to_string();
+        assert!(other.has_object(&main).await.unwrap());
+        assert_eq!(other.get_commit(&main).await.unwrap(), commit);
+        assert!(
+            !other
+                .has_object(&Blob::from_content("missing").id.to_string())
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_object_store_repository_ranged_reads() {
+        let storage = RecordingStorage::default();
+        let repo = ObjectStoreRepository::new(storage.clone(), "repos/test");
+        let content: String = (0..200).map(|i| format!("line {i}\n")).collect();
+        let blobs = vec![
+            Blob::from_content(&content),
+            Blob::from_content(&format!("{content}one more line\n")),
+        ];
+        let items = blobs
+            .iter()
+            .zip(["a.txt", "b.txt"])
+            .map(|(blob, name)| TreeItem::new(TreeItemMode::Blob, blob.id, name.to_string()))
+            .collect();
+        let tree = Tree::from_tree_items(items).unwrap();
+        let signature = |kind| Signature::new(kind, "t".to_string(), "t@example.com".to_string());
+        let commit = Commit::new(
+            signature(SignatureType::Author),
+            signature(SignatureType::Committer),
+            tree.id,
+            vec![],
+            "\ninit",
+        );
+        repo.handle_pack_objects(
+            vec![commit.clone()],
+            vec![tree],
+            blobs.clone(),
+            vec![],
+            None,
+        )
+        .await
+        .unwrap();
+        repo.update_reference("refs/heads/main", None, &commit.id.to_string())
+            .await
+            .unwrap();
+        let report = repack(&repo, &RepackOptions::default())
+            .await
+            .unwrap()
+            .unwrap();
+        let pack_key = repo.pack_key(&report.pack_hash, "pack");
+        let pack = storage.inner.get(&pack_key).await.unwrap().unwrap().data;
+        let idx_key = repo.pack_key(&report.pack_hash, "idx");
+        let idx = storage.inner.get(&idx_key).await.unwrap().unwrap().data;
+        let deltas = PackIndex::from_bytes(idx)
+            .unwrap()
+            .packed_deltas(&pack)
+            .unwrap();
+        let delta = *deltas
+            .keys()
+            .next()
+            .expect("one blob is a delta of the other");
+        let expected = blobs.iter().find(|blob| blob.id == delta).unwrap();
+
+        // The delta and its base are read entry by entry, never the whole pack
+        let other = ObjectStoreRepository::new(storage.clone(), "repos/test");
+        storage.take_reads(&pack_key);
+        let blob = other.get_blob(&delta.to_string()).await.unwrap();
+        assert_eq!(blob.data, expected.data);
+        let reads = storage.take_reads(&pack_key);
+        assert_eq!(reads.len(), 2, "{reads:?}");
+        for range in reads {
+            let range = range.expect("a ranged read");
+            assert!(range.end == u64::MAX || range.end - range.start < pack.len() as u64);
+        }
+
+        // The base is kept in the delta base cache
+        let blob = other.get_blob(&delta.to_string()).await.unwrap();
+        assert_eq!(blob.data, expected.data);
+        assert_eq!(storage.take_reads(&pack_key).len(), 1);
+        assert_eq!(
+            other.get_commit(&commit.id.to_string()).await.unwrap(),
+            commit
+        );
+    }
+}
@@ -1,15 +1,43 @@
-use bytes::Bytes;
-use std::collections::{HashSet, VecDeque};
-use std::io::Cursor;
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Arc;
 use tokio;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tokio_stream::wrappers::ReceiverStream;
 
 use super::core::RepositoryAccess;
-use super::types::ProtocolError;
+use super::types::{ProtocolError, ProtocolStream};
+use crate::errors::GitError;
+use crate::fsck::{Fsck, FsckOptions, FsckProblem};
+use crate::hash::SHA1;
 use crate::internal::object::types::ObjectType;
-use crate::internal::object::{ObjectTrait, blob::Blob, commit::Commit, tree::Tree};
-use crate::internal::pack::{Pack, encode::PackEncoder, entry::Entry};
+use crate::internal::object::{ObjectTrait, blob::Blob, commit::Commit, tag::Tag, tree::Tree};
+use crate::internal::pack::bitmap::Bitmap;
+use crate::internal::pack::channel_reader::StreamBufReader;
+use crate::internal::pack::encode::{
+    DEFAULT_BIG_FILE_THRESHOLD, PackEncoder, PackedDelta, name_hash,
+};
+use crate::internal::pack::idx::IdxVersion;
+use crate::internal::pack::{LargeObjectSink, Pack, entry::Entry};
+
+/// Chunks of an incoming pack queued for the decoder, the stream is read no further ahead
+const UNPACK_QUEUED_CHUNKS: usize = 64;
+
+/// Size of the chunks large objects are stored in
+const LARGE_OBJECT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Most objects of a received pack handed to storage at once
+const STORE_BATCH_OBJECTS: usize = 1000;
+
+/// Content size past which the objects of a received pack are handed to storage, unless
+/// a single object is bigger
+const STORE_BATCH_BYTES: usize = 16 * 1024 * 1024;
+
+/// Objects carried by a pack, grouped by type: commits, trees, blobs and annotated tags
+pub type PackObjects = (Vec<Commit>, Vec<Tree>, Vec<Blob>, Vec<Tag>);
 
 /// Pack generation service for Git protocol operations
 ///
@@ -20,6 +48,247 @@ where
     R: RepositoryAccess,
 {
     repo_access: &'a R,
+    /// Whether generated packs may hold `OBJ_OFS_DELTA` entries
+    ofs_delta: bool,
+    delta_options: DeltaOptions,
+    unpack_options: UnpackOptions,
+    /// Check received objects before they are stored, see [`PackGenerator::set_fsck_options`]
+    fsck_options: Option<FsckOptions>,
+}
+
+/// Delta search and compression settings of generated packs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeltaOptions {
+    /// Number of preceding objects tried as delta bases, 0 disables deltas
+    pub window: usize,
+    /// Longest delta chain, shallow chains are cheaper for clients to read back
+    pub depth: usize,
+    /// Objects larger than this many bytes are sent whole
+    pub big_file_threshold: usize,
+    /// Copy the deltas offered by `RepositoryAccess::get_packed_deltas` instead of searching
+    pub reuse_deltas: bool,
+    /// zlib level from 0 to 9, low levels answer sooner for bigger packs
+    pub compression_level: u32,
+    /// zlib level of already compressed blobs such as archives and images, `None` for the
+    /// level of the other objects
+    pub compressed_blob_level: Option<u32>,
+    /// Threads deltifying and compressing objects, 0 for one per core
+    pub threads: usize,
+    /// Refer to delta bases by offset, or by id for clients without the `ofs-delta` capability
+    /// (see [`PackGenerator::set_ofs_delta`])
+    pub ofs_delta: bool,
+}
+
+impl Default for DeltaOptions {
+    fn default() -> Self {
+        Self {
+            window: 10,
+            depth: 50,
+            big_file_threshold: DEFAULT_BIG_FILE_THRESHOLD,
+            reuse_deltas: true,
+            compression_level: 6,
+            compressed_blob_level: None,
+            threads: 0,
+            ofs_delta: true,
+        }
+    }
+}
+
+/// Memory and index settings of the decoder of received packs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UnpackOptions {
+    /// Bytes of resolved objects kept in memory as delta bases, `None` for no limit. Past it
+    /// the least recently used bases are written to `temp_dir` and read back when needed.
+    /// The objects themselves are only all held by [`PackGenerator::unpack_received`],
+    /// [`PackGenerator::unpack_received_in_batches`] stores them as they are resolved.
+    pub mem_limit: Option<usize>,
+    /// Directory of the spilled bases, removed once the pack is decoded. Defaults to
+    /// `git-internal-cache` in [`std::env::temp_dir`], whatever the working directory.
+    pub temp_dir: Option<PathBuf>,
+    /// Whole blobs larger than this many bytes are written to
+    /// `RepositoryAccess::store_object_stream` as they are inflated instead of being held in
+    /// memory, `None` unpacks every object in memory
+    pub large_object_threshold: Option<usize>,
+    /// The version of the `.idx` written for packs stored whole, version 2 by default
+    pub idx_version: IdxVersion,
+}
+
+/// Objects of a received pack, see [`PackGenerator::unpack_received`]
+#[derive(Debug, Default)]
+pub struct UnpackedPack {
+    /// Empty when the objects were handed to storage in batches, see
+    /// [`PackGenerator::unpack_received_in_batches`]
+    pub objects: PackObjects,
+    /// The ids of the blobs stored through `RepositoryAccess::store_object_stream`, which are
+    /// not in `objects`
+    pub streamed: Vec<SHA1>,
+    /// The `.idx` of the pack, when asked for and every object was resolved
+    pub idx: Option<Vec<u8>>,
+    /// Problems below [`FsckSeverity::Error`](crate::fsck::FsckSeverity::Error) found by fsck,
+    /// when it is enabled
+    pub fsck_problems: Vec<FsckProblem>,
+}
+
+/// A received pack stored with its index, see [`PackGenerator::index_pack`]
+#[derive(Debug)]
+pub struct IndexedPack {
+    /// The checksum of the pack, which names its `pack-<hash>.pack` and `.idx`
+    pub pack_hash: SHA1,
+    /// The token the pack is kept by, when it was stored kept
+    pub keep: Option<String>,
+    pub unpacked: UnpackedPack,
+}
+
+impl std::fmt::Display for IndexedPack {
+    /// The line `git index-pack --stdin` prints: `keep` or `pack`, a tab and the pack checksum
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.keep {
+            Some(_) => write!(f, "keep\t{}", self.pack_hash),
+            None => write!(f, "pack\t{}", self.pack_hash),
+        }
+    }
+}
+
+/// A large object handed over by the decoder, with the chunks of its content and the channel
+/// that reports its storage
+struct LargeObject {
+    obj_type: ObjectType,
+    size: usize,
+    data: mpsc::Receiver<Bytes>,
+    stored: oneshot::Sender<Result<SHA1, ProtocolError>>,
+}
+
+/// Objects of a received pack on their way to storage, in batches of at most
+/// [`STORE_BATCH_OBJECTS`] objects or about [`STORE_BATCH_BYTES`] of content
+struct ObjectBatches {
+    batch: std::sync::Mutex<(PackObjects, usize)>,
+    /// Taken once the batches end or are discarded
+    batch_tx: std::sync::Mutex<Option<mpsc::Sender<PackObjects>>>,
+}
+
+impl ObjectBatches {
+    fn new(batch_tx: mpsc::Sender<PackObjects>) -> Self {
+        Self {
+            batch: Default::default(),
+            batch_tx: std::sync::Mutex::new(Some(batch_tx)),
+        }
+    }
+
+    /// Add a decoded object, and hand the batch over once it is full. Called by the decoder,
+    /// which waits while the previous batch is stored.
+    fn push(&self, entry: Entry) {
+        if self.batch_tx.lock().unwrap().is_none() {
+            return;
+        }
+        let mut batch = self.batch.lock().unwrap();
+        let (commits, trees, blobs, tags) = &mut batch.0;
+        match entry.obj_type {
+            ObjectType::Commit => match Commit::from_bytes(&entry.data, entry.hash) {
+                Ok(commit) => commits.push(commit),
+                Err(_) => tracing::warn!("Failed to parse commit from pack entry"),
+            },
+            ObjectType::Tree => match Tree::from_bytes(&entry.data, entry.hash) {
+                Ok(tree) => trees.push(tree),
+                Err(_) => tracing::warn!("Failed to parse tree from pack entry"),
+            },
+            ObjectType::Blob => match Blob::from_bytes(&entry.data, entry.hash) {
+                Ok(blob) => blobs.push(blob),
+                Err(_) => tracing::warn!("Failed to parse blob from pack entry"),
+            },
+            ObjectType::Tag => match Tag::from_bytes(&entry.data, entry.hash) {
+                Ok(tag) => tags.push(tag),
+                Err(_) => tracing::warn!("Failed to parse tag from pack entry"),
+            },
+            _ => {
+                tracing::warn!("Unknown object type in pack: {:?}", entry.obj_type);
+                return;
+            }
+        }
+        batch.1 += entry.data.len();
+        if object_count(&batch.0) >= STORE_BATCH_OBJECTS || batch.1 >= STORE_BATCH_BYTES {
+            let (full, _) = std::mem::take(&mut *batch);
+            let batch_tx = self.batch_tx.lock().unwrap().clone();
+            // The storage failed when the receiver is gone, the unpack reports its error
+            if let Some(batch_tx) = batch_tx {
+                let _ = batch_tx.blocking_send(full);
+            }
+        }
+    }
+
+    /// Drop the batch being filled and the objects still to come, the unpack failed
+    fn discard(&self) {
+        self.batch_tx.lock().unwrap().take();
+        *self.batch.lock().unwrap() = Default::default();
+    }
+
+    /// Hand the last batch over and end the batches
+    async fn close(&self) {
+        let (last, _) = std::mem::take(&mut *self.batch.lock().unwrap());
+        let batch_tx = self.batch_tx.lock().unwrap().take();
+        if let Some(batch_tx) = batch_tx
+            && object_count(&last) > 0
+        {
+            let _ = batch_tx.send(last).await;
+        }
+    }
+}
+
+pub(crate) fn object_count((commits, trees, blobs, tags): &PackObjects) -> usize {
+    commits.len() + trees.len() + blobs.len() + tags.len()
+}
+
+/// The `store` of [`PackGenerator::unpack_received_in_batches`] that gathers every batch into
+/// `objects`
+fn collect_into(
+    objects: &mut PackObjects,
+) -> impl FnMut(PackObjects) -> std::future::Ready<Result<(), ProtocolError>> + '_ {
+    |(commits, trees, blobs, tags)| {
+        objects.0.extend(commits);
+        objects.1.extend(trees);
+        objects.2.extend(blobs);
+        objects.3.extend(tags);
+        std::future::ready(Ok(()))
+    }
+}
+
+/// Sink of the decoder that moves the content of large objects to the runtime side of
+/// [`PackGenerator::unpack_received`], and waits for them to be stored
+fn large_object_sink(large_tx: mpsc::Sender<LargeObject>) -> LargeObjectSink {
+    Arc::new(move |obj_type, size, content: &mut dyn Read| {
+        let stopped = || GitError::CustomError("Unpack stopped".to_string());
+        let (data_tx, data) = mpsc::channel(UNPACK_QUEUED_CHUNKS);
+        let (stored, stored_rx) = oneshot::channel();
+        large_tx
+            .blocking_send(LargeObject {
+                obj_type,
+                size,
+                data,
+                stored,
+            })
+            .map_err(|_| stopped())?;
+        let mut buf = vec![0; LARGE_OBJECT_CHUNK_SIZE];
+        loop {
+            let n = content
+                .read(&mut buf)
+                .map_err(|e| GitError::InvalidPackFile(format!("Decompression error: {e}")))?;
+            // Stop when the storage gave up, its error is reported below
+            if n == 0
+                || data_tx
+                    .blocking_send(Bytes::copy_from_slice(&buf[..n]))
+                    .is_err()
+            {
+                break;
+            }
+        }
+        drop(data_tx);
+        match stored_rx.blocking_recv() {
+            Ok(Ok(id)) => Ok(id),
+            Ok(Err(e)) => Err(GitError::CustomError(format!(
+                "Failed to store large object: {e}"
+            ))),
+            Err(_) => Err(stopped()),
+        }
+    })
 }
 
 impl<'a, R> PackGenerator<'a, R>
@@ -27,7 +296,63 @@ where
     R: RepositoryAccess,
 {
     pub fn new(repo_access: &'a R) -> Self {
-        Self { repo_access }
+        Self {
+            repo_access,
+            ofs_delta: true,
+            delta_options: DeltaOptions::default(),
+            unpack_options: UnpackOptions::default(),
+            fsck_options: None,
+        }
+    }
+
+    /// Follow the client's `ofs-delta` capability: without it, deltas of generated packs refer
+    /// to their base by id (REF_DELTA) instead of by offset.
+    pub fn set_ofs_delta(&mut self, ofs_delta: bool) {
+        self.ofs_delta = ofs_delta;
+    }
+
+    /// Set the delta window, depth and big file threshold of generated packs
+    pub fn set_delta_options(&mut self, delta_options: DeltaOptions) {
+        self.delta_options = delta_options;
+    }
+
+    /// Set the memory limit of unpacking received packs, see [`UnpackOptions`]
+    pub fn set_unpack_options(&mut self, unpack_options: UnpackOptions) {
+        self.unpack_options = unpack_options;
+    }
+
+    /// Check the objects of received packs with [`Fsck`], failing the unpack on an error.
+    /// `None`, the default, stores them unchecked.
+    pub fn set_fsck_options(&mut self, fsck_options: Option<FsckOptions>) {
+        self.fsck_options = fsck_options;
+    }
+
+    /// Delta options used for the packs of this generator
+    fn delta_options(&self) -> DeltaOptions {
+        DeltaOptions {
+            ofs_delta: self.ofs_delta && self.delta_options.ofs_delta,
+            ..self.delta_options
+        }
+    }
+
+    /// Get the stored deltas of `objects` that the generated pack may copy
+    async fn reused_deltas(
+        &self,
+        objects: &PackObjects,
+        delta_options: &DeltaOptions,
+    ) -> Result<HashMap<SHA1, PackedDelta>, ProtocolError> {
+        if !delta_options.reuse_deltas {
+            return Ok(HashMap::new());
+        }
+        let (commits, trees, blobs, tags) = objects;
+        let ids = commits.iter().map(|c| c.id);
+        let ids = ids.chain(trees.iter().map(|t| t.id));
+        let ids = ids.chain(blobs.iter().map(|b| b.id));
+        let ids: Vec<String> = ids
+            .chain(tags.iter().map(|t| t.id))
+            .map(|id| id.to_string())
+            .collect();
+        self.repo_access.get_packed_deltas(&ids).await
     }
 
     /// Generate a full pack containing all requested objects
@@ -35,19 +360,8 @@ where
         &self,
         want: Vec<String>,
     ) -> Result<ReceiverStream<Vec<u8>>, ProtocolError> {
-        let (tx, rx) = mpsc::channel(1024);
-
-        // Collect all objects needed for the wanted commits
-        let all_objects = self.collect_all_objects(want).await?;
-
-        // Generate pack data
-        tokio::spawn(async move {
-            if let Err(e) = Self::generate_pack_stream(all_objects, tx).await {
-                tracing::error!("Failed to generate pack stream: {}", e);
-            }
-        });
-
-        Ok(ReceiverStream::new(rx))
+        let objects = self.full_pack_objects(want).await?;
+        self.generate_pack(objects).await
     }
 
     /// Generate an incremental pack containing only objects not in 'have'
@@ -56,8 +370,29 @@ where
         want: Vec<String>,
         have: Vec<String>,
     ) -> Result<ReceiverStream<Vec<u8>>, ProtocolError> {
-        let (tx, rx) = mpsc::channel(1024);
+        let objects = self.incremental_pack_objects(want, have).await?;
+        self.generate_pack(objects).await
+    }
+
+    /// The objects of [`PackGenerator::generate_full_pack`]
+    pub(crate) async fn full_pack_objects(
+        &self,
+        want: Vec<String>,
+    ) -> Result<PackObjects, ProtocolError> {
+        // Collect all objects needed for the wanted commits, from the reachability bitmaps
+        // when they cover every wanted tip
+        match self.collect_bitmap_objects(&want).await? {
+            Some(objects) => Ok(objects),
+            None => self.collect_all_objects(want).await,
+        }
+    }
 
+    /// The objects of [`PackGenerator::generate_incremental_pack`]
+    pub(crate) async fn incremental_pack_objects(
+        &self,
+        want: Vec<String>,
+        have: Vec<String>,
+    ) -> Result<PackObjects, ProtocolError> {
         // Collect objects for wanted commits
         let wanted_objects = self.collect_all_objects(want).await?;
 
@@ -65,12 +400,22 @@ where
         let have_objects = self.collect_all_objects(have).await?;
 
         // Filter out objects that are already in 'have'
-        let incremental_objects = Self::filter_objects(wanted_objects, have_objects);
+        Ok(Self::filter_objects(wanted_objects, have_objects))
+    }
 
-        // Generate pack data
+    /// Encode `objects` into a pack, streamed as it is generated
+    pub(crate) async fn generate_pack(
+        &self,
+        objects: PackObjects,
+    ) -> Result<ReceiverStream<Vec<u8>>, ProtocolError> {
+        let (tx, rx) = mpsc::channel(1024);
+        let delta_options = self.delta_options();
+        let reused_deltas = self.reused_deltas(&objects, &delta_options).await?;
         tokio::spawn(async move {
-            if let Err(e) = Self::generate_pack_stream(incremental_objects, tx).await {
-                tracing::error!("Failed to generate incremental pack stream: {}", e);
+            if let Err(e) =
+                Self::generate_pack_stream(objects, delta_options, reused_deltas, tx).await
+            {
+                tracing::error!("Failed to generate pack stream: {}", e);
             }
         });
 
@@ -78,78 +423,390 @@ where
     }
 
     /// Unpack incoming pack stream and extract objects
-    pub async fn unpack_stream(
+    pub async fn unpack_stream(&self, pack_data: Bytes) -> Result<PackObjects, ProtocolError> {
+        let pack_stream = futures::stream::once(async move { Ok(pack_data) });
+        self.unpack_from_stream(Box::pin(pack_stream)).await
+    }
+
+    /// Unpack a pack as it arrives, decoding each chunk of `pack_stream` while the next one is
+    /// received, so the pack is never held in memory as a whole
+    pub async fn unpack_from_stream(
+        &self,
+        pack_stream: ProtocolStream,
+    ) -> Result<PackObjects, ProtocolError> {
+        Ok(self.unpack_received(pack_stream, false).await?.objects)
+    }
+
+    /// Unpack incoming pack stream like [`PackGenerator::unpack_stream`], and also return the
+    /// `.idx` of the pack, so it can be stored as-is next to its index
+    pub async fn unpack_stream_with_idx(
         &self,
         pack_data: Bytes,
-    ) -> Result<(Vec<Commit>, Vec<Tree>, Vec<Blob>), ProtocolError> {
-        use std::sync::{Arc, Mutex};
+    ) -> Result<(PackObjects, Vec<u8>), ProtocolError> {
+        let pack_stream = futures::stream::once(async move { Ok(pack_data) });
+        let unpacked = self.unpack_received(Box::pin(pack_stream), true).await?;
+        let idx = unpacked.idx.ok_or_else(|| {
+            ProtocolError::invalid_request("Failed to build pack index: unresolved objects")
+        })?;
+        Ok((unpacked.objects, idx))
+    }
 
-        let commits = Arc::new(Mutex::new(Vec::new()));
-        let trees = Arc::new(Mutex::new(Vec::new()));
-        let blobs = Arc::new(Mutex::new(Vec::new()));
+    /// Decode a pack as it arrives, resolving the deltas of a thin pack against objects of the
+    /// repository. The [`PackGenerator::unpack_stream`] family returns the objects only, this
+    /// also returns the blobs streamed to `RepositoryAccess::store_object_stream` and, when
+    /// `build_idx` is set and every object resolved, the `.idx` of the pack in the version of
+    /// [`UnpackOptions::idx_version`].
+    pub async fn unpack_received(
+        &self,
+        pack_stream: ProtocolStream,
+        build_idx: bool,
+    ) -> Result<UnpackedPack, ProtocolError> {
+        let mut objects = PackObjects::default();
+        let mut unpacked = self
+            .unpack_received_in_batches(pack_stream, build_idx, collect_into(&mut objects))
+            .await?;
+        unpacked.objects = objects;
+        Ok(unpacked)
+    }
+
+    /// Decode a pack as it arrives like [`PackGenerator::unpack_received`], handing its objects
+    /// to `store` in batches as they are resolved instead of returning them, so that only the
+    /// batch being stored is held in memory. The `objects` of the result are empty.
+    ///
+    /// Full batches are stored before the rest of the pack is checked: when the unpack fails,
+    /// the objects stored so far are left unreferenced. The last batch, and the objects past
+    /// the first fsck error, are only handed over when the pack checks out.
+    pub async fn unpack_received_in_batches<F, Fut>(
+        &self,
+        mut pack_stream: ProtocolStream,
+        build_idx: bool,
+        mut store: F,
+    ) -> Result<UnpackedPack, ProtocolError>
+    where
+        F: FnMut(PackObjects) -> Fut,
+        Fut: Future<Output = Result<(), ProtocolError>>,
+    {
+        use std::sync::Mutex;
 
-        let commits_clone = commits.clone();
-        let trees_clone = trees.clone();
-        let blobs_clone = blobs.clone();
+        let (batch_tx, batch_rx) = mpsc::channel(1);
+        let batches = Arc::new(ObjectBatches::new(batch_tx));
+        let batches_clone = batches.clone();
+        let fsck = self
+            .fsck_options
+            .clone()
+            .map(|options| Arc::new(Mutex::new(Fsck::new(options))));
+        let fsck_clone = fsck.clone();
 
         // Create a Pack instance for decoding
-        let mut pack = Pack::new(None, None, None, true);
-        let mut cursor = Cursor::new(pack_data.to_vec());
-
-        // Decode the pack and collect entries
-        pack.decode(
-            &mut cursor,
-            move |entry: Entry, _offset: usize| match entry.obj_type {
-                ObjectType::Commit => {
-                    if let Ok(commit) = Commit::from_bytes(&entry.data, entry.hash) {
-                        commits_clone.lock().unwrap().push(commit);
-                    } else {
-                        tracing::warn!("Failed to parse commit from pack entry");
+        let UnpackOptions {
+            mem_limit,
+            temp_dir,
+            large_object_threshold,
+            idx_version,
+        } = self.unpack_options.clone();
+        let mut pack = Pack::new(None, mem_limit, temp_dir, true);
+        pack.set_build_idx(build_idx);
+        pack.set_idx_version(idx_version);
+        pack.set_thin(true);
+        pack.set_stop_at_trailer(true);
+        let (large_tx, mut large_rx) = mpsc::channel(1);
+        match large_object_threshold {
+            Some(threshold) => {
+                pack.set_large_object_sink(Some((threshold, large_object_sink(large_tx))))
+            }
+            None => drop(large_tx),
+        }
+        let (chunk_tx, chunk_rx) = mpsc::channel(UNPACK_QUEUED_CHUNKS);
+        let mut reader = StreamBufReader::new(chunk_rx);
+
+        // Decode the pack and batch entries, reading blocks so it runs off the runtime
+        let decoding = tokio::task::spawn_blocking(move || {
+            let result = pack.decode(&mut reader, move |entry: Entry, _offset: usize| {
+                if let Some(fsck) = &fsck_clone {
+                    let mut fsck = fsck.lock().unwrap();
+                    fsck.check_object(entry.obj_type, entry.hash, &entry.data);
+                    // The unpack fails, don't store what comes after
+                    if fsck.first_error().is_some() {
+                        batches_clone.discard();
+                        return;
                     }
                 }
-                ObjectType::Tree => {
-                    if let Ok(tree) = Tree::from_bytes(&entry.data, entry.hash) {
-                        trees_clone.lock().unwrap().push(tree);
-                    } else {
-                        tracing::warn!("Failed to parse tree from pack entry");
+                batches_clone.push(entry);
+            });
+            // Closes the channel of large objects, ending `store_large_objects`
+            pack.set_large_object_sink(None);
+            (pack, result)
+        });
+
+        // Feed the decoder, which drops the reader and so stops the feed if the pack is invalid,
+        // or once it ends: clients on a connection like git:// don't close their end after it
+        let feed = async {
+            let mut stream_error = None;
+            loop {
+                let chunk = tokio::select! {
+                    chunk = pack_stream.next() => chunk,
+                    _ = chunk_tx.closed() => break,
+                };
+                let Some(chunk) = chunk else {
+                    break;
+                };
+                match chunk {
+                    Ok(chunk) => {
+                        if chunk_tx.send(chunk.to_vec()).await.is_err() {
+                            break;
+                        }
                     }
-                }
-                ObjectType::Blob => {
-                    if let Ok(blob) = Blob::from_bytes(&entry.data, entry.hash) {
-                        blobs_clone.lock().unwrap().push(blob);
-                    } else {
-                        tracing::warn!("Failed to parse blob from pack entry");
+                    Err(e) => {
+                        stream_error = Some(e);
+                        break;
                     }
                 }
-                _ => {
-                    tracing::warn!("Unknown object type in pack: {:?}", entry.obj_type);
+            }
+            drop(chunk_tx);
+            stream_error
+        };
+        // Store the large objects while the decoder inflates them, alongside the feed
+        let store_large_objects = async {
+            let mut store_error = None;
+            while let Some(large) = large_rx.recv().await {
+                let LargeObject {
+                    obj_type,
+                    size,
+                    data,
+                    stored,
+                } = large;
+                let data = Box::pin(ReceiverStream::new(data).map(Ok));
+                let result = self
+                    .repo_access
+                    .store_object_stream(obj_type, size, data)
+                    .await;
+                if let Err(e) = &result {
+                    store_error.get_or_insert_with(|| e.to_string());
+                }
+                let _ = stored.send(result);
+            }
+            store_error
+        };
+        // Wait for the decoder, then resolve the deltas on the bases a thin pack left out, those
+        // not in the repository stay unresolved, and check the links of the objects
+        let resolve = async {
+            let (mut pack, result) = decoding
+                .await
+                .map_err(|e| ProtocolError::repository_error(format!("Pack decode failed: {e}")))?;
+            if result.is_err() {
+                return Ok((pack, result, Ok(Vec::new())));
+            }
+            let mut bases = Vec::new();
+            for id in pack.missing_bases() {
+                if let Some(base) = self.load_base(&id).await? {
+                    bases.push(base);
                 }
-            },
-        )
-        .map_err(|e| ProtocolError::invalid_request(&format!("Failed to decode pack: {}", e)))?;
+            }
+            let (pack, result) = tokio::task::spawn_blocking(move || {
+                let result = pack.resolve_thin(bases);
+                (pack, result)
+            })
+            .await
+            .map_err(|e| ProtocolError::repository_error(format!("Pack decode failed: {e}")))?;
+            let fsck_problems = match fsck {
+                Some(fsck) if result.is_ok() => {
+                    let fsck = Arc::try_unwrap(fsck).unwrap().into_inner().unwrap();
+                    self.finish_fsck(fsck, pack.streamed_objects()).await
+                }
+                _ => Ok(Vec::new()),
+            };
+            Ok((pack, result, fsck_problems))
+        };
+        // The last batch is only stored when the whole pack checked out
+        let resolved = async {
+            let resolved: Result<_, ProtocolError> = resolve.await;
+            if !matches!(resolved, Ok((_, Ok(()), Ok(_)))) {
+                batches.discard();
+            }
+            batches.close().await;
+            resolved
+        };
+        // Store the batches while the next ones are decoded
+        let store_batches = async {
+            let mut batch_rx = batch_rx;
+            while let Some(batch) = batch_rx.recv().await {
+                // Dropping the receiver makes the decoder stop handing batches over
+                store(batch).await?;
+            }
+            Ok::<_, ProtocolError>(())
+        };
+        let (stream_error, store_error, resolved, stored) =
+            tokio::join!(feed, store_large_objects, resolved, store_batches);
+        let (pack, result, fsck_problems) = resolved?;
+        if let Some(e) = store_error {
+            return Err(ProtocolError::repository_error(format!(
+                "Failed to store large object: {e}"
+            )));
+        }
+        if let Some(e) = stream_error {
+            return Err(ProtocolError::invalid_request(&format!(
+                "Stream error: {}",
+                e
+            )));
+        }
+        stored?;
+        result.map_err(|e| match e {
+            // Reported to the client as the unpack status of the push
+            GitError::PackChecksumMismatch(_) => {
+                ProtocolError::pack_corrupt("pack checksum mismatch")
+            }
+            e => ProtocolError::pack_corrupt(format!("Failed to decode pack: {}", e)),
+        })?;
+
+        Ok(UnpackedPack {
+            objects: PackObjects::default(),
+            streamed: pack.streamed_objects().to_vec(),
+            idx: pack.idx().map(<[u8]>::to_vec),
+            fsck_problems: fsck_problems?,
+        })
+    }
+
+    /// Store a received pack as-is with its index, like `git index-pack --stdin --keep`
+    ///
+    /// The whole pack is collected, unpacked and handed with its `.idx` to
+    /// `RepositoryAccess::store_kept_pack` when `keep` is given, or to
+    /// `RepositoryAccess::store_pack_index` otherwise. A kept pack stays marked until
+    /// `RepositoryAccess::release_pack_keep` is called with the same token.
+    pub async fn index_pack(
+        &self,
+        pack_stream: ProtocolStream,
+        keep: Option<&str>,
+    ) -> Result<IndexedPack, ProtocolError> {
+        let mut objects = PackObjects::default();
+        let mut indexed = self
+            .index_pack_in_batches(pack_stream, keep, collect_into(&mut objects))
+            .await?;
+        indexed.unpacked.objects = objects;
+        Ok(indexed)
+    }
+
+    /// Store a received pack with its index like [`PackGenerator::index_pack`], handing its
+    /// objects to `store` in batches as they are resolved, see
+    /// [`PackGenerator::unpack_received_in_batches`]. The pack is stored once every batch was.
+    pub async fn index_pack_in_batches<F, Fut>(
+        &self,
+        pack_stream: ProtocolStream,
+        keep: Option<&str>,
+        store: F,
+    ) -> Result<IndexedPack, ProtocolError>
+    where
+        F: FnMut(PackObjects) -> Fut,
+        Fut: Future<Output = Result<(), ProtocolError>>,
+    {
+        // Keep the pack as it is decoded, which reads it up to its end
+        let received = Arc::new(std::sync::Mutex::new(BytesMut::new()));
+        let recorded = received.clone();
+        let pack_stream = pack_stream.inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                recorded.lock().unwrap().extend_from_slice(chunk);
+            }
+        });
+        let unpacked = self
+            .unpack_received_in_batches(Box::pin(pack_stream), true, store)
+            .await?;
+        let pack_data = std::mem::take(&mut *received.lock().unwrap()).freeze();
+        let idx = unpacked.idx.as_deref().ok_or_else(|| {
+            ProtocolError::invalid_request("Failed to build pack index: unresolved objects")
+        })?;
+        let stored = match keep {
+            Some(keep) => {
+                self.repo_access
+                    .store_kept_pack(&pack_data, idx, keep)
+                    .await
+            }
+            None => self.repo_access.store_pack_index(&pack_data, idx).await,
+        };
+        stored.map_err(|e| {
+            ProtocolError::repository_error(format!("Failed to store pack index: {}", e))
+        })?;
+        Ok(IndexedPack {
+            // The decoder checked the trailer
+            pack_hash: SHA1::from_bytes(&pack_data[pack_data.len() - SHA1::SIZE..]),
+            keep: keep.map(str::to_string),
+            unpacked,
+        })
+    }
 
-        // Extract the results
-        let commits_result = Arc::try_unwrap(commits).unwrap().into_inner().unwrap();
-        let trees_result = Arc::try_unwrap(trees).unwrap().into_inner().unwrap();
-        let blobs_result = Arc::try_unwrap(blobs).unwrap().into_inner().unwrap();
+    /// Check the links of the objects of a received pack against the repository, failing on
+    /// the first fsck error and returning the other problems
+    async fn finish_fsck(
+        &self,
+        mut fsck: Fsck,
+        streamed: &[SHA1],
+    ) -> Result<Vec<FsckProblem>, ProtocolError> {
+        for id in streamed {
+            fsck.add_checked(ObjectType::Blob, *id);
+        }
+        let mut present = HashSet::new();
+        for id in fsck.unresolved_links() {
+            if self.repo_access.has_object(&id.to_string()).await? {
+                present.insert(id);
+            }
+        }
+        fsck.check_links(|id| present.contains(id));
+        if let Some(error) = fsck.first_error() {
+            // Reported to the client as the unpack status of the push
+            return Err(ProtocolError::pack_corrupt(error));
+        }
+        let problems = fsck.into_problems();
+        for problem in &problems {
+            tracing::warn!("{problem}");
+        }
+        Ok(problems)
+    }
 
-        Ok((commits_result, trees_result, blobs_result))
+    /// Load the type and content of a delta base from the repository
+    async fn load_base(&self, id: &SHA1) -> Result<Option<(ObjectType, Vec<u8>)>, ProtocolError> {
+        load_object(self.repo_access, id).await
     }
 
-    /// Collect all objects reachable from the given commit hashes
+    /// Collect all objects reachable from the given commit or annotated tag hashes
     async fn collect_all_objects(
         &self,
         commit_hashes: Vec<String>,
-    ) -> Result<(Vec<Commit>, Vec<Tree>, Vec<Blob>), ProtocolError> {
+    ) -> Result<PackObjects, ProtocolError> {
         let mut commits = Vec::new();
         let mut trees = Vec::new();
         let mut blobs = Vec::new();
+        let mut tags = Vec::new();
 
         let mut visited_commits = HashSet::new();
         let mut visited_trees = HashSet::new();
         let mut visited_blobs = HashSet::new();
 
-        let mut commit_queue = VecDeque::from(commit_hashes);
+        // Wanted tag refs point at tag objects, peel them down to the objects they name
+        let mut commit_queue = VecDeque::new();
+        for hash in commit_hashes {
+            match self.peel_tags(hash, &mut tags).await? {
+                (ObjectType::Tree, tree_hash) => {
+                    Box::pin(self.collect_tree_objects(
+                        &tree_hash,
+                        &mut trees,
+                        &mut blobs,
+                        &mut visited_trees,
+                        &mut visited_blobs,
+                    ))
+                    .await?;
+                }
+                (ObjectType::Blob, blob_hash) => {
+                    if visited_blobs.insert(blob_hash.clone()) {
+                        let blob = self.repo_access.get_blob(&blob_hash).await.map_err(|e| {
+                            ProtocolError::repository_error(format!(
+                                "Failed to get blob {}: {}",
+                                blob_hash, e
+                            ))
+                        })?;
+                        blobs.push(blob);
+                    }
+                }
+                (_, commit_hash) => commit_queue.push_back(commit_hash),
+            }
+        }
 
         // BFS traversal of commit graph
         while let Some(commit_hash) = commit_queue.pop_front() {
@@ -191,7 +848,80 @@ where
             commits.push(commit);
         }
 
-        Ok((commits, trees, blobs))
+        Ok((commits, trees, blobs, tags))
+    }
+
+    /// Collect the objects reachable from `wants` with the repository's pack bitmaps, wanted
+    /// annotated tags being followed to their commits. Returns None when there are no bitmaps
+    /// or a wanted tip has none, history is then walked instead.
+    async fn collect_bitmap_objects(
+        &self,
+        wants: &[String],
+    ) -> Result<Option<PackObjects>, ProtocolError> {
+        let Some(bitmap) = self.repo_access.get_pack_bitmap().await? else {
+            return Ok(None);
+        };
+        let mut reachable = Bitmap::new();
+        for want in wants {
+            let mut id: SHA1 = want.parse().map_err(|_| {
+                ProtocolError::invalid_request(&format!("Invalid object id: {}", want))
+            })?;
+            loop {
+                let Some(position) = bitmap.position(&id) else {
+                    return Ok(None);
+                };
+                if let Some(commit_bitmap) = bitmap.commit_bitmap(&id) {
+                    reachable.union_with(commit_bitmap);
+                    break;
+                }
+                if bitmap.object_type(&id) != Some(ObjectType::Tag) {
+                    return Ok(None);
+                }
+                reachable.set(position);
+                id = self.repo_access.get_tag(&id.to_string()).await?.object_hash;
+            }
+        }
+
+        let (mut commits, mut trees, mut blobs, mut tags) = PackObjects::default();
+        for (id, object_type) in bitmap.objects(&reachable) {
+            let hash = id.to_string();
+            match object_type {
+                ObjectType::Commit => commits.push(self.repo_access.get_commit(&hash).await?),
+                ObjectType::Tree => trees.push(self.repo_access.get_tree(&hash).await?),
+                ObjectType::Blob => blobs.push(self.repo_access.get_blob(&hash).await?),
+                _ => tags.push(self.repo_access.get_tag(&hash).await?),
+            }
+        }
+        Ok(Some((commits, trees, blobs, tags)))
+    }
+
+    /// Follow a chain of annotated tags starting at `hash`, collecting the tag objects and
+    /// returning the type and hash of the object at the end of the chain. Hashes of objects
+    /// the repository doesn't have are returned as commits.
+    async fn peel_tags(
+        &self,
+        hash: String,
+        tags: &mut Vec<Tag>,
+    ) -> Result<(ObjectType, String), ProtocolError> {
+        let loaded = match hash.parse::<SHA1>() {
+            Ok(id) => self.load_base(&id).await?,
+            Err(_) => None,
+        };
+        // Let the commit walk report the missing object
+        let Some((mut object_type, _)) = loaded else {
+            return Ok((ObjectType::Commit, hash));
+        };
+        let mut current = hash;
+        // The type of each target is the one its tag names
+        while object_type == ObjectType::Tag {
+            let tag = self.repo_access.get_tag(&current).await?;
+            current = tag.object_hash.to_string();
+            object_type = tag.object_type;
+            if !tags.iter().any(|t| t.id == tag.id) {
+                tags.push(tag);
+            }
+        }
+        Ok((object_type, current))
     }
 
     /// Recursively collect tree and blob objects
@@ -225,19 +955,24 @@ where
                     ))
                     .await?;
                 }
+                // Symlinks are stored as blobs holding the link target
                 crate::internal::object::tree::TreeItemMode::Blob
-                | crate::internal::object::tree::TreeItemMode::BlobExecutable => {
-                    if !visited_blobs.contains(&entry_hash) {
-                        visited_blobs.insert(entry_hash.clone());
-                        let blob = self.repo_access.get_blob(&entry_hash).await.map_err(|e| {
-                            ProtocolError::repository_error(format!(
-                                "Failed to get blob {}: {}",
-                                entry_hash, e
-                            ))
-                        })?;
-                        blobs.push(blob);
-                    }
+                | crate::internal::object::tree::TreeItemMode::BlobExecutable
+                | crate::internal::object::tree::TreeItemMode::Link
+                    if !visited_blobs.contains(&entry_hash) =>
+                {
+                    visited_blobs.insert(entry_hash.clone());
+                    let blob = self.repo_access.get_blob(&entry_hash).await.map_err(|e| {
+                        ProtocolError::repository_error(format!(
+                            "Failed to get blob {}: {}",
+                            entry_hash, e
+                        ))
+                    })?;
+                    blobs.push(blob);
                 }
+                // A gitlink (submodule) names a commit of another repository, which is neither
+                // stored here nor part of the pack
+                crate::internal::object::tree::TreeItemMode::Commit => {}
                 _ => {}
             }
         }
@@ -247,12 +982,9 @@ where
     }
 
     /// Filter objects to exclude those already in 'have'
-    fn filter_objects(
-        wanted: (Vec<Commit>, Vec<Tree>, Vec<Blob>),
-        have: (Vec<Commit>, Vec<Tree>, Vec<Blob>),
-    ) -> (Vec<Commit>, Vec<Tree>, Vec<Blob>) {
-        let (wanted_commits, wanted_trees, wanted_blobs) = wanted;
-        let (have_commits, have_trees, have_blobs) = have;
+    fn filter_objects(wanted: PackObjects, have: PackObjects) -> PackObjects {
+        let (wanted_commits, wanted_trees, wanted_blobs, wanted_tags) = wanted;
+        let (have_commits, have_trees, have_blobs, have_tags) = have;
 
         // Create hash sets for efficient lookup
         let have_commit_hashes: HashSet<String> =
@@ -278,15 +1010,36 @@ where
             .filter(|b| !have_blob_hashes.contains(&b.id.to_string()))
             .collect();
 
-        (filtered_commits, filtered_trees, filtered_blobs)
+        let filtered_tags: Vec<Tag> = wanted_tags
+            .into_iter()
+            .filter(|t| !have_tags.iter().any(|h| h.id == t.id))
+            .collect();
+
+        (
+            filtered_commits,
+            filtered_trees,
+            filtered_blobs,
+            filtered_tags,
+        )
     }
 
-    /// Generate pack stream from objects
+    /// Generate pack stream from objects, deltified as `delta_options` allow, copying the
+    /// `reused_deltas` whose base is in the pack
     async fn generate_pack_stream(
-        objects: (Vec<Commit>, Vec<Tree>, Vec<Blob>),
+        objects: PackObjects,
+        delta_options: DeltaOptions,
+        reused_deltas: HashMap<SHA1, PackedDelta>,
         tx: mpsc::Sender<Vec<u8>>,
     ) -> Result<(), ProtocolError> {
-        let (commits, trees, blobs) = objects;
+        let (commits, trees, blobs, tags) = objects;
+
+        // Name the trees and blobs after the first path they show up at
+        let mut name_hashes = HashMap::new();
+        for item in trees.iter().flat_map(|tree| &tree.tree_items) {
+            name_hashes
+                .entry(item.id)
+                .or_insert_with(|| name_hash(&item.name));
+        }
 
         // Convert objects to entries
         let mut entries = Vec::new();
@@ -303,10 +1056,22 @@ where
             entries.push(Entry::from(blob));
         }
 
+        for tag in tags {
+            entries.push(Entry::from(tag));
+        }
+
         // Create PackEncoder and encode entries
         let (pack_tx, mut pack_rx) = mpsc::channel(1024);
         let (entry_tx, entry_rx) = mpsc::channel(1024);
-        let mut encoder = PackEncoder::new(entries.len(), 10, pack_tx); // window_size = 10
+        let mut encoder = PackEncoder::new(entries.len(), delta_options.window, pack_tx);
+        encoder.set_depth(delta_options.depth);
+        encoder.set_big_file_threshold(delta_options.big_file_threshold);
+        encoder.set_compression_level(delta_options.compression_level);
+        encoder.set_compressed_blob_level(delta_options.compressed_blob_level);
+        encoder.set_threads(delta_options.threads);
+        encoder.set_ofs_delta(delta_options.ofs_delta);
+        encoder.set_name_hashes(name_hashes);
+        encoder.set_reused_deltas(reused_deltas);
 
         // Spawn encoding task
         tokio::spawn(async move {
@@ -336,15 +1101,57 @@ where
     }
 }
 
+/// Hand a batch of received objects to `RepositoryAccess::handle_pack_objects`, a `store` of
+/// [`PackGenerator::unpack_received_in_batches`] for objects without provenance
+pub(crate) async fn store_objects<R: RepositoryAccess>(
+    repo: &R,
+    (commits, trees, blobs, tags): PackObjects,
+) -> Result<(), ProtocolError> {
+    repo.handle_pack_objects(commits, trees, blobs, tags, None)
+        .await
+        .map_err(|e| ProtocolError::repository_error(format!("Failed to store pack objects: {e}")))
+}
+
+/// Load the type and content of the object `id` from `repo`, None when it doesn't have it
+///
+/// `get_object` returns the content only, so the type is the one whose id matches.
+pub(crate) async fn load_object<R: RepositoryAccess>(
+    repo: &R,
+    id: &SHA1,
+) -> Result<Option<(ObjectType, Vec<u8>)>, ProtocolError> {
+    let hash = id.to_string();
+    if !repo.has_object(&hash).await? {
+        return Ok(None);
+    }
+    let data = repo.get_object(&hash).await?;
+    let obj_type = [
+        ObjectType::Commit,
+        ObjectType::Tree,
+        ObjectType::Blob,
+        ObjectType::Tag,
+    ]
+    .into_iter()
+    .find(|t| SHA1::from_type_and_data(*t, &data) == *id)
+    .ok_or_else(|| ProtocolError::corrupt_object(*id, "content doesn't match the id"))?;
+    Ok(Some((obj_type, data)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hash::SHA1;
     use crate::internal::object::blob::Blob;
     use crate::internal::object::commit::Commit;
     use crate::internal::object::signature::{Signature, SignatureType};
     use crate::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+    use crate::internal::pack::bitmap::PackBitmap;
+    use crate::internal::pack::diagnostics::DeltaStats;
+    use crate::internal::pack::idx::PackIndex;
+    use crate::protocol::memory::MemoryRepository;
     use async_trait::async_trait;
     use bytes::Bytes;
+    use std::io::Cursor;
+    use std::sync::Arc;
 
     #[derive(Clone)]
     struct DummyRepoAccess;
@@ -388,6 +1195,584 @@ mod tests {
         }
     }
 
+    /// A [`MemoryRepository`] serving the bitmap and deltas of a pack of its objects.
+    #[derive(Clone, Default)]
+    struct PackedRepo {
+        repo: MemoryRepository,
+        bitmap: Option<Arc<PackBitmap>>,
+        packed_deltas: HashMap<SHA1, PackedDelta>,
+    }
+
+    #[async_trait]
+    impl RepositoryAccess for PackedRepo {
+        async fn get_repository_refs(&self) -> Result<Vec<(String, String)>, ProtocolError> {
+            self.repo.get_repository_refs().await
+        }
+        async fn has_object(&self, object_hash: &str) -> Result<bool, ProtocolError> {
+            self.repo.has_object(object_hash).await
+        }
+        async fn get_pack_bitmap(&self) -> Result<Option<Arc<PackBitmap>>, ProtocolError> {
+            Ok(self.bitmap.clone())
+        }
+        async fn get_packed_deltas(
+            &self,
+            object_hashes: &[String],
+        ) -> Result<HashMap<SHA1, PackedDelta>, ProtocolError> {
+            let mut deltas = self.packed_deltas.clone();
+            deltas.retain(|id, _| object_hashes.contains(&id.to_string()));
+            Ok(deltas)
+        }
+        async fn get_object(&self, object_hash: &str) -> Result<Vec<u8>, ProtocolError> {
+            self.repo.get_object(object_hash).await
+        }
+        async fn store_pack_data(&self, pack_data: &[u8]) -> Result<(), ProtocolError> {
+            self.repo.store_pack_data(pack_data).await
+        }
+        async fn update_reference(
+            &self,
+            ref_name: &str,
+            old_hash: Option<&str>,
+            new_hash: &str,
+        ) -> Result<(), ProtocolError> {
+            self.repo
+                .update_reference(ref_name, old_hash, new_hash)
+                .await
+        }
+        async fn get_objects_for_pack(
+            &self,
+            wants: &[String],
+            haves: &[String],
+        ) -> Result<Vec<String>, ProtocolError> {
+            self.repo.get_objects_for_pack(wants, haves).await
+        }
+        async fn has_default_branch(&self) -> Result<bool, ProtocolError> {
+            self.repo.has_default_branch().await
+        }
+        async fn post_receive_hook(&self) -> Result<(), ProtocolError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collect_tree_with_symlink_and_submodule() {
+        let file = Blob::from_content("hello");
+        let link = Blob::from_content("hello.txt");
+        let submodule_commit = SHA1::from_type_and_data(ObjectType::Commit, b"elsewhere");
+        let tree = Tree::from_tree_items(vec![
+            TreeItem::new(TreeItemMode::Blob, file.id, "hello.txt".to_string()),
+            TreeItem::new(TreeItemMode::Link, link.id, "link".to_string()),
+            TreeItem::new(TreeItemMode::Commit, submodule_commit, "vendor".to_string()),
+        ])
+        .unwrap();
+
+        let repo = MemoryRepository::new();
+        repo.insert_object(&tree).unwrap();
+        repo.insert_object(&file).unwrap();
+        repo.insert_object(&link).unwrap();
+
+        let generator = PackGenerator::new(&repo);
+        let (mut trees, mut blobs) = (Vec::new(), Vec::new());
+        generator
+            .collect_tree_objects(
+                &tree.id.to_string(),
+                &mut trees,
+                &mut blobs,
+                &mut HashSet::new(),
+                &mut HashSet::new(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(trees, [tree]);
+        let blob_ids: Vec<SHA1> = blobs.iter().map(|blob| blob.id).collect();
+        assert_eq!(blob_ids, [file.id, link.id]);
+    }
+
+    #[tokio::test]
+    async fn test_full_pack_from_bitmaps() {
+        let blob = Blob::from_content("hello");
+        let item = TreeItem::new(TreeItemMode::Blob, blob.id, "hello.txt".to_string());
+        let tree = Tree::from_tree_items(vec![item]).unwrap();
+        let signature =
+            |kind| Signature::new(kind, "tester".to_string(), "tester@example.com".to_string());
+        let commit = |parents: Vec<SHA1>, message: &str| {
+            let (author, committer) = (SignatureType::Author, SignatureType::Committer);
+            Commit::new(
+                signature(author),
+                signature(committer),
+                tree.id,
+                parents,
+                message,
+            )
+        };
+        let first = commit(vec![], "first");
+        let second = commit(vec![first.id], "second");
+        let tag = Tag::new(
+            second.id,
+            ObjectType::Commit,
+            "v1.0".to_string(),
+            signature(SignatureType::Tagger),
+            "release\n".to_string(),
+        );
+
+        let mut repo = PackedRepo::default();
+        for commit in [&first, &second] {
+            repo.repo.insert_object(commit).unwrap();
+        }
+        repo.repo.insert_object(&tree).unwrap();
+        repo.repo.insert_object(&blob).unwrap();
+        repo.repo.insert_object(&tag).unwrap();
+
+        // Pack everything, index the pack and build the bitmap of the tip
+        let objects: PackObjects = (
+            vec![first.clone(), second.clone()],
+            vec![tree.clone()],
+            vec![blob.clone()],
+            vec![tag.clone()],
+        );
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(64);
+        PackGenerator::<PackedRepo>::generate_pack_stream(
+            objects.clone(),
+            DeltaOptions::default(),
+            HashMap::new(),
+            tx,
+        )
+        .await
+        .unwrap();
+        let mut pack = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            pack.extend_from_slice(&chunk);
+        }
+        let (_, idx) = PackGenerator::new(&repo)
+            .unpack_stream_with_idx(Bytes::from(pack))
+            .await
+            .unwrap();
+        let index = PackIndex::from_bytes(idx).unwrap();
+        let bitmap =
+            PackBitmap::build(&index, &objects.0, &objects.1, &objects.3, &[second.id]).unwrap();
+        repo.bitmap = Some(Arc::new(bitmap));
+
+        let generator = PackGenerator::new(&repo);
+        let (commits, trees, blobs, tags) = generator
+            .collect_bitmap_objects(&[tag.id.to_string()])
+            .await
+            .unwrap()
+            .unwrap();
+        let commit_ids: HashSet<SHA1> = commits.iter().map(|c| c.id).collect();
+        assert_eq!(commit_ids, HashSet::from([first.id, second.id]));
+        assert_eq!(trees, [tree]);
+        assert_eq!(blobs.iter().map(|b| b.id).collect::<Vec<_>>(), [blob.id]);
+        assert_eq!(tags.iter().map(|t| t.id).collect::<Vec<_>>(), [tag.id]);
+
+        // A tip without a bitmap falls back to walking history
+        assert!(
+            generator
+                .collect_bitmap_objects(&[first.id.to_string()])
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_collect_tags_of_trees_and_blobs() {
+        let blob = Blob::from_content("hello");
+        let item = TreeItem::new(TreeItemMode::Blob, blob.id, "hello.txt".to_string());
+        let tree = Tree::from_tree_items(vec![item]).unwrap();
+        let other = Blob::from_content("key");
+        let tag = |object_hash, object_type, name: &str| {
+            let tagger =
+                Signature::new(SignatureType::Tagger, "tester".to_string(), String::new());
+            Tag::new(object_hash, object_type, name.to_string(), tagger, String::new())
+        };
+        let tree_tag = tag(tree.id, ObjectType::Tree, "tree");
+        let blob_tag = tag(other.id, ObjectType::Blob, "key");
+        // A tag of a tag, peeled down to the blob
+        let nested = tag(blob_tag.id, ObjectType::Tag, "nested");
+
+        let repo = MemoryRepository::new();
+        repo.insert_object(&blob).unwrap();
+        repo.insert_object(&tree).unwrap();
+        repo.insert_object(&other).unwrap();
+        for tag in [&tree_tag, &blob_tag, &nested] {
+            repo.insert_object(tag).unwrap();
+        }
+
+        let (commits, trees, blobs, tags) = PackGenerator::new(&repo)
+            .collect_all_objects(vec![tree_tag.id.to_string(), nested.id.to_string()])
+            .await
+            .unwrap();
+        assert!(commits.is_empty());
+        assert_eq!(trees, [tree]);
+        let blob_ids: HashSet<SHA1> = blobs.iter().map(|blob| blob.id).collect();
+        assert_eq!(blob_ids, HashSet::from([blob.id, other.id]));
+        let tag_ids: HashSet<SHA1> = tags.iter().map(|tag| tag.id).collect();
+        assert_eq!(tag_ids, HashSet::from([tree_tag.id, blob_tag.id, nested.id]));
+    }
+
+    #[tokio::test]
+    async fn test_unpack_thin_pack() {
+        use flate2::{Compression, write::ZlibEncoder};
+        use std::io::Write;
+
+        // A pack holding only a ref delta on a blob it leaves out
+        let base = Blob::from_content("hello");
+        // base size 5, result size 11: copy "hello", insert " world"
+        let delta = [&[0x05, 0x0b, 0x90, 0x05, 0x06][..], b" world"].concat();
+        let mut pack = b"PACK".to_vec();
+        pack.extend_from_slice(&2u32.to_be_bytes());
+        pack.extend_from_slice(&1u32.to_be_bytes());
+        pack.push(0x70 | delta.len() as u8);
+        pack.extend_from_slice(&base.id.0);
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&delta).unwrap();
+        pack.extend(encoder.finish().unwrap());
+        let checksum = SHA1::new(&pack);
+        pack.extend_from_slice(&checksum.0);
+
+        let repo = MemoryRepository::new();
+        let generator = PackGenerator::new(&repo);
+        assert!(
+            generator
+                .unpack_stream(Bytes::from(pack.clone()))
+                .await
+                .is_err()
+        );
+
+        repo.insert_object(&base).unwrap();
+        let (_, _, blobs, _) = generator.unpack_stream(Bytes::from(pack)).await.unwrap();
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(blobs[0].data, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn test_unpack_from_stream() {
+        let text: String = (0..200).map(|i| format!("line {i}\n")).collect();
+        let blobs: Vec<Blob> = (0..5)
+            .map(|i| Blob::from_content(&text.repeat(i + 1)))
+            .collect();
+        let objects = (vec![], vec![], blobs.clone(), vec![]);
+        let pack = generate_pack(objects, DeltaOptions::default(), HashMap::new()).await;
+        let chunks: Vec<Bytes> = pack.chunks(7).map(Bytes::copy_from_slice).collect();
+        let stream = |chunks: &[Bytes]| -> Vec<Result<Bytes, ProtocolError>> {
+            chunks.iter().cloned().map(Ok).collect()
+        };
+
+        let repo = MemoryRepository::new();
+        let generator = PackGenerator::new(&repo);
+        let (_, _, unpacked, _) = generator
+            .unpack_from_stream(Box::pin(futures::stream::iter(stream(&chunks))))
+            .await
+            .unwrap();
+        let ids: HashSet<SHA1> = unpacked.iter().map(|b| b.id).collect();
+        assert_eq!(ids, blobs.iter().map(|b| b.id).collect());
+
+        // A stream failing halfway reports its own error rather than a truncated pack
+        let mut broken = stream(&chunks[..chunks.len() / 2]);
+        broken.push(Err(ProtocolError::invalid_request("connection reset")));
+        let err = generator
+            .unpack_from_stream(Box::pin(futures::stream::iter(broken)))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("connection reset"));
+    }
+
+    #[tokio::test]
+    async fn test_index_pack() {
+        let blobs: Vec<Blob> = (0..3)
+            .map(|i| Blob::from_content(&format!("{i}")))
+            .collect();
+        let objects = (vec![], vec![], blobs.clone(), vec![]);
+        let pack = generate_pack(objects, DeltaOptions::default(), HashMap::new()).await;
+        let pack_hash = SHA1::from_bytes(&pack[pack.len() - 20..]);
+        let chunks: Vec<Result<Bytes, ProtocolError>> = pack
+            .chunks(5)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect();
+
+        let repo = MemoryRepository::new();
+        let generator = PackGenerator::new(&repo);
+        let indexed = generator
+            .index_pack(
+                Box::pin(futures::stream::iter(chunks)),
+                Some("receive-pack 1"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(indexed.pack_hash, pack_hash);
+        assert_eq!(indexed.to_string(), format!("keep\t{pack_hash}"));
+        assert_eq!(indexed.unpacked.objects.2.len(), 3);
+        let idx = PackIndex::from_bytes(indexed.unpacked.idx.unwrap()).unwrap();
+        assert_eq!((idx.len(), idx.pack_hash()), (3, pack_hash));
+
+        let pack_stream = Box::pin(futures::stream::once(async { Ok(Bytes::from(pack)) }));
+        let mut generator = PackGenerator::new(&repo);
+        generator.set_unpack_options(UnpackOptions {
+            idx_version: IdxVersion::V3,
+            ..UnpackOptions::default()
+        });
+        let indexed = generator.index_pack(pack_stream, None).await.unwrap();
+        assert_eq!(indexed.to_string(), format!("pack\t{pack_hash}"));
+        let idx = PackIndex::from_bytes(indexed.unpacked.idx.unwrap()).unwrap();
+        assert_eq!(idx.version(), 3);
+        assert_eq!((idx.len(), idx.pack_hash()), (3, pack_hash));
+    }
+
+    #[tokio::test]
+    async fn test_unpack_with_mem_limit() {
+        // Far more blob content than the limit, so bases are spilled and read back
+        let blobs: Vec<Blob> = (0..40)
+            .map(|i| Blob::from_content(&format!("blob {i}\n").repeat(2000)))
+            .collect();
+        let objects = (vec![], vec![], blobs.clone(), vec![]);
+        let pack = generate_pack(objects, DeltaOptions::default(), HashMap::new()).await;
+
+        let temp_dir = std::env::temp_dir().join(format!("unpack-{}", uuid::Uuid::new_v4()));
+        let repo = MemoryRepository::new();
+        let mut generator = PackGenerator::new(&repo);
+        generator.set_unpack_options(UnpackOptions {
+            mem_limit: Some(64 * 1024),
+            temp_dir: Some(temp_dir.clone()),
+            ..Default::default()
+        });
+        let (_, _, unpacked, _) = generator.unpack_stream(Bytes::from(pack)).await.unwrap();
+        let ids: HashSet<SHA1> = unpacked.iter().map(|b| b.id).collect();
+        assert_eq!(ids, blobs.iter().map(|b| b.id).collect());
+
+        // The spilled bases are removed with the decoder
+        assert_eq!(std::fs::read_dir(&temp_dir).unwrap().count(), 0);
+        std::fs::remove_dir(&temp_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unpack_in_batches() {
+        let blobs: Vec<Blob> = (0..STORE_BATCH_OBJECTS * 2 + 10)
+            .map(|i| Blob::from_content(&format!("blob {i}")))
+            .collect();
+        let objects = (vec![], vec![], blobs.clone(), vec![]);
+        let pack = generate_pack(objects, DeltaOptions::default(), HashMap::new()).await;
+
+        let repo = MemoryRepository::new();
+        let generator = PackGenerator::new(&repo);
+        let mut batches = Vec::new();
+        let store = |(_, _, blobs, _): PackObjects| {
+            batches.push(blobs.len());
+            std::future::ready(Ok(()))
+        };
+        let pack_stream = futures::stream::iter([Ok(Bytes::from(pack.clone()))]);
+        let unpacked = generator
+            .unpack_received_in_batches(Box::pin(pack_stream), false, store)
+            .await
+            .unwrap();
+        assert_eq!(batches, [STORE_BATCH_OBJECTS, STORE_BATCH_OBJECTS, 10]);
+        assert_eq!(object_count(&unpacked.objects), 0);
+
+        // A failed store ends the unpack
+        let store = |_| std::future::ready(Err(ProtocolError::repository_error("full".into())));
+        let pack_stream = futures::stream::iter([Ok(Bytes::from(pack))]);
+        let result = generator
+            .unpack_received_in_batches(Box::pin(pack_stream), false, store)
+            .await;
+        assert!(matches!(result, Err(ProtocolError::Internal(_))));
+    }
+
+    #[tokio::test]
+    async fn test_unpack_streams_large_objects() {
+        let large = Blob::from_content(&(0..20000).map(|i| format!("{i}\n")).collect::<String>());
+        let small = Blob::from_content("small");
+        let objects = (vec![], vec![], vec![large.clone(), small.clone()], vec![]);
+        let pack = generate_pack(objects, DeltaOptions::default(), HashMap::new()).await;
+
+        let repo = MemoryRepository::new();
+        let mut generator = PackGenerator::new(&repo);
+        generator.set_unpack_options(UnpackOptions {
+            large_object_threshold: Some(1024),
+            ..Default::default()
+        });
+        let pack_stream = futures::stream::iter([Ok(Bytes::from(pack))]);
+        let unpacked = generator
+            .unpack_received(Box::pin(pack_stream), true)
+            .await
+            .unwrap();
+        let (_, _, blobs, _) = &unpacked.objects;
+        assert_eq!(blobs.iter().map(|b| b.id).collect::<Vec<_>>(), [small.id]);
+        assert_eq!(unpacked.streamed, [large.id]);
+        assert_eq!(repo.object(&large.id), Some((ObjectType::Blob, large.data)));
+        let idx = PackIndex::from_bytes(unpacked.idx.unwrap()).unwrap();
+        assert_eq!(idx.len(), 2);
+        assert!(idx.position(&large.id).is_some());
+    }
+
+    /// Generate a pack of `objects`
+    async fn generate_pack(
+        objects: PackObjects,
+        delta_options: DeltaOptions,
+        reused_deltas: HashMap<SHA1, PackedDelta>,
+    ) -> Vec<u8> {
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(64);
+        let generating = PackGenerator::<MemoryRepository>::generate_pack_stream(
+            objects,
+            delta_options,
+            reused_deltas,
+            tx,
+        );
+        // Read along, the pack of many objects doesn't fit in the channel
+        let reading = async {
+            let mut data = Vec::new();
+            while let Some(chunk) = rx.recv().await {
+                data.extend_from_slice(&chunk);
+            }
+            data
+        };
+        let (generated, data) = tokio::join!(generating, reading);
+        generated.unwrap();
+        data
+    }
+
+    /// Decode a pack and return its delta structure
+    fn delta_stats(data: &[u8]) -> DeltaStats {
+        let mut pack = Pack::new(None, None, None, true);
+        pack.decode(&mut Cursor::new(data.to_vec()), |_, _| {})
+            .unwrap();
+        pack.delta_stats.clone()
+    }
+
+    /// Generate a pack of `objects` and return its size and delta structure
+    async fn pack_stats(objects: PackObjects, delta_options: DeltaOptions) -> (usize, DeltaStats) {
+        let data = generate_pack(objects, delta_options, HashMap::new()).await;
+        (data.len(), delta_stats(&data))
+    }
+
+    #[tokio::test]
+    async fn test_generate_pack_ofs_delta() {
+        let text: String = (0..200).map(|i| format!("line {i}\n")).collect();
+        let first = Blob::from_content(&text);
+        let second = Blob::from_content(&format!("{text}one more line\n"));
+        let repo = MemoryRepository::new();
+        let blobs = vec![first, second];
+
+        let mut generator = PackGenerator::new(&repo);
+        let objects = (vec![], vec![], blobs.clone(), vec![]);
+        let (_, stats) = pack_stats(objects, generator.delta_options()).await;
+        assert_eq!((stats.base_objects, stats.offset_deltas), (1, 1));
+
+        generator.set_ofs_delta(false);
+        let objects = (vec![], vec![], blobs, vec![]);
+        let (_, stats) = pack_stats(objects, generator.delta_options()).await;
+        assert_eq!((stats.base_objects, stats.ref_deltas), (1, 1));
+        assert_eq!(stats.offset_deltas, 0);
+    }
+
+    #[tokio::test]
+    async fn test_generate_pack_deltas_by_path() {
+        // Two versions of a dozen unrelated files: sorted by size alone, all the second versions
+        // come before all the first ones and fall out of each other's delta window
+        let mut trees = Vec::new();
+        let mut blobs = Vec::new();
+        for version in 0..2 {
+            let mut items = Vec::new();
+            for file in 0..12 {
+                let mut text: String = (0..60).map(|i| format!("{file}: line {i}\n")).collect();
+                if version == 1 {
+                    text.push_str(&"appended line\n".repeat(20));
+                }
+                let blob = Blob::from_content(&text);
+                let name = format!("file{file}.txt");
+                items.push(TreeItem::new(TreeItemMode::Blob, blob.id, name));
+                blobs.push(blob);
+            }
+            trees.push(Tree::from_tree_items(items).unwrap());
+        }
+
+        let (unnamed_size, unnamed) = pack_stats(
+            (vec![], vec![], blobs.clone(), vec![]),
+            DeltaOptions::default(),
+        )
+        .await;
+        let (named_size, named) =
+            pack_stats((vec![], trees, blobs, vec![]), DeltaOptions::default()).await;
+        assert!(named.offset_deltas >= 12);
+        assert!(named.offset_deltas > unnamed.offset_deltas);
+        assert!(named_size < unnamed_size);
+    }
+
+    #[tokio::test]
+    async fn test_generate_pack_delta_options() {
+        // Each version appends to the previous one, the encoder chains them as deep as allowed
+        let mut text = String::new();
+        let mut blobs = Vec::new();
+        for version in 0..6 {
+            text.push_str(&format!("{version}: some more text for the next version\n").repeat(8));
+            blobs.push(Blob::from_content(&text));
+        }
+        let objects = || (vec![], vec![], blobs.clone(), vec![]);
+
+        let (_, stats) = pack_stats(objects(), DeltaOptions::default()).await;
+        assert_eq!(stats.offset_deltas, 5);
+        let shallow = DeltaOptions {
+            depth: 1,
+            ..DeltaOptions::default()
+        };
+        let (_, stats) = pack_stats(objects(), shallow).await;
+        assert_eq!(stats.max_chain_len, 1);
+        let no_big_files = DeltaOptions {
+            big_file_threshold: blobs[2].data.len(),
+            ..DeltaOptions::default()
+        };
+        let (default_size, stats) = pack_stats(objects(), no_big_files).await;
+        assert_eq!((stats.base_objects, stats.offset_deltas), (4, 2));
+        let stored = DeltaOptions {
+            compression_level: 0,
+            ..no_big_files
+        };
+        let (stored_size, _) = pack_stats(objects(), stored).await;
+        assert!(stored_size > default_size);
+    }
+
+    #[tokio::test]
+    async fn test_generate_pack_reuses_deltas() {
+        let text = "common line\n".repeat(40);
+        let first = Blob::from_content(&text);
+        let second = Blob::from_content(&format!("{text}one more line\n"));
+        let objects = || (vec![], vec![], vec![first.clone(), second.clone()], vec![]);
+
+        // An existing pack storing one of the blobs as a delta on the other
+        let existing = generate_pack(objects(), DeltaOptions::default(), HashMap::new()).await;
+        let mut decoder = Pack::new(None, None, None, true);
+        decoder.set_build_idx(true);
+        decoder
+            .decode(&mut Cursor::new(existing.clone()), |_, _| {})
+            .unwrap();
+        let index = PackIndex::from_bytes(decoder.idx().unwrap().to_vec()).unwrap();
+        let repo = PackedRepo {
+            packed_deltas: index.packed_deltas(&existing).unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(repo.packed_deltas.len(), 1);
+        let (delta_id, delta) = repo.packed_deltas.iter().next().unwrap();
+
+        // Without a window the only delta is the reused one, copied byte for byte
+        let options = DeltaOptions {
+            window: 0,
+            ..DeltaOptions::default()
+        };
+        let generator = PackGenerator::new(&repo);
+        let reused = generator.reused_deltas(&objects(), &options).await.unwrap();
+        let pack = generate_pack(objects(), options, reused).await;
+        assert!(pack.windows(delta.data.len()).any(|w| w == delta.data));
+        assert_eq!(delta_stats(&pack).offset_deltas, 1);
+        let (_, _, blobs, _) = generator.unpack_stream(Bytes::from(pack)).await.unwrap();
+        assert!(blobs.iter().any(|b| b.id == *delta_id));
+
+        // Its base isn't in this pack, the object is sent whole
+        let blob = [&first, &second].into_iter().find(|b| b.id == *delta_id);
+        let alone = (vec![], vec![], vec![blob.unwrap().clone()], vec![]);
+        let reused = generator.reused_deltas(&alone, &options).await.unwrap();
+        assert_eq!(reused.len(), 1);
+        let stats = delta_stats(&generate_pack(alone, options, reused).await);
+        assert_eq!((stats.base_objects, stats.deltas()), (1, 0));
+    }
+
     #[tokio::test]
     async fn test_pack_roundtrip_encode_decode() {
         // Create two Blob objects
@@ -412,6 +1797,20 @@ mod tests {
         );
         let commit = Commit::new(author, committer, tree.id, vec![], "init commit");
 
+        // Create an annotated tag pointing to the Commit
+        let tagger = Signature::new(
+            SignatureType::Tagger,
+            "tester".to_string(),
+            "tester@example.com".to_string(),
+        );
+        let tag = Tag::new(
+            commit.id,
+            ObjectType::Commit,
+            "v1.0".to_string(),
+            tagger,
+            "release\n".to_string(),
+        );
+
         // Generate pack stream
         let (tx, mut rx) = mpsc::channel::<Vec<u8>>(64);
         PackGenerator::<DummyRepoAccess>::generate_pack_stream(
@@ -419,7 +1818,10 @@ mod tests {
                 vec![commit.clone()],
                 vec![tree.clone()],
                 vec![blob1.clone(), blob2.clone()],
+                vec![tag.clone()],
             ),
+            DeltaOptions::default(),
+            HashMap::new(),
             tx,
         )
         .await
@@ -434,8 +1836,8 @@ mod tests {
         // Unpack the pack stream
         let dummy = DummyRepoAccess;
         let generator = PackGenerator::new(&dummy);
-        let (decoded_commits, decoded_trees, decoded_blobs) = generator
-            .unpack_stream(Bytes::from(pack_bytes))
+        let (decoded_commits, decoded_trees, decoded_blobs, decoded_tags) = generator
+            .unpack_stream(Bytes::from(pack_bytes.clone()))
             .await
             .unwrap();
 
@@ -468,6 +1870,9 @@ mod tests {
 
         assert_eq!(decoded_commits[0].id, commit.id);
         assert_eq!(decoded_trees[0].id, tree.id);
+        assert_eq!(decoded_tags.len(), 1);
+        assert_eq!(decoded_tags[0].id, tag.id);
+        assert_eq!(decoded_tags[0].object_hash, commit.id);
 
         let mut orig_blob_ids = vec![blob1.id.to_string(), blob2.id.to_string()];
         orig_blob_ids.sort();
@@ -477,5 +1882,18 @@ mod tests {
             .collect::<Vec<_>>();
         decoded_blob_ids.sort();
         assert_eq!(orig_blob_ids, decoded_blob_ids);
+
+        // The idx built alongside lists every object and ends with the pack checksum
+        let (_, idx) = generator
+            .unpack_stream_with_idx(Bytes::from(pack_bytes.clone()))
+            .await
+            .unwrap();
+        assert_eq!(&idx[..4], b"\xfftOc");
+        assert_eq!(
+            u32::from_be_bytes(idx[8 + 255 * 4..8 + 256 * 4].try_into().unwrap()),
+            5
+        );
+        let pack_hash = &pack_bytes[pack_bytes.len() - 20..];
+        assert_eq!(&idx[idx.len() - 40..idx.len() - 20], pack_hash);
     }
 }
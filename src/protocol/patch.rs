@@ -0,0 +1,301 @@
+//! `.patch` downloads of commits stored behind [`RepositoryAccess`].
+//!
+//! [`format_patch`] loads the trees and blobs a commit changed and renders them with
+//! [`diff::patch::format_patch`], [`format_patch_range`] does the same for a series of commits and
+//! returns one mbox that `git am` applies in order.
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::str::FromStr;
+
+use crate::diff;
+use crate::diff::patch::{FileDiff, PatchOptions, submodule_content};
+use crate::diff::rename::detect_renames;
+use crate::diff::tree::{TreeChange, diff_trees};
+use crate::hash::SHA1;
+use crate::internal::object::commit::Commit;
+use crate::internal::object::tree::Tree;
+use crate::protocol::core::RepositoryAccess;
+use crate::protocol::types::ProtocolError;
+use crate::revwalk::{CommitWalker, RevWalkOptions, WalkOrder};
+
+/// Render `commit_hash` as a `[PATCH]` message diffed against its first parent, or against the
+/// empty tree for a root commit.
+pub async fn format_patch<R: RepositoryAccess>(
+    repo: &R,
+    commit_hash: &str,
+    options: &PatchOptions,
+) -> Result<Vec<u8>, ProtocolError> {
+    let commit = repo.get_commit(commit_hash).await?;
+    let files = commit_diff(repo, &commit, options).await?;
+    Ok(diff::patch::format_patch(&commit, &files, None))
+}
+
+/// Render the commits reachable from `until` but not from `since` as `[PATCH n/m]` messages,
+/// oldest first, like `git format-patch since..until`.
+///
+/// Commits come in reverse topological order, so every patch applies on top of the ones before
+/// it; merge commits are skipped as git does, the commits of merged branches are not. Without
+/// `since`, the series goes back to the root commits.
+pub async fn format_patch_range<R: RepositoryAccess>(
+    repo: &R,
+    since: Option<&str>,
+    until: &str,
+    options: &PatchOptions,
+) -> Result<Vec<u8>, ProtocolError> {
+    let parse = |hash: &str| {
+        SHA1::from_str(hash)
+            .map_err(|_| ProtocolError::invalid_request(&format!("Invalid commit id {hash}")))
+    };
+    let mut walker = CommitWalker::new(repo)
+        .push(parse(until)?)
+        .options(RevWalkOptions::new().no_merges())
+        .order(WalkOrder::Topological);
+    if let Some(since) = since {
+        walker = walker.hide(parse(since)?);
+    }
+    let mut series = walker.collect().await?;
+    series.reverse();
+
+    let total = series.len();
+    let mut mbox = Vec::new();
+    for (index, commit) in series.iter().enumerate() {
+        let files = commit_diff(repo, commit, options).await?;
+        mbox.extend_from_slice(&diff::patch::format_patch(
+            commit,
+            &files,
+            Some((index + 1, total)),
+        ));
+    }
+    Ok(mbox)
+}
+
+/// The file diffs of `commit` against its first parent.
+async fn commit_diff<R: RepositoryAccess>(
+    repo: &R,
+    commit: &Commit,
+    options: &PatchOptions,
+) -> Result<Vec<FileDiff>, ProtocolError> {
+    let old_root = match commit.parent_commit_ids.first() {
+        Some(parent) => Some(repo.get_commit(&parent.to_string()).await?.tree_id),
+        None => None,
+    };
+    let trees = load_changed_trees(repo, old_root, commit.tree_id).await?;
+    let lookup_error = |e: crate::errors::GitError| ProtocolError::repository_error(e.to_string());
+    let changes = diff_trees(
+        old_root.and_then(|id| trees.get(&id)),
+        trees.get(&commit.tree_id),
+        |id| trees.get(id).cloned(),
+    )
+    .map_err(lookup_error)?;
+
+    let mut blobs: HashMap<SHA1, Vec<u8>> = HashMap::new();
+    for entry in changes
+        .iter()
+        .flat_map(|c| [c.old_entry(), c.new_entry()])
+        .flatten()
+    {
+        if blobs.contains_key(&entry.id) {
+            continue;
+        }
+        let data = match submodule_content(entry) {
+            Some(data) => data,
+            None => repo.get_blob(&entry.id.to_string()).await?.data,
+        };
+        blobs.insert(entry.id, data);
+    }
+
+    let changes: Vec<TreeChange> = match &options.renames {
+        Some(renames) => {
+            detect_renames(changes, renames, |id| blobs.get(id).cloned()).map_err(lookup_error)?
+        }
+        None => changes,
+    };
+    Ok(changes
+        .into_iter()
+        .map(|change| {
+            let content = |entry: Option<&diff::tree::DiffEntry>| {
+                entry.map_or(&[][..], |entry| blobs[&entry.id].as_slice())
+            };
+            let (old, new) = (content(change.old_entry()), content(change.new_entry()));
+            FileDiff::new(change.clone(), old, new, options.context)
+        })
+        .collect())
+}
+
+/// Load the trees [`diff_trees`] visits between `old_root` and `new_root`: subtrees with equal
+/// ids on both sides are skipped, added and deleted subtrees are loaded entirely.
+async fn load_changed_trees<R: RepositoryAccess>(
+    repo: &R,
+    old_root: Option<SHA1>,
+    new_root: SHA1,
+) -> Result<HashMap<SHA1, Tree>, ProtocolError> {
+    let mut trees: HashMap<SHA1, Tree> = HashMap::new();
+    let mut pending = vec![(old_root, Some(new_root))];
+    while let Some((old, new)) = pending.pop() {
+        for id in [old, new].into_iter().flatten() {
+            if let Entry::Vacant(slot) = trees.entry(id) {
+                slot.insert(repo.get_tree(&id.to_string()).await?);
+            }
+        }
+        let subtrees = |id: Option<SHA1>| -> HashMap<String, SHA1> {
+            id.map(|id| {
+                trees[&id]
+                    .tree_items
+                    .iter()
+                    .filter(|item| item.is_tree())
+                    .map(|item| (item.name.clone(), item.id))
+                    .collect()
+            })
+            .unwrap_or_default()
+        };
+        let (old_subtrees, mut new_subtrees) = (subtrees(old), subtrees(new));
+        for (name, old_id) in old_subtrees {
+            match new_subtrees.remove(&name) {
+                Some(new_id) if new_id == old_id => {}
+                new_id => pending.push((Some(old_id), new_id)),
+            }
+        }
+        pending.extend(new_subtrees.into_values().map(|id| (None, Some(id))));
+    }
+    Ok(trees)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::object::blob::Blob;
+    use crate::internal::object::signature::Signature;
+    use crate::internal::object::tree::{TreeItem, TreeItemMode};
+    use crate::protocol::memory::MemoryRepository;
+
+    /// Commit a tree `docs/<name>` of the given files on top of `parents`.
+    fn commit(
+        repo: &MemoryRepository,
+        parents: &[SHA1],
+        files: &[(&str, &str)],
+        message: &str,
+    ) -> SHA1 {
+        let items = files
+            .iter()
+            .map(|(name, content)| {
+                let blob = Blob::from_content(content);
+                let id = repo.insert_object(&blob).unwrap();
+                TreeItem::new(TreeItemMode::Blob, id, name.to_string())
+            })
+            .collect();
+        let docs = Tree::from_tree_items(items).unwrap();
+        let docs_id = repo.insert_object(&docs).unwrap();
+        let root = Tree::from_tree_items(vec![TreeItem::new(
+            TreeItemMode::Tree,
+            docs_id,
+            "docs".to_string(),
+        )])
+        .unwrap();
+        let root_id = repo.insert_object(&root).unwrap();
+        let signature = |kind: &str| {
+            Signature::from_data(
+                format!("{kind} Tester <tester@example.com> 1700000000 +0000").into_bytes(),
+            )
+            .unwrap()
+        };
+        let commit = Commit::new(
+            signature("author"),
+            signature("committer"),
+            root_id,
+            parents.to_vec(),
+            message,
+        );
+        repo.insert_object(&commit).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_format_patch_range() {
+        let repo = MemoryRepository::new();
+        let readme = "line one\nline two\nline three\nline four\n";
+        let first = commit(&repo, &[], &[("readme.md", readme)], "\nAdd readme\n");
+        let second = commit(
+            &repo,
+            &[first],
+            &[("README.md", readme), ("todo.md", "nothing\n")],
+            "\nRename readme and add todo\n",
+        );
+        let third = commit(
+            &repo,
+            &[second],
+            &[("README.md", readme), ("todo.md", "everything\n")],
+            "\nUpdate todo\n",
+        );
+
+        let patch = format_patch(&repo, &third.to_string(), &PatchOptions::default())
+            .await
+            .map(|patch| String::from_utf8(patch).unwrap())
+            .unwrap();
+        assert!(patch.starts_with(&format!("From {third} Mon Sep 17 00:00:00 2001\n")));
+        assert!(patch.contains("Subject: [PATCH] Update todo\n"));
+        assert!(patch.contains(" docs/todo.md | 2 +-\n"));
+        assert!(patch.contains("-nothing\n+everything\n"));
+
+        let mbox = format_patch_range(
+            &repo,
+            Some(&first.to_string()),
+            &third.to_string(),
+            &PatchOptions::default(),
+        )
+        .await
+        .map(|mbox| String::from_utf8(mbox).unwrap())
+        .unwrap();
+        let subjects: Vec<&str> = mbox
+            .lines()
+            .filter(|line| line.starts_with("Subject: "))
+            .collect();
+        assert_eq!(
+            subjects,
+            [
+                "Subject: [PATCH 1/2] Rename readme and add todo",
+                "Subject: [PATCH 2/2] Update todo",
+            ]
+        );
+        assert!(mbox.contains(
+            "similarity index 100%\nrename from docs/readme.md\nrename to docs/README.md\n"
+        ));
+        assert!(mbox.contains(" create mode 100644 docs/todo.md\n"));
+
+        let root = format_patch_range(&repo, None, &first.to_string(), &PatchOptions::default())
+            .await
+            .map(|root| String::from_utf8(root).unwrap())
+            .unwrap();
+        assert!(root.contains("Subject: [PATCH 1/1] Add readme\n"));
+        assert!(root.contains("--- /dev/null\n+++ b/docs/readme.md\n"));
+
+        // `since` on a merged side branch: the series stops at the history they share
+        let notes = commit(
+            &repo,
+            &[first],
+            &[("readme.md", readme), ("notes.md", "notes\n")],
+            "\nAdd notes\n",
+        );
+        let merge = commit(
+            &repo,
+            &[third, notes],
+            &[
+                ("README.md", readme),
+                ("notes.md", "notes\n"),
+                ("todo.md", "everything\n"),
+            ],
+            "\nMerge notes\n",
+        );
+        let mbox = format_patch_range(
+            &repo,
+            Some(&notes.to_string()),
+            &merge.to_string(),
+            &PatchOptions::default(),
+        )
+        .await
+        .map(|mbox| String::from_utf8(mbox).unwrap())
+        .unwrap();
+        assert!(mbox.starts_with(&format!("From {second} ")), "{mbox}");
+        assert!(mbox.contains("Subject: [PATCH 2/2] Update todo\n"));
+        assert!(!mbox.contains("Add readme"));
+        assert!(!mbox.contains("Merge notes"));
+    }
+}
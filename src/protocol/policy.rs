@@ -0,0 +1,302 @@
+//! Branch protection policies for receive-pack.
+//!
+//! This module defines a serializable [`BranchProtection`] model mirroring the push rules of
+//! GitHub-style protected branches (force-push, deletion, linear history and signed commits).
+//! Hosts can persist the rules in their own storage, import/export them as GitHub API JSON,
+//! and hand them to [`SmartProtocol`](super::smart::SmartProtocol) so that every push is checked
+//! uniformly before any reference is updated.
+//!
+//! Review requirements are intentionally out of scope: they are a property of the code review
+//! workflow and not something the git protocol can enforce.
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use crate::internal::object::commit::Commit;
+
+/// Push rules applied to every reference whose short branch name matches `pattern`.
+///
+/// Patterns follow GitHub's fnmatch flavour: `*` matches any run of characters except `/`,
+/// `**` matches across `/`, and `?` matches a single character. Patterns are matched against
+/// the branch name without the `refs/heads/` prefix (e.g. `main`, `release/*`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BranchProtection {
+    pub pattern: String,
+    #[serde(default)]
+    pub allow_force_pushes: bool,
+    #[serde(default)]
+    pub allow_deletions: bool,
+    #[serde(default)]
+    pub require_linear_history: bool,
+    #[serde(default)]
+    pub require_signed_commits: bool,
+}
+
+/// Facts about a single reference update, computed by the protocol layer and checked
+/// against the matching [`BranchProtection`] rules.
+#[derive(Debug, Clone, Default)]
+pub struct RefUpdateFacts<'a> {
+    /// The update removes the reference.
+    pub is_delete: bool,
+    /// The old tip is not an ancestor of the new tip.
+    pub is_force: bool,
+    /// Commits introduced by this update (reachable from the new tip but not from the old one).
+    pub new_commits: &'a [Commit],
+}
+
+/// Why a reference update was rejected by a [`BranchProtection`] rule.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PolicyViolation {
+    #[error("protected branch {0}: deletion is not allowed")]
+    Deletion(String),
+
+    #[error("protected branch {0}: force-push is not allowed")]
+    ForcePush(String),
+
+    #[error("protected branch {0}: merge commit {1} violates linear history")]
+    NonLinearHistory(String, String),
+
+    #[error("protected branch {0}: commit {1} is not signed")]
+    UnsignedCommit(String, String),
+}
+
+impl PolicyViolation {
+    /// Stable identifier of the violation, independent of the (possibly translated) message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            PolicyViolation::Deletion(_) => "protected-deletion",
+            PolicyViolation::ForcePush(_) => "protected-force-push",
+            PolicyViolation::NonLinearHistory(_, _) => "non-linear-history",
+            PolicyViolation::UnsignedCommit(_, _) => "unsigned-commit",
+        }
+    }
+}
+
+impl BranchProtection {
+    /// Create a rule for `pattern` with every push capability denied but no extra requirements,
+    /// which is what GitHub applies when a branch is first marked as protected.
+    pub fn new(pattern: &str) -> Self {
+        Self {
+            pattern: pattern.to_string(),
+            allow_force_pushes: false,
+            allow_deletions: false,
+            require_linear_history: false,
+            require_signed_commits: false,
+        }
+    }
+
+    /// Check whether this rule applies to the given full reference name.
+    ///
+    /// Only branches (`refs/heads/*`) can be protected; other namespaces never match.
+    pub fn matches(&self, ref_name: &str) -> bool {
+        match ref_name.strip_prefix("refs/heads/") {
+            Some(branch) => glob_match(self.pattern.as_bytes(), branch.as_bytes()),
+            None => false,
+        }
+    }
+
+    /// Validate a reference update against this rule.
+    pub fn check(&self, ref_name: &str, facts: &RefUpdateFacts) -> Result<(), PolicyViolation> {
+        let branch = ref_name.strip_prefix("refs/heads/").unwrap_or(ref_name);
+        if facts.is_delete {
+            if !self.allow_deletions {
+                return Err(PolicyViolation::Deletion(branch.to_string()));
+            }
+            return Ok(());
+        }
+        if facts.is_force && !self.allow_force_pushes {
+            return Err(PolicyViolation::ForcePush(branch.to_string()));
+        }
+        for commit in facts.new_commits {
+            if self.require_linear_history && commit.parent_commit_ids.len() > 1 {
+                return Err(PolicyViolation::NonLinearHistory(
+                    branch.to_string(),
+                    commit.id.to_string(),
+                ));
+            }
+            if self.require_signed_commits && !is_signed(commit) {
+                return Err(PolicyViolation::UnsignedCommit(
+                    branch.to_string(),
+                    commit.id.to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Import a rule from a GitHub "branch protection" REST API document.
+    ///
+    /// The API does not carry the pattern itself (it is part of the URL), so it is passed in
+    /// separately. Both the response shape (`{"allow_deletions": {"enabled": true}}`) and the
+    /// request shape (`{"allow_deletions": true}`) are accepted.
+    pub fn from_github_json(pattern: &str, json: &str) -> Result<Self, serde_json::Error> {
+        let value: Value = serde_json::from_str(json)?;
+        let flag = |key: &str| match value.get(key) {
+            Some(Value::Bool(b)) => *b,
+            Some(Value::Object(obj)) => {
+                obj.get("enabled").and_then(Value::as_bool).unwrap_or(false)
+            }
+            _ => false,
+        };
+        Ok(Self {
+            pattern: pattern.to_string(),
+            allow_force_pushes: flag("allow_force_pushes"),
+            allow_deletions: flag("allow_deletions"),
+            require_linear_history: flag("required_linear_history"),
+            require_signed_commits: flag("required_signatures"),
+        })
+    }
+
+    /// Export the rule in the GitHub "branch protection" REST API response shape.
+    pub fn to_github_json(&self) -> String {
+        json!({
+            "allow_force_pushes": { "enabled": self.allow_force_pushes },
+            "allow_deletions": { "enabled": self.allow_deletions },
+            "required_linear_history": { "enabled": self.require_linear_history },
+            "required_signatures": { "enabled": self.require_signed_commits },
+        })
+        .to_string()
+    }
+}
+
+/// Find the first rule matching `ref_name`, GitHub applies the most specific one but a host
+/// typically stores a single rule per pattern, so first-match keeps the behaviour predictable.
+pub fn find_protection<'a>(
+    rules: &'a [BranchProtection],
+    ref_name: &str,
+) -> Option<&'a BranchProtection> {
+    rules.iter().find(|rule| rule.matches(ref_name))
+}
+
+/// A commit is considered signed when it carries a `gpgsig` header.
+fn is_signed(commit: &Commit) -> bool {
+    commit.gpg_signature().is_some()
+}
+
+/// Minimal fnmatch-style matcher used for branch patterns.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some(b'*') if pattern.get(1) == Some(&b'*') => {
+            let rest = &pattern[2..];
+            (0..=name.len()).any(|i| glob_match(rest, &name[i..]))
+        }
+        Some(b'*') => {
+            let rest = &pattern[1..];
+            for i in 0..=name.len() {
+                if glob_match(rest, &name[i..]) {
+                    return true;
+                }
+                if i < name.len() && name[i] == b'/' {
+                    break;
+                }
+            }
+            false
+        }
+        Some(b'?') => !name.is_empty() && name[0] != b'/' && glob_match(&pattern[1..], &name[1..]),
+        Some(c) => name.first() == Some(c) && glob_match(&pattern[1..], &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::SHA1;
+    use crate::internal::object::signature::{Signature, SignatureType};
+
+    fn commit_with_parents(parents: Vec<SHA1>, message: &str) -> Commit {
+        let author = Signature::new(
+            SignatureType::Author,
+            "tester".to_string(),
+            "tester@example.com".to_string(),
+        );
+        let committer = Signature::new(
+            SignatureType::Committer,
+            "tester".to_string(),
+            "tester@example.com".to_string(),
+        );
+        Commit::new(author, committer, SHA1::default(), parents, message)
+    }
+
+    #[test]
+    fn test_pattern_matching() {
+        let rule = BranchProtection::new("release/*");
+        assert!(rule.matches("refs/heads/release/1.0"));
+        assert!(!rule.matches("refs/heads/release/1.0/hotfix"));
+        assert!(!rule.matches("refs/tags/release/1.0"));
+        assert!(BranchProtection::new("release/**").matches("refs/heads/release/1.0/hotfix"));
+        assert!(BranchProtection::new("main").matches("refs/heads/main"));
+        assert!(!BranchProtection::new("main").matches("refs/heads/main2"));
+    }
+
+    #[test]
+    fn test_check_rules() {
+        let mut rule = BranchProtection::new("main");
+        let delete = RefUpdateFacts {
+            is_delete: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            rule.check("refs/heads/main", &delete),
+            Err(PolicyViolation::Deletion(_))
+        ));
+        let force = RefUpdateFacts {
+            is_force: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            rule.check("refs/heads/main", &force),
+            Err(PolicyViolation::ForcePush(_))
+        ));
+        rule.allow_force_pushes = true;
+        assert!(rule.check("refs/heads/main", &force).is_ok());
+
+        rule.require_linear_history = true;
+        rule.require_signed_commits = true;
+        let merge = commit_with_parents(vec![SHA1::new(b"a"), SHA1::new(b"b")], "\nmerge");
+        let commits = [merge];
+        let facts = RefUpdateFacts {
+            new_commits: &commits,
+            ..Default::default()
+        };
+        assert!(matches!(
+            rule.check("refs/heads/main", &facts),
+            Err(PolicyViolation::NonLinearHistory(_, _))
+        ));
+
+        let unsigned = [commit_with_parents(vec![SHA1::new(b"a")], "\nplain")];
+        let facts = RefUpdateFacts {
+            new_commits: &unsigned,
+            ..Default::default()
+        };
+        assert!(matches!(
+            rule.check("refs/heads/main", &facts),
+            Err(PolicyViolation::UnsignedCommit(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_github_json_round_trip() {
+        let json = r#"{
+            "allow_force_pushes": {"enabled": true},
+            "allow_deletions": {"enabled": false},
+            "required_linear_history": {"enabled": true},
+            "required_signatures": {"url": "https://api.github.com/x", "enabled": true},
+            "required_pull_request_reviews": {"required_approving_review_count": 2}
+        }"#;
+        let rule = BranchProtection::from_github_json("main", json).unwrap();
+        assert!(rule.allow_force_pushes);
+        assert!(!rule.allow_deletions);
+        assert!(rule.require_linear_history);
+        assert!(rule.require_signed_commits);
+
+        let exported = BranchProtection::from_github_json("main", &rule.to_github_json()).unwrap();
+        assert_eq!(rule, exported);
+
+        let request_shape = r#"{"allow_deletions": true}"#;
+        assert!(
+            BranchProtection::from_github_json("dev", request_shape)
+                .unwrap()
+                .allow_deletions
+        );
+    }
+}
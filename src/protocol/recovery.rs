@@ -0,0 +1,148 @@
+//! Rolling references back through their reflog.
+//!
+//! [`revert_ref_update`] is the `git reset --hard <ref>@{n}` of a hosted repository: it looks up
+//! the reflog entry, checks that the reference still has the value the reflog says it has, and
+//! moves it back with a compare-and-swap [`update_reference`](RepositoryAccess::update_reference),
+//! so an admin can undo a bad push without racing a concurrent one.
+use crate::protocol::core::RepositoryAccess;
+use crate::protocol::types::{ProtocolError, RefUpdate, ZERO_ID};
+
+/// Move `ref_name` back to the value it had after reflog entry `to_reflog_entry`.
+///
+/// Entries are counted like `<ref>@{n}`: `0` is the current value, `1` undoes the last update
+/// (e.g. a bad push). If the reference did not exist at that point it is deleted.
+///
+/// Fails without touching the reference when the entry does not exist, when the reference was
+/// updated since its last reflog entry, or when the target object is missing, a commit or, for a
+/// tag ref, an annotated tag. Returns the update that was applied.
+pub async fn revert_ref_update<R: RepositoryAccess>(
+    repo: &R,
+    ref_name: &str,
+    to_reflog_entry: usize,
+) -> Result<RefUpdate, ProtocolError> {
+    let reflog = repo.get_reflog(ref_name).await?;
+    let (Some(latest), Some(entry)) = (reflog.first(), reflog.get(to_reflog_entry)) else {
+        return Err(ProtocolError::InvalidRequest(format!(
+            "{ref_name}@{{{to_reflog_entry}}}: reflog has only {} entries",
+            reflog.len()
+        )));
+    };
+
+    let current = repo
+        .get_repository_refs()
+        .await?
+        .into_iter()
+        .find(|(name, _)| name == ref_name)
+        .map(|(_, hash)| hash)
+        .unwrap_or_else(|| ZERO_ID.to_string());
+    if current != latest.new_hash {
+        return Err(ProtocolError::InvalidRequest(format!(
+            "{ref_name} is at {current} but its reflog ends at {}, refusing to revert",
+            latest.new_hash
+        )));
+    }
+
+    let target = entry.new_hash.clone();
+    if target != ZERO_ID && !repo.has_object(&target).await? {
+        return Err(ProtocolError::ObjectNotFound(target));
+    }
+
+    let update = RefUpdate {
+        ref_name: ref_name.to_string(),
+        old_hash: (current != ZERO_ID).then_some(current),
+        new_hash: target,
+    };
+    repo.update_reference(
+        &update.ref_name,
+        update.old_hash.as_deref(),
+        &update.new_hash,
+    )
+    .await?;
+    Ok(update)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::SHA1;
+    use crate::internal::object::commit::Commit;
+    use crate::internal::object::signature::{Signature, SignatureType};
+    use crate::internal::object::tag::Tag;
+    use crate::internal::object::types::ObjectType;
+    use crate::protocol::memory::MemoryRepository;
+
+    /// `refs/heads/main` created at the first of three commits, then pushed to the second and
+    /// the third; the reference is then moved back to `at`, without a reflog entry.
+    async fn pushed_repo(at: usize) -> (MemoryRepository, [String; 3]) {
+        let repo = MemoryRepository::new();
+        let signature = |kind| Signature::new(kind, "t".to_string(), "t@example.com".to_string());
+        let mut parents = vec![];
+        let mut ids: Vec<SHA1> = vec![];
+        for message in ["a", "b", "c"] {
+            let commit = Commit::new(
+                signature(SignatureType::Author),
+                signature(SignatureType::Committer),
+                SHA1::default(),
+                parents,
+                &format!("\n{message}"),
+            );
+            repo.insert_object(&commit).unwrap();
+            let old = ids.last().map(SHA1::to_string);
+            repo.update_reference("refs/heads/main", old.as_deref(), &commit.id.to_string())
+                .await
+                .unwrap();
+            parents = vec![commit.id];
+            ids.push(commit.id);
+        }
+        repo.set_ref("refs/heads/main", ids[at]);
+        (repo, [0, 1, 2].map(|i| ids[i].to_string()))
+    }
+
+    #[tokio::test]
+    async fn test_revert_last_push() {
+        let (repo, [_, b, c]) = pushed_repo(2).await;
+        let update = revert_ref_update(&repo, "refs/heads/main", 1)
+            .await
+            .unwrap();
+        assert_eq!(update.old_hash, Some(c));
+        assert_eq!(update.new_hash, b);
+        assert_eq!(repo.get_ref("refs/heads/main").unwrap().to_string(), b);
+    }
+
+    #[tokio::test]
+    async fn test_revert_to_annotated_tag() {
+        let (repo, [a, _, c]) = pushed_repo(2).await;
+        let tagger = Signature::new(SignatureType::Tagger, "t".to_string(), String::new());
+        let tag = Tag::new(
+            a.parse().unwrap(),
+            ObjectType::Commit,
+            "v1".to_string(),
+            tagger,
+            String::new(),
+        );
+        repo.insert_object(&tag).unwrap();
+        let tag_id = tag.id.to_string();
+        repo.update_reference("refs/tags/v1", None, &tag_id)
+            .await
+            .unwrap();
+        repo.update_reference("refs/tags/v1", Some(&tag_id), &c)
+            .await
+            .unwrap();
+
+        let update = revert_ref_update(&repo, "refs/tags/v1", 1).await.unwrap();
+        assert_eq!(update.new_hash, tag_id);
+        assert_eq!(repo.get_ref("refs/tags/v1").unwrap(), tag.id);
+    }
+
+    #[tokio::test]
+    async fn test_revert_refuses_stale_reflog() {
+        let (repo, [a, ..]) = pushed_repo(0).await;
+        let result = revert_ref_update(&repo, "refs/heads/main", 1).await;
+        assert!(matches!(result, Err(ProtocolError::InvalidRequest(_))));
+        assert_eq!(repo.get_ref("refs/heads/main").unwrap().to_string(), a);
+
+        let (repo, _) = pushed_repo(2).await;
+        let result = revert_ref_update(&repo, "refs/heads/main", 3).await;
+        assert!(matches!(result, Err(ProtocolError::InvalidRequest(_))));
+    }
+}
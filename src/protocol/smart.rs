@@ -1,16 +1,53 @@
 use bytes::{BufMut, Bytes, BytesMut};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use tokio_stream::wrappers::ReceiverStream;
 
-use super::core::{AuthenticationService, RepositoryAccess};
-use super::pack::PackGenerator;
+use crate::errors::GitError;
+use crate::fsck::FsckOptions;
+use crate::hash::SHA1;
+use crate::internal::object::ObjectTrait;
+use crate::internal::object::commit::Commit;
+use crate::internal::object::signing::{SignatureVerifier, Verification};
+use crate::internal::object::tag::Tag;
+use crate::internal::object::types::ObjectType;
+use crate::internal::pack::Pack;
+use crate::internal::pack::bloom::ObjectFilter;
+use crate::internal::refs::validate_ref_name;
+use crate::revwalk::{GenerationNumbers, is_ancestor};
+
+use super::bundle_uri::BundleList;
+use super::codec::PktLineReader;
+use super::core::{AuthenticationService, AuthorizationService, RefOperation, RepositoryAccess};
+use super::hidden_refs::{HiddenRefs, RefFilter, RefFilterContext, Visibility};
+use super::pack::{
+    DeltaOptions, PackGenerator, PackObjects, UnpackOptions, UnpackedPack, load_object,
+    object_count,
+};
+use super::policy::{BranchProtection, PolicyViolation, RefUpdateFacts, find_protection};
+use super::trace::{PacketDirection, PacketTracer, trace_packets};
 use super::types::ProtocolError;
 use super::types::{
-    COMMON_CAP_LIST, Capability, LF, NUL, PKT_LINE_END_MARKER, ProtocolStream, RECEIVE_CAP_LIST,
-    RefCommand, RefTypeEnum, SP, ServiceType, SideBand, TransportProtocol, UPLOAD_CAP_LIST,
-    ZERO_ID,
+    COMMON_CAP_LIST, Capability, CommandStatus, DEFAULT_AGENT, LF, NUL, NegotiationState,
+    OBJECT_FORMAT, ObjectProvenance, PKT_LINE_DELIM_MARKER, PKT_LINE_END_MARKER, ProtocolStream,
+    ProtocolVersion, RECEIVE_CAP_LIST, RefCommand, RefTypeEnum, RefUpdate, SP, ServiceType,
+    SideBand, TransportProtocol, UPLOAD_CAP_LIST, WantPolicy, ZERO_ID,
+};
+use super::upload_archive::ArchiveRequest;
+use super::utils::{
+    MAX_PKT_LINE_LENGTH, PktLine, add_pkt_line_parts, add_pkt_line_string, add_service_header,
+    band_packets, build_error_pkt_line, parse_pkt_line, parse_word, read_until_white_space,
+    side_band_limit, side_band_packets, split_word,
 };
-use super::utils::{add_pkt_line_string, build_smart_reply, read_pkt_line, read_until_white_space};
+
+/// The most tags of tags `ls-refs` peels through
+const MAX_PEEL_DEPTH: usize = 32;
+
+/// Callback receiving the client capabilities unknown to this crate, see
+/// [`SmartProtocol::set_unknown_capability_handler`]
+pub type UnknownCapabilityHandler = Arc<dyn Fn(&str) + Send + Sync>;
 
 /// Smart Git Protocol implementation
 ///
@@ -22,9 +59,61 @@ where
     A: AuthenticationService,
 {
     pub transport_protocol: TransportProtocol,
+    /// Selected by [`SmartProtocol::negotiate_protocol_version`], version 0 until then
+    pub protocol_version: ProtocolVersion,
+    /// The upload-pack negotiation so far, updated by every request
+    pub negotiation: NegotiationState,
     pub capabilities: Vec<Capability>,
     pub side_band: Option<SideBand>,
     pub command_list: Vec<RefCommand>,
+    pub branch_protections: Vec<BranchProtection>,
+    /// When set, pushed tags must be annotated tags with a good signature
+    pub tag_verifier: Option<Arc<dyn SignatureVerifier>>,
+    /// Authenticated user, recorded in the provenance of pushed objects
+    pub pusher: Option<String>,
+    /// Advertised in the `agent` capability
+    pub agent: String,
+    /// Advertised in the `session-id` capability, a random id unless set
+    pub session_id: String,
+    /// Advertised after the built-in capabilities of their service
+    pub extra_capabilities: Vec<(ServiceType, Capability)>,
+    /// Called with every capability of the client that isn't known to this crate
+    pub unknown_capability_handler: Option<UnknownCapabilityHandler>,
+    /// When set, every reference update of a push must be authorized for the pusher
+    pub authorization: Option<Arc<dyn AuthorizationService>>,
+    /// Consulted before asking the repository whether an object exists
+    pub object_filter: Option<Arc<ObjectFilter>>,
+    /// Refs left out of the advertisement, not fetchable and not pushable
+    pub hidden_refs: HiddenRefs,
+    /// Which objects clients may want, the advertised tips by default
+    pub want_policy: WantPolicy,
+    /// Let upload-archive archive any object the repository has, not only the tree-ishes of
+    /// the refs clients see, like `uploadArchive.allowUnreachable`
+    pub allow_unreachable_archives: bool,
+    /// The bundles listed by the protocol v2 `bundle-uri` command, advertised when set
+    pub bundle_list: Option<BundleList>,
+    /// Consulted for every ref not in `hidden_refs`, with the authenticated user
+    pub ref_filter: Option<Arc<dyn RefFilter>>,
+    /// Receives every pkt-line read from the client and written to it
+    pub packet_tracer: Option<Arc<dyn PacketTracer>>,
+    /// Branches advertised as `HEAD` when the repository reports neither a resolvable default
+    /// branch nor a resolvable `HEAD`
+    pub head_fallbacks: Vec<String>,
+    /// Build the `.idx` of received packs and hand both to `RepositoryAccess::store_kept_pack`,
+    /// keeping the pack until the references of the push are updated
+    pub write_pack_index: bool,
+    /// With `write_pack_index`, packs of fewer objects than this are exploded into individual
+    /// objects instead of being kept, like `receive.unpackLimit`; `None` keeps every pack
+    pub unpack_limit: Option<u32>,
+    /// Delta window, depth and big file threshold of the packs sent to fetches
+    pub delta_options: DeltaOptions,
+    /// Memory limit of unpacking pushed packs, past which delta bases spill to disk
+    pub unpack_options: UnpackOptions,
+    /// Check pushed objects before storing them, like `receive.fsckObjects`; a problem at
+    /// [`FsckSeverity::Error`](crate::fsck::FsckSeverity::Error) rejects the push. Pushes are
+    /// checked in [strict](FsckOptions::strict) mode like git does, so warnings are errors
+    /// unless their severity is set
+    pub fsck_objects: Option<FsckOptions>,
 
     // Trait-based dependencies
     repo_storage: R,
@@ -43,6 +132,29 @@ where
             capabilities: Vec::new(),
             side_band: None,
             command_list: Vec::new(),
+            branch_protections: Vec::new(),
+            tag_verifier: None,
+            pusher: None,
+            agent: DEFAULT_AGENT.to_string(),
+            session_id: uuid::Uuid::new_v4().to_string(),
+            protocol_version: ProtocolVersion::V0,
+            negotiation: NegotiationState::default(),
+            extra_capabilities: Vec::new(),
+            unknown_capability_handler: None,
+            authorization: None,
+            object_filter: None,
+            hidden_refs: HiddenRefs::new(),
+            want_policy: WantPolicy::default(),
+            allow_unreachable_archives: false,
+            bundle_list: None,
+            ref_filter: None,
+            packet_tracer: None,
+            head_fallbacks: DEFAULT_HEAD_FALLBACKS.map(String::from).to_vec(),
+            write_pack_index: false,
+            unpack_limit: None,
+            delta_options: DeltaOptions::default(),
+            unpack_options: UnpackOptions::default(),
+            fsck_objects: None,
             repo_storage,
             auth_service,
         }
@@ -72,35 +184,201 @@ where
         self.transport_protocol = protocol;
     }
 
+    /// Set the branch protection rules enforced during receive-pack
+    pub fn set_branch_protections(&mut self, rules: Vec<BranchProtection>) {
+        self.branch_protections = rules;
+    }
+
+    /// Reject pushed tags that are not signed, or whose signature `verifier` does not accept
+    pub fn set_tag_verifier(&mut self, verifier: Arc<dyn SignatureVerifier>) {
+        self.tag_verifier = Some(verifier);
+    }
+
+    /// Set the authenticated user, recorded as the pusher of received objects
+    pub fn set_pusher(&mut self, pusher: &str) {
+        self.pusher = Some(pusher.to_string());
+    }
+
+    /// Advertise `agent` instead of `git-internal/<version>`, e.g. to name the host's build.
+    /// Like git, characters that can't appear in a capability are replaced by `.`.
+    pub fn set_agent(&mut self, agent: &str) {
+        self.agent = capability_value(agent);
+    }
+
+    /// The agent the client sent, e.g. `git/2.43.0`, to tell client versions apart in logs
+    pub fn client_agent(&self) -> Option<&str> {
+        self.capabilities.iter().find_map(|cap| match cap {
+            Capability::Agent(agent) => Some(agent.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Select the protocol version of the session from the transport hints, see
+    /// [`ProtocolVersion::from_hints`], e.g. the `Git-Protocol` header of the request
+    ///
+    /// The selected version is recorded for the rest of the session and returned. In version 2,
+    /// upload-pack opens with [`git_v2_capabilities`](Self::git_v2_capabilities) and serves
+    /// [`git_v2_command`](Self::git_v2_command); receive-pack has no version 2 and answers
+    /// in version 0, like git.
+    pub fn negotiate_protocol_version<'a>(
+        &mut self,
+        hints: impl IntoIterator<Item = &'a str>,
+    ) -> ProtocolVersion {
+        self.protocol_version = ProtocolVersion::from_hints(hints);
+        self.protocol_version
+    }
+
+    /// Resume the upload-pack negotiation from `state`, e.g. kept by the host since the
+    /// previous request of the fetch
+    pub fn set_negotiation(&mut self, state: NegotiationState) {
+        self.negotiation = state;
+    }
+
+    /// Advertise `session_id` instead of the generated one, e.g. the id of the host's request,
+    /// sanitized like [`SmartProtocol::set_agent`]
+    pub fn set_session_id(&mut self, session_id: &str) {
+        self.session_id = capability_value(session_id);
+    }
+
+    /// The `session-id` the client sent, naming its side of the session in its own traces
+    pub fn client_session_id(&self) -> Option<&str> {
+        self.capabilities.iter().find_map(|cap| match cap {
+            Capability::SessionId(id) => Some(id.as_str()),
+            _ => None,
+        })
+    }
+
+    /// A span carrying the session ids of both sides and the client agent, for the host to
+    /// run the request in so its logs can be matched with the client's
+    pub fn session_span(&self) -> tracing::Span {
+        tracing::info_span!(
+            "git_session",
+            session_id = %self.session_id,
+            client_session_id = self.client_session_id(),
+            client_agent = self.client_agent(),
+        )
+    }
+
+    /// Also advertise `capability` to clients of `service`, e.g. an experimental
+    /// `Capability::Unknown("x-review-refs".into())` handled by the host
+    pub fn add_capability(&mut self, service: ServiceType, capability: Capability) {
+        self.extra_capabilities.push((service, capability));
+    }
+
+    /// Call `handler` with each capability the client sends that this crate doesn't know, such
+    /// as the ones added with [`SmartProtocol::add_capability`]
+    pub fn set_unknown_capability_handler(
+        &mut self,
+        handler: impl Fn(&str) + Send + Sync + 'static,
+    ) {
+        self.unknown_capability_handler = Some(Arc::new(handler));
+    }
+
+    /// Reject the reference updates `authorization` doesn't allow for the pusher
+    pub fn set_authorization_service(&mut self, authorization: Arc<dyn AuthorizationService>) {
+        self.authorization = Some(authorization);
+    }
+
+    /// Use a Bloom filter of the repository's objects to skip backend lookups for objects that
+    /// are certainly missing. Objects received by later pushes are added to it.
+    pub fn set_object_filter(&mut self, filter: Arc<ObjectFilter>) {
+        self.object_filter = Some(filter);
+    }
+
+    /// Set the branches tried, in order, for `HEAD` when the repository reports no default
+    /// branch and no `HEAD`, or unresolved ones (e.g. a detached worktree `HEAD` that isn't
+    /// served).
+    pub fn set_head_fallbacks(&mut self, fallbacks: Vec<String>) {
+        self.head_fallbacks = fallbacks;
+    }
+
+    /// Hide the refs matching `hidden_refs` from clients, like git's `hideRefs` settings
+    pub fn set_hidden_refs(&mut self, hidden_refs: HiddenRefs) {
+        self.hidden_refs = hidden_refs;
+    }
+
+    /// Set which objects clients may want, like git's `uploadpack.allow*SHA1InWant`, and
+    /// advertise the matching `allow-*-sha1-in-want` capabilities
+    pub fn set_want_policy(&mut self, policy: WantPolicy) {
+        self.want_policy = policy;
+    }
+
+    /// Ask `filter` which refs the authenticated user (see `set_pusher`) may see, fetch and push
+    pub fn set_ref_filter(&mut self, filter: Arc<dyn RefFilter>) {
+        self.ref_filter = Some(filter);
+    }
+
+    /// Hand every pkt-line of the requests and replies to `tracer`, like `GIT_TRACE_PACKET`
+    pub fn set_packet_tracer(&mut self, tracer: Arc<dyn PacketTracer>) {
+        self.packet_tracer = Some(tracer);
+    }
+
+    fn trace_outbound(&self, reply: &[u8]) {
+        if let Some(tracer) = &self.packet_tracer {
+            trace_packets(tracer.as_ref(), PacketDirection::Outbound, reply);
+        }
+    }
+
+    /// The `ERR` reply failing a fetch, e.g. of an object the client may not fetch
+    fn error_reply(&self, message: &str) -> ReceiverStream<Vec<u8>> {
+        let reply = build_error_pkt_line(message);
+        self.trace_outbound(&reply);
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        // The channel has room for the reply
+        let _ = tx.try_send(reply.to_vec());
+        ReceiverStream::new(rx)
+    }
+
     /// Get git info refs for the repository, with explicit service type
+    ///
+    /// Upload-pack in protocol version 2 opens with its capabilities instead, see
+    /// [`git_v2_capabilities`](Self::git_v2_capabilities).
     pub async fn git_info_refs(
         &self,
         service_type: ServiceType,
     ) -> Result<BytesMut, ProtocolError> {
-        let refs =
-            self.repo_storage.get_repository_refs().await.map_err(|e| {
-                ProtocolError::repository_error(format!("Failed to get refs: {}", e))
-            })?;
-
-        // Convert to the expected format (head_hash, git_refs)
-        let head_hash = refs
-            .iter()
-            .find(|(name, _)| {
-                name == "HEAD" || name.ends_with("/main") || name.ends_with("/master")
-            })
-            .map(|(_, hash)| hash.clone())
-            .unwrap_or_else(|| "0000000000000000000000000000000000000000".to_string());
+        if self.protocol_version == ProtocolVersion::V2 && service_type == ServiceType::UploadPack {
+            return Ok(self.git_v2_capabilities());
+        }
+        let refs = self.visible_refs(service_type, false).await?;
 
-        let git_refs: Vec<super::types::GitRef> = refs
-            .into_iter()
-            .map(|(name, hash)| super::types::GitRef { name, hash })
-            .collect();
+        // Convert to the expected format (head_hash, git_refs), HEAD is only advertised once,
+        // with the branch it points to when that is known.
+        let (head_hash, head_target) = self
+            .resolve_head(&refs)
+            .await?
+            .unwrap_or_else(|| (ZERO_ID.to_string(), None));
 
         // Determine capabilities based on service type
-        let cap_list = match service_type {
+        let mut cap_list = match service_type {
             ServiceType::UploadPack => format!("{UPLOAD_CAP_LIST}{COMMON_CAP_LIST}"),
             ServiceType::ReceivePack => format!("{RECEIVE_CAP_LIST}{COMMON_CAP_LIST}"),
+            // upload-archive reads its arguments without advertising refs
+            ServiceType::UploadArchive => {
+                return Err(ProtocolError::invalid_service(&service_type.to_string()));
+            }
         };
+        cap_list.push_str(&format!(" {}", Capability::Agent(self.agent.clone())));
+        cap_list.push_str(&format!(
+            " {}",
+            Capability::SessionId(self.session_id.clone())
+        ));
+        if service_type == ServiceType::UploadPack {
+            for cap in self.want_policy.capabilities() {
+                cap_list.push_str(&format!(" {cap}"));
+            }
+        }
+        for (service, cap) in &self.extra_capabilities {
+            if *service == service_type {
+                cap_list.push_str(&format!(" {cap}"));
+            }
+        }
+        if let Some(target) = head_target {
+            cap_list.push_str(&format!(
+                " {}",
+                Capability::Symref(format!("HEAD:{target}"))
+            ));
+        }
 
         // The stream MUST include capability declarations behind a NUL on the first ref.
         let name = if head_hash == ZERO_ID {
@@ -109,207 +387,355 @@ where
             "HEAD"
         };
         let pkt_line = format!("{head_hash}{SP}{name}{NUL}{cap_list}{LF}");
-        let mut ref_list = vec![pkt_line];
-
-        for git_ref in git_refs {
-            let pkt_line = format!("{}{}{}{}", git_ref.hash, SP, git_ref.name, LF);
-            ref_list.push(pkt_line);
+        // Written straight into the reply, advertisements can list many thousands of refs
+        let size: usize = refs
+            .iter()
+            .map(|(name, hash)| name.len() + hash.len() + 6)
+            .sum();
+        let mut pkt_line_stream = BytesMut::with_capacity(pkt_line.len() + size + 64);
+        add_service_header(
+            &mut pkt_line_stream,
+            self.transport_protocol,
+            &service_type.to_string(),
+        );
+        if self.protocol_version == ProtocolVersion::V1 {
+            add_pkt_line_string(&mut pkt_line_stream, "version 1\n".to_string());
         }
-
-        let pkt_line_stream =
-            build_smart_reply(self.transport_protocol, &ref_list, service_type.to_string());
+        add_pkt_line_string(&mut pkt_line_stream, pkt_line);
+        for (name, hash) in refs.iter().filter(|(name, _)| name != "HEAD") {
+            add_pkt_line_parts(
+                &mut pkt_line_stream,
+                &[hash.as_bytes(), b" ", name.as_bytes(), b"\n"],
+            );
+        }
+        pkt_line_stream.put(&PKT_LINE_END_MARKER[..]);
         tracing::debug!("git_info_refs, return: --------> {:?}", pkt_line_stream);
+        self.trace_outbound(&pkt_line_stream);
         Ok(pkt_line_stream)
     }
 
+    /// The value of HEAD among `refs`, with the branch it points to when that is known
+    ///
+    /// HEAD resolves to the default branch, then the HEAD the repository reports, then the
+    /// fallbacks.
+    async fn resolve_head(
+        &self,
+        refs: &[(String, String)],
+    ) -> Result<Option<(String, Option<String>)>, ProtocolError> {
+        let default_branch = self.repo_storage.default_branch().await.map_err(|e| {
+            ProtocolError::repository_error(format!("Failed to get default branch: {}", e))
+        })?;
+        let lookup = |wanted: &str| {
+            refs.iter()
+                .find(|(name, hash)| name == wanted && hash != ZERO_ID)
+                .map(|(_, hash)| hash.clone())
+        };
+        let branch = |target: &String| lookup(target).map(|hash| (hash, Some(target.clone())));
+        Ok(default_branch
+            .as_ref()
+            .and_then(branch)
+            .or_else(|| lookup("HEAD").map(|hash| (hash, None)))
+            .or_else(|| self.head_fallbacks.iter().find_map(branch)))
+    }
+
     /// Handle git-upload-pack request
+    ///
+    /// See [`git_upload_pack_stream`](Self::git_upload_pack_stream), which reads the request
+    /// as it arrives.
     pub async fn git_upload_pack(
         &mut self,
         upload_request: Bytes,
     ) -> Result<(ReceiverStream<Vec<u8>>, BytesMut), ProtocolError> {
-        let mut upload_request = upload_request;
+        self.git_upload_pack_stream(Box::pin(futures::stream::iter([Ok(upload_request)])))
+            .await
+    }
+
+    /// Handle git-upload-pack request, reading it from `upload_request` as it arrives
+    ///
+    /// A request is one round of the negotiation, as over smart HTTP where every round is a
+    /// request of its own: the haves are acknowledged according to the negotiated multi_ack
+    /// mode, and when the haves end with a flush-pkt instead of `done` the reply holds only
+    /// the acknowledgments, for the client to send its next round. The pack is generated once
+    /// the client is `done`, or right away with no-done once the server is ready. What the
+    /// rounds established is kept in [`negotiation`](Self::negotiation).
+    pub async fn git_upload_pack_stream(
+        &mut self,
+        upload_request: ProtocolStream,
+    ) -> Result<(ReceiverStream<Vec<u8>>, BytesMut), ProtocolError> {
+        self.git_upload_pack_round(&mut PktLineReader::new(upload_request))
+            .await
+    }
+
+    /// Handle one round of a git-upload-pack negotiation read from `upload_request`
+    ///
+    /// Like [`git_upload_pack_stream`](Self::git_upload_pack_stream), but the pkt-lines after
+    /// the round are left in `upload_request`, for stateful transports like SSH where the
+    /// rounds follow each other on one connection: there the wants are only sent in the first
+    /// round, and the flush-pkt of a round without them ends its haves.
+    pub async fn git_upload_pack_round<S>(
+        &mut self,
+        upload_request: &mut PktLineReader<S>,
+    ) -> Result<(ReceiverStream<Vec<u8>>, BytesMut), ProtocolError>
+    where
+        S: futures::Stream<Item = Result<Bytes, ProtocolError>> + Unpin,
+    {
+        upload_request.set_packet_tracer(self.packet_tracer.clone());
+        // A resumed stateful negotiation continues with the haves
+        let resumed = !self.negotiation.wants.is_empty();
         let mut want: Vec<String> = Vec::new();
         let mut have: Vec<String> = Vec::new();
-        let mut last_common_commit = String::new();
+        let mut shallow: Vec<String> = Vec::new();
 
         let mut read_first_line = false;
+        // The flush-pkt after the wants opens the haves, the next one ends the round
+        let mut reading_haves = false;
+        let mut done = true;
         loop {
-            let (bytes_take, pkt_line) = read_pkt_line(&mut upload_request);
-
-            if bytes_take == 0 {
-                break;
-            }
-
-            if pkt_line.is_empty() {
-                break;
-            }
-
-            let mut pkt_line = pkt_line;
-            let command = read_until_white_space(&mut pkt_line);
+            let mut pkt_line = match futures::StreamExt::next(upload_request).await.transpose()? {
+                Some(PktLine::Data(pkt_line)) => pkt_line,
+                // Sections of a protocol v2 request
+                Some(PktLine::Delim) => continue,
+                Some(PktLine::Flush) if !reading_haves && (!resumed || !want.is_empty()) => {
+                    reading_haves = true;
+                    continue;
+                }
+                Some(PktLine::Flush) => {
+                    done = false;
+                    break;
+                }
+                Some(PktLine::ResponseEnd) | None => break,
+            };
+            let command = split_word(&mut pkt_line);
 
-            match command.as_str() {
-                "want" => {
-                    let hash = read_until_white_space(&mut pkt_line);
+            match &command[..] {
+                b"want" => {
+                    let hash = parse_word(&mut pkt_line)?;
                     want.push(hash);
                     if !read_first_line {
-                        let cap_str = String::from_utf8_lossy(&pkt_line).to_string();
-                        self.parse_capabilities(&cap_str);
+                        self.parse_capabilities(&String::from_utf8_lossy(&pkt_line));
+                        self.check_object_format()?;
                         read_first_line = true;
                     }
                 }
-                "have" => {
-                    let hash = read_until_white_space(&mut pkt_line);
+                b"shallow" => {
+                    shallow.push(parse_word(&mut pkt_line)?);
+                }
+                b"have" => {
+                    let hash = parse_word(&mut pkt_line)?;
                     have.push(hash);
                 }
-                "done" => {
+                b"done" => {
                     break;
                 }
                 _ => {
-                    tracing::warn!("Unknown upload-pack command: {}", command);
+                    tracing::warn!(
+                        "Unknown upload-pack command: {}",
+                        String::from_utf8_lossy(&command)
+                    );
                 }
             }
         }
 
-        let mut protocol_buf = BytesMut::new();
-
-        // Create pack generator for this operation
-        let pack_generator = PackGenerator::new(&self.repo_storage);
+        if let Some(refused) = self.check_wants(&want).await? {
+            let reply = self.error_reply(&format!("upload-pack: not our ref {refused}"));
+            return Ok((reply, BytesMut::new()));
+        }
 
-        if have.is_empty() {
-            // Full pack
-            add_pkt_line_string(&mut protocol_buf, String::from("NAK\n"));
-            let pack_stream = pack_generator.generate_full_pack(want).await?;
-            return Ok((pack_stream, protocol_buf));
+        // Clients send their wants again in every round, a resumed negotiation may not
+        if !want.is_empty() {
+            self.negotiation.wants = want;
+        }
+        for id in shallow {
+            if !self.negotiation.shallow.contains(&id) {
+                self.negotiation.shallow.push(id);
+            }
         }
 
-        // Check for common commits
+        // Acknowledge the haves like git, per multi_ack mode
+        let detailed = self.capabilities.contains(&Capability::MultiAckDetailed);
+        let multi_ack = detailed || self.capabilities.contains(&Capability::MultiAck);
+        let mut protocol_buf = BytesMut::new();
+        let mut acks = BytesMut::new();
+        let mut got_common = false;
+        let mut got_other = false;
+        let mut sent_ready = false;
         for hash in &have {
-            let exists = self.repo_storage.commit_exists(hash).await.map_err(|e| {
-                ProtocolError::repository_error(format!("Failed to check commit existence: {}", e))
-            })?;
+            let known = self.negotiation.common.contains(hash);
+            let exists = known
+                || (may_exist(self.object_filter.as_deref(), hash)
+                    && self.repo_storage.commit_exists(hash).await.map_err(|e| {
+                        ProtocolError::repository_error(format!(
+                            "Failed to check commit existence: {}",
+                            e
+                        ))
+                    })?);
             if exists {
-                add_pkt_line_string(&mut protocol_buf, format!("ACK {hash} common\n"));
-                if last_common_commit.is_empty() {
-                    last_common_commit = hash.clone();
+                let first = !got_common;
+                got_common = true;
+                if !known {
+                    self.negotiation.common.push(hash.clone());
+                }
+                if detailed {
+                    add_pkt_line_string(&mut acks, format!("ACK {hash} common\n"));
+                } else if multi_ack {
+                    add_pkt_line_string(&mut acks, format!("ACK {hash} continue\n"));
+                } else if first {
+                    add_pkt_line_string(&mut acks, format!("ACK {hash}\n"));
+                }
+            } else {
+                got_other = true;
+                if multi_ack && self.ok_to_give_up() {
+                    let status = if detailed { "ready" } else { "continue" };
+                    sent_ready |= detailed;
+                    add_pkt_line_string(&mut acks, format!("ACK {hash} {status}\n"));
                 }
             }
         }
-
-        if last_common_commit.is_empty() {
-            // No common commits found
-            add_pkt_line_string(&mut protocol_buf, String::from("NAK\n"));
-            let pack_stream = pack_generator.generate_full_pack(want).await?;
-            return Ok((pack_stream, protocol_buf));
+        let last_common = self.negotiation.common.last().cloned();
+
+        if !done {
+            // The end of a round: the client sends more haves in its next request, unless the
+            // server is ready and the client doesn't wait for `done` with no-done
+            if detailed
+                && got_common
+                && !got_other
+                && self.ok_to_give_up()
+                && let Some(last) = &last_common
+            {
+                add_pkt_line_string(&mut acks, format!("ACK {last} ready\n"));
+                sent_ready = true;
+            }
+            if last_common.is_none() || multi_ack {
+                add_pkt_line_string(&mut acks, "NAK\n".to_string());
+            }
+            let no_done = detailed && self.capabilities.contains(&Capability::NoDone);
+            if !(no_done && sent_ready) {
+                protocol_buf.put(acks);
+                self.trace_outbound(&protocol_buf);
+                return Ok((
+                    ReceiverStream::new(tokio::sync::mpsc::channel(1).1),
+                    protocol_buf,
+                ));
+            }
+        }
+        match &last_common {
+            Some(last) if multi_ack || !done => {
+                add_pkt_line_string(&mut acks, format!("ACK {last}\n"));
+            }
+            Some(_) => {}
+            None => add_pkt_line_string(&mut acks, "NAK\n".to_string()),
         }
+        self.negotiation.done = true;
 
-        // Generate incremental pack
-        add_pkt_line_string(
-            &mut protocol_buf,
-            format!("ACK {last_common_commit} ready\n"),
-        );
-        protocol_buf.put(&PKT_LINE_END_MARKER[..]);
+        let want = self.negotiation.wants.clone();
+        protocol_buf.put(acks);
 
-        add_pkt_line_string(&mut protocol_buf, format!("ACK {last_common_commit} \n"));
+        // Create pack generator for this operation
+        let mut pack_generator = PackGenerator::new(&self.repo_storage);
+        pack_generator.set_ofs_delta(self.capabilities.contains(&Capability::OfsDelta));
+        pack_generator.set_delta_options(self.delta_options);
 
-        let pack_stream = pack_generator.generate_incremental_pack(want, have).await?;
+        let objects = if last_common.is_none() {
+            pack_generator.full_pack_objects(want).await?
+        } else {
+            let common = self.negotiation.common.clone();
+            pack_generator
+                .incremental_pack_objects(want, common)
+                .await?
+        };
+        // The side-band starts after the acknowledgments, progress goes ahead of the pack
+        let progress = format!("Enumerating objects: {}, done.\n", object_count(&objects));
+        if let Some(progress) = self.build_progress(ServiceType::UploadPack, &progress) {
+            protocol_buf.put(progress);
+        }
+        let pack_stream = pack_generator.generate_pack(objects).await?;
+        self.trace_outbound(&protocol_buf);
 
         Ok((pack_stream, protocol_buf))
     }
 
-    /// Parse receive pack commands from protocol bytes
-    pub fn parse_receive_pack_commands(&mut self, mut protocol_bytes: Bytes) {
-        loop {
-            let (bytes_take, pkt_line) = read_pkt_line(&mut protocol_bytes);
+    /// Whether the client may stop sending haves, like git's `ok_to_give_up`, approximated
+    /// as soon as a common commit is known
+    fn ok_to_give_up(&self) -> bool {
+        !self.negotiation.common.is_empty()
+    }
 
-            if bytes_take == 0 {
-                break;
-            }
+    /// Parse receive pack commands from protocol bytes, up to the flush-pkt
+    pub fn parse_receive_pack_commands(
+        &mut self,
+        mut protocol_bytes: Bytes,
+    ) -> Result<(), ProtocolError> {
+        let request = protocol_bytes.clone();
+        while let Some(PktLine::Data(pkt_line)) = parse_pkt_line(&mut protocol_bytes)? {
+            self.add_command_line(pkt_line);
+        }
+        if let Some(tracer) = &self.packet_tracer {
+            let read = request.len() - protocol_bytes.len();
+            trace_packets(tracer.as_ref(), PacketDirection::Inbound, &request[..read]);
+        }
+        Ok(())
+    }
 
-            if pkt_line.is_empty() {
-                break;
+    /// Parse the command list at the start of a receive-pack request while it arrives,
+    /// returning the bytes received after the flush-pkt (the beginning of the pack).
+    ///
+    /// Only incomplete pkt-lines are buffered, so the cost stays linear in the number of
+    /// commands no matter how the transport chunks the request.
+    pub async fn read_receive_pack_commands(
+        &mut self,
+        stream: &mut ProtocolStream,
+    ) -> Result<BytesMut, ProtocolError> {
+        let mut commands = PktLineReader::new(stream);
+        commands.set_packet_tracer(self.packet_tracer.clone());
+        // A push without commands starts with the pack directly
+        if self.command_list.is_empty() && commands.fill_buf(4).await?.starts_with(b"PACK") {
+            return Ok(commands.into_parts().0);
+        }
+        while let Some(pkt_line) = futures::StreamExt::next(&mut commands).await.transpose()? {
+            match pkt_line {
+                PktLine::Data(pkt_line) => self.add_command_line(pkt_line),
+                PktLine::Flush => break,
+                PktLine::Delim | PktLine::ResponseEnd => {
+                    return Err(ProtocolError::invalid_request(
+                        "Unexpected delimiter in the command list",
+                    ));
+                }
             }
+        }
+        Ok(commands.into_parts().0)
+    }
 
-            let ref_command = self.parse_ref_command(&mut pkt_line.clone());
-            self.command_list.push(ref_command);
+    fn add_command_line(&mut self, mut pkt_line: Bytes) {
+        let ref_command = self.parse_ref_command(&mut pkt_line);
+        // Capabilities are only sent after the first command
+        if self.command_list.is_empty() {
+            self.parse_capabilities(&String::from_utf8_lossy(&pkt_line));
         }
+        self.command_list.push(ref_command);
     }
 
     /// Handle git receive-pack operation (push)
+    ///
+    /// The commands are read from the start of `data_stream` unless they were already
+    /// parsed into `command_list`.
     pub async fn git_receive_pack_stream(
         &mut self,
         data_stream: ProtocolStream,
     ) -> Result<Bytes, ProtocolError> {
-        // Collect all pack data from stream
-        let mut pack_data = BytesMut::new();
-        let mut stream = data_stream;
-
-        while let Some(chunk_result) = futures::StreamExt::next(&mut stream).await {
-            let chunk = chunk_result
-                .map_err(|e| ProtocolError::invalid_request(&format!("Stream error: {}", e)))?;
-            pack_data.extend_from_slice(&chunk);
-        }
-
-        // Create pack generator for unpacking
-        let pack_generator = PackGenerator::new(&self.repo_storage);
-
-        // Unpack the received data
-        let (commits, trees, blobs) = pack_generator.unpack_stream(pack_data.freeze()).await?;
-
-        // Store the unpacked objects via the repository access trait
-        self.repo_storage
-            .handle_pack_objects(commits, trees, blobs)
-            .await
-            .map_err(|e| {
-                ProtocolError::repository_error(format!("Failed to store pack objects: {}", e))
-            })?;
+        let mut context = match self.receive_pack_objects(data_stream).await {
+            Err(ProtocolError::PackCorrupt { reason, .. }) => {
+                return Ok(self.unpack_failure_report(&reason));
+            }
+            context => context?,
+        };
 
         // Build status report
         let mut report_status = BytesMut::new();
         add_pkt_line_string(&mut report_status, "unpack ok\n".to_owned());
-
-        let mut default_exist = self.repo_storage.has_default_branch().await.map_err(|e| {
-            ProtocolError::repository_error(format!("Failed to check default branch: {}", e))
-        })?;
-
-        // Update refs with proper error handling
-        for command in &mut self.command_list {
-            if command.ref_type == RefTypeEnum::Tag {
-                // Just update if refs type is tag
-                // Convert ZERO_ID to None for old hash
-                let old_hash = if command.old_hash == ZERO_ID {
-                    None
-                } else {
-                    Some(command.old_hash.as_str())
-                };
-                if let Err(e) = self
-                    .repo_storage
-                    .update_reference(&command.ref_name, old_hash, &command.new_hash)
-                    .await
-                {
-                    command.failed(e.to_string());
-                }
-            } else {
-                // Handle default branch setting for the first branch
-                if !default_exist {
-                    command.default_branch = true;
-                    default_exist = true;
-                }
-                // Convert ZERO_ID to None for old hash
-                let old_hash = if command.old_hash == ZERO_ID {
-                    None
-                } else {
-                    Some(command.old_hash.as_str())
-                };
-                if let Err(e) = self
-                    .repo_storage
-                    .update_reference(&command.ref_name, old_hash, &command.new_hash)
-                    .await
-                {
-                    command.failed(e.to_string());
-                }
-            }
-            add_pkt_line_string(&mut report_status, command.get_status());
+        for batch in self.command_list.chunks_mut(REF_UPDATE_BATCH_SIZE) {
+            report_status.put(context.apply_batch(batch).await);
         }
+        release_kept_pack(&context.repo, context.kept_pack.as_ref()).await;
 
         // Post-receive hook
         self.repo_storage.post_receive_hook().await.map_err(|e| {
@@ -317,265 +743,2821 @@ where
         })?;
 
         report_status.put(&PKT_LINE_END_MARKER[..]);
+        self.trace_outbound(&report_status);
         Ok(report_status.freeze())
     }
 
-    /// Builds the packet data in the sideband format if the SideBand/64k capability is enabled.
-    pub fn build_side_band_format(&self, from_bytes: BytesMut, length: usize) -> BytesMut {
-        let mut to_bytes = BytesMut::new();
-        if self.capabilities.contains(&Capability::SideBand)
-            || self.capabilities.contains(&Capability::SideBand64k)
-        {
-            let length = length + 5;
-            to_bytes.put(Bytes::from(format!("{length:04x}")));
-            to_bytes.put_u8(SideBand::PackfileData.value());
-            to_bytes.put(from_bytes);
-        } else {
-            to_bytes.put(from_bytes);
-        }
-        to_bytes
-    }
+    /// Handle git receive-pack operation (push), streaming the status report back
+    ///
+    /// Behaves like [`SmartProtocol::git_receive_pack_stream`], but references are updated
+    /// in a background task and the status of each batch is sent as soon as it is known,
+    /// so large pushes neither wait for nor buffer the whole report. The commands are moved
+    /// into the task, leaving `command_list` empty.
+    ///
+    /// With a side-band the report is sent in band 1, after the progress (unless `quiet`)
+    /// and the fsck warnings in band 2, and the stream ends with the flush-pkt of the
+    /// side-band.
+    pub async fn git_receive_pack_incremental(
+        &mut self,
+        data_stream: ProtocolStream,
+    ) -> Result<ProtocolStream, ProtocolError>
+    where
+        R: 'static,
+    {
+        let received = self.receive_pack_objects(data_stream).await;
+        // The commands, read by now, negotiated the side-band of the report
+        let side_band = side_band_limit(&self.capabilities);
+        let frame = move |report: Bytes| match side_band {
+            Some(limit) => side_band_packets(&report, limit),
+            None => report,
+        };
+        let end_side_band = side_band.map(|_| Bytes::from_static(PKT_LINE_END_MARKER));
+        let mut context = match received {
+            Err(ProtocolError::PackCorrupt { reason, .. }) => {
+                let report = frame(self.unpack_failure_report(&reason));
+                let reply = std::iter::once(report).chain(end_side_band).map(Ok);
+                return Ok(Box::pin(futures::stream::iter(reply)));
+            }
+            context => context?,
+        };
+        let mut commands = std::mem::take(&mut self.command_list);
 
-    /// Parse capabilities from capability string
-    pub fn parse_capabilities(&mut self, cap_str: &str) {
-        for cap in cap_str.split_whitespace() {
-            if let Ok(capability) = cap.parse::<Capability>() {
-                self.capabilities.push(capability);
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        tokio::spawn(async move {
+            // Keep updating even if the client went away, the objects are already stored
+            for message in std::mem::take(&mut context.messages) {
+                let _ = tx.send(Ok(message)).await;
             }
-        }
+            let mut unpack_status = BytesMut::new();
+            add_pkt_line_string(&mut unpack_status, "unpack ok\n".to_owned());
+            let _ = tx.send(Ok(frame(unpack_status.freeze()))).await;
+            for batch in commands.chunks_mut(REF_UPDATE_BATCH_SIZE) {
+                let report = context.apply_batch(batch).await;
+                let _ = tx.send(Ok(frame(report.freeze()))).await;
+            }
+            release_kept_pack(&context.repo, context.kept_pack.as_ref()).await;
+            match context.repo.post_receive_hook().await {
+                Ok(()) => {
+                    let end = frame(Bytes::from_static(PKT_LINE_END_MARKER));
+                    for end in std::iter::once(end).chain(end_side_band) {
+                        let _ = tx.send(Ok(end)).await;
+                    }
+                }
+                Err(e) => {
+                    let _ = tx
+                        .send(Err(ProtocolError::repository_error(format!(
+                            "Post-receive hook failed: {}",
+                            e
+                        ))))
+                        .await;
+                }
+            }
+        });
+        Ok(Box::pin(ReceiverStream::new(rx)))
     }
 
-    /// Parse a reference command from packet line
-    pub fn parse_ref_command(&self, pkt_line: &mut Bytes) -> RefCommand {
-        let old_id = read_until_white_space(pkt_line);
-        let new_id = read_until_white_space(pkt_line);
-        let ref_name = read_until_white_space(pkt_line);
-        let _capabilities = String::from_utf8_lossy(&pkt_line[..]).to_string();
-
-        RefCommand::new(old_id, new_id, ref_name)
+    /// The checks of pushed objects, `fsck_objects` in strict mode
+    fn receive_fsck_options(&self) -> Option<FsckOptions> {
+        self.fsck_objects.clone().map(|options| FsckOptions {
+            strict: true,
+            ..options
+        })
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::internal::object::blob::Blob;
-    use crate::internal::object::commit::Commit;
-    use crate::internal::object::signature::{Signature, SignatureType};
-    use crate::internal::object::tree::{Tree, TreeItem, TreeItemMode};
-    use crate::internal::pack::{encode::PackEncoder, entry::Entry};
-    use crate::protocol::types::{RefCommand, ZERO_ID}; // import sibling types
-    use crate::protocol::utils; // import sibling module
-    use async_trait::async_trait;
-    use bytes::Bytes;
-    use futures;
-    use std::sync::{
-        Arc, Mutex,
-        atomic::{AtomicBool, Ordering},
-    };
-    use tokio::sync::mpsc;
 
-    // Simplify complex type via aliases to satisfy clippy::type_complexity
-    type UpdateRecord = (String, Option<String>, String);
-    type UpdateList = Vec<UpdateRecord>;
-    type SharedUpdates = Arc<Mutex<UpdateList>>;
-
-    #[derive(Clone)]
-    struct TestRepoAccess {
-        updates: SharedUpdates,
-        stored_count: Arc<Mutex<usize>>,
-        default_branch_exists: Arc<Mutex<bool>>,
-        post_called: Arc<AtomicBool>,
+    /// Whether a received pack starting with `pack_data` is stored whole rather than exploded
+    fn keeps_pack(&self, pack_data: &[u8]) -> bool {
+        if !self.write_pack_index {
+            return false;
+        }
+        let Some(limit) = self.unpack_limit else {
+            return true;
+        };
+        // A bad header is reported by the decoder of either path
+        let mut header = pack_data;
+        Pack::check_header(&mut header).is_ok_and(|(count, _)| count >= limit)
     }
 
-    impl TestRepoAccess {
-        fn new() -> Self {
-            Self {
-                updates: Arc::new(Mutex::new(vec![])),
-                stored_count: Arc::new(Mutex::new(0)),
-                default_branch_exists: Arc::new(Mutex::new(false)),
-                post_called: Arc::new(AtomicBool::new(false)),
-            }
+    /// Status report of a push whose pack could not be unpacked, no reference is updated
+    fn unpack_failure_report(&mut self, reason: &str) -> Bytes {
+        let machine_status = self.capabilities.contains(&Capability::MachineStatus);
+        let mut report_status = BytesMut::new();
+        add_pkt_line_string(&mut report_status, format!("unpack {reason}\n"));
+        for command in self.command_list.iter_mut() {
+            command.failed_with_code("unpack-failed", "unpacker error".to_string());
+            add_command_status(&mut report_status, command, machine_status);
         }
+        report_status.put(&PKT_LINE_END_MARKER[..]);
+        self.trace_outbound(&report_status);
+        report_status.freeze()
+    }
 
-        fn updates_len(&self) -> usize {
-            self.updates.lock().unwrap().len()
+    /// Read the commands (if needed) and the pack of a push, and store the pushed objects
+    async fn receive_pack_objects(
+        &mut self,
+        data_stream: ProtocolStream,
+    ) -> Result<RefUpdateContext<R>, ProtocolError> {
+        let mut stream = data_stream;
+        let mut pack_data = if self.command_list.is_empty() {
+            self.read_receive_pack_commands(&mut stream).await?
+        } else {
+            BytesMut::new()
+        };
+
+        // Refuse before reading the pack, objects in another format cannot be stored
+        self.check_object_format()?;
+        self.check_filtered_commands().await;
+
+        // Wait for the header of the pack, a push that only deletes refs has no pack
+        while pack_data.len() < PACK_HEADER_LEN {
+            let Some(chunk_result) = futures::StreamExt::next(&mut stream).await else {
+                break;
+            };
+            let chunk = chunk_result
+                .map_err(|e| ProtocolError::invalid_request(&format!("Stream error: {}", e)))?;
+            pack_data.extend_from_slice(&chunk);
         }
 
-        fn post_hook_called(&self) -> bool {
-            self.post_called.load(Ordering::SeqCst)
+        let mut messages = Vec::new();
+        if let Ok((count, _)) = Pack::check_header(&mut &pack_data[..]) {
+            let progress = format!("Unpacking objects: {count}, done.\n");
+            messages.extend(self.build_progress(ServiceType::ReceivePack, &progress));
         }
-    }
+
+        let session_id = self.capabilities.iter().find_map(|cap| match cap {
+            Capability::SessionId(id) => Some(id.clone()),
+            _ => None,
+        });
+        let provenance = ObjectProvenance::new(self.pusher.clone(), session_id);
+
+        // Store the objects via the repository access trait as they are unpacked, keeping the
+        // pushed commits and tags around when they are needed to evaluate the push
+        let mut pack_commits: HashMap<SHA1, Commit> = HashMap::new();
+        let mut pack_tags: HashMap<SHA1, Tag> = HashMap::new();
+        let keep_commits = !self.branch_protections.is_empty();
+        let keep_tags = self.tag_verifier.is_some();
+        let repo_storage = &self.repo_storage;
+        let object_filter = self.object_filter.as_ref();
+        let store = |(commits, trees, blobs, tags): PackObjects| {
+            if let Some(filter) = object_filter {
+                commits.iter().for_each(|c| filter.insert(&c.id));
+                trees.iter().for_each(|t| filter.insert(&t.id));
+                blobs.iter().for_each(|b| filter.insert(&b.id));
+                tags.iter().for_each(|t| filter.insert(&t.id));
+            }
+            if keep_commits {
+                pack_commits.extend(commits.iter().map(|c| (c.id, c.clone())));
+            }
+            if keep_tags {
+                pack_tags.extend(tags.iter().map(|t| (t.id, t.clone())));
+            }
+            let provenance = &provenance;
+            async move {
+                repo_storage
+                    .handle_pack_objects(commits, trees, blobs, tags, Some(provenance))
+                    .await
+                    .map_err(|e| {
+                        ProtocolError::repository_error(format!(
+                            "Failed to store pack objects: {}",
+                            e
+                        ))
+                    })
+            }
+        };
+
+        // Unpack the received data
+        let mut kept_pack = None;
+        let UnpackedPack {
+            streamed,
+            fsck_problems,
+            ..
+        } = if pack_data.is_empty() {
+            UnpackedPack::default()
+        } else if self.keeps_pack(&pack_data) {
+            // The pack is stored as a whole next to its index, kept until the refs are updated
+            let head = futures::stream::once(async move { Ok(pack_data.freeze()) });
+            let pack_stream = Box::pin(futures::StreamExt::chain(head, stream));
+            let mut pack_generator = PackGenerator::new(&self.repo_storage);
+            pack_generator.set_unpack_options(self.unpack_options.clone());
+            pack_generator.set_fsck_options(self.receive_fsck_options());
+            let keep = receive_pack_keep();
+            let indexed = pack_generator
+                .index_pack_in_batches(pack_stream, Some(&keep), store)
+                .await?;
+            kept_pack = Some((indexed.pack_hash, keep));
+            indexed.unpacked
+        } else {
+            // Decode the pack while the rest of it is received
+            let head = futures::stream::once(async move { Ok(pack_data.freeze()) });
+            let pack_stream = Box::pin(futures::StreamExt::chain(head, stream));
+            let mut pack_generator = PackGenerator::new(&self.repo_storage);
+            pack_generator.set_unpack_options(self.unpack_options.clone());
+            pack_generator.set_fsck_options(self.receive_fsck_options());
+            pack_generator
+                .unpack_received_in_batches(pack_stream, false, store)
+                .await?
+        };
+
+        if let Some(filter) = &self.object_filter {
+            streamed.iter().for_each(|id| filter.insert(id));
+        }
+        // Warnings are not progress, `quiet` keeps them
+        for problem in &fsck_problems {
+            messages.extend(self.side_band_message(&format!("{problem}\n")));
+        }
+
+        let stored = async {
+            // The large blobs were stored while unpacking
+            for id in &streamed {
+                self.repo_storage
+                    .record_provenance(&id.to_string(), &provenance)
+                    .await?;
+            }
+            self.repo_storage.has_default_branch().await.map_err(|e| {
+                ProtocolError::repository_error(format!("Failed to check default branch: {}", e))
+            })
+        };
+        let default_exist = match stored.await {
+            Ok(default_exist) => default_exist,
+            Err(e) => {
+                // No reference will point to the kept pack
+                release_kept_pack(&self.repo_storage, kept_pack.as_ref()).await;
+                return Err(e);
+            }
+        };
+
+        Ok(RefUpdateContext {
+            repo: self.repo_storage.clone(),
+            branch_protections: self.branch_protections.clone(),
+            tag_verifier: self.tag_verifier.clone(),
+            authorization: self.authorization.clone(),
+            pusher: self.pusher.clone(),
+            pack_commits,
+            pack_tags,
+            default_exist,
+            machine_status: self.capabilities.contains(&Capability::MachineStatus),
+            kept_pack,
+            messages,
+        })
+    }
+
+    /// The protocol v2 capability advertisement of upload-pack
+    ///
+    /// Only the commands [`git_v2_command`](Self::git_v2_command) serves are advertised:
+    /// `ls-refs`, `fetch`, with `ref-in-want`, and `bundle-uri` when `bundle_list` is set.
+    pub fn git_v2_capabilities(&self) -> BytesMut {
+        let mut advertisement = BytesMut::new();
+        add_pkt_line_string(&mut advertisement, "version 2\n".to_string());
+        add_pkt_line_string(&mut advertisement, format!("agent={}\n", self.agent));
+        add_pkt_line_string(
+            &mut advertisement,
+            format!("object-format={OBJECT_FORMAT}\n"),
+        );
+        add_pkt_line_string(&mut advertisement, "ls-refs\n".to_string());
+        add_pkt_line_string(&mut advertisement, "fetch=ref-in-want\n".to_string());
+        if self.bundle_list.is_some() {
+            add_pkt_line_string(&mut advertisement, "bundle-uri\n".to_string());
+        }
+        advertisement.put(&PKT_LINE_END_MARKER[..]);
+        self.trace_outbound(&advertisement);
+        advertisement
+    }
+
+    /// Serve the protocol v2 command read from `request`, up to its flush-pkt
+    ///
+    /// A request is a `command=<name>` pkt-line, the capabilities of the client, and after a
+    /// delim-pkt the arguments of the command. `ls-refs` is served like
+    /// [`git_v2_ls_refs`](Self::git_v2_ls_refs), `fetch` like
+    /// [`git_v2_fetch`](Self::git_v2_fetch), `bundle-uri` is answered with the lines of
+    /// `bundle_list`; other commands are refused.
+    pub async fn git_v2_command<S>(
+        &mut self,
+        request: &mut PktLineReader<S>,
+    ) -> Result<ProtocolStream, ProtocolError>
+    where
+        S: futures::Stream<Item = Result<Bytes, ProtocolError>> + Unpin,
+    {
+        request.set_packet_tracer(self.packet_tracer.clone());
+        let line = |pkt_line: &Bytes| {
+            let line = String::from_utf8_lossy(pkt_line);
+            line.strip_suffix('\n').unwrap_or(&line).to_string()
+        };
+        let command = match futures::StreamExt::next(request).await.transpose()? {
+            Some(PktLine::Data(pkt_line)) => line(&pkt_line),
+            _ => return Err(ProtocolError::invalid_request("Missing command")),
+        };
+        let command = command
+            .strip_prefix("command=")
+            .ok_or_else(|| ProtocolError::invalid_request(&format!("Invalid command '{command}'")))?
+            .to_string();
+        let mut capabilities = Vec::new();
+        let mut arguments = Vec::new();
+        let mut in_arguments = false;
+        loop {
+            match futures::StreamExt::next(request).await.transpose()? {
+                Some(PktLine::Data(pkt_line)) if in_arguments => arguments.push(line(&pkt_line)),
+                Some(PktLine::Data(pkt_line)) => capabilities.push(line(&pkt_line)),
+                Some(PktLine::Delim) if !in_arguments => in_arguments = true,
+                Some(PktLine::Flush) => break,
+                _ => return Err(ProtocolError::invalid_request("Missing flush-pkt")),
+            }
+        }
+        self.parse_capabilities(&capabilities.join(" "));
+        self.check_object_format()?;
+
+        let mut reply = BytesMut::new();
+        match (command.as_str(), &self.bundle_list) {
+            ("ls-refs", _) => return self.git_v2_ls_refs(&arguments).await,
+            ("fetch", _) => return self.git_v2_fetch(&arguments).await,
+            ("bundle-uri", Some(bundle_list)) => {
+                if let Some(argument) = arguments.first() {
+                    return Err(ProtocolError::invalid_request(&format!(
+                        "bundle-uri: unexpected argument: '{argument}'"
+                    )));
+                }
+                for line in bundle_list.to_lines() {
+                    add_pkt_line_string(&mut reply, format!("{line}\n"));
+                }
+            }
+            _ => {
+                return Err(ProtocolError::invalid_request(&format!(
+                    "invalid command '{command}'"
+                )));
+            }
+        }
+        reply.put(&PKT_LINE_END_MARKER[..]);
+        Ok(self.reply_stream(reply))
+    }
+
+    /// Serve the protocol v2 `ls-refs` of `arguments`
+    ///
+    /// The refs upload-pack advertises are listed, HEAD first when it resolves, limited to
+    /// those starting with one of the `ref-prefix` arguments when there are any. `symrefs`
+    /// adds the branch HEAD points to, `peel` the object an annotated tag points to.
+    async fn git_v2_ls_refs(&self, arguments: &[String]) -> Result<ProtocolStream, ProtocolError> {
+        let mut symrefs = false;
+        let mut peel = false;
+        let mut prefixes = Vec::new();
+        for argument in arguments {
+            match argument.split_once(' ') {
+                None if argument == "symrefs" => symrefs = true,
+                None if argument == "peel" => peel = true,
+                Some(("ref-prefix", prefix)) => prefixes.push(prefix),
+                _ => {
+                    return Err(ProtocolError::invalid_request(&format!(
+                        "ls-refs: unexpected argument: '{argument}'"
+                    )));
+                }
+            }
+        }
+        let refs = self.visible_refs(ServiceType::UploadPack, false).await?;
+        let head = self.resolve_head(&refs).await?;
+        let listed = head
+            .iter()
+            .map(|(hash, target)| ("HEAD", hash, target.as_deref()))
+            .chain(
+                refs.iter()
+                    .filter(|(name, hash)| name != "HEAD" && hash != ZERO_ID)
+                    .map(|(name, hash)| (name.as_str(), hash, None)),
+            )
+            .filter(|(name, _, _)| {
+                prefixes.is_empty() || prefixes.iter().any(|prefix| name.starts_with(prefix))
+            });
+
+        let mut reply = BytesMut::new();
+        for (name, hash, target) in listed {
+            let mut line = format!("{hash} {name}");
+            if let Some(target) = target.filter(|_| symrefs) {
+                line.push_str(&format!(" symref-target:{target}"));
+            }
+            if peel && let Some(peeled) = self.peel(hash).await? {
+                line.push_str(&format!(" peeled:{peeled}"));
+            }
+            line.push('\n');
+            add_pkt_line_string(&mut reply, line);
+        }
+        reply.put(&PKT_LINE_END_MARKER[..]);
+        Ok(self.reply_stream(reply))
+    }
+
+    /// The object the annotated tag `hash` points to, through tags of tags, or None when
+    /// `hash` isn't a tag
+    async fn peel(&self, hash: &str) -> Result<Option<SHA1>, ProtocolError> {
+        let mut id = SHA1::from_str(hash)
+            .map_err(|_| ProtocolError::repository_error(format!("Invalid object id {hash}")))?;
+        let mut peeled = None;
+        for _ in 0..MAX_PEEL_DEPTH {
+            match load_object(&self.repo_storage, &id).await? {
+                Some((ObjectType::Tag, data)) => {
+                    let tag = Tag::from_bytes(&data, id)
+                        .map_err(|e| ProtocolError::corrupt_object(id, e))?;
+                    id = tag.object_hash;
+                    peeled = Some(id);
+                }
+                _ => break,
+            }
+        }
+        Ok(peeled)
+    }
+
+    /// Serve the protocol v2 `fetch` of `arguments`
+    ///
+    /// v2 is stateless: every request carries the wants and the haves sent so far. Until the
+    /// client is `done` or a common commit is known, the reply only acknowledges the haves
+    /// found in the repository. Then the pack follows in the `packfile` section, multiplexed
+    /// like side-band-64k, after a `wanted-refs` section with the tips the `want-ref`
+    /// arguments resolved to, read from a single listing so they match the objects sent.
+    async fn git_v2_fetch(
+        &mut self,
+        arguments: &[String],
+    ) -> Result<ProtocolStream, ProtocolError> {
+        let mut want = Vec::new();
+        let mut want_refs = Vec::new();
+        let mut have = Vec::new();
+        let mut done = false;
+        for argument in arguments {
+            let (name, value) = argument.split_once(' ').unwrap_or((argument, ""));
+            match name {
+                "want" => want.push(value.to_string()),
+                "want-ref" => want_refs.push(value.to_string()),
+                "have" => have.push(value.to_string()),
+                "done" => done = true,
+                "thin-pack" | "no-progress" | "include-tag" | "ofs-delta" => {
+                    self.parse_capabilities(name)
+                }
+                _ => {
+                    return Err(ProtocolError::invalid_request(&format!(
+                        "fetch: unexpected argument: '{argument}'"
+                    )));
+                }
+            }
+        }
+        if want.is_empty() && want_refs.is_empty() {
+            return Err(ProtocolError::invalid_request("fetch: missing want"));
+        }
+        if let Some(refused) = self.check_wants(&want).await? {
+            let reply = build_error_pkt_line(&format!("upload-pack: not our ref {refused}"));
+            return Ok(self.reply_stream(reply));
+        }
+        let mut wanted_refs = Vec::new();
+        if !want_refs.is_empty() {
+            let refs: HashMap<String, String> = self
+                .visible_refs(ServiceType::UploadPack, false)
+                .await?
+                .into_iter()
+                .collect();
+            for name in want_refs {
+                let Some(id) = refs.get(&name).filter(|id| *id != ZERO_ID) else {
+                    let reply = build_error_pkt_line(&format!("unknown ref {name}"));
+                    return Ok(self.reply_stream(reply));
+                };
+                want.push(id.clone());
+                wanted_refs.push((id.clone(), name));
+            }
+        }
+
+        let mut common = Vec::new();
+        for hash in have {
+            if may_exist(self.object_filter.as_deref(), &hash)
+                && self.repo_storage.commit_exists(&hash).await.map_err(|e| {
+                    ProtocolError::repository_error(format!(
+                        "Failed to check commit existence: {}",
+                        e
+                    ))
+                })?
+                && !common.contains(&hash)
+            {
+                common.push(hash);
+            }
+        }
+        self.negotiation = NegotiationState {
+            wants: want.clone(),
+            common: common.clone(),
+            ..NegotiationState::default()
+        };
+
+        let mut reply = BytesMut::new();
+        if !done {
+            add_pkt_line_string(&mut reply, "acknowledgments\n".to_string());
+            for hash in &common {
+                add_pkt_line_string(&mut reply, format!("ACK {hash}\n"));
+            }
+            if common.is_empty() {
+                add_pkt_line_string(&mut reply, "NAK\n".to_string());
+            }
+            if !self.ok_to_give_up() {
+                reply.put(&PKT_LINE_END_MARKER[..]);
+                return Ok(self.reply_stream(reply));
+            }
+            add_pkt_line_string(&mut reply, "ready\n".to_string());
+            reply.put(&PKT_LINE_DELIM_MARKER[..]);
+        }
+        if !wanted_refs.is_empty() {
+            add_pkt_line_string(&mut reply, "wanted-refs\n".to_string());
+            for (id, name) in &wanted_refs {
+                add_pkt_line_string(&mut reply, format!("{id} {name}\n"));
+            }
+            reply.put(&PKT_LINE_DELIM_MARKER[..]);
+        }
+        add_pkt_line_string(&mut reply, "packfile\n".to_string());
+
+        // The packfile section is always multiplexed
+        if !self.capabilities.contains(&Capability::SideBand64k) {
+            self.capabilities.push(Capability::SideBand64k);
+        }
+        let mut pack_generator = PackGenerator::new(&self.repo_storage);
+        pack_generator.set_ofs_delta(self.capabilities.contains(&Capability::OfsDelta));
+        pack_generator.set_delta_options(self.delta_options);
+        let objects = if common.is_empty() {
+            pack_generator.full_pack_objects(want).await?
+        } else {
+            pack_generator
+                .incremental_pack_objects(want, common)
+                .await?
+        };
+        let progress = format!("Enumerating objects: {}, done.\n", object_count(&objects));
+        if let Some(progress) = self.build_progress(ServiceType::UploadPack, &progress) {
+            reply.put(progress);
+        }
+        let pack = pack_generator.generate_pack(objects).await?;
+        self.negotiation.done = true;
+        self.trace_outbound(&reply);
+
+        let limit = MAX_PKT_LINE_LENGTH - 5;
+        let pack = futures::StreamExt::map(pack, move |chunk: Vec<u8>| {
+            Ok(side_band_packets(&chunk, limit))
+        });
+        let head = futures::stream::iter([Ok(reply.freeze())]);
+        let end = futures::stream::iter([Ok(Bytes::from_static(PKT_LINE_END_MARKER))]);
+        let reply = futures::StreamExt::chain(futures::StreamExt::chain(head, pack), end);
+        Ok(Box::pin(reply))
+    }
+
+    /// `reply` as the whole response stream
+    fn reply_stream(&self, reply: BytesMut) -> ProtocolStream {
+        self.trace_outbound(&reply);
+        Box::pin(futures::stream::iter([Ok(reply.freeze())]))
+    }
+
+    /// Resolve the tree-ish of an upload-archive `request` and have the repository write the
+    /// archive, see [`upload_archive`](super::upload_archive)
+    ///
+    /// The tree-ish names a ref the client may see, in full or by the short name of a tag or
+    /// branch, or `HEAD`; with `allow_unreachable_archives`, it may be any object id the
+    /// repository has as well.
+    pub async fn git_upload_archive(
+        &self,
+        request: &ArchiveRequest,
+    ) -> Result<ProtocolStream, ProtocolError>
+    where
+        R: 'static,
+    {
+        let rev = request.tree_ish.as_str();
+        let refs = self.visible_refs(ServiceType::UploadPack, false).await?;
+        let lookup = |wanted: &str| {
+            refs.iter()
+                .find(|(name, hash)| name == wanted && hash != ZERO_ID)
+                .map(|(_, hash)| hash.clone())
+        };
+        let mut hash = if rev == "HEAD" {
+            let default_branch = self.repo_storage.default_branch().await?;
+            default_branch
+                .as_deref()
+                .and_then(lookup)
+                .or_else(|| lookup("HEAD"))
+        } else {
+            // The order in which git expands a short ref name
+            ["", "refs/", "refs/tags/", "refs/heads/", "refs/remotes/"]
+                .iter()
+                .find_map(|prefix| lookup(&format!("{prefix}{rev}")))
+        };
+        if hash.is_none()
+            && self.allow_unreachable_archives
+            && SHA1::from_str(rev).is_ok()
+            && self.repo_storage.has_object(rev).await?
+        {
+            hash = Some(rev.to_string());
+        }
+        let hash =
+            hash.ok_or_else(|| ProtocolError::invalid_request(&format!("no such ref: {rev}")))?;
+        let id = SHA1::from_str(&hash)
+            .map_err(|e| ProtocolError::repository_error(format!("Invalid hash {hash}: {}", e)))?;
+        self.repo_storage.write_archive(&id, request).await
+    }
+
+    /// Builds the packet data in the sideband format if the SideBand/64k capability is enabled.
+    pub fn build_side_band_format(&self, from_bytes: BytesMut, length: usize) -> BytesMut {
+        let mut to_bytes = BytesMut::new();
+        if self.capabilities.contains(&Capability::SideBand)
+            || self.capabilities.contains(&Capability::SideBand64k)
+        {
+            let length = length + 5;
+            to_bytes.put(Bytes::from(format!("{length:04x}")));
+            to_bytes.put_u8(SideBand::PackfileData.value());
+            to_bytes.put(from_bytes);
+        } else {
+            to_bytes.put(from_bytes);
+        }
+        to_bytes
+    }
+
+    /// Whether progress may be sent to the client of `service`: it negotiated a side-band and
+    /// didn't ask for silence, with `no-progress` for upload-pack or `quiet` for receive-pack.
+    pub fn progress_enabled(&self, service: ServiceType) -> bool {
+        let silence = match service {
+            ServiceType::UploadPack => Capability::NoProgress,
+            ServiceType::ReceivePack => Capability::Quiet,
+            ServiceType::UploadArchive => return false,
+        };
+        side_band_limit(&self.capabilities).is_some() && !self.capabilities.contains(&silence)
+    }
+
+    /// Builds the side-band progress packets of `message` for the client of `service`, e.g.
+    /// `"Enumerating objects: 3, done.\n"`, or `None` when progress isn't enabled (see
+    /// [`SmartProtocol::progress_enabled`]).
+    pub fn build_progress(&self, service: ServiceType, message: &str) -> Option<Bytes> {
+        if !self.progress_enabled(service) {
+            return None;
+        }
+        self.side_band_message(message)
+    }
+
+    /// `message` in band 2 of the negotiated side-band, split when it is longer than a packet
+    fn side_band_message(&self, message: &str) -> Option<Bytes> {
+        let limit = side_band_limit(&self.capabilities)?;
+        Some(band_packets(SideBand::ProgressInfo, message.as_bytes(), limit))
+    }
+
+    /// Parse capabilities from capability string, keeping the ones this crate doesn't know
+    /// as `Capability::Unknown`
+    pub fn parse_capabilities(&mut self, cap_str: &str) {
+        for cap in cap_str.split_whitespace() {
+            let Ok(capability) = cap.parse::<Capability>();
+            match &capability {
+                Capability::SessionId(id) => tracing::debug!(
+                    session_id = %self.session_id,
+                    client_session_id = %id,
+                    "client session"
+                ),
+                Capability::Unknown(token) => {
+                    if let Some(handler) = &self.unknown_capability_handler {
+                        handler(token);
+                    }
+                }
+                _ => {}
+            }
+            self.capabilities.push(capability);
+        }
+    }
+
+    /// Ensure the client did not request an object format other than the one we serve
+    fn check_object_format(&self) -> Result<(), ProtocolError> {
+        for cap in &self.capabilities {
+            if let Capability::ObjectFormat(format) = cap
+                && format != OBJECT_FORMAT
+            {
+                return Err(ProtocolError::UnsupportedObjectFormat(format!(
+                    "client requested {format}, server only supports {OBJECT_FORMAT}"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// The first of `wants` that `want_policy` doesn't allow, so that objects only reachable
+    /// from hidden refs or from no ref at all aren't fetchable by id
+    async fn check_wants(&self, wants: &[String]) -> Result<Option<String>, ProtocolError> {
+        if wants.is_empty() || self.want_policy == WantPolicy::Any {
+            return Ok(None);
+        }
+        let advertised: HashSet<String> = self
+            .visible_refs(ServiceType::UploadPack, self.want_policy == WantPolicy::Tip)
+            .await?
+            .into_iter()
+            .map(|(_, hash)| hash)
+            .collect();
+        let mut missing: HashSet<&String> = wants
+            .iter()
+            .filter(|want| !advertised.contains(*want))
+            .collect();
+        if missing.is_empty() || self.want_policy != WantPolicy::Reachable {
+            return Ok(wants.iter().find(|want| missing.contains(want)).cloned());
+        }
+
+        // Walk the history of the advertised tips until every other want is found
+        let mut visited = HashSet::new();
+        let mut queue: VecDeque<String> = advertised.into_iter().collect();
+        while let Some(id) = queue.pop_front() {
+            if !visited.insert(id.clone()) {
+                continue;
+            }
+            if let Ok(commit) = self.repo_storage.get_commit(&id).await {
+                queue.extend(commit.parent_commit_ids.iter().map(SHA1::to_string));
+            } else if let Ok(tag) = self.repo_storage.get_tag(&id).await {
+                queue.push_back(tag.object_hash.to_string());
+            }
+            missing.remove(&id);
+            if missing.is_empty() {
+                return Ok(None);
+            }
+        }
+        Ok(wants.iter().find(|want| missing.contains(want)).cloned())
+    }
+
+    /// The refs of the repository that neither `hidden_refs`, unless `with_hidden`, nor
+    /// `ref_filter` hide from `service`.
+    async fn visible_refs(
+        &self,
+        service: ServiceType,
+        with_hidden: bool,
+    ) -> Result<Vec<(String, String)>, ProtocolError> {
+        let mut refs =
+            self.repo_storage.get_repository_refs().await.map_err(|e| {
+                ProtocolError::repository_error(format!("Failed to get refs: {}", e))
+            })?;
+        if !with_hidden {
+            refs.retain(|(name, _)| !self.hidden_refs.is_hidden(service, name));
+        }
+        if let Some(filter) = &self.ref_filter {
+            let ctx = self.ref_filter_context(service);
+            let mut visible = Vec::with_capacity(refs.len());
+            for (name, hash) in refs {
+                if filter.visibility(&name, &ctx).await == Visibility::Visible {
+                    visible.push((name, hash));
+                }
+            }
+            refs = visible;
+        }
+        Ok(refs)
+    }
+
+    fn ref_filter_context(&self, service: ServiceType) -> RefFilterContext<'_> {
+        RefFilterContext {
+            service,
+            user: self.pusher.as_deref(),
+        }
+    }
+
+    /// Fail the commands updating refs that `ref_filter` hides from receive-pack.
+    async fn check_filtered_commands(&mut self) {
+        let Some(filter) = &self.ref_filter else {
+            return;
+        };
+        let ctx = RefFilterContext {
+            service: ServiceType::ReceivePack,
+            user: self.pusher.as_deref(),
+        };
+        for command in &mut self.command_list {
+            if !matches!(command.status, CommandStatus::Failed)
+                && filter.visibility(&command.ref_name, &ctx).await == Visibility::Hidden
+            {
+                command.failed_with_code("hidden-ref", "deny updating a hidden ref".to_string());
+            }
+        }
+    }
+
+    /// Parse a reference command from packet line
+    pub fn parse_ref_command(&self, pkt_line: &mut Bytes) -> RefCommand {
+        let old_id = read_until_white_space(pkt_line);
+        let new_id = read_until_white_space(pkt_line);
+        let ref_name = read_until_white_space(pkt_line);
+
+        // Like git, every pushed ref lives under refs/ and passes check-ref-format
+        let valid = if ref_name.starts_with("refs/") {
+            validate_ref_name(&ref_name)
+        } else {
+            Err(GitError::InvalidRefName(
+                ref_name.clone(),
+                "not under refs/".to_string(),
+            ))
+        };
+        let hidden = self
+            .hidden_refs
+            .is_hidden(ServiceType::ReceivePack, &ref_name);
+        let mut command = RefCommand::new(old_id, new_id, ref_name);
+        if let Err(e) = valid {
+            command.failed_with_code("invalid-ref-name", e.to_string());
+        } else if hidden {
+            command.failed_with_code("hidden-ref", "deny updating a hidden ref".to_string());
+        }
+        command
+    }
+}
+
+/// Number of reference updates handed to `RepositoryAccess::update_references` at once
+const REF_UPDATE_BATCH_SIZE: usize = 1000;
+
+/// Length of the signature, version and object count heading a pack
+const PACK_HEADER_LEN: usize = 12;
+
+/// A keep token naming this process and push, like git's `receive-pack <pid> on <host>`
+fn receive_pack_keep() -> String {
+    static PUSHES: AtomicUsize = AtomicUsize::new(0);
+    let push = PUSHES.fetch_add(1, Ordering::Relaxed);
+    format!("receive-pack {} push {push}", std::process::id())
+}
+
+/// Release the keep of a received pack; the refs are already updated, so a failure only
+/// leaves a stale keep behind
+async fn release_kept_pack<R: RepositoryAccess>(repo: &R, kept_pack: Option<&(SHA1, String)>) {
+    if let Some((pack_hash, keep)) = kept_pack
+        && let Err(e) = repo.release_pack_keep(pack_hash, keep).await
+    {
+        tracing::warn!("Failed to release the keep of pack {pack_hash}: {e}");
+    }
+}
+
+/// Everything needed to validate and apply the reference updates of one push
+struct RefUpdateContext<R: RepositoryAccess> {
+    repo: R,
+    branch_protections: Vec<BranchProtection>,
+    tag_verifier: Option<Arc<dyn SignatureVerifier>>,
+    authorization: Option<Arc<dyn AuthorizationService>>,
+    pusher: Option<String>,
+    pack_commits: HashMap<SHA1, Commit>,
+    pack_tags: HashMap<SHA1, Tag>,
+    default_exist: bool,
+    machine_status: bool,
+    /// The checksum and keep token of the received pack, released once the refs are updated
+    kept_pack: Option<(SHA1, String)>,
+    /// Band 2 packets of the progress and warnings, sent ahead of the report
+    messages: Vec<Bytes>,
+}
+
+impl<R: RepositoryAccess> RefUpdateContext<R> {
+    /// Validate and apply a batch of commands, returning their status lines
+    async fn apply_batch(&mut self, commands: &mut [RefCommand]) -> BytesMut {
+        let mut pending = Vec::with_capacity(commands.len());
+        for (index, command) in commands.iter_mut().enumerate() {
+            // Rejected when parsed
+            if matches!(command.status, CommandStatus::Failed) {
+                continue;
+            }
+            if let Err((code, error)) = self.check_command(command).await {
+                command.failed_with_code(code, error);
+                continue;
+            }
+            // Handle default branch setting for the first branch
+            if command.ref_type == RefTypeEnum::Branch && !self.default_exist {
+                command.default_branch = true;
+                self.default_exist = true;
+            }
+            pending.push(index);
+        }
+
+        let updates: Vec<RefUpdate> = pending
+            .iter()
+            .map(|&index| RefUpdate::from(&commands[index]))
+            .collect();
+        match self.repo.update_references(&updates).await {
+            Ok(results) => {
+                for (&index, result) in pending.iter().zip(results) {
+                    if let Err(e) = result {
+                        commands[index].failed_with_code("update-failed", e.to_string());
+                    }
+                }
+            }
+            Err(e) => {
+                for &index in &pending {
+                    commands[index].failed_with_code("update-failed", e.to_string());
+                }
+            }
+        }
+
+        let mut report_status = BytesMut::new();
+        for command in commands.iter() {
+            add_command_status(&mut report_status, command, self.machine_status);
+        }
+        report_status
+    }
+
+    /// Check a command against the authorization service, the branch protections and the tag
+    /// signature requirement
+    async fn check_command(&self, command: &RefCommand) -> Result<(), (&'static str, String)> {
+        if let Some(authorization) = &self.authorization {
+            let operation = self.ref_operation(command).await;
+            authorization
+                .authorize_ref_update(self.pusher.as_deref(), &command.ref_name, operation)
+                .await
+                .map_err(|e| ("unauthorized", e.to_string()))?;
+        }
+        if command.ref_type == RefTypeEnum::Tag {
+            if let Some(verifier) = &self.tag_verifier
+                && command.new_hash != ZERO_ID
+            {
+                return check_tag_signature(verifier.as_ref(), command, &self.pack_tags).await;
+            }
+        } else if let Some(rule) = find_protection(&self.branch_protections, &command.ref_name) {
+            return check_protection(&self.repo, rule, command, &self.pack_commits)
+                .await
+            .map_err(|violation| (violation.code(), violation.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Whether `command` creates, fast-forwards, deletes or force-updates its reference
+    async fn ref_operation(&self, command: &RefCommand) -> RefOperation {
+        if command.old_hash == ZERO_ID {
+            return RefOperation::Create;
+        }
+        if command.new_hash == ZERO_ID {
+            return RefOperation::Delete;
+        }
+        match (
+            SHA1::from_str(&command.old_hash),
+            SHA1::from_str(&command.new_hash),
+        ) {
+            (Ok(old), Ok(new)) if !is_fast_forward(&self.repo, old, new).await => {
+                RefOperation::Force
+            }
+            _ => RefOperation::Update,
+        }
+    }
+}
+
+/// Evaluate a protection rule against a branch update, using the pushed commits and falling
+/// back to the repository for history that already existed before the push.
+async fn check_protection<R: RepositoryAccess>(
+    repo: &R,
+    rule: &BranchProtection,
+    command: &RefCommand,
+    pack_commits: &HashMap<SHA1, Commit>,
+) -> Result<(), PolicyViolation> {
+    let is_delete = command.new_hash == ZERO_ID;
+    let old = SHA1::from_str(&command.old_hash).ok();
+    let new = SHA1::from_str(&command.new_hash).ok();
+
+    // Commits introduced by the push: reachable from the new tip inside the pack
+    let mut new_commits = Vec::new();
+    if let Some(new) = new.filter(|_| !is_delete) {
+        let mut visited = HashSet::new();
+        let mut queue = VecDeque::from([new]);
+        while let Some(id) = queue.pop_front() {
+            if Some(id) == old || !visited.insert(id) {
+                continue;
+            }
+            if let Some(commit) = pack_commits.get(&id) {
+                queue.extend(commit.parent_commit_ids.iter().copied());
+                new_commits.push(commit.clone());
+            }
+        }
+    }
+
+    let is_force = match (old, new) {
+        (Some(old), Some(new)) if command.old_hash != ZERO_ID && !is_delete => {
+            !is_fast_forward(repo, old, new).await
+        }
+        _ => false,
+    };
+
+    let facts = RefUpdateFacts {
+        is_delete,
+        is_force,
+        new_commits: &new_commits,
+    };
+    rule.check(&command.ref_name, &facts)
+}
+
+/// Require the pushed tag to be an annotated tag with a signature accepted by `verifier`,
+/// returning the machine-status code and message otherwise.
+async fn check_tag_signature(
+    verifier: &dyn SignatureVerifier,
+    command: &RefCommand,
+    pack_tags: &HashMap<SHA1, Tag>,
+) -> Result<(), (&'static str, String)> {
+    let unsigned = || {
+        (
+            "unsigned-tag",
+            format!("{} is not a signed annotated tag", command.ref_name),
+        )
+    };
+    let tag = SHA1::from_str(&command.new_hash)
+        .ok()
+        .and_then(|id| pack_tags.get(&id))
+        .ok_or_else(unsigned)?;
+    match verifier.verify_tag(tag).await {
+        Ok(Some(Verification::Good { .. })) => Ok(()),
+        Ok(Some(Verification::Bad(reason))) => Err((
+            "bad-signature",
+            format!("{}: bad signature: {}", command.ref_name, reason),
+        )),
+        Ok(Some(Verification::UnknownKey(key))) => Err((
+            "unknown-key",
+            format!("{}: signed with unknown key {}", command.ref_name, key),
+        )),
+        Ok(None) => Err(unsigned()),
+        Err(e) => Err((
+            "bad-signature",
+            format!("{}: signature verification failed: {}", command.ref_name, e),
+        )),
+    }
+}
+
+/// `value` with the characters that can't appear in a capability value replaced by `.`
+fn capability_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_ascii_graphic() { c } else { '.' })
+        .collect()
+}
+
+/// Append the status line of `command`, followed by its key=value detail line when the
+/// client negotiated `machine-status`.
+fn add_command_status(buf: &mut BytesMut, command: &RefCommand, machine_status: bool) {
+    add_pkt_line_string(buf, command.get_status());
+    if machine_status {
+        add_pkt_line_string(buf, command.get_machine_status());
+    }
+}
+
+/// Branches tried for `HEAD` by default, see [`SmartProtocol::set_head_fallbacks`].
+pub(crate) const DEFAULT_HEAD_FALLBACKS: [&str; 2] = ["refs/heads/main", "refs/heads/master"];
+
+/// `false` if `object_filter` rules out `hash`; hashes that don't parse are left to the backend.
+fn may_exist(object_filter: Option<&ObjectFilter>, hash: &str) -> bool {
+    match (object_filter, SHA1::from_str(hash)) {
+        (Some(filter), Ok(id)) => filter.may_contain(&id),
+        _ => true,
+    }
+}
+
+/// Whether `new` fast-forwards `old`, walking by the generation numbers of the repository's
+/// commit-graph when it has one. History that can't be loaded doesn't reach `old`.
+async fn is_fast_forward<R: RepositoryAccess>(repo: &R, old: SHA1, new: SHA1) -> bool {
+    let mut generations = match repo.get_commit_graph().await {
+        Ok(Some(graph)) => GenerationNumbers::with_commit_graph(graph),
+        _ => GenerationNumbers::new(),
+    };
+    is_ancestor(repo, old, new, &mut generations)
+        .await
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::errors::GitError;
+    use crate::fsck::{FsckMsgId, FsckSeverity};
+    use crate::internal::object::blob::Blob;
+    use crate::internal::object::commit::Commit;
+    use crate::internal::object::signature::{Signature, SignatureType};
+    use crate::internal::object::signing::ObjectSignature;
+    use crate::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+    use crate::internal::pack::{encode::PackEncoder, entry::Entry};
+    use crate::protocol::memory::MemoryRepository;
+    use crate::protocol::trace::tests::RecordingTracer;
+    use crate::protocol::types::{RefCommand, ZERO_ID}; // import sibling types
+    use crate::protocol::utils; // import sibling module
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use futures;
+    use std::sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    };
+    use tokio::sync::mpsc;
+
+    // Simplify complex type via aliases to satisfy clippy::type_complexity
+    type UpdateRecord = (String, Option<String>, String);
+    type UpdateList = Vec<UpdateRecord>;
+    type SharedUpdates = Arc<Mutex<UpdateList>>;
+
+    #[derive(Clone)]
+    struct TestRepoAccess {
+        updates: SharedUpdates,
+        stored_count: Arc<Mutex<usize>>,
+        default_branch_exists: Arc<Mutex<bool>>,
+        post_called: Arc<AtomicBool>,
+        provenance: Arc<Mutex<Vec<(String, ObjectProvenance)>>>,
+        batch_sizes: Arc<Mutex<Vec<usize>>>,
+        object_lookups: Arc<AtomicUsize>,
+        pack_keeps: Arc<Mutex<Vec<String>>>,
+        default_branch: Arc<Mutex<Option<String>>>,
+    }
+
+    impl TestRepoAccess {
+        fn new() -> Self {
+            Self {
+                updates: Arc::new(Mutex::new(vec![])),
+                stored_count: Arc::new(Mutex::new(0)),
+                default_branch_exists: Arc::new(Mutex::new(false)),
+                post_called: Arc::new(AtomicBool::new(false)),
+                provenance: Arc::new(Mutex::new(vec![])),
+                batch_sizes: Arc::new(Mutex::new(vec![])),
+                object_lookups: Arc::new(AtomicUsize::new(0)),
+                pack_keeps: Arc::new(Mutex::new(vec![])),
+                default_branch: Arc::new(Mutex::new(None)),
+            }
+        }
+
+        fn updates_len(&self) -> usize {
+            self.updates.lock().unwrap().len()
+        }
+
+        fn post_hook_called(&self) -> bool {
+            self.post_called.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl RepositoryAccess for TestRepoAccess {
+        async fn get_repository_refs(&self) -> Result<Vec<(String, String)>, ProtocolError> {
+            Ok(vec![
+                (
+                    "HEAD".to_string(),
+                    "0000000000000000000000000000000000000000".to_string(),
+                ),
+                (
+                    "refs/heads/main".to_string(),
+                    "1111111111111111111111111111111111111111".to_string(),
+                ),
+            ])
+        }
+
+        async fn default_branch(&self) -> Result<Option<String>, ProtocolError> {
+            Ok(self.default_branch.lock().unwrap().clone())
+        }
+
+        async fn has_object(&self, _object_hash: &str) -> Result<bool, ProtocolError> {
+            self.object_lookups.fetch_add(1, Ordering::SeqCst);
+            Ok(true)
+        }
+
+        async fn get_object(&self, _object_hash: &str) -> Result<Vec<u8>, ProtocolError> {
+            Ok(vec![])
+        }
+
+        async fn store_pack_data(&self, _pack_data: &[u8]) -> Result<(), ProtocolError> {
+            *self.stored_count.lock().unwrap() += 1;
+            Ok(())
+        }
+
+        async fn store_kept_pack(
+            &self,
+            pack_data: &[u8],
+            _idx_data: &[u8],
+            keep: &str,
+        ) -> Result<(), ProtocolError> {
+            let pack_hash = SHA1::from_bytes(&pack_data[pack_data.len() - 20..]);
+            let event = format!("keep {pack_hash} by {keep}");
+            self.pack_keeps.lock().unwrap().push(event);
+            Ok(())
+        }
+
+        async fn release_pack_keep(
+            &self,
+            pack_hash: &SHA1,
+            keep: &str,
+        ) -> Result<(), ProtocolError> {
+            let event = format!(
+                "release {pack_hash} by {keep} after {} updates",
+                self.updates_len()
+            );
+            self.pack_keeps.lock().unwrap().push(event);
+            Ok(())
+        }
+
+        async fn record_provenance(
+            &self,
+            object_hash: &str,
+            provenance: &ObjectProvenance,
+        ) -> Result<(), ProtocolError> {
+            self.provenance
+                .lock()
+                .unwrap()
+                .push((object_hash.to_string(), provenance.clone()));
+            Ok(())
+        }
+
+        async fn update_reference(
+            &self,
+            ref_name: &str,
+            old_hash: Option<&str>,
+            new_hash: &str,
+        ) -> Result<(), ProtocolError> {
+            self.updates.lock().unwrap().push((
+                ref_name.to_string(),
+                old_hash.map(|s| s.to_string()),
+                new_hash.to_string(),
+            ));
+            Ok(())
+        }
+
+        async fn update_references(
+            &self,
+            updates: &[RefUpdate],
+        ) -> Result<Vec<Result<(), ProtocolError>>, ProtocolError> {
+            self.batch_sizes.lock().unwrap().push(updates.len());
+            let mut results = Vec::new();
+            for update in updates {
+                results.push(
+                    self.update_reference(
+                        &update.ref_name,
+                        update.old_hash.as_deref(),
+                        &update.new_hash,
+                    )
+                    .await,
+                );
+            }
+            Ok(results)
+        }
+
+        async fn get_objects_for_pack(
+            &self,
+            _wants: &[String],
+            _haves: &[String],
+        ) -> Result<Vec<String>, ProtocolError> {
+            Ok(vec![])
+        }
+
+        async fn has_default_branch(&self) -> Result<bool, ProtocolError> {
+            let mut exists = self.default_branch_exists.lock().unwrap();
+            let current = *exists;
+            *exists = true; // flip to true after first check
+            Ok(current)
+        }
+
+        async fn post_receive_hook(&self) -> Result<(), ProtocolError> {
+            self.post_called.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    pub(crate) struct TestAuth;
+
+    #[async_trait]
+    impl AuthenticationService for TestAuth {
+        async fn authenticate_http(
+            &self,
+            _headers: &std::collections::HashMap<String, String>,
+        ) -> Result<(), ProtocolError> {
+            Ok(())
+        }
+
+        async fn authenticate_ssh(
+            &self,
+            _username: &str,
+            _public_key: &[u8],
+        ) -> Result<(), ProtocolError> {
+            Ok(())
+        }
+    }
+
+    /// Build a pack holding a single root commit with two blobs, returning the commit and bytes
+    pub(crate) async fn build_test_pack() -> (Commit, Vec<u8>) {
+        // Build simple objects
+        let blob1 = Blob::from_content("hello");
+        let blob2 = Blob::from_content("world");
+
+        let item1 = TreeItem::new(TreeItemMode::Blob, blob1.id, "hello.txt".to_string());
+        let item2 = TreeItem::new(TreeItemMode::Blob, blob2.id, "world.txt".to_string());
+        let tree = Tree::from_tree_items(vec![item1, item2]).unwrap();
+
+        let author = Signature::new(
+            SignatureType::Author,
+            "tester".to_string(),
+            "tester@example.com".to_string(),
+        );
+        let committer = Signature::new(
+            SignatureType::Committer,
+            "tester".to_string(),
+            "tester@example.com".to_string(),
+        );
+        let commit = Commit::new(author, committer, tree.id, vec![], "init commit");
+
+        let entries = vec![
+            Entry::from(commit.clone()),
+            Entry::from(tree),
+            Entry::from(blob1),
+            Entry::from(blob2),
+        ];
+        (commit, encode_test_pack(entries).await)
+    }
+
+    /// Encode a pack of `entries` via PackEncoder
+    pub(crate) async fn encode_test_pack(entries: Vec<Entry>) -> Vec<u8> {
+        let (pack_tx, mut pack_rx) = mpsc::channel(1024);
+        let (entry_tx, entry_rx) = mpsc::channel(1024);
+        let mut encoder = PackEncoder::new(entries.len(), 10, pack_tx);
+
+        tokio::spawn(async move {
+            if let Err(e) = encoder.encode(entry_rx).await {
+                panic!("Failed to encode pack: {}", e);
+            }
+        });
+
+        tokio::spawn(async move {
+            for entry in entries {
+                let _ = entry_tx.send(entry).await;
+            }
+            // sender drop indicates end
+        });
+
+        let mut pack_bytes: Vec<u8> = Vec::new();
+        while let Some(chunk) = pack_rx.recv().await {
+            pack_bytes.extend_from_slice(&chunk);
+        }
+        pack_bytes
+    }
+
+    #[tokio::test]
+    async fn test_receive_pack_stream_status_report() {
+        let (commit, pack_bytes) = build_test_pack().await;
+
+        // Prepare protocol and command
+        let repo_access = TestRepoAccess::new();
+        let auth = TestAuth;
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo_access.clone(), auth);
+        smart.command_list.push(RefCommand::new(
+            ZERO_ID.to_string(),
+            commit.id.to_string(),
+            "refs/heads/main".to_string(),
+        ));
+
+        // Create request stream
+        let request_stream = Box::pin(futures::stream::once(async { Ok(Bytes::from(pack_bytes)) }));
+
+        // Execute receive-pack
+        let result_bytes = smart
+            .git_receive_pack_stream(request_stream)
+            .await
+            .expect("receive-pack should succeed");
+
+        // Verify pkt-lines
+        let mut out = result_bytes.clone();
+        let (_c1, l1) = utils::read_pkt_line(&mut out);
+        assert_eq!(String::from_utf8(l1.to_vec()).unwrap(), "unpack ok\n");
+
+        let (_c2, l2) = utils::read_pkt_line(&mut out);
+        assert_eq!(
+            String::from_utf8(l2.to_vec()).unwrap(),
+            "ok refs/heads/main"
+        );
+
+        let (c3, l3) = utils::read_pkt_line(&mut out);
+        assert_eq!(c3, 4);
+        assert!(l3.is_empty());
+
+        // Verify side effects
+        assert_eq!(repo_access.updates_len(), 1);
+        assert!(repo_access.post_hook_called());
+    }
+
+    #[tokio::test]
+    async fn test_receive_pack_rejects_pack_checksum_mismatch() {
+        let (commit, mut pack_bytes) = build_test_pack().await;
+        // Corrupt the trailer
+        *pack_bytes.last_mut().unwrap() ^= 0xff;
+
+        let repo_access = TestRepoAccess::new();
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo_access.clone(), TestAuth);
+        smart.command_list.push(RefCommand::new(
+            ZERO_ID.to_string(),
+            commit.id.to_string(),
+            "refs/heads/main".to_string(),
+        ));
+        let request_stream = Box::pin(futures::stream::once(async { Ok(Bytes::from(pack_bytes)) }));
+        let mut out = smart.git_receive_pack_stream(request_stream).await.unwrap();
+
+        let (_, l1) = utils::read_pkt_line(&mut out);
+        assert_eq!(l1, "unpack pack checksum mismatch\n");
+        let (_, l2) = utils::read_pkt_line(&mut out);
+        assert_eq!(l2, "ng refs/heads/main unpacker error");
+        let (c3, _) = utils::read_pkt_line(&mut out);
+        assert_eq!(c3, 4);
+
+        // Neither the objects nor the reference were stored
+        assert_eq!(*repo_access.stored_count.lock().unwrap(), 0);
+        assert_eq!(repo_access.updates_len(), 0);
+        assert!(!repo_access.post_hook_called());
+    }
+
+    #[tokio::test]
+    async fn test_receive_pack_fsck_objects() {
+        let blob = Blob::from_content("[core]\n");
+        let item = TreeItem::new(TreeItemMode::Blob, blob.id, ".GIT".to_string());
+        let tree = Tree::from_tree_items(vec![item]).unwrap();
+        let signature =
+            |t| Signature::new(t, "tester".to_string(), "tester@example.com".to_string());
+        let commit = Commit::new(
+            signature(SignatureType::Author),
+            signature(SignatureType::Committer),
+            tree.id,
+            vec![],
+            "\nadd .GIT",
+        );
+        let pack_bytes = encode_test_pack(vec![
+            Entry::from(commit.clone()),
+            Entry::from(tree.clone()),
+            Entry::from(blob),
+        ])
+        .await;
+        let push = |fsck_objects: Option<FsckOptions>| {
+            let repo_access = TestRepoAccess::new();
+            let mut smart =
+                SmartProtocol::new(TransportProtocol::Http, repo_access.clone(), TestAuth);
+            smart.fsck_objects = fsck_objects;
+            smart.command_list.push(RefCommand::new(
+                ZERO_ID.to_string(),
+                commit.id.to_string(),
+                "refs/heads/main".to_string(),
+            ));
+            let pack_bytes = Bytes::from(pack_bytes.clone());
+            async move {
+                let request_stream = Box::pin(futures::stream::once(async { Ok(pack_bytes) }));
+                let out = smart.git_receive_pack_stream(request_stream).await.unwrap();
+                (repo_access, out)
+            }
+        };
+
+        // `hasDotgit` is a warning, which pushes are rejected for as git does
+        let (repo_access, mut out) = push(Some(FsckOptions::default())).await;
+        assert_eq!(
+            utils::read_pkt_line(&mut out).1,
+            format!(
+                "unpack error in tree {}: hasDotgit: contains '.git'\n",
+                tree.id
+            )
+        );
+        assert_eq!(
+            utils::read_pkt_line(&mut out).1,
+            "ng refs/heads/main unpacker error"
+        );
+        assert_eq!(repo_access.updates_len(), 0);
+        assert_eq!(*repo_access.stored_count.lock().unwrap(), 0);
+
+        // Unless the host lowers it
+        let mut options = FsckOptions::default();
+        options.set_severity(FsckMsgId::HasDotgit, FsckSeverity::Warn);
+        let (repo_access, mut out) = push(Some(options)).await;
+        assert_eq!(utils::read_pkt_line(&mut out).1, "unpack ok\n");
+        assert_eq!(repo_access.updates_len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_receive_pack_keeps_indexed_pack() {
+        let (commit, pack_bytes) = build_test_pack().await;
+        let pack_hash = SHA1::from_bytes(&pack_bytes[pack_bytes.len() - 20..]);
+
+        let repo_access = TestRepoAccess::new();
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo_access.clone(), TestAuth);
+        smart.write_pack_index = true;
+        smart.command_list.push(RefCommand::new(
+            ZERO_ID.to_string(),
+            commit.id.to_string(),
+            "refs/heads/main".to_string(),
+        ));
+        let request_stream = Box::pin(futures::stream::once(async { Ok(Bytes::from(pack_bytes)) }));
+        let mut out = smart.git_receive_pack_stream(request_stream).await.unwrap();
+        assert_eq!(utils::read_pkt_line(&mut out).1, "unpack ok\n");
+
+        // Kept while the objects are stored, released once the ref points at them
+        let keeps = repo_access.pack_keeps.lock().unwrap().clone();
+        assert_eq!(keeps.len(), 2);
+        let keep = keeps[0]
+            .strip_prefix(&format!("keep {pack_hash} by "))
+            .unwrap();
+        assert!(keep.starts_with("receive-pack "), "{keep}");
+        assert_eq!(
+            keeps[1],
+            format!("release {pack_hash} by {keep} after 1 updates")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_receive_pack_unpack_limit() {
+        for (unpack_limit, kept) in [(Some(5), false), (Some(4), true), (None, true)] {
+            let (commit, pack_bytes) = build_test_pack().await;
+            let repo_access = TestRepoAccess::new();
+            let mut smart =
+                SmartProtocol::new(TransportProtocol::Http, repo_access.clone(), TestAuth);
+            smart.write_pack_index = true;
+            smart.unpack_limit = unpack_limit;
+            smart.command_list.push(RefCommand::new(
+                ZERO_ID.to_string(),
+                commit.id.to_string(),
+                "refs/heads/main".to_string(),
+            ));
+            let request_stream =
+                Box::pin(futures::stream::once(async { Ok(Bytes::from(pack_bytes)) }));
+            let mut out = smart.git_receive_pack_stream(request_stream).await.unwrap();
+            assert_eq!(utils::read_pkt_line(&mut out).1, "unpack ok\n");
+
+            // The pack of 4 objects is only kept when it reaches the limit
+            let keeps = repo_access.pack_keeps.lock().unwrap().len();
+            assert_eq!(keeps == 2, kept, "{unpack_limit:?}");
+            assert_eq!(repo_access.updates.lock().unwrap().len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receive_pack_rejects_protected_branch_update() {
+        let (commit, pack_bytes) = build_test_pack().await;
+
+        let repo_access = TestRepoAccess::new();
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo_access.clone(), TestAuth);
+        let mut rule = BranchProtection::new("main");
+        rule.require_signed_commits = true;
+        smart.set_branch_protections(vec![rule]);
+        smart.command_list.push(RefCommand::new(
+            ZERO_ID.to_string(),
+            commit.id.to_string(),
+            "refs/heads/main".to_string(),
+        ));
+
+        let request_stream = Box::pin(futures::stream::once(async { Ok(Bytes::from(pack_bytes)) }));
+        let result_bytes = smart
+            .git_receive_pack_stream(request_stream)
+            .await
+            .expect("receive-pack should succeed");
+
+        let mut out = result_bytes.clone();
+        let (_c1, l1) = utils::read_pkt_line(&mut out);
+        assert_eq!(String::from_utf8(l1.to_vec()).unwrap(), "unpack ok\n");
+
+        let (_c2, l2) = utils::read_pkt_line(&mut out);
+        assert_eq!(
+            String::from_utf8(l2.to_vec()).unwrap(),
+            format!(
+                "ng refs/heads/main protected branch main: commit {} is not signed",
+                commit.id
+            )
+        );
+        assert_eq!(repo_access.updates_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_receive_pack_machine_status() {
+        let (commit, pack_bytes) = build_test_pack().await;
+
+        let repo_access = TestRepoAccess::new();
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo_access.clone(), TestAuth);
+        smart.set_branch_protections(vec![BranchProtection::new("main")]);
+        smart.parse_capabilities("report-status machine-status");
+        smart.command_list.push(RefCommand::new(
+            ZERO_ID.to_string(),
+            commit.id.to_string(),
+            "refs/heads/dev".to_string(),
+        ));
+        smart.command_list.push(RefCommand::new(
+            commit.id.to_string(),
+            ZERO_ID.to_string(),
+            "refs/heads/main".to_string(),
+        ));
+
+        let request_stream = Box::pin(futures::stream::once(async { Ok(Bytes::from(pack_bytes)) }));
+        let result_bytes = smart
+            .git_receive_pack_stream(request_stream)
+            .await
+            .expect("receive-pack should succeed");
+
+        let mut out = result_bytes.clone();
+        let mut lines = Vec::new();
+        loop {
+            let (_, line) = utils::read_pkt_line(&mut out);
+            if line.is_empty() {
+                break;
+            }
+            lines.push(String::from_utf8(line.to_vec()).unwrap());
+        }
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[1], "ok refs/heads/dev");
+        assert_eq!(
+            lines[2],
+            format!(
+                "status ref=refs/heads/dev result=ok type=create old={ZERO_ID} new={}",
+                commit.id
+            )
+        );
+        assert_eq!(
+            lines[3],
+            "ng refs/heads/main protected branch main: deletion is not allowed"
+        );
+        assert_eq!(
+            lines[4],
+            format!(
+                "status ref=refs/heads/main result=ng type=delete old={} new={ZERO_ID} \
+                 code=protected-deletion \
+                 message=protected%20branch%20main:%20deletion%20is%20not%20allowed",
+                commit.id
+            )
+        );
+    }
+
+    struct AcceptAll;
 
     #[async_trait]
-    impl RepositoryAccess for TestRepoAccess {
-        async fn get_repository_refs(&self) -> Result<Vec<(String, String)>, ProtocolError> {
-            Ok(vec![
-                (
-                    "HEAD".to_string(),
-                    "0000000000000000000000000000000000000000".to_string(),
-                ),
-                (
-                    "refs/heads/main".to_string(),
-                    "1111111111111111111111111111111111111111".to_string(),
-                ),
-            ])
+    impl SignatureVerifier for AcceptAll {
+        async fn verify(
+            &self,
+            _signature: &ObjectSignature,
+            _signer: &Signature,
+        ) -> Result<Verification, GitError> {
+            Ok(Verification::Good {
+                key_id: "test".to_string(),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receive_pack_rejects_unsigned_tag() {
+        let (commit, pack_bytes) = build_test_pack().await;
+
+        let repo_access = TestRepoAccess::new();
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo_access.clone(), TestAuth);
+        smart.set_tag_verifier(Arc::new(AcceptAll));
+        smart.command_list.push(RefCommand::new(
+            ZERO_ID.to_string(),
+            commit.id.to_string(),
+            "refs/tags/v1.0".to_string(),
+        ));
+
+        let request_stream = Box::pin(futures::stream::once(async { Ok(Bytes::from(pack_bytes)) }));
+        let result_bytes = smart
+            .git_receive_pack_stream(request_stream)
+            .await
+            .expect("receive-pack should succeed");
+
+        let mut out = result_bytes.clone();
+        let _ = utils::read_pkt_line(&mut out);
+        let (_c2, l2) = utils::read_pkt_line(&mut out);
+        assert_eq!(
+            String::from_utf8(l2.to_vec()).unwrap(),
+            "ng refs/tags/v1.0 refs/tags/v1.0 is not a signed annotated tag"
+        );
+        assert_eq!(
+            smart.command_list[0].error_code.as_deref(),
+            Some("unsigned-tag")
+        );
+        assert_eq!(repo_access.updates_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_receive_pack_rejects_invalid_ref_names() {
+        let (commit, pack_bytes) = build_test_pack().await;
+
+        let repo_access = TestRepoAccess::new();
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo_access.clone(), TestAuth);
+        let mut commands = BytesMut::new();
+        for (index, name) in ["refs/heads/main", "refs/heads/a..b", "HEAD"]
+            .iter()
+            .enumerate()
+        {
+            let caps = if index == 0 { "\0report-status" } else { "" };
+            add_pkt_line_string(
+                &mut commands,
+                format!("{ZERO_ID} {} {name}{caps}\n", commit.id),
+            );
+        }
+        commands.put(&PKT_LINE_END_MARKER[..]);
+        smart
+            .parse_receive_pack_commands(commands.freeze())
+            .unwrap();
+
+        let request_stream = Box::pin(futures::stream::once(async { Ok(Bytes::from(pack_bytes)) }));
+        let result_bytes = smart
+            .git_receive_pack_stream(request_stream)
+            .await
+            .expect("receive-pack should succeed");
+
+        let mut out = result_bytes.clone();
+        let lines: Vec<String> = std::iter::from_fn(|| {
+            let (_, line) = utils::read_pkt_line(&mut out);
+            (!line.is_empty()).then(|| String::from_utf8(line.to_vec()).unwrap())
+        })
+        .collect();
+        assert_eq!(lines[1], "ok refs/heads/main");
+        assert_eq!(
+            lines[2],
+            "ng refs/heads/a..b Invalid reference name `refs/heads/a..b`: contains `..`"
+        );
+        assert_eq!(
+            lines[3],
+            "ng HEAD Invalid reference name `HEAD`: not under refs/"
+        );
+        assert_eq!(
+            smart.command_list[1].error_code.as_deref(),
+            Some("invalid-ref-name")
+        );
+        assert_eq!(repo_access.updates_len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_receive_pack_records_provenance() {
+        let (commit, pack_bytes) = build_test_pack().await;
+
+        let repo_access = TestRepoAccess::new();
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo_access.clone(), TestAuth);
+        smart.set_pusher("alice");
+        smart.parse_capabilities("report-status session-id=push-42");
+        smart.command_list.push(RefCommand::new(
+            ZERO_ID.to_string(),
+            commit.id.to_string(),
+            "refs/heads/main".to_string(),
+        ));
+
+        let request_stream = Box::pin(futures::stream::once(async { Ok(Bytes::from(pack_bytes)) }));
+        smart
+            .git_receive_pack_stream(request_stream)
+            .await
+            .expect("receive-pack should succeed");
+
+        let recorded = repo_access.provenance.lock().unwrap().clone();
+        assert_eq!(recorded.len(), 4);
+        assert!(recorded.iter().any(|(id, _)| *id == commit.id.to_string()));
+        for (_, provenance) in &recorded {
+            assert_eq!(provenance.pusher.as_deref(), Some("alice"));
+            assert_eq!(provenance.session_id.as_deref(), Some("push-42"));
+            assert!(provenance.timestamp > 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receive_pack_mirror_push_streams_report() {
+        let (commit, pack_bytes) = build_test_pack().await;
+
+        // Commands and pack in one request, as sent by `git push --mirror`
+        let ref_count = 2500;
+        let mut request = BytesMut::new();
+        for i in 0..ref_count {
+            let caps = if i == 0 { "\0report-status" } else { "" };
+            add_pkt_line_string(
+                &mut request,
+                format!("{ZERO_ID} {} refs/heads/branch-{i}{caps}\n", commit.id),
+            );
+        }
+        request.put(&PKT_LINE_END_MARKER[..]);
+        request.extend_from_slice(&pack_bytes);
+        // Deliver the request in small chunks that split pkt-lines
+        let chunks: Vec<Result<Bytes, ProtocolError>> = request
+            .freeze()
+            .chunks(1000)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+
+        let repo_access = TestRepoAccess::new();
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo_access.clone(), TestAuth);
+        let mut report = smart
+            .git_receive_pack_incremental(Box::pin(futures::stream::iter(chunks)))
+            .await
+            .expect("receive-pack should succeed");
+
+        let mut report_chunks = Vec::new();
+        while let Some(chunk) = futures::StreamExt::next(&mut report).await {
+            report_chunks.push(chunk.unwrap());
+        }
+        // "unpack ok", one chunk per batch of ref updates, flush-pkt
+        assert_eq!(report_chunks.len(), 5);
+        let mut out = Bytes::from(report_chunks.concat());
+        let (_, first) = utils::read_pkt_line(&mut out);
+        assert_eq!(first, "unpack ok\n");
+        for i in 0..ref_count {
+            let (_, line) = utils::read_pkt_line(&mut out);
+            assert_eq!(line, format!("ok refs/heads/branch-{i}"));
         }
+        let (c, last) = utils::read_pkt_line(&mut out);
+        assert_eq!(c, 4);
+        assert!(last.is_empty());
+
+        assert_eq!(
+            *repo_access.batch_sizes.lock().unwrap(),
+            vec![1000, 1000, 500]
+        );
+        assert_eq!(repo_access.updates_len(), ref_count);
+        assert!(repo_access.post_hook_called());
+    }
+
+    #[tokio::test]
+    async fn test_upload_pack_haves_skip_filtered_objects() {
+        let filter = ObjectFilter::new(10, 0.01);
+        filter.insert(&SHA1::from_str(&"1".repeat(40)).unwrap());
+        let repo = TestRepoAccess::new();
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo.clone(), TestAuth);
+        smart.set_object_filter(Arc::new(filter));
+
+        let mut request = BytesMut::new();
+        add_pkt_line_string(&mut request, format!("have {}\n", "2".repeat(40)));
+        add_pkt_line_string(&mut request, format!("have {}\n", "3".repeat(40)));
+        add_pkt_line_string(&mut request, "done\n".to_string());
+        let (_, response) = smart.git_upload_pack(request.freeze()).await.unwrap();
+        assert_eq!(response, "0008NAK\n");
+        assert_eq!(repo.object_lookups.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_info_refs_head_fallback() {
+        let mut smart =
+            SmartProtocol::new(TransportProtocol::Http, TestRepoAccess::new(), TestAuth);
+        let advertised = smart.git_info_refs(ServiceType::UploadPack).await.unwrap();
+        let advertised = String::from_utf8_lossy(&advertised).to_string();
+        // The repository reports an unresolved HEAD, refs/heads/main is advertised instead
+        assert!(advertised.contains(&format!("{} HEAD\0", "1".repeat(40))));
+        assert!(!advertised.contains(&format!("{ZERO_ID} HEAD")));
+        assert!(advertised.contains(" symref=HEAD:refs/heads/main"));
+
+        smart.set_head_fallbacks(vec!["refs/heads/trunk".to_string()]);
+        let advertised = smart.git_info_refs(ServiceType::UploadPack).await.unwrap();
+        let advertised = String::from_utf8_lossy(&advertised).to_string();
+        assert!(advertised.contains(&format!("{ZERO_ID} capabilities^{{}}\0")));
+        assert!(!advertised.contains("symref="));
+
+        // The default branch the repository reports comes first
+        let repo = TestRepoAccess::new();
+        *repo.default_branch.lock().unwrap() = Some("refs/heads/main".to_string());
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo, TestAuth);
+        smart.set_head_fallbacks(vec!["refs/heads/trunk".to_string()]);
+        let advertised = smart.git_info_refs(ServiceType::UploadPack).await.unwrap();
+        let advertised = String::from_utf8_lossy(&advertised).to_string();
+        assert!(advertised.contains(&format!("{} HEAD\0", "1".repeat(40))));
+        assert!(advertised.contains(" symref=HEAD:refs/heads/main"));
+    }
+
+    #[tokio::test]
+    async fn test_info_refs_symref() {
+        let repo = MemoryRepository::new();
+        let main = SHA1::from_str(&"1".repeat(40)).unwrap();
+        let dev = SHA1::from_str(&"2".repeat(40)).unwrap();
+        repo.set_ref("refs/heads/main", main);
+        repo.set_ref("refs/heads/dev", dev);
+        repo.set_symbolic_ref("HEAD", "refs/heads/dev")
+            .await
+            .unwrap();
+        assert!(repo.set_symbolic_ref("HEAD", "refs/tags/v1").await.is_err());
+        assert!(
+            repo.set_symbolic_ref("refs/heads/alias", "refs/heads/dev")
+                .await
+                .is_err()
+        );
+
+        let smart = SmartProtocol::new(TransportProtocol::Http, repo.clone(), TestAuth);
+        let advertised = smart.git_info_refs(ServiceType::UploadPack).await.unwrap();
+        let advertised = String::from_utf8_lossy(&advertised).to_string();
+        assert!(advertised.contains(&format!("{dev} HEAD\0")));
+        assert!(advertised.contains(" symref=HEAD:refs/heads/dev"));
+        assert_eq!(
+            repo.get_symbolic_ref("HEAD").await.unwrap().as_deref(),
+            Some("refs/heads/dev")
+        );
+    }
 
-        async fn has_object(&self, _object_hash: &str) -> Result<bool, ProtocolError> {
-            Ok(true)
+    /// The first reply of upload-pack to `request`
+    async fn upload_pack_reply<R: RepositoryAccess, A: AuthenticationService>(
+        smart: &mut SmartProtocol<R, A>,
+        request: BytesMut,
+    ) -> String {
+        let (mut stream, _) = smart.git_upload_pack(request.freeze()).await.unwrap();
+        let reply = futures::StreamExt::next(&mut stream).await.unwrap();
+        String::from_utf8_lossy(&reply).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_protocol_version() {
+        let repo = MemoryRepository::new();
+        let main = SHA1::from_str(&"1".repeat(40)).unwrap();
+        repo.set_ref("refs/heads/main", main);
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo, TestAuth);
+        let advertised = smart.git_info_refs(ServiceType::UploadPack).await.unwrap();
+        assert!(advertised.starts_with(b"001e# service=git-upload-pack\n0000"));
+        assert!(!advertised.windows(9).any(|line| line == b"version 1"));
+
+        assert_eq!(
+            smart.negotiate_protocol_version(Some("version=1")),
+            ProtocolVersion::V1
+        );
+        let advertised = smart.git_info_refs(ServiceType::UploadPack).await.unwrap();
+        assert!(advertised.starts_with(b"001e# service=git-upload-pack\n0000000eversion 1\n"));
+
+        // Upload-pack opens with its capabilities in version 2, receive-pack stays in version 0
+        assert_eq!(
+            smart.negotiate_protocol_version(Some("version=2")),
+            ProtocolVersion::V2
+        );
+        let advertised = smart.git_info_refs(ServiceType::UploadPack).await.unwrap();
+        assert_eq!(advertised, smart.git_v2_capabilities());
+        let advertised = smart.git_info_refs(ServiceType::ReceivePack).await.unwrap();
+        assert!(advertised.starts_with(b"001f# service=git-receive-pack\n0000"));
+        assert!(!advertised.windows(9).any(|line| line == b"version 2"));
+        assert_eq!(smart.negotiate_protocol_version(None), ProtocolVersion::V0);
+    }
+
+    #[tokio::test]
+    async fn test_bundle_uri() {
+        use crate::protocol::bundle_uri::BundleUri;
+
+        let mut smart =
+            SmartProtocol::new(TransportProtocol::Http, MemoryRepository::new(), TestAuth);
+        smart.agent = "test".to_string();
+        let request = v2_request;
+        let bundle_uri = ["command=bundle-uri", "agent=git/2.45", "object-format=sha1"];
+        assert_eq!(
+            smart.git_v2_capabilities(),
+            &b"000eversion 2\n000fagent=test\n0017object-format=sha1\n000cls-refs\n\
+               0016fetch=ref-in-want\n0000"[..]
+        );
+        assert!(
+            smart
+                .git_v2_command(&mut request(&bundle_uri, &[]))
+                .await
+                .is_err()
+        );
+
+        let mut bundle_list = BundleList::new();
+        bundle_list
+            .bundles
+            .push(BundleUri::new("full", "https://cdn.example.com/full.bundle").unwrap());
+        smart.bundle_list = Some(bundle_list);
+        assert!(
+            smart
+                .git_v2_capabilities()
+                .ends_with(b"000fbundle-uri\n0000")
+        );
+        let reply = v2_reply(&mut smart, &bundle_uri, &[]).await;
+        assert_eq!(
+            reply,
+            &b"0015bundle.version=1\n0014bundle.mode=all\n\
+               0038bundle.full.uri=https://cdn.example.com/full.bundle\n0000"[..]
+        );
+        for (lines, arguments) in [
+            (&bundle_uri[..], &["unexpected"][..]),
+            (&["command=object-info"], &[]),
+            (&["command=bundle-uri", "object-format=sha256"], &[]),
+        ] {
+            assert!(
+                smart
+                    .git_v2_command(&mut request(lines, arguments))
+                    .await
+                    .is_err()
+            );
         }
+    }
 
-        async fn get_object(&self, _object_hash: &str) -> Result<Vec<u8>, ProtocolError> {
-            Ok(vec![])
+    #[tokio::test]
+    async fn test_packet_tracer() {
+        let repo = MemoryRepository::new();
+        let main = SHA1::from_str(&"1".repeat(40)).unwrap();
+        repo.set_ref("refs/heads/main", main);
+        let mut smart = SmartProtocol::new(TransportProtocol::Ssh, repo, TestAuth);
+        let tracer = Arc::new(RecordingTracer::default());
+        smart.set_packet_tracer(tracer.clone());
+
+        smart.git_info_refs(ServiceType::UploadPack).await.unwrap();
+        let missing = "2".repeat(40);
+        let mut request = BytesMut::new();
+        add_pkt_line_string(&mut request, format!("want {missing}\n"));
+        request.put(&PKT_LINE_END_MARKER[..]);
+        upload_pack_reply(&mut smart, request).await;
+
+        let packets = tracer.0.lock().unwrap();
+        let (inbound, outbound): (Vec<_>, Vec<_>) = packets
+            .iter()
+            .partition(|(direction, _)| *direction == PacketDirection::Inbound);
+        let inbound: Vec<&str> = inbound.iter().map(|(_, packet)| packet.as_str()).collect();
+        assert_eq!(inbound, [format!("0032want {missing}\n").as_str(), "0000"]);
+        let outbound: Vec<&str> = outbound.iter().map(|(_, packet)| packet.as_str()).collect();
+        assert_eq!(outbound.len(), 4, "{outbound:?}");
+        assert!(outbound[0].contains(&format!("{main} HEAD\0")));
+        assert_eq!(outbound[1], format!("003d{main} refs/heads/main\n"));
+        assert_eq!(outbound[2], "0000");
+        assert!(outbound[3].starts_with("004aERR upload-pack: not our ref"));
+    }
+
+    #[tokio::test]
+    async fn test_upload_pack_want_policy() {
+        let repo = MemoryRepository::new();
+        let blob = Blob::from_content("hello");
+        let item = TreeItem::new(TreeItemMode::Blob, blob.id, "hello.txt".to_string());
+        let tree = Tree::from_tree_items(vec![item]).unwrap();
+        let commit = |parents, message| {
+            let author = Signature::new(
+                SignatureType::Author,
+                "tester".to_string(),
+                "tester@example.com".to_string(),
+            );
+            let committer = Signature::new(
+                SignatureType::Committer,
+                "tester".to_string(),
+                "tester@example.com".to_string(),
+            );
+            Commit::new(author, committer, tree.id, parents, message)
+        };
+        let first = commit(vec![], "first");
+        let second = commit(vec![first.id], "second");
+        let hidden = commit(vec![], "hidden");
+        let dangling = commit(vec![], "dangling");
+        repo.insert_object(&blob).unwrap();
+        repo.insert_object(&tree).unwrap();
+        for commit in [&first, &second, &hidden, &dangling] {
+            repo.insert_object(commit).unwrap();
+        }
+        repo.set_ref("refs/heads/main", second.id);
+        repo.set_ref("refs/internal/ci", hidden.id);
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo, TestAuth);
+        let mut hidden_refs = HiddenRefs::new();
+        hidden_refs.hide("refs/internal");
+        smart.set_hidden_refs(hidden_refs);
+
+        let want = |id: SHA1| {
+            let mut request = BytesMut::new();
+            add_pkt_line_string(&mut request, format!("want {id} side-band-64k\n"));
+            add_pkt_line_string(&mut request, "done\n".to_string());
+            request
+        };
+        let refused = |id: SHA1| format!("ERR upload-pack: not our ref {id}\n");
+        for (policy, id, allowed) in [
+            (WantPolicy::Advertised, second.id, true),
+            (WantPolicy::Advertised, first.id, false),
+            (WantPolicy::Advertised, hidden.id, false),
+            (WantPolicy::Tip, hidden.id, true),
+            (WantPolicy::Tip, first.id, false),
+            (WantPolicy::Reachable, first.id, true),
+            (WantPolicy::Reachable, hidden.id, false),
+            (WantPolicy::Reachable, dangling.id, false),
+            (WantPolicy::Any, dangling.id, true),
+        ] {
+            smart.set_want_policy(policy);
+            let reply = upload_pack_reply(&mut smart, want(id)).await;
+            assert_eq!(!reply.ends_with(&refused(id)), allowed, "{policy:?} {id}");
         }
 
-        async fn store_pack_data(&self, _pack_data: &[u8]) -> Result<(), ProtocolError> {
-            *self.stored_count.lock().unwrap() += 1;
-            Ok(())
+        // The policy is advertised to upload-pack clients
+        async fn caps<R: RepositoryAccess, A: AuthenticationService>(
+            smart: &SmartProtocol<R, A>,
+            service: ServiceType,
+        ) -> (bool, bool) {
+            let refs = smart.git_info_refs(service).await.unwrap();
+            let advertised = String::from_utf8_lossy(&refs).to_string();
+            (
+                advertised.contains(" allow-tip-sha1-in-want"),
+                advertised.contains(" allow-reachable-sha1-in-want"),
+            )
         }
+        assert_eq!(caps(&smart, ServiceType::UploadPack).await, (true, true));
+        assert_eq!(caps(&smart, ServiceType::ReceivePack).await, (false, false));
+        smart.set_want_policy(WantPolicy::Reachable);
+        assert_eq!(caps(&smart, ServiceType::UploadPack).await, (false, true));
+        smart.set_want_policy(WantPolicy::Advertised);
+        assert_eq!(caps(&smart, ServiceType::UploadPack).await, (false, false));
+    }
 
-        async fn update_reference(
-            &self,
-            ref_name: &str,
-            old_hash: Option<&str>,
-            new_hash: &str,
-        ) -> Result<(), ProtocolError> {
-            self.updates.lock().unwrap().push((
-                ref_name.to_string(),
-                old_hash.map(|s| s.to_string()),
-                new_hash.to_string(),
-            ));
-            Ok(())
+    #[tokio::test]
+    async fn test_upload_pack_stateless_rounds() {
+        let repo = MemoryRepository::new();
+        let (commit, pack) = build_test_pack().await;
+        repo.store_pack_data(&pack).await.unwrap();
+        // The client has `commit` and fetches its child
+        let tip = Commit::new(
+            commit.author.clone(),
+            commit.committer.clone(),
+            commit.tree_id,
+            vec![commit.id],
+            "second",
+        );
+        repo.insert_object(&tip).unwrap();
+        repo.set_ref("refs/heads/main", tip.id);
+        let unknown = "4".repeat(40);
+        let round = |caps: &str, haves: &[&str], done: bool| {
+            let mut request = BytesMut::new();
+            add_pkt_line_string(&mut request, format!("want {} {caps}\n", tip.id));
+            add_pkt_line_string(&mut request, format!("shallow {unknown}\n"));
+            request.put(&PKT_LINE_END_MARKER[..]);
+            for have in haves {
+                add_pkt_line_string(&mut request, format!("have {have}\n"));
+            }
+            match done {
+                true => add_pkt_line_string(&mut request, "done\n".to_string()),
+                false => request.put(&PKT_LINE_END_MARKER[..]),
+            }
+            request.freeze()
+        };
+        let lines = |reply: &[u8]| {
+            let mut reply = Bytes::copy_from_slice(reply);
+            let mut lines = Vec::new();
+            while let Some(PktLine::Data(line)) = parse_pkt_line(&mut reply).unwrap() {
+                lines.push(String::from_utf8_lossy(&line).trim_end().to_string());
+            }
+            lines
+        };
+        let common = commit.id.to_string();
+
+        // Every round over HTTP is a request of its own, answered without a pack until done
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo.clone(), TestAuth);
+        let caps = "multi_ack_detailed side-band-64k";
+        let (mut pack_stream, reply) = smart
+            .git_upload_pack(round(caps, &[&unknown], false))
+            .await
+            .unwrap();
+        assert_eq!(lines(&reply), ["NAK"]);
+        assert!(futures::StreamExt::next(&mut pack_stream).await.is_none());
+
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo.clone(), TestAuth);
+        let (_, reply) = smart
+            .git_upload_pack(round(caps, &[&common, &unknown], false))
+            .await
+            .unwrap();
+        let expected = [
+            format!("ACK {common} common"),
+            format!("ACK {unknown} ready"),
+            "NAK".to_string(),
+        ];
+        assert_eq!(lines(&reply), expected);
+        assert!(!smart.negotiation.done);
+        assert_eq!(smart.negotiation.common, [common.as_str()]);
+        assert_eq!(smart.negotiation.shallow, [unknown.as_str()]);
+
+        // The state carries over to the next request, where the common have needs no lookup
+        let state = serde_json::to_string(&smart.negotiation).unwrap();
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo.clone(), TestAuth);
+        smart.set_negotiation(serde_json::from_str(&state).unwrap());
+        let (mut pack_stream, reply) = smart
+            .git_upload_pack(round(caps, &[&common], true))
+            .await
+            .unwrap();
+        assert_eq!(
+            lines(&reply),
+            [
+                format!("ACK {common} common"),
+                format!("ACK {common}"),
+                "\x02Enumerating objects: 1, done.".to_string(),
+            ]
+        );
+        assert!(futures::StreamExt::next(&mut pack_stream).await.is_some());
+        assert!(smart.negotiation.done);
+
+        // With no-done the pack follows the ready round, and multi_ack continues
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo.clone(), TestAuth);
+        let (_, reply) = smart
+            .git_upload_pack(round("multi_ack_detailed no-done", &[&common], false))
+            .await
+            .unwrap();
+        let expected = [
+            format!("ACK {common} common"),
+            format!("ACK {common} ready"),
+            "NAK".to_string(),
+            format!("ACK {common}"),
+        ];
+        assert_eq!(lines(&reply), expected);
+        assert!(smart.negotiation.done);
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo, TestAuth);
+        let (_, reply) = smart
+            .git_upload_pack(round("multi_ack", &[&common, &unknown], false))
+            .await
+            .unwrap();
+        let expected = [
+            format!("ACK {common} continue"),
+            format!("ACK {unknown} continue"),
+            "NAK".to_string(),
+        ];
+        assert_eq!(lines(&reply), expected);
+    }
+
+    /// A protocol v2 request of `lines`, the command and the capabilities, and `arguments`
+    fn v2_request(lines: &[&str], arguments: &[&str]) -> PktLineReader<ProtocolStream> {
+        let mut request = BytesMut::new();
+        for line in lines {
+            add_pkt_line_string(&mut request, format!("{line}\n"));
+        }
+        request.put(&PKT_LINE_DELIM_MARKER[..]);
+        for argument in arguments {
+            add_pkt_line_string(&mut request, format!("{argument}\n"));
         }
+        request.put(&PKT_LINE_END_MARKER[..]);
+        PktLineReader::new(Box::pin(futures::stream::iter([Ok(request.freeze())])))
+    }
 
-        async fn get_objects_for_pack(
-            &self,
-            _wants: &[String],
-            _haves: &[String],
-        ) -> Result<Vec<String>, ProtocolError> {
-            Ok(vec![])
+    /// The whole reply to the protocol v2 request of `lines` and `arguments`
+    async fn v2_reply<R: RepositoryAccess, A: AuthenticationService>(
+        smart: &mut SmartProtocol<R, A>,
+        lines: &[&str],
+        arguments: &[&str],
+    ) -> Bytes {
+        let reply = smart
+            .git_v2_command(&mut v2_request(lines, arguments))
+            .await
+            .unwrap();
+        let chunks: Vec<Bytes> = futures::StreamExt::collect::<Vec<_>>(reply)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        Bytes::from(chunks.concat())
+    }
+
+    #[tokio::test]
+    async fn test_v2_ls_refs() {
+        let repo = MemoryRepository::new();
+        let (commit, pack) = build_test_pack().await;
+        repo.store_pack_data(&pack).await.unwrap();
+        let tag = Tag::new(
+            commit.id,
+            ObjectType::Commit,
+            "v1".to_string(),
+            Signature::new(
+                SignatureType::Tagger,
+                "tester".to_string(),
+                "tester@example.com".to_string(),
+            ),
+            "\nfirst".to_string(),
+        );
+        repo.insert_object(&tag).unwrap();
+        repo.set_ref("refs/heads/main", commit.id);
+        repo.set_ref("refs/tags/v1", tag.id);
+        repo.set_ref("refs/internal/ci", commit.id);
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo, TestAuth);
+        let mut hidden = HiddenRefs::new();
+        hidden.hide("refs/internal");
+        smart.set_hidden_refs(hidden);
+        let ls_refs = ["command=ls-refs", "agent=git/2.45", "object-format=sha1"];
+
+        let reply = v2_reply(&mut smart, &ls_refs, &[]).await;
+        assert_eq!(
+            reply,
+            format!(
+                "0032{0} HEAD\n003d{0} refs/heads/main\n003a{1} refs/tags/v1\n0000",
+                commit.id, tag.id
+            )
+            .as_bytes()
+        );
+        let arguments = [
+            "symrefs",
+            "peel",
+            "ref-prefix HEAD",
+            "ref-prefix refs/tags/",
+        ];
+        let reply = v2_reply(&mut smart, &ls_refs, &arguments).await;
+        assert_eq!(
+            reply,
+            format!(
+                "0050{0} HEAD symref-target:refs/heads/main\n\
+                 006a{1} refs/tags/v1 peeled:{0}\n0000",
+                commit.id, tag.id
+            )
+            .as_bytes()
+        );
+        let reply = v2_reply(&mut smart, &ls_refs, &["ref-prefix refs/internal/"]).await;
+        assert_eq!(reply, &b"0000"[..]);
+
+        for argument in ["unborn", "ref-prefix"] {
+            assert!(
+                smart
+                    .git_v2_command(&mut v2_request(&ls_refs, &[argument]))
+                    .await
+                    .is_err()
+            );
         }
+    }
 
-        async fn has_default_branch(&self) -> Result<bool, ProtocolError> {
-            let mut exists = self.default_branch_exists.lock().unwrap();
-            let current = *exists;
-            *exists = true; // flip to true after first check
-            Ok(current)
+    #[tokio::test]
+    async fn test_v2_fetch() {
+        let repo = MemoryRepository::new();
+        let (commit, pack) = build_test_pack().await;
+        repo.store_pack_data(&pack).await.unwrap();
+        repo.set_ref("refs/heads/main", commit.id);
+        repo.set_ref("refs/internal/ci", commit.id);
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo, TestAuth);
+        let mut hidden = HiddenRefs::new();
+        hidden.hide("refs/internal");
+        smart.set_hidden_refs(hidden);
+        let fetch = ["command=fetch", "agent=git/2.45", "object-format=sha1"];
+        let pkt_lines = |mut reply: Bytes| {
+            let mut pkt_lines = Vec::new();
+            while let Some(pkt_line) = parse_pkt_line(&mut reply).unwrap() {
+                pkt_lines.push(pkt_line);
+            }
+            pkt_lines
+        };
+        let data = |line: String| PktLine::Data(Bytes::from(line));
+
+        // A want-ref is resolved in the wanted-refs section, the pack follows in band 1
+        let want_ref = [
+            "want-ref refs/heads/main",
+            "ofs-delta",
+            "no-progress",
+            "done",
+        ];
+        let reply = pkt_lines(v2_reply(&mut smart, &fetch, &want_ref).await);
+        assert_eq!(
+            reply[..4],
+            [
+                data("wanted-refs\n".to_string()),
+                data(format!("{} refs/heads/main\n", commit.id)),
+                PktLine::Delim,
+                data("packfile\n".to_string()),
+            ]
+        );
+        assert_eq!(reply.last(), Some(&PktLine::Flush));
+        let mut received = Vec::new();
+        for pkt_line in &reply[4..reply.len() - 1] {
+            let PktLine::Data(packet) = pkt_line else {
+                panic!("{pkt_line:?} in the packfile section");
+            };
+            assert_eq!(packet[0], SideBand::PackfileData.value());
+            received.extend_from_slice(&packet[1..]);
+        }
+        assert!(received.starts_with(b"PACK"));
+        assert_eq!(received[8..12], 4u32.to_be_bytes());
+        assert!(smart.negotiation.done);
+
+        for name in ["refs/heads/missing", "refs/internal/ci"] {
+            let argument = format!("want-ref {name}");
+            let reply = v2_reply(&mut smart, &fetch, &[&argument, "done"]).await;
+            assert_eq!(
+                pkt_lines(reply),
+                [data(format!("ERR unknown ref {name}\n"))]
+            );
         }
 
-        async fn post_receive_hook(&self) -> Result<(), ProtocolError> {
-            self.post_called.store(true, Ordering::SeqCst);
-            Ok(())
+        // Without done, the haves are acknowledged until one of them is common
+        let want = format!("want {}", commit.id);
+        let unknown = format!("have {}", "2".repeat(40));
+        let reply = v2_reply(&mut smart, &fetch, &[&want, &unknown]).await;
+        assert_eq!(
+            pkt_lines(reply),
+            [
+                data("acknowledgments\n".to_string()),
+                data("NAK\n".to_string()),
+                PktLine::Flush,
+            ]
+        );
+        let common = format!("have {}", commit.id);
+        let reply = v2_reply(&mut smart, &fetch, &[&want, &unknown, &common]).await;
+        let reply = pkt_lines(reply);
+        assert_eq!(
+            reply[..5],
+            [
+                data("acknowledgments\n".to_string()),
+                data(format!("ACK {}\n", commit.id)),
+                data("ready\n".to_string()),
+                PktLine::Delim,
+                data("packfile\n".to_string()),
+            ]
+        );
+        assert_eq!(reply.last(), Some(&PktLine::Flush));
+
+        for arguments in [&["deepen 1", "done"][..], &["done"]] {
+            assert!(
+                smart
+                    .git_v2_command(&mut v2_request(&fetch, arguments))
+                    .await
+                    .is_err()
+            );
         }
     }
 
-    struct TestAuth;
-
-    #[async_trait]
-    impl AuthenticationService for TestAuth {
-        async fn authenticate_http(
-            &self,
-            _headers: &std::collections::HashMap<String, String>,
-        ) -> Result<(), ProtocolError> {
-            Ok(())
+    #[tokio::test]
+    async fn test_upload_pack_progress() {
+        let repo = MemoryRepository::new();
+        let blob = Blob::from_content("hello");
+        let item = TreeItem::new(TreeItemMode::Blob, blob.id, "hello.txt".to_string());
+        let tree = Tree::from_tree_items(vec![item]).unwrap();
+        let signature = |kind| {
+            Signature::new(kind, "tester".to_string(), "tester@example.com".to_string())
+        };
+        let author = signature(SignatureType::Author);
+        let commit = Commit::new(author, signature(SignatureType::Committer), tree.id, vec![], "");
+        repo.insert_object(&blob).unwrap();
+        repo.insert_object(&tree).unwrap();
+        repo.insert_object(&commit).unwrap();
+        repo.set_ref("refs/heads/main", commit.id);
+
+        for (caps, progress) in [
+            ("side-band-64k", "0023\x02Enumerating objects: 3, done.\n"),
+            ("side-band-64k no-progress", ""),
+            ("ofs-delta", ""),
+        ] {
+            let mut smart = SmartProtocol::new(TransportProtocol::Http, repo.clone(), TestAuth);
+            let mut request = BytesMut::new();
+            add_pkt_line_string(&mut request, format!("want {} {caps}\n", commit.id));
+            add_pkt_line_string(&mut request, "done\n".to_string());
+            let (_, reply) = smart.git_upload_pack(request.freeze()).await.unwrap();
+            assert_eq!(reply, format!("0008NAK\n{progress}").as_bytes(), "{caps}");
         }
+    }
 
-        async fn authenticate_ssh(
-            &self,
-            _username: &str,
-            _public_key: &[u8],
-        ) -> Result<(), ProtocolError> {
-            Ok(())
+    #[tokio::test]
+    async fn test_receive_pack_progress() {
+        let (commit, pack_bytes) = build_test_pack().await;
+        for (caps, progress) in [("side-band-64k", true), ("side-band-64k quiet", false)] {
+            let mut request = BytesMut::new();
+            add_pkt_line_string(
+                &mut request,
+                format!("{ZERO_ID} {} refs/heads/main\0report-status {caps}\n", commit.id),
+            );
+            request.put(&PKT_LINE_END_MARKER[..]);
+            request.extend_from_slice(&pack_bytes);
+            let mut smart =
+                SmartProtocol::new(TransportProtocol::Http, TestRepoAccess::new(), TestAuth);
+            let report = smart
+                .git_receive_pack_incremental(Box::pin(futures::stream::iter([Ok(
+                    request.freeze(),
+                )])))
+                .await
+                .unwrap();
+            let chunks: Vec<Bytes> = futures::StreamExt::collect::<Vec<_>>(report)
+                .await
+                .into_iter()
+                .map(Result::unwrap)
+                .collect();
+            let mut reply = Bytes::from(chunks.concat());
+            let mut packets = Vec::new();
+            while !reply.is_empty() {
+                packets.push(utils::read_pkt_line(&mut reply).1);
+            }
+
+            // The progress comes in band 2, the report in band 1, then the side-band ends
+            let report_start = usize::from(progress);
+            if progress {
+                let message = String::from_utf8_lossy(&packets[0]).to_string();
+                assert!(message.starts_with("\x02Unpacking objects: "), "{message}");
+                assert!(message.ends_with(", done.\n"), "{message}");
+            }
+            assert!(packets[report_start].starts_with(b"\x01"), "{caps}");
+            let mut report = packets[report_start].slice(1..);
+            assert_eq!(utils::read_pkt_line(&mut report).1, "unpack ok\n");
+            assert_eq!(packets.len(), report_start + 4, "{caps}");
+            assert!(packets.last().unwrap().is_empty());
         }
     }
 
     #[tokio::test]
-    async fn test_receive_pack_stream_status_report() {
-        // Build simple objects
-        let blob1 = Blob::from_content("hello");
-        let blob2 = Blob::from_content("world");
+    async fn test_agent_capability() {
+        let mut smart =
+            SmartProtocol::new(TransportProtocol::Http, TestRepoAccess::new(), TestAuth);
+        let refs = smart.git_info_refs(ServiceType::UploadPack).await.unwrap();
+        let advertised = String::from_utf8_lossy(&refs).to_string();
+        assert!(advertised.contains(&format!(" agent={DEFAULT_AGENT}")));
+
+        smart.set_agent("forge 1.2\n");
+        let refs = smart.git_info_refs(ServiceType::ReceivePack).await.unwrap();
+        let advertised = String::from_utf8_lossy(&refs).to_string();
+        assert!(advertised.contains(" agent=forge.1.2. "), "{advertised}");
+
+        assert_eq!(smart.client_agent(), None);
+        smart.parse_capabilities("side-band-64k agent=git/2.43.0 ofs-delta");
+        assert_eq!(smart.client_agent(), Some("git/2.43.0"));
+    }
 
-        let item1 = TreeItem::new(TreeItemMode::Blob, blob1.id, "hello.txt".to_string());
-        let item2 = TreeItem::new(TreeItemMode::Blob, blob2.id, "world.txt".to_string());
-        let tree = Tree::from_tree_items(vec![item1, item2]).unwrap();
+    #[tokio::test]
+    async fn test_session_id_capability() {
+        let mut smart =
+            SmartProtocol::new(TransportProtocol::Http, TestRepoAccess::new(), TestAuth);
+        let other = SmartProtocol::new(TransportProtocol::Http, TestRepoAccess::new(), TestAuth);
+        assert_ne!(smart.session_id, other.session_id);
+        let refs = smart.git_info_refs(ServiceType::UploadPack).await.unwrap();
+        let advertised = String::from_utf8_lossy(&refs).to_string();
+        assert!(advertised.contains(&format!(" session-id={}", smart.session_id)));
+
+        smart.set_session_id("req 42");
+        let refs = smart.git_info_refs(ServiceType::UploadPack).await.unwrap();
+        let advertised = String::from_utf8_lossy(&refs).to_string();
+        assert!(advertised.contains(" session-id=req.42"), "{advertised}");
+
+        assert_eq!(smart.client_session_id(), None);
+        smart.parse_capabilities("side-band-64k session-id=20260101T000000.000000Z-Hdeadbeef-P1");
+        assert_eq!(
+            smart.client_session_id(),
+            Some("20260101T000000.000000Z-Hdeadbeef-P1")
+        );
+        let _span = smart.session_span().entered();
+    }
 
-        let author = Signature::new(
-            SignatureType::Author,
-            "tester".to_string(),
-            "tester@example.com".to_string(),
+    #[test]
+    fn test_parse_capabilities() {
+        let mut smart =
+            SmartProtocol::new(TransportProtocol::Http, TestRepoAccess::new(), TestAuth);
+        let tokens = "multi_ack_detailed side-band-64k symref=HEAD:refs/heads/main \
+            agent=git/2.43.0 object-format=sha1 filter=blob:none x-shard=3 wait-for-done";
+        smart.parse_capabilities(tokens);
+        assert_eq!(
+            smart.capabilities[2],
+            Capability::Symref("HEAD:refs/heads/main".to_string())
         );
-        let committer = Signature::new(
-            SignatureType::Committer,
-            "tester".to_string(),
-            "tester@example.com".to_string(),
+        assert_eq!(
+            smart.capabilities[3],
+            Capability::Agent("git/2.43.0".to_string())
         );
-        let commit = Commit::new(author, committer, tree.id, vec![], "init commit");
+        assert_eq!(smart.capabilities[6].name(), "x-shard");
+        assert_eq!(smart.capabilities[6].value(), Some("3"));
+        assert_eq!(smart.capabilities[5].value(), Some("blob:none"));
+        assert_eq!(
+            smart.capabilities[7],
+            Capability::Unknown("wait-for-done".to_string())
+        );
+        assert_eq!(smart.capabilities[7].value(), None);
 
-        // Encode pack bytes via PackEncoder
-        let (pack_tx, mut pack_rx) = mpsc::channel(1024);
-        let (entry_tx, entry_rx) = mpsc::channel(1024);
-        let mut encoder = PackEncoder::new(4, 10, pack_tx);
+        // Every token is kept as it was sent
+        let round_trip: Vec<String> = smart.capabilities.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            round_trip.join(" "),
+            tokens.split_whitespace().collect::<Vec<_>>().join(" ")
+        );
+    }
 
-        tokio::spawn(async move {
-            if let Err(e) = encoder.encode(entry_rx).await {
-                panic!("Failed to encode pack: {}", e);
-            }
+    #[tokio::test]
+    async fn test_custom_capabilities() {
+        let mut smart =
+            SmartProtocol::new(TransportProtocol::Http, TestRepoAccess::new(), TestAuth);
+        smart.add_capability(
+            ServiceType::UploadPack,
+            Capability::Unknown("x-review-refs".to_string()),
+        );
+        let upload = smart.git_info_refs(ServiceType::UploadPack).await.unwrap();
+        assert!(String::from_utf8_lossy(&upload).contains(" x-review-refs"));
+        let receive = smart.git_info_refs(ServiceType::ReceivePack).await.unwrap();
+        assert!(!String::from_utf8_lossy(&receive).contains("x-review-refs"));
+
+        let unknown = Arc::new(Mutex::new(Vec::new()));
+        let seen = unknown.clone();
+        smart.set_unknown_capability_handler(move |token| {
+            seen.lock().unwrap().push(token.to_string());
         });
+        smart.parse_capabilities("side-band-64k x-review-refs ofs-delta x-shard=3");
+        assert_eq!(*unknown.lock().unwrap(), ["x-review-refs", "x-shard=3"]);
+    }
 
-        let commit_clone = commit.clone();
-        let tree_clone = tree.clone();
-        let blob1_clone = blob1.clone();
-        let blob2_clone = blob2.clone();
-        tokio::spawn(async move {
-            let _ = entry_tx.send(Entry::from(commit_clone)).await;
-            let _ = entry_tx.send(Entry::from(tree_clone)).await;
-            let _ = entry_tx.send(Entry::from(blob1_clone)).await;
-            let _ = entry_tx.send(Entry::from(blob2_clone)).await;
-            // sender drop indicates end
-        });
+    #[test]
+    fn test_error_replies() {
+        assert_eq!(
+            build_error_pkt_line("access denied\nask an admin\n"),
+            "0023ERR access denied ask an admin\n"
+        );
+        let reply = utils::build_error_advertisement(
+            TransportProtocol::Http,
+            "git-upload-pack",
+            "repository not found",
+        );
+        assert_eq!(
+            reply,
+            "001e# service=git-upload-pack\n0000001dERR repository not found\n"
+        );
+        let reply =
+            utils::build_error_advertisement(TransportProtocol::Ssh, "git-upload-pack", "denied");
+        assert_eq!(reply, "000fERR denied\n");
+
+        let (body, content_type) =
+            super::super::http::error_response("git-receive-pack", false, "push rejected");
+        assert_eq!(body, b"0016ERR push rejected\n");
+        assert_eq!(content_type, "application/x-git-receive-pack-result");
+        let (_, content_type) = super::super::http::error_response("git-receive-pack", true, "x");
+        assert_eq!(content_type, "application/x-git-receive-pack-advertisement");
+    }
 
-        let mut pack_bytes: Vec<u8> = Vec::new();
-        while let Some(chunk) = pack_rx.recv().await {
-            pack_bytes.extend_from_slice(&chunk);
+    #[tokio::test]
+    async fn test_malformed_requests() {
+        let mut smart =
+            SmartProtocol::new(TransportProtocol::Http, MemoryRepository::new(), TestAuth);
+        for request in [&b"0032want 1234"[..], b"00zzwant 1234\n0000", b"0003"] {
+            let err = smart
+                .git_upload_pack(Bytes::from_static(request))
+                .await
+                .err()
+                .unwrap();
+            assert!(matches!(err, ProtocolError::InvalidRequest(_)), "{err}");
         }
 
-        // Prepare protocol and command
-        let repo_access = TestRepoAccess::new();
-        let auth = TestAuth;
-        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo_access.clone(), auth);
-        smart.command_list.push(RefCommand::new(
-            ZERO_ID.to_string(),
-            commit.id.to_string(),
-            "refs/heads/main".to_string(),
-        ));
+        let mut smart =
+            SmartProtocol::new(TransportProtocol::Http, MemoryRepository::new(), TestAuth);
+        let mut request: ProtocolStream = Box::pin(futures::stream::iter([
+            Ok(Bytes::from_static(b"0068")),
+            Ok(Bytes::from_static(b"0000000000000000")),
+        ]));
+        let err = smart
+            .read_receive_pack_commands(&mut request)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("truncated pkt-line"), "{err}");
+        let mut request: ProtocolStream = Box::pin(futures::stream::once(async {
+            Ok(Bytes::from_static(b"ffff"))
+        }));
+        let err = smart
+            .read_receive_pack_commands(&mut request)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("exceeds the maximum"), "{err}");
+        assert!(
+            smart
+                .parse_receive_pack_commands(Bytes::from_static(b"00x0"))
+                .is_err()
+        );
+    }
 
-        // Create request stream
-        let request_stream = Box::pin(futures::stream::once(async { Ok(Bytes::from(pack_bytes)) }));
+    #[tokio::test]
+    async fn test_hidden_refs() {
+        let repo = MemoryRepository::new();
+        let main = SHA1::from_str(&"1".repeat(40)).unwrap();
+        let pull = SHA1::from_str(&"2".repeat(40)).unwrap();
+        repo.set_ref("refs/heads/main", main);
+        repo.set_ref("refs/pull/1/head", pull);
+        repo.set_ref("refs/internal/ci", main);
+        let mut hidden = HiddenRefs::new();
+        hidden.hide("refs/pull/*/head");
+        hidden.hide_for(ServiceType::ReceivePack, "refs/internal");
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo, TestAuth);
+        smart.set_hidden_refs(hidden);
+
+        let advertised = |service| {
+            let smart = &smart;
+            async move {
+                let refs = smart.git_info_refs(service).await.unwrap();
+                String::from_utf8_lossy(&refs).to_string()
+            }
+        };
+        let upload = advertised(ServiceType::UploadPack).await;
+        assert!(upload.contains("refs/internal/ci") && !upload.contains("refs/pull/1/head"));
+        let receive = advertised(ServiceType::ReceivePack).await;
+        assert!(!receive.contains("refs/internal/ci") && !receive.contains("refs/pull/1/head"));
+
+        // Hidden tips can't be wanted
+        let mut request = BytesMut::new();
+        add_pkt_line_string(&mut request, format!("want {pull} side-band-64k\n"));
+        add_pkt_line_string(&mut request, "done\n".to_string());
+        let reply = upload_pack_reply(&mut smart, request).await;
+        assert!(reply.ends_with(&format!("ERR upload-pack: not our ref {pull}\n")));
+
+        // Nor pushed to
+        let mut line = Bytes::from(format!("{ZERO_ID} {main} refs/internal/ci"));
+        let command = smart.parse_ref_command(&mut line);
+        assert_eq!(command.error_code.as_deref(), Some("hidden-ref"));
+    }
 
-        // Execute receive-pack
-        let result_bytes = smart
-            .git_receive_pack_stream(request_stream)
-            .await
-            .expect("receive-pack should succeed");
+    /// Shows the branches, and the refs under `refs/users/<user>/` to their user.
+    struct UserRefs;
 
-        // Verify pkt-lines
-        let mut out = result_bytes.clone();
-        let (_c1, l1) = utils::read_pkt_line(&mut out);
-        assert_eq!(String::from_utf8(l1.to_vec()).unwrap(), "unpack ok\n");
+    #[async_trait]
+    impl RefFilter for UserRefs {
+        async fn visibility(&self, ref_name: &str, ctx: &RefFilterContext<'_>) -> Visibility {
+            let own = ctx
+                .user
+                .is_some_and(|user| ref_name.starts_with(&format!("refs/users/{user}/")));
+            if own || ref_name.starts_with("refs/heads/") {
+                Visibility::Visible
+            } else {
+                Visibility::Hidden
+            }
+        }
+    }
 
-        let (_c2, l2) = utils::read_pkt_line(&mut out);
+    #[tokio::test]
+    async fn test_ref_filter() {
+        let repo = MemoryRepository::new();
+        let main = SHA1::from_str(&"1".repeat(40)).unwrap();
+        let alice = SHA1::from_str(&"2".repeat(40)).unwrap();
+        let bob = SHA1::from_str(&"3".repeat(40)).unwrap();
+        repo.set_ref("refs/heads/main", main);
+        repo.set_ref("refs/users/alice/wip", alice);
+        repo.set_ref("refs/users/bob/wip", bob);
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo.clone(), TestAuth);
+        smart.set_ref_filter(Arc::new(UserRefs));
+
+        let refs = smart.git_info_refs(ServiceType::UploadPack).await.unwrap();
+        let advertised = String::from_utf8_lossy(&refs).to_string();
+        assert!(advertised.contains("refs/heads/main") && !advertised.contains("refs/users/"));
+
+        smart.set_pusher("alice");
+        let refs = smart.git_info_refs(ServiceType::UploadPack).await.unwrap();
+        let advertised = String::from_utf8_lossy(&refs).to_string();
+        assert!(advertised.contains("refs/users/alice/wip"));
+        assert!(!advertised.contains("refs/users/bob/wip"));
+
+        // Alice can't fetch the refs of Bob
+        let mut request = BytesMut::new();
+        add_pkt_line_string(&mut request, format!("want {bob} side-band-64k\n"));
+        add_pkt_line_string(&mut request, "done\n".to_string());
+        let reply = upload_pack_reply(&mut smart, request).await;
+        assert!(reply.ends_with(&format!("ERR upload-pack: not our ref {bob}\n")));
+
+        // Nor delete them
+        for (ref_name, id) in [("refs/users/alice/wip", alice), ("refs/users/bob/wip", bob)] {
+            smart.command_list.push(RefCommand::new(
+                id.to_string(),
+                ZERO_ID.to_string(),
+                ref_name.to_string(),
+            ));
+        }
+        let request_stream = Box::pin(futures::stream::empty());
+        let out = smart.git_receive_pack_stream(request_stream).await.unwrap();
+        let report = String::from_utf8_lossy(&out).to_string();
+        assert!(report.contains("ok refs/users/alice/wip"), "{report}");
+        assert!(report.contains("ng refs/users/bob/wip"), "{report}");
+        assert!(repo.get_ref("refs/users/bob/wip").is_some());
+    }
+
+    /// Lets only the maintainer write `refs/heads/main`, recording what it's asked.
+    #[derive(Default)]
+    struct MainForMaintainer {
+        asked: Mutex<Vec<(String, RefOperation)>>,
+    }
+
+    #[async_trait]
+    impl AuthorizationService for MainForMaintainer {
+        async fn authorize_ref_update(
+            &self,
+            user: Option<&str>,
+            ref_name: &str,
+            operation: RefOperation,
+        ) -> Result<(), ProtocolError> {
+            self.asked
+                .lock()
+                .unwrap()
+                .push((ref_name.to_string(), operation));
+            if ref_name == "refs/heads/main" && user != Some("maintainer") {
+                return Err(ProtocolError::auth_failed(
+                    user,
+                    &format!("{} may not write {ref_name}", user.unwrap_or("anonymous")),
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receive_pack_authorization() {
+        let repo = MemoryRepository::new();
+        let one = SHA1::from_str(&"1".repeat(40)).unwrap();
+        let two = SHA1::from_str(&"2".repeat(40)).unwrap();
+        repo.set_ref("refs/heads/main", one);
+        repo.set_ref("refs/heads/topic", one);
+        let authorization = Arc::new(MainForMaintainer::default());
+        let mut smart = SmartProtocol::new(TransportProtocol::Http, repo.clone(), TestAuth);
+        smart.set_authorization_service(authorization.clone());
+        smart.set_pusher("dev");
+
+        for (old, new, ref_name) in [
+            (one, ZERO_ID.to_string(), "refs/heads/main"),
+            (one, two.to_string(), "refs/heads/topic"),
+            (one, ZERO_ID.to_string(), "refs/heads/gone"),
+        ] {
+            smart
+                .command_list
+                .push(RefCommand::new(old.to_string(), new, ref_name.to_string()));
+        }
+        smart.command_list.push(RefCommand::new(
+            ZERO_ID.to_string(),
+            one.to_string(),
+            "refs/heads/new".to_string(),
+        ));
+        let request_stream = Box::pin(futures::stream::empty());
+        let out = smart.git_receive_pack_stream(request_stream).await.unwrap();
+        let report = String::from_utf8_lossy(&out).to_string();
+        assert!(
+            report.contains("ng refs/heads/main Unauthorized: dev may not write refs/heads/main"),
+            "{report}"
+        );
+        assert!(report.contains("ok refs/heads/new"), "{report}");
+        assert_eq!(repo.get_ref("refs/heads/main"), Some(one));
         assert_eq!(
-            String::from_utf8(l2.to_vec()).unwrap(),
-            "ok refs/heads/main"
+            *authorization.asked.lock().unwrap(),
+            [
+                ("refs/heads/main".to_string(), RefOperation::Delete),
+                ("refs/heads/topic".to_string(), RefOperation::Force),
+                ("refs/heads/gone".to_string(), RefOperation::Delete),
+                ("refs/heads/new".to_string(), RefOperation::Create),
+            ]
         );
+    }
 
-        let (c3, l3) = utils::read_pkt_line(&mut out);
-        assert_eq!(c3, 4);
-        assert!(l3.is_empty());
+    #[tokio::test]
+    async fn test_object_format_negotiation() {
+        let mut smart =
+            SmartProtocol::new(TransportProtocol::Http, TestRepoAccess::new(), TestAuth);
+        let refs = smart.git_info_refs(ServiceType::UploadPack).await.unwrap();
+        let advertised = String::from_utf8_lossy(&refs).to_string();
+        assert!(advertised.contains("object-format=sha1"));
+
+        let mut request = BytesMut::new();
+        add_pkt_line_string(
+            &mut request,
+            format!(
+                "want {} side-band-64k object-format=sha256\n",
+                "1".repeat(40)
+            ),
+        );
+        request.put(&PKT_LINE_END_MARKER[..]);
+        let result = smart.git_upload_pack(request.freeze()).await;
+        assert!(matches!(
+            result,
+            Err(ProtocolError::UnsupportedObjectFormat(_))
+        ));
 
-        // Verify side effects
-        assert_eq!(repo_access.updates_len(), 1);
-        assert!(repo_access.post_hook_called());
+        let mut smart =
+            SmartProtocol::new(TransportProtocol::Http, TestRepoAccess::new(), TestAuth);
+        let mut commands = BytesMut::new();
+        add_pkt_line_string(
+            &mut commands,
+            format!(
+                "{ZERO_ID} {} refs/heads/main\0report-status object-format=sha256\n",
+                "1".repeat(40)
+            ),
+        );
+        commands.put(&PKT_LINE_END_MARKER[..]);
+        smart
+            .parse_receive_pack_commands(commands.freeze())
+            .unwrap();
+        assert_eq!(smart.command_list[0].ref_name, "refs/heads/main");
+
+        let request_stream = Box::pin(futures::stream::empty());
+        let result = smart.git_receive_pack_stream(request_stream).await;
+        assert!(matches!(
+            result,
+            Err(ProtocolError::UnsupportedObjectFormat(_))
+        ));
     }
 }
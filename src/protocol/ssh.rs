@@ -3,8 +3,33 @@
 /// This module provides SSH-specific handling for Git smart protocol operations.
 /// It's a thin wrapper around the core GitProtocol that handles SSH command
 /// execution and data streaming.
+///
+/// Servers built on an SSH library parse the `exec` request with [`GitSshCommand`] and run it
+/// with [`serve_git_command`] over the channel; the `russh` feature does both on the session
+/// channels of a russh server.
+use bytes::{BufMut, BytesMut};
+use futures::StreamExt;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use super::codec::PktLineReader;
 use super::core::{AuthenticationService, GitProtocol, RepositoryAccess};
-use super::types::{ProtocolError, ProtocolStream};
+use super::smart::SmartProtocol;
+use super::types::{
+    PKT_LINE_END_MARKER, ProtocolError, ProtocolStream, ProtocolVersion, ServiceType, SideBand,
+    ZERO_ID,
+};
+use super::upload_archive::ArchiveRequest;
+use super::utils::{
+    MAX_PKT_LINE_LENGTH, add_pkt_line_parts, add_pkt_line_string, side_band_limit,
+    side_band_packets,
+};
+
+#[cfg(feature = "russh")]
+pub mod russh;
+
+/// Environment variable carrying the protocol version hints of git clients, which send it with
+/// an SSH `env` request when the server accepts it
+pub const GIT_PROTOCOL_ENV: &str = "GIT_PROTOCOL";
 
 /// SSH Git protocol handler
 pub struct SshGitHandler<R: RepositoryAccess, A: AuthenticationService> {
@@ -29,6 +54,12 @@ impl<R: RepositoryAccess, A: AuthenticationService> SshGitHandler<R, A> {
         self.protocol.authenticate_ssh(username, public_key).await
     }
 
+    /// Select the protocol version from the [`GIT_PROTOCOL_ENV`] variable the client set, call
+    /// before running Git commands
+    pub fn negotiate_protocol_version(&mut self, git_protocol: Option<&str>) -> ProtocolVersion {
+        self.protocol.negotiate_protocol_version(git_protocol)
+    }
+
     /// Handle git-upload-pack command (for clone/fetch)
     pub async fn handle_upload_pack(
         &mut self,
@@ -41,7 +72,10 @@ impl<R: RepositoryAccess, A: AuthenticationService> SshGitHandler<R, A> {
     pub async fn handle_receive_pack(
         &mut self,
         request_stream: ProtocolStream,
-    ) -> Result<ProtocolStream, ProtocolError> {
+    ) -> Result<ProtocolStream, ProtocolError>
+    where
+        R: 'static,
+    {
         self.protocol.receive_pack(request_stream).await
     }
 
@@ -67,10 +101,505 @@ pub fn parse_ssh_command(command_line: &str) -> Option<(String, Vec<String>)> {
 
 /// Check if command is a valid Git SSH command
 pub fn is_git_ssh_command(command: &str) -> bool {
-    matches!(command, "git-upload-pack" | "git-receive-pack")
+    matches!(
+        command,
+        "git-upload-pack" | "git-receive-pack" | "git-upload-archive"
+    )
 }
 
 /// Extract repository path from SSH command arguments
 pub fn extract_repo_path_from_args(args: &[String]) -> Option<&str> {
     args.first().map(|s| s.as_str())
 }
+
+/// A git command of an SSH `exec` request, e.g. `git-upload-pack '/org/project.git'`
+#[derive(Debug, Clone, PartialEq)]
+pub struct GitSshCommand {
+    pub service: ServiceType,
+    /// The repository argument as sent by the client, e.g. `/org/project.git` or `~/project`
+    pub repo_path: String,
+}
+
+impl GitSshCommand {
+    /// Parse the command line of an `exec` request, in which git quotes the path with single
+    /// quotes, escaping them as `'\''`; `git upload-pack` spellings and paths quoted by other
+    /// clients with double quotes, or not at all, are accepted as well
+    pub fn parse(command_line: &str) -> Result<Self, ProtocolError> {
+        let command_line = command_line.trim();
+        let (command, rest) = command_line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| ProtocolError::invalid_request("Missing repository path"))?;
+        let (command, rest) = match command {
+            "git" => rest
+                .trim_start()
+                .split_once(char::is_whitespace)
+                .map(|(sub, rest)| (format!("git-{sub}"), rest))
+                .ok_or_else(|| ProtocolError::invalid_request("Missing repository path"))?,
+            command => (command.to_string(), rest),
+        };
+        let service: ServiceType = command.parse()?;
+        let repo_path = unquote(rest.trim())
+            .filter(|path| !path.is_empty())
+            .ok_or_else(|| {
+                ProtocolError::invalid_request(&format!("Bad repository path: {}", rest.trim()))
+            })?;
+        Ok(Self { service, repo_path })
+    }
+
+    /// The command line of the `exec` request, as git sends it.
+    pub fn to_command_line(&self) -> String {
+        format!(
+            "{} '{}'",
+            self.service,
+            self.repo_path.replace('\'', "'\\''")
+        )
+    }
+}
+
+/// The single shell word `arg`, `None` if it is badly quoted or more than one word
+fn unquote(arg: &str) -> Option<String> {
+    let mut word = String::new();
+    let mut chars = arg.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => loop {
+                match chars.next()? {
+                    '\'' => break,
+                    c => word.push(c),
+                }
+            },
+            '"' => loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => word.push(chars.next()?),
+                    c => word.push(c),
+                }
+            },
+            '\\' => word.push(chars.next()?),
+            c if c.is_whitespace() => return None,
+            c => word.push(c),
+        }
+    }
+    Some(word)
+}
+
+/// Serve `service` over the stdin and stdout of an SSH channel, or a `git://` connection, from
+/// the refs advertisement to the last packet of the reply
+///
+/// `input` is what the client writes to the channel, e.g. a `ReaderStream` of an `AsyncRead`
+/// with its errors mapped.
+///
+/// The upload-pack negotiation runs in rounds on the connection, the pack following once the
+/// client is done, and receive-pack reads the commands and their pack. Both frame the pack or
+/// the report in the negotiated side-band. A client with nothing to fetch or push only sends a
+/// flush-pkt, which ends the session. In protocol version 2, upload-pack advertises its
+/// capabilities and serves commands until the client sends a flush-pkt or closes the
+/// connection. upload-archive advertises nothing: it reads the arguments, then sends the
+/// archive in a side-band after its `ACK`. Errors answered to the client, in an `ERR` pkt-line
+/// or the `NACK` of upload-archive, are returned as well, for the server to exit with a failure
+/// status.
+pub async fn serve_git_command<R, A, O>(
+    smart: &mut SmartProtocol<R, A>,
+    service: ServiceType,
+    input: ProtocolStream,
+    mut output: O,
+) -> Result<(), ProtocolError>
+where
+    R: RepositoryAccess + 'static,
+    A: AuthenticationService,
+    O: AsyncWrite + Unpin,
+{
+    if service != ServiceType::UploadArchive {
+        let advertisement = smart.git_info_refs(service).await?;
+        output.write_all(&advertisement).await?;
+    }
+    match service {
+        ServiceType::UploadPack if smart.protocol_version == ProtocolVersion::V2 => {
+            serve_v2_commands(smart, input, &mut output).await?
+        }
+        ServiceType::UploadPack => serve_upload_pack(smart, input, &mut output).await?,
+        ServiceType::ReceivePack => serve_receive_pack(smart, input, &mut output).await?,
+        ServiceType::UploadArchive => serve_upload_archive(smart, input, &mut output).await?,
+    }
+    output.flush().await?;
+    Ok(())
+}
+
+async fn serve_upload_pack<R, A, O>(
+    smart: &mut SmartProtocol<R, A>,
+    input: ProtocolStream,
+    output: &mut O,
+) -> Result<(), ProtocolError>
+where
+    R: RepositoryAccess,
+    A: AuthenticationService,
+    O: AsyncWrite + Unpin,
+{
+    let mut request = PktLineReader::new(input);
+    if request.fill_buf(4).await?.starts_with(PKT_LINE_END_MARKER) {
+        return Ok(());
+    }
+    loop {
+        let (mut pack, acks) = smart.git_upload_pack_round(&mut request).await?;
+        output.write_all(&acks).await?;
+        if smart.negotiation.done {
+            let side_band = side_band_limit(&smart.capabilities);
+            while let Some(chunk) = pack.next().await {
+                write_side_band(output, &chunk, side_band).await?;
+            }
+            return end_side_band(output, side_band).await;
+        }
+        // The end of a round, unless the wants were refused with an `ERR` reply
+        if let Some(reply) = pack.next().await {
+            output.write_all(&reply).await?;
+            let message = String::from_utf8_lossy(reply.get(8..).unwrap_or_default());
+            return Err(ProtocolError::PolicyRejected(
+                message.trim_end().to_string(),
+            ));
+        }
+    }
+}
+
+async fn serve_v2_commands<R, A, O>(
+    smart: &mut SmartProtocol<R, A>,
+    input: ProtocolStream,
+    output: &mut O,
+) -> Result<(), ProtocolError>
+where
+    R: RepositoryAccess,
+    A: AuthenticationService,
+    O: AsyncWrite + Unpin,
+{
+    let mut request = PktLineReader::new(input);
+    loop {
+        let next = request.fill_buf(4).await?;
+        if next.is_empty() || next.starts_with(PKT_LINE_END_MARKER) {
+            return Ok(());
+        }
+        let mut reply = smart.git_v2_command(&mut request).await?;
+        while let Some(chunk) = reply.next().await {
+            output.write_all(&chunk?).await?;
+        }
+        output.flush().await?;
+    }
+}
+
+async fn serve_receive_pack<R, A, O>(
+    smart: &mut SmartProtocol<R, A>,
+    mut input: ProtocolStream,
+    output: &mut O,
+) -> Result<(), ProtocolError>
+where
+    R: RepositoryAccess + 'static,
+    A: AuthenticationService,
+    O: AsyncWrite + Unpin,
+{
+    let buffered = smart.read_receive_pack_commands(&mut input).await?.freeze();
+    if smart.command_list.is_empty() {
+        return Ok(());
+    }
+    // Only deletions come without a pack, the client then waits for the report
+    let pack: ProtocolStream = if smart.command_list.iter().all(|c| c.new_hash == ZERO_ID) {
+        Box::pin(futures::stream::empty())
+    } else {
+        Box::pin(futures::stream::once(async { Ok(buffered) }).chain(input))
+    };
+    let mut report = smart.git_receive_pack_incremental(pack).await?;
+    while let Some(chunk) = report.next().await {
+        output.write_all(&chunk?).await?;
+    }
+    Ok(())
+}
+
+async fn serve_upload_archive<R, A, O>(
+    smart: &mut SmartProtocol<R, A>,
+    input: ProtocolStream,
+    output: &mut O,
+) -> Result<(), ProtocolError>
+where
+    R: RepositoryAccess + 'static,
+    A: AuthenticationService,
+    O: AsyncWrite + Unpin,
+{
+    let mut request = PktLineReader::new(input);
+    let archive = match ArchiveRequest::read(&mut request).await {
+        Ok(request) => smart.git_upload_archive(&request).await,
+        Err(e) => Err(e),
+    };
+    let mut reply = BytesMut::new();
+    let mut archive = match archive {
+        Ok(archive) => archive,
+        Err(e) => {
+            add_pkt_line_string(&mut reply, format!("NACK {}\n", e.client_message()));
+            output.write_all(&reply).await?;
+            return Err(e);
+        }
+    };
+    add_pkt_line_string(&mut reply, "ACK\n".to_string());
+    reply.put(&PKT_LINE_END_MARKER[..]);
+    output.write_all(&reply).await?;
+
+    // The archive always goes in a side-band, failures in its band 3
+    let side_band = Some(MAX_PKT_LINE_LENGTH - 5);
+    while let Some(chunk) = archive.next().await {
+        match chunk {
+            Ok(chunk) => write_side_band(output, &chunk, side_band).await?,
+            Err(e) => {
+                let mut error = BytesMut::new();
+                let message = format!("fatal: {}\n", e.client_message());
+                add_pkt_line_parts(
+                    &mut error,
+                    &[&[SideBand::Error.value()], message.as_bytes()],
+                );
+                output.write_all(&error).await?;
+                end_side_band(output, side_band).await?;
+                return Err(e);
+            }
+        }
+    }
+    end_side_band(output, side_band).await
+}
+
+/// Write `data` in band 1 of the side-band of payloads of at most `side_band` bytes, or as is
+async fn write_side_band<O: AsyncWrite + Unpin>(
+    output: &mut O,
+    data: &[u8],
+    side_band: Option<usize>,
+) -> Result<(), ProtocolError> {
+    match side_band {
+        Some(limit) => output.write_all(&side_band_packets(data, limit)).await?,
+        None => output.write_all(data).await?,
+    }
+    Ok(())
+}
+
+/// End the side-band, if any, with a flush-pkt
+async fn end_side_band<O: AsyncWrite + Unpin>(
+    output: &mut O,
+    side_band: Option<usize>,
+) -> Result<(), ProtocolError> {
+    if side_band.is_some() {
+        output.write_all(PKT_LINE_END_MARKER).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::object::commit::Commit;
+    use crate::protocol::memory::MemoryRepository;
+    use crate::protocol::smart::tests::{TestAuth, build_test_pack};
+    use crate::protocol::types::{PKT_LINE_DELIM_MARKER, TransportProtocol};
+    use crate::protocol::utils::add_pkt_line_string;
+    use bytes::{BufMut, BytesMut};
+
+    #[test]
+    fn test_git_ssh_command() {
+        for (line, service, path) in [
+            (
+                "git-upload-pack '/org/project.git'",
+                ServiceType::UploadPack,
+                "/org/project.git",
+            ),
+            (
+                "git-receive-pack 'it'\\''s.git'",
+                ServiceType::ReceivePack,
+                "it's.git",
+            ),
+            (
+                "git upload-pack \"~/my repo\"",
+                ServiceType::UploadPack,
+                "~/my repo",
+            ),
+            (
+                "git-upload-pack project.git\n",
+                ServiceType::UploadPack,
+                "project.git",
+            ),
+            (
+                "git-upload-archive '/org/project.git'",
+                ServiceType::UploadArchive,
+                "/org/project.git",
+            ),
+        ] {
+            let command = GitSshCommand::parse(line).unwrap();
+            assert_eq!(
+                (command.service, command.repo_path.as_str()),
+                (service, path)
+            );
+            let command_line = command.to_command_line();
+            assert_eq!(GitSshCommand::parse(&command_line).unwrap(), command);
+        }
+        for line in [
+            "git-upload-pack",
+            "git-upload-pack ''",
+            "git-upload-pack 'a' 'b'",
+            "git-upload-pack 'unterminated",
+            "rm -rf /",
+        ] {
+            assert!(GitSshCommand::parse(line).is_err(), "{line}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve_git_command() {
+        let repo = MemoryRepository::new();
+        let (commit, pack) = build_test_pack().await;
+        repo.store_pack_data(&pack).await.unwrap();
+        let tip = Commit::new(
+            commit.author.clone(),
+            commit.committer.clone(),
+            commit.tree_id,
+            vec![commit.id],
+            "second",
+        );
+        repo.insert_object(&tip).unwrap();
+        repo.set_ref("refs/heads/main", tip.id);
+        let serve = |service, request: BytesMut| {
+            let repo = repo.clone();
+            async move {
+                let mut smart = SmartProtocol::new(TransportProtocol::Ssh, repo, TestAuth);
+                let advertisement = smart.git_info_refs(service).await.unwrap();
+                let input: ProtocolStream = Box::pin(
+                    futures::stream::once(async { Ok(request.freeze()) })
+                        .chain(futures::stream::pending()),
+                );
+                let mut output = Vec::new();
+                let result = serve_git_command(&mut smart, service, input, &mut output).await;
+                assert!(output.starts_with(&advertisement));
+                (result, output.split_off(advertisement.len()))
+            }
+        };
+
+        // Two rounds of a fetch on the connection, the wants sent once
+        let unknown = "4".repeat(40);
+        let mut request = BytesMut::new();
+        let caps = "multi_ack_detailed side-band-64k";
+        add_pkt_line_string(&mut request, format!("want {} {caps}\n", tip.id));
+        request.put(&PKT_LINE_END_MARKER[..]);
+        add_pkt_line_string(&mut request, format!("have {unknown}\n"));
+        request.put(&PKT_LINE_END_MARKER[..]);
+        add_pkt_line_string(&mut request, format!("have {}\n", commit.id));
+        add_pkt_line_string(&mut request, "done\n".to_string());
+        let (result, reply) = serve(ServiceType::UploadPack, request).await;
+        result.unwrap();
+        let acks = format!(
+            "0008NAK\n0038ACK {0} common\n0031ACK {0}\n0023\x02Enumerating objects: 1, done.\n",
+            commit.id
+        );
+        assert!(reply.starts_with(acks.as_bytes()));
+        assert_eq!(&reply[acks.len() + 4..acks.len() + 9], b"\x01PACK");
+        assert!(reply.ends_with(PKT_LINE_END_MARKER));
+
+        // Up to date, nothing to fetch
+        let request = BytesMut::from(&PKT_LINE_END_MARKER[..]);
+        let (result, reply) = serve(ServiceType::UploadPack, request).await;
+        result.unwrap();
+        assert!(reply.is_empty());
+        let mut request = BytesMut::new();
+        add_pkt_line_string(&mut request, format!("want {unknown}\n"));
+        request.put(&PKT_LINE_END_MARKER[..]);
+        add_pkt_line_string(&mut request, "done\n".to_string());
+        let (result, reply) = serve(ServiceType::UploadPack, request).await;
+        assert!(matches!(result, Err(ProtocolError::PolicyRejected(_))));
+        assert!(reply.starts_with(b"004aERR upload-pack: not our ref"));
+
+        // A deletion has no pack, the client waits for the report without closing its end
+        let mut request = BytesMut::new();
+        add_pkt_line_string(
+            &mut request,
+            format!("{} {ZERO_ID} refs/heads/main\0report-status\n", tip.id),
+        );
+        request.put(&PKT_LINE_END_MARKER[..]);
+        let (result, reply) = serve(ServiceType::ReceivePack, request).await;
+        result.unwrap();
+        assert_eq!(reply, b"000eunpack ok\n0016ok refs/heads/main0000");
+    }
+
+    #[tokio::test]
+    async fn test_serve_git_command_v2() {
+        let repo = MemoryRepository::new();
+        let (commit, pack) = build_test_pack().await;
+        repo.store_pack_data(&pack).await.unwrap();
+        repo.set_ref("refs/heads/main", commit.id);
+        let mut smart = SmartProtocol::new(TransportProtocol::Ssh, repo, TestAuth);
+        smart.negotiate_protocol_version(Some("version=2"));
+
+        // ls-refs and a fetch of the branch by name in one session, ended by a flush-pkt
+        let mut request = BytesMut::new();
+        for (command, arguments) in [
+            ("ls-refs", &["ref-prefix refs/heads/"][..]),
+            ("fetch", &["want-ref refs/heads/main", "no-progress", "done"]),
+        ] {
+            add_pkt_line_string(&mut request, format!("command={command}\n"));
+            request.put(&PKT_LINE_DELIM_MARKER[..]);
+            for argument in arguments {
+                add_pkt_line_string(&mut request, format!("{argument}\n"));
+            }
+            request.put(&PKT_LINE_END_MARKER[..]);
+        }
+        request.put(&PKT_LINE_END_MARKER[..]);
+        let input: ProtocolStream = Box::pin(
+            futures::stream::once(async { Ok(request.freeze()) }).chain(futures::stream::pending()),
+        );
+        let mut output = Vec::new();
+        serve_git_command(&mut smart, ServiceType::UploadPack, input, &mut output)
+            .await
+            .unwrap();
+
+        let advertisement = smart.git_v2_capabilities();
+        assert!(output.starts_with(&advertisement));
+        let sections = format!(
+            "003d{0} refs/heads/main\n0000\
+             0010wanted-refs\n003d{0} refs/heads/main\n0001000dpackfile\n",
+            commit.id
+        );
+        let reply = &output[advertisement.len()..];
+        assert!(reply.starts_with(sections.as_bytes()));
+        assert_eq!(&reply[sections.len() + 4..sections.len() + 9], b"\x01PACK");
+        assert!(reply.ends_with(PKT_LINE_END_MARKER));
+    }
+
+    #[tokio::test]
+    async fn test_serve_upload_archive() {
+        let repo = MemoryRepository::new();
+        let (commit, pack) = build_test_pack().await;
+        repo.store_pack_data(&pack).await.unwrap();
+        repo.set_ref("refs/heads/main", commit.id);
+        let serve = |tree_ish: String, allow_unreachable| {
+            let repo = repo.clone();
+            async move {
+                let mut smart = SmartProtocol::new(TransportProtocol::Ssh, repo, TestAuth);
+                smart.allow_unreachable_archives = allow_unreachable;
+                let mut request = BytesMut::new();
+                add_pkt_line_string(&mut request, "argument --format=zip\n".to_string());
+                add_pkt_line_string(&mut request, format!("argument {tree_ish}\n"));
+                request.put(&PKT_LINE_END_MARKER[..]);
+                let input: ProtocolStream = Box::pin(futures::stream::iter([Ok(request.freeze())]));
+                let mut output = Vec::new();
+                let service = ServiceType::UploadArchive;
+                let result = serve_git_command(&mut smart, service, input, &mut output).await;
+                (result, output)
+            }
+        };
+
+        // Nothing is advertised, and only refs resolve unless unreachable objects are allowed
+        let (result, reply) = serve("v9".to_string(), false).await;
+        assert!(matches!(result, Err(ProtocolError::InvalidRequest(_))));
+        assert_eq!(reply, b"002aNACK Invalid request: no such ref: v9\n");
+        let (_, reply) = serve(commit.id.to_string(), false).await;
+        assert!(String::from_utf8_lossy(&reply).contains("no such ref"));
+
+        // A zip, in band 1 after the ACK
+        for (tree_ish, allow_unreachable) in
+            [("main".to_string(), false), (commit.id.to_string(), true)]
+        {
+            let (result, reply) = serve(tree_ish, allow_unreachable).await;
+            result.unwrap();
+            assert!(reply.starts_with(b"0008ACK\n0000"));
+            assert_eq!(&reply[16..19], b"\x01PK");
+            assert!(reply.ends_with(b"0000"));
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! Git over the session channels of a russh server, behind the `russh` feature.
+//!
+//! [`serve_channel`] runs the git command a client executes on a session channel, as
+//! `git clone ssh://` does: it takes the [`GIT_PROTOCOL_ENV`] variable and the `exec`
+//! request, parses the command into a [`GitSshCommand`], opens its repository with the
+//! callback of the host, and serves it with [`serve_git_command`] before reporting the exit
+//! status and closing the channel. A failure is written to the standard error of the client,
+//! which git prints, with an exit status of 128 like git's own commands.
+//!
+//! The host keeps to its [`Handler`](::russh::server::Handler): it authenticates the clients,
+//! spawns [`serve_channel`] on the channels it accepts in `channel_open_session`, and accepts
+//! their `env` and `exec` requests with `Session::channel_success`.
+use std::future::Future;
+
+use ::russh::server::Msg;
+use ::russh::{Channel, ChannelMsg, ChannelReadHalf};
+use bytes::Bytes;
+use tokio::io::AsyncWriteExt;
+
+use super::{GIT_PROTOCOL_ENV, GitSshCommand, serve_git_command};
+use crate::protocol::core::{AuthenticationService, RepositoryAccess};
+use crate::protocol::smart::SmartProtocol;
+use crate::protocol::types::{ProtocolError, ProtocolStream};
+
+/// The exit status of a failed git command.
+const FAILURE_STATUS: u32 = 128;
+
+/// Serve the git command executed on `channel`, see the [module documentation](self).
+///
+/// `open` returns the protocol serving the repository of the command, authorized for the
+/// authenticated user and configured by the host, or the error answered to the client, e.g.
+/// [`ProtocolError::RepositoryNotFound`]. A channel closed before its `exec` request is left
+/// alone.
+pub async fn serve_channel<R, A, F, Fut>(
+    mut channel: Channel<Msg>,
+    open: F,
+) -> Result<(), ::russh::Error>
+where
+    R: RepositoryAccess + 'static,
+    A: AuthenticationService,
+    F: FnOnce(GitSshCommand) -> Fut,
+    Fut: Future<Output = Result<SmartProtocol<R, A>, ProtocolError>>,
+{
+    let mut git_protocol = None;
+    let command = loop {
+        match channel.wait().await {
+            Some(ChannelMsg::SetEnv {
+                variable_name,
+                variable_value,
+                ..
+            }) if variable_name == GIT_PROTOCOL_ENV => git_protocol = Some(variable_value),
+            Some(ChannelMsg::Exec { command, .. }) => {
+                break String::from_utf8_lossy(&command).into_owned();
+            }
+            Some(ChannelMsg::Eof | ChannelMsg::Close) | None => return Ok(()),
+            Some(_) => {}
+        }
+    };
+
+    let (read_half, write_half) = channel.split();
+    let result = async {
+        let command = GitSshCommand::parse(&command)?;
+        let service = command.service;
+        let mut smart = open(command).await?;
+        smart.negotiate_protocol_version(git_protocol.as_deref());
+        serve_git_command(
+            &mut smart,
+            service,
+            channel_data(read_half),
+            write_half.make_writer(),
+        )
+        .await
+    }
+    .await;
+
+    let status = match result {
+        Ok(()) => 0,
+        Err(e) => {
+            tracing::warn!("git command `{command}` failed: {e}");
+            let message = format!("fatal: {}\n", e.client_message());
+            let mut stderr = write_half.make_writer_ext(Some(1));
+            stderr.write_all(message.as_bytes()).await?;
+            stderr.flush().await?;
+            FAILURE_STATUS
+        }
+    };
+    write_half.exit_status(status).await?;
+    write_half.eof().await?;
+    write_half.close().await
+}
+
+/// The data the client sends on the channel, up to its end-of-file.
+fn channel_data(read_half: ChannelReadHalf) -> ProtocolStream {
+    Box::pin(futures::stream::unfold(
+        read_half,
+        |mut read_half| async move {
+            loop {
+                match read_half.wait().await? {
+                    ChannelMsg::Data { data } => {
+                        return Some((Ok(Bytes::copy_from_slice(&data)), read_half));
+                    }
+                    ChannelMsg::Eof | ChannelMsg::Close => return None,
+                    _ => {}
+                }
+            }
+        },
+    ))
+}
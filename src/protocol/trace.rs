@@ -0,0 +1,97 @@
+//! Packet tracing, the equivalent of git's `GIT_TRACE_PACKET`.
+//!
+//! A [`PacketTracer`] set on [`SmartProtocol`](super::smart::SmartProtocol) receives every
+//! pkt-line it reads from the client and every pkt-line of the replies it builds, so
+//! interoperability problems with a given git version can be looked at without patching the
+//! crate. The pack data, sent through the side-band framing of the integrator, isn't traced.
+//! [`LogPacketTracer`] logs the packets through `tracing`, in the format of git's traces.
+use std::time::SystemTime;
+
+use super::utils::parse_pkt_length;
+
+/// Whether a traced packet was received or sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketDirection {
+    /// Read from the client
+    Inbound,
+    /// Sent to the client
+    Outbound,
+}
+
+/// Receives the traced packets, see the [module documentation](self).
+///
+/// Called on the request path for every packet, so implementations should be quick, e.g.
+/// append to a buffer or a log.
+pub trait PacketTracer: Send + Sync {
+    /// `packet` is the whole pkt-line, length prefix included, e.g. `0000` for a flush-pkt.
+    fn trace_packet(&self, direction: PacketDirection, packet: &[u8], timestamp: SystemTime);
+}
+
+/// Logs every packet at the `debug` level of the `git_packet` target, like
+/// `packet: git< want <id>` in git's traces.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogPacketTracer;
+
+impl PacketTracer for LogPacketTracer {
+    fn trace_packet(&self, direction: PacketDirection, packet: &[u8], _timestamp: SystemTime) {
+        let arrow = match direction {
+            PacketDirection::Inbound => '<',
+            PacketDirection::Outbound => '>',
+        };
+        let payload = match packet.get(4..) {
+            Some(payload) if !payload.is_empty() => payload.escape_ascii().to_string(),
+            _ => String::from_utf8_lossy(packet).into_owned(),
+        };
+        tracing::debug!(target: "git_packet", "packet: git{arrow} {payload}");
+    }
+}
+
+/// Trace each pkt-line of `bytes`, stopping at the first bytes that aren't one, e.g. a pack.
+pub(crate) fn trace_packets(tracer: &dyn PacketTracer, direction: PacketDirection, bytes: &[u8]) {
+    let timestamp = SystemTime::now();
+    let mut rest = bytes;
+    while rest.len() >= 4 {
+        let pkt_length = match parse_pkt_length(&rest[..4]) {
+            Ok(0..=2) => 4,
+            Ok(pkt_length) if pkt_length >= 4 && pkt_length <= rest.len() => pkt_length,
+            _ => break,
+        };
+        let (packet, next) = rest.split_at(pkt_length);
+        tracer.trace_packet(direction, packet, timestamp);
+        rest = next;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Records the traced packets.
+    #[derive(Default)]
+    pub(crate) struct RecordingTracer(pub Mutex<Vec<(PacketDirection, String)>>);
+
+    impl PacketTracer for RecordingTracer {
+        fn trace_packet(&self, direction: PacketDirection, packet: &[u8], _: SystemTime) {
+            let packet = String::from_utf8_lossy(packet).into_owned();
+            self.0.lock().unwrap().push((direction, packet));
+        }
+    }
+
+    #[test]
+    fn test_trace_packets() {
+        let tracer = RecordingTracer::default();
+        trace_packets(
+            &tracer,
+            PacketDirection::Outbound,
+            b"000eunpack ok\n00010000PACK\0\0\0\x02",
+        );
+        let packets = tracer.0.into_inner().unwrap();
+        let expected = ["000eunpack ok\n", "0001", "0000"];
+        assert_eq!(
+            packets,
+            expected.map(|packet| (PacketDirection::Outbound, packet.to_string()))
+        );
+    }
+}
@@ -1,8 +1,13 @@
 use bytes::Bytes;
 use futures::stream::Stream;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
 use std::fmt;
 use std::pin::Pin;
 use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::hash::SHA1;
 
 /// Type alias for protocol data streams to reduce nesting
 pub type ProtocolStream = Pin<Box<dyn Stream<Item = Result<Bytes, ProtocolError>> + Send>>;
@@ -22,17 +27,35 @@ pub enum ProtocolError {
     #[error("Invalid request: {0}")]
     InvalidRequest(String),
 
-    #[error("Unauthorized: {0}")]
-    Unauthorized(String),
+    /// The client isn't authenticated, or not allowed to do what it asked for; `user` is the
+    /// user it claimed to be, when known
+    #[error("Unauthorized: {reason}")]
+    AuthFailed {
+        user: Option<String>,
+        reason: String,
+    },
+
+    #[error("Rejected by policy: {0}")]
+    PolicyRejected(String),
+
+    #[error("Unsupported object format: {0}")]
+    UnsupportedObjectFormat(String),
 
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
-    #[error("Pack error: {0}")]
-    Pack(String),
+    /// A pack, or the object `object`, that can't be read, e.g. one sent by the client
+    #[error("Corrupt {}: {reason}", corrupt_part(.object))]
+    PackCorrupt {
+        object: Option<SHA1>,
+        reason: String,
+    },
 
     #[error("Internal error: {0}")]
     Internal(String),
+
+    #[error("Remote error: {0}")]
+    Remote(String),
 }
 
 impl ProtocolError {
@@ -48,8 +71,88 @@ impl ProtocolError {
         ProtocolError::InvalidRequest(msg.to_string())
     }
 
-    pub fn unauthorized(msg: &str) -> Self {
-        ProtocolError::Unauthorized(msg.to_string())
+    pub fn auth_failed(user: Option<&str>, reason: &str) -> Self {
+        ProtocolError::AuthFailed {
+            user: user.map(str::to_string),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// The pack can't be read, see [`ProtocolError::PackCorrupt`]
+    pub fn pack_corrupt(reason: impl fmt::Display) -> Self {
+        ProtocolError::PackCorrupt {
+            object: None,
+            reason: reason.to_string(),
+        }
+    }
+
+    /// The object `id` can't be parsed, see [`ProtocolError::PackCorrupt`]
+    pub fn corrupt_object(id: SHA1, reason: impl fmt::Display) -> Self {
+        ProtocolError::PackCorrupt {
+            object: Some(id),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Stable identifier of the error, independent of its message, e.g. for metrics.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProtocolError::InvalidService(_) => "invalid-service",
+            ProtocolError::RepositoryNotFound(_) => "repository-not-found",
+            ProtocolError::ObjectNotFound(_) => "object-not-found",
+            ProtocolError::InvalidRequest(_) => "invalid-request",
+            ProtocolError::AuthFailed { .. } => "auth-failed",
+            ProtocolError::PolicyRejected(_) => "policy-rejected",
+            ProtocolError::UnsupportedObjectFormat(_) => "unsupported-object-format",
+            ProtocolError::Io(_) => "io",
+            ProtocolError::PackCorrupt { .. } => "pack-corrupt",
+            ProtocolError::Internal(_) => "internal",
+            ProtocolError::Remote(_) => "remote",
+        }
+    }
+
+    /// The HTTP status answering a request that failed with this error.
+    pub fn http_status(&self) -> http::StatusCode {
+        use http::StatusCode;
+        match self {
+            ProtocolError::InvalidService(_) | ProtocolError::PolicyRejected(_) => {
+                StatusCode::FORBIDDEN
+            }
+            ProtocolError::RepositoryNotFound(_) | ProtocolError::ObjectNotFound(_) => {
+                StatusCode::NOT_FOUND
+            }
+            ProtocolError::InvalidRequest(_)
+            | ProtocolError::UnsupportedObjectFormat(_)
+            | ProtocolError::PackCorrupt { .. } => StatusCode::BAD_REQUEST,
+            ProtocolError::AuthFailed { .. } => StatusCode::UNAUTHORIZED,
+            ProtocolError::Io(_) | ProtocolError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ProtocolError::Remote(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// The message for the client, the error itself unless it is internal to the server,
+    /// whose details stay in the server logs.
+    pub fn client_message(&self) -> String {
+        match self {
+            ProtocolError::Io(_) | ProtocolError::Internal(_) => {
+                "internal server error".to_string()
+            }
+            _ => self.to_string(),
+        }
+    }
+
+    /// The `ERR` pkt-line reporting this error to the client, see
+    /// [`build_error_pkt_line`](super::utils::build_error_pkt_line).
+    pub fn to_err_pkt_line(&self) -> bytes::BytesMut {
+        super::utils::build_error_pkt_line(&self.client_message())
+    }
+}
+
+/// What [`ProtocolError::PackCorrupt`] says is corrupt
+fn corrupt_part(object: &Option<SHA1>) -> String {
+    match object {
+        Some(id) => format!("object {id}"),
+        None => "pack".to_string(),
     }
 }
 
@@ -63,11 +166,74 @@ pub enum TransportProtocol {
     Git,
 }
 
+/// Which objects upload-pack sends for the `want` lines of a fetch
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WantPolicy {
+    /// Only the tips of advertised refs, git's default
+    #[default]
+    Advertised,
+    /// Also the tips of hidden refs, like `uploadpack.allowTipSHA1InWant`
+    Tip,
+    /// Also commits and tags reachable from an advertised tip, like
+    /// `uploadpack.allowReachableSHA1InWant`
+    Reachable,
+    /// Any object, like `uploadpack.allowAnySHA1InWant`
+    Any,
+}
+
+impl WantPolicy {
+    /// The capabilities telling clients they may want unadvertised objects
+    pub fn capabilities(&self) -> Vec<Capability> {
+        match self {
+            WantPolicy::Advertised => vec![],
+            WantPolicy::Tip => vec![Capability::AllowTipSha1InWant],
+            WantPolicy::Reachable => vec![Capability::AllowReachableSha1InWant],
+            WantPolicy::Any => vec![
+                Capability::AllowTipSha1InWant,
+                Capability::AllowReachableSha1InWant,
+            ],
+        }
+    }
+}
+
+/// Version of the wire protocol, requested by clients through the `GIT_PROTOCOL` hints
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProtocolVersion {
+    /// The original protocol, used by clients which send no hint
+    #[default]
+    V0,
+    /// Version 0 announced by a `version 1` line
+    V1,
+    V2,
+}
+
+impl ProtocolVersion {
+    /// The version requested by transport hints: the `Git-Protocol` header over HTTP, the
+    /// `GIT_PROTOCOL` environment variable over SSH or the extra parameters of a `git://`
+    /// request. Each hint is a colon-separated list of `key=value` parameters; like git, the
+    /// highest `version=` wins and anything not understood is ignored.
+    pub fn from_hints<'a>(hints: impl IntoIterator<Item = &'a str>) -> Self {
+        hints
+            .into_iter()
+            .flat_map(|hint| hint.split(':'))
+            .filter_map(|param| match param.trim().strip_prefix("version=")? {
+                "0" => Some(ProtocolVersion::V0),
+                "1" => Some(ProtocolVersion::V1),
+                "2" => Some(ProtocolVersion::V2),
+                _ => None,
+            })
+            .max()
+            .unwrap_or_default()
+    }
+}
+
 /// Git service types for smart protocol
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum ServiceType {
     UploadPack,
     ReceivePack,
+    /// The server side of `git archive --remote`, over SSH and `git://` only
+    UploadArchive,
 }
 
 impl fmt::Display for ServiceType {
@@ -75,6 +241,7 @@ impl fmt::Display for ServiceType {
         match self {
             ServiceType::UploadPack => write!(f, "git-upload-pack"),
             ServiceType::ReceivePack => write!(f, "git-receive-pack"),
+            ServiceType::UploadArchive => write!(f, "git-upload-archive"),
         }
     }
 }
@@ -86,6 +253,7 @@ impl FromStr for ServiceType {
         match s {
             "git-upload-pack" => Ok(ServiceType::UploadPack),
             "git-receive-pack" => Ok(ServiceType::ReceivePack),
+            "git-upload-archive" => Ok(ServiceType::UploadArchive),
             _ => Err(ProtocolError::InvalidService(s.to_string())),
         }
     }
@@ -99,19 +267,21 @@ impl FromStr for ServiceType {
 /// - **Data transmission**: SideBand, SideBand64k - Multiplexed data streams via side-band formatter
 /// - **Status reporting**: ReportStatus, ReportStatusv2 - Push status feedback via protocol handlers
 /// - **Pack optimization**: OfsDelta, ThinPack, NoThin - Delta compression and efficient transmission
-/// - **Protocol control**: MultiAckDetailed, NoDone - ACK mechanism optimization for upload-pack
+/// - **Protocol control**: MultiAck, MultiAckDetailed, NoDone - ACK modes of the upload-pack
+///   negotiation, one round per request like smart HTTP or in turn on an SSH connection
 /// - **Push control**: Atomic, DeleteRefs, Quiet - Atomic operations and reference management
 /// - **Tag handling**: IncludeTag - Automatic tag inclusion for upload-pack
 /// - **Client identification**: Agent - Client/server identification in capability negotiation
+/// - **Special fetch**: AllowTipSha1InWant, AllowReachableSha1InWant - SHA1 validation in want processing
+/// - **Progress control**: NoProgress, Quiet - Progress output suppression
+/// - **Session management**: SessionId, ObjectFormat - Session ids of both sides for log
+///   correlation, and the hash algorithm both sides use
+/// - **Ref advertisement**: Symref - The branch `HEAD` points to
 ///
 /// ### Not yet implemented capabilities:
-/// - **Basic protocol**: MultiAck - Basic multi-ack support (only detailed version implemented)
 /// - **Shallow cloning**: Shallow, DeepenSince, DeepenNot, DeepenRelative - Depth control for shallow clones
-/// - **Progress control**: NoProgress - Progress output suppression
-/// - **Special fetch**: AllowTipSha1InWant, AllowReachableSha1InWant - SHA1 validation in want processing
 /// - **Security**: PushCert - Push certificate verification mechanism
-/// - **Extensions**: PushOptions, Filter, Symref - Extended parameter handling
-/// - **Session management**: SessionId, ObjectFormat - Session and format negotiation
+/// - **Extensions**: PushOptions, Filter - Extended parameter handling
 #[derive(Debug, Clone, PartialEq)]
 pub enum Capability {
     /// Multi-ack capability for upload-pack protocol
@@ -170,95 +340,120 @@ pub enum Capability {
     Symref(String),
     /// Agent capability for client/server identification
     Agent(String),
+    /// Machine-status capability (git-internal extension) for key=value ref status details
+    MachineStatus,
     /// Unknown capability for forward compatibility
     Unknown(String),
 }
 
 impl FromStr for Capability {
-    type Err = ();
+    type Err = Infallible;
 
+    /// Parse a capability token; tokens this crate doesn't know are kept as
+    /// [`Capability::Unknown`], so parsing never fails.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         // Parameterized capabilities
-        if let Some(rest) = s.strip_prefix("agent=") {
-            return Ok(Capability::Agent(rest.to_string()));
-        }
-        if let Some(rest) = s.strip_prefix("session-id=") {
-            return Ok(Capability::SessionId(rest.to_string()));
-        }
-        if let Some(rest) = s.strip_prefix("push-cert=") {
-            return Ok(Capability::PushCert(rest.to_string()));
-        }
-        if let Some(rest) = s.strip_prefix("object-format=") {
-            return Ok(Capability::ObjectFormat(rest.to_string()));
+        if let Some((name, value)) = s.split_once('=') {
+            let value = value.to_string();
+            return Ok(match name {
+                "agent" => Capability::Agent(value),
+                "session-id" => Capability::SessionId(value),
+                "push-cert" => Capability::PushCert(value),
+                "object-format" => Capability::ObjectFormat(value),
+                "filter" => Capability::Filter(value),
+                "symref" => Capability::Symref(value),
+                _ => Capability::Unknown(s.to_string()),
+            });
         }
-        if let Some(rest) = s.strip_prefix("filter=") {
-            return Ok(Capability::Filter(rest.to_string()));
-        }
-        if let Some(rest) = s.strip_prefix("symref=") {
-            return Ok(Capability::Symref(rest.to_string()));
+
+        Ok(match s {
+            "multi_ack" => Capability::MultiAck,
+            "multi_ack_detailed" => Capability::MultiAckDetailed,
+            "no-done" => Capability::NoDone,
+            "side-band" => Capability::SideBand,
+            "side-band-64k" => Capability::SideBand64k,
+            "report-status" => Capability::ReportStatus,
+            "report-status-v2" => Capability::ReportStatusv2,
+            "ofs-delta" => Capability::OfsDelta,
+            "deepen-since" => Capability::DeepenSince,
+            "deepen-not" => Capability::DeepenNot,
+            "deepen-relative" => Capability::DeepenRelative,
+            "thin-pack" => Capability::ThinPack,
+            "shallow" => Capability::Shallow,
+            "include-tag" => Capability::IncludeTag,
+            "delete-refs" => Capability::DeleteRefs,
+            "quiet" => Capability::Quiet,
+            "atomic" => Capability::Atomic,
+            "no-thin" => Capability::NoThin,
+            "no-progress" => Capability::NoProgress,
+            "allow-tip-sha1-in-want" => Capability::AllowTipSha1InWant,
+            "allow-reachable-sha1-in-want" => Capability::AllowReachableSha1InWant,
+            "push-options" => Capability::PushOptions,
+            "machine-status" => Capability::MachineStatus,
+            _ => Capability::Unknown(s.to_string()),
+        })
+    }
+}
+
+impl Capability {
+    /// The name of the capability, before the `=` of a value-carrying one
+    pub fn name(&self) -> &str {
+        match self {
+            Capability::MultiAck => "multi_ack",
+            Capability::MultiAckDetailed => "multi_ack_detailed",
+            Capability::NoDone => "no-done",
+            Capability::SideBand => "side-band",
+            Capability::SideBand64k => "side-band-64k",
+            Capability::ReportStatus => "report-status",
+            Capability::ReportStatusv2 => "report-status-v2",
+            Capability::OfsDelta => "ofs-delta",
+            Capability::DeepenSince => "deepen-since",
+            Capability::DeepenNot => "deepen-not",
+            Capability::DeepenRelative => "deepen-relative",
+            Capability::ThinPack => "thin-pack",
+            Capability::Shallow => "shallow",
+            Capability::IncludeTag => "include-tag",
+            Capability::DeleteRefs => "delete-refs",
+            Capability::Quiet => "quiet",
+            Capability::Atomic => "atomic",
+            Capability::NoThin => "no-thin",
+            Capability::NoProgress => "no-progress",
+            Capability::AllowTipSha1InWant => "allow-tip-sha1-in-want",
+            Capability::AllowReachableSha1InWant => "allow-reachable-sha1-in-want",
+            Capability::PushCert(_) => "push-cert",
+            Capability::PushOptions => "push-options",
+            Capability::ObjectFormat(_) => "object-format",
+            Capability::SessionId(_) => "session-id",
+            Capability::Filter(_) => "filter",
+            Capability::Symref(_) => "symref",
+            Capability::Agent(_) => "agent",
+            Capability::MachineStatus => "machine-status",
+            Capability::Unknown(token) => token.split_once('=').map_or(token, |(name, _)| name),
         }
+    }
 
-        match s {
-            "multi_ack" => Ok(Capability::MultiAck),
-            "multi_ack_detailed" => Ok(Capability::MultiAckDetailed),
-            "no-done" => Ok(Capability::NoDone),
-            "side-band" => Ok(Capability::SideBand),
-            "side-band-64k" => Ok(Capability::SideBand64k),
-            "report-status" => Ok(Capability::ReportStatus),
-            "report-status-v2" => Ok(Capability::ReportStatusv2),
-            "ofs-delta" => Ok(Capability::OfsDelta),
-            "deepen-since" => Ok(Capability::DeepenSince),
-            "deepen-not" => Ok(Capability::DeepenNot),
-            "deepen-relative" => Ok(Capability::DeepenRelative),
-            "thin-pack" => Ok(Capability::ThinPack),
-            "shallow" => Ok(Capability::Shallow),
-            "include-tag" => Ok(Capability::IncludeTag),
-            "delete-refs" => Ok(Capability::DeleteRefs),
-            "quiet" => Ok(Capability::Quiet),
-            "atomic" => Ok(Capability::Atomic),
-            "no-thin" => Ok(Capability::NoThin),
-            "no-progress" => Ok(Capability::NoProgress),
-            "allow-tip-sha1-in-want" => Ok(Capability::AllowTipSha1InWant),
-            "allow-reachable-sha1-in-want" => Ok(Capability::AllowReachableSha1InWant),
-            "push-options" => Ok(Capability::PushOptions),
-            _ => Ok(Capability::Unknown(s.to_string())),
+    /// The value after the `=`, e.g. `HEAD:refs/heads/main` of a `symref`
+    pub fn value(&self) -> Option<&str> {
+        match self {
+            Capability::PushCert(value)
+            | Capability::ObjectFormat(value)
+            | Capability::SessionId(value)
+            | Capability::Filter(value)
+            | Capability::Symref(value)
+            | Capability::Agent(value) => Some(value),
+            Capability::Unknown(token) => token.split_once('=').map(|(_, value)| value),
+            _ => None,
         }
     }
 }
 
 impl std::fmt::Display for Capability {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Capability::MultiAck => write!(f, "multi_ack"),
-            Capability::MultiAckDetailed => write!(f, "multi_ack_detailed"),
-            Capability::NoDone => write!(f, "no-done"),
-            Capability::SideBand => write!(f, "side-band"),
-            Capability::SideBand64k => write!(f, "side-band-64k"),
-            Capability::ReportStatus => write!(f, "report-status"),
-            Capability::ReportStatusv2 => write!(f, "report-status-v2"),
-            Capability::OfsDelta => write!(f, "ofs-delta"),
-            Capability::DeepenSince => write!(f, "deepen-since"),
-            Capability::DeepenNot => write!(f, "deepen-not"),
-            Capability::DeepenRelative => write!(f, "deepen-relative"),
-            Capability::ThinPack => write!(f, "thin-pack"),
-            Capability::Shallow => write!(f, "shallow"),
-            Capability::IncludeTag => write!(f, "include-tag"),
-            Capability::DeleteRefs => write!(f, "delete-refs"),
-            Capability::Quiet => write!(f, "quiet"),
-            Capability::Atomic => write!(f, "atomic"),
-            Capability::NoThin => write!(f, "no-thin"),
-            Capability::NoProgress => write!(f, "no-progress"),
-            Capability::AllowTipSha1InWant => write!(f, "allow-tip-sha1-in-want"),
-            Capability::AllowReachableSha1InWant => write!(f, "allow-reachable-sha1-in-want"),
-            Capability::PushCert(value) => write!(f, "push-cert={}", value),
-            Capability::PushOptions => write!(f, "push-options"),
-            Capability::ObjectFormat(format) => write!(f, "object-format={}", format),
-            Capability::SessionId(id) => write!(f, "session-id={}", id),
-            Capability::Filter(filter) => write!(f, "filter={}", filter),
-            Capability::Symref(symref) => write!(f, "symref={}", symref),
-            Capability::Agent(agent) => write!(f, "agent={}", agent),
-            Capability::Unknown(s) => write!(f, "{}", s),
+        f.write_str(self.name())?;
+        if let Some(value) = self.value() {
+            write!(f, "={}", value)?;
         }
+        Ok(())
     }
 }
 
@@ -296,6 +491,50 @@ pub struct GitRef {
     pub hash: String,
 }
 
+/// Who introduced an object, recorded next to every object written by a push
+///
+/// Hosts receive it through [`RepositoryAccess::handle_pack_objects`](super::core::RepositoryAccess::handle_pack_objects)
+/// and can persist it for later forensics (which push introduced which object).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectProvenance {
+    /// Authenticated user that pushed the objects, if known
+    pub pusher: Option<String>,
+    /// Value of the client's `session-id` capability, if sent
+    pub session_id: Option<String>,
+    /// Unix timestamp (seconds) of the push
+    pub timestamp: i64,
+}
+
+impl ObjectProvenance {
+    /// Provenance for a push happening now
+    pub fn new(pusher: Option<String>, session_id: Option<String>) -> Self {
+        Self {
+            pusher,
+            session_id,
+            timestamp: chrono::Utc::now().timestamp(),
+        }
+    }
+}
+
+/// What an upload-pack negotiation established so far
+///
+/// Over smart HTTP every round of the negotiation is a request of its own, and clients send
+/// again their wants, shallow commits and the haves acknowledged as common. The state is derived
+/// from each request, see [`SmartProtocol::negotiation`](super::smart::SmartProtocol::negotiation),
+/// and serializable, so hosts can keep it between the requests of a fetch, e.g. to resume it
+/// with [`SmartProtocol::set_negotiation`](super::smart::SmartProtocol::set_negotiation).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NegotiationState {
+    /// The objects the client wants
+    pub wants: Vec<String>,
+    /// The haves found in the repository and acknowledged, in the order they were found
+    pub common: Vec<String>,
+    /// The commits at the boundary of the client's shallow clone
+    pub shallow: Vec<String>,
+    /// Whether the negotiation is over and the pack was sent
+    pub done: bool,
+}
+
 /// Reference command for push operations
 #[derive(Debug, Clone)]
 pub struct RefCommand {
@@ -306,6 +545,106 @@ pub struct RefCommand {
     pub default_branch: bool,
     pub status: CommandStatus,
     pub error_message: Option<String>,
+    /// Stable, untranslated identifier of the failure, reported in machine-status lines
+    pub error_code: Option<String>,
+}
+
+/// A single reference update, as handed to
+/// [`RepositoryAccess::update_references`](super::core::RepositoryAccess::update_references)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefUpdate {
+    pub ref_name: String,
+    /// `None` when the reference is created
+    pub old_hash: Option<String>,
+    pub new_hash: String,
+}
+
+impl From<&RefCommand> for RefUpdate {
+    fn from(command: &RefCommand) -> Self {
+        Self {
+            ref_name: command.ref_name.clone(),
+            old_hash: (command.old_hash != ZERO_ID).then(|| command.old_hash.clone()),
+            new_hash: command.new_hash.clone(),
+        }
+    }
+}
+
+/// One entry of a reference's reflog, as returned by
+/// [`RepositoryAccess::get_reflog`](super::core::RepositoryAccess::get_reflog)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReflogEntry {
+    /// Value before the update, [`ZERO_ID`] when the reference was created
+    pub old_hash: String,
+    /// Value after the update, [`ZERO_ID`] when the reference was deleted
+    pub new_hash: String,
+    /// Who made the update, e.g. `name <email>`
+    pub identity: String,
+    /// Unix timestamp (seconds) of the update
+    pub timestamp: i64,
+    pub message: String,
+}
+
+impl ReflogEntry {
+    /// An entry for an update made now
+    pub fn new(
+        old_hash: impl Into<String>,
+        new_hash: impl Into<String>,
+        identity: impl Into<String>,
+        message: impl Into<String>,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as i64);
+        Self {
+            old_hash: old_hash.into(),
+            new_hash: new_hash.into(),
+            identity: identity.into(),
+            timestamp,
+            message: message.into(),
+        }
+    }
+
+    /// Parse a line of a git reflog file, `<old> <new> <identity> <timestamp> <tz>\t<message>`
+    pub fn from_line(line: &str) -> Option<Self> {
+        let (head, message) = line.split_once('\t').unwrap_or((line, ""));
+        let (old_hash, rest) = head.split_once(' ')?;
+        let (new_hash, rest) = rest.split_once(' ')?;
+        let mut signature = rest.rsplitn(3, ' ');
+        let _timezone = signature.next()?;
+        let timestamp = signature.next()?.parse().ok()?;
+        let identity = signature.next()?;
+        let is_id = |hash: &str| hash.len() == 40 && hash.bytes().all(|b| b.is_ascii_hexdigit());
+        if !is_id(old_hash) || !is_id(new_hash) {
+            return None;
+        }
+        Some(Self {
+            old_hash: old_hash.to_string(),
+            new_hash: new_hash.to_string(),
+            identity: identity.to_string(),
+            timestamp,
+            message: message.to_string(),
+        })
+    }
+
+    /// The line of this entry in a git reflog file, without newline; the timezone is UTC
+    pub fn to_line(&self) -> String {
+        // A message spans a single line
+        let message = self.message.replace('\n', " ");
+        format!(
+            "{} {} {} {} +0000\t{message}",
+            self.old_hash, self.new_hash, self.identity, self.timestamp
+        )
+    }
+}
+
+/// A pack of the repository, as listed by
+/// [`RepositoryAccess::list_packs`](super::core::RepositoryAccess::list_packs)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackInfo {
+    /// The trailer checksum of the pack, which names its files
+    pub pack_hash: SHA1,
+    /// Marked by a `.keep` file or store_kept_pack; kept packs are never repacked or removed
+    pub kept: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -332,6 +671,7 @@ impl RefCommand {
             default_branch: false,
             status: CommandStatus::Pending,
             error_message: None,
+            error_code: None,
         }
     }
 
@@ -340,9 +680,26 @@ impl RefCommand {
         self.error_message = Some(error);
     }
 
+    /// Mark the command as failed with a stable `code` alongside the human readable message.
+    pub fn failed_with_code(&mut self, code: &str, error: String) {
+        self.failed(error);
+        self.error_code = Some(code.to_string());
+    }
+
     pub fn success(&mut self) {
         self.status = CommandStatus::Success;
         self.error_message = None;
+        self.error_code = None;
+    }
+
+    pub fn command_type(&self) -> CommandType {
+        if self.old_hash == ZERO_ID {
+            CommandType::Create
+        } else if self.new_hash == ZERO_ID {
+            CommandType::Delete
+        } else {
+            CommandType::Update
+        }
     }
 
     pub fn get_status(&self) -> String {
@@ -355,6 +712,66 @@ impl RefCommand {
             CommandStatus::Pending => format!("ok {}", self.ref_name), // Default to ok for pending
         }
     }
+
+    /// Status detail line sent after [`RefCommand::get_status`] when the client requested
+    /// the `machine-status` capability, e.g.
+    /// `status ref=refs/heads/main result=ng type=update old=<oid> new=<oid> code=force-push message=...`.
+    ///
+    /// Values are percent-encoded so a line always splits on spaces and `=` unambiguously.
+    pub fn get_machine_status(&self) -> String {
+        let (result, code) = match &self.status {
+            CommandStatus::Failed => ("ng", Some(self.error_code.as_deref().unwrap_or("error"))),
+            _ => ("ok", None),
+        };
+        let command_type = match self.command_type() {
+            CommandType::Create => "create",
+            CommandType::Update => "update",
+            CommandType::Delete => "delete",
+        };
+        let mut line = format!(
+            "status ref={} result={} type={} old={} new={}",
+            encode_status_value(&self.ref_name),
+            result,
+            command_type,
+            self.old_hash,
+            self.new_hash
+        );
+        if let Some(code) = code {
+            line.push_str(&format!(" code={}", encode_status_value(code)));
+            let message = self.error_message.as_deref().unwrap_or("unknown error");
+            line.push_str(&format!(" message={}", encode_status_value(message)));
+        }
+        line
+    }
+}
+
+/// Check a symbolic reference update of a repository whose only symbolic reference is `HEAD`,
+/// pointing to a branch.
+pub(crate) fn check_symbolic_ref(ref_name: &str, target: &str) -> Result<(), ProtocolError> {
+    if ref_name != "HEAD" {
+        return Err(ProtocolError::invalid_request(&format!(
+            "{ref_name}: only HEAD can be a symbolic reference"
+        )));
+    }
+    if !target.starts_with("refs/heads/") {
+        return Err(ProtocolError::invalid_request(&format!(
+            "HEAD can't point to {target}, which isn't a branch"
+        )));
+    }
+    Ok(())
+}
+
+/// Percent-encode everything outside printable ASCII, plus the `%`, ` ` and `=` separators.
+fn encode_status_value(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_graphic() && !matches!(byte, b'%' | b'=') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -372,9 +789,80 @@ pub const LF: char = '\n';
 pub const SP: char = ' ';
 pub const NUL: char = '\0';
 pub const PKT_LINE_END_MARKER: &[u8; 4] = b"0000";
+/// Separates the sections of a protocol v2 response
+pub const PKT_LINE_DELIM_MARKER: &[u8; 4] = b"0001";
 
 // Git protocol capability lists
 pub const RECEIVE_CAP_LIST: &str =
-    "report-status report-status-v2 delete-refs quiet atomic no-thin ";
-pub const COMMON_CAP_LIST: &str = "side-band-64k ofs-delta agent=git-internal/0.1.0";
+    "report-status report-status-v2 delete-refs quiet atomic machine-status ";
+pub const COMMON_CAP_LIST: &str = "side-band-64k ofs-delta object-format=sha1";
+/// Agent advertised by default, see `SmartProtocol::set_agent`
+pub const DEFAULT_AGENT: &str = concat!("git-internal/", env!("CARGO_PKG_VERSION"));
+
+/// Hash algorithm used for every object handled by this crate, as named by `object-format`
+pub const OBJECT_FORMAT: &str = "sha1";
 pub const UPLOAD_CAP_LIST: &str = "multi_ack_detailed no-done include-tag ";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protocol_version_hints() {
+        assert_eq!(ProtocolVersion::from_hints(None), ProtocolVersion::V0);
+        assert_eq!(
+            ProtocolVersion::from_hints(["version=1"]),
+            ProtocolVersion::V1
+        );
+        assert_eq!(
+            ProtocolVersion::from_hints(["object-format=sha1:version=2:version=1"]),
+            ProtocolVersion::V2
+        );
+        assert_eq!(
+            ProtocolVersion::from_hints(["version=3", "host=example.com", "version=1"]),
+            ProtocolVersion::V1
+        );
+    }
+
+    #[test]
+    fn test_protocol_error_mapping() {
+        let error = ProtocolError::RepositoryNotFound("acme/widgets".to_string());
+        assert_eq!(error.code(), "repository-not-found");
+        assert_eq!(error.http_status(), http::StatusCode::NOT_FOUND);
+        assert_eq!(
+            error.to_err_pkt_line(),
+            "002bERR Repository not found: acme/widgets\n"
+        );
+
+        let error = ProtocolError::PolicyRejected("pushes to main need a review".to_string());
+        assert_eq!(error.http_status(), http::StatusCode::FORBIDDEN);
+        assert_eq!(
+            error.client_message(),
+            "Rejected by policy: pushes to main need a review"
+        );
+        let error = ProtocolError::auth_failed(Some("alice"), "bad token");
+        assert_eq!(error.code(), "auth-failed");
+        assert_eq!(error.http_status(), http::StatusCode::UNAUTHORIZED);
+        assert_eq!(error.client_message(), "Unauthorized: bad token");
+
+        // Corrupt data from the client is its error, told with the object at fault
+        let id = SHA1::from_str(&"1".repeat(40)).unwrap();
+        let error = ProtocolError::corrupt_object(id, "Missing tagger");
+        assert_eq!(error.code(), "pack-corrupt");
+        assert_eq!(error.http_status(), http::StatusCode::BAD_REQUEST);
+        assert_eq!(
+            error.client_message(),
+            format!("Corrupt object {id}: Missing tagger")
+        );
+        assert_eq!(
+            ProtocolError::pack_corrupt("pack checksum mismatch").to_string(),
+            "Corrupt pack: pack checksum mismatch"
+        );
+
+        // Internal details aren't sent to clients
+        let error = ProtocolError::repository_error("db at 10.0.0.3 is down".to_string());
+        assert_eq!(error.code(), "internal");
+        assert_eq!(error.http_status(), http::StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(error.client_message(), "internal server error");
+    }
+}
@@ -0,0 +1,165 @@
+//! The upload-archive service, the server side of `git archive --remote`.
+//!
+//! The client sends the arguments of its command line, e.g. `--format=zip`, `--prefix=v1/`,
+//! the tree-ish and paths, in one `argument <arg>` pkt-line each, then a flush-pkt. The server
+//! answers `ACK` and a flush-pkt, then sends the archive in band 1 of a side-band, or answers
+//! `NACK <reason>` when it refuses the request. [`ArchiveRequest`] reads and parses the
+//! arguments; [`SmartProtocol::git_upload_archive`](super::smart::SmartProtocol::git_upload_archive)
+//! resolves the tree-ish and has the repository write the archive, by default the one
+//! [`archive`](crate::archive::archive) generates.
+//!
+//! Like git for remote clients, the tree-ish must name a ref the client may see, optionally
+//! followed by `:<path>` to archive a subtree, unless unreachable objects are allowed, in which
+//! case any object id the repository has is accepted.
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+
+use super::codec::PktLineReader;
+use super::types::ProtocolError;
+use super::utils::PktLine;
+pub use crate::archive::{ArchiveFormat, ArchiveOptions};
+
+/// The most arguments a client may send, like git.
+pub const MAX_ARCHIVE_ARGUMENTS: usize = 64;
+
+/// The arguments of an upload-archive request, see the [module documentation](self).
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ArchiveRequest {
+    /// The tree-ish before any `:<path>`, e.g. `HEAD`, `v1.0` or `refs/heads/main`
+    pub tree_ish: String,
+    /// The archive asked for, its `tree_path` the path after the `:` of the tree-ish
+    pub options: ArchiveOptions,
+}
+
+impl ArchiveRequest {
+    /// Parse the arguments of a request, as `git archive` sends them.
+    pub fn parse<'a>(arguments: impl IntoIterator<Item = &'a str>) -> Result<Self, ProtocolError> {
+        let mut options = ArchiveOptions::default();
+        let mut tree_ish = None;
+        let mut options_done = false;
+        for arg in arguments {
+            if !options_done && arg.starts_with('-') {
+                if let Some(format) = arg.strip_prefix("--format=") {
+                    options.format = format.parse()?;
+                } else if let Some(prefix) = arg.strip_prefix("--prefix=") {
+                    options.prefix = prefix.to_string();
+                } else if let Some(level) = arg.strip_prefix('-')
+                    && let [digit @ b'0'..=b'9'] = level.as_bytes()
+                {
+                    options.compression_level = Some(u32::from(digit - b'0'));
+                } else if arg == "--" {
+                    options_done = true;
+                } else if arg != "--worktree-attributes" {
+                    // The attributes of the worktree of the server are those of its tree
+                    return Err(ProtocolError::invalid_request(&format!(
+                        "Unknown archive argument: {arg}"
+                    )));
+                }
+            } else if tree_ish.is_none() {
+                tree_ish = Some(arg);
+            } else {
+                options.paths.push(arg.to_string());
+            }
+        }
+        let tree_ish =
+            tree_ish.ok_or_else(|| ProtocolError::invalid_request("Missing tree-ish"))?;
+        let (tree_ish, tree_path) = match tree_ish.split_once(':') {
+            Some((rev, path)) => (rev, Some(path.trim_matches('/').to_string())),
+            None => (tree_ish, None),
+        };
+        options.tree_path = tree_path;
+        Ok(Self {
+            tree_ish: tree_ish.to_string(),
+            options,
+        })
+    }
+
+    /// Read the `argument` pkt-lines of a request up to its flush-pkt, and parse them.
+    pub async fn read<S>(reader: &mut PktLineReader<S>) -> Result<Self, ProtocolError>
+    where
+        S: Stream<Item = Result<Bytes, ProtocolError>> + Unpin,
+    {
+        let mut arguments = Vec::new();
+        loop {
+            match reader.next().await.transpose()? {
+                Some(PktLine::Flush) => break,
+                Some(PktLine::Data(line)) => {
+                    let line = std::str::from_utf8(&line)
+                        .map_err(|_| ProtocolError::invalid_request("Argument is not UTF-8"))?;
+                    let line = line.strip_suffix('\n').unwrap_or(line);
+                    let arg = line.strip_prefix("argument ").ok_or_else(|| {
+                        ProtocolError::invalid_request("'argument' token or flush expected")
+                    })?;
+                    if arguments.len() == MAX_ARCHIVE_ARGUMENTS {
+                        return Err(ProtocolError::invalid_request(&format!(
+                            "Too many arguments (>{MAX_ARCHIVE_ARGUMENTS})"
+                        )));
+                    }
+                    arguments.push(arg.to_string());
+                }
+                _ => return Err(ProtocolError::invalid_request("Missing flush-pkt")),
+            }
+        }
+        Self::parse(arguments.iter().map(String::as_str))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::types::PKT_LINE_END_MARKER;
+    use crate::protocol::utils::add_pkt_line_string;
+    use bytes::{BufMut, BytesMut};
+
+    #[test]
+    fn test_archive_request_parse() {
+        let request =
+            ArchiveRequest::parse(["--format=zip", "--prefix=p-1/", "-9", "v1:docs/", "a.md"])
+                .unwrap();
+        assert_eq!(request.tree_ish, "v1");
+        assert_eq!(
+            request.options,
+            ArchiveOptions {
+                format: ArchiveFormat::Zip,
+                prefix: "p-1/".to_string(),
+                compression_level: Some(9),
+                tree_path: Some("docs".to_string()),
+                paths: vec!["a.md".to_string()],
+            }
+        );
+
+        let request = ArchiveRequest::parse(["HEAD", "--", "-notes"]).unwrap();
+        assert_eq!(request.options.format, ArchiveFormat::Tar);
+        assert_eq!(request.options.paths, ["-notes"]);
+        assert_eq!(
+            "tgz".parse::<ArchiveFormat>().unwrap(),
+            ArchiveFormat::TarGz
+        );
+
+        for arguments in [
+            &["--format=rar", "HEAD"][..],
+            &["--output=/tmp/x", "HEAD"],
+            &["-10", "HEAD"],
+            &["--prefix=x/"],
+        ] {
+            assert!(ArchiveRequest::parse(arguments.iter().copied()).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_archive_request_read() {
+        let mut request = BytesMut::new();
+        add_pkt_line_string(&mut request, "argument --format=tgz\n".to_string());
+        add_pkt_line_string(&mut request, "argument main\n".to_string());
+        request.put(&PKT_LINE_END_MARKER[..]);
+        let mut reader = PktLineReader::new(futures::stream::iter([Ok(request.freeze())]));
+        let request = ArchiveRequest::read(&mut reader).await.unwrap();
+        assert_eq!(request.options.format, ArchiveFormat::TarGz);
+        assert_eq!(request.tree_ish, "main");
+
+        let mut request = BytesMut::new();
+        add_pkt_line_string(&mut request, "want main\n".to_string());
+        let mut reader = PktLineReader::new(futures::stream::iter([Ok(request.freeze())]));
+        assert!(ArchiveRequest::read(&mut reader).await.is_err());
+    }
+}
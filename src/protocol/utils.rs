@@ -1,92 +1,184 @@
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 
-use super::types::{PKT_LINE_END_MARKER, TransportProtocol};
+use super::types::{Capability, PKT_LINE_END_MARKER, ProtocolError, SideBand, TransportProtocol};
 
-/// Read a packet line from the given bytes buffer
+/// Largest pkt-line, length prefix included, that git sends or accepts
+pub const MAX_PKT_LINE_LENGTH: usize = 65520;
+
+/// A pkt-line read by [`parse_pkt_line`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PktLine {
+    /// `0000`, the end of a message
+    Flush,
+    /// `0001`, the end of a section of a protocol v2 message
+    Delim,
+    /// `0002`, the end of a protocol v2 response
+    ResponseEnd,
+    /// The payload of a data line, without its length prefix
+    Data(Bytes),
+}
+
+/// Why a pkt-line couldn't be read
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PktLineError {
+    #[error("invalid pkt-line length {0:?}")]
+    BadLength(String),
+
+    #[error("pkt-line length {0} exceeds the maximum of {MAX_PKT_LINE_LENGTH}")]
+    Oversize(usize),
+
+    #[error("truncated pkt-line: {expected} bytes announced, {available} available")]
+    Truncated { expected: usize, available: usize },
+
+    #[error("invalid UTF-8 in pkt-line")]
+    InvalidUtf8,
+}
+
+impl From<PktLineError> for ProtocolError {
+    fn from(e: PktLineError) -> Self {
+        ProtocolError::InvalidRequest(e.to_string())
+    }
+}
+
+/// Read the next pkt-line of `bytes`, `None` at the end of the data.
 ///
-/// Returns a tuple of (bytes_consumed, packet_data)
+/// The payload of a data line is a slice of `bytes`, not a copy.
 ///
-/// This is the original simple implementation from ceres
-pub fn read_pkt_line(bytes: &mut Bytes) -> (usize, Bytes) {
+/// Nothing is consumed when the line is malformed or truncated, so a caller reading from a
+/// stream can wait for more data after a [`PktLineError::Truncated`].
+pub fn parse_pkt_line(bytes: &mut Bytes) -> Result<Option<PktLine>, PktLineError> {
     if bytes.is_empty() {
-        return (0, Bytes::new());
+        return Ok(None);
     }
-
-    // Ensure we have at least 4 bytes for the length prefix
     if bytes.len() < 4 {
-        return (0, Bytes::new());
+        return Err(PktLineError::Truncated {
+            expected: 4,
+            available: bytes.len(),
+        });
     }
-
-    let pkt_length = bytes.copy_to_bytes(4);
-    let pkt_length_str = match core::str::from_utf8(&pkt_length) {
-        Ok(s) => s,
-        Err(_) => {
-            tracing::warn!("Invalid UTF-8 in packet length: {:?}", pkt_length);
-            return (0, Bytes::new());
-        }
+    let pkt_length = parse_pkt_length(&bytes[..4])?;
+    let special = match pkt_length {
+        0 => Some(PktLine::Flush),
+        1 => Some(PktLine::Delim),
+        2 => Some(PktLine::ResponseEnd),
+        3 => return Err(PktLineError::BadLength(format!("{pkt_length:04x}"))),
+        _ => None,
     };
-
-    let pkt_length = match usize::from_str_radix(pkt_length_str, 16) {
-        Ok(len) => len,
-        Err(_) => {
-            tracing::warn!("Invalid hex packet length: {:?}", pkt_length_str);
-            return (0, Bytes::new());
-        }
-    };
-
-    if pkt_length == 0 {
-        return (4, Bytes::new()); // Consumed 4 bytes for the "0000" marker
+    if let Some(special) = special {
+        bytes.advance(4);
+        return Ok(Some(special));
     }
-
-    if pkt_length < 4 {
-        tracing::warn!("Invalid packet length: {} (must be >= 4)", pkt_length);
-        return (0, Bytes::new());
+    if pkt_length > MAX_PKT_LINE_LENGTH {
+        return Err(PktLineError::Oversize(pkt_length));
     }
-
-    let data_length = pkt_length - 4;
-    if bytes.len() < data_length {
-        tracing::warn!(
-            "Insufficient data: need {} bytes, have {}",
-            data_length,
-            bytes.len()
-        );
-        return (0, Bytes::new());
+    if bytes.len() < pkt_length {
+        return Err(PktLineError::Truncated {
+            expected: pkt_length,
+            available: bytes.len(),
+        });
     }
-
-    // this operation will change the original bytes
-    let pkt_line = bytes.copy_to_bytes(data_length);
+    bytes.advance(4);
+    let pkt_line = bytes.split_to(pkt_length - 4);
     tracing::debug!("pkt line: {:?}", pkt_line);
+    Ok(Some(PktLine::Data(pkt_line)))
+}
+
+/// The length announced by the 4 hex digits of a pkt-line prefix
+pub(crate) fn parse_pkt_length(prefix: &[u8]) -> Result<usize, PktLineError> {
+    core::str::from_utf8(prefix)
+        .ok()
+        .filter(|hex| hex.len() == 4 && hex.bytes().all(|c| c.is_ascii_hexdigit()))
+        .and_then(|hex| usize::from_str_radix(hex, 16).ok())
+        .ok_or_else(|| PktLineError::BadLength(String::from_utf8_lossy(prefix).into_owned()))
+}
+
+/// Read a packet line from the given bytes buffer
+///
+/// Returns a tuple of (bytes_consumed, packet_data); flush, delim and response-end packets
+/// are returned as an empty line consuming 4 bytes, and malformed or truncated input as
+/// (0, empty). Use [`parse_pkt_line`] to tell these apart.
+///
+/// This is the original simple implementation from ceres
+pub fn read_pkt_line(bytes: &mut Bytes) -> (usize, Bytes) {
+    match parse_pkt_line(bytes) {
+        Ok(Some(PktLine::Data(pkt_line))) => (pkt_line.len() + 4, pkt_line),
+        Ok(Some(_)) => (4, Bytes::new()),
+        Ok(None) => (0, Bytes::new()),
+        Err(e) => {
+            tracing::warn!("{}", e);
+            (0, Bytes::new())
+        }
+    }
+}
 
-    (pkt_length, pkt_line)
+/// The 4 hex digits prefixing a pkt-line of `pkt_length` bytes
+pub(crate) fn pkt_length_prefix(pkt_length: usize) -> [u8; 4] {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    [12, 8, 4, 0].map(|shift| HEX[(pkt_length >> shift) & 0xf])
 }
 
 /// Add a packet line string to the buffer with proper length prefix
 ///
 /// This is the original simple implementation from ceres
 pub fn add_pkt_line_string(pkt_line_stream: &mut BytesMut, buf_str: String) {
-    let buf_str_length = buf_str.len() + 4;
-    pkt_line_stream.put(Bytes::from(format!("{buf_str_length:04x}")));
-    pkt_line_stream.put(buf_str.as_bytes());
+    add_pkt_line_parts(pkt_line_stream, &[buf_str.as_bytes()]);
+}
+
+/// Add a pkt-line made of the concatenation of `parts` to the buffer, without building the
+/// line first, e.g. `[id, b" ", name, b"\n"]` for each ref of an advertisement
+pub fn add_pkt_line_parts(pkt_line_stream: &mut BytesMut, parts: &[&[u8]]) {
+    let pkt_length = parts.iter().map(|part| part.len()).sum::<usize>() + 4;
+    pkt_line_stream.reserve(pkt_length);
+    pkt_line_stream.put_slice(&pkt_length_prefix(pkt_length));
+    for part in parts {
+        pkt_line_stream.put_slice(part);
+    }
+}
+
+/// Split off the word before the next whitespace (or NUL), a slice of `bytes`, and skip the
+/// separator
+pub fn split_word(bytes: &mut Bytes) -> Bytes {
+    let end = bytes
+        .iter()
+        .position(|&c| c.is_ascii_whitespace() || c == 0)
+        .unwrap_or(bytes.len());
+    let word = bytes.split_to(end);
+    if bytes.has_remaining() {
+        bytes.advance(1);
+    }
+    word
+}
+
+/// Read until whitespace (or NUL) and return the extracted word, failing on invalid UTF-8
+pub fn parse_word(bytes: &mut Bytes) -> Result<String, PktLineError> {
+    let word = split_word(bytes);
+    core::str::from_utf8(&word)
+        .map(str::to_owned)
+        .map_err(|_| PktLineError::InvalidUtf8)
 }
 
 /// Read until whitespace and return the extracted string
 ///
+/// Returns an empty string on invalid UTF-8, [`parse_word`] reports it instead.
+///
 /// This is the original implementation from ceres
 pub fn read_until_white_space(bytes: &mut Bytes) -> String {
-    let mut buf = Vec::new();
-    while bytes.has_remaining() {
-        let c = bytes.get_u8();
-        if c.is_ascii_whitespace() || c == 0 {
-            break;
-        }
-        buf.push(c);
-    }
-    match String::from_utf8(buf) {
-        Ok(s) => s,
-        Err(e) => {
-            tracing::warn!("Invalid UTF-8 in protocol data: {}", e);
-            String::new() // Return empty string on invalid UTF-8
-        }
+    parse_word(bytes).unwrap_or_else(|e| {
+        tracing::warn!("Invalid UTF-8 in protocol data: {}", e);
+        String::new() // Return empty string on invalid UTF-8
+    })
+}
+
+/// Add the `# service=<service>` section opening advertisements over HTTP; other transports
+/// have none
+pub fn add_service_header(
+    pkt_line_stream: &mut BytesMut,
+    transport_protocol: TransportProtocol,
+    service: &str,
+) {
+    if transport_protocol == TransportProtocol::Http {
+        add_pkt_line_parts(pkt_line_stream, &[b"# service=", service.as_bytes(), b"\n"]);
+        pkt_line_stream.put(&PKT_LINE_END_MARKER[..]);
     }
 }
 
@@ -96,21 +188,145 @@ pub fn build_smart_reply(
     service: String,
 ) -> BytesMut {
     let mut pkt_line_stream = BytesMut::new();
-    if transport_protocol == TransportProtocol::Http {
-        add_pkt_line_string(&mut pkt_line_stream, format!("# service={service}\n"));
-        pkt_line_stream.put(&PKT_LINE_END_MARKER[..]);
-    }
+    add_service_header(&mut pkt_line_stream, transport_protocol, &service);
 
     for ref_line in ref_list {
-        add_pkt_line_string(&mut pkt_line_stream, ref_line.to_string());
+        add_pkt_line_parts(&mut pkt_line_stream, &[ref_line.as_bytes()]);
     }
     pkt_line_stream.put(&PKT_LINE_END_MARKER[..]);
     pkt_line_stream
 }
 
+/// Build the `ERR <message>` pkt-line that stops git clients with `remote error: <message>`,
+/// e.g. after a failed authentication or for a missing repository. The client prints a single
+/// line, so line breaks in `message` are replaced by spaces.
+pub fn build_error_pkt_line(message: &str) -> BytesMut {
+    let mut pkt_line_stream = BytesMut::new();
+    let message = message.trim_end().replace(['\r', '\n'], " ");
+    add_pkt_line_string(&mut pkt_line_stream, format!("ERR {message}\n"));
+    pkt_line_stream
+}
+
+/// Build the reply to an info/refs request of `service` that failed with `message`, in place
+/// of the refs advertisement; over HTTP it keeps the `# service` header clients read first.
+pub fn build_error_advertisement(
+    transport_protocol: TransportProtocol,
+    service: &str,
+    message: &str,
+) -> BytesMut {
+    let mut pkt_line_stream = BytesMut::new();
+    add_service_header(&mut pkt_line_stream, transport_protocol, service);
+    pkt_line_stream.put(build_error_pkt_line(message));
+    pkt_line_stream
+}
+
+/// The largest side-band payload of the negotiated `capabilities`, `None` without side-band
+pub(crate) fn side_band_limit(capabilities: &[Capability]) -> Option<usize> {
+    if capabilities.contains(&Capability::SideBand64k) {
+        Some(MAX_PKT_LINE_LENGTH - 5)
+    } else if capabilities.contains(&Capability::SideBand) {
+        Some(1000 - 5)
+    } else {
+        None
+    }
+}
+
+/// `data` in band 1 packets of payloads of at most `limit` bytes
+pub(crate) fn side_band_packets(data: &[u8], limit: usize) -> Bytes {
+    band_packets(SideBand::PackfileData, data, limit)
+}
+
+/// `data` in `band` packets of payloads of at most `limit` bytes, a message longer than a
+/// pkt-line is split over several
+pub(crate) fn band_packets(band: SideBand, data: &[u8], limit: usize) -> Bytes {
+    let mut packets = BytesMut::with_capacity(data.len() + data.len() / limit * 5 + 5);
+    for chunk in data.chunks(limit) {
+        packets.put_slice(&pkt_length_prefix(chunk.len() + 5));
+        packets.put_u8(band.value());
+        packets.put_slice(chunk);
+    }
+    packets.freeze()
+}
+
 /// Search for a subsequence in a byte slice
 pub fn search_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
     haystack
         .windows(needle.len())
         .position(|window| window == needle)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_pkt_line() {
+        let mut buf = BytesMut::new();
+        add_pkt_line_parts(&mut buf, &[b"1234", b" ", b"refs/heads/main", b"\n"]);
+        add_pkt_line_string(&mut buf, "done\n".to_string());
+        assert_eq!(buf, "00191234 refs/heads/main\n0009done\n");
+        assert_eq!(pkt_length_prefix(MAX_PKT_LINE_LENGTH), *b"fff0");
+        assert_eq!(
+            side_band_packets(b"abcdefg", 4),
+            &b"0009\x01abcd0008\x01efg"[..]
+        );
+    }
+
+    #[test]
+    fn test_parse_pkt_line() {
+        let mut bytes = Bytes::from_static(b"0009want\n00010002000ahave x0000");
+        assert_eq!(
+            parse_pkt_line(&mut bytes),
+            Ok(Some(PktLine::Data(Bytes::from_static(b"want\n"))))
+        );
+        assert_eq!(parse_pkt_line(&mut bytes), Ok(Some(PktLine::Delim)));
+        assert_eq!(parse_pkt_line(&mut bytes), Ok(Some(PktLine::ResponseEnd)));
+        let mut line = match parse_pkt_line(&mut bytes) {
+            Ok(Some(PktLine::Data(line))) => line,
+            other => panic!("{other:?}"),
+        };
+        assert_eq!(parse_word(&mut line).unwrap(), "have");
+        assert_eq!(parse_word(&mut line).unwrap(), "x");
+        assert_eq!(parse_pkt_line(&mut bytes), Ok(Some(PktLine::Flush)));
+        assert_eq!(parse_pkt_line(&mut bytes), Ok(None));
+
+        // Malformed input is reported and left in place
+        for (input, err) in [
+            (&b"00zzwant"[..], PktLineError::BadLength("00zz".into())),
+            (b"+00ahave", PktLineError::BadLength("+00a".into())),
+            (b"0003", PktLineError::BadLength("0003".into())),
+            (b"fff5", PktLineError::Oversize(0xfff5)),
+            (
+                b"000ahave",
+                PktLineError::Truncated {
+                    expected: 10,
+                    available: 8,
+                },
+            ),
+            (
+                b"00",
+                PktLineError::Truncated {
+                    expected: 4,
+                    available: 2,
+                },
+            ),
+        ] {
+            let mut bytes = Bytes::from_static(input);
+            assert_eq!(parse_pkt_line(&mut bytes), Err(err));
+            assert_eq!(bytes, input);
+        }
+        assert_eq!(
+            read_pkt_line(&mut Bytes::from_static(b"00zzwant")),
+            (0, Bytes::new())
+        );
+
+        let mut line = Bytes::from_static(b"0000 refs/heads/main\0report-status");
+        let input = line.as_ptr();
+        assert_eq!(split_word(&mut line).as_ptr(), input);
+        assert_eq!(split_word(&mut line), "refs/heads/main");
+        assert_eq!(line, "report-status");
+
+        let mut invalid = Bytes::from_static(b"\xff\xfe rest");
+        assert_eq!(parse_word(&mut invalid), Err(PktLineError::InvalidUtf8));
+    }
+}
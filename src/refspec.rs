@@ -0,0 +1,270 @@
+//! Refspecs, the `[+]<src>:<dst>` mappings of `git fetch` and `git push`.
+//!
+//! A refspec maps the refs matching its source to destination refs, e.g.
+//! `+refs/heads/*:refs/remotes/origin/*` copies every branch to a remote-tracking ref, forcing
+//! non-fast-forward updates. A `*` in the source matches any part of a name and is replaced by
+//! that part in the destination. A negative refspec, `^refs/heads/wip/*`, excludes the refs it
+//! matches from the others, so mirroring can skip a namespace. [`map_refs`] applies a list of
+//! refspecs to ref names, the way fetch and push pick what to update.
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use crate::errors::GitError;
+use crate::internal::refs::validate_ref_name;
+
+/// A parsed refspec, see the [module documentation](self).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Refspec {
+    force: bool,
+    negative: bool,
+    src: String,
+    dst: Option<String>,
+}
+
+/// A ref selected by [`map_refs`], with the ref it maps to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefMapping {
+    pub src: String,
+    pub dst: String,
+    /// Update `dst` even when it isn't a fast-forward.
+    pub force: bool,
+}
+
+impl Refspec {
+    /// Parse `spec`, e.g. `+refs/heads/*:refs/remotes/origin/*`, `main`, `:refs/heads/gone` or
+    /// `^refs/heads/wip/*`.
+    pub fn parse(spec: &str) -> Result<Self, GitError> {
+        let invalid = |reason: &str| GitError::InvalidRefspec(spec.to_string(), reason.into());
+        let (negative, rest) = match spec.strip_prefix('^') {
+            Some(rest) => (true, rest),
+            None => (false, spec),
+        };
+        let (force, rest) = match rest.strip_prefix('+') {
+            Some(rest) if !negative => (true, rest),
+            Some(_) => return Err(invalid("a negative refspec can't be forced")),
+            None => (false, rest),
+        };
+        let (src, dst) = match rest.split_once(':') {
+            Some((src, dst)) => (src, Some(dst)),
+            None => (rest, None),
+        };
+        if negative && dst.is_some() {
+            return Err(invalid("a negative refspec has no destination"));
+        }
+        // `<src>:` is `<src>` without a destination
+        let dst = dst.filter(|dst| !dst.is_empty());
+        if src.is_empty() && dst.is_none() {
+            return Err(invalid("empty source and destination"));
+        }
+        let globs = |side: &str| side.matches('*').count();
+        for side in std::iter::once(src).chain(dst) {
+            if globs(side) > 1 {
+                return Err(invalid("more than one `*`"));
+            }
+            if !side.is_empty() && validate_ref_name(&side.replacen('*', "x", 1)).is_err() {
+                return Err(invalid(&format!("`{side}` isn't a valid ref name")));
+            }
+        }
+        if let Some(dst) = dst
+            && globs(src) != globs(dst)
+        {
+            return Err(invalid("`*` on one side only"));
+        }
+        Ok(Self {
+            force,
+            negative,
+            src: src.to_string(),
+            dst: dst.map(str::to_string),
+        })
+    }
+
+    /// Whether refs are updated even when it isn't a fast-forward, the leading `+`.
+    pub fn is_force(&self) -> bool {
+        self.force
+    }
+
+    /// Whether the refspec excludes the refs it matches, the leading `^`.
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    /// Whether the refspec has a `*` and matches many refs.
+    pub fn is_pattern(&self) -> bool {
+        self.src.contains('*')
+    }
+
+    /// The source, empty when a push deletes the destination.
+    pub fn src(&self) -> &str {
+        &self.src
+    }
+
+    /// The destination, `None` when there is none, e.g. `git fetch origin main`.
+    pub fn dst(&self) -> Option<&str> {
+        self.dst.as_deref()
+    }
+
+    /// Whether the ref `name` matches the source.
+    pub fn matches(&self, name: &str) -> bool {
+        match_pattern(&self.src, name).is_some()
+    }
+
+    /// The destination of the ref `name`, `None` when it doesn't match the source or the
+    /// refspec has no destination, or is negative.
+    pub fn map(&self, name: &str) -> Option<String> {
+        let dst = self.dst.as_deref()?;
+        let matched = match_pattern(&self.src, name)?;
+        Some(dst.replacen('*', matched, 1))
+    }
+
+    /// The source mapped to the ref `name` of the destination, e.g. the remote branch of a
+    /// remote-tracking ref.
+    pub fn map_reverse(&self, name: &str) -> Option<String> {
+        let matched = match_pattern(self.dst.as_deref()?, name)?;
+        Some(self.src.replacen('*', matched, 1))
+    }
+}
+
+impl FromStr for Refspec {
+    type Err = GitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl Display for Refspec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.negative {
+            f.write_str("^")?;
+        }
+        if self.force {
+            f.write_str("+")?;
+        }
+        f.write_str(&self.src)?;
+        if let Some(dst) = &self.dst {
+            write!(f, ":{dst}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The part of `name` matched by the `*` of `pattern`, or the empty string when `pattern` has
+/// no `*` and is `name`.
+fn match_pattern<'a>(pattern: &str, name: &'a str) -> Option<&'a str> {
+    match pattern.split_once('*') {
+        Some((prefix, suffix)) => name.strip_prefix(prefix)?.strip_suffix(suffix),
+        None => (pattern == name).then_some(""),
+    }
+}
+
+/// Map `names` through `refspecs`: each name matched by a refspec with a destination maps to
+/// it, the first matching refspec winning, unless a negative refspec matches the name. Names
+/// are kept in order.
+pub fn map_refs<'a>(
+    refspecs: &[Refspec],
+    names: impl IntoIterator<Item = &'a str>,
+) -> Vec<RefMapping> {
+    let (negative, positive): (Vec<&Refspec>, Vec<&Refspec>) =
+        refspecs.iter().partition(|refspec| refspec.negative);
+    names
+        .into_iter()
+        .filter(|name| !negative.iter().any(|refspec| refspec.matches(name)))
+        .filter_map(|name| {
+            positive.iter().find_map(|refspec| {
+                refspec.map(name).map(|dst| RefMapping {
+                    src: name.to_string(),
+                    dst,
+                    force: refspec.force,
+                })
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refspec_parse() {
+        let refspec = Refspec::parse("+refs/heads/*:refs/remotes/origin/*").unwrap();
+        assert!(refspec.is_force() && refspec.is_pattern() && !refspec.is_negative());
+        assert_eq!(refspec.src(), "refs/heads/*");
+        assert_eq!(refspec.dst(), Some("refs/remotes/origin/*"));
+
+        // Round trips
+        for spec in [
+            "+refs/heads/*:refs/remotes/origin/*",
+            "refs/heads/main",
+            ":refs/heads/gone",
+            "^refs/heads/wip/*",
+            "refs/heads/feat-*:refs/heads/review/*",
+        ] {
+            assert_eq!(spec.parse::<Refspec>().unwrap().to_string(), spec);
+        }
+        assert_eq!(Refspec::parse("main:").unwrap().dst(), None);
+
+        for spec in [
+            "",
+            ":",
+            "refs/heads/*:refs/heads/main",
+            "refs/heads/main:refs/heads/*",
+            "refs/*/*:refs/*/*",
+            "^+refs/heads/x",
+            "^refs/heads/x:refs/heads/y",
+            "refs/heads/a..b",
+            "refs/heads/x:refs/heads/y z",
+        ] {
+            let err = Refspec::parse(spec).unwrap_err();
+            assert!(matches!(err, GitError::InvalidRefspec(..)), "{spec}: {err}");
+        }
+    }
+
+    #[test]
+    fn test_refspec_mapping() {
+        let refspec: Refspec = "+refs/heads/*:refs/remotes/origin/*".parse().unwrap();
+        assert!(refspec.matches("refs/heads/topic/x"));
+        assert!(!refspec.matches("refs/tags/v1"));
+        assert_eq!(
+            refspec.map("refs/heads/topic/x").as_deref(),
+            Some("refs/remotes/origin/topic/x")
+        );
+        assert_eq!(
+            refspec.map_reverse("refs/remotes/origin/main").as_deref(),
+            Some("refs/heads/main")
+        );
+        let exact: Refspec = "refs/heads/main:refs/heads/backup".parse().unwrap();
+        assert_eq!(
+            exact.map("refs/heads/main").as_deref(),
+            Some("refs/heads/backup")
+        );
+        assert_eq!(exact.map("refs/heads/mainline"), None);
+
+        // A partial glob
+        let partial: Refspec = "refs/heads/feat-*:refs/review/*".parse().unwrap();
+        assert_eq!(
+            partial.map("refs/heads/feat-login").as_deref(),
+            Some("refs/review/login")
+        );
+        assert_eq!(partial.map("refs/heads/fix-login"), None);
+
+        let refspecs: Vec<Refspec> = ["+refs/*:refs/*", "^refs/heads/wip/*", "^refs/pull/*"]
+            .iter()
+            .map(|spec| spec.parse().unwrap())
+            .collect();
+        let names = [
+            "refs/heads/main",
+            "refs/heads/wip/draft",
+            "refs/pull/1/head",
+            "refs/tags/v1",
+        ];
+        let mapped: Vec<String> = map_refs(&refspecs, names)
+            .into_iter()
+            .map(|mapping| {
+                assert!(mapping.force);
+                mapping.dst
+            })
+            .collect();
+        assert_eq!(mapped, ["refs/heads/main", "refs/tags/v1"]);
+    }
+}
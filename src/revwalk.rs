@@ -0,0 +1,1334 @@
+//! History traversal options and a simple `git rev-list`-like walk.
+//!
+//! [`RevWalkOptions`] carries the `git log` switches that change which commits a walk visits
+//! (`--first-parent`) and which of them it reports (`--merges`, `--no-merges`), so tools such as
+//! changelog generators can reproduce the output of their git-CLI workflows. [`rev_list`] applies
+//! them to commits loaded through any lookup function.
+//!
+//! [`CommitWalker`] is the asynchronous counterpart over [`RepositoryAccess`], with `--not`
+//! exclusions and `--topo-order`, yielding commits one at a time so a log view can page through
+//! history without loading all of it. [`merge_bases`] and [`ahead_behind`] answer the questions a
+//! branch view asks about two commits.
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::GitError;
+use crate::hash::SHA1;
+use crate::internal::commit_graph::CommitGraph;
+use crate::internal::object::commit::Commit;
+use crate::internal::object::tree::TreeItemMode;
+use crate::protocol::core::RepositoryAccess;
+use crate::protocol::types::ProtocolError;
+
+/// Selects commits by their number of parents.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MergeFilter {
+    /// Report every commit.
+    #[default]
+    All,
+    /// `--no-merges`: skip commits with more than one parent.
+    NoMerges,
+    /// `--merges`: only report commits with more than one parent.
+    MergesOnly,
+}
+
+/// Options of a history walk.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RevWalkOptions {
+    /// `--first-parent`: follow only the first parent of merge commits.
+    pub first_parent: bool,
+    /// `--merges` / `--no-merges`.
+    pub merges: MergeFilter,
+}
+
+impl RevWalkOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Equivalent of `--first-parent`.
+    pub fn first_parent(mut self) -> Self {
+        self.first_parent = true;
+        self
+    }
+
+    /// Equivalent of `--no-merges`.
+    pub fn no_merges(mut self) -> Self {
+        self.merges = MergeFilter::NoMerges;
+        self
+    }
+
+    /// Equivalent of `--merges`.
+    pub fn merges_only(mut self) -> Self {
+        self.merges = MergeFilter::MergesOnly;
+        self
+    }
+
+    /// Whether a visited commit is part of the output.
+    pub fn includes(&self, commit: &Commit) -> bool {
+        let is_merge = commit.parent_commit_ids.len() > 1;
+        match self.merges {
+            MergeFilter::All => true,
+            MergeFilter::NoMerges => !is_merge,
+            MergeFilter::MergesOnly => is_merge,
+        }
+    }
+
+    /// The parents the walk continues with after `commit`.
+    pub fn parents<'a>(&self, commit: &'a Commit) -> &'a [SHA1] {
+        if self.first_parent {
+            &commit.parent_commit_ids[..commit.parent_commit_ids.len().min(1)]
+        } else {
+            &commit.parent_commit_ids
+        }
+    }
+}
+
+/// Commit ordered for the walk queue: by corrected commit date when generation numbers are used
+/// (`0` otherwise), then by committer date, ties broken by insertion order.
+struct Queued<T = Commit> {
+    generation: u64,
+    timestamp: usize,
+    sequence: usize,
+    commit: T,
+}
+
+impl<T> PartialEq for Queued<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl<T> Eq for Queued<T> {}
+
+impl<T> PartialOrd for Queued<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Queued<T> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.generation
+            .cmp(&other.generation)
+            .then_with(|| self.timestamp.cmp(&other.timestamp))
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+/// List the history of `tips`, newest first by committer date like `git rev-list`.
+///
+/// `lookup` loads a commit by id; a missing commit is an error, as git reports a broken
+/// history instead of silently truncating it.
+pub fn rev_list<F>(
+    tips: &[SHA1],
+    options: &RevWalkOptions,
+    mut lookup: F,
+) -> Result<Vec<Commit>, GitError>
+where
+    F: FnMut(&SHA1) -> Option<Commit>,
+{
+    let mut seen = HashSet::new();
+    let mut queue = BinaryHeap::new();
+    let mut sequence = 0;
+    let mut enqueue = |id: &SHA1, queue: &mut BinaryHeap<Queued>| -> Result<(), GitError> {
+        if !seen.insert(*id) {
+            return Ok(());
+        }
+        let commit = lookup(id).ok_or_else(|| GitError::ObjectNotFound(id.to_string()))?;
+        queue.push(Queued {
+            generation: 0,
+            timestamp: commit.committer.timestamp,
+            sequence,
+            commit,
+        });
+        sequence += 1;
+        Ok(())
+    };
+
+    for tip in tips {
+        enqueue(tip, &mut queue)?;
+    }
+    let mut commits = Vec::new();
+    while let Some(Queued { commit, .. }) = queue.pop() {
+        for parent in options.parents(&commit) {
+            enqueue(parent, &mut queue)?;
+        }
+        if options.includes(&commit) {
+            commits.push(commit);
+        }
+    }
+    Ok(commits)
+}
+
+/// Generation numbers of a commit, as stored in git's commit-graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Generation {
+    /// Generation number v1: 1 for a root commit, one more than its highest parent otherwise.
+    pub topo_level: u32,
+    /// Generation number v2, the corrected commit date: the committer date, raised to one more
+    /// than the corrected date of every parent when clocks were skewed.
+    pub corrected_date: u64,
+}
+
+/// A cache of [`Generation`]s.
+///
+/// A commit is never numbered before its parents, so the first computation on a repository loads
+/// the whole history. The cache is meant to be kept (see [`GenerationNumbers::iter`] and
+/// [`GenerationNumbers::insert`] to persist it) and extended as commits are pushed, which then
+/// only loads the new commits. Backed by a [`CommitGraph`], numbers are read from the graph and
+/// only commits written after it are loaded.
+#[derive(Debug, Clone, Default)]
+pub struct GenerationNumbers {
+    generations: HashMap<SHA1, Generation>,
+    graph: Option<Arc<CommitGraph>>,
+}
+
+impl GenerationNumbers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A cache reading the numbers of the commits in `graph` from it.
+    pub fn with_commit_graph(graph: Arc<CommitGraph>) -> Self {
+        Self {
+            generations: HashMap::new(),
+            graph: Some(graph),
+        }
+    }
+
+    pub fn commit_graph(&self) -> Option<&Arc<CommitGraph>> {
+        self.graph.as_ref()
+    }
+
+    pub fn get(&self, id: &SHA1) -> Option<Generation> {
+        if let Some(generation) = self.generations.get(id) {
+            return Some(*generation);
+        }
+        let commit = self.graph.as_ref()?.get(id)?;
+        Some(Generation {
+            topo_level: (commit.topo_level != 0).then_some(commit.topo_level)?,
+            corrected_date: commit.corrected_date?,
+        })
+    }
+
+    pub fn insert(&mut self, id: SHA1, generation: Generation) {
+        self.generations.insert(id, generation);
+    }
+
+    /// The computed numbers, not including those read from the commit-graph.
+    pub fn iter(&self) -> impl Iterator<Item = (&SHA1, &Generation)> {
+        self.generations.iter()
+    }
+
+    /// Number of computed generations.
+    pub fn len(&self) -> usize {
+        self.generations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.generations.is_empty()
+    }
+
+    /// Number `commit` from its parents' generations, `None` if one of them isn't known yet.
+    pub fn compute_from(&mut self, commit: &Commit) -> Option<Generation> {
+        self.number(
+            commit.id,
+            &commit.parent_commit_ids,
+            commit.committer.timestamp as u64,
+        )
+    }
+
+    fn number(&mut self, id: SHA1, parents: &[SHA1], time: u64) -> Option<Generation> {
+        if let Some(generation) = self.get(&id) {
+            return Some(generation);
+        }
+        let mut generation = Generation {
+            topo_level: 1,
+            corrected_date: time,
+        };
+        for parent in parents {
+            let parent = self.get(parent)?;
+            generation.topo_level = generation.topo_level.max(parent.topo_level + 1);
+            generation.corrected_date = generation.corrected_date.max(parent.corrected_date + 1);
+        }
+        self.insert(id, generation);
+        Some(generation)
+    }
+
+    /// The generation of `id`, numbering its uncached ancestors first. Commits in the
+    /// commit-graph are read from it rather than loaded.
+    pub async fn compute<R: RepositoryAccess>(
+        &mut self,
+        repo: &R,
+        id: SHA1,
+    ) -> Result<Generation, ProtocolError> {
+        let graph = self.graph.clone();
+        let mut pending: HashMap<SHA1, CommitNode> = HashMap::new();
+        let mut stack = vec![id];
+        while let Some(&top) = stack.last() {
+            if self.get(&top).is_some() {
+                stack.pop();
+                continue;
+            }
+            let node = match pending.remove(&top) {
+                Some(node) => node,
+                None => CommitNode::load(repo, graph.as_deref(), top).await?,
+            };
+            if self
+                .number(top, &node.parents, node.timestamp as u64)
+                .is_some()
+            {
+                stack.pop();
+                continue;
+            }
+            stack.extend(
+                node.parents
+                    .iter()
+                    .filter(|parent| self.get(parent).is_none()),
+            );
+            pending.insert(top, node);
+        }
+        Ok(self.get(&id).expect("numbered above"))
+    }
+}
+
+/// The parents and committer date of a commit, all a walk that doesn't report commits needs.
+#[derive(Clone)]
+struct CommitNode {
+    parents: Vec<SHA1>,
+    timestamp: usize,
+}
+
+impl CommitNode {
+    /// Read the commit from `graph` when it is in it, load it from `repo` otherwise.
+    async fn load<R: RepositoryAccess>(
+        repo: &R,
+        graph: Option<&CommitGraph>,
+        id: SHA1,
+    ) -> Result<Self, ProtocolError> {
+        if let Some(commit) = graph.and_then(|graph| graph.get(&id)) {
+            return Ok(Self {
+                parents: commit.parents,
+                timestamp: commit.commit_time as usize,
+            });
+        }
+        let commit = repo.get_commit(&id.to_string()).await?;
+        Ok(Self {
+            parents: commit.parent_commit_ids,
+            timestamp: commit.committer.timestamp,
+        })
+    }
+}
+
+/// Output order of a [`CommitWalker`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WalkOrder {
+    /// Newest committer date first, the default of `git rev-list`.
+    #[default]
+    Date,
+    /// `--topo-order`: no parent before all of its children, and the commits of a merged branch
+    /// kept together instead of interleaved by date.
+    Topological,
+}
+
+/// What the walk knows about a commit it has loaded.
+struct Visit {
+    /// Reachable from an excluded commit.
+    hidden: bool,
+    /// Still in the queue, its parents not visited yet.
+    queued: bool,
+    parents: Vec<SHA1>,
+}
+
+/// Asynchronous `git rev-list <tips> --not <hidden>` over a [`RepositoryAccess`].
+///
+/// Without exclusions and in date order, commits are loaded as they are yielded. Otherwise the
+/// walk has to be limited first, like git does: it runs until no queued commit can still reach a
+/// reported one, then yields the result. Without generation numbers this assumes parents are not
+/// newer than their children; with [`CommitWalker::generations`] the queue is ordered by corrected
+/// commit date, which is exact under clock skew and lets a limited walk stop as soon as only
+/// excluded commits are left. When the repository has a commit-graph
+/// ([`RepositoryAccess::get_commit_graph`]), the walk uses its generation numbers by default.
+///
+/// [`CommitWalker::path`] limits the output to the commits changing given paths, like
+/// `git log --full-history -- <paths>` without parent rewriting: a commit is reported when the
+/// paths differ from every one of its parents. The changed-path filters of the commit-graph let
+/// most other commits be skipped without loading their trees.
+pub struct CommitWalker<'a, R> {
+    repo: &'a R,
+    options: RevWalkOptions,
+    order: WalkOrder,
+    tips: Vec<SHA1>,
+    hidden: Vec<SHA1>,
+    queue: BinaryHeap<Queued>,
+    visits: HashMap<SHA1, Visit>,
+    sequence: usize,
+    /// Queued commits not hidden.
+    interesting: usize,
+    started: bool,
+    /// The output of a limited walk, reversed.
+    limited: Option<Vec<Commit>>,
+    generations: Option<GenerationNumbers>,
+    paths: Vec<String>,
+}
+
+impl<'a, R: RepositoryAccess> CommitWalker<'a, R> {
+    pub fn new(repo: &'a R) -> Self {
+        Self {
+            repo,
+            options: RevWalkOptions::default(),
+            order: WalkOrder::default(),
+            tips: Vec::new(),
+            hidden: Vec::new(),
+            queue: BinaryHeap::new(),
+            visits: HashMap::new(),
+            sequence: 0,
+            interesting: 0,
+            started: false,
+            limited: None,
+            generations: None,
+            paths: Vec::new(),
+        }
+    }
+
+    /// Start the walk at `tip`.
+    pub fn push(mut self, tip: SHA1) -> Self {
+        self.tips.push(tip);
+        self
+    }
+
+    /// Exclude `id` and its ancestors, like `--not <id>` or `^<id>`.
+    pub fn hide(mut self, id: SHA1) -> Self {
+        self.hidden.push(id);
+        self
+    }
+
+    pub fn options(mut self, options: RevWalkOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn order(mut self, order: WalkOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Only report commits changing `path`, a file or a directory, like `git log -- <path>`.
+    /// Repeat to report commits changing any of several paths.
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.paths
+            .push(path.into().trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Order the walk by generation numbers, computing missing ones into `cache`.
+    pub fn generations(mut self, cache: GenerationNumbers) -> Self {
+        self.generations = Some(cache);
+        self
+    }
+
+    /// The generation of a commit the walk has loaded, when walking with generation numbers.
+    pub fn generation(&self, id: &SHA1) -> Option<Generation> {
+        self.generations.as_ref()?.get(id)
+    }
+
+    /// The generation cache, extended with every commit the walk loaded and not found in the
+    /// commit-graph.
+    pub fn into_generations(self) -> Option<GenerationNumbers> {
+        self.generations
+    }
+
+    /// The next commit of the walk, `None` once it is done.
+    pub async fn next_commit(&mut self) -> Result<Option<Commit>, ProtocolError> {
+        if !self.started {
+            self.started = true;
+            if self.generations.is_none() {
+                self.generations = self
+                    .repo
+                    .get_commit_graph()
+                    .await?
+                    .map(GenerationNumbers::with_commit_graph);
+            }
+            for tip in self.tips.clone() {
+                self.enqueue(tip, false).await?;
+            }
+            for id in self.hidden.clone() {
+                self.enqueue(id, true).await?;
+            }
+            if !self.hidden.is_empty() || self.order == WalkOrder::Topological {
+                self.limited = Some(self.limit().await?);
+            }
+        }
+
+        if let Some(limited) = &mut self.limited {
+            return Ok(limited.pop());
+        }
+        while let Some((commit, hidden)) = self.step().await? {
+            if !hidden && self.options.includes(&commit) && self.changes_paths(&commit).await? {
+                return Ok(Some(commit));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Run the walk to the end.
+    pub async fn collect(mut self) -> Result<Vec<Commit>, ProtocolError> {
+        let mut commits = Vec::new();
+        while let Some(commit) = self.next_commit().await? {
+            commits.push(commit);
+        }
+        Ok(commits)
+    }
+
+    async fn enqueue(&mut self, id: SHA1, hidden: bool) -> Result<(), ProtocolError> {
+        if self.visits.contains_key(&id) {
+            if hidden {
+                for parent in self.hide_visited(id) {
+                    Box::pin(self.enqueue(parent, true)).await?;
+                }
+            }
+            return Ok(());
+        }
+        let commit = self.repo.get_commit(&id.to_string()).await?;
+        self.visits.insert(
+            id,
+            Visit {
+                hidden,
+                queued: true,
+                parents: commit.parent_commit_ids.clone(),
+            },
+        );
+        if !hidden {
+            self.interesting += 1;
+        }
+        let generation = match &mut self.generations {
+            Some(cache) => cache.compute(self.repo, id).await?.corrected_date,
+            None => 0,
+        };
+        self.queue.push(Queued {
+            generation,
+            timestamp: commit.committer.timestamp,
+            sequence: self.sequence,
+            commit,
+        });
+        self.sequence += 1;
+        Ok(())
+    }
+
+    /// Mark a visited commit and its visited ancestors hidden. Returns the parents of already
+    /// walked commits that were never visited, which have to be loaded as hidden.
+    fn hide_visited(&mut self, id: SHA1) -> Vec<SHA1> {
+        let mut unvisited = Vec::new();
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            match self.visits.get_mut(&id) {
+                Some(visit) if !visit.hidden => {
+                    visit.hidden = true;
+                    if visit.queued {
+                        // Its parents are hidden when it is taken from the queue
+                        self.interesting -= 1;
+                    } else {
+                        stack.extend(visit.parents.iter().copied());
+                    }
+                }
+                Some(_) => {}
+                None => unvisited.push(id),
+            }
+        }
+        unvisited
+    }
+
+    /// Take the newest queued commit and queue its parents. Hidden commits pass their flag on to
+    /// every parent, visible ones continue with the parents selected by the options.
+    async fn step(&mut self) -> Result<Option<(Commit, bool)>, ProtocolError> {
+        let Some(Queued { commit, .. }) = self.queue.pop() else {
+            return Ok(None);
+        };
+        let visit = self
+            .visits
+            .get_mut(&commit.id)
+            .expect("queued commits are visited");
+        visit.queued = false;
+        let hidden = visit.hidden;
+        if hidden {
+            for parent in commit.parent_commit_ids.clone() {
+                self.enqueue(parent, true).await?;
+            }
+        } else {
+            self.interesting -= 1;
+            for parent in self.options.parents(&commit).to_vec() {
+                self.enqueue(parent, false).await?;
+            }
+        }
+        Ok(Some((commit, hidden)))
+    }
+
+    /// Walk until the result can't change any more and return it, reversed.
+    async fn limit(&mut self) -> Result<Vec<Commit>, ProtocolError> {
+        let mut commits: Vec<Commit> = Vec::new();
+        loop {
+            let oldest = commits.last().map(|c| c.committer.timestamp);
+            let may_reach_output = self.generations.is_none()
+                && self
+                    .queue
+                    .peek()
+                    .zip(oldest)
+                    .is_some_and(|(next, oldest)| next.timestamp >= oldest);
+            if self.interesting == 0 && !may_reach_output {
+                break;
+            }
+            match self.step().await? {
+                Some((commit, false)) => commits.push(commit),
+                Some(_) => {}
+                None => break,
+            }
+        }
+        commits.retain(|commit| !self.visits[&commit.id].hidden);
+        if self.order == WalkOrder::Topological {
+            commits = topo_sort(commits, &self.options);
+        }
+        let mut reported = Vec::with_capacity(commits.len());
+        for commit in commits {
+            if self.options.includes(&commit) && self.changes_paths(&commit).await? {
+                reported.push(commit);
+            }
+        }
+        reported.reverse();
+        Ok(reported)
+    }
+
+    /// Whether the walk's paths differ between `commit` and each of its parents, or exist in a
+    /// root commit. Always true when the walk isn't limited to paths.
+    async fn changes_paths(&self, commit: &Commit) -> Result<bool, ProtocolError> {
+        if self.paths.is_empty() {
+            return Ok(true);
+        }
+        let graph = self
+            .generations
+            .as_ref()
+            .and_then(|cache| cache.commit_graph());
+        // The filter describes the diff against the first parent
+        if let Some(filter) = graph.and_then(|graph| graph.changed_path_filter(&commit.id))
+            && !self.paths.iter().any(|path| filter.may_contain(path))
+        {
+            return Ok(false);
+        }
+        let entries = self.path_entries(commit.tree_id).await?;
+        if commit.parent_commit_ids.is_empty() {
+            return Ok(entries.iter().any(Option::is_some));
+        }
+        for parent in &commit.parent_commit_ids {
+            let tree_id = match graph.and_then(|graph| graph.get(parent)) {
+                Some(parent) => parent.tree_id,
+                None => self.repo.get_commit(&parent.to_string()).await?.tree_id,
+            };
+            if self.path_entries(tree_id).await? == entries {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// The mode and id of each of the walk's paths in the tree `root`, `None` where a path
+    /// doesn't exist.
+    async fn path_entries(
+        &self,
+        root: SHA1,
+    ) -> Result<Vec<Option<(TreeItemMode, SHA1)>>, ProtocolError> {
+        let root = self.repo.get_tree(&root.to_string()).await?;
+        let mut entries = Vec::with_capacity(self.paths.len());
+        for path in &self.paths {
+            let mut entry = None;
+            let mut tree = Some(root.clone());
+            for name in path.split('/') {
+                let item = tree
+                    .take()
+                    .and_then(|tree| tree.tree_items.into_iter().find(|item| item.name == name));
+                entry = item.map(|item| (item.mode, item.id));
+                if let Some((TreeItemMode::Tree, id)) = entry {
+                    tree = Some(self.repo.get_tree(&id.to_string()).await?);
+                }
+            }
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+}
+
+/// Reorder date-ordered `commits` like git's `--topo-order`: a commit is shown once all of its
+/// children are, and after a merge the walk descends its last parent first.
+fn topo_sort(commits: Vec<Commit>, options: &RevWalkOptions) -> Vec<Commit> {
+    let index: HashMap<SHA1, usize> = commits.iter().enumerate().map(|(i, c)| (c.id, i)).collect();
+    let mut children = vec![0usize; commits.len()];
+    for commit in &commits {
+        for parent in options.parents(commit) {
+            if let Some(&i) = index.get(parent) {
+                children[i] += 1;
+            }
+        }
+    }
+
+    let mut order = Vec::with_capacity(commits.len());
+    let mut stack: Vec<usize> = (0..commits.len())
+        .filter(|&i| children[i] == 0)
+        .rev()
+        .collect();
+    while let Some(i) = stack.pop() {
+        order.push(i);
+        for parent in options.parents(&commits[i]) {
+            if let Some(&p) = index.get(parent) {
+                children[p] -= 1;
+                if children[p] == 0 {
+                    stack.push(p);
+                }
+            }
+        }
+    }
+
+    let mut commits: Vec<Option<Commit>> = commits.into_iter().map(Some).collect();
+    order
+        .into_iter()
+        .map(|i| commits[i].take().expect("each commit is emitted once"))
+        .collect()
+}
+
+/// The commit could be reached from the first side of a merge-base walk.
+const PARENT1: u8 = 1;
+/// The commit could be reached from the second side.
+const PARENT2: u8 = 2;
+/// A common ancestor was found above the commit, so it can't be a best one.
+const STALE: u8 = 4;
+/// The commit was reported as a common ancestor.
+const RESULT: u8 = 8;
+
+/// git's `paint_down_to_common`: walk down from `one` and `twos` by date, flagging which side each
+/// commit is reachable from, until every queued commit is below a common ancestor. Returns the
+/// common ancestors found, newest first, and the flags of every commit visited.
+async fn paint_down_to_common<R: RepositoryAccess>(
+    repo: &R,
+    one: SHA1,
+    twos: &[SHA1],
+    mut generations: Option<&mut GenerationNumbers>,
+) -> Result<(Vec<SHA1>, HashMap<SHA1, u8>), ProtocolError> {
+    let graph = generations
+        .as_deref()
+        .and_then(|cache| cache.commit_graph().cloned());
+    let mut nodes: HashMap<SHA1, CommitNode> = HashMap::new();
+    let mut flags: HashMap<SHA1, u8> = HashMap::new();
+    let mut queue: BinaryHeap<Queued<SHA1>> = BinaryHeap::new();
+    // The queue entries of each commit, and how many are of commits not stale yet
+    let mut entries: HashMap<SHA1, usize> = HashMap::new();
+    let mut nonstale = 0;
+    let mut sequence = 0;
+    for (id, side) in std::iter::once((one, PARENT1)).chain(twos.iter().map(|id| (*id, PARENT2))) {
+        *flags.entry(id).or_default() |= side;
+        let node = load_node(repo, graph.as_deref(), &mut nodes, id).await?;
+        let generation = match generations.as_deref_mut() {
+            Some(cache) => cache.compute(repo, id).await?.corrected_date,
+            None => 0,
+        };
+        queue.push(Queued {
+            generation,
+            timestamp: node.timestamp,
+            sequence,
+            commit: id,
+        });
+        *entries.entry(id).or_default() += 1;
+        nonstale += 1;
+        sequence += 1;
+    }
+
+    let mut common = Vec::new();
+    while nonstale > 0 {
+        let Some(Queued { commit: id, .. }) = queue.pop() else {
+            break;
+        };
+        *entries.get_mut(&id).expect("queued commits are counted") -= 1;
+        let commit_flags = flags.get_mut(&id).expect("queued commits are flagged");
+        if *commit_flags & STALE == 0 {
+            nonstale -= 1;
+        }
+        let mut inherited = *commit_flags & (PARENT1 | PARENT2 | STALE);
+        if inherited & (PARENT1 | PARENT2) == PARENT1 | PARENT2 {
+            if *commit_flags & RESULT == 0 {
+                *commit_flags |= RESULT;
+                common.push(id);
+            }
+            inherited |= STALE;
+        }
+        for parent in nodes[&id].parents.clone() {
+            let parent_flags = flags.entry(parent).or_default();
+            if *parent_flags & inherited == inherited {
+                continue;
+            }
+            let parent_entries = entries.entry(parent).or_default();
+            if *parent_flags & STALE == 0 {
+                if inherited & STALE != 0 {
+                    nonstale -= *parent_entries;
+                } else {
+                    nonstale += 1;
+                }
+            }
+            *parent_entries += 1;
+            *parent_flags |= inherited;
+            let node = load_node(repo, graph.as_deref(), &mut nodes, parent).await?;
+            // Parents of a numbered commit are numbered
+            let generation = generations
+                .as_deref()
+                .and_then(|cache| cache.get(&parent))
+                .map_or(0, |generation| generation.corrected_date);
+            queue.push(Queued {
+                generation,
+                timestamp: node.timestamp,
+                sequence,
+                commit: parent,
+            });
+            sequence += 1;
+        }
+    }
+    // A common ancestor reached again from a newer one is not a best one
+    common.retain(|id| flags[id] & STALE == 0);
+    Ok((common, flags))
+}
+
+async fn load_node<R: RepositoryAccess>(
+    repo: &R,
+    graph: Option<&CommitGraph>,
+    cache: &mut HashMap<SHA1, CommitNode>,
+    id: SHA1,
+) -> Result<CommitNode, ProtocolError> {
+    if let Some(node) = cache.get(&id) {
+        return Ok(node.clone());
+    }
+    let node = CommitNode::load(repo, graph, id).await?;
+    cache.insert(id, node.clone());
+    Ok(node)
+}
+
+/// All best common ancestors of `a` and `b`, like `git merge-base --all`: common ancestors that
+/// are not ancestors of another common ancestor. Newest first; empty for unrelated histories.
+///
+/// Walks by the generation numbers of the repository's commit-graph when it has one.
+pub async fn merge_bases<R: RepositoryAccess>(
+    repo: &R,
+    a: SHA1,
+    b: SHA1,
+) -> Result<Vec<SHA1>, ProtocolError> {
+    let mut generations = repo
+        .get_commit_graph()
+        .await?
+        .map(GenerationNumbers::with_commit_graph);
+    find_merge_bases(repo, a, b, generations.as_mut()).await
+}
+
+/// [`merge_bases`] walking by generation numbers, which keeps the result exact when committer
+/// dates are skewed. Parents are read from the commit-graph `generations` is backed by, if any.
+pub async fn merge_bases_with_generations<R: RepositoryAccess>(
+    repo: &R,
+    a: SHA1,
+    b: SHA1,
+    generations: &mut GenerationNumbers,
+) -> Result<Vec<SHA1>, ProtocolError> {
+    find_merge_bases(repo, a, b, Some(generations)).await
+}
+
+async fn find_merge_bases<R: RepositoryAccess>(
+    repo: &R,
+    a: SHA1,
+    b: SHA1,
+    mut generations: Option<&mut GenerationNumbers>,
+) -> Result<Vec<SHA1>, ProtocolError> {
+    if a == b {
+        return Ok(vec![a]);
+    }
+    let (candidates, _) = paint_down_to_common(repo, a, &[b], generations.as_deref_mut()).await?;
+    if candidates.len() < 2 {
+        return Ok(candidates);
+    }
+
+    // Drop candidates reachable from another one, as git's `remove_redundant`
+    let mut redundant = vec![false; candidates.len()];
+    for i in 0..candidates.len() {
+        if redundant[i] {
+            continue;
+        }
+        let others: Vec<usize> = (0..candidates.len())
+            .filter(|&j| j != i && !redundant[j])
+            .collect();
+        let other_ids: Vec<SHA1> = others.iter().map(|&j| candidates[j]).collect();
+        let (_, flags) =
+            paint_down_to_common(repo, candidates[i], &other_ids, generations.as_deref_mut())
+                .await?;
+        if flags[&candidates[i]] & PARENT2 != 0 {
+            redundant[i] = true;
+        }
+        for j in others {
+            if flags[&candidates[j]] & PARENT1 != 0 {
+                redundant[j] = true;
+            }
+        }
+    }
+    Ok(candidates
+        .into_iter()
+        .zip(redundant)
+        .filter(|(_, redundant)| !redundant)
+        .map(|(id, _)| id)
+        .collect())
+}
+
+/// The best common ancestor of `a` and `b`, like `git merge-base`. When there are several (after
+/// criss-cross merges), the newest one.
+pub async fn merge_base<R: RepositoryAccess>(
+    repo: &R,
+    a: SHA1,
+    b: SHA1,
+) -> Result<Option<SHA1>, ProtocolError> {
+    Ok(merge_bases(repo, a, b).await?.into_iter().next())
+}
+
+/// Whether `ancestor` is reachable from `tip`. The walk never goes below the generation of
+/// `ancestor`, since no commit there can reach it, so checking a recent commit against a long
+/// history stops early.
+pub async fn is_ancestor<R: RepositoryAccess>(
+    repo: &R,
+    ancestor: SHA1,
+    tip: SHA1,
+    generations: &mut GenerationNumbers,
+) -> Result<bool, ProtocolError> {
+    let floor = generations.compute(repo, ancestor).await?.topo_level;
+    let graph = generations.commit_graph().cloned();
+    let mut seen = HashSet::new();
+    let mut stack = vec![tip];
+    while let Some(id) = stack.pop() {
+        if id == ancestor {
+            return Ok(true);
+        }
+        if !seen.insert(id) || generations.compute(repo, id).await?.topo_level <= floor {
+            continue;
+        }
+        stack.extend(CommitNode::load(repo, graph.as_deref(), id).await?.parents);
+    }
+    Ok(false)
+}
+
+/// Number of commits reachable from `a` but not from `b`, and from `b` but not from `a`, like
+/// `git rev-list --left-right --count a...b`.
+pub async fn ahead_behind<R: RepositoryAccess>(
+    repo: &R,
+    a: SHA1,
+    b: SHA1,
+) -> Result<(usize, usize), ProtocolError> {
+    let ahead = CommitWalker::new(repo).push(a).hide(b).collect().await?;
+    let behind = CommitWalker::new(repo).push(b).hide(a).collect().await?;
+    Ok((ahead.len(), behind.len()))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::diff::tree::diff_trees;
+    use crate::internal::object::ObjectTrait;
+    use crate::internal::object::signature::Signature;
+    use crate::internal::object::tree::{Tree, TreeItem};
+    use crate::protocol::memory::MemoryRepository;
+
+    /// A commit of the empty tree id made at `time` by a single tester.
+    pub(crate) fn commit(parents: Vec<SHA1>, time: usize, message: &str) -> Commit {
+        commit_tree(SHA1::default(), parents, time, message)
+    }
+
+    fn commit_tree(tree_id: SHA1, parents: Vec<SHA1>, time: usize, message: &str) -> Commit {
+        let sign = |kind: &str| {
+            Signature::from_data(format!("{kind} tester <t@example.com> {time} +0000").into_bytes())
+                .unwrap()
+        };
+        Commit::new(
+            sign("author"),
+            sign("committer"),
+            tree_id,
+            parents,
+            &format!("\n{message}\n"),
+        )
+    }
+
+    /// `main: a - b - m`, with `m` merging `feature: a - f`
+    fn history() -> (HashMap<SHA1, Commit>, SHA1) {
+        let a = commit(vec![], 1, "a");
+        let b = commit(vec![a.id], 2, "b");
+        let f = commit(vec![a.id], 3, "f");
+        let m = commit(vec![b.id, f.id], 4, "m");
+        let tip = m.id;
+        let commits = [a, b, f, m].into_iter().map(|c| (c.id, c)).collect();
+        (commits, tip)
+    }
+
+    fn messages(options: RevWalkOptions) -> Vec<String> {
+        let (commits, tip) = history();
+        rev_list(&[tip], &options, |id| commits.get(id).cloned())
+            .unwrap()
+            .iter()
+            .map(|c| c.message.trim().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_rev_list_options() {
+        assert_eq!(messages(RevWalkOptions::new()), ["m", "f", "b", "a"]);
+        assert_eq!(
+            messages(RevWalkOptions::new().first_parent()),
+            ["m", "b", "a"]
+        );
+        assert_eq!(messages(RevWalkOptions::new().no_merges()), ["f", "b", "a"]);
+        assert_eq!(messages(RevWalkOptions::new().merges_only()), ["m"]);
+        assert_eq!(
+            messages(RevWalkOptions::new().first_parent().no_merges()),
+            ["b", "a"]
+        );
+    }
+
+    #[test]
+    fn test_rev_list_missing_commit() {
+        let (mut commits, tip) = history();
+        let root = commits
+            .values()
+            .find(|c| c.message.trim() == "a")
+            .unwrap()
+            .id;
+        commits.remove(&root);
+        let result = rev_list(&[tip], &RevWalkOptions::new(), |id| {
+            commits.get(id).cloned()
+        });
+        assert!(matches!(result, Err(GitError::ObjectNotFound(_))));
+    }
+
+    /// A repository holding `commits`.
+    fn history_repo<'a>(commits: impl IntoIterator<Item = &'a Commit>) -> MemoryRepository {
+        let repo = MemoryRepository::new();
+        for commit in commits {
+            repo.insert_object(commit).unwrap();
+        }
+        repo
+    }
+
+    /// `main: a - b - c - m`, with `m` merging `feature: a - f1 - f2` where the feature commits
+    /// are interleaved in time with `b` and `c`.
+    fn branchy_commits() -> (Vec<Commit>, HashMap<&'static str, SHA1>) {
+        let a = commit(vec![], 1, "a");
+        let f1 = commit(vec![a.id], 2, "f1");
+        let b = commit(vec![a.id], 3, "b");
+        let f2 = commit(vec![f1.id], 4, "f2");
+        let c = commit(vec![b.id], 5, "c");
+        let m = commit(vec![c.id, f2.id], 6, "m");
+        let ids = [
+            ("a", &a),
+            ("b", &b),
+            ("c", &c),
+            ("f1", &f1),
+            ("f2", &f2),
+            ("m", &m),
+        ]
+        .into_iter()
+        .map(|(name, commit)| (name, commit.id))
+        .collect();
+        (vec![a, f1, b, f2, c, m], ids)
+    }
+
+    /// The [`branchy_commits`] in a repository.
+    fn branchy_history() -> (MemoryRepository, HashMap<&'static str, SHA1>) {
+        let (commits, ids) = branchy_commits();
+        (history_repo(&commits), ids)
+    }
+
+    async fn walk(walker: CommitWalker<'_, MemoryRepository>) -> Vec<String> {
+        walker
+            .collect()
+            .await
+            .unwrap()
+            .iter()
+            .map(|c| c.message.trim().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_commit_walker_orders() {
+        let (repo, ids) = branchy_history();
+        let walker = || CommitWalker::new(&repo).push(ids["m"]);
+        assert_eq!(walk(walker()).await, ["m", "c", "f2", "b", "f1", "a"]);
+        assert_eq!(
+            walk(walker().order(WalkOrder::Topological)).await,
+            ["m", "f2", "f1", "c", "b", "a"]
+        );
+        assert_eq!(
+            walk(walker().options(RevWalkOptions::new().first_parent())).await,
+            ["m", "c", "b", "a"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_commit_walker_exclusions() {
+        let (repo, ids) = branchy_history();
+        // What merging the feature brought in, like `git log c..m`
+        let walker = CommitWalker::new(&repo).push(ids["m"]).hide(ids["c"]);
+        assert_eq!(walk(walker).await, ["m", "f2", "f1"]);
+
+        let walker = CommitWalker::new(&repo)
+            .push(ids["m"])
+            .hide(ids["f1"])
+            .order(WalkOrder::Topological)
+            .options(RevWalkOptions::new().no_merges());
+        assert_eq!(walk(walker).await, ["f2", "c", "b"]);
+
+        // A tip that is also excluded reports nothing
+        let walker = CommitWalker::new(&repo).push(ids["b"]).hide(ids["b"]);
+        assert!(walk(walker).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_merge_bases() {
+        let (repo, ids) = branchy_history();
+        let base = |a: &str, b: &str| merge_base(&repo, ids[a], ids[b]);
+        assert_eq!(base("c", "f2").await.unwrap(), Some(ids["a"]));
+        assert_eq!(base("m", "f2").await.unwrap(), Some(ids["f2"]));
+        assert_eq!(base("b", "b").await.unwrap(), Some(ids["b"]));
+        assert_eq!(
+            ahead_behind(&repo, ids["c"], ids["f2"]).await.unwrap(),
+            (2, 2)
+        );
+        assert_eq!(
+            ahead_behind(&repo, ids["m"], ids["c"]).await.unwrap(),
+            (3, 0)
+        );
+
+        // Criss-cross: `x` and `y` both merge `b` and `f1`, so both are best merge bases
+        let x = commit(vec![ids["b"], ids["f1"]], 7, "x");
+        let y = commit(vec![ids["f1"], ids["b"]], 8, "y");
+        let orphan = commit(vec![], 9, "orphan");
+        let (x_id, y_id, orphan_id) = (x.id, y.id, orphan.id);
+        for commit in [x, y, orphan] {
+            repo.insert_object(&commit).unwrap();
+        }
+        assert_eq!(
+            merge_bases(&repo, x_id, y_id).await.unwrap(),
+            [ids["b"], ids["f1"]]
+        );
+        assert!(
+            merge_bases(&repo, orphan_id, x_id)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_generation_numbers() {
+        let (repo, ids) = branchy_history();
+        let mut generations = GenerationNumbers::new();
+        let m = generations.compute(&repo, ids["m"]).await.unwrap();
+        assert_eq!(generations.len(), 6);
+        assert_eq!(m.topo_level, 4);
+        assert_eq!(generations.get(&ids["f1"]).unwrap().topo_level, 2);
+        assert_eq!(generations.get(&ids["c"]).unwrap().corrected_date, 5);
+
+        assert!(
+            is_ancestor(&repo, ids["f1"], ids["m"], &mut generations)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !is_ancestor(&repo, ids["b"], ids["f2"], &mut generations)
+                .await
+                .unwrap()
+        );
+        assert!(
+            is_ancestor(&repo, ids["c"], ids["c"], &mut generations)
+                .await
+                .unwrap()
+        );
+
+        // `s` was committed with a clock behind its parent
+        let s = commit(vec![ids["f2"]], 1, "s");
+        let n = commit(vec![s.id, ids["c"]], 2, "n");
+        let (s_id, n_id) = (s.id, n.id);
+        for commit in [s, n] {
+            repo.insert_object(&commit).unwrap();
+        }
+        let s = generations.compute(&repo, s_id).await.unwrap();
+        assert_eq!(
+            s,
+            Generation {
+                topo_level: 4,
+                corrected_date: 5
+            }
+        );
+        assert_eq!(
+            generations
+                .compute(&repo, n_id)
+                .await
+                .unwrap()
+                .corrected_date,
+            6
+        );
+        assert_eq!(
+            merge_bases_with_generations(&repo, n_id, ids["m"], &mut generations)
+                .await
+                .unwrap(),
+            [ids["c"], ids["f2"]]
+        );
+
+        let mut walker = CommitWalker::new(&repo)
+            .push(n_id)
+            .hide(ids["m"])
+            .generations(generations);
+        let mut walked = vec![];
+        while let Some(commit) = walker.next_commit().await.unwrap() {
+            walked.push(commit.message.trim().to_string());
+        }
+        assert_eq!(walked, ["n", "s"]);
+        assert_eq!(walker.generation(&s_id).unwrap().corrected_date, 5);
+        assert_eq!(walker.into_generations().unwrap().len(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_walks_use_commit_graph() {
+        let (commits, ids) = branchy_commits();
+        let graph = Arc::new(CommitGraph::from_commits(&commits).unwrap());
+        let n = commit(vec![ids["m"]], 7, "n");
+        let n_id = n.id;
+
+        // Only `n` can be loaded, the rest of the history is read from the graph
+        let repo = history_repo([&n]);
+        repo.set_commit_graph(graph.clone());
+        assert_eq!(
+            merge_base(&repo, n_id, ids["f2"]).await.unwrap(),
+            Some(ids["f2"])
+        );
+        assert_eq!(
+            merge_base(&repo, ids["c"], ids["f2"]).await.unwrap(),
+            Some(ids["a"])
+        );
+        let mut generations = GenerationNumbers::with_commit_graph(graph.clone());
+        assert_eq!(
+            generations.compute(&repo, n_id).await.unwrap().topo_level,
+            5
+        );
+        assert_eq!(generations.len(), 1);
+        assert!(
+            is_ancestor(&repo, ids["b"], n_id, &mut generations)
+                .await
+                .unwrap()
+        );
+
+        let repo = history_repo(commits.iter().chain([&n]));
+        repo.set_commit_graph(graph);
+        let mut walker = CommitWalker::new(&repo).push(n_id).hide(ids["c"]);
+        assert_eq!(walker.next_commit().await.unwrap().unwrap().id, n_id);
+        assert_eq!(walker.generation(&ids["m"]).unwrap().topo_level, 4);
+        assert_eq!(walk(walker).await, ["m", "f2", "f1"]);
+    }
+
+    /// Store the trees of `files`, `(path, content)` pairs with content standing in for blob ids,
+    /// and commit the root tree on top of `parent`.
+    fn commit_files(
+        repo: &MemoryRepository,
+        parent: Option<&Commit>,
+        files: &[(&str, &str)],
+        time: usize,
+        message: &str,
+    ) -> Commit {
+        fn store(repo: &MemoryRepository, files: &[(&str, &str)]) -> SHA1 {
+            let mut items = Vec::new();
+            let mut dirs: Vec<&str> = Vec::new();
+            for (path, content) in files {
+                match path.split_once('/') {
+                    Some((dir, _)) if !dirs.contains(&dir) => dirs.push(dir),
+                    Some(_) => {}
+                    None => items.push(TreeItem::new(
+                        TreeItemMode::Blob,
+                        SHA1::new(content.as_bytes()),
+                        path.to_string(),
+                    )),
+                }
+            }
+            for dir in dirs {
+                let nested: Vec<(&str, &str)> = files
+                    .iter()
+                    .filter_map(|(path, content)| {
+                        let rest = path.strip_prefix(dir)?.strip_prefix('/')?;
+                        Some((rest, *content))
+                    })
+                    .collect();
+                let id = store(repo, &nested);
+                items.push(TreeItem::new(TreeItemMode::Tree, id, dir.to_string()));
+            }
+            let tree = Tree::from_tree_items(items).unwrap();
+            repo.insert_object(&tree).unwrap()
+        }
+        let tree_id = store(repo, files);
+        let parents = parent.into_iter().map(|p| p.id).collect();
+        let commit = commit_tree(tree_id, parents, time, message);
+        repo.insert_object(&commit).unwrap();
+        commit
+    }
+
+    #[tokio::test]
+    async fn test_commit_walker_paths() {
+        let repo = MemoryRepository::new();
+        let r = commit_files(&repo, None, &[("README", "1"), ("src/lib.rs", "1")], 1, "r");
+        let s = commit_files(
+            &repo,
+            Some(&r),
+            &[("README", "1"), ("src/lib.rs", "2")],
+            2,
+            "s",
+        );
+        let t = commit_files(
+            &repo,
+            Some(&s),
+            &[("README", "2"), ("src/lib.rs", "2")],
+            3,
+            "t",
+        );
+        let u = commit_files(
+            &repo,
+            Some(&t),
+            &[
+                ("README", "2"),
+                ("src/lib.rs", "2"),
+                ("src/diff/mod.rs", "1"),
+            ],
+            4,
+            "u",
+        );
+
+        let log = |path| walk(CommitWalker::new(&repo).push(u.id).path(path));
+        let assert_logs = || async {
+            assert_eq!(log("src").await, ["u", "s", "r"]);
+            assert_eq!(log("src/lib.rs").await, ["s", "r"]);
+            assert_eq!(log("README").await, ["t", "r"]);
+            assert!(log("docs/").await.is_empty());
+        };
+        assert_logs().await;
+
+        // Same answers from the changed-path filters of a commit-graph
+        let tree = |id: &SHA1| {
+            let (_, data) = repo.object(id)?;
+            Some(<Tree as ObjectTrait>::from_bytes(&data, *id).unwrap())
+        };
+        let commits = [&r, &s, &t, &u];
+        let graph = CommitGraph::from_commits_with_changed_paths(commits, |commit| {
+            let parent = commit.parent_commit_ids.first().map(|id| {
+                let parent = commits.iter().find(|c| c.id == *id).unwrap();
+                tree(&parent.tree_id).unwrap()
+            });
+            let changes = diff_trees(parent.as_ref(), tree(&commit.tree_id).as_ref(), tree)?;
+            Ok(changes.iter().map(|c| c.path().to_string()).collect())
+        })
+        .unwrap();
+        assert!(!graph.changed_path_filter(&t.id).unwrap().may_contain("src"));
+        repo.set_commit_graph(Arc::new(graph));
+        assert_logs().await;
+    }
+}